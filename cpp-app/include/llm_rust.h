@@ -29,7 +29,7 @@
  *
  * Structures:
  * - CpuInfo: System CPU information.
- * - llama_model, llama_context, llama_sampler, llama_vocab, llama_batch, etc.: Opaque handles for model, context, sampler, vocabulary, batch, and backend.
+ * - llama_model, llama_context, llama_sampler, llama_vocab, etc.: Opaque handles for model, context, sampler, vocabulary, and backend; llama_batch holds its token arrays directly.
  * - common_params, llama_model_params, llama_context_params: Configuration for model/context initialization.
  * - lora_adapter: LoRA adapter configuration.
  * - token_list: Token sequence container.
@@ -51,8 +51,40 @@
 extern "C" {
 #endif // __cplusplus
 
-/** @brief Default pooling type rank for LLAMA models */
-#define LLAMA_POOLING_TYPE_RANK 2
+/** @name Pooling types (llama_context_params::pooling_type) */
+///@{
+#define LLAMA_POOLING_TYPE_UNSPECIFIED -1 /**< Use the model's default */
+#define LLAMA_POOLING_TYPE_NONE 0         /**< One embedding per output token */
+#define LLAMA_POOLING_TYPE_MEAN 1         /**< Mean of the sequence's hidden states */
+#define LLAMA_POOLING_TYPE_CLS 2          /**< Hidden state of the token at position 0 */
+#define LLAMA_POOLING_TYPE_LAST 3         /**< Hidden state of the last token */
+#define LLAMA_POOLING_TYPE_RANK 4         /**< Classification scores of the CLS state (rerankers) */
+///@}
+
+/** @name Attention types (llama_context_params::attention_type) */
+///@{
+#define LLAMA_ATTENTION_TYPE_UNSPECIFIED -1 /**< Use the model's default */
+#define LLAMA_ATTENTION_TYPE_CAUSAL 0       /**< Tokens see only earlier positions */
+#define LLAMA_ATTENTION_TYPE_NON_CAUSAL 1   /**< Tokens see their whole sequence (encoders) */
+///@}
+
+/** @name RoPE scaling types (llama_context_params::rope_scaling_type) */
+///@{
+#define LLAMA_ROPE_SCALING_TYPE_UNSPECIFIED -1 /**< Use the model's default */
+#define LLAMA_ROPE_SCALING_TYPE_NONE 0         /**< No scaling; rope_freq_scale is ignored */
+#define LLAMA_ROPE_SCALING_TYPE_LINEAR 1       /**< Positions scaled by rope_freq_scale */
+#define LLAMA_ROPE_SCALING_TYPE_YARN 2         /**< YaRN: interpolate low frequencies only */
+#define LLAMA_ROPE_SCALING_TYPE_LONGROPE 3     /**< Per-dimension short/long frequency factors */
+#define LLAMA_ROPE_SCALING_TYPE_NTK 4          /**< NTK-aware: rope_freq_scale becomes a larger base */
+#define LLAMA_ROPE_SCALING_TYPE_DYNAMIC 5      /**< Dynamic NTK: a larger base only past the original context */
+///@}
+
+/** @name Flash attention types (llama_context_params::flash_attn_type) */
+///@{
+#define LLAMA_FLASH_ATTN_TYPE_AUTO -1    /**< Fused kernel where supported (always on CPU) */
+#define LLAMA_FLASH_ATTN_TYPE_DISABLED 0 /**< Naive kernel: full scores, then softmax */
+#define LLAMA_FLASH_ATTN_TYPE_ENABLED 1  /**< Fused tiled kernel with online softmax */
+///@}
 
 /**
  * @brief CPU Information structure
@@ -173,9 +205,9 @@ typedef struct common_params {
   const char *input_suffix;           ///< Input suffix string
   int antiprompt_count;               ///< Number of antiprompts
   bool escape;                        ///< Enable escape sequence processing
-  bool prompt_cache_all;              ///< Cache all prompts
-  bool prompt_cache_ro;               ///< Read-only prompt cache
-  const char *path_prompt_cache;      ///< Path to prompt cache file
+  bool prompt_cache_all;              ///< Also save generated tokens to the prompt cache
+  bool prompt_cache_ro;               ///< Load the prompt cache but never write it
+  const char *path_prompt_cache;      ///< Session file restored at startup (NULL/empty: none)
   bool special;                       ///< Enable special token processing
   const char *default_template_kwargs; ///< Default template keyword arguments
   bool use_jinja;                     ///< Use Jinja template engine
@@ -236,13 +268,22 @@ typedef struct token_list {
 } token_list;
 
 /**
- * @brief Opaque LLaMA batch structure
+ * @brief LLaMA batch structure
  * 
- * Represents a batch of tokens for efficient parallel processing.
- * Used for batched inference operations to improve performance.
+ * Represents a batch of tokens for efficient parallel processing, laid out
+ * as in llama.cpp. A batch from llama_batch_init() has room for
+ * n_tokens_alloc tokens in each array; llama_batch_get_one() sets only
+ * token and leaves the other arrays NULL, which llama_decode() fills in
+ * with their defaults.
  */
 typedef struct llama_batch {
-  uint8_t _private[0];  ///< Private implementation data (do not access directly)
+  int32_t n_tokens;     ///< Number of tokens in the batch
+  llama_token *token;   ///< Token IDs
+  float *embd;          ///< Input embeddings instead of tokens (not supported by llama_decode)
+  int32_t *pos;         ///< Position of each token in its sequences
+  int32_t *n_seq_id;    ///< Number of sequences of each token
+  int32_t **seq_id;     ///< Sequences of each token; NULL after the last allocated token
+  int8_t *logits;       ///< Whether to output the logits of each token
 } llama_batch;
 
 /**
@@ -299,27 +340,53 @@ typedef union llama_model_kv_override_value {
   char val_str[128];    ///< String value (max 128 characters)
 } llama_model_kv_override_value;
 
+/**
+ * @brief Value type tags of llama_model_kv_override
+ */
+enum llama_model_kv_override_type {
+  LLAMA_KV_OVERRIDE_TYPE_INT   = 0,  ///< val_i64
+  LLAMA_KV_OVERRIDE_TYPE_FLOAT = 1,  ///< val_f64
+  LLAMA_KV_OVERRIDE_TYPE_BOOL  = 2,  ///< val_bool
+  LLAMA_KV_OVERRIDE_TYPE_STR   = 3,  ///< val_str
+};
+
 /**
  * @brief Model key-value override structure
  * 
  * Allows overriding specific model metadata key-value pairs.
  * Used for runtime customization of model behavior and parameters.
+ * The overrides replace the file's values before the hyperparameters are
+ * read; a tag that does not match the type the key is read as fails the load.
+ * The kv_overrides array ends with an entry whose key is empty.
  */
 typedef struct llama_model_kv_override {
   char key[128];                              ///< Key name (max 128 characters)
-  int tag;                                    ///< Value type tag
+  int tag;                                    ///< Value type tag (llama_model_kv_override_type)
   union llama_model_kv_override_value value;  ///< Override value
 } llama_model_kv_override;
 
+/**
+ * @brief CPU buffer types of llama_model_tensor_buft_override
+ */
+enum llama_buft_type {
+  LLAMA_BUFT_CPU        = 0,  ///< Anonymous host memory ("CPU")
+  LLAMA_BUFT_CPU_MAPPED = 1,  ///< Pages of the memory-mapped model file ("CPU_Mapped")
+  LLAMA_BUFT_CPU_REPACK = 2,  ///< Repacked quantized weights ("CPU_REPACK"; Q4_0/Q8_0 matrices)
+};
+
 /**
  * @brief Model tensor buffer type override
  * 
  * Allows overriding tensor buffer types for specific tensor patterns.
  * Used for optimizing memory layout and device placement.
+ * The pattern is a regular expression searched for in the tensor name
+ * (e.g. "ffn_.*_exps" for MoE expert weights); the first matching override
+ * wins. The array ends with an entry whose pattern is NULL. The placement of
+ * each tensor group is logged when the model is loaded.
  */
 typedef struct llama_model_tensor_buft_override {
   const char *pattern;  ///< Tensor name pattern to match
-  int buft_type;        ///< Buffer type to use for matching tensors
+  int buft_type;        ///< Buffer type to use for matching tensors (llama_buft_type)
 } llama_model_tensor_buft_override;
 /**
  * @brief LLaMA model parameters
@@ -358,17 +425,17 @@ typedef struct llama_context_params {
   int n_threads;       ///< Number of threads for computation
   int n_threads_batch; ///< Number of threads for batch processing
   bool embeddings;     ///< Enable embedding mode
-  int rope_scaling_type; ///< RoPE scaling type
-  float rope_freq_base; ///< RoPE frequency base
-  float rope_freq_scale;///< RoPE frequency scale
-  float yarn_ext_factor;///< Yarn extension factor
-  float yarn_attn_factor;///< Yarn attention factor
-  float yarn_beta_fast; ///< Yarn fast beta parameter
-  float yarn_beta_slow; ///< Yarn slow beta parameter
-  int yarn_orig_ctx;    ///< Original context size for Yarn
+  int rope_scaling_type; ///< RoPE scaling type (LLAMA_ROPE_SCALING_TYPE_*, -1 = model default)
+  float rope_freq_base; ///< RoPE frequency base (0 = model default)
+  float rope_freq_scale;///< RoPE frequency scale (0 = model default)
+  float yarn_ext_factor;///< YaRN extrapolation mix (negative = 1 with YaRN scaling, 0 otherwise)
+  float yarn_attn_factor;///< Magnitude scale of rotated values (negative = model default)
+  float yarn_beta_fast; ///< YaRN ramp end, in rotations over the original context
+  float yarn_beta_slow; ///< YaRN ramp start, in rotations over the original context
+  int yarn_orig_ctx;    ///< Original context size for YaRN and LongRoPE (0 = model default)
   int pooling_type;     ///< Pooling type
-  int attention_type;   ///< Attention type
-  int flash_attn_type;  ///< Flash attention type
+  int attention_type;   ///< Attention type (LLAMA_ATTENTION_TYPE_*)
+  int flash_attn_type;  ///< Flash attention type (LLAMA_FLASH_ATTN_TYPE_*, default auto)
   void (*cb_eval)(void);///< Evaluation callback function
   void *cb_eval_user_data; ///< User data for evaluation callback
   bool offload_kqv;     ///< Enable offloading of KQV tensors
@@ -376,8 +443,8 @@ typedef struct llama_context_params {
   bool op_offload;      ///< Enable operator offloading
   bool swa_full;        ///< Enable full SWA (Stochastic Weight Averaging)
   bool kv_unified;      ///< Use unified KV cache
-  int type_k;           ///< Data type for K tensor
-  int type_v;           ///< Data type for V tensor
+  int type_k;           ///< KV cache type for K (ggml_type: 0 = f32, 1 = f16, 2 = q4_0, 8 = q8_0)
+  int type_v;           ///< KV cache type for V (ggml_type: 0 = f32, 1 = f16, 2 = q4_0, 8 = q8_0)
 } llama_context_params;
 
 /**
@@ -394,6 +461,14 @@ typedef struct lora_adapter {
   const char *prompt_prefix; ///< Prefix to add to prompts when using this adapter
 } lora_adapter;
 
+/**
+ * @brief Control vector file to load and its strength
+ */
+typedef struct common_control_vector_load_info {
+  float strength;           ///< Factor the file's directions are added with
+  const char *fname;        ///< Path to the control vector GGUF file
+} common_control_vector_load_info;
+
 /** @brief Null token constant representing an invalid or empty token */
 #define LLAMA_TOKEN_NULL -1
 
@@ -669,10 +744,11 @@ const struct llama_vocab *llama_model_get_vocab(struct llama_model *_model);
 /**
  * @brief Get context memory handle
  * 
- * Retrieves the memory management handle for the given context.
+ * Retrieves the memory management handle for the given context. The
+ * handle is owned by the context and freed with it.
  * 
  * @param[in] _ctx LLaMA context to query
- * @return Pointer to memory handle
+ * @return Pointer to memory handle, or NULL if the context has no memory
  */
 void *llama_get_memory(struct llama_context *_ctx);
 
@@ -696,6 +772,59 @@ int llama_model_n_ctx_train(struct llama_model *_model);
  */
 int llama_n_ctx(struct llama_context *_ctx);
 
+/**
+ * @brief Switch a context between embeddings and logits
+ * 
+ * @param[in] ctx LLaMA context to update
+ * @param[in] embeddings true to extract embeddings on the next decode
+ */
+void llama_set_embeddings(struct llama_context *ctx, bool embeddings);
+
+/**
+ * @brief Switch a context between causal and non-causal attention
+ * 
+ * Overrides the attention type the context was created with. Non-causal
+ * decodes need the whole batch within n_ubatch tokens.
+ * 
+ * @param[in] ctx LLaMA context to update
+ * @param[in] causal_attn true for causal attention on the next decode
+ */
+void llama_set_causal_attn(struct llama_context *ctx, bool causal_attn);
+
+/**
+ * @brief Get the logits of a token of the last decoded batch
+ * 
+ * @param[in] ctx LLaMA context to query
+ * @param[in] i Token index in the batch; negative values count back from the last output
+ * @return n_vocab logits owned by the context, or NULL if the token has no output
+ */
+float *llama_get_logits_ith(struct llama_context *ctx, int32_t i);
+
+/**
+ * @brief Get the embedding of a token of the last decoded batch
+ * 
+ * Only available when the context extracts embeddings without pooling
+ * (LLAMA_POOLING_TYPE_NONE).
+ * 
+ * @param[in] ctx LLaMA context to query
+ * @param[in] i Token index in the batch; negative values count back from the last output
+ * @return n_embd values owned by the context, or NULL if the token has no output
+ */
+float *llama_get_embeddings_ith(struct llama_context *ctx, int32_t i);
+
+/**
+ * @brief Get the pooled embedding of a sequence of the last decoded batch
+ * 
+ * Available when the context extracts embeddings with mean, CLS, last or
+ * rank pooling. For rank pooling the values are the classification scores.
+ * The embedding is not normalized.
+ * 
+ * @param[in] ctx LLaMA context to query
+ * @param[in] seq_id Sequence to query
+ * @return Values owned by the context, or NULL if the sequence was not in the batch
+ */
+float *llama_get_embeddings_seq(struct llama_context *ctx, int seq_id);
+
 /**
  * @brief Check if model has encoder
  * 
@@ -1020,23 +1149,28 @@ void common_sampler_reset(struct common_sampler *_s);
  * Processes a batch of tokens through the model encoder, updating
  * the model's internal state with the encoded representations.
  * 
- * @param[in] _ctx LLaMA context containing model state
- * @param[in] _batch Batch of tokens to encode
- * @return 0 on success, negative value on error
+ * @param[in] ctx LLaMA context containing model state
+ * @param[in] batch Batch of tokens to encode
+ * @return 0 on success, 1 if the memory cannot store the batch,
+ *         -1 for an invalid batch, -2 if the evaluation fails
  */
-int llama_encode(struct llama_context *_ctx, struct llama_batch _batch);
+int llama_encode(struct llama_context *ctx, struct llama_batch batch);
 
 /**
  * @brief Decode tokens and generate logits
  * 
  * Processes a batch of tokens through the model decoder to generate
- * output logits for next token prediction.
+ * output logits for next token prediction. Tokens of a batch without
+ * seq_id belong to sequence 0, without pos they continue their sequence,
+ * and without logits only the last token outputs (every token when the
+ * context computes embeddings).
  * 
- * @param[in] _ctx LLaMA context containing model state
- * @param[in] _batch Batch of tokens to decode
- * @return 0 on success, negative value on error
+ * @param[in] ctx LLaMA context containing model state
+ * @param[in] batch Batch of tokens to decode
+ * @return 0 on success, 1 if the memory cannot store the batch,
+ *         -1 for an invalid batch, -2 if the evaluation fails
  */
-int llama_decode(struct llama_context *_ctx, struct llama_batch _batch);
+int llama_decode(struct llama_context *ctx, struct llama_batch batch);
 
 /**
  * @brief Create batch from token array
@@ -1044,11 +1178,28 @@ int llama_decode(struct llama_context *_ctx, struct llama_batch _batch);
  * Creates a simple batch containing a sequence of tokens.
  * Useful for processing single sequences through the model.
  * 
- * @param[in] _data Array of token IDs
- * @param[in] _n Number of tokens in the array
+ * @param[in] data Array of token IDs; must outlive the batch
+ * @param[in] n Number of tokens in the array
  * @return Batch structure containing the tokens
  */
-struct llama_batch llama_batch_get_one(const llama_token *_data, int _n);
+struct llama_batch llama_batch_get_one(const llama_token *data, int n);
+
+/**
+ * @brief Allocate a batch
+ * 
+ * @param[in] n_tokens_alloc Maximum number of tokens
+ * @param[in] embd Width of input embeddings, or 0 to allocate token IDs
+ * @param[in] n_seq_max Maximum number of sequences per token
+ * @return Empty batch; release it with llama_batch_free()
+ */
+struct llama_batch llama_batch_init(int n_tokens_alloc, int embd, int n_seq_max);
+
+/**
+ * @brief Free a batch allocated with llama_batch_init()
+ * 
+ * @param[in] batch Batch to free
+ */
+void llama_batch_free(struct llama_batch batch);
 
 ///@}
 ///@name State Management Functions
@@ -1060,13 +1211,17 @@ struct llama_batch llama_batch_get_one(const llama_token *_data, int _n);
  * Restores the model's internal state from a previously saved file,
  * including KV cache and token history. Enables resuming conversations
  * or continuing from specific checkpoints.
+ *
+ * Session files start with a magic number, a format version and a model
+ * fingerprint; files of another version or saved for a different model
+ * are rejected.
  * 
  * @param[in] _ctx LLaMA context to load state into
  * @param[in] _path Path to the state file
  * @param[out] _out_tokens Buffer to store loaded tokens
  * @param[in] _capacity Maximum number of tokens the buffer can hold
  * @param[out] out_count Number of tokens actually loaded
- * @return true on successful load, false on error
+ * @return true on successful load, false on error or mismatch
  */
 bool llama_state_load_file(struct llama_context *_ctx,
                            const char *_path,
@@ -1092,6 +1247,82 @@ bool llama_state_save_file(struct llama_context *_ctx,
                            const llama_token *_tokens,
                            uintptr_t _count);
 
+/**
+ * @brief Get the size of the context state
+ * 
+ * Returns the number of bytes needed to hold the whole context state:
+ * the KV cache cells of every sequence and the state of each sequence's
+ * sampler chain (RNG, grammar position). Use it to size the buffer passed
+ * to llama_state_get_data().
+ * 
+ * @param[in] ctx LLaMA context to query
+ * @return State size in bytes, or 0 on error
+ */
+uintptr_t llama_state_get_size(struct llama_context *ctx);
+
+/**
+ * @brief Copy the context state into a buffer
+ * 
+ * The state starts with a magic number, a format version and a model
+ * fingerprint, so it can only be restored into a context of the same model.
+ * 
+ * @param[in] ctx LLaMA context to save state from
+ * @param[out] dst Destination buffer
+ * @param[in] size Size of the destination buffer in bytes
+ * @return Number of bytes written, or 0 if the buffer is too small or on error
+ */
+uintptr_t llama_state_get_data(struct llama_context *ctx, uint8_t *dst, uintptr_t size);
+
+/**
+ * @brief Restore the context state from a buffer
+ * 
+ * Replaces the KV cache contents and the sampler state with a state copied
+ * by llama_state_get_data(). State of a different model is rejected.
+ * 
+ * @param[in] ctx LLaMA context to load state into
+ * @param[in] src Buffer holding the state
+ * @param[in] size Size of the buffer in bytes
+ * @return Number of bytes read, or 0 on error
+ */
+uintptr_t llama_state_set_data(struct llama_context *ctx, const uint8_t *src, uintptr_t size);
+
+/**
+ * @brief Get the size of a sequence's state
+ * 
+ * @param[in] ctx LLaMA context to query
+ * @param[in] seq_id Sequence to measure
+ * @return State size in bytes, or 0 on error
+ */
+uintptr_t llama_state_seq_get_size(struct llama_context *ctx, int seq_id);
+
+/**
+ * @brief Copy the state of one sequence into a buffer
+ * 
+ * Copies the KV cache cells of the sequence and the state of its sampler
+ * chain, if it has one.
+ * 
+ * @param[in] ctx LLaMA context to save state from
+ * @param[out] dst Destination buffer
+ * @param[in] size Size of the destination buffer in bytes
+ * @param[in] seq_id Sequence to save
+ * @return Number of bytes written, or 0 if the buffer is too small or on error
+ */
+uintptr_t llama_state_seq_get_data(struct llama_context *ctx, uint8_t *dst, uintptr_t size, int seq_id);
+
+/**
+ * @brief Restore the state of one sequence from a buffer
+ * 
+ * Replaces the cells of the sequence with a state copied by
+ * llama_state_seq_get_data(), possibly from another sequence.
+ * 
+ * @param[in] ctx LLaMA context to load state into
+ * @param[in] src Buffer holding the state
+ * @param[in] size Size of the buffer in bytes
+ * @param[in] seq_id Sequence to restore into
+ * @return Number of bytes read, or 0 on error
+ */
+uintptr_t llama_state_seq_set_data(struct llama_context *ctx, const uint8_t *src, uintptr_t size, int seq_id);
+
 ///@}
 ///@name Memory Sequence Management Functions
 ///@{
@@ -1106,8 +1337,33 @@ bool llama_state_save_file(struct llama_context *_ctx,
  * @param[in] _seq_id Sequence ID to modify
  * @param[in] _p0 Start position (inclusive)
  * @param[in] _p1 End position (exclusive)
+ * @return true on success, false if the sequence ID is invalid or no memory is attached
+ */
+bool llama_memory_seq_rm(void *_mem, int _seq_id, uintptr_t _p0, int _p1);
+
+/**
+ * @brief Copy memory sequence range
+ * 
+ * Makes the cells of the source sequence within the position range also
+ * belong to the destination sequence. The cells are shared, not duplicated.
+ * 
+ * @param[in] _mem Memory handle from llama_get_memory()
+ * @param[in] _seq_id_src Source sequence ID
+ * @param[in] _seq_id_dst Destination sequence ID
+ * @param[in] _p0 Start position (inclusive, negative for 0)
+ * @param[in] _p1 End position (exclusive, negative for no limit)
+ */
+void llama_memory_seq_cp(void *_mem, int _seq_id_src, int _seq_id_dst, int _p0, int _p1);
+
+/**
+ * @brief Keep a single memory sequence
+ * 
+ * Removes every sequence except the given one.
+ * 
+ * @param[in] _mem Memory handle from llama_get_memory()
+ * @param[in] _seq_id Sequence ID to keep
  */
-void llama_memory_seq_rm(void *_mem, int _seq_id, uintptr_t _p0, int _p1);
+void llama_memory_seq_keep(void *_mem, int _seq_id);
 
 /**
  * @brief Add offset to memory sequence range
@@ -1137,6 +1393,24 @@ void llama_memory_seq_add(void *_mem, int _seq_id, uintptr_t _p0, int _p1, int _
  */
 void llama_memory_seq_div(void *_mem, int _seq_id, uintptr_t _p0, uintptr_t _p1, int _div);
 
+/**
+ * @brief Get smallest position of a memory sequence
+ * 
+ * @param[in] _mem Memory handle from llama_get_memory()
+ * @param[in] _seq_id Sequence ID to query
+ * @return Smallest position, or -1 if the sequence is empty
+ */
+int llama_memory_seq_pos_min(void *_mem, int _seq_id);
+
+/**
+ * @brief Get largest position of a memory sequence
+ * 
+ * @param[in] _mem Memory handle from llama_get_memory()
+ * @param[in] _seq_id Sequence ID to query
+ * @return Largest position, or -1 if the sequence is empty
+ */
+int llama_memory_seq_pos_max(void *_mem, int _seq_id);
+
 ///@}
 ///@name GGML Backend Management Functions
 ///@{
@@ -1545,10 +1819,12 @@ int llama_vocab_n_tokens(const struct llama_vocab *vocab);
 /**
  * @brief Get context pooling type
  * 
- * Returns the pooling method used by the context for sequence processing.
+ * Returns the pooling method used by the context for sequence processing:
+ * the context's pooling_type, or the model's default when it was created
+ * with LLAMA_POOLING_TYPE_UNSPECIFIED.
  * 
  * @param[in] ctx LLaMA context to query
- * @return Pooling type identifier
+ * @return One of the LLAMA_POOLING_TYPE_* values
  */
 int llama_pooling_type(struct llama_context *ctx);
 
@@ -1619,12 +1895,25 @@ void llama_set_warmup(struct llama_context *ctx, bool warmup);
  * Loads and initializes a LoRA (Low-Rank Adaptation) adapter for the model.
  * LoRA adapters allow fine-tuning without modifying the base model weights.
  * 
+ * The file must be a GGUF adapter (general.type "adapter", adapter.type
+ * "lora") for the model's architecture, and every lora_a/lora_b pair must
+ * match the shape of the base weight it patches.
+ *
  * @param[in] model LLaMA model to attach adapter to
  * @param[in] path Path to the LoRA adapter file
  * @return Pointer to initialized adapter, or NULL on error
  */
 void *llama_adapter_lora_init(struct llama_model *model, const char *path);
 
+/**
+ * @brief Free a LoRA adapter handle
+ *
+ * Contexts that enabled the adapter keep using it until they disable it.
+ *
+ * @param[in] adapter Adapter handle from llama_adapter_lora_init()
+ */
+void llama_adapter_lora_free(void *adapter);
+
 /**
  * @brief Get adapter metadata value as string
  * 
@@ -1635,20 +1924,53 @@ void *llama_adapter_lora_init(struct llama_model *model, const char *path);
  * @param[in] key Metadata key to retrieve
  * @param[out] buf Buffer to store the string value
  * @param[in] buf_size Size of the output buffer
- * @return Length of the retrieved string, or negative on error
+ * @return Length of the full value (the copy is truncated to buf_size - 1
+ *         characters), or -1 if the key is missing
  */
 int llama_adapter_meta_val_str(void *adapter, const char *key, char *buf, uintptr_t buf_size);
 
+/**
+ * @brief Enable a LoRA adapter on a context
+ *
+ * Enables the adapter with the given scale, or changes its scale if it is
+ * already enabled. Takes effect from the next decode; the base model is not
+ * reloaded. Several adapters can be enabled at once.
+ *
+ * @param[in] ctx LLaMA context
+ * @param[in] adapter Adapter handle from llama_adapter_lora_init()
+ * @param[in] scale Scale of the adapter's delta
+ * @return 0 on success, -1 on error
+ */
+int llama_set_adapter_lora(struct llama_context *ctx, void *adapter, float scale);
+
+/**
+ * @brief Disable a LoRA adapter on a context
+ *
+ * @param[in] ctx LLaMA context
+ * @param[in] adapter Adapter handle from llama_adapter_lora_init()
+ * @return 0 on success, -1 if the adapter was not enabled
+ */
+int llama_rm_adapter_lora(struct llama_context *ctx, void *adapter);
+
+/**
+ * @brief Disable all LoRA adapters on a context
+ *
+ * @param[in] ctx LLaMA context
+ */
+void llama_clear_adapter_lora(struct llama_context *ctx);
+
 /**
  * @brief Apply control vector to context
  * 
- * Applies a control vector to influence model behavior across specified layers.
- * Control vectors can be used to steer model outputs in desired directions.
+ * Adds a per-layer direction to the residual stream (the output of each
+ * layer) of every token, for the layers layer_start..layer_end. Replaces the
+ * context's previous control vector; the model is not reloaded.
  * 
  * @param[in] ctx LLaMA context to apply vector to
- * @param[in] data Control vector data array
+ * @param[in] data n_embd values per layer starting at layer 1, or NULL to
+ *                 clear the control vector
  * @param[in] len Length of the control vector data
- * @param[in] n_embd Embedding dimension size
+ * @param[in] n_embd Embedding dimension size; must match the model
  * @param[in] layer_start First layer to apply the vector to
  * @param[in] layer_end Last layer to apply the vector to
  * @return 0 on success, negative on error
@@ -1710,6 +2032,43 @@ struct llama_context_params common_context_params_to_llama(const struct common_p
  */
 struct common_init_result common_init_from_params_enhanced(const struct common_params *params);
 
+/**
+ * @brief Reuse the restored prompt cache session
+ * 
+ * Matches the prompt against the session restored from path_prompt_cache
+ * at initialization and removes the KV cells past the matching prefix.
+ * The last prompt token is always evaluated again.
+ * 
+ * @param[in] ctx Context created by common_init_from_params_enhanced()
+ * @param[in] tokens Prompt tokens
+ * @param[in] n_tokens Number of prompt tokens
+ * @return Number of leading prompt tokens that need not be evaluated
+ */
+uintptr_t common_prompt_cache_reuse(struct llama_context *ctx,
+                                    const llama_token *tokens,
+                                    uintptr_t n_tokens);
+
+/**
+ * @brief Save the prompt cache session
+ * 
+ * Call once the prompt has been evaluated, and again with the prompt and
+ * generated tokens at the end of the run (after_generation), which only
+ * writes when prompt_cache_all is set. Nothing is written when
+ * prompt_cache_ro is set.
+ * 
+ * @param[in] ctx Context to save the KV cells of
+ * @param[in] params Common parameters with the prompt cache settings
+ * @param[in] tokens Tokens to save with the session
+ * @param[in] n_tokens Number of tokens
+ * @param[in] after_generation Whether the run has finished generating
+ * @return true if the session file was written
+ */
+bool common_prompt_cache_save(struct llama_context *ctx,
+                              const struct common_params *params,
+                              const llama_token *tokens,
+                              uintptr_t n_tokens,
+                              bool after_generation);
+
 ///@}
 ///@name Batch Processing Functions
 ///@{
@@ -1717,8 +2076,7 @@ struct common_init_result common_init_from_params_enhanced(const struct common_p
 /**
  * @brief Clear batch contents
  * 
- * Resets a batch structure to empty state, clearing all tokens
- * and associated metadata.
+ * Resets a batch structure to empty state by setting n_tokens to 0.
  * 
  * @param[in,out] batch Batch structure to clear
  */
@@ -1729,6 +2087,9 @@ void common_batch_clear(struct llama_batch *batch);
  * 
  * Adds a token with associated metadata to a batch for processing.
  * Supports multi-sequence batching with position and logit control.
+ * The batch must come from llama_batch_init(); a token that does not fit,
+ * because the batch is full or it has more than n_seq_max sequences, is
+ * left out.
  * 
  * @param[in,out] batch Batch to add token to
  * @param[in] id Token ID to add
@@ -1761,8 +2122,8 @@ const char *get_model_endpoint(void);
 /**
  * @brief Set LoRA adapters for context
  * 
- * Applies multiple LoRA adapters to a context for multi-task fine-tuning.
- * Allows combining different adapters for complex model behavior.
+ * Replaces the adapters enabled on the context by the given ones. Adapters
+ * with a scale of 0 are left disabled.
  * 
  * @param[in] ctx LLaMA context to apply adapters to
  * @param[in] adapters Array of LoRA adapter configurations
@@ -1775,14 +2136,43 @@ void common_set_adapter_lora(struct llama_context *ctx,
 /**
  * @brief Load control vectors from files
  * 
- * Loads control vectors from multiple files for behavior steering.
- * Control vectors can influence model outputs in specific directions.
+ * Loads control vector GGUF files (one direction.<layer> tensor per layer)
+ * and sums them, each multiplied by its strength. All files must have the
+ * same n_embd.
  * 
- * @param[in] file_paths Array of file paths containing control vectors
- * @param[in] count Number of file paths in the array
- * @return Handle to loaded control vectors, or NULL on error
+ * @param[in] load_infos Files to load with their strengths
+ * @param[in] count Number of entries in load_infos
+ * @return Handle to the summed control vector, or NULL on error
+ */
+void *common_control_vector_load(const struct common_control_vector_load_info *load_infos,
+                                 uintptr_t count);
+
+/**
+ * @brief Embedding size of a loaded control vector
+ *
+ * @param[in] cvec Handle from common_control_vector_load()
+ * @return n_embd, or -1 for a NULL handle
+ */
+int common_control_vector_n_embd(void *cvec);
+
+/**
+ * @brief Data of a loaded control vector
+ *
+ * The layout is the one expected by llama_apply_adapter_cvec(): n_embd
+ * values per layer, starting at layer 1.
+ *
+ * @param[in] cvec Handle from common_control_vector_load()
+ * @param[out] len Number of values
+ * @return Pointer to the values, valid until common_control_vector_free()
  */
-void *common_control_vector_load(const char *const *file_paths, uintptr_t count);
+const float *common_control_vector_data(void *cvec, uintptr_t *len);
+
+/**
+ * @brief Free a control vector loaded by common_control_vector_load()
+ *
+ * @param[in] cvec Handle to free
+ */
+void common_control_vector_free(void *cvec);
 
 ///@}
 ///@name Dynamic Model Management Functions
@@ -1944,6 +2334,22 @@ int rust_generate_and_validate_config(void);
  */
 int rust_run_llm_engine(const char *config_path);
 
+/**
+ * @brief Benchmark prompt processing with and without weight repacking
+ * 
+ * Runs a synthetic LLaMA-style layer stack with quantized weights, once in
+ * file layout and once repacked into the interleaved layout of this CPU,
+ * and prints the tokens per second of each run as a markdown table.
+ * 
+ * Options: -p N[,N...] prompt sizes, -r N repetitions, --type q4_0,q8_0,
+ * --repack 0,1, --n-embd N, --n-ff N, --n-layer N
+ * 
+ * @param[in] argc Number of benchmark arguments
+ * @param[in] argv Benchmark arguments, without the program and subcommand
+ * @return 0 on success, 1 on invalid arguments or a failed run
+ */
+int rust_llama_bench(int argc, const char *const *argv);
+
 
 #ifdef __cplusplus
 }  // extern "C"
@@ -96,6 +96,7 @@ int main(int argc, char* argv[]) {
         LLMRC_PRINT_I("LLM Subcommands:");
         LLMRC_PRINT_I("  llm run              Start HTTP API server for LLM inference (default)");
         LLMRC_PRINT_I("  llm list             List all available GGUF models");
+        LLMRC_PRINT_I("  llm bench            Benchmark prompt processing with repacked weights");
         LLMRC_PRINT_I("  llm config_gen       Generate and validate model configuration");
         LLMRC_PRINT_I("  llm config_validate  Validate existing model configuration");
         LLMRC_PRINT_I("  llm config_show      Show current model configuration");
@@ -241,6 +242,12 @@ int main(int argc, char* argv[]) {
         }
 
 
+        else if (subcommand == "bench") {
+            rs_log_info("Prompt Processing Benchmark (via LLM command)");
+            return rust_llama_bench(argc - 3, argv + 3);
+        }
+
+
         else if (subcommand == "config_gen") {
             rs_log_info("Generating Dynamic Model Configuration (via LLM command)");
             int result = generate_model_config();
@@ -310,6 +317,8 @@ int main(int argc, char* argv[]) {
             LLMRC_PRINT_I("Subcommands:");
             LLMRC_PRINT_I("  run              Start HTTP API server for LLM inference (default)");
             LLMRC_PRINT_I("  list             List all available GGUF models");
+            LLMRC_PRINT_I("  bench            Benchmark prompt processing with and without repacked weights");
+            LLMRC_PRINT_I("                   Options: -p 512,128 -r 5 --type q4_0,q8_0 --repack 0,1");
             LLMRC_PRINT_I("  config_gen       Generate and validate model configuration");
             LLMRC_PRINT_I("  config_validate  Validate existing model configuration");
             LLMRC_PRINT_I("                   Optional: specify config file path as next argument");
@@ -15,7 +15,9 @@
 #![allow(unused_variables)]
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
+#![allow(clippy::missing_safety_doc)]
 
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{CStr, CString};
 use std::io::{self, Read, Write};
 use std::{mem, slice};
@@ -23,6 +25,14 @@ use std::os::raw::{c_char, c_int, c_uint, c_void};
 use std::path::Path;
 use std::ptr::{self, null, null_mut};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaUbatch};
+use crate::llmrust::src::llama_context as llama_context_handle;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::LlamaPoolingType;
+use crate::llmrust::src::llama_kv_cache::LlamaKvUbatchToken;
+use crate::llmrust::src::llama_memory::{self, LlamaPos};
 
 #[cfg(any(unix, all(target_os = "macos", target_family = "unix")))]
 use libc::{signal, sigaction, sighandler_t, SIGINT};
@@ -171,14 +181,27 @@ pub struct token_list {
     pub len: usize,
 }
 
+/// Tokens of a decode call, laid out as in llama.cpp. A batch from
+/// `llama_batch_init` has room for `n_tokens_alloc` tokens in each array;
+/// `llama_batch_get_one` sets only `token` and leaves the other arrays null,
+/// which `llama_decode` fills in with their defaults.
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct llama_batch {
-    _private: [u8; 0],
+    pub n_tokens: i32,
+    pub token: *mut llama_token,
+    /// Input embeddings instead of tokens; not supported by `llama_decode`
+    pub embd: *mut f32,
+    pub pos: *mut LlamaPos,
+    /// Number of sequences of each token
+    pub n_seq_id: *mut i32,
+    /// Sequences of each token, `n_seq_max` entries each; the pointer after
+    /// the last allocated token is null
+    pub seq_id: *mut *mut i32,
+    /// Whether to output the logits of each token
+    pub logits: *mut i8,
 }
 
-// Mock implementations of llama.cpp functions
-// All external C functions are now implemented as Rust mock functions
-
 // Logging functions - Mock implementations
 #[no_mangle]
 pub extern "C" fn LOG(_fmt: *const c_char) { /* Mock LOG */ }
@@ -274,11 +297,47 @@ pub extern "C" fn llama_numa_init(_mode: c_int) {
 #[no_mangle]
 pub extern "C" fn llama_model_get_vocab(_model: *mut llama_model) -> *const llama_vocab { null() }
 #[no_mangle]
-pub extern "C" fn llama_get_memory(_ctx: *mut llama_context) -> *mut c_void { null_mut() }
+pub extern "C" fn llama_get_memory(ctx: *mut llama_context) -> *mut c_void {
+    unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }
+        .and_then(|ctx| ctx.memory_mut())
+        .map_or(null_mut(), |memory| memory as *mut llama_memory::LlamaMemoryHandle as *mut c_void)
+}
+#[no_mangle]
+pub extern "C" fn llama_model_n_ctx_train(model: *mut llama_model) -> c_int {
+    unsafe { crate::llmrust::src::llama_model::from_handle(model as *mut c_void) }.map_or(0, |model| model.hparams().n_ctx_train as c_int)
+}
+#[no_mangle]
+pub extern "C" fn llama_n_ctx(ctx: *mut llama_context) -> c_int {
+    unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }.map_or(4096, |ctx| ctx.n_ctx() as c_int)
+}
+/// Output row of a context as a C pointer; null when there is none.
+fn output_ptr(row: Option<&[f32]>) -> *mut f32 {
+    row.map_or(null_mut(), |row| row.as_ptr() as *mut f32)
+}
+#[no_mangle]
+pub extern "C" fn llama_set_embeddings(ctx: *mut llama_context, embeddings: bool) {
+    if let Some(ctx) = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        ctx.set_embeddings(embeddings);
+    }
+}
 #[no_mangle]
-pub extern "C" fn llama_model_n_ctx_train(_model: *mut llama_model) -> c_int { 4096 }
+pub extern "C" fn llama_set_causal_attn(ctx: *mut llama_context, causal_attn: bool) {
+    if let Some(ctx) = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        ctx.set_causal_attn(causal_attn);
+    }
+}
 #[no_mangle]
-pub extern "C" fn llama_n_ctx(_ctx: *mut llama_context) -> c_int { 4096 }
+pub extern "C" fn llama_get_logits_ith(ctx: *mut llama_context, i: i32) -> *mut f32 {
+    output_ptr(unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }.and_then(|ctx| ctx.get_logits_ith(i)))
+}
+#[no_mangle]
+pub extern "C" fn llama_get_embeddings_ith(ctx: *mut llama_context, i: i32) -> *mut f32 {
+    output_ptr(unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }.and_then(|ctx| ctx.get_embeddings_ith(i)))
+}
+#[no_mangle]
+pub extern "C" fn llama_get_embeddings_seq(ctx: *mut llama_context, seq_id: c_int) -> *mut f32 {
+    output_ptr(unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }.and_then(|ctx| ctx.get_embeddings_seq(seq_id)))
+}
 #[no_mangle]
 pub extern "C" fn llama_model_has_encoder(_model: *mut llama_model) -> bool { false }
 #[no_mangle]
@@ -364,36 +423,411 @@ pub extern "C" fn common_sampler_last(_s: *mut common_sampler) -> llama_token {
 #[no_mangle]
 pub extern "C" fn common_sampler_reset(_s: *mut common_sampler) { /* Mock */ }
 
-// Decoding / encoding - Mock implementations
+// Decoding / encoding
+// The library has no kernels for whole models yet, so C API contexts are
+// evaluated with a stand-in forward pass; batches, memory and outputs go
+// through `LlamaContext::decode` as they would for a real model.
+/// Tokens and sequences per token of the batches from `llama_batch_init`,
+/// by the address of their `seq_id` array; the C struct has no room for them
+static BATCH_SIZES: Mutex<BTreeMap<usize, (usize, usize)>> = Mutex::new(BTreeMap::new());
+
+/// Capacity of a batch from `llama_batch_init`: how many tokens it holds and
+/// how many sequences each token can belong to; `None` for other batches.
+pub(crate) fn batch_capacity(batch: &llama_batch) -> Option<(usize, usize)> {
+    BATCH_SIZES.lock().unwrap().get(&(batch.seq_id as usize)).copied()
+}
+#[no_mangle]
+pub extern "C" fn llama_batch_init(n_tokens_alloc: c_int, embd: c_int, n_seq_max: c_int) -> llama_batch {
+    let n = n_tokens_alloc.max(0) as usize;
+    let alloc = |size: usize| unsafe { libc::malloc(size.max(1)) };
+    let (token, embd) = if embd > 0 {
+        (null_mut(), alloc(n * embd as usize * mem::size_of::<f32>()) as *mut f32)
+    } else {
+        (alloc(n * mem::size_of::<llama_token>()) as *mut llama_token, null_mut())
+    };
+    let seq_id = alloc((n + 1) * mem::size_of::<*mut i32>()) as *mut *mut i32;
+    for i in 0..n {
+        unsafe { *seq_id.add(i) = alloc(n_seq_max.max(1) as usize * mem::size_of::<i32>()) as *mut i32 };
+    }
+    unsafe { *seq_id.add(n) = null_mut() };
+    BATCH_SIZES.lock().unwrap().insert(seq_id as usize, (n, n_seq_max.max(1) as usize));
+    llama_batch {
+        n_tokens: 0,
+        token,
+        embd,
+        pos: alloc(n * mem::size_of::<LlamaPos>()) as *mut LlamaPos,
+        n_seq_id: alloc(n * mem::size_of::<i32>()) as *mut i32,
+        seq_id,
+        logits: alloc(n) as *mut i8,
+    }
+}
+/// Releases the arrays of a batch from `llama_batch_init`.
 #[no_mangle]
-pub extern "C" fn llama_encode(_ctx: *mut llama_context, _batch: llama_batch) -> c_int { 0 }
+pub extern "C" fn llama_batch_free(batch: llama_batch) {
+    BATCH_SIZES.lock().unwrap().remove(&(batch.seq_id as usize));
+    unsafe {
+        if !batch.seq_id.is_null() {
+            let mut i = 0;
+            while !(*batch.seq_id.add(i)).is_null() {
+                libc::free(*batch.seq_id.add(i) as *mut c_void);
+                i += 1;
+            }
+        }
+        for array in [
+            batch.token as *mut c_void,
+            batch.embd as *mut c_void,
+            batch.pos as *mut c_void,
+            batch.n_seq_id as *mut c_void,
+            batch.seq_id as *mut c_void,
+            batch.logits as *mut c_void,
+        ] {
+            libc::free(array);
+        }
+    }
+}
+/// A batch over `n` tokens of `data`, which must outlive it. The tokens
+/// continue sequence 0 and only the last one outputs.
+#[no_mangle]
+pub extern "C" fn llama_batch_get_one(data: *const llama_token, n: c_int) -> llama_batch {
+    llama_batch {
+        n_tokens: n,
+        token: data as *mut llama_token,
+        embd: null_mut(),
+        pos: null_mut(),
+        n_seq_id: null_mut(),
+        seq_id: null_mut(),
+        logits: null_mut(),
+    }
+}
+
+/// Copies a C batch, filling in the arrays left null: tokens belong to
+/// sequence 0, positions continue each sequence after the cells of `memory`,
+/// and only the last token outputs unless `output_all` is set.
+///
+/// # Safety
+/// The non-null arrays of `batch` must hold `n_tokens` entries.
+unsafe fn batch_from_c(
+    batch: &llama_batch,
+    memory: Option<&dyn llama_memory::LlamaMemory>,
+    output_all: bool,
+) -> Result<LlamaBatch, String> {
+    if !batch.embd.is_null() {
+        return Err("batches of input embeddings are not supported".to_string());
+    }
+    if batch.token.is_null() || batch.n_tokens < 0 {
+        return Err(format!("invalid batch of {} tokens", batch.n_tokens));
+    }
+    let n = batch.n_tokens as usize;
+    let mut next_pos: HashMap<llama_memory::LlamaSeqId, LlamaPos> = HashMap::new();
+    let mut out = LlamaBatch::new();
+    for i in 0..n {
+        let seq_ids = if batch.seq_id.is_null() {
+            vec![0]
+        } else {
+            let n_seq_id = if batch.n_seq_id.is_null() { 1 } else { *batch.n_seq_id.add(i) };
+            slice::from_raw_parts(*batch.seq_id.add(i), n_seq_id.max(0) as usize).to_vec()
+        };
+        let pos = if batch.pos.is_null() {
+            let seq_id = seq_ids.first().copied().unwrap_or(0);
+            let pos = next_pos.entry(seq_id).or_insert_with(|| memory.map_or(-1, |m| m.seq_pos_max(seq_id)) + 1);
+            *pos += 1;
+            *pos - 1
+        } else {
+            *batch.pos.add(i)
+        };
+        let logits = if batch.logits.is_null() { output_all || i + 1 == n } else { *batch.logits.add(i) != 0 };
+        out.add(*batch.token.add(i), pos, &seq_ids, logits);
+    }
+    Ok(out)
+}
+
+/// Stand-in forward pass of the C API contexts, shaped by the model's
+/// hyperparameters: the logits strongly favour the token after the input one,
+/// and the hidden state of a token depends only on the token and its position
+struct CApiGraph {
+    n_vocab: usize,
+    n_embd: usize,
+    pooling_type: LlamaPoolingType,
+}
+
+impl LlamaGraph for CApiGraph {
+    fn n_vocab(&self) -> usize {
+        self.n_vocab
+    }
+
+    fn compute(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        if self.n_vocab == 0 {
+            return Err("the model has no vocabulary".to_string());
+        }
+        Ok(ubatch
+            .idxs
+            .iter()
+            .filter(|&&i| batch.logits[i])
+            .map(|&i| {
+                let mut logits = vec![0.0; self.n_vocab];
+                logits[(batch.token[i].max(0) as usize + 1) % self.n_vocab] = 10.0;
+                logits
+            })
+            .collect())
+    }
+
+    fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    fn pooling_type(&self) -> LlamaPoolingType {
+        self.pooling_type
+    }
+
+    fn embed(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        Ok(ubatch
+            .idxs
+            .iter()
+            .map(|&i| {
+                let (token, pos) = (batch.token[i] as f32, batch.pos[i] as f32);
+                (0..self.n_embd)
+                    .map(|d| (token * 0.618 + d as f32 * 1.37).sin() + 0.1 * ((pos + 1.0) * (d as f32 + 1.0) * 0.01).cos())
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// Evaluates a C batch: 0 on success, 1 when the memory cannot store it,
+/// -1 for an invalid batch and -2 when the evaluation fails, which leaves the
+/// memory as it was.
+fn decode_c_batch(ctx: *mut llama_context, batch: &llama_batch) -> c_int {
+    let Some(ctx) = (unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }) else {
+        rs_log_error(cstr("llama_decode: context is null").as_ptr());
+        return -1;
+    };
+    let batch = match unsafe { batch_from_c(batch, ctx.memory(), ctx.cparams().embeddings) }.and_then(|b| b.validate().map(|()| b)) {
+        Ok(batch) => batch,
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_decode: invalid batch: {}", e)).as_ptr());
+            return -1;
+        }
+    };
+    if let Some(memory) = ctx.memory_mut() {
+        let tokens: Vec<LlamaKvUbatchToken> = batch
+            .pos
+            .iter()
+            .zip(&batch.seq_id)
+            .map(|(&pos, seq_ids)| LlamaKvUbatchToken { pos, seq_ids: seq_ids.clone() })
+            .collect();
+        if let Err(e) = memory.add_ubatch(&tokens) {
+            rs_log_warn(cstr(&format!("llama_decode: {}", e)).as_ptr());
+            return 1;
+        }
+    }
+    let hparams = ctx.model_hparams().cloned().unwrap_or_else(super::model::mock_model_hparams);
+    let mut graph =
+        CApiGraph { n_vocab: hparams.n_vocab as usize, n_embd: hparams.n_embd as usize, pooling_type: hparams.pooling_type };
+    match ctx.decode(&batch, &mut graph) {
+        Ok(()) => 0,
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_decode: {}", e)).as_ptr());
+            // the batch produced no outputs, so its cells must not stay behind
+            if let Some(memory) = ctx.memory_mut() {
+                for (&pos, seq_ids) in batch.pos.iter().zip(&batch.seq_id) {
+                    for &seq_id in seq_ids {
+                        memory.seq_rm(seq_id, pos, pos + 1);
+                    }
+                }
+            }
+            -2
+        }
+    }
+}
 #[no_mangle]
-pub extern "C" fn llama_decode(_ctx: *mut llama_context, _batch: llama_batch) -> c_int { 0 }
+pub extern "C" fn llama_encode(ctx: *mut llama_context, batch: llama_batch) -> c_int {
+    decode_c_batch(ctx, &batch)
+}
 #[no_mangle]
-pub extern "C" fn llama_batch_get_one(_data: *const llama_token, _n: c_int) -> llama_batch {
-    llama_batch { _private: [0; 0] }
+pub extern "C" fn llama_decode(ctx: *mut llama_context, batch: llama_batch) -> c_int {
+    decode_c_batch(ctx, &batch)
 }
 
-// State save/load - Mock implementations
+// State save/load
+// Session files (see llmrust/src/llama_io.rs): the header and token list,
+// followed by the KV cells of sequence 0 when the context has memory. Files
+// written for a different model are rejected by the fingerprint check.
+fn session_fingerprint(ctx: *mut llama_context) -> u64 {
+    unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }
+        .map_or_else(|| super::model::mock_model_hparams().fingerprint(), |ctx| ctx.fingerprint())
+}
 #[no_mangle]
-pub extern "C" fn llama_state_load_file(_ctx: *mut llama_context, _path: *const c_char, _out_tokens: *mut llama_token, _capacity: usize, out_count: *mut usize) -> bool {
+pub extern "C" fn llama_state_load_file(ctx: *mut llama_context, path: *const c_char, out_tokens: *mut llama_token, capacity: usize, out_count: *mut usize) -> bool {
+    use crate::llmrust::src::llama_io::{llama_session_read_header, LlamaIoReadFile};
+
     unsafe { if !out_count.is_null() { *out_count = 0; } }
-    rs_log_info(cstr("Mock: llama_state_load_file called").as_ptr());
-    false
+    if path.is_null() || out_tokens.is_null() {
+        rs_log_error(cstr("llama_state_load_file: path or out_tokens is null").as_ptr());
+        return false;
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let fingerprint = session_fingerprint(ctx);
+    let result = LlamaIoReadFile::open(Path::new(&path)).and_then(|mut io| {
+        let tokens = llama_session_read_header(&mut io, fingerprint, capacity)?;
+        if let Some(memory) = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }.and_then(|ctx| ctx.memory_mut()) {
+            memory.state_read(&mut io, 0)?;
+        }
+        Ok(tokens)
+    });
+    match result {
+        Ok(tokens) => {
+            if !tokens.is_empty() {
+                unsafe { ptr::copy_nonoverlapping(tokens.as_ptr(), out_tokens, tokens.len()) };
+            }
+            unsafe { if !out_count.is_null() { *out_count = tokens.len(); } }
+            true
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_state_load_file: failed to load {}: {}", path, e)).as_ptr());
+            false
+        }
+    }
 }
 #[no_mangle]
-pub extern "C" fn llama_state_save_file(_ctx: *mut llama_context, _path: *const c_char, _tokens: *const llama_token, _count: usize) -> bool {
-    rs_log_info(cstr("Mock: llama_state_save_file called").as_ptr());
-    true
+pub extern "C" fn llama_state_save_file(ctx: *mut llama_context, path: *const c_char, tokens: *const llama_token, count: usize) -> bool {
+    use crate::llmrust::src::llama_io::{llama_session_write_header, LlamaIoWrite, LlamaIoWriteFile};
+
+    if path.is_null() || (tokens.is_null() && count > 0) {
+        rs_log_error(cstr("llama_state_save_file: path or tokens is null").as_ptr());
+        return false;
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let tokens = if count > 0 { unsafe { slice::from_raw_parts(tokens, count) } } else { &[] };
+    let fingerprint = session_fingerprint(ctx);
+    let result = LlamaIoWriteFile::create(Path::new(&path)).and_then(|mut io| {
+        llama_session_write_header(&mut io, fingerprint, tokens)?;
+        match unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }.and_then(|ctx| ctx.memory()) {
+            Some(memory) => memory.state_write(&mut io, 0)?,
+            // no KV cells
+            None => io.write_u32(0)?,
+        }
+        io.finish()
+    });
+    match result {
+        Ok(n_bytes) => {
+            rs_log_info(cstr(&format!("llama_state_save_file: saved {} tokens to {} ({} bytes)", tokens.len(), path, n_bytes)).as_ptr());
+            true
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_state_save_file: {}", e)).as_ptr());
+            false
+        }
+    }
 }
 
-// Memory (kv) ops - Mock implementations
+// Context state - copied to and from caller buffers (see llmrust/src/llama_context.rs).
+// Sizes and byte counts of 0 report an error, which is logged.
+fn state_result(name: &str, result: Result<usize, String>) -> usize {
+    result.unwrap_or_else(|e| {
+        rs_log_error(cstr(&format!("{}: {}", name, e)).as_ptr());
+        0
+    })
+}
 #[no_mangle]
-pub extern "C" fn llama_memory_seq_rm(_mem: *mut c_void, _seq_id: c_int, _p0: usize, _p1: c_int) { /* Mock */ }
+pub extern "C" fn llama_state_get_size(ctx: *mut llama_context) -> usize {
+    let result = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }
+        .ok_or_else(|| "context is null".to_string())
+        .and_then(|ctx| ctx.state_get_size());
+    state_result("llama_state_get_size", result)
+}
 #[no_mangle]
-pub extern "C" fn llama_memory_seq_add(_mem: *mut c_void, _seq_id: c_int, _p0: usize, _p1: c_int, _delta: c_int) { /* Mock */ }
+pub extern "C" fn llama_state_get_data(ctx: *mut llama_context, dst: *mut u8, size: usize) -> usize {
+    let result = match unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        Some(_) if dst.is_null() => Err("destination is null".to_string()),
+        Some(ctx) => ctx.state_get_data(unsafe { slice::from_raw_parts_mut(dst, size) }),
+        None => Err("context is null".to_string()),
+    };
+    state_result("llama_state_get_data", result)
+}
 #[no_mangle]
-pub extern "C" fn llama_memory_seq_div(_mem: *mut c_void, _seq_id: c_int, _p0: usize, _p1: usize, _div: c_int) { /* Mock */ }
+pub extern "C" fn llama_state_set_data(ctx: *mut llama_context, src: *const u8, size: usize) -> usize {
+    let result = match unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        Some(_) if src.is_null() => Err("source is null".to_string()),
+        Some(ctx) => ctx.state_set_data(unsafe { slice::from_raw_parts(src, size) }),
+        None => Err("context is null".to_string()),
+    };
+    state_result("llama_state_set_data", result)
+}
+#[no_mangle]
+pub extern "C" fn llama_state_seq_get_size(ctx: *mut llama_context, seq_id: c_int) -> usize {
+    let result = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }
+        .ok_or_else(|| "context is null".to_string())
+        .and_then(|ctx| ctx.state_seq_get_size(seq_id));
+    state_result("llama_state_seq_get_size", result)
+}
+#[no_mangle]
+pub extern "C" fn llama_state_seq_get_data(ctx: *mut llama_context, dst: *mut u8, size: usize, seq_id: c_int) -> usize {
+    let result = match unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        Some(_) if dst.is_null() => Err("destination is null".to_string()),
+        Some(ctx) => ctx.state_seq_get_data(unsafe { slice::from_raw_parts_mut(dst, size) }, seq_id),
+        None => Err("context is null".to_string()),
+    };
+    state_result("llama_state_seq_get_data", result)
+}
+#[no_mangle]
+pub extern "C" fn llama_state_seq_set_data(ctx: *mut llama_context, src: *const u8, size: usize, seq_id: c_int) -> usize {
+    let result = match unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        Some(_) if src.is_null() => Err("source is null".to_string()),
+        Some(ctx) => ctx.state_seq_set_data(unsafe { slice::from_raw_parts(src, size) }, seq_id),
+        None => Err("context is null".to_string()),
+    };
+    state_result("llama_state_seq_set_data", result)
+}
+
+// Memory (kv) ops - forwarded to the llama_memory handle (see llmrust/src/llama_kv_cache.rs).
+// Positions arrive as uintptr_t in some signatures; a value of -1 from C wraps
+// around and converts back to -1 ("open-ended").
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_rm(mem: *mut c_void, seq_id: c_int, p0: usize, p1: c_int) -> bool {
+    match unsafe { llama_memory::from_handle(mem) } {
+        Some(memory) => memory.seq_rm(seq_id, p0 as LlamaPos, p1),
+        None => {
+            rs_log_info(cstr("llama_memory_seq_rm: no memory attached").as_ptr());
+            false
+        }
+    }
+}
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_cp(mem: *mut c_void, seq_id_src: c_int, seq_id_dst: c_int, p0: c_int, p1: c_int) {
+    if let Some(memory) = unsafe { llama_memory::from_handle(mem) } {
+        memory.seq_cp(seq_id_src, seq_id_dst, p0, p1);
+    }
+}
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_keep(mem: *mut c_void, seq_id: c_int) {
+    if let Some(memory) = unsafe { llama_memory::from_handle(mem) } {
+        memory.seq_keep(seq_id);
+    }
+}
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_add(mem: *mut c_void, seq_id: c_int, p0: usize, p1: c_int, delta: c_int) {
+    match unsafe { llama_memory::from_handle(mem) } {
+        Some(memory) if memory.can_shift() => memory.seq_add(seq_id, p0 as LlamaPos, p1, delta),
+        Some(_) => rs_log_info(cstr("llama_memory_seq_add: memory does not support shifting").as_ptr()),
+        None => rs_log_info(cstr("llama_memory_seq_add: no memory attached").as_ptr()),
+    }
+}
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_div(mem: *mut c_void, seq_id: c_int, p0: usize, p1: usize, div: c_int) {
+    match unsafe { llama_memory::from_handle(mem) } {
+        Some(memory) if memory.can_shift() => memory.seq_div(seq_id, p0 as LlamaPos, p1 as LlamaPos, div),
+        Some(_) => rs_log_info(cstr("llama_memory_seq_div: memory does not support shifting").as_ptr()),
+        None => rs_log_info(cstr("llama_memory_seq_div: no memory attached").as_ptr()),
+    }
+}
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_pos_min(mem: *mut c_void, seq_id: c_int) -> c_int {
+    unsafe { llama_memory::from_handle(mem) }.map_or(-1, |memory| memory.seq_pos_min(seq_id))
+}
+#[no_mangle]
+pub extern "C" fn llama_memory_seq_pos_max(mem: *mut c_void, seq_id: c_int) -> c_int {
+    unsafe { llama_memory::from_handle(mem) }.map_or(-1, |memory| memory.seq_pos_max(seq_id))
+}
 
 // GGML backend & threadpool - Mock implementations
 #[no_mangle]
@@ -665,7 +1099,7 @@ pub extern "C" fn rust_entry(argc: i32, argv: *mut *mut std::os::raw::c_char) ->
         #[cfg(any(unix, all(target_os = "macos", target_family = "unix")))]
         {
             let mut sa: sigaction = mem::zeroed();
-            sa.sa_sigaction = _sigint_handler_rust as sighandler_t;
+            sa.sa_sigaction = _sigint_handler_rust as *const () as sighandler_t;
             libc::sigemptyset(&mut sa.sa_mask);
             sa.sa_flags = 0;
             libc::sigaction(SIGINT, &sa, null_mut());
@@ -685,7 +1119,7 @@ fn check_directory_files(dir_path: &str) -> io::Result<(u32, u32, u32, u32)> {
     let mut debug_enabled_files = 0u32;
     
     // Define expected .rs files for each directory
-    let expected_files = match dir_path.split('/').last().unwrap_or("") {
+    let expected_files = match dir_path.split('/').next_back().unwrap_or("") {
         "common" => vec![
             "log.rs", "mod.rs", "model.rs", "utils.rs", "base64.rs", "build_info.rs",
             "console.rs", "json.rs", "mlock.rs", "ngram_cache.rs", "sampling.rs",
@@ -766,7 +1200,7 @@ fn test_debug_prints() {
     let rustlib_path = "/Users/mac/Desktop/workspace/llm_rust/rustlib";
     
     if let Ok(entries) = std::fs::read_dir(rustlib_path) {
-        scan_directory_recursively(&rustlib_path.to_string(), "");
+        scan_directory_recursively(rustlib_path, "");
     } else {
         rs_log_info(cstr("Failed to scan rustlib directory").as_ptr());
     }
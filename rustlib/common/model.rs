@@ -17,14 +17,33 @@
 
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_uint, c_void, c_float};
-use std::ptr::{null_mut, null};
+use std::ptr::{self, null_mut, null};
 use std::env;
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::io::Read;
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+use crate::llmrust::common::common::{common_control_vector_load as load_control_vectors, string_parse_kv_override, CommonControlVectorData, CommonControlVectorLoadInfo};
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_backend::GgmlBackendBufferType;
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+use crate::llmrust::src::adapter_loader::llama_adapter_lora_load;
+use crate::llmrust::src::llama_adapter::{self as llama_adapter_handle, LlamaAdapterCvec};
+use crate::llmrust::src::llama_arch::{llama_tensor_shape, LLM_ARCH_LLAMA};
+use crate::llmrust::src::llama_context::{self as llama_context_handle, LlamaContext};
+use crate::llmrust::src::llama_cparams::{LlamaCparams, LlamaFlashAttnType};
+use crate::llmrust::src::llama_hparams::{LlamaAttentionType, LlamaHparams, LlamaPoolingType, LlamaRopeScalingType};
+use crate::llmrust::src::llama_kv_cache::LlamaKvCache;
+use crate::llmrust::src::llama_memory;
+use crate::llmrust::src::llama_model::{self as llama_model_handle, llama_model_load_info, LlamaModel, LlamaModelInfo};
+use crate::llmrust::src::llama_model_loader::{
+    llama_tensor_groups, LlamaModelKvOverride, LlamaModelKvOverrideValue, LlamaModelLoader, LlamaModelTensorBuftOverride,
+    LlamaTensorData, LlamaTensorPlacement,
+};
+
 // Import types from log.rs
 use super::log::{
     llama_context, llama_model, common_sampler, common_params, cpu_params,
@@ -92,6 +111,11 @@ pub struct llama_model_kv_override {
     pub value: llama_model_kv_override_value,
 }
 
+pub const LLAMA_KV_OVERRIDE_TYPE_INT: c_int = 0;
+pub const LLAMA_KV_OVERRIDE_TYPE_FLOAT: c_int = 1;
+pub const LLAMA_KV_OVERRIDE_TYPE_BOOL: c_int = 2;
+pub const LLAMA_KV_OVERRIDE_TYPE_STR: c_int = 3;
+
 #[repr(C)]
 pub union llama_model_kv_override_value {
     pub val_i64: i64,
@@ -125,9 +149,33 @@ pub struct lora_adapter {
     pub prompt_prefix: *const c_char,
 }
 
+#[repr(C)]
+pub struct common_control_vector_load_info {
+    pub strength: c_float,
+    pub fname: *const c_char,
+}
+
 // Constants
 pub const LLAMA_TOKEN_NULL: llama_token = -1;
-pub const LLAMA_POOLING_TYPE_RANK: c_int = 2;
+pub const LLAMA_POOLING_TYPE_UNSPECIFIED: c_int = LlamaPoolingType::Unspecified as c_int;
+pub const LLAMA_POOLING_TYPE_NONE: c_int = LlamaPoolingType::None as c_int;
+pub const LLAMA_POOLING_TYPE_MEAN: c_int = LlamaPoolingType::Mean as c_int;
+pub const LLAMA_POOLING_TYPE_CLS: c_int = LlamaPoolingType::Cls as c_int;
+pub const LLAMA_POOLING_TYPE_LAST: c_int = LlamaPoolingType::Last as c_int;
+pub const LLAMA_POOLING_TYPE_RANK: c_int = LlamaPoolingType::Rank as c_int;
+pub const LLAMA_ATTENTION_TYPE_UNSPECIFIED: c_int = LlamaAttentionType::Unspecified as c_int;
+pub const LLAMA_ATTENTION_TYPE_CAUSAL: c_int = LlamaAttentionType::Causal as c_int;
+pub const LLAMA_ATTENTION_TYPE_NON_CAUSAL: c_int = LlamaAttentionType::NonCausal as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_UNSPECIFIED: c_int = LlamaRopeScalingType::Unspecified as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_NONE: c_int = LlamaRopeScalingType::None as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_LINEAR: c_int = LlamaRopeScalingType::Linear as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_YARN: c_int = LlamaRopeScalingType::Yarn as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_LONGROPE: c_int = LlamaRopeScalingType::LongRope as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_NTK: c_int = LlamaRopeScalingType::Ntk as c_int;
+pub const LLAMA_ROPE_SCALING_TYPE_DYNAMIC: c_int = LlamaRopeScalingType::Dynamic as c_int;
+pub const LLAMA_FLASH_ATTN_TYPE_AUTO: c_int = LlamaFlashAttnType::Auto as c_int;
+pub const LLAMA_FLASH_ATTN_TYPE_DISABLED: c_int = LlamaFlashAttnType::Disabled as c_int;
+pub const LLAMA_FLASH_ATTN_TYPE_ENABLED: c_int = LlamaFlashAttnType::Enabled as c_int;
 
 // Mock model loading functions
 #[no_mangle]
@@ -146,9 +194,96 @@ pub extern "C" fn llama_model_load_from_file(
     rs_log_info(cstr(&format!("  - Main GPU: {}", params.main_gpu)).as_ptr());
     rs_log_info(cstr(&format!("  - Use mmap: {}", params.use_mmap)).as_ptr());
     rs_log_info(cstr(&format!("  - Use mlock: {}", params.use_mlock)).as_ptr());
-    
-    // Return mock model pointer (non-null to indicate success)
-    0x1000 as *mut llama_model
+
+    // Metadata overrides take effect before the hyperparameters are read
+    let overrides = match unsafe { kv_overrides_from_params(params.kv_overrides) } {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_model_load: {}", e)).as_ptr());
+            return null_mut();
+        }
+    };
+    let (ml, info) = match load_model(path_str, overrides, params.use_mmap) {
+        Ok((ml, info)) => {
+            let hp = &info.hparams;
+            rs_log_info(cstr(&format!(
+                "  - {}: {} layers, n_embd = {}, n_ctx_train = {}, experts = {}/{}, add_bos = {}",
+                info.arch, hp.n_layer, hp.n_embd, hp.n_ctx_train, hp.n_expert_used, hp.n_expert, info.add_bos
+            ))
+            .as_ptr());
+            (ml, info)
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_model_load: error loading model hyperparameters: {}", e)).as_ptr());
+            return null_mut();
+        }
+    };
+
+    // Tensor placement: buffer type overrides by name regex, first match wins
+    let placement = unsafe { tensor_buft_overrides_from_params(params.tensor_buft_overrides) }
+        .and_then(|buft_overrides| Ok((ml.tensor_placement(params.use_mmap, params.use_extra_bufts, &buft_overrides)?, buft_overrides)));
+    let placement = match placement {
+        Ok((placement, buft_overrides)) => {
+            for group in llama_tensor_groups(&placement) {
+                let source = match group.override_idx {
+                    Some(i) => format!("override '{}'", buft_overrides[i].pattern),
+                    None => "default".to_string(),
+                };
+                rs_log_info(cstr(&format!(
+                    "  - tensor group {}: {} tensors, {:.2} MiB -> {} ({})",
+                    group.name,
+                    group.n_tensors,
+                    group.n_bytes as f64 / (1024.0 * 1024.0),
+                    group.buft.name(),
+                    source
+                ))
+                .as_ptr());
+            }
+            if params.use_extra_bufts {
+                for (name, reason) in ml.repack_skipped(&placement) {
+                    rs_log_info(cstr(&format!("  - tensor {} is not repacked: {}", name, reason)).as_ptr());
+                }
+            }
+            placement
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_model_load: {}", e)).as_ptr());
+            return null_mut();
+        }
+    };
+
+    let tensors = match load_tensors(&ml, &placement) {
+        Ok(tensors) => tensors,
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_model_load: error loading tensors: {}", e)).as_ptr());
+            return null_mut();
+        }
+    };
+
+    llama_model_handle::into_handle(LlamaModel { info, tensors }) as *mut llama_model
+}
+
+/// Loads every placed tensor, repacking the ones placed in the repack
+/// buffer, and logs each repacked tensor with its new layout.
+fn load_tensors(ml: &LlamaModelLoader, placement: &[LlamaTensorPlacement]) -> Result<Vec<(String, LlamaTensorData)>, String> {
+    let mut tensors = Vec::with_capacity(placement.len());
+    for p in placement {
+        let data = ml.load_tensor(p)?;
+        if let (Some(layout), Some(t)) = (data.layout, ml.gguf().tensor(&p.name)) {
+            rs_log_info(cstr(&format!("  - repacked tensor {} ({} -> {})", p.name, t.ty.name(), layout.type_name(t.ty))).as_ptr());
+        }
+        tensors.push((p.name.clone(), data));
+    }
+    let n_repacked = tensors.iter().filter(|(_, data)| data.layout.is_some()).count();
+    if n_repacked > 0 {
+        rs_log_info(cstr(&format!("  - repacked {} of {} tensors", n_repacked, tensors.len())).as_ptr());
+    }
+    Ok(tensors)
+}
+
+/// The model behind a `llama_model` handle
+fn model_ref<'a>(model: *mut llama_model) -> Option<&'a LlamaModel> {
+    unsafe { llama_model_handle::from_handle(model as *mut c_void) }
 }
 
 #[no_mangle]
@@ -162,23 +297,244 @@ pub extern "C" fn llama_init_from_model(
     rs_log_info(cstr(&format!("  - Threads: {}", params.n_threads)).as_ptr());
     rs_log_info(cstr(&format!("  - Embeddings: {}", params.embeddings)).as_ptr());
     
-    if model.is_null() {
-        rs_log_error(cstr("Mock: Model is null, cannot create context").as_ptr());
+    let Some(model) = model_ref(model) else {
+        rs_log_error(cstr("Model is null, cannot create context").as_ptr());
         return null_mut();
+    };
+
+    // KV cache element types
+    let (type_k, type_v) = match (GgmlType::from_i32(params.type_k), GgmlType::from_i32(params.type_v)) {
+        (Some(type_k), Some(type_v)) => (type_k, type_v),
+        _ => {
+            rs_log_error(cstr(&format!(
+                "Unsupported KV cache type (type_k = {}, type_v = {}); supported: f32, f16, q8_0, q4_0",
+                params.type_k, params.type_v
+            )).as_ptr());
+            return null_mut();
+        }
+    };
+    let hparams = model.hparams();
+    let kv_size = params.n_ctx.max(1) as u32;
+    let (k_bytes, v_bytes) = LlamaKvCache::memory_footprint(hparams, kv_size, type_k, type_v);
+    let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+    rs_log_info(cstr(&format!(
+        "llama_kv_cache: size = {:7.2} MiB ({:6} cells, {:3} layers, {:2} seqs), K ({}): {:7.2} MiB, V ({}): {:7.2} MiB",
+        mib(k_bytes + v_bytes),
+        kv_size,
+        hparams.n_layer,
+        params.n_seq_max.max(1),
+        type_k.name(),
+        mib(k_bytes),
+        type_v.name(),
+        mib(v_bytes)
+    )).as_ptr());
+    
+    let Some(pooling_type) = LlamaPoolingType::from_raw(params.pooling_type) else {
+        rs_log_error(cstr(&format!("Unsupported pooling type {}", params.pooling_type)).as_ptr());
+        return null_mut();
+    };
+    let Some(attention_type) = LlamaAttentionType::from_raw(params.attention_type) else {
+        rs_log_error(cstr(&format!("Unsupported attention type {}", params.attention_type)).as_ptr());
+        return null_mut();
+    };
+    let Some(rope_scaling_type) = LlamaRopeScalingType::from_raw(params.rope_scaling_type) else {
+        rs_log_error(cstr(&format!("Unsupported rope scaling type {}", params.rope_scaling_type)).as_ptr());
+        return null_mut();
+    };
+    let Some(flash_attn_type) = LlamaFlashAttnType::from_raw(params.flash_attn_type) else {
+        rs_log_error(cstr(&format!("Unsupported flash attention type {}", params.flash_attn_type)).as_ptr());
+        return null_mut();
+    };
+    let cparams = LlamaCparams {
+        n_ctx: kv_size,
+        n_ubatch: params.n_ubatch.max(1) as usize,
+        embeddings: params.embeddings,
+        pooling_type,
+        attention_type,
+        rope_scaling_type,
+        rope_freq_base: params.rope_freq_base,
+        rope_freq_scale: params.rope_freq_scale,
+        yarn_ext_factor: params.yarn_ext_factor,
+        yarn_attn_factor: params.yarn_attn_factor,
+        yarn_beta_fast: params.yarn_beta_fast,
+        yarn_beta_slow: params.yarn_beta_slow,
+        yarn_orig_ctx: params.yarn_orig_ctx.max(0) as u32,
+        flash_attn_type,
+        ..Default::default()
+    };
+    rs_log_info(cstr(&format!(
+        "  - Flash attention: {} ({})",
+        flash_attn_type.name(),
+        if cparams.flash_attn() { "enabled" } else { "disabled" }
+    )).as_ptr());
+    rs_log_info(cstr(&format!(
+        "  - Attention: {}",
+        if cparams.causal_attn_for(hparams.causal_attn) { "causal" } else { "non-causal" }
+    )).as_ptr());
+    if cparams.embeddings {
+        rs_log_info(cstr(&format!("  - Pooling: {}", cparams.pooling_for(hparams.pooling_type).name())).as_ptr());
+    }
+    let rope = match cparams.rope_params(hparams) {
+        Ok(Some(rope)) => {
+            let scaling_type = cparams.rope_scaling_for(hparams.rope_scaling_type_train);
+            rs_log_info(cstr(&format!(
+                "  - RoPE: {} scaling, freq_base = {}, freq_scale = {}, n_ctx_orig_yarn = {}, ext_factor = {}, attn_factor = {}",
+                scaling_type.name(),
+                rope.freq_base,
+                rope.freq_scale,
+                rope.n_ctx_orig,
+                rope.ext_factor,
+                rope.attn_factor
+            )).as_ptr());
+            Some(rope)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            rs_log_error(cstr(&format!("Failed to create context: {}", e)).as_ptr());
+            return null_mut();
+        }
+    };
+
+    let mut kv = match LlamaKvCache::new(hparams, kv_size, params.n_seq_max.max(1) as u32, type_k, type_v) {
+        Ok(kv) => kv,
+        Err(e) => {
+            rs_log_error(cstr(&format!("Failed to create KV cache: {}", e)).as_ptr());
+            return null_mut();
+        }
+    };
+    kv.set_rope(rope);
+    kv.set_flash_attn(cparams.flash_attn());
+    match LlamaContext::with_cparams(hparams.fingerprint(), cparams, Some(Box::new(kv))) {
+        Ok(ctx) => llama_context_handle::into_handle(ctx.with_model_hparams(hparams.clone())) as *mut llama_context,
+        Err(e) => {
+            rs_log_error(cstr(&format!("Failed to create context: {}", e)).as_ptr());
+            null_mut()
+        }
+    }
+}
+
+/// Reads the `kv_overrides` array of `llama_model_params`, terminated by an
+/// entry with an empty key.
+unsafe fn kv_overrides_from_params(mut p: *const llama_model_kv_override) -> Result<Vec<LlamaModelKvOverride>, String> {
+    let mut overrides = Vec::new();
+    while !p.is_null() && (*p).key[0] != 0 {
+        let ovr = &*p;
+        let key = CStr::from_ptr(ovr.key.as_ptr()).to_string_lossy().into_owned();
+        let value = match ovr.tag {
+            LLAMA_KV_OVERRIDE_TYPE_INT => LlamaModelKvOverrideValue::Int(ovr.value.val_i64),
+            LLAMA_KV_OVERRIDE_TYPE_FLOAT => LlamaModelKvOverrideValue::Float(ovr.value.val_f64),
+            LLAMA_KV_OVERRIDE_TYPE_BOOL => LlamaModelKvOverrideValue::Bool(ovr.value.val_bool),
+            LLAMA_KV_OVERRIDE_TYPE_STR => {
+                LlamaModelKvOverrideValue::Str(CStr::from_ptr(ovr.value.val_str.as_ptr()).to_string_lossy().into_owned())
+            }
+            tag => return Err(format!("unknown type tag {} of metadata override '{}'", tag, key)),
+        };
+        overrides.push(LlamaModelKvOverride { key, value });
+        p = p.add(1);
+    }
+    Ok(overrides)
+}
+
+/// Reads the `tensor_buft_overrides` array of `llama_model_params`, terminated
+/// by an entry with a null pattern.
+unsafe fn tensor_buft_overrides_from_params(
+    mut p: *const llama_model_tensor_buft_override,
+) -> Result<Vec<LlamaModelTensorBuftOverride>, String> {
+    let mut overrides = Vec::new();
+    while !p.is_null() && !(*p).pattern.is_null() {
+        let pattern = CStr::from_ptr((*p).pattern).to_string_lossy();
+        let buft = GgmlBackendBufferType::from_i32((*p).buft_type)
+            .ok_or_else(|| format!("unknown buffer type {} for tensor override '{}'", (*p).buft_type, pattern))?;
+        overrides.push(LlamaModelTensorBuftOverride::new(&pattern, buft)?);
+        p = p.add(1);
+    }
+    Ok(overrides)
+}
+
+/// Mock: separator token of the mock model, an otherwise unused id of its vocab
+pub(crate) const MOCK_TOKEN_SEP: u32 = 31990;
+
+/// Model metadata of the mock model, as its GGUF file would hold it
+pub(crate) fn mock_model_metadata() -> GgufFile {
+    let hp = mock_model_hparams();
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", LLM_ARCH_LLAMA)
+        .set("llama.vocab_size", GgufValue::U32(hp.n_vocab))
+        .set("llama.context_length", GgufValue::U32(hp.n_ctx_train))
+        .set("llama.embedding_length", GgufValue::U32(hp.n_embd))
+        .set("llama.block_count", GgufValue::U32(hp.n_layer))
+        .set("llama.feed_forward_length", GgufValue::U32(hp.n_ff))
+        .set("llama.attention.head_count", GgufValue::U32(hp.n_head))
+        .set("llama.attention.head_count_kv", GgufValue::U32(hp.n_head_kv))
+        .set("llama.rope.dimension_count", GgufValue::U32(hp.n_rot))
+        .set("llama.rope.freq_base", GgufValue::F32(hp.rope_freq_base_train))
+        .set("llama.attention.layer_norm_rms_epsilon", GgufValue::F32(hp.f_norm_rms_eps))
+        .set("tokenizer.ggml.add_bos_token", GgufValue::Bool(true))
+        .set("tokenizer.ggml.seperator_token_id", GgufValue::U32(MOCK_TOKEN_SEP));
+    GgufFile::from_bytes(&w.to_bytes()).expect("mock model metadata")
+}
+
+/// Reads the metadata of the model at `path` with `overrides` applied,
+/// without its tensor data. A missing file (the mock model) uses the mock
+/// model's metadata, so overrides still take effect.
+pub(crate) fn load_model_info(path: &str, overrides: Vec<LlamaModelKvOverride>) -> Result<LlamaModelInfo, String> {
+    let gguf = if Path::new(path).exists() { GgufFile::read_metadata(path)? } else { mock_model_metadata() };
+    model_loader(gguf, overrides).map(|(_, info)| info)
+}
+
+/// Reads the model at `path` with `overrides` applied, memory mapping it with
+/// `use_mmap` and reading it into memory without.
+fn load_model(
+    path: &str,
+    overrides: Vec<LlamaModelKvOverride>,
+    use_mmap: bool,
+) -> Result<(LlamaModelLoader, LlamaModelInfo), String> {
+    let gguf = match Path::new(path).exists() {
+        true if use_mmap => GgufFile::read_mmap(path)?,
+        true => GgufFile::read(path)?,
+        false => mock_model_metadata(),
+    };
+    model_loader(gguf, overrides)
+}
+
+fn model_loader(gguf: GgufFile, overrides: Vec<LlamaModelKvOverride>) -> Result<(LlamaModelLoader, LlamaModelInfo), String> {
+    let ml = LlamaModelLoader::new(gguf, overrides);
+    let info = llama_model_load_info(&ml)?;
+    for ovr in ml.used_overrides() {
+        rs_log_info(cstr(&format!("  - Using metadata override ({:>5}) '{}' = {}", ovr.value.type_name(), ovr.key, ovr.value)).as_ptr());
+    }
+    for ovr in ml.unused_overrides() {
+        rs_log_warn(cstr(&format!("  - Metadata override '{}' matches no key the model reads", ovr.key)).as_ptr());
+    }
+    Ok((ml, info))
+}
+
+/// Mock: hyperparameters of the mock model (LLaMA-7B shapes)
+pub(crate) fn mock_model_hparams() -> LlamaHparams {
+    LlamaHparams {
+        n_vocab: 32000,
+        n_ctx_train: 4096,
+        n_embd: 4096,
+        n_layer: 32,
+        n_head: 32,
+        n_head_kv: 32,
+        n_embd_head_k: 128,
+        n_embd_head_v: 128,
+        n_ff: 11008,
+        n_rot: 128,
+        ..Default::default()
     }
-    
-    // Return mock context pointer (non-null to indicate success)
-    0x2000 as *mut llama_context
 }
 
 #[no_mangle]
 pub extern "C" fn llama_model_free(model: *mut llama_model) {
-    rs_log_info(cstr("Mock: Freeing model").as_ptr());
+    unsafe { llama_model_handle::free_handle(model as *mut c_void) };
 }
 
 #[no_mangle]
 pub extern "C" fn llama_free(ctx: *mut llama_context) {
     rs_log_info(cstr("Mock: Freeing context").as_ptr());
+    unsafe { llama_context_handle::free_handle(ctx as *mut c_void) };
 }
 
 #[no_mangle]
@@ -212,17 +568,17 @@ pub extern "C" fn llama_context_default_params() -> llama_context_params {
         n_threads: 8,
         n_threads_batch: 8,
         embeddings: false,
-        rope_scaling_type: 0,
-        rope_freq_base: 10000.0,
-        rope_freq_scale: 1.0,
+        rope_scaling_type: LLAMA_ROPE_SCALING_TYPE_UNSPECIFIED,
+        rope_freq_base: 0.0,
+        rope_freq_scale: 0.0,
         yarn_ext_factor: -1.0,
-        yarn_attn_factor: 1.0,
+        yarn_attn_factor: -1.0,
         yarn_beta_fast: 32.0,
         yarn_beta_slow: 1.0,
         yarn_orig_ctx: 0,
-        pooling_type: 0,
-        attention_type: 0,
-        flash_attn_type: 0,
+        pooling_type: LLAMA_POOLING_TYPE_UNSPECIFIED,
+        attention_type: LLAMA_ATTENTION_TYPE_UNSPECIFIED,
+        flash_attn_type: LLAMA_FLASH_ATTN_TYPE_AUTO,
         cb_eval: None,
         cb_eval_user_data: null_mut(),
         offload_kqv: true,
@@ -238,8 +594,7 @@ pub extern "C" fn llama_context_default_params() -> llama_context_params {
 // Model utility functions
 #[no_mangle]
 pub extern "C" fn llama_model_n_layer(model: *mut llama_model) -> c_int {
-    rs_log_info(cstr("Mock: Getting model layer count").as_ptr());
-    32 // Mock layer count
+    model_ref(model).map_or(0, |model| model.hparams().n_layer as c_int)
 }
 
 #[no_mangle]
@@ -262,19 +617,24 @@ pub extern "C" fn llama_vocab_n_tokens(vocab: *const llama_vocab) -> c_int {
 
 #[no_mangle]
 pub extern "C" fn llama_pooling_type(ctx: *mut llama_context) -> c_int {
-    rs_log_info(cstr("Mock: Getting pooling type").as_ptr());
-    0 // Mock pooling type
+    unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }
+        .map_or(LLAMA_POOLING_TYPE_NONE, |ctx| ctx.pooling_type() as c_int)
 }
 
 #[no_mangle]
 pub extern "C" fn llama_memory_can_shift(mem: *mut c_void) -> bool {
-    rs_log_info(cstr("Mock: Checking if memory can shift").as_ptr());
-    true
+    unsafe { llama_memory::from_handle(mem) }.is_some_and(|memory| memory.can_shift())
 }
 
 #[no_mangle]
 pub extern "C" fn llama_memory_clear(mem: *mut c_void, clear_kv: bool) {
-    rs_log_info(cstr(&format!("Mock: Clearing memory (clear_kv: {})", clear_kv)).as_ptr());
+    match unsafe { llama_memory::from_handle(mem) } {
+        Some(memory) => {
+            memory.clear(clear_kv);
+            rs_log_info(cstr(&format!("Memory cleared (clear_kv: {})", clear_kv)).as_ptr());
+        }
+        None => rs_log_info(cstr("llama_memory_clear: no memory attached").as_ptr()),
+    }
 }
 
 #[no_mangle]
@@ -298,16 +658,37 @@ pub extern "C" fn llama_adapter_lora_init(
     model: *mut llama_model,
     path: *const c_char
 ) -> *mut c_void {
-    let path_str = if path.is_null() {
-        "unknown_lora.bin"
-    } else {
-        unsafe { CStr::from_ptr(path).to_str().unwrap_or("unknown_lora.bin") }
+    let Some(model) = model_ref(model).filter(|_| !path.is_null()) else {
+        rs_log_error(cstr("llama_adapter_lora_init: model and path are required").as_ptr());
+        return null_mut();
     };
-    
-    rs_log_info(cstr(&format!("Mock: Loading LoRA adapter from {}", path_str)).as_ptr());
-    0x3000 as *mut c_void // Mock LoRA adapter pointer
+    let path_str = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    match llama_adapter_lora_load(&path_str, &model.info.arch, |name| llama_tensor_shape(model.hparams(), name)) {
+        Ok(adapter) => {
+            rs_log_info(cstr(&format!(
+                "llama_adapter_lora_init: loaded LoRA adapter {} ({} tensors, alpha = {})",
+                path_str,
+                adapter.weight_names().count(),
+                adapter.alpha
+            )).as_ptr());
+            llama_adapter_handle::into_handle(Arc::new(adapter))
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_adapter_lora_init: failed to load adapter: {}", e)).as_ptr());
+            null_mut()
+        }
+    }
+}
+
+/// Releases an adapter handle; contexts that enabled it keep it alive until
+/// they disable it or are freed.
+#[no_mangle]
+pub extern "C" fn llama_adapter_lora_free(adapter: *mut c_void) {
+    unsafe { llama_adapter_handle::free_handle(adapter) };
 }
 
+/// Copies the metadata value of `key` to `buf` (NUL-terminated, truncated to
+/// `buf_size`) and returns its full length, or -1 if the key is missing.
 #[no_mangle]
 pub extern "C" fn llama_adapter_meta_val_str(
     adapter: *mut c_void,
@@ -315,33 +696,60 @@ pub extern "C" fn llama_adapter_meta_val_str(
     buf: *mut c_char,
     buf_size: usize
 ) -> c_int {
-    let key_str = if key.is_null() {
-        "unknown_key"
-    } else {
-        unsafe { CStr::from_ptr(key).to_str().unwrap_or("unknown_key") }
+    let Some(adapter) = (unsafe { llama_adapter_handle::from_handle(adapter) }) else {
+        return -1;
     };
-    
-    rs_log_info(cstr(&format!("Mock: Getting adapter meta value for key: {}", key_str)).as_ptr());
-    
-    // Mock return values based on key
-    let mock_value = match key_str {
-        "adapter.lora.task_name" => "mock_task",
-        "adapter.lora.prompt_prefix" => "Mock: ",
-        _ => "mock_value",
+    if key.is_null() {
+        return -1;
+    }
+    let key_str = unsafe { CStr::from_ptr(key) }.to_string_lossy();
+    let Some(value) = adapter.meta_val_str(&key_str) else {
+        return -1;
     };
-    
     if !buf.is_null() && buf_size > 0 {
-        let mock_cstring = cstr(mock_value);
-        let mock_bytes = mock_cstring.as_bytes_with_nul();
-        let copy_len = std::cmp::min(mock_bytes.len(), buf_size);
+        let n = value.len().min(buf_size - 1);
         unsafe {
-            std::ptr::copy_nonoverlapping(mock_bytes.as_ptr(), buf as *mut u8, copy_len);
+            std::ptr::copy_nonoverlapping(value.as_ptr(), buf as *mut u8, n);
+            *buf.add(n) = 0;
         }
     }
-    
-    0 // Success
+    value.len() as c_int
+}
+
+/// Enables `adapter` on `ctx` with `scale`, or rescales it; 0 on success.
+#[no_mangle]
+pub extern "C" fn llama_set_adapter_lora(ctx: *mut llama_context, adapter: *mut c_void, scale: c_float) -> c_int {
+    let ctx = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) };
+    match (ctx, unsafe { llama_adapter_handle::from_handle(adapter) }) {
+        (Some(ctx), Some(adapter)) => {
+            ctx.set_adapter_lora(adapter, scale);
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// Disables `adapter` on `ctx`; -1 if it was not enabled.
+#[no_mangle]
+pub extern "C" fn llama_rm_adapter_lora(ctx: *mut llama_context, adapter: *mut c_void) -> c_int {
+    let ctx = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) };
+    let removed = match (ctx, unsafe { llama_adapter_handle::from_handle(adapter) }) {
+        (Some(ctx), Some(adapter)) => ctx.rm_adapter_lora(&adapter),
+        _ => false,
+    };
+    if removed { 0 } else { -1 }
+}
+
+#[no_mangle]
+pub extern "C" fn llama_clear_adapter_lora(ctx: *mut llama_context) {
+    if let Some(ctx) = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+        ctx.clear_adapter_lora();
+    }
 }
 
+/// Sets the control vector of `ctx` from `data` (`n_embd` values per layer,
+/// starting at layer 1) for layers `layer_start..=layer_end`; null `data`
+/// clears it.
 #[no_mangle]
 pub extern "C" fn llama_apply_adapter_cvec(
     ctx: *mut llama_context,
@@ -351,9 +759,28 @@ pub extern "C" fn llama_apply_adapter_cvec(
     layer_start: c_int,
     layer_end: c_int
 ) -> c_int {
-    rs_log_info(cstr(&format!("Mock: Applying control vector (embd: {}, layers: {}-{})", 
-                              n_embd, layer_start, layer_end)).as_ptr());
-    0 // Success
+    let Some(ctx) = (unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }) else {
+        return -1;
+    };
+    if data.is_null() {
+        ctx.set_adapter_cvec(LlamaAdapterCvec::default());
+        return 0;
+    }
+    let Some(hparams) = ctx.model_hparams() else {
+        rs_log_error(cstr("llama_apply_adapter_cvec: the context has no model").as_ptr());
+        return -1;
+    };
+    let data = unsafe { std::slice::from_raw_parts(data, len) };
+    match LlamaAdapterCvec::new(hparams, data, n_embd.max(0) as usize, layer_start, layer_end) {
+        Ok(cvec) => {
+            ctx.set_adapter_cvec(cvec);
+            0
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("llama_apply_adapter_cvec: {}", e)).as_ptr());
+            -1
+        }
+    }
 }
 
 // Threadpool functions
@@ -417,6 +844,90 @@ pub extern "C" fn common_context_params_to_llama(params: *const common_params) -
     cparams
 }
 
+/// Loads the prompt cache file at startup and keeps its tokens in the
+/// context, for `common_prompt_cache_reuse`. A file saved for another model is
+/// rejected and will be overwritten, unless the cache is read-only.
+pub(crate) fn restore_prompt_cache(ctx: *mut llama_context, path: &str, read_only: bool) {
+    rs_log_info(cstr(&format!("attempting to load saved session from '{}'", path)).as_ptr());
+    if !Path::new(path).exists() {
+        if read_only {
+            rs_log_info(cstr("session file does not exist, prompt cache is read-only so it will not be created").as_ptr());
+        } else {
+            rs_log_info(cstr("session file does not exist, will create.").as_ptr());
+        }
+        return;
+    }
+
+    let capacity = super::log::llama_n_ctx(ctx).max(0) as usize;
+    let mut tokens = vec![0; capacity];
+    let mut n_tokens = 0usize;
+    if super::log::llama_state_load_file(ctx, cstr(path).as_ptr(), tokens.as_mut_ptr(), capacity, &mut n_tokens) {
+        rs_log_info(cstr(&format!("loaded a session with prompt size of {} tokens", n_tokens)).as_ptr());
+        tokens.truncate(n_tokens);
+        if let Some(ctx) = unsafe { llama_context_handle::from_handle(ctx as *mut c_void) } {
+            ctx.set_session_tokens(tokens);
+        }
+    } else if read_only {
+        rs_log_warn(cstr("ignoring the session file; the prompt cache is read-only").as_ptr());
+    } else {
+        rs_log_warn(cstr("ignoring the session file; it will be replaced").as_ptr());
+    }
+}
+
+/// Matches the prompt against the session restored at startup. Returns the
+/// number of leading prompt tokens whose cells are already in sequence 0 and
+/// need not be evaluated; the last prompt token is always evaluated again so
+/// that it produces logits. The cells past them are removed.
+#[no_mangle]
+pub extern "C" fn common_prompt_cache_reuse(ctx: *mut llama_context, tokens: *const llama_token, n_tokens: usize) -> usize {
+    let Some(handle) = (unsafe { llama_context_handle::from_handle(ctx as *mut c_void) }) else {
+        return 0;
+    };
+    if handle.session_tokens().is_empty() || tokens.is_null() || n_tokens == 0 {
+        return 0;
+    }
+    let prompt = unsafe { std::slice::from_raw_parts(tokens, n_tokens) };
+    let n_match = handle.session_tokens().iter().zip(prompt).take_while(|(a, b)| a == b).count().min(n_tokens - 1);
+    if n_match == 0 {
+        rs_log_warn(cstr("session file has little in common with the prompt; it will be evaluated again").as_ptr());
+    } else {
+        rs_log_info(cstr(&format!("using the saved session: {} of {} prompt tokens reused", n_match, n_tokens)).as_ptr());
+    }
+    handle.set_session_tokens(prompt[..n_match].to_vec());
+    let memory = super::log::llama_get_memory(ctx);
+    if !memory.is_null() {
+        super::log::llama_memory_seq_rm(memory, 0, n_match, -1);
+    }
+    n_match
+}
+
+/// Saves the session to `path_prompt_cache`: call it once the prompt has been
+/// evaluated, and again with the prompt and generated tokens at the end of the
+/// run (`after_generation`), which only writes when `prompt_cache_all` is set.
+/// A read-only cache is never written. Returns whether the file was written.
+#[no_mangle]
+pub extern "C" fn common_prompt_cache_save(
+    ctx: *mut llama_context,
+    params: *const common_params,
+    tokens: *const llama_token,
+    n_tokens: usize,
+    after_generation: bool,
+) -> bool {
+    let Some(params) = (unsafe { params.as_ref() }) else {
+        return false;
+    };
+    if params.path_prompt_cache.is_null() || params.prompt_cache_ro || (after_generation && !params.prompt_cache_all) {
+        return false;
+    }
+    if unsafe { CStr::from_ptr(params.path_prompt_cache) }.to_bytes().is_empty() {
+        return false;
+    }
+    if after_generation {
+        rs_log_info(cstr("saving final output to session file").as_ptr());
+    }
+    super::log::llama_state_save_file(ctx, params.path_prompt_cache, tokens, n_tokens)
+}
+
 // Enhanced common_init_from_params with comprehensive model loading
 #[no_mangle]
 pub extern "C" fn common_init_from_params_enhanced(params: *const common_params) -> common_init_result {
@@ -531,6 +1042,16 @@ pub extern "C" fn common_init_from_params_enhanced(params: *const common_params)
     llama_perf_context_reset(ctx);
     llama_set_warmup(ctx, false);
     
+    // Prompt cache: restore the session saved by a previous run, after the
+    // warmup has cleared the memory
+    let path_prompt_cache = unsafe { (*params).path_prompt_cache };
+    if !path_prompt_cache.is_null() {
+        let path = unsafe { CStr::from_ptr(path_prompt_cache) }.to_string_lossy().into_owned();
+        if !path.is_empty() {
+            restore_prompt_cache(ctx, &path, unsafe { (*params).prompt_cache_ro });
+        }
+    }
+
     rs_log_info(cstr("Model initialization completed successfully").as_ptr());
     
     // Set result
@@ -543,10 +1064,13 @@ pub extern "C" fn common_init_from_params_enhanced(params: *const common_params)
 // Batch utility functions
 #[no_mangle]
 pub extern "C" fn common_batch_clear(batch: *mut llama_batch) {
-    rs_log_info(cstr("Mock: Clearing batch").as_ptr());
-    // In real implementation, would clear batch fields
+    if let Some(batch) = unsafe { batch.as_mut() } {
+        batch.n_tokens = 0;
+    }
 }
 
+/// Appends a token to a batch from `llama_batch_init`; a token that does not
+/// fit in the batch is logged and left out.
 #[no_mangle]
 pub extern "C" fn common_batch_add(
     batch: *mut llama_batch,
@@ -556,8 +1080,35 @@ pub extern "C" fn common_batch_add(
     seq_ids_len: usize,
     logits: bool
 ) {
-    rs_log_info(cstr(&format!("Mock: Adding token {} to batch at pos {}", id, pos)).as_ptr());
-    // In real implementation, would add token to batch
+    let Some(batch) = (unsafe { batch.as_mut() }) else {
+        return;
+    };
+    let Some((n_tokens_alloc, n_seq_max)) = super::log::batch_capacity(batch) else {
+        rs_log_error(cstr("common_batch_add: the batch was not allocated with llama_batch_init").as_ptr());
+        return;
+    };
+    let i = batch.n_tokens as usize;
+    if i >= n_tokens_alloc || batch.token.is_null() {
+        rs_log_error(cstr(&format!("common_batch_add: the batch is full ({} tokens)", i)).as_ptr());
+        return;
+    }
+    if seq_ids_len > n_seq_max || (seq_ids.is_null() && seq_ids_len > 0) {
+        rs_log_error(cstr(&format!(
+            "common_batch_add: invalid sequence list of {} ids (n_seq_max = {})",
+            seq_ids_len, n_seq_max
+        )).as_ptr());
+        return;
+    }
+    unsafe {
+        *batch.token.add(i) = id;
+        *batch.pos.add(i) = pos;
+        *batch.n_seq_id.add(i) = seq_ids_len as i32;
+        if seq_ids_len > 0 {
+            ptr::copy_nonoverlapping(seq_ids, *batch.seq_id.add(i), seq_ids_len);
+        }
+        *batch.logits.add(i) = logits as i8;
+    }
+    batch.n_tokens += 1;
 }
 
 // Model endpoint utility
@@ -587,21 +1138,84 @@ pub extern "C" fn get_model_endpoint() -> *const c_char {
 }
 
 // Additional model management functions
+/// Replaces the adapters of `ctx` by those of `adapters` with a non-zero
+/// scale; the model is not reloaded.
 #[no_mangle]
 pub extern "C" fn common_set_adapter_lora(
     ctx: *mut llama_context,
     adapters: *const lora_adapter,
     adapter_count: usize
 ) {
-    rs_log_info(cstr(&format!("Mock: Setting {} LoRA adapters", adapter_count)).as_ptr());
-    // In real implementation, would apply LoRA adapters to context
+    llama_clear_adapter_lora(ctx);
+    if adapters.is_null() {
+        return;
+    }
+    for la in unsafe { std::slice::from_raw_parts(adapters, adapter_count) } {
+        if la.scale != 0.0 && llama_set_adapter_lora(ctx, la.ptr, la.scale) != 0 {
+            rs_log_warn(cstr("common_set_adapter_lora: skipping an adapter that was not loaded").as_ptr());
+        }
+    }
 }
 
+/// Loads control vector files and sums them with their strengths; null on
+/// error. Release the result with `common_control_vector_free`.
 #[no_mangle]
-pub extern "C" fn common_control_vector_load(file_paths: *const *const c_char, count: usize) -> *mut c_void {
-    rs_log_info(cstr(&format!("Mock: Loading {} control vectors", count)).as_ptr());
-    // Return mock control vector data
-    0x4000 as *mut c_void
+pub extern "C" fn common_control_vector_load(
+    load_infos: *const common_control_vector_load_info,
+    count: usize
+) -> *mut c_void {
+    if load_infos.is_null() {
+        return null_mut();
+    }
+    let infos: Vec<CommonControlVectorLoadInfo> = unsafe { std::slice::from_raw_parts(load_infos, count) }
+        .iter()
+        .filter(|info| !info.fname.is_null())
+        .map(|info| CommonControlVectorLoadInfo {
+            strength: info.strength,
+            fname: unsafe { CStr::from_ptr(info.fname) }.to_string_lossy().into_owned(),
+        })
+        .collect();
+    match load_control_vectors(&infos) {
+        Ok(data) => {
+            rs_log_info(cstr(&format!(
+                "common_control_vector_load: loaded {} control vectors (n_embd = {}, {} layers)",
+                infos.len(),
+                data.n_embd,
+                data.n_layers()
+            )).as_ptr());
+            Box::into_raw(Box::new(data)) as *mut c_void
+        }
+        Err(e) => {
+            rs_log_error(cstr(&format!("common_control_vector_load: {}", e)).as_ptr());
+            null_mut()
+        }
+    }
+}
+
+fn control_vector_data<'a>(cvec: *mut c_void) -> Option<&'a CommonControlVectorData> {
+    unsafe { (cvec as *const CommonControlVectorData).as_ref() }
+}
+
+#[no_mangle]
+pub extern "C" fn common_control_vector_n_embd(cvec: *mut c_void) -> c_int {
+    control_vector_data(cvec).map_or(-1, |data| data.n_embd as c_int)
+}
+
+/// Summed directions of a loaded control vector; `len` receives their count.
+#[no_mangle]
+pub extern "C" fn common_control_vector_data(cvec: *mut c_void, len: *mut usize) -> *const c_float {
+    let data = control_vector_data(cvec).map_or(&[][..], |data| data.data.as_slice());
+    if !len.is_null() {
+        unsafe { *len = data.len() };
+    }
+    if data.is_empty() { null() } else { data.as_ptr() }
+}
+
+#[no_mangle]
+pub extern "C" fn common_control_vector_free(cvec: *mut c_void) {
+    if !cvec.is_null() {
+        drop(unsafe { Box::from_raw(cvec as *mut CommonControlVectorData) });
+    }
 }
 
 // common_token_to_piece is already defined in log.rs
@@ -622,15 +1236,13 @@ fn discover_available_models(models_dir: &str) -> Vec<String> {
     
     match fs::read_dir(models_path) {
         Ok(entries) => {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if let Some(extension) = path.extension() {
-                        if extension == "gguf" {
-                            if let Some(filename) = path.file_name() {
-                                if let Some(filename_str) = filename.to_str() {
-                                    models.push(filename_str.to_string());
-                                }
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if let Some(extension) = path.extension() {
+                    if extension == "gguf" {
+                        if let Some(filename) = path.file_name() {
+                            if let Some(filename_str) = filename.to_str() {
+                                models.push(filename_str.to_string());
                             }
                         }
                     }
@@ -795,6 +1407,165 @@ pub struct ModelConfig {
     pub fallback_models: Vec<String>,
     pub model_preferences: ModelPreferences,
     pub environment_variables: EnvironmentConfig,
+    /// Chat format name (e.g. "hermes-2-pro") or Jinja template source; selects
+    /// the default lazy grammar triggers
+    #[serde(default)]
+    pub chat_template: Option<String>,
+    /// Context size of a server slot, in tokens
+    #[serde(default = "default_n_ctx")]
+    pub n_ctx: u32,
+    /// Shift the context instead of failing when a generation fills it
+    #[serde(default = "default_ctx_shift")]
+    pub ctx_shift: bool,
+    /// Tokens per block of the server's paged KV cache
+    #[serde(default = "default_kv_block_size")]
+    pub kv_block_size: u32,
+    /// Blocks in the server's paged KV cache, shared by all requests
+    #[serde(default = "default_kv_blocks")]
+    pub kv_blocks: u32,
+    /// Element type of the server's cached keys: "f32", "f16", "q8_0" or "q4_0"
+    #[serde(default = "default_cache_type_k")]
+    pub cache_type_k: String,
+    /// Element type of the server's cached values
+    #[serde(default = "default_cache_type_v")]
+    pub cache_type_v: String,
+    /// Attention kernel of the server's KV cache: "auto", "on" or "off"
+    #[serde(default = "default_flash_attn")]
+    pub flash_attn: String,
+    /// Memory cap of the prompt cache, in MiB
+    #[serde(default = "default_prompt_cache_mib")]
+    pub prompt_cache_mib: u32,
+    /// Maximum tokens per forward pass, shared by all active requests
+    #[serde(default = "default_n_batch")]
+    pub n_batch: u32,
+    /// Maximum tokens per micro-batch a forward pass is split into
+    #[serde(default = "default_n_ubatch")]
+    pub n_ubatch: u32,
+    /// How forward passes are split into micro-batches: "simple", "equal" or "seq"
+    #[serde(default = "default_batch_split")]
+    pub batch_split: String,
+    /// Pooling of `/v1/embeddings`: "mean", "cls" or "last"
+    #[serde(default = "default_pooling")]
+    pub pooling: String,
+    /// Normalization of returned embeddings: -1 none, 0 max-abs, 1 taxicab, 2 euclidean, p > 2 p-norm
+    #[serde(default = "default_embd_normalize")]
+    pub embd_normalize: i32,
+    /// Control vector files; a request selects them by index with strengths
+    #[serde(default = "default_control_vectors")]
+    pub control_vectors: Vec<String>,
+    /// Layers `[start, end]` request control vectors apply to; all layers if unset
+    #[serde(default = "default_control_vector_layer_range")]
+    pub control_vector_layer_range: Option<[i32; 2]>,
+    /// LoRA adapters loaded at startup; a request selects them by index
+    #[serde(default = "default_lora_adapters")]
+    pub lora_adapters: Vec<LoraAdapterConfig>,
+    /// GGUF metadata overrides per model file name, as `key=type:value` specs
+    /// applied in order when the model is loaded
+    #[serde(default)]
+    pub kv_overrides: std::collections::BTreeMap<String, Vec<String>>,
+}
+
+impl ModelConfig {
+    /// Metadata overrides of the model at `model_path`, looked up by file
+    /// name (or by the path as given)
+    pub fn model_kv_overrides(&self, model_path: &str) -> Result<Vec<LlamaModelKvOverride>, String> {
+        let file_name = Path::new(model_path).file_name().and_then(|n| n.to_str()).unwrap_or(model_path);
+        self.kv_overrides
+            .get(file_name)
+            .or_else(|| self.kv_overrides.get(model_path))
+            .into_iter()
+            .flatten()
+            .map(|spec| string_parse_kv_override(spec).map_err(|e| format!("kv_overrides of {}: {}", file_name, e)))
+            .collect()
+    }
+}
+
+/// A LoRA adapter file and the scale requests use when they do not choose one
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LoraAdapterConfig {
+    pub path: String,
+    #[serde(default = "default_lora_scale")]
+    pub scale: f32,
+}
+
+fn default_n_ctx() -> u32 {
+    env::var("N_CTX").ok().and_then(|v| v.parse().ok()).unwrap_or(4096)
+}
+
+fn default_ctx_shift() -> bool {
+    env::var("CTX_SHIFT").map(|v| v.to_lowercase() != "false").unwrap_or(true)
+}
+
+fn default_kv_block_size() -> u32 {
+    env::var("KV_BLOCK_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(16)
+}
+
+fn default_kv_blocks() -> u32 {
+    env::var("KV_BLOCKS").ok().and_then(|v| v.parse().ok()).unwrap_or(1024)
+}
+
+fn default_cache_type_k() -> String {
+    env::var("CACHE_TYPE_K").unwrap_or_else(|_| "f16".to_string())
+}
+
+fn default_cache_type_v() -> String {
+    env::var("CACHE_TYPE_V").unwrap_or_else(|_| "f16".to_string())
+}
+
+fn default_flash_attn() -> String {
+    env::var("FLASH_ATTN").unwrap_or_else(|_| "auto".to_string())
+}
+
+fn default_prompt_cache_mib() -> u32 {
+    env::var("PROMPT_CACHE_MIB").ok().and_then(|v| v.parse().ok()).unwrap_or(256)
+}
+
+fn default_n_batch() -> u32 {
+    env::var("N_BATCH").ok().and_then(|v| v.parse().ok()).unwrap_or(2048)
+}
+
+fn default_n_ubatch() -> u32 {
+    env::var("N_UBATCH").ok().and_then(|v| v.parse().ok()).unwrap_or(512)
+}
+
+fn default_batch_split() -> String {
+    env::var("BATCH_SPLIT").unwrap_or_else(|_| "simple".to_string())
+}
+
+fn default_pooling() -> String {
+    env::var("POOLING").unwrap_or_else(|_| "mean".to_string())
+}
+
+fn default_embd_normalize() -> i32 {
+    env::var("EMBD_NORMALIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(2)
+}
+
+fn default_control_vectors() -> Vec<String> {
+    env::var("CONTROL_VECTORS")
+        .map(|v| v.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn default_lora_adapters() -> Vec<LoraAdapterConfig> {
+    env::var("LORA_ADAPTERS")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|path| LoraAdapterConfig { path: path.to_string(), scale: default_lora_scale() })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn default_lora_scale() -> f32 {
+    1.0
+}
+
+fn default_control_vector_layer_range() -> Option<[i32; 2]> {
+    let range = env::var("CONTROL_VECTOR_LAYER_RANGE").ok()?;
+    let (start, end) = range.split_once(',')?;
+    Some([start.trim().parse().ok()?, end.trim().parse().ok()?])
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -863,6 +1634,24 @@ impl Default for ModelConfig {
                 default_model_var: env::var("DEFAULT_MODEL_VAR").unwrap_or_else(|_| "DEFAULT_MODEL".to_string()),
                 models_dir_var: env::var("MODELS_DIR_VAR").unwrap_or_else(|_| "MODELS_DIR".to_string()),
             },
+            chat_template: env::var("CHAT_TEMPLATE").ok(),
+            n_ctx: default_n_ctx(),
+            ctx_shift: default_ctx_shift(),
+            kv_block_size: default_kv_block_size(),
+            kv_blocks: default_kv_blocks(),
+            cache_type_k: default_cache_type_k(),
+            cache_type_v: default_cache_type_v(),
+            flash_attn: default_flash_attn(),
+            prompt_cache_mib: default_prompt_cache_mib(),
+            n_batch: default_n_batch(),
+            n_ubatch: default_n_ubatch(),
+            batch_split: default_batch_split(),
+            pooling: default_pooling(),
+            embd_normalize: default_embd_normalize(),
+            control_vectors: default_control_vectors(),
+            control_vector_layer_range: default_control_vector_layer_range(),
+            lora_adapters: default_lora_adapters(),
+            kv_overrides: Default::default(),
         }
     }
 }
@@ -874,7 +1663,7 @@ pub fn select_best_model() -> Option<PathBuf> {
     // 1. Check environment variable for specific model path
     if let Ok(model_path) = env::var(&config.environment_variables.model_path_var) {
         let path = PathBuf::from(&model_path);
-        if path.exists() && path.extension().map_or(false, |ext| ext == "gguf") {
+        if path.exists() && path.extension().is_some_and(|ext| ext == "gguf") {
             rs_log_info(cstr(&format!("Using model from {}: {}", 
                                       config.environment_variables.model_path_var, model_path)).as_ptr());
             return Some(path);
@@ -1085,8 +1874,6 @@ fn create_gguf_context_params() -> llama_context_params {
     
     // Optimize for text generation
     params.embeddings = false;
-    params.rope_freq_base = 10000.0;
-    params.rope_freq_scale = 1.0;
     
     // Enable performance optimizations
     params.offload_kqv = true;
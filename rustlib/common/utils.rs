@@ -236,7 +236,7 @@ pub fn load_engine_config(filepath: &str, config: &mut EngineConfig) -> Result<(
     Ok(())
 }
 
-/// Model configuration structure
+// Model configuration structure
 // ModelConfig is now imported from model.rs
 
 /// Structure representing validation status
@@ -263,8 +263,10 @@ impl Default for ValidationStatus {
 
 /// Function to perform secondary validation of model configuration
 pub fn validate_model_config(config_path: &str) -> Result<ValidationStatus, Box<dyn std::error::Error>> {
-    let mut validation = ValidationStatus::default();
-    validation.validation_time = get_current_time();
+    let mut validation = ValidationStatus {
+        validation_time: get_current_time(),
+        ..Default::default()
+    };
 
     // 1. Check file existence
     if !std::path::Path::new(config_path).exists() {
@@ -338,6 +340,7 @@ pub fn save_validation_result(config_path: &str, validation: &ValidationStatus)
 
 /// FFI functions for C++ calls
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn rust_validate_model_config(config_path: *const std::os::raw::c_char) -> std::os::raw::c_int {
     // Initialize logging if not already done
     let _ = init_logging();
@@ -408,17 +411,15 @@ pub fn run_llm_engine(config_path: &str) -> Result<(), Box<dyn std::error::Error
         // Find first .gguf file in models/ directory
         let models_dir = std::path::Path::new("models");
         if let Ok(entries) = std::fs::read_dir(models_dir) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if path.extension().and_then(|s| s.to_str()) == Some("gguf") {
-                        log_info!("Found model: {}", path.display());
-                        return run_llm_with_model(&path.to_string_lossy(), &config);
-                    }
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().and_then(|s| s.to_str()) == Some("gguf") {
+                    log_info!("Found model: {}", path.display());
+                    return run_llm_with_model(&path.to_string_lossy(), &config);
                 }
             }
         }
-        return Err(format!("No GGUF model found in models/ directory").into());
+        return Err("No GGUF model found in models/ directory".into());
     }
     
     run_llm_with_model(&config.model_path, &config)
@@ -433,7 +434,19 @@ fn run_llm_with_model(model_path: &str, config: &ModelConfig) -> Result<(), Box<
     log_info!("   - Prefer quantized: {}", config.model_preferences.prefer_quantized);
     log_info!("   - Max file size: {} GB", config.model_preferences.max_file_size_gb);
     log_info!("   - Min file size: {} MB", config.model_preferences.min_file_size_mb);
-    
+
+    // `--override-kv` flags apply to the model being run, after its models.json overrides
+    let args: Vec<String> = std::env::args().collect();
+    let cli_overrides = crate::llmrust::common::arg::common_params_parse_kv_overrides(&args)?;
+    let mut config = config.clone();
+    config.model_path = model_path.to_string();
+    if !cli_overrides.is_empty() {
+        let file_name = std::path::Path::new(model_path).file_name().map_or(model_path.into(), |n| n.to_string_lossy());
+        let specs = config.kv_overrides.entry(file_name.into_owned()).or_default();
+        specs.extend(cli_overrides.iter().map(ToString::to_string));
+    }
+    let config = &config;
+
     // Model loading
     log_info!("Initializing model context...");
     std::thread::sleep(std::time::Duration::from_millis(500));
@@ -458,6 +471,9 @@ fn start_llm_api_server_with_engine(config: &ModelConfig) -> Result<(), Box<dyn
     log_info!("");
     log_info!("API Endpoints:");
     log_info!("  POST /v1/chat/completions - Chat completions");
+    log_info!("  POST /v1/embeddings       - Embeddings (OpenAI-compatible)");
+    log_info!("  POST /v1/rerank           - Rerank documents against a query");
+    log_info!("  GET  /lora-adapters       - List loaded LoRA adapters");
     log_info!("  GET  /v1/models           - List available models");
     log_info!("  GET  /health              - Health check");
     log_info!("  POST /stop                - Graceful server shutdown");
@@ -484,14 +500,21 @@ fn start_llm_api_server_with_engine(config: &ModelConfig) -> Result<(), Box<dyn
     log_info!("");
 
     let config_clone = config.clone();
+    let kv_pool = Arc::new(ServerKvPool::new(config)?);
+    log_info!(
+        "KV cache: {} blocks of {} tokens shared by all requests",
+        config.kv_blocks,
+        config.kv_block_size
+    );
     
     let http_handle = thread::spawn(move || {
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
                     let config = config_clone.clone();
+                    let kv_pool = Arc::clone(&kv_pool);
                     thread::spawn(move || {
-                        handle_client(stream, &config);
+                        handle_client(stream, &config, &kv_pool);
                     });
                 }
                 Err(e) => {
@@ -541,14 +564,13 @@ fn start_llm_api_server_with_engine(config: &ModelConfig) -> Result<(), Box<dyn
     Ok(())
 }
 
-fn handle_client(mut stream: std::net::TcpStream, config: &ModelConfig) {
-    use std::io::{Read, Write};
+fn handle_client(mut stream: std::net::TcpStream, config: &ModelConfig, kv_pool: &ServerKvPool) {
+    use std::io::Write;
     
-    let mut buffer = [0; 1024];
     let client_addr = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown".to_string());
     
-    if let Ok(bytes_read) = stream.read(&mut buffer) {
-        let request = String::from_utf8_lossy(&buffer[0..bytes_read]);
+    if let Ok(request) = read_request(&mut stream) {
+        let request = String::from_utf8_lossy(&request);
         let lines: Vec<&str> = request.lines().collect();
         
         if let Some(first_line) = lines.first() {
@@ -581,7 +603,7 @@ fn handle_client(mut stream: std::net::TcpStream, config: &ModelConfig) {
                                 (create_json_response(400, error_response), 400)
                             } else {
                                 match serde_json::from_str::<serde_json::Value>(body) {
-                                    Ok(_) => (handle_chat_completion(body, config), 200),
+                                    Ok(json) => handle_chat_completion(body, &json, config, kv_pool),
                                     Err(_) => {
                                         let error_response = r#"{"error": "Invalid JSON format"}"#;
                                         (create_json_response(400, error_response), 400)
@@ -593,6 +615,17 @@ fn handle_client(mut stream: std::net::TcpStream, config: &ModelConfig) {
                             (create_json_response(400, error_response), 400)
                         }
                     }
+                    ("POST", "/v1/embeddings") | ("POST", "/embeddings") => match request_json(&request) {
+                        Ok(json) => handle_embeddings(&json, config),
+                        Err(response) => response,
+                    },
+                    ("POST", "/v1/rerank") | ("POST", "/rerank") => match request_json(&request) {
+                        Ok(json) => handle_rerank(&json, config, &kv_pool.vocab),
+                        Err(response) => response,
+                    },
+                    ("GET", "/lora-adapters") => {
+                        (create_json_response(200, &kv_pool.lora_adapters_json().to_string()), 200)
+                    }
                     ("POST", "/stop") | ("GET", "/stop") => {
                         let stop_response = r#"{"message": "Server shutdown initiated", "status": "stopping", "timestamp": ""}"#;
                         let timestamp = std::time::SystemTime::now()
@@ -661,6 +694,46 @@ fn handle_client(mut stream: std::net::TcpStream, config: &ModelConfig) {
     }
 }
 
+/// Reads one HTTP request: the headers and a body of `Content-Length` bytes
+fn read_request(stream: &mut impl std::io::Read) -> std::io::Result<Vec<u8>> {
+    const MAX_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let n = stream.read(&mut buffer)?;
+        request.extend_from_slice(&buffer[..n]);
+        let Some(header_end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if n == 0 || request.len() > MAX_REQUEST_BYTES {
+                return Ok(request);
+            }
+            continue;
+        };
+        let headers = String::from_utf8_lossy(&request[..header_end]);
+        let content_length = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0)
+            .min(MAX_REQUEST_BYTES);
+        if n == 0 || request.len() >= header_end + 4 + content_length {
+            return Ok(request);
+        }
+    }
+}
+
+/// JSON body of a request, or the error response to send back
+fn request_json(request: &str) -> Result<serde_json::Value, (String, u16)> {
+    let Some(body_start) = request.find("\r\n\r\n") else {
+        return Err((create_json_response(400, r#"{"error": "Invalid request format"}"#), 400));
+    };
+    let body = &request[body_start + 4..];
+    if body.trim().is_empty() {
+        return Err((create_json_response(400, r#"{"error": "Request body is empty"}"#), 400));
+    }
+    serde_json::from_str(body).map_err(|_| (create_json_response(400, r#"{"error": "Invalid JSON format"}"#), 400))
+}
+
 /// Get HTTP status text for status code
 fn get_status_text(status_code: u16) -> &'static str {
     match status_code {
@@ -668,6 +741,7 @@ fn get_status_text(status_code: u16) -> &'static str {
         400 => "Bad Request",
         404 => "Not Found",
         500 => "Internal Server Error",
+        503 => "Service Unavailable",
         _ => "Unknown",
     }
 }
@@ -683,7 +757,7 @@ fn create_json_response(status_code: u16, json_body: &str) -> String {
 }
 
 /// Handle chat completion requests
-fn handle_chat_completion(body: &str, _config: &ModelConfig) -> String {
+fn handle_chat_completion(body: &str, json: &serde_json::Value, config: &ModelConfig, kv_pool: &ServerKvPool) -> (String, u16) {
     // 요청 내용 로깅
     let truncated_body = if body.len() > 100 {
         format!("{}...", &body[0..100])
@@ -692,18 +766,226 @@ fn handle_chat_completion(body: &str, _config: &ModelConfig) -> String {
     };
     log_info!("📝 Processing chat completion request: {}", truncated_body);
 
-    let response_content = if body.contains("Hello") || body.contains("hello") || body.contains("hi") {
+    if json.get("grammar").is_some() && json.get("response_format").is_some() {
+        let message = "`grammar` and `response_format` cannot be used together";
+        log_error!("{}", message);
+        return (create_error_response(400, "invalid_request_error", message), 400);
+    }
+
+    let mut grammar = None;
+    let mut sampling = crate::llmrust::common::sampling::CommonParamsSampling::default();
+
+    // Structured output: response_format is enforced through a grammar built from the schema
+    if let Some(response_format) = json.get("response_format") {
+        match response_format_grammar(response_format) {
+            Ok(Some((format_type, grammar_text, format_grammar))) => {
+                log_info!("Structured output enabled: {} ({} rules)", format_type, format_grammar.rules().len());
+                sampling.grammar = grammar_text;
+                grammar = Some(format_grammar);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                log_error!("Unsupported response_format: {}", e);
+                return (create_error_response(400, "invalid_response_format", &e), 400);
+            }
+        }
+    }
+
+    // Grammar-constrained sampling: a built-in grammar name or inline GBNF
+    if let Some(grammar_spec) = json.get("grammar").and_then(|g| g.as_str()) {
+        match crate::llmrust::grammars::resolve(grammar_spec) {
+            Ok(request_grammar) => {
+                let grammar_name = crate::llmrust::grammars::lookup(grammar_spec.trim())
+                    .map(|g| g.name)
+                    .unwrap_or("inline");
+                log_info!("Grammar-constrained sampling enabled: {} ({} rules)", grammar_name, request_grammar.rules().len());
+                sampling.grammar = crate::llmrust::grammars::source(grammar_spec).to_string();
+                grammar = Some(request_grammar);
+            }
+            Err(e) => {
+                log_error!("Invalid grammar: {}", e);
+                return (create_error_response(400, "invalid_grammar", &e), 400);
+            }
+        }
+    }
+
+    // Regex-constrained sampling: the pattern is compiled to a DFA and masks tokens like a grammar
+    if let Some(pattern) = json.get("regex") {
+        if grammar.is_some() {
+            let message = "`regex` cannot be combined with `grammar` or `response_format`";
+            log_error!("{}", message);
+            return (create_error_response(400, "invalid_request_error", message), 400);
+        }
+        let compiled = pattern
+            .as_str()
+            .ok_or_else(|| "regex must be a string".to_string())
+            .and_then(crate::llmrust::src::llama_regex::RegexDfa::compile);
+        match compiled {
+            Ok(dfa) => {
+                log_info!("Regex-constrained sampling enabled: {} ({} DFA states)", dfa.pattern(), dfa.n_states());
+                sampling.regex = dfa.pattern().to_string();
+            }
+            Err(e) => {
+                log_error!("Invalid regex: {}", e);
+                return (create_error_response(400, "invalid_regex", &e), 400);
+            }
+        }
+    }
+
+    // Lazy grammar: generation is free until a trigger (by default the chat template's
+    // tool-call opener) appears, then the grammar is enforced from the trigger onwards
+    if json.get("grammar_lazy").and_then(|v| v.as_bool()).unwrap_or(false) {
+        let chat_format = config
+            .chat_template
+            .as_deref()
+            .map(crate::llmrust::common::chat::ChatFormat::from_template)
+            .unwrap_or(crate::llmrust::common::chat::ChatFormat::ContentOnly);
+        let triggers = lazy_grammar_triggers(json, chat_format, &kv_pool.vocab);
+        let lazy_grammar = match grammar {
+            Some(grammar) => triggers.and_then(|t| grammar.into_lazy(t)),
+            None => Err("grammar_lazy requires `grammar` or `response_format`".to_string()),
+        };
+        match lazy_grammar {
+            Ok(grammar) => {
+                log_info!(
+                    "Lazy grammar enabled: waiting for {} trigger(s) (chat format: {})",
+                    grammar.triggers().len(),
+                    chat_format.name()
+                );
+                sampling.grammar_lazy = true;
+                sampling.grammar_triggers = grammar.triggers().to_vec();
+            }
+            Err(e) => {
+                log_error!("Invalid lazy grammar: {}", e);
+                return (create_error_response(400, "invalid_grammar", &e), 400);
+            }
+        }
+    }
+
+    // Logit bias and banned strings; string keys are resolved through the model's tokenizer
+    if let Some(logit_bias) = json.get("logit_bias") {
+        match crate::llmrust::common::sampling::parse_logit_bias(logit_bias) {
+            Ok(entries) => {
+                let n_text = entries
+                    .iter()
+                    .filter(|e| matches!(e.key, crate::llmrust::common::sampling::LogitBiasKey::Text(_)))
+                    .count();
+                log_info!("Logit bias: {} entries ({} by token id, {} by text)", entries.len(), entries.len() - n_text, n_text);
+                sampling.logit_bias = entries;
+            }
+            Err(e) => {
+                log_error!("Invalid logit_bias: {}", e);
+                return (create_error_response(400, "invalid_request_error", &e), 400);
+            }
+        }
+    }
+    if let Some(banned) = json.get("banned_strings") {
+        let strings: Option<Vec<&str>> = banned
+            .as_array()
+            .and_then(|items| items.iter().map(|s| s.as_str().filter(|s| !s.is_empty())).collect());
+        match strings {
+            Some(strings) => {
+                log_info!("Banned strings: {}", strings.len());
+                sampling.banned_strings = strings.into_iter().map(str::to_string).collect();
+            }
+            None => {
+                let message = "banned_strings must be an array of non-empty strings";
+                log_error!("{}", message);
+                return (create_error_response(400, "invalid_request_error", message), 400);
+            }
+        }
+    }
+
+    // Token accounting: the slot's decode loop runs against an n_ctx-sized KV cache
+    let n_predict = match json.get("max_tokens").or_else(|| json.get("n_predict")) {
+        None => 20,
+        Some(v) => match v.as_u64().filter(|&n| n <= i32::MAX as u64) {
+            Some(n) => n as u32,
+            None => {
+                let message = format!("max_tokens must be an integer in [0, {}]", i32::MAX);
+                log_error!("{}", message);
+                return (create_error_response(400, "invalid_request_error", &message), 400);
+            }
+        },
+    };
+    let n_keep = match json.get("n_keep") {
+        None => 0,
+        Some(v) => match v.as_i64().filter(|&n| n >= -1) {
+            Some(n) => n as i32,
+            None => {
+                let message = "n_keep must be an integer >= -1";
+                log_error!("{}", message);
+                return (create_error_response(400, "invalid_request_error", message), 400);
+            }
+        },
+    };
+    let cache_prompt = json.get("cache_prompt").and_then(|v| v.as_bool()).unwrap_or(true);
+    // Control vectors: configured files selected by index, summed with per-request strengths
+    let cvec = match kv_pool.control_vector(json, config) {
+        Ok(cvec) => cvec,
+        Err(e) => {
+            log_error!("Invalid control_vectors: {}", e);
+            return (create_error_response(400, "invalid_request_error", &e), 400);
+        }
+    };
+    if let Some(cvec) = &cvec {
+        let (start, end) = cvec.layer_range();
+        log_info!("Control vector enabled on layers {}-{}", start, end);
+    }
+    // LoRA adapters: preloaded adapters selected by index with per-request scales
+    let lora = match kv_pool.lora_request(json) {
+        Ok(lora) => lora,
+        Err(e) => {
+            log_error!("Invalid lora: {}", e);
+            return (create_error_response(400, "invalid_request_error", &e), 400);
+        }
+    };
+    if !lora.is_empty() {
+        log_info!("LoRA adapters enabled: {:?}", lora);
+    }
+    let sampler = match crate::llmrust::common::sampling::common_sampler_init(Arc::clone(&kv_pool.vocab), &sampling) {
+        Ok(sampler) => sampler,
+        Err(e) => {
+            log_error!("Invalid sampling parameters: {}", e);
+            return (create_error_response(400, "invalid_request_error", &e), 400);
+        }
+    };
+
+    // The simulated model answers with a canned reply, which constrained sampling may override
+    let reply = if body.contains("Hello") || body.contains("hello") || body.contains("hi") {
         "Hello! I'm an LLM running on Rust via HTTP API. How can I help you today?"
     } else if body.contains("config") {
         "Current model configuration: temperature=0.7, top_p=0.9, context_size=2048"
     } else if body.contains("error") {
         // 에러 시뮬레이션
         log_error!("Simulating internal error for testing");
-        return create_json_response(500, r#"{"error": "Internal server error", "message": "Simulated processing error"}"#);
+        return (create_json_response(500, r#"{"error": "Internal server error", "message": "Simulated processing error"}"#), 500);
     } else {
         "I received your message. This is a simulated response from the LLM HTTP API."
     };
-    
+
+    let params = SlotParams {
+        n_predict,
+        n_keep,
+        cache_prompt,
+        adapters: kv_pool.adapters(lora, cvec),
+        sampler,
+        reply: kv_pool.vocab.tokenize(reply),
+    };
+    let prompt = prompt_tokens(json);
+    let generation = match run_slot_generation(&prompt, &params, config, kv_pool) {
+        Ok(generation) => generation,
+        Err(e) => {
+            let (status, error_type, message) = e.response();
+            log_error!("{}", message);
+            return (create_error_response(status, error_type, message), status);
+        }
+    };
+
+    let usage = &generation.usage;
+    let response_content = kv_pool.vocab.detokenize(&generation.tokens);
+    let finish_reason = if generation.eog { "stop" } else { "length" };
+
     let chat_response = format!(
         r#"{{
   "id": "chatcmpl-{}", 
@@ -714,23 +996,1184 @@ fn handle_chat_completion(body: &str, _config: &ModelConfig) -> String {
     "index": 0, 
     "message": {{
       "role": "assistant", 
-      "content": "{}"
+      "content": {}
     }}, 
-    "finish_reason": "stop"
+    "finish_reason": "{}"
   }}], 
   "usage": {{
-    "prompt_tokens": 10, 
-    "completion_tokens": 20, 
-    "total_tokens": 30
+    "prompt_tokens": {}, 
+    "completion_tokens": {}, 
+    "total_tokens": {},
+    "context_shifts": {},
+    "tokens_discarded": {}
+  }},
+  "timings": {{
+    "cache_n": {},
+    "prompt_n": {},
+    "prompt_ms": {:.3},
+    "predicted_n": {},
+    "predicted_ms": {:.3}
   }}
 }}"#,
         generate_id(),
         std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
-        response_content
+        serde_json::Value::from(response_content),
+        finish_reason,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.prompt_tokens + usage.completion_tokens,
+        usage.context_shifts,
+        usage.tokens_discarded,
+        usage.cached_tokens,
+        usage.prompt_tokens - usage.cached_tokens,
+        usage.prompt_ms,
+        usage.completion_tokens,
+        usage.predicted_ms
     );
     
     log_info!("💬 Generated chat completion response");
-    create_json_response(200, &chat_response)
+    (create_json_response(200, &chat_response), 200)
+}
+
+/// Output of one completion
+struct Generation {
+    /// Generated tokens, without the end of generation token
+    tokens: Vec<i32>,
+    /// Stopped at an end of generation token rather than at the token limit
+    eog: bool,
+    usage: GenerationUsage,
+}
+
+/// Token accounting of one completion
+#[derive(Debug, Default, PartialEq)]
+struct GenerationUsage {
+    prompt_tokens: u32,
+    /// Prompt tokens reused from the prompt cache instead of evaluated
+    cached_tokens: u32,
+    completion_tokens: u32,
+    /// Number of context shifts performed during generation
+    context_shifts: u32,
+    /// Tokens dropped from the context by those shifts
+    tokens_discarded: u32,
+    prompt_ms: f64,
+    predicted_ms: f64,
+}
+
+/// Rough prompt tokens: one token per whitespace-separated word of the message
+/// contents, with ids derived from the word so equal prompts give equal tokens
+fn prompt_tokens(json: &serde_json::Value) -> Vec<i32> {
+    json.get("messages")
+        .and_then(|m| m.as_array())
+        .map(|messages| {
+            messages
+                .iter()
+                .filter_map(|m| m.get("content").and_then(|c| c.as_str()))
+                .flat_map(text_tokens)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Rough tokens of a text: one per whitespace-separated word
+fn text_tokens(text: &str) -> Vec<i32> {
+    // FNV-1a, stable across runs
+    text.split_whitespace()
+        .map(|word| {
+            let hash = word.bytes().fold(0x811c_9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193));
+            (hash % SERVER_N_VOCAB as u32) as i32
+        })
+        .collect()
+}
+
+/// Why a generation could not run
+#[derive(Debug)]
+enum GenerationError {
+    /// The request does not fit in its context
+    ContextFull(String),
+    /// Not enough free KV blocks right now; the request may be retried
+    Busy(String),
+    /// The server failed while evaluating the request
+    Internal(String),
+}
+
+impl GenerationError {
+    /// HTTP status, error type and message reported to the client
+    fn response(&self) -> (u16, &'static str, &str) {
+        match self {
+            GenerationError::ContextFull(e) => (400, "exceed_context_size_error", e),
+            GenerationError::Busy(e) => (503, "unavailable_error", e),
+            GenerationError::Internal(e) => (500, "server_error", e),
+        }
+    }
+}
+
+/// BOS token of the mock vocabulary; an empty prompt is evaluated as BOS alone
+const SERVER_TOKEN_BOS: i32 = 1;
+const SERVER_TOKEN_EOS: i32 = 2;
+const SERVER_N_VOCAB: usize = 32000;
+/// Tool-call control tokens at the end of the vocab
+const SERVER_TOOL_TOKENS: [&str; 4] = ["<tool_call>", "</tool_call>", "<|python_tag|>", "[TOOL_CALLS]"];
+
+/// Triggers of a lazy grammar: the request's `grammar_triggers`, or else the
+/// chat format's tool-call openers, resolved to tokens where the vocab has them
+/// as control tokens.
+fn lazy_grammar_triggers(
+    json: &serde_json::Value,
+    chat_format: crate::llmrust::common::chat::ChatFormat,
+    vocab: &crate::llmrust::src::llama_vocab::LlamaVocab,
+) -> Result<Vec<crate::llmrust::src::llama_grammar::GrammarTrigger>, String> {
+    match json.get("grammar_triggers") {
+        Some(value) => crate::llmrust::common::chat::parse_grammar_triggers(value),
+        None => Ok(chat_format.grammar_triggers(Some(vocab))),
+    }
+}
+
+/// Mock vocabulary of the served model: `<unk>`, BOS and EOS, the 256 byte
+/// tokens, so that any text can be spelled, unused ids and, at the end of the
+/// vocab, the tool-call control tokens of the chat formats. The model's
+/// separator token, if it has one, takes the place of an unused id.
+fn server_vocab(sep: Option<i32>) -> crate::llmrust::src::llama_vocab::LlamaVocab {
+    use crate::llmrust::src::llama_vocab::{LlamaVocab, LlamaVocabType, LLAMA_TOKEN_NULL};
+
+    // GGUF token types: 3 control, 5 unused, 6 byte
+    let mut tokens: Vec<String> = ["<unk>", "<s>", "</s>"].iter().map(|t| t.to_string()).collect();
+    let mut token_types = vec![3; tokens.len()];
+    tokens.extend((0..=255u8).map(|b| format!("<0x{:02X}>", b)));
+    token_types.resize(tokens.len(), 6);
+    tokens.extend((tokens.len()..SERVER_N_VOCAB - SERVER_TOOL_TOKENS.len()).map(|i| format!("<unused{}>", i)));
+    token_types.resize(tokens.len(), 5);
+    tokens.extend(SERVER_TOOL_TOKENS.iter().map(|t| t.to_string()));
+    token_types.resize(tokens.len(), 3);
+    let sep = sep.filter(|&t| token_types.get(t as usize) == Some(&5));
+    if let Some(sep) = sep {
+        tokens[sep as usize] = "[SEP]".to_string();
+        token_types[sep as usize] = 3;
+    }
+    let mut vocab = LlamaVocab::new(LlamaVocabType::Spm, tokens, Vec::new(), token_types);
+    vocab.set_special_tokens(SERVER_TOKEN_BOS, SERVER_TOKEN_EOS, LLAMA_TOKEN_NULL, sep.unwrap_or(LLAMA_TOKEN_NULL));
+    vocab
+}
+
+/// Logits of the simulated model: `next` is far more likely than any other
+/// token, and end of generation is unlikely unless it is `next`.
+fn server_logits(n_vocab: usize, next: i32) -> Vec<f32> {
+    let mut logits = vec![0.0; n_vocab];
+    logits[SERVER_TOKEN_EOS as usize] = -20.0;
+    logits[next as usize] = 10.0;
+    logits
+}
+
+/// KV blocks shared by all requests. Instead of a fixed number of slots, a
+/// request is admitted when the blocks its context can grow to are free, so
+/// many short requests can run where only a few long ones would fit. Blocks of
+/// finished prompts are kept in a prompt cache until they are needed.
+///
+/// Admitted requests are evaluated together by a continuous batching
+/// scheduler: every forward pass carries the next token of each generating
+/// request plus chunks of pending prompts, up to `n_batch` tokens.
+pub struct ServerKvPool {
+    state: Mutex<ServerKvPoolState>,
+    /// Control vector files of `config.control_vectors`, in order
+    control_vectors: Vec<crate::llmrust::src::adapter_loader::LlamaControlVectorFile>,
+    /// LoRA adapters of `config.lora_adapters`, in order
+    lora_adapters: Vec<ServerLoraAdapter>,
+    /// Hyperparameters of the served model, after metadata overrides
+    hparams: crate::llmrust::src::llama_hparams::LlamaHparams,
+    vocab: Arc<crate::llmrust::src::llama_vocab::LlamaVocab>,
+}
+
+/// A preloaded LoRA adapter
+struct ServerLoraAdapter {
+    path: String,
+    /// Scale used by requests that do not select adapters
+    scale: f32,
+    adapter: Arc<crate::llmrust::src::llama_adapter::LlamaAdapterLora>,
+}
+
+struct ServerKvPoolState {
+    kv: crate::llmrust::src::llama_kv_cache::LlamaKvCachePaged,
+    cache: crate::llmrust::common::prompt_cache::PromptCache,
+    /// Blocks promised to admitted requests
+    reserved: usize,
+    next_seq_id: i32,
+    scheduler: crate::llmrust::src::batch_processor::BatchScheduler,
+    /// Requests being evaluated by the scheduler
+    slots: std::collections::BTreeMap<i32, ServerSlot>,
+    /// Results of finished requests, until their thread picks them up
+    finished: std::collections::BTreeMap<i32, Result<Generation, GenerationError>>,
+    /// Inference context shared by all requests
+    ctx: crate::llmrust::src::llama_context::LlamaContext,
+    /// Scheduler group whose adapters are set on `ctx`
+    ctx_group: u64,
+    vocab: Arc<crate::llmrust::src::llama_vocab::LlamaVocab>,
+}
+
+/// Generation settings of one request
+struct SlotParams {
+    n_predict: u32,
+    n_keep: i32,
+    cache_prompt: bool,
+    adapters: ServerAdapters,
+    /// Sampler chain built from the request's sampling options
+    sampler: crate::llmrust::src::llama_sampling::SamplerChain,
+    /// Tokens the simulated model continues the prompt with
+    reply: Vec<i32>,
+}
+
+/// Adapters a request runs with. The inference context applies one set to
+/// a whole forward pass, so only requests with equal sets share a batch.
+#[derive(Clone, Default)]
+struct ServerAdapters {
+    /// `(id, scale)` of the enabled LoRA adapters, by id; no zero scales
+    lora: Vec<(usize, f32)>,
+    loras: crate::llmrust::src::llama_adapter::LlamaAdapterLoras,
+    /// Control vector added to the residual stream
+    cvec: Option<Arc<crate::llmrust::src::llama_adapter::LlamaAdapterCvec>>,
+}
+
+impl ServerAdapters {
+    /// Scheduler group: 0 without adapters, otherwise a hash of the set
+    fn group(&self) -> u64 {
+        if self.lora.is_empty() && self.cvec.is_none() {
+            return 0;
+        }
+        let words = self
+            .lora
+            .iter()
+            .flat_map(|&(id, scale)| [id as u64, scale.to_bits() as u64])
+            .chain(self.cvec.iter().map(|cvec| cvec.fingerprint()));
+        // FNV-1a
+        words
+            .flat_map(|w| w.to_le_bytes())
+            .fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+            .max(1)
+    }
+}
+
+/// Per-request settings and counters of a scheduled sequence
+struct ServerSlot {
+    n_ctx: usize,
+    ctx_shift: bool,
+    n_keep: i32,
+    adapters: ServerAdapters,
+    sampler: crate::llmrust::src::llama_sampling::SamplerChain,
+    reply: Vec<i32>,
+    /// Tokens sampled so far, kept across context shifts
+    output: Vec<i32>,
+    /// Tokens the sampler took back after the last sample, still to be
+    /// dropped from the sequence
+    rollback: usize,
+    usage: GenerationUsage,
+    t_start: std::time::Instant,
+    /// Set when the prompt has been evaluated
+    t_predict: Option<std::time::Instant>,
+}
+
+impl ServerSlot {
+    /// Samples the next token from the simulated logits through the slot's
+    /// sampler chain and appends it to the output. When the chain takes
+    /// tokens back (a banned string was completed), they are removed from the
+    /// output and counted in `rollback`.
+    fn sample(&mut self, n_vocab: usize) -> i32 {
+        use crate::llmrust::src::llama_sampling::LlamaSampler;
+
+        let next = self.reply.get(self.output.len()).copied().unwrap_or(SERVER_TOKEN_EOS);
+        let token = self.sampler.sample(&server_logits(n_vocab, next));
+        self.sampler.accept(token);
+        self.output.push(token);
+        let rollback = self.sampler.take_rollback().min(self.output.len());
+        if rollback > 0 {
+            self.output.truncate(self.output.len() - rollback);
+            self.usage.completion_tokens -= rollback as u32;
+            self.rollback = rollback;
+        }
+        token
+    }
+}
+
+impl ServerKvPool {
+    pub fn new(config: &ModelConfig) -> Result<Self, String> {
+        use crate::llmrust::common::prompt_cache::PromptCache;
+        use crate::llmrust::ggml::src::ggml::GgmlType;
+        use crate::llmrust::src::adapter_loader::llama_adapter_lora_load;
+        use crate::llmrust::src::batch_processor::BatchScheduler;
+        use crate::llmrust::src::llama_arch::llama_tensor_shape;
+        use crate::llmrust::src::llama_batch::LlamaSplitStrategy;
+        use crate::llmrust::src::llama_context::LlamaContext;
+        use crate::llmrust::src::llama_cparams::{LlamaCparams, LlamaFlashAttnType};
+        use crate::llmrust::src::llama_kv_cache::LlamaKvCachePaged;
+
+        let cache_type = |name: &str| {
+            GgmlType::from_name(name).ok_or_else(|| format!("unsupported KV cache type '{}', expected f32, f16, q8_0 or q4_0", name))
+        };
+        let (type_k, type_v) = (cache_type(&config.cache_type_k)?, cache_type(&config.cache_type_v)?);
+        // the model's metadata overrides apply before anything depends on its shape
+        let overrides = config.model_kv_overrides(&config.model_path)?;
+        let info = crate::common::model::load_model_info(&config.model_path, overrides)?;
+        let hparams = info.hparams;
+        let flash_attn_type = LlamaFlashAttnType::from_name(&config.flash_attn)
+            .ok_or_else(|| format!("unknown flash attention setting '{}', expected auto, on or off", config.flash_attn))?;
+        let cparams = LlamaCparams { n_ctx: config.n_ctx, flash_attn_type, ..Default::default() };
+        let mut kv = LlamaKvCachePaged::new(&hparams, config.kv_blocks, config.kv_block_size, type_k, type_v)?;
+        kv.set_rope(cparams.rope_params(&hparams)?);
+        kv.set_flash_attn(cparams.flash_attn());
+        log_info!(
+            "KV cache: {} blocks of {} tokens, K ({}), V ({}), {:.2} MiB per block, flash attention {}",
+            config.kv_blocks,
+            config.kv_block_size,
+            type_k.name(),
+            type_v.name(),
+            kv.block_memory_size() as f64 / (1024.0 * 1024.0),
+            if kv.flash_attn() { "enabled" } else { "disabled" }
+        );
+        let cache = PromptCache::new(config.prompt_cache_mib as usize * 1024 * 1024);
+        let mut scheduler = BatchScheduler::new(config.n_batch as usize, config.n_ubatch as usize)?;
+        let split = LlamaSplitStrategy::from_name(&config.batch_split)
+            .ok_or_else(|| format!("unknown batch split '{}', expected simple, equal or seq", config.batch_split))?;
+        scheduler.set_split(split);
+        let control_vectors = config
+            .control_vectors
+            .iter()
+            .map(crate::llmrust::src::adapter_loader::llama_control_vector_load)
+            .collect::<Result<Vec<_>, _>>()?;
+        for (id, path) in config.control_vectors.iter().enumerate() {
+            log_info!("Control vector {}: {}", id, path);
+        }
+        let vocab = Arc::new(server_vocab(info.sep_token));
+        let mut lora_adapters = Vec::new();
+        for (id, lora) in config.lora_adapters.iter().enumerate() {
+            let adapter = llama_adapter_lora_load(&lora.path, &info.arch, |name| llama_tensor_shape(&hparams, name))?;
+            log_info!("LoRA adapter {}: {} (scale {})", id, lora.path, lora.scale);
+            lora_adapters.push(ServerLoraAdapter { path: lora.path.clone(), scale: lora.scale, adapter: Arc::new(adapter) });
+        }
+        Ok(Self {
+            state: Mutex::new(ServerKvPoolState {
+                kv,
+                cache,
+                reserved: 0,
+                next_seq_id: 0,
+                scheduler,
+                slots: Default::default(),
+                finished: Default::default(),
+                ctx: LlamaContext::new(hparams.fingerprint(), config.n_ctx, None),
+                ctx_group: 0,
+                vocab: Arc::clone(&vocab),
+            }),
+            control_vectors,
+            lora_adapters,
+            hparams,
+            vocab,
+        })
+    }
+
+    /// `GET /lora-adapters`: the preloaded adapters and their default scales
+    fn lora_adapters_json(&self) -> serde_json::Value {
+        let adapters: Vec<serde_json::Value> = self
+            .lora_adapters
+            .iter()
+            .enumerate()
+            .map(|(id, lora)| {
+                let mut entry = serde_json::json!({ "id": id, "path": lora.path, "scale": lora.scale });
+                for (key, field) in [("adapter.lora.task_name", "task_name"), ("adapter.lora.prompt_prefix", "prompt_prefix")] {
+                    if let Some(value) = lora.adapter.meta_val_str(key) {
+                        entry[field] = serde_json::Value::from(value);
+                    }
+                }
+                entry
+            })
+            .collect();
+        serde_json::Value::Array(adapters)
+    }
+
+    /// `(id, scale)` of the adapters a request enables: its `lora` field
+    /// (`[{"id": 0, "scale": 0.5}, ...]`, unlisted adapters disabled) or the
+    /// default scales. Zero scales are left out.
+    fn lora_request(&self, json: &serde_json::Value) -> Result<Vec<(usize, f32)>, String> {
+        let scales: Vec<f32> = match json.get("lora") {
+            None => self.lora_adapters.iter().map(|lora| lora.scale).collect(),
+            Some(entries) => {
+                let entries = entries.as_array().ok_or("lora must be an array of {id, scale} objects")?;
+                let mut scales = vec![0.0; self.lora_adapters.len()];
+                for entry in entries {
+                    let id = entry.get("id").and_then(|v| v.as_u64()).ok_or("each lora entry needs an integer id")? as usize;
+                    let scale = entry.get("scale").and_then(|v| v.as_f64()).ok_or("each lora entry needs a numeric scale")?;
+                    *scales
+                        .get_mut(id)
+                        .ok_or_else(|| format!("unknown lora id {} ({} adapters loaded)", id, self.lora_adapters.len()))? = scale as f32;
+                }
+                scales
+            }
+        };
+        Ok(scales.into_iter().enumerate().filter(|&(_, scale)| scale != 0.0).collect())
+    }
+
+    fn adapters(&self, lora: Vec<(usize, f32)>, cvec: Option<Arc<crate::llmrust::src::llama_adapter::LlamaAdapterCvec>>) -> ServerAdapters {
+        let mut loras = crate::llmrust::src::llama_adapter::LlamaAdapterLoras::default();
+        for &(id, scale) in &lora {
+            loras.set(Arc::clone(&self.lora_adapters[id].adapter), scale);
+        }
+        ServerAdapters { lora, loras, cvec }
+    }
+
+    /// Control vector selected by the request's `control_vectors`
+    /// (`[{"id": 0, "strength": 0.8}, ...]`, ids indexing the configured
+    /// files), summed with the strengths and applied to
+    /// `control_vector_layer_range` or the configured range. None if the
+    /// request selects nothing.
+    fn control_vector(
+        &self,
+        json: &serde_json::Value,
+        config: &ModelConfig,
+    ) -> Result<Option<Arc<crate::llmrust::src::llama_adapter::LlamaAdapterCvec>>, String> {
+        use crate::llmrust::common::common::CommonControlVectorData;
+        use crate::llmrust::src::llama_adapter::LlamaAdapterCvec;
+
+        let Some(selection) = json.get("control_vectors") else {
+            return Ok(None);
+        };
+        let entries = selection.as_array().ok_or("control_vectors must be an array of {id, strength} objects")?;
+        let mut data = CommonControlVectorData::default();
+        for entry in entries {
+            let id = entry.get("id").and_then(|v| v.as_u64()).ok_or("each control vector needs an integer id")?;
+            let file = self
+                .control_vectors
+                .get(id as usize)
+                .ok_or_else(|| format!("unknown control vector id {} ({} configured)", id, self.control_vectors.len()))?;
+            let strength = match entry.get("strength") {
+                None => 1.0,
+                Some(v) => v.as_f64().ok_or("control vector strength must be a number")? as f32,
+            };
+            data.add(file, strength)?;
+        }
+        if data.n_embd == 0 {
+            return Ok(None);
+        }
+        let hparams = &self.hparams;
+        let range = match json.get("control_vector_layer_range") {
+            None => config.control_vector_layer_range,
+            Some(v) => Some(
+                serde_json::from_value::<[i32; 2]>(v.clone())
+                    .map_err(|_| "control_vector_layer_range must be [start, end]".to_string())?,
+            ),
+        };
+        let [start, end] = range.unwrap_or([1, hparams.n_layer as i32]);
+        if start > end {
+            return Err(format!("control_vector_layer_range [{}, {}] is empty", start, end));
+        }
+        LlamaAdapterCvec::new(hparams, &data.data, data.n_embd, start, end).map(|cvec| Some(Arc::new(cvec)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ServerKvPoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reserves the blocks for a sequence of up to `n_tokens` tokens and returns
+    /// the sequence id to use. Cached prompts are evicted to make room.
+    fn admit(&self, n_tokens: usize) -> Result<(i32, usize), GenerationError> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let need = state.kv.blocks_for(n_tokens);
+        let total = state.kv.n_blocks();
+        if need > total {
+            return Err(GenerationError::ContextFull(format!(
+                "context full: the request needs {} KV blocks but the cache only has {}",
+                need, total
+            )));
+        }
+        if need > total - state.reserved {
+            return Err(GenerationError::Busy(format!(
+                "server busy: the request needs {} KV blocks but only {} are free",
+                need,
+                total - state.reserved
+            )));
+        }
+        while state.reserved + state.cache.n_blocks(&state.kv) + need > total {
+            match state.cache.evict_lru(&mut state.kv) {
+                Some(seq_id) => log_info!("Prompt cache: evicted seq {} to free KV blocks", seq_id),
+                None => break,
+            }
+        }
+        state.reserved += need;
+        let seq_id = state.next_seq_id;
+        state.next_seq_id = state.next_seq_id.wrapping_add(1).max(0);
+        Ok((seq_id, need))
+    }
+
+    /// Frees the sequence's reservation. With `prompt` the sequence is kept
+    /// in the prompt cache holding those tokens, under the adapter `group` it
+    /// was evaluated with, otherwise its cells are freed.
+    fn release(&self, seq_id: i32, reserved: usize, group: u64, prompt: Option<&[i32]>) {
+        use crate::llmrust::src::llama_memory::LlamaMemory;
+
+        let mut state = self.lock();
+        let state = &mut *state;
+        match prompt {
+            Some(tokens) => {
+                state.kv.seq_rm(seq_id, tokens.len() as i32, -1);
+                state.cache.insert(&mut state.kv, seq_id, group, tokens.to_vec());
+            }
+            None => {
+                state.kv.seq_rm(seq_id, -1, -1);
+            }
+        }
+        state.reserved -= reserved;
+    }
+
+    /// Hands an admitted sequence to the scheduler. The longest cached prefix
+    /// of the prompt is reused and only the rest is evaluated.
+    fn submit(&self, seq_id: i32, prompt: &[i32], params: &SlotParams, config: &ModelConfig) -> Result<(), GenerationError> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let n_prompt = prompt.len();
+        let mut usage = GenerationUsage { prompt_tokens: n_prompt as u32, ..Default::default() };
+        // the last prompt token is always evaluated to get logits
+        // cells computed with other adapters cannot be reused
+        let group = params.adapters.group();
+        let hit = if params.cache_prompt { state.cache.lookup(group, prompt) } else { None };
+        if let Some(hit) = hit.filter(|_| n_prompt > 1) {
+            let n_cached = hit.n_tokens.min(n_prompt - 1);
+            state.kv.try_seq_cp(hit.seq_id, seq_id, 0, n_cached as i32).map_err(GenerationError::Internal)?;
+            usage.cached_tokens = n_cached as u32;
+            log_info!("Prompt cache: reusing {} of {} prompt tokens from seq {}", n_cached, n_prompt, hit.seq_id);
+        }
+        // requests with different adapters cannot share a forward pass
+        state
+            .scheduler
+            .add_to_group(seq_id, prompt.to_vec(), usage.cached_tokens as usize, params.n_predict as usize, group)
+            .map_err(GenerationError::Internal)?;
+        state.slots.insert(
+            seq_id,
+            ServerSlot {
+                n_ctx: config.n_ctx as usize,
+                ctx_shift: config.ctx_shift,
+                n_keep: if params.n_keep < 0 { n_prompt as i32 } else { params.n_keep.min(n_prompt as i32) },
+                adapters: params.adapters.clone(),
+                sampler: params.sampler.clone(),
+                reply: params.reply.clone(),
+                output: Vec::new(),
+                rollback: 0,
+                usage,
+                t_start: std::time::Instant::now(),
+                t_predict: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Runs scheduler steps until the sequence has finished. Whichever waiting
+    /// request holds the lock steps every scheduled sequence, so concurrent
+    /// requests share forward passes.
+    fn wait(&self, seq_id: i32) -> Result<Generation, GenerationError> {
+        loop {
+            let mut state = self.lock();
+            if let Some(result) = state.finished.remove(&seq_id) {
+                return result;
+            }
+            if !state.step() {
+                state.fail(seq_id, GenerationError::Internal("generation stalled: nothing left to evaluate".to_string()));
+            }
+        }
+    }
+}
+
+impl ServerKvPoolState {
+    /// One forward pass over the next batch. Generation is simulated: the
+    /// K/V cells of every token are stored and every output row is sampled
+    /// by its request's sampler chain from the simulated logits. Returns
+    /// false when nothing was scheduled.
+    fn step(&mut self) -> bool {
+        use std::time::Instant;
+
+        // make room for the next token of sequences that filled their context
+        let full: Vec<i32> = self
+            .scheduler
+            .sequences()
+            .iter()
+            .filter(|s| s.is_generating() && s.n_past < s.tokens.len())
+            .filter(|s| self.slots.get(&s.seq_id).is_some_and(|slot| s.n_past >= slot.n_ctx))
+            .map(|s| s.seq_id)
+            .collect();
+        for seq_id in full {
+            // a sequence that cannot be shifted does not fit in its context
+            if let Err(e) = self.shift_context(seq_id) {
+                self.fail(seq_id, GenerationError::ContextFull(e));
+            }
+        }
+
+        let Some(scheduled) = self.scheduler.next_batch() else {
+            return false;
+        };
+        self.set_adapters(&scheduled);
+        for ubatch in &scheduled.ubatches {
+            for &i in &ubatch.idxs {
+                for &seq_id in &scheduled.batch.seq_id[i] {
+                    if !self.slots.contains_key(&seq_id) {
+                        continue;
+                    }
+                    if let Err(e) = self.kv.append(seq_id, scheduled.batch.pos[i]) {
+                        self.fail(seq_id, GenerationError::Internal(e));
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let n_vocab = self.vocab.n_tokens();
+        let mut sampled = Vec::with_capacity(scheduled.outputs.len());
+        for seq_id in &scheduled.outputs {
+            // rows of failed sequences are skipped by `commit`
+            let Some(slot) = self.slots.get_mut(seq_id) else {
+                sampled.push(SERVER_TOKEN_EOS);
+                continue;
+            };
+            if slot.t_predict.is_none() {
+                slot.usage.prompt_ms = now.duration_since(slot.t_start).as_secs_f64() * 1000.0;
+                slot.t_predict = Some(now);
+            }
+            slot.usage.completion_tokens += 1;
+            sampled.push(slot.sample(n_vocab));
+        }
+        let vocab = Arc::clone(&self.vocab);
+        let finished = self.scheduler.commit(&scheduled, &sampled, |token| vocab.is_eog(token)).unwrap_or_default();
+        for seq_id in &scheduled.outputs {
+            if let Err(e) = self.rollback(*seq_id) {
+                self.fail(*seq_id, GenerationError::Internal(e));
+            }
+        }
+        for seq in finished {
+            if let Some(mut slot) = self.slots.remove(&seq.seq_id) {
+                match slot.t_predict {
+                    Some(t_predict) => slot.usage.predicted_ms = t_predict.elapsed().as_secs_f64() * 1000.0,
+                    None => slot.usage.prompt_ms = slot.t_start.elapsed().as_secs_f64() * 1000.0,
+                }
+                let eog = matches!(seq.stop, Some(crate::llmrust::src::batch_processor::BatchStopReason::Eog));
+                if eog {
+                    slot.output.pop();
+                }
+                self.finished.insert(seq.seq_id, Ok(Generation { tokens: slot.output, eog, usage: slot.usage }));
+            }
+        }
+        true
+    }
+
+    /// Sets the adapters of the batch's group on the context, if another
+    /// group ran before.
+    fn set_adapters(&mut self, scheduled: &crate::llmrust::src::batch_processor::ScheduledBatch) {
+        if scheduled.group == self.ctx_group {
+            return;
+        }
+        let adapters = scheduled
+            .batch
+            .seq_id
+            .iter()
+            .flatten()
+            .find_map(|seq_id| self.slots.get(seq_id))
+            .map(|slot| slot.adapters.clone())
+            .unwrap_or_default();
+        self.ctx.clear_adapter_lora();
+        for (adapter, scale) in adapters.loras.iter() {
+            self.ctx.set_adapter_lora(Arc::clone(adapter), scale);
+        }
+        self.ctx.set_adapter_cvec(adapters.cvec.as_deref().cloned().unwrap_or_default());
+        self.ctx_group = scheduled.group;
+        log_info!("Adapters switched: {} LoRA adapters {:?}, control vector: {}", adapters.lora.len(), adapters.lora, adapters.cvec.is_some());
+    }
+
+    /// Discards half of the tokens after `n_keep` of a full sequence and
+    /// shifts the rest back; with context shifting disabled the request fails.
+    fn shift_context(&mut self, seq_id: i32) -> Result<(), String> {
+        use crate::llmrust::common::common::common_context_shift;
+
+        let (Some(slot), Some(seq)) = (self.slots.get_mut(&seq_id), self.scheduler.get_mut(seq_id)) else {
+            return Ok(());
+        };
+        if !slot.ctx_shift {
+            return Err(format!(
+                "context full: {} tokens reached the context size of {} and context shifting is disabled",
+                seq.n_past, slot.n_ctx
+            ));
+        }
+        // the sampled token waiting to be evaluated is not in memory yet
+        let pending = seq.tokens.split_off(seq.n_past);
+        let result = common_context_shift(&mut self.kv, seq_id, &mut seq.tokens, slot.n_keep);
+        seq.n_past = seq.tokens.len();
+        seq.tokens.extend(pending);
+        let shift = result?;
+        self.kv.update();
+        slot.usage.context_shifts += 1;
+        slot.usage.tokens_discarded += shift.n_discard as u32;
+        log_info!(
+            "Context shift: n_keep = {}, discarded {} tokens, n_past = {} (n_ctx = {})",
+            shift.n_keep,
+            shift.n_discard,
+            seq.n_past,
+            slot.n_ctx
+        );
+        Ok(())
+    }
+
+    /// Drops the tokens the slot's sampler took back from a running sequence
+    /// and its KV cells. The last kept token is evaluated again so that the
+    /// next token is sampled at the rolled back position. The sampler chain
+    /// has already rewound its grammar and regex samplers to the kept tokens.
+    fn rollback(&mut self, seq_id: i32) -> Result<(), String> {
+        use crate::llmrust::src::llama_memory::LlamaMemory;
+
+        let Some(slot) = self.slots.get_mut(&seq_id) else {
+            return Ok(());
+        };
+        let n = std::mem::take(&mut slot.rollback);
+        let Some(seq) = self.scheduler.get_mut(seq_id).filter(|_| n > 0) else {
+            return Ok(());
+        };
+        // tokens dropped by a context shift are gone already
+        let n = n.min(seq.tokens.len() - seq.n_prompt.min(seq.tokens.len()));
+        seq.tokens.truncate(seq.tokens.len() - n);
+        seq.n_past = seq.tokens.len() - 1;
+        seq.n_remain += n;
+        if !self.kv.seq_rm(seq_id, seq.n_past as i32, -1) {
+            return Err(format!("cannot roll back {} tokens of sequence {}", n, seq_id));
+        }
+        log_info!("Banned string: rolled back {} tokens of seq {}", n, seq_id);
+        Ok(())
+    }
+
+    /// Stops evaluating a sequence and reports `error` to its request.
+    fn fail(&mut self, seq_id: i32, error: GenerationError) {
+        self.scheduler.remove(seq_id);
+        self.slots.remove(&seq_id);
+        self.finished.entry(seq_id).or_insert(Err(error));
+    }
+}
+
+/// Runs a request on its own sequence of the shared paged KV cache, evaluated
+/// by the shared scheduler together with the other active requests. The
+/// sequence holds at most `config.n_ctx` tokens: when it is full, half of the
+/// tokens after `n_keep` are discarded and the rest shifted back
+/// (`n_keep = -1` keeps the whole prompt); with context shifting disabled the
+/// request fails instead.
+fn run_slot_generation(
+    prompt: &[i32],
+    params: &SlotParams,
+    config: &ModelConfig,
+    kv_pool: &ServerKvPool,
+) -> Result<Generation, GenerationError> {
+    let n_prompt = prompt.len() as u32;
+    let n_ctx = config.n_ctx;
+    if n_prompt >= n_ctx {
+        return Err(GenerationError::ContextFull(format!(
+            "context full: the prompt has {} tokens but the context size is {}",
+            n_prompt, n_ctx
+        )));
+    }
+
+    let n_tokens = n_prompt.saturating_add(params.n_predict).min(n_ctx) as usize;
+    let (seq_id, reserved) = kv_pool.admit(n_tokens)?;
+    let eval_prompt = if prompt.is_empty() { vec![SERVER_TOKEN_BOS] } else { prompt.to_vec() };
+    let result = kv_pool
+        .submit(seq_id, &eval_prompt, params, config)
+        .and_then(|()| kv_pool.wait(seq_id))
+        .map(|generation| Generation { usage: GenerationUsage { prompt_tokens: n_prompt, ..generation.usage }, ..generation });
+    // after a context shift the cells no longer match the prompt
+    let keep = params.cache_prompt && matches!(&result, Ok(generation) if generation.usage.context_shifts == 0);
+    kv_pool.release(seq_id, reserved, params.adapters.group(), keep.then_some(prompt));
+    result
+}
+
+/// Handle OpenAI-compatible embeddings requests; `input` is a string, an
+/// array of strings, an array of token ids or an array of token id arrays.
+fn handle_embeddings(json: &serde_json::Value, config: &ModelConfig) -> (String, u16) {
+    use crate::llmrust::common::common::common_embd_normalize;
+    use crate::llmrust::src::llama_hparams::LlamaPoolingType;
+
+    let inputs = match embedding_inputs(json) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            log_error!("Invalid embeddings request: {}", e);
+            return (create_error_response(400, "invalid_request_error", &e), 400);
+        }
+    };
+    let base64 = match json.get("encoding_format").map(|f| f.as_str()) {
+        None | Some(Some("float")) => false,
+        Some(Some("base64")) => true,
+        _ => {
+            let message = "encoding_format must be \"float\" or \"base64\"";
+            return (create_error_response(400, "invalid_request_error", message), 400);
+        }
+    };
+    let embd_normalize = match json.get("embd_normalize") {
+        None => config.embd_normalize,
+        Some(v) => match v.as_i64().filter(|&n| n >= -1) {
+            Some(n) => n as i32,
+            None => return (create_error_response(400, "invalid_request_error", "embd_normalize must be an integer >= -1"), 400),
+        },
+    };
+    let pooling = match LlamaPoolingType::from_name(&config.pooling) {
+        Some(LlamaPoolingType::None) => {
+            let message = "Pooling type 'none' is not OAI compatible. Please use a different pooling type";
+            return (create_error_response(400, "invalid_request_error", message), 400);
+        }
+        Some(LlamaPoolingType::Rank) => {
+            let message = "Pooling type 'rank' is only for reranking, use /v1/rerank";
+            return (create_error_response(400, "invalid_request_error", message), 400);
+        }
+        Some(pooling) => pooling,
+        None => {
+            let message = format!("unknown pooling type '{}' in the server configuration", config.pooling);
+            log_error!("{}", message);
+            return (create_error_response(500, "server_error", &message), 500);
+        }
+    };
+
+    let embeddings = match compute_embeddings(&mut ServerEmbeddingGraph, &inputs, pooling, config) {
+        Ok(embeddings) => embeddings,
+        Err(e) => {
+            log_error!("{}", e);
+            return (create_error_response(400, "invalid_request_error", &e), 400);
+        }
+    };
+    let n_tokens: usize = inputs.iter().map(|t| t.len()).sum();
+    let data: Vec<serde_json::Value> = embeddings
+        .iter()
+        .enumerate()
+        .map(|(index, embd)| {
+            let embd = common_embd_normalize(embd, embd_normalize);
+            let embedding = if base64 {
+                serde_json::Value::from(crate::llmrust::common::base64::encode_f32(&embd))
+            } else {
+                serde_json::Value::from(embd)
+            };
+            serde_json::json!({ "object": "embedding", "index": index, "embedding": embedding })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "object": "list",
+        "data": data,
+        "model": json.get("model").and_then(|m| m.as_str()).unwrap_or("llm-rust"),
+        "usage": { "prompt_tokens": n_tokens, "total_tokens": n_tokens },
+    });
+    log_info!("Computed {} embeddings ({} tokens, {} pooling)", embeddings.len(), n_tokens, pooling.name());
+    (create_json_response(200, &body.to_string()), 200)
+}
+
+/// Token sequences of an embeddings request; texts start with BOS (CLS)
+fn embedding_inputs(json: &serde_json::Value) -> Result<Vec<Vec<i32>>, String> {
+    let input = json.get("input").or_else(|| json.get("content")).ok_or("\"input\" is required")?;
+    let text = |value: &serde_json::Value| {
+        let text = value.as_str()?;
+        Some(if text.trim().is_empty() { Vec::new() } else { [vec![SERVER_TOKEN_BOS], text_tokens(text)].concat() })
+    };
+    let token_ids = |value: &serde_json::Value| -> Option<Vec<i32>> {
+        value.as_array()?.iter().map(|t| t.as_i64().filter(|&t| (0..SERVER_N_VOCAB as i64).contains(&t)).map(|t| t as i32)).collect()
+    };
+    let inputs: Vec<Vec<i32>> = match input {
+        serde_json::Value::String(_) => vec![text(input).unwrap_or_default()],
+        serde_json::Value::Array(items) if !items.is_empty() && items.iter().all(|i| i.is_number()) => {
+            vec![token_ids(input).ok_or_else(|| format!("token ids must be integers in [0, {})", SERVER_N_VOCAB))?]
+        }
+        serde_json::Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .map(|item| text(item).or_else(|| token_ids(item)))
+            .collect::<Option<_>>()
+            .ok_or("input must be a string, an array of strings or an array of token id arrays")?,
+        _ => return Err("input must be a non-empty string or array".to_string()),
+    };
+    if let Some(i) = inputs.iter().position(|t| t.is_empty()) {
+        return Err(format!("input {} is empty", i));
+    }
+    Ok(inputs)
+}
+
+/// Pooled embeddings of `inputs`, one sequence each, evaluated in batches of
+/// up to `n_batch` tokens. An encoder attends over the whole input, so every
+/// input must fit in one ubatch, and ubatches hold one sequence each; a
+/// non-causal model also needs the whole batch in one ubatch, so its batches
+/// are limited to `n_ubatch` tokens.
+fn compute_embeddings(
+    graph: &mut dyn crate::llmrust::src::llama_graph::LlamaGraph,
+    inputs: &[Vec<i32>],
+    pooling: crate::llmrust::src::llama_hparams::LlamaPoolingType,
+    config: &ModelConfig,
+) -> Result<Vec<Vec<f32>>, String> {
+    use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaSplitStrategy};
+    use crate::llmrust::src::llama_context::LlamaContext;
+    use crate::llmrust::src::llama_cparams::LlamaCparams;
+
+    let hparams = server_encoder_hparams();
+    let n_batch = config.n_batch.max(1) as usize;
+    let n_ubatch = (config.n_ubatch as usize).clamp(1, n_batch);
+    let n_batch = if hparams.causal_attn { n_batch } else { n_ubatch };
+    if let Some((i, tokens)) = inputs.iter().enumerate().find(|(_, t)| t.len() > n_ubatch) {
+        return Err(format!(
+            "input {} ({} tokens) is too large to process. increase the physical batch size (n_ubatch = {})",
+            i,
+            tokens.len(),
+            n_ubatch
+        ));
+    }
+    let cparams = LlamaCparams {
+        n_ctx: n_batch as u32,
+        n_ubatch,
+        split: LlamaSplitStrategy::Seq,
+        embeddings: true,
+        pooling_type: pooling,
+        ..Default::default()
+    };
+    let mut ctx = LlamaContext::with_cparams(0, cparams, None)?.with_model_hparams(hparams);
+
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut batch = LlamaBatch::new();
+    let mut first = 0;
+    for (k, tokens) in inputs.iter().enumerate() {
+        if batch.n_tokens() + tokens.len() > n_batch {
+            decode_embeddings(&mut ctx, graph, &batch, k - first, &mut embeddings)?;
+            batch.clear();
+            first = k;
+        }
+        for (pos, &token) in tokens.iter().enumerate() {
+            batch.add(token, pos as i32, &[(k - first) as i32], true);
+        }
+    }
+    decode_embeddings(&mut ctx, graph, &batch, inputs.len() - first, &mut embeddings)?;
+    Ok(embeddings)
+}
+
+/// Hyperparameters of the mock encoders behind /v1/embeddings and
+/// /v1/rerank: both attend over their whole input
+fn server_encoder_hparams() -> crate::llmrust::src::llama_hparams::LlamaHparams {
+    crate::llmrust::src::llama_hparams::LlamaHparams { causal_attn: false, ..Default::default() }
+}
+
+fn decode_embeddings(
+    ctx: &mut crate::llmrust::src::llama_context::LlamaContext,
+    graph: &mut dyn crate::llmrust::src::llama_graph::LlamaGraph,
+    batch: &crate::llmrust::src::llama_batch::LlamaBatch,
+    n_seqs: usize,
+    out: &mut Vec<Vec<f32>>,
+) -> Result<(), String> {
+    ctx.decode(batch, graph)?;
+    for seq_id in 0..n_seqs as i32 {
+        let embd = ctx.get_embeddings_seq(seq_id).ok_or_else(|| format!("no embedding for sequence {}", seq_id))?;
+        out.push(embd.to_vec());
+    }
+    Ok(())
+}
+
+/// Handle Jina/Cohere-style rerank requests: scores every document against
+/// the query with a cross-encoder and returns them by decreasing relevance.
+fn handle_rerank(
+    json: &serde_json::Value,
+    config: &ModelConfig,
+    vocab: &crate::llmrust::src::llama_vocab::LlamaVocab,
+) -> (String, u16) {
+    use crate::llmrust::src::llama_hparams::LlamaPoolingType;
+    use crate::llmrust::src::llama_vocab::LLAMA_TOKEN_NULL;
+
+    let request = json.get("query").and_then(|q| q.as_str()).ok_or("\"query\" must be a string").and_then(|query| {
+        let documents = json.get("documents").and_then(|d| d.as_array()).filter(|d| !d.is_empty());
+        let documents = documents.ok_or("\"documents\" must be a non-empty array")?;
+        let texts = documents
+            .iter()
+            .map(|d| d.as_str().or_else(|| d.get("text").and_then(|t| t.as_str())))
+            .collect::<Option<Vec<&str>>>()
+            .ok_or("each document must be a string or an object with a \"text\" string")?;
+        Ok((query, texts))
+    });
+    let top_n = match json.get("top_n") {
+        None => Ok(None),
+        Some(v) => v.as_u64().filter(|&n| n > 0).map(|n| Some(n as usize)).ok_or("top_n must be a positive integer"),
+    };
+    let ((query, documents), top_n) = match request.and_then(|r| Ok((r, top_n?))) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log_error!("Invalid rerank request: {}", e);
+            return (create_error_response(400, "invalid_request_error", e), 400);
+        }
+    };
+    let return_documents = json.get("return_documents").and_then(|v| v.as_bool()).unwrap_or(true);
+    if vocab.sep() == LLAMA_TOKEN_NULL {
+        let message = "the model has no separator token and cannot rerank";
+        log_error!("{}", message);
+        return (create_error_response(501, "not_supported_error", message), 501);
+    }
+
+    let inputs: Vec<Vec<i32>> = documents.iter().map(|document| format_rerank(vocab, query, document)).collect();
+    let mut graph = ServerRerankGraph { sep: vocab.sep(), eos: vocab.eos() };
+    let scores = match compute_embeddings(&mut graph, &inputs, LlamaPoolingType::Rank, config) {
+        Ok(scores) => scores,
+        Err(e) => {
+            log_error!("{}", e);
+            return (create_error_response(400, "invalid_request_error", &e), 400);
+        }
+    };
+    let mut ranked: Vec<(usize, f32)> = scores.iter().map(|s| s.first().copied().unwrap_or(f32::NEG_INFINITY)).enumerate().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked.truncate(top_n.unwrap_or(ranked.len()));
+
+    let results: Vec<serde_json::Value> = ranked
+        .iter()
+        .map(|&(index, score)| {
+            let mut result = serde_json::json!({ "index": index, "relevance_score": score });
+            if return_documents {
+                result["document"] = serde_json::json!({ "text": documents[index] });
+            }
+            result
+        })
+        .collect();
+    let n_tokens: usize = inputs.iter().map(|t| t.len()).sum();
+    let body = serde_json::json!({
+        "object": "list",
+        "model": json.get("model").and_then(|m| m.as_str()).unwrap_or("llm-rust"),
+        "results": results,
+        "usage": { "prompt_tokens": n_tokens, "total_tokens": n_tokens },
+    });
+    log_info!("Reranked {} documents ({} tokens)", documents.len(), n_tokens);
+    (create_json_response(200, &body.to_string()), 200)
+}
+
+/// Cross-encoder input for a query/document pair: `[BOS]query[EOS][SEP]document[EOS]`
+fn format_rerank(vocab: &crate::llmrust::src::llama_vocab::LlamaVocab, query: &str, document: &str) -> Vec<i32> {
+    let (bos, eos, sep) = (vocab.bos(), vocab.eos(), vocab.sep());
+    [vec![bos], text_tokens(query), vec![eos, sep], text_tokens(document), vec![eos]].concat()
+}
+
+/// Stand-in for a cross-encoder reranker: the CLS state holds the share of
+/// query words found in the document, and the classification head maps it to
+/// a relevance logit
+struct ServerRerankGraph {
+    sep: i32,
+    eos: i32,
+}
+
+impl crate::llmrust::src::llama_graph::LlamaGraph for ServerRerankGraph {
+    fn n_vocab(&self) -> usize {
+        SERVER_N_VOCAB
+    }
+
+    fn compute(
+        &mut self,
+        _batch: &crate::llmrust::src::llama_batch::LlamaBatch,
+        _ubatch: &crate::llmrust::src::llama_batch::LlamaUbatch,
+    ) -> Result<Vec<Vec<f32>>, String> {
+        Err("the reranking model does not produce logits".to_string())
+    }
+
+    fn n_embd(&self) -> usize {
+        1
+    }
+
+    fn pooling_type(&self) -> crate::llmrust::src::llama_hparams::LlamaPoolingType {
+        crate::llmrust::src::llama_hparams::LlamaPoolingType::Rank
+    }
+
+    /// Attends over the whole pair, which the per-sequence split keeps in one ubatch.
+    fn embed(
+        &mut self,
+        batch: &crate::llmrust::src::llama_batch::LlamaBatch,
+        ubatch: &crate::llmrust::src::llama_batch::LlamaUbatch,
+    ) -> Result<Vec<Vec<f32>>, String> {
+        let (sep, eos) = (self.sep, self.eos);
+        let tokens: Vec<i32> = ubatch.idxs.iter().map(|&i| batch.token[i]).collect();
+        let split = tokens.iter().position(|&t| t == sep).unwrap_or(tokens.len());
+        let words = |tokens: &[i32]| tokens.iter().copied().filter(|&t| t != eos && t != SERVER_TOKEN_BOS).collect::<Vec<_>>();
+        let (query, document) = (words(&tokens[..split]), words(tokens.get(split + 1..).unwrap_or(&[])));
+        let overlap = match query.len() {
+            0 => 0.0,
+            n => query.iter().filter(|t| document.contains(t)).count() as f32 / n as f32,
+        };
+        Ok(ubatch.idxs.iter().map(|&i| vec![if batch.pos[i] == 0 { overlap } else { 0.0 }]).collect())
+    }
+
+    fn classify(&self, cls: &[f32]) -> Result<Vec<f32>, String> {
+        Ok(vec![8.0 * cls[0] - 4.0])
+    }
+}
+
+/// Width of the mock encoder's hidden states
+const SERVER_N_EMBD: usize = 384;
+
+/// Stand-in for the encoder of an embedding model: the hidden state of a
+/// token depends only on the token and its position, so equal texts get equal
+/// embeddings and texts sharing words get similar ones
+struct ServerEmbeddingGraph;
+
+impl crate::llmrust::src::llama_graph::LlamaGraph for ServerEmbeddingGraph {
+    fn n_vocab(&self) -> usize {
+        SERVER_N_VOCAB
+    }
+
+    fn compute(
+        &mut self,
+        _batch: &crate::llmrust::src::llama_batch::LlamaBatch,
+        _ubatch: &crate::llmrust::src::llama_batch::LlamaUbatch,
+    ) -> Result<Vec<Vec<f32>>, String> {
+        Err("the embedding model does not produce logits".to_string())
+    }
+
+    fn n_embd(&self) -> usize {
+        SERVER_N_EMBD
+    }
+
+    fn pooling_type(&self) -> crate::llmrust::src::llama_hparams::LlamaPoolingType {
+        crate::llmrust::src::llama_hparams::LlamaPoolingType::Mean
+    }
+
+    fn embed(
+        &mut self,
+        batch: &crate::llmrust::src::llama_batch::LlamaBatch,
+        ubatch: &crate::llmrust::src::llama_batch::LlamaUbatch,
+    ) -> Result<Vec<Vec<f32>>, String> {
+        Ok(ubatch
+            .idxs
+            .iter()
+            .map(|&i| {
+                let (token, pos) = (batch.token[i] as f32, batch.pos[i] as f32);
+                (0..SERVER_N_EMBD)
+                    .map(|d| (token * 0.618 + d as f32 * 1.37).sin() + 0.1 * ((pos + 1.0) * (d as f32 + 1.0) * 0.01).cos())
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// Grammar enforcing an OpenAI-style `response_format`, as GBNF and parsed;
+/// `None` for plain text
+fn response_format_grammar(
+    response_format: &serde_json::Value,
+) -> Result<Option<(&'static str, String, crate::llmrust::src::llama_grammar::LlamaGrammar)>, String> {
+    use crate::llmrust::common::json_schema_to_grammar::json_schema_to_grammar;
+    use crate::llmrust::src::llama_grammar::LlamaGrammar;
+
+    let format_type = match response_format.get("type") {
+        None => "text",
+        Some(t) => t.as_str().ok_or("response_format.type must be a string")?,
+    };
+    let schema = match format_type {
+        "text" => return Ok(None),
+        "json_object" => serde_json::json!({ "type": "object" }),
+        "json_schema" => response_format
+            .get("schema")
+            .or_else(|| response_format.get("json_schema").and_then(|s| s.get("schema")))
+            .cloned()
+            .ok_or("response_format.json_schema.schema is required")?,
+        other => return Err(format!("unsupported response_format type {:?}", other)),
+    };
+    let grammar_text = json_schema_to_grammar(&schema)?;
+    let grammar = LlamaGrammar::parse(&grammar_text, "root")?;
+    let format_type = if format_type == "json_object" { "json_object" } else { "json_schema" };
+    Ok(Some((format_type, grammar_text, grammar)))
+}
+
+/// JSON error response with an OpenAI-style error object
+fn create_error_response(status_code: u16, error_type: &str, message: &str) -> String {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "code": status_code,
+        }
+    });
+    create_json_response(status_code, &body.to_string())
 }
 
 fn generate_id() -> String {
@@ -777,6 +2220,7 @@ pub extern "C" fn rust_generate_and_validate_config() -> std::os::raw::c_int {
 
 /// Run LLM engine with given configuration file
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn rust_run_llm_engine(config_path: *const std::os::raw::c_char) -> std::os::raw::c_int {
     let _ = init_logging();
     
@@ -832,4 +2276,648 @@ mod tests {
         let result = trim_whitespace("  hello world  ");
         assert_eq!(result, "hello world");
     }
+
+    #[test]
+    fn test_chat_completion_grammar() {
+        let config = ModelConfig::default();
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"grammar":"json_arr"}"#;
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let (_, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+        assert_eq!(status, 200);
+
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"grammar":"root ::= undefined_rule"}"#;
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+        assert_eq!(status, 400);
+        assert!(response.contains("invalid_grammar"));
+
+        let run = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+            assert_eq!(status, 200);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["choices"][0].clone()
+        };
+        // unconstrained, the model writes its reply up to the token limit
+        let choice = run(r#"{"messages":[{"role":"user","content":"hi"}],"max_tokens":10}"#);
+        assert_eq!(choice["message"]["content"], "Hello! I'm");
+        assert_eq!(choice["finish_reason"], "length");
+        // the grammar overrides the model's preference and ends the output
+        for _ in 0..4 {
+            let choice = run(r#"{"messages":[{"role":"user","content":"hi"}],"grammar":"root ::= \"yes\" | \"no\""}"#);
+            let content = choice["message"]["content"].as_str().unwrap();
+            assert!(content == "yes" || content == "no", "{:?}", content);
+            assert_eq!(choice["finish_reason"], "stop");
+        }
+    }
+
+    #[test]
+    fn test_chat_completion_response_format() {
+        let config = ModelConfig::default();
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            handle_chat_completion(body, &json, &config, &kv_pool)
+        };
+
+        let (_, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"response_format":{"type":"json_schema","json_schema":{"name":"person","schema":{"type":"object","properties":{"name":{"type":"string"},"age":{"type":"integer"}},"required":["name"]}}}}"#);
+        assert_eq!(status, 200);
+
+        let (_, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"response_format":{"type":"json_object"}}"#);
+        assert_eq!(status, 200);
+
+        let (response, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"response_format":{"type":"json_schema","schema":{"type":"string","pattern":"^a+$"}}}"#);
+        assert_eq!(status, 400);
+        assert!(response.contains("invalid_response_format"));
+        assert!(response.contains("pattern"));
+
+        let (_, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"response_format":{"type":"xml"}}"#);
+        assert_eq!(status, 400);
+
+        let (response, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"response_format":{"type":"json_schema","schema":{"type":"object","properties":{"a":{"type":"string"}},"required":["a","b"]}}}"#);
+        assert_eq!(status, 400);
+        assert!(response.contains("unsupported JSON schema"));
+
+        // the output follows the schema instead of the model's reply
+        let (response, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"max_tokens":200,"response_format":{"type":"json_schema","schema":{"type":"object","properties":{"answer":{"enum":["yes","no"]}},"required":["answer"]}}}"#);
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        let content: serde_json::Value = serde_json::from_str(body["choices"][0]["message"]["content"].as_str().unwrap()).unwrap();
+        assert!(content["answer"] == "yes" || content["answer"] == "no", "{}", content);
+
+        let (_, status) = run(r#"{"messages":[{"role":"user","content":"hi"}],"grammar":"json","response_format":{"type":"json_object"}}"#);
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn test_chat_completion_regex() {
+        let config = ModelConfig::default();
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            handle_chat_completion(body, &json, &config, &kv_pool)
+        };
+
+        assert_eq!(run(r#"{"messages":[],"regex":"\\d{4}-\\d{2}-\\d{2}"}"#).1, 200);
+        let (response, status) = run(r#"{"messages":[],"regex":"(a"}"#);
+        assert_eq!(status, 400);
+        assert!(response.contains("invalid_regex"));
+        assert_eq!(run(r#"{"messages":[],"regex":"a+","grammar":"json"}"#).1, 400);
+        let (response, status) = run(r#"{"messages":[],"regex":"((a{1000}){1000}){1000}"}"#);
+        assert_eq!(status, 400);
+        assert!(response.contains("too large"));
+
+        // the output matches the pattern instead of the model's reply
+        let (response, status) = run(r#"{"messages":[],"regex":"\\d{4}-\\d{2}-\\d{2}"}"#);
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let content = response["choices"][0]["message"]["content"].as_str().unwrap();
+        assert!(crate::llmrust::src::llama_regex::RegexDfa::compile(r"\d{4}-\d{2}-\d{2}").unwrap().matches(content), "{:?}", content);
+        assert_eq!(response["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[test]
+    fn test_chat_completion_logit_bias() {
+        let config = ModelConfig::default();
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            handle_chat_completion(body, &json, &config, &kv_pool).1
+        };
+
+        assert_eq!(run(r#"{"messages":[],"logit_bias":{"15043":-100,"Hello":5}}"#), 200);
+        assert_eq!(run(r#"{"messages":[],"logit_bias":[[15043,false],["Hi",1.5]],"banned_strings":["As an AI"]}"#), 200);
+        assert_eq!(run(r#"{"messages":[],"logit_bias":{"15043":"high"}}"#), 400);
+        assert_eq!(run(r#"{"messages":[],"logit_bias":[[1,2,3]]}"#), 400);
+        assert_eq!(run(r#"{"messages":[],"banned_strings":["ok",""]}"#), 400);
+        assert_eq!(run(r#"{"messages":[],"logit_bias":{"32000":5}}"#), 400);
+
+        let content = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+            assert_eq!(status, 200);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+            response["choices"][0]["message"]["content"].as_str().unwrap().to_string()
+        };
+        // the model's reply starts with "I received"
+        assert_eq!(content(r#"{"messages":[],"max_tokens":5}"#), "I rec");
+        assert_eq!(content(r#"{"messages":[],"max_tokens":5,"logit_bias":{"Z":20}}"#), "ZZZZZ");
+        let banned = content(r#"{"messages":[],"max_tokens":5,"logit_bias":[["I",false]]}"#);
+        assert!(!banned.starts_with('I') && banned.ends_with(" rec"), "{:?}", banned);
+        let banned = content(r#"{"messages":[],"max_tokens":30,"banned_strings":["received"]}"#);
+        assert!(banned.starts_with("I receive") && !banned.contains("received"), "{:?}", banned);
+    }
+
+    #[test]
+    fn test_chat_completion_lazy_grammar() {
+        let mut config = ModelConfig::default();
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |config: &ModelConfig, body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            handle_chat_completion(body, &json, config, &kv_pool).1
+        };
+
+        // Explicit triggers work with any template
+        assert_eq!(run(&config, r#"{"messages":[],"grammar":"json","grammar_lazy":true,"grammar_triggers":[{"type":"word","value":"<tool_call>"}]}"#), 200);
+        // Without a template there are no default triggers
+        assert_eq!(run(&config, r#"{"messages":[],"grammar":"json","grammar_lazy":true}"#), 400);
+        assert_eq!(run(&config, r#"{"messages":[],"grammar_lazy":true,"grammar_triggers":[{"type":"word","value":"x"}]}"#), 400);
+        assert_eq!(run(&config, r#"{"messages":[],"grammar":"json","grammar_lazy":true,"grammar_triggers":[{"type":"regex","value":"x"}]}"#), 400);
+
+        assert_eq!(run(&config, r#"{"messages":[],"grammar":"json","grammar_lazy":true,"grammar_triggers":[{"type":"pattern","value":"(x"}]}"#), 400);
+
+        // free text until the trigger, then the grammar from the trigger on
+        let body = r#"{"messages":[{"role":"user","content":"ask"}],"max_tokens":40,"grammar":"root ::= \"your answer\"","grammar_lazy":true,"grammar_triggers":[{"type":"word","value":"your"}]}"#;
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(response["choices"][0]["message"]["content"], "I received your answer");
+        assert_eq!(response["choices"][0]["finish_reason"], "stop");
+
+        // a pattern trigger starts the grammar where its match starts
+        let body = r#"{"messages":[{"role":"user","content":"ask"}],"max_tokens":40,"grammar":"root ::= \"your answer\"","grammar_lazy":true,"grammar_triggers":[{"type":"pattern","value":"yo[a-z]r"}]}"#;
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(response["choices"][0]["message"]["content"], "I received your answer");
+
+        config.chat_template = Some("hermes-2-pro".to_string());
+        assert_eq!(run(&config, r#"{"messages":[],"grammar":"json","grammar_lazy":true}"#), 200);
+    }
+
+    #[test]
+    fn test_generation_error_response() {
+        let stalled = GenerationError::Internal("generation stalled: nothing left to evaluate".to_string());
+        assert_eq!(stalled.response(), (500, "server_error", "generation stalled: nothing left to evaluate"));
+        assert_eq!(GenerationError::ContextFull("full".to_string()).response().0, 400);
+        assert_eq!(GenerationError::Busy("busy".to_string()).response().0, 503);
+    }
+
+    #[test]
+    fn test_lazy_grammar_default_triggers() {
+        use crate::llmrust::common::chat::ChatFormat;
+        use crate::llmrust::src::llama_grammar::GrammarTrigger;
+
+        let vocab = server_vocab(None);
+        let request = serde_json::json!({});
+        // Tool-call openers that are control tokens of the model trigger on the token
+        let tool_call = vocab.text_to_token("<tool_call>").unwrap();
+        assert_eq!(lazy_grammar_triggers(&request, ChatFormat::Hermes2Pro, &vocab), Ok(vec![GrammarTrigger::Token(tool_call)]));
+        let python_tag = vocab.text_to_token("<|python_tag|>").unwrap();
+        assert_eq!(
+            lazy_grammar_triggers(&request, ChatFormat::Llama3x, &vocab),
+            Ok(vec![GrammarTrigger::Word("{\"name\"".to_string()), GrammarTrigger::Token(python_tag)])
+        );
+        // Explicit triggers are taken as given
+        let request = serde_json::json!({"grammar_triggers": [{"type": "word", "value": "<tool_call>"}]});
+        assert_eq!(lazy_grammar_triggers(&request, ChatFormat::Hermes2Pro, &vocab), Ok(vec![GrammarTrigger::Word("<tool_call>".to_string())]));
+    }
+
+    #[test]
+    fn test_chat_completion_context_shift() {
+        let mut config = ModelConfig { n_ctx: 16, ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |config: &ModelConfig, body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            let (response, status) = handle_chat_completion(body, &json, config, &kv_pool);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            (status, serde_json::from_str::<serde_json::Value>(&body).unwrap())
+        };
+        let body = r#"{"messages":[{"role":"user","content":"one two three four"}],"max_tokens":40,"n_keep":-1}"#;
+
+        let (status, response) = run(&config, body);
+        assert_eq!(status, 200);
+        assert_eq!(response["usage"]["prompt_tokens"], 4);
+        assert_eq!(response["usage"]["completion_tokens"], 40);
+        assert!(response["usage"]["context_shifts"].as_u64().unwrap() > 0);
+        assert!(response["usage"]["tokens_discarded"].as_u64().unwrap() >= 28);
+
+        config.ctx_shift = false;
+        let (status, response) = run(&config, body);
+        assert_eq!(status, 400);
+        assert_eq!(response["error"]["type"], "exceed_context_size_error");
+        assert!(response["error"]["message"].as_str().unwrap().starts_with("context full"));
+
+        // fits without shifting
+        let (status, response) = run(&config, r#"{"messages":[{"role":"user","content":"hi"}],"max_tokens":10}"#);
+        assert_eq!(status, 200);
+        assert_eq!(response["usage"]["context_shifts"], 0);
+        assert_eq!(run(&config, r#"{"messages":[],"n_keep":-2}"#).0, 400);
+        for max_tokens in ["-1", "2.5", "\"10\"", "2147483648", "4294967295", "4294967296"] {
+            let (status, response) = run(&config, &format!(r#"{{"messages":[],"max_tokens":{}}}"#, max_tokens));
+            assert_eq!(status, 400, "max_tokens {}", max_tokens);
+            assert_eq!(response["error"]["type"], "invalid_request_error");
+        }
+    }
+    #[test]
+    fn test_chat_completion_kv_admission() {
+        let config = ModelConfig { n_ctx: 64, kv_blocks: 4, kv_block_size: 4, ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            (status, serde_json::from_str::<serde_json::Value>(&body).unwrap())
+        };
+        let body = r#"{"messages":[{"role":"user","content":"one two three"}],"max_tokens":10}"#;
+
+        assert_eq!(run(body).0, 200);
+        {
+            // the prompt stays in the prompt cache
+            let state = kv_pool.lock();
+            assert_eq!(state.reserved, 0);
+            assert_eq!(state.cache.len(), 1);
+            assert_eq!(state.kv.n_free_blocks(), 3);
+        }
+
+        // more than the whole cache
+        let (status, response) = run(r#"{"messages":[],"max_tokens":40}"#);
+        assert_eq!(status, 400);
+        assert_eq!(response["error"]["type"], "exceed_context_size_error");
+
+        // the blocks are taken by another request, evicting the cached prompt
+        let (seq_id, reserved) = kv_pool.admit(13).unwrap();
+        assert!(kv_pool.lock().cache.is_empty());
+        let (status, response) = run(body);
+        assert_eq!(status, 503);
+        assert_eq!(response["error"]["type"], "unavailable_error");
+        assert_eq!(run(r#"{"messages":[],"max_tokens":2}"#).0, 503);
+        kv_pool.release(seq_id, reserved, 0, None);
+        assert_eq!(run(body).0, 200);
+    }
+
+    #[test]
+    fn test_server_kv_cache_layout() {
+        use crate::llmrust::ggml::src::ggml::GgmlType;
+        use crate::llmrust::src::llama_memory::LlamaMemory;
+
+        let config = ModelConfig { kv_blocks: 4, cache_type_k: "q8_0".to_string(), ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let state = kv_pool.lock();
+        // rows are laid out for the model, in the configured types
+        let hparams = crate::common::model::mock_model_hparams();
+        let (type_k, type_v) = (state.kv.type_k(), state.kv.type_v());
+        assert_eq!((type_k, type_v), (GgmlType::Q8_0, GgmlType::F16));
+        let row_bytes = type_k.row_size(hparams.n_embd_k_gqa() as usize) + type_v.row_size(hparams.n_embd_v_gqa() as usize);
+        assert_eq!(state.kv.block_memory_size(), config.kv_block_size as usize * hparams.n_layer as usize * row_bytes);
+        assert!(state.kv.can_shift());
+        assert!(state.kv.flash_attn());
+        drop(state);
+
+        // the configured attention kernel reaches the cache
+        let config = ModelConfig { kv_blocks: 4, flash_attn: "off".to_string(), ..Default::default() };
+        assert!(!ServerKvPool::new(&config).unwrap().lock().kv.flash_attn());
+        let config = ModelConfig { flash_attn: "fast".to_string(), ..Default::default() };
+        assert!(ServerKvPool::new(&config).err().unwrap().contains("unknown flash attention setting 'fast'"));
+
+        let config = ModelConfig { cache_type_v: "q5_1".to_string(), ..Default::default() };
+        assert!(ServerKvPool::new(&config).err().unwrap().contains("unsupported KV cache type 'q5_1'"));
+    }
+
+    #[test]
+    fn test_chat_completion_prompt_cache() {
+        let config = ModelConfig { kv_block_size: 4, ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let run = |body: &str| {
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            let (response, status) = handle_chat_completion(body, &json, &config, &kv_pool);
+            assert_eq!(status, 200);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["timings"].clone()
+        };
+        let system = r#"{"role":"system","content":"You are a helpful assistant that answers briefly"}"#;
+
+        let timings = run(&format!(r#"{{"messages":[{},{{"role":"user","content":"what is rust"}}]}}"#, system));
+        assert_eq!(timings["cache_n"], 0);
+        assert_eq!(timings["prompt_n"], 11);
+
+        // the shared system prompt is reused
+        let timings = run(&format!(r#"{{"messages":[{},{{"role":"user","content":"what is go"}}]}}"#, system));
+        assert_eq!(timings["cache_n"], 10);
+        assert_eq!(timings["prompt_n"], 1);
+        assert_eq!(timings["predicted_n"], 20);
+
+        // an identical prompt still evaluates its last token
+        let timings = run(&format!(r#"{{"messages":[{},{{"role":"user","content":"what is go"}}]}}"#, system));
+        assert_eq!(timings["cache_n"], 10);
+
+        let timings = run(&format!(r#"{{"messages":[{},{{"role":"user","content":"what is go"}}],"cache_prompt":false}}"#, system));
+        assert_eq!(timings["cache_n"], 0);
+        assert_eq!(kv_pool.lock().cache.len(), 2);
+    }
+
+    #[test]
+    fn test_chat_completion_continuous_batching() {
+        let config = ModelConfig { n_ctx: 64, n_batch: 8, n_ubatch: 4, kv_block_size: 4, ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let bodies: Vec<String> = (0..4)
+            .map(|i| format!(r#"{{"messages":[{{"role":"user","content":"request {} with a few more words"}}],"max_tokens":{}}}"#, i, 5 + i))
+            .collect();
+        let responses: Vec<(u16, serde_json::Value)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = bodies
+                .iter()
+                .map(|body| {
+                    let (config, kv_pool) = (&config, &kv_pool);
+                    scope.spawn(move || {
+                        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+                        let (response, status) = handle_chat_completion(body, &json, config, kv_pool);
+                        let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+                        (status, serde_json::from_str(&body).unwrap())
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for (i, (status, response)) in responses.iter().enumerate() {
+            assert_eq!(*status, 200);
+            assert_eq!(response["usage"]["prompt_tokens"], 7);
+            assert_eq!(response["usage"]["completion_tokens"], 5 + i as u64);
+        }
+
+        let state = kv_pool.lock();
+        assert_eq!(state.reserved, 0);
+        assert!(state.scheduler.is_empty());
+        assert!(state.slots.is_empty() && state.finished.is_empty());
+    }
+    #[test]
+    fn test_embeddings_endpoint_batched_inputs() {
+        let embed = |config: &ModelConfig, request: serde_json::Value| {
+            let (response, status) = handle_embeddings(&request, config);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            (status, serde_json::from_str::<serde_json::Value>(&body).unwrap())
+        };
+        let config = ModelConfig { n_batch: 8, n_ubatch: 8, pooling: "mean".to_string(), embd_normalize: 2, ..Default::default() };
+        let inputs = serde_json::json!(["the quick brown fox", "the quick brown fox jumps", "stock market report"]);
+        let (status, response) = embed(&config, serde_json::json!({ "input": inputs, "model": "e5-small" }));
+        assert_eq!(status, 200);
+        assert_eq!(response["object"], "list");
+        assert_eq!(response["model"], "e5-small");
+        // BOS + words per input
+        assert_eq!(response["usage"]["prompt_tokens"], 5 + 6 + 4);
+        let data = response["data"].as_array().unwrap();
+        assert_eq!(data.len(), 3);
+        let vectors: Vec<Vec<f32>> = data
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                assert_eq!(d["index"], i);
+                d["embedding"].as_array().unwrap().iter().map(|x| x.as_f64().unwrap() as f32).collect()
+            })
+            .collect();
+        assert!(vectors.iter().all(|v| v.len() == SERVER_N_EMBD));
+        for v in &vectors {
+            assert!((v.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-4);
+        }
+        let cos = crate::llmrust::common::common::common_embd_similarity_cos;
+        assert!(cos(&vectors[0], &vectors[1]) > cos(&vectors[0], &vectors[2]));
+
+        // the same inputs in one large batch, as base64; the encoder is
+        // non-causal, so batches still hold at most n_ubatch tokens
+        assert!(!server_encoder_hparams().causal_attn);
+        let config_large = ModelConfig { n_batch: 64, ..config.clone() };
+        let (_, response) = embed(&config_large, serde_json::json!({ "input": inputs, "encoding_format": "base64" }));
+        for (d, expected) in response["data"].as_array().unwrap().iter().zip(&vectors) {
+            let bytes = crate::llmrust::common::base64::decode(d["embedding"].as_str().unwrap()).unwrap();
+            let decoded: Vec<f32> = bytes.chunks(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+            assert_eq!(&decoded, expected);
+        }
+
+        // token ids and a single string
+        let (status, response) = embed(&config, serde_json::json!({ "input": [[1, 20, 30], [1, 20]] }));
+        assert_eq!(status, 200);
+        assert_eq!(response["usage"]["total_tokens"], 5);
+        assert_eq!(embed(&config, serde_json::json!({ "input": "hello" })).1["data"].as_array().unwrap().len(), 1);
+
+        for request in [
+            serde_json::json!({}),
+            serde_json::json!({ "input": "" }),
+            serde_json::json!({ "input": [] }),
+            serde_json::json!({ "input": [1, 40000] }),
+            serde_json::json!({ "input": "a b c d e f g h i j" }),
+            serde_json::json!({ "input": "hi", "encoding_format": "int8" }),
+        ] {
+            assert_eq!(embed(&config, request).0, 400);
+        }
+        let config_none = ModelConfig { pooling: "none".to_string(), ..config.clone() };
+        assert_eq!(embed(&config_none, serde_json::json!({ "input": "hi" })).0, 400);
+    }
+    #[test]
+    fn test_rerank_endpoint_sorts_and_truncates() {
+        let vocab = server_vocab(Some(crate::common::model::MOCK_TOKEN_SEP as i32));
+        let rerank = |config: &ModelConfig, request: serde_json::Value| {
+            let (response, status) = handle_rerank(&request, config, &vocab);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+            (status, serde_json::from_str::<serde_json::Value>(&body).unwrap())
+        };
+        let config = ModelConfig { n_batch: 64, n_ubatch: 32, ..Default::default() };
+        let documents = serde_json::json!([
+            "the weather is sunny today",
+            "paris is the capital of france",
+            { "text": "the capital city of france" },
+        ]);
+        let request = serde_json::json!({ "model": "bge-reranker", "query": "what is the capital of france", "documents": documents });
+        let (status, response) = rerank(&config, request.clone());
+        assert_eq!(status, 200);
+        assert_eq!(response["model"], "bge-reranker");
+        let results = response["results"].as_array().unwrap();
+        let order: Vec<u64> = results.iter().map(|r| r["index"].as_u64().unwrap()).collect();
+        assert_eq!(order, vec![1, 2, 0]);
+        let scores: Vec<f64> = results.iter().map(|r| r["relevance_score"].as_f64().unwrap()).collect();
+        assert!(scores.windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(results[0]["document"]["text"], "paris is the capital of france");
+        assert_eq!(results[1]["document"]["text"], "the capital city of france");
+        // [BOS] query [EOS][SEP] document [EOS] per pair
+        assert_eq!(response["usage"]["prompt_tokens"], 3 * (6 + 4) + 5 + 6 + 5);
+        assert_eq!(format_rerank(&vocab, "a", "b")[2..4], [2, crate::common::model::MOCK_TOKEN_SEP as i32]);
+
+        // the same scores when the pairs are spread over several batches
+        let small = ModelConfig { n_batch: 16, n_ubatch: 16, ..Default::default() };
+        let mut request_small = request.clone();
+        request_small["top_n"] = serde_json::json!(2);
+        request_small["return_documents"] = serde_json::json!(false);
+        let (_, response) = rerank(&small, request_small);
+        let results_small = response["results"].as_array().unwrap();
+        assert_eq!(results_small.len(), 2);
+        assert!(results_small[0].get("document").is_none());
+        assert_eq!(results_small[0]["relevance_score"], results[0]["relevance_score"]);
+
+        for request in [
+            serde_json::json!({ "documents": ["a"] }),
+            serde_json::json!({ "query": "q", "documents": [] }),
+            serde_json::json!({ "query": "q", "documents": [1] }),
+            serde_json::json!({ "query": "q", "documents": ["a"], "top_n": 0 }),
+        ] {
+            assert_eq!(rerank(&config, request).0, 400);
+        }
+        let tiny = ModelConfig { n_batch: 4, n_ubatch: 4, ..Default::default() };
+        assert_eq!(rerank(&tiny, request.clone()).0, 400);
+
+        // a model without a separator token cannot rerank
+        let (_, status) = handle_rerank(&request, &config, &server_vocab(None));
+        assert_eq!(status, 501);
+        // the served model's separator comes from its metadata
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        assert_eq!(kv_pool.vocab.sep(), crate::common::model::MOCK_TOKEN_SEP as i32);
+        assert!(kv_pool.vocab.is_control(kv_pool.vocab.sep()));
+    }
+
+    #[test]
+    fn test_chat_completion_control_vectors() {
+        use crate::llmrust::ggml::src::gguf::GgufWriter;
+
+        let n_embd = crate::common::model::mock_model_hparams().n_embd as usize;
+        let paths: Vec<String> = (0..2)
+            .map(|i| {
+                let mut w = GgufWriter::new();
+                w.add_tensor_f32("direction.1", &[n_embd], &vec![1.0 + i as f32; n_embd]).unwrap();
+                w.add_tensor_f32("direction.2", &[n_embd], &vec![-1.0; n_embd]).unwrap();
+                let path = std::env::temp_dir().join(format!("llmrust_server_cvec_{}_{}.gguf", i, std::process::id()));
+                w.write(&path).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        let config = ModelConfig { control_vectors: paths.clone(), control_vector_layer_range: Some([2, 8]), ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let chat = |extra: &str| {
+            let body = format!(r#"{{"messages":[{{"role":"user","content":"steer me"}}],"max_tokens":2{}}}"#, extra);
+            let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+            handle_chat_completion(&body, &json, &config, &kv_pool).1
+        };
+
+        // the selection is summed with its strengths on the configured range
+        let select = serde_json::json!({ "control_vectors": [{ "id": 0, "strength": 2.0 }, { "id": 1 }] });
+        let cvec = kv_pool.control_vector(&select, &config).unwrap().unwrap();
+        assert_eq!(cvec.layer_range(), (2, 8));
+        assert!(cvec.tensor_for(1).is_none());
+        assert_eq!(cvec.tensor_for(2).unwrap()[0], -3.0);
+        let mut ranged = select.clone();
+        ranged["control_vector_layer_range"] = serde_json::json!([1, 1]);
+        let cvec_1 = kv_pool.control_vector(&ranged, &config).unwrap().unwrap();
+        assert_eq!(cvec_1.tensor_for(1).unwrap()[0], 4.0);
+        assert_ne!(cvec.fingerprint(), cvec_1.fingerprint());
+        assert_eq!(cvec.fingerprint(), kv_pool.control_vector(&select, &config).unwrap().unwrap().fingerprint());
+        assert!(kv_pool.control_vector(&serde_json::json!({}), &config).unwrap().is_none());
+
+        assert_eq!(chat(r#","control_vectors":[{"id":1,"strength":0.5}]"#), 200);
+        assert_eq!(chat(""), 200);
+        assert_eq!(chat(r#","control_vectors":[{"id":2}]"#), 400);
+        assert_eq!(chat(r#","control_vectors":[{"id":0,"strength":"high"}]"#), 400);
+        assert_eq!(chat(r#","control_vectors":{"id":0}"#), 400);
+        assert_eq!(chat(r#","control_vectors":[{"id":0}],"control_vector_layer_range":[5,1]"#), 400);
+        assert!(kv_pool.lock().scheduler.is_empty());
+
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert!(ServerKvPool::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_chat_completion_lora_adapters() {
+        use crate::llmrust::ggml::src::gguf::GgufWriter;
+
+        let n_embd = crate::common::model::mock_model_hparams().n_embd as usize;
+        let paths: Vec<String> = (0..2)
+            .map(|i| {
+                let mut w = GgufWriter::new();
+                w.set_str("general.type", "adapter")
+                    .set_str("general.architecture", "llama")
+                    .set_str("adapter.type", "lora")
+                    .set_str("adapter.lora.task_name", &format!("task{}", i));
+                w.add_tensor_f32("blk.0.attn_q.weight.lora_a", &[n_embd, 1], &vec![0.5; n_embd]).unwrap();
+                w.add_tensor_f32("blk.0.attn_q.weight.lora_b", &[1, n_embd], &vec![0.25; n_embd]).unwrap();
+                let path = std::env::temp_dir().join(format!("llmrust_server_lora_{}_{}.gguf", i, std::process::id()));
+                w.write(&path).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        let lora_adapters = vec![
+            crate::common::model::LoraAdapterConfig { path: paths[0].clone(), scale: 1.0 },
+            crate::common::model::LoraAdapterConfig { path: paths[1].clone(), scale: 0.0 },
+        ];
+        let config = ModelConfig { lora_adapters, ..Default::default() };
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        let chat = |extra: &str| {
+            let body = format!(r#"{{"messages":[{{"role":"user","content":"adapt me"}}],"max_tokens":2{}}}"#, extra);
+            let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+            handle_chat_completion(&body, &json, &config, &kv_pool).1
+        };
+
+        let listed = kv_pool.lora_adapters_json();
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+        assert_eq!(listed[0]["id"], 0);
+        assert_eq!(listed[1]["path"], paths[1].as_str());
+        assert_eq!(listed[1]["scale"], 0.0);
+        assert_eq!(listed[1]["task_name"], "task1");
+
+        // defaults apply without a lora field; listed adapters replace them
+        let request = |json: serde_json::Value| kv_pool.lora_request(&json);
+        assert_eq!(request(serde_json::json!({})).unwrap(), vec![(0, 1.0)]);
+        assert_eq!(request(serde_json::json!({ "lora": [{ "id": 1, "scale": 0.5 }] })).unwrap(), vec![(1, 0.5)]);
+        assert!(request(serde_json::json!({ "lora": [] })).unwrap().is_empty());
+        assert!(request(serde_json::json!({ "lora": [{ "id": 2, "scale": 1.0 }] })).unwrap_err().contains("unknown lora id"));
+
+        // different adapter sets are scheduled in different groups
+        let group = |lora: Vec<(usize, f32)>| kv_pool.adapters(lora, None).group();
+        assert_eq!(group(vec![]), 0);
+        assert_ne!(group(vec![(0, 1.0)]), group(vec![(1, 1.0)]));
+        assert_ne!(group(vec![(0, 1.0)]), group(vec![(0, 0.5)]));
+        assert_eq!(group(vec![(0, 1.0), (1, 0.5)]), group(vec![(0, 1.0), (1, 0.5)]));
+
+        // the context runs with the adapters of the last scheduled request
+        assert_eq!(chat(r#","lora":[{"id":0,"scale":0.5},{"id":1,"scale":2.0}]"#), 200);
+        {
+            let state = kv_pool.lock();
+            assert_eq!(state.ctx.adapter_loras().len(), 2);
+            assert_eq!(state.ctx_group, group(vec![(0, 0.5), (1, 2.0)]));
+        }
+        assert_eq!(chat(r#","lora":[]"#), 200);
+        assert!(kv_pool.lock().ctx.adapter_loras().is_empty());
+        assert_eq!(chat(""), 200);
+        assert_eq!(kv_pool.lock().ctx.adapter_loras().len(), 1);
+
+        // prompts cached under one adapter set are not reused under another
+        let cache_n = |extra: &str| {
+            let body = format!(r#"{{"messages":[{{"role":"user","content":"adapt me"}}],"max_tokens":2{}}}"#, extra);
+            let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+            let (response, status) = handle_chat_completion(&body, &json, &config, &kv_pool);
+            assert_eq!(status, 200);
+            let response: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+            response["timings"]["cache_n"].as_u64().unwrap()
+        };
+        assert_eq!(cache_n(r#","lora":[{"id":1,"scale":1.0}]"#), 0);
+        assert!(cache_n(r#","lora":[{"id":1,"scale":1.0}]"#) > 0);
+        assert!(cache_n(r#","lora":[]"#) > 0);
+
+        assert_eq!(chat(r#","lora":[{"id":5,"scale":1.0}]"#), 400);
+        assert_eq!(chat(r#","lora":[{"id":0,"scale":"high"}]"#), 400);
+        assert_eq!(chat(r#","lora":{"id":0}"#), 400);
+        assert!(kv_pool.lock().scheduler.is_empty());
+
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert!(ServerKvPool::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_server_model_kv_overrides() {
+        let mut config = ModelConfig { model_path: "models/tiny-llama.gguf".to_string(), ..Default::default() };
+        config.kv_overrides.insert("tiny-llama.gguf".to_string(), vec!["llama.block_count=int:8".to_string()]);
+        config.kv_overrides.insert("other.gguf".to_string(), vec!["llama.block_count=int:2".to_string()]);
+        let kv_pool = ServerKvPool::new(&config).unwrap();
+        assert_eq!(kv_pool.hparams.n_layer, 8);
+        assert_eq!(config.model_kv_overrides("elsewhere/other.gguf").unwrap()[0].to_string(), "llama.block_count=int:2");
+        assert!(config.model_kv_overrides("unlisted.gguf").unwrap().is_empty());
+
+        config.kv_overrides.insert("tiny-llama.gguf".to_string(), vec!["llama.block_count=eight".to_string()]);
+        assert!(ServerKvPool::new(&config).is_err());
+        config.kv_overrides.insert("tiny-llama.gguf".to_string(), vec!["llama.block_count=str:8".to_string()]);
+        assert!(ServerKvPool::new(&config).err().unwrap().contains("bad metadata override type"));
+    }
 }
\ No newline at end of file
@@ -30,6 +30,7 @@
 /// - `EngineInitFailed`: Engine initialization failed.
 /// - `EngineRunFailed`: Engine run failed.
 /// - `EngineReleaseFailed`: Engine resource release failed.
+///
 /**
  * @file engine_.rs
  * 
@@ -1,6 +1,34 @@
 // common/arg.rs - Command line argument parsing
+//
+// Only the options the Rust side consumes itself are parsed here; the rest
+// of the command line belongs to the C++ front end and is skipped. Options
+// take their value either as the next argument or after `=`.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: common/arg.rs - File loaded successfully");
+use crate::llmrust::common::common::string_parse_kv_override;
+use crate::llmrust::src::llama_model_loader::LlamaModelKvOverride;
+
+pub const ARG_OVERRIDE_KV: &str = "--override-kv";
+
+/// Values of every occurrence of `name` in `args`
+fn arg_values<'a>(args: &'a [String], name: &str) -> Result<Vec<&'a str>, String> {
+    let mut values = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            values.push(iter.next().ok_or_else(|| format!("error: {} expects a value", name))?.as_str());
+        } else if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+/// `--override-kv key=type:value` (repeatable): metadata overrides applied
+/// when the model is loaded, in command line order
+pub fn common_params_parse_kv_overrides(args: &[String]) -> Result<Vec<LlamaModelKvOverride>, String> {
+    arg_values(args, ARG_OVERRIDE_KV)?
+        .into_iter()
+        .map(|spec| string_parse_kv_override(spec).map_err(|e| format!("error: {}: {}", ARG_OVERRIDE_KV, e)))
+        .collect()
 }
@@ -1,6 +1,45 @@
 // common/base64.rs - Base64 encoding/decoding utilities
+//
+// Standard alphabet with `=` padding (RFC 4648), as used by the OpenAI API
+// for `encoding_format: "base64"` embeddings: the little-endian bytes of the
+// f32 values.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: common/base64.rs - File loaded successfully");
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn decode(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let v = ALPHABET.iter().position(|&a| a == c).ok_or_else(|| format!("invalid base64 character '{}'", c as char))?;
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Encodes f32 values as their little-endian bytes.
+pub fn encode_f32(values: &[f32]) -> String {
+    encode(&values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>())
 }
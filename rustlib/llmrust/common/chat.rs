@@ -1,6 +1,126 @@
 // common/chat.rs - Chat functionality and conversation handling
+//
+// Chat formats differ in how a model announces a tool call. Each format knows
+// the words or tokens that open a call, which are used as triggers for lazy
+// grammars: generation stays free until the model starts a call, and the
+// tool-call grammar is enforced from that point on.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: common/chat.rs - File loaded successfully");
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_grammar::GrammarTrigger;
+use crate::llmrust::src::llama_regex::RegexSearch;
+use crate::llmrust::src::llama_vocab::LlamaVocab;
+
+/// Tool-calling conventions of the supported chat templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatFormat {
+    ContentOnly,
+    /// Hermes 2 Pro / Qwen 2.5: `<tool_call>{"name": ...}</tool_call>`
+    Hermes2Pro,
+    /// Llama 3.1+: bare `{"name": ...}` objects or `<|python_tag|>`
+    Llama3x,
+    /// Mistral Nemo: `[TOOL_CALLS][{"name": ...}]`
+    MistralNemo,
+    /// DeepSeek R1: `<｜tool▁calls▁begin｜>`
+    DeepSeekR1,
+    /// Functionary v3.2: `>>>name\n{...}`
+    FunctionaryV32,
+}
+
+const CHAT_FORMATS: &[(ChatFormat, &str)] = &[
+    (ChatFormat::ContentOnly, "content-only"),
+    (ChatFormat::Hermes2Pro, "hermes-2-pro"),
+    (ChatFormat::Llama3x, "llama3"),
+    (ChatFormat::MistralNemo, "mistral-nemo"),
+    (ChatFormat::DeepSeekR1, "deepseek-r1"),
+    (ChatFormat::FunctionaryV32, "functionary-v3.2"),
+];
+
+impl ChatFormat {
+    pub fn name(&self) -> &'static str {
+        CHAT_FORMATS.iter().find(|(f, _)| f == self).map(|(_, n)| *n).unwrap_or("content-only")
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        CHAT_FORMATS.iter().find(|(_, n)| *n == name).map(|(f, _)| *f)
+    }
+
+    /// Detects the format from the source of a Jinja chat template.
+    pub fn detect(template_src: &str) -> Self {
+        if template_src.contains("<tool_call>") {
+            Self::Hermes2Pro
+        } else if template_src.contains("[TOOL_CALLS]") {
+            Self::MistralNemo
+        } else if template_src.contains("<｜tool▁calls▁begin｜>") {
+            Self::DeepSeekR1
+        } else if template_src.contains(">>>all") {
+            Self::FunctionaryV32
+        } else if template_src.contains("<|start_header_id|>") && template_src.contains("ipython") {
+            Self::Llama3x
+        } else {
+            Self::ContentOnly
+        }
+    }
+
+    /// Resolves a configured template, given either a format name or template source.
+    pub fn from_template(template: &str) -> Self {
+        Self::from_name(template.trim()).unwrap_or_else(|| Self::detect(template))
+    }
+
+    /// Text that opens a tool call in this format
+    pub fn trigger_words(&self) -> &'static [&'static str] {
+        match self {
+            Self::ContentOnly => &[],
+            Self::Hermes2Pro => &["<tool_call>"],
+            Self::Llama3x => &["{\"name\"", "<|python_tag|>"],
+            Self::MistralNemo => &["[TOOL_CALLS]"],
+            Self::DeepSeekR1 => &["<｜tool▁calls▁begin｜>"],
+            Self::FunctionaryV32 => &[">>>"],
+        }
+    }
+
+    /// Lazy grammar triggers for this format. Words that are control tokens of
+    /// `vocab` never show up in the output text, so they trigger on the token.
+    pub fn grammar_triggers(&self, vocab: Option<&LlamaVocab>) -> Vec<GrammarTrigger> {
+        self.trigger_words()
+            .iter()
+            .map(|word| match vocab.and_then(|v| v.text_to_token(word).filter(|&t| v.is_control(t))) {
+                Some(token) => GrammarTrigger::Token(token),
+                None => GrammarTrigger::Word(word.to_string()),
+            })
+            .collect()
+    }
+}
+
+/// Parses the `grammar_triggers` field of a request:
+/// `[{"type": "word", "value": "<tool_call>"}, {"type": "token", "value": 128010}]`.
+/// A `pattern` trigger fires on a regex match anywhere in the output, a
+/// `pattern_full` one when the whole output matches.
+pub fn parse_grammar_triggers(value: &serde_json::Value) -> Result<Vec<GrammarTrigger>, String> {
+    let items = value.as_array().ok_or("grammar_triggers must be an array")?;
+    items
+        .iter()
+        .map(|item| {
+            let trigger_type = item.get("type").and_then(|t| t.as_str()).unwrap_or("word");
+            let value = item.get("value").ok_or("grammar trigger is missing a value")?;
+            match trigger_type {
+                "word" => value
+                    .as_str()
+                    .map(|w| GrammarTrigger::Word(w.to_string()))
+                    .ok_or_else(|| "word trigger value must be a string".to_string()),
+                "token" => value
+                    .as_i64()
+                    .and_then(|t| i32::try_from(t).ok())
+                    .map(GrammarTrigger::Token)
+                    .ok_or_else(|| "token trigger value must be a token id".to_string()),
+                "pattern" | "pattern_full" => value
+                    .as_str()
+                    .ok_or_else(|| "pattern trigger value must be a string".to_string())
+                    .and_then(|p| RegexSearch::new(p, trigger_type == "pattern_full"))
+                    .map(|search| GrammarTrigger::Pattern(Arc::new(search))),
+                other => Err(format!("unknown grammar trigger type {:?}", other)),
+            }
+        })
+        .collect()
 }
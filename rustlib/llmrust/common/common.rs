@@ -1,6 +1,166 @@
 // common/common.rs - Common utilities and shared functionality
+//
+// Helpers shared by the CLI and the server that sit on top of the context
+// and memory APIs, such as context shifting when the KV cache is full, and
+// embedding normalization.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: common/common.rs - File loaded successfully");
+use crate::llmrust::src::adapter_loader::{llama_control_vector_load, LlamaControlVectorFile};
+use crate::llmrust::src::llama_memory::{LlamaMemory, LlamaPos, LlamaSeqId};
+use crate::llmrust::src::llama_model_loader::{LlamaModelKvOverride, LlamaModelKvOverrideValue, LLAMA_KV_OVERRIDE_MAX_LEN};
+use crate::llmrust::src::llama_vocab::LlamaToken;
+
+/// Result of one context shift
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextShift {
+    /// Tokens kept at the start of the sequence
+    pub n_keep: i32,
+    /// Tokens removed after the kept ones
+    pub n_discard: i32,
+}
+
+/// Frees room in a full context: keeps the first `n_keep` tokens, discards half
+/// of the tokens after them and moves the rest back so that the sequence is
+/// contiguous again. `tokens` is the sequence's token history and is updated to
+/// match; `n_past` becomes `tokens.len()`.
+///
+/// A negative `n_keep` keeps nothing; `n_keep` larger than the history is an
+/// error, as is a removal the memory refuses (the history is then unchanged)
+/// or a position move it fails to apply.
+pub fn common_context_shift(
+    memory: &mut dyn LlamaMemory,
+    seq_id: LlamaSeqId,
+    tokens: &mut Vec<LlamaToken>,
+    n_keep: i32,
+) -> Result<ContextShift, String> {
+    if !memory.can_shift() {
+        return Err("the KV cache of this model does not support context shifting".to_string());
+    }
+
+    let n_past = tokens.len() as i32;
+    let n_keep = n_keep.max(0);
+    let n_left = n_past - n_keep;
+    if n_left < 2 {
+        return Err(format!("cannot shift context: n_keep = {} leaves {} of {} tokens to discard", n_keep, n_left.max(0), n_past));
+    }
+    let n_discard = n_left / 2;
+
+    if !memory.seq_rm(seq_id, n_keep as LlamaPos, (n_keep + n_discard) as LlamaPos) {
+        return Err(format!("cannot shift context: failed to remove {} tokens of sequence {}", n_discard, seq_id));
+    }
+    let pos_max = memory.seq_pos_max(seq_id);
+    memory.seq_add(seq_id, (n_keep + n_discard) as LlamaPos, n_past as LlamaPos, -n_discard);
+    if pos_max >= n_keep + n_discard && memory.seq_pos_max(seq_id) != pos_max - n_discard {
+        return Err(format!("cannot shift context: failed to move the positions of sequence {} by {}", seq_id, -n_discard));
+    }
+    tokens.drain(n_keep as usize..(n_keep + n_discard) as usize);
+
+    Ok(ContextShift { n_keep, n_discard })
+}
+
+/// Normalizes an embedding (`common_embd_normalize`): `embd_norm` -1 leaves it
+/// as is, 0 scales the largest component to 32760 (int16 range), 1 divides by
+/// the taxicab norm, 2 by the euclidean norm and p > 2 by the p-norm.
+pub fn common_embd_normalize(inp: &[f32], embd_norm: i32) -> Vec<f32> {
+    let sum = match embd_norm {
+        -1 => 1.0,
+        0 => inp.iter().fold(0.0f64, |m, &x| m.max(x.abs() as f64)) / 32760.0,
+        2 => inp.iter().map(|&x| (x as f64) * (x as f64)).sum::<f64>().sqrt(),
+        p => inp.iter().map(|&x| (x.abs() as f64).powi(p)).sum::<f64>().powf(1.0 / p as f64),
+    };
+    let norm = if sum > 0.0 { (1.0 / sum) as f32 } else { 0.0 };
+    inp.iter().map(|&x| x * norm).collect()
+}
+
+/// Cosine similarity of two embeddings; 1.0 when both are zero, 0.0 when only one is.
+pub fn common_embd_similarity_cos(a: &[f32], b: &[f32]) -> f32 {
+    let (mut sum, mut sum_a, mut sum_b) = (0.0f64, 0.0f64, 0.0f64);
+    for (&x, &y) in a.iter().zip(b) {
+        sum += x as f64 * y as f64;
+        sum_a += x as f64 * x as f64;
+        sum_b += y as f64 * y as f64;
+    }
+    if sum_a == 0.0 || sum_b == 0.0 {
+        return if sum_a == 0.0 && sum_b == 0.0 { 1.0 } else { 0.0 };
+    }
+    (sum / (sum_a.sqrt() * sum_b.sqrt())) as f32
+}
+
+/// A control vector file and the strength it is added with
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommonControlVectorLoadInfo {
+    pub strength: f32,
+    pub fname: String,
+}
+
+/// Weighted sum of control vectors in the layout of `llama_apply_adapter_cvec`:
+/// `n_embd` values per layer, starting at layer 1
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommonControlVectorData {
+    pub n_embd: usize,
+    pub data: Vec<f32>,
+}
+
+impl CommonControlVectorData {
+    /// Adds `strength` times the directions of `file`.
+    pub fn add(&mut self, file: &LlamaControlVectorFile, strength: f32) -> Result<(), String> {
+        if self.n_embd == 0 {
+            self.n_embd = file.n_embd;
+        } else if file.n_embd != self.n_embd {
+            return Err(format!("control vector n_embd = {} does not match the previous ones ({})", file.n_embd, self.n_embd));
+        }
+        for (&il, dir) in &file.directions {
+            let off = (il - 1) * self.n_embd;
+            if self.data.len() < off + self.n_embd {
+                self.data.resize(off + self.n_embd, 0.0);
+            }
+            for (d, v) in self.data[off..off + self.n_embd].iter_mut().zip(dir) {
+                *d += strength * v;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of layers with a direction slot, from layer 1
+    pub fn n_layers(&self) -> usize {
+        self.data.len().checked_div(self.n_embd).unwrap_or(0)
+    }
+}
+
+/// Loads the control vectors of `load_infos` and sums them with their strengths.
+pub fn common_control_vector_load(load_infos: &[CommonControlVectorLoadInfo]) -> Result<CommonControlVectorData, String> {
+    let mut result = CommonControlVectorData::default();
+    for info in load_infos {
+        result.add(&llama_control_vector_load(&info.fname)?, info.strength)?;
+    }
+    if result.n_embd == 0 {
+        return Err("no control vectors loaded".to_string());
+    }
+    Ok(result)
+}
+
+/// Parses a metadata override of the form `key=type:value`, with type one of
+/// `int`, `float`, `bool` (`true`/`false`) or `str`.
+pub fn string_parse_kv_override(data: &str) -> Result<LlamaModelKvOverride, String> {
+    let (key, typed) = data.split_once('=').ok_or_else(|| format!("malformed KV override '{}': expected key=type:value", data))?;
+    if key.is_empty() || key.len() > LLAMA_KV_OVERRIDE_MAX_LEN {
+        return Err(format!("malformed KV override '{}': key must be 1 to {} bytes", data, LLAMA_KV_OVERRIDE_MAX_LEN));
+    }
+    let (ty, value) = typed.split_once(':').ok_or_else(|| format!("malformed KV override '{}': expected key=type:value", data))?;
+    let invalid = |what: &str| format!("invalid {} value for KV override '{}': '{}'", what, data, value);
+    let value = match ty {
+        "int" => LlamaModelKvOverrideValue::Int(value.parse().map_err(|_| invalid("int"))?),
+        "float" => LlamaModelKvOverrideValue::Float(value.parse().map_err(|_| invalid("float"))?),
+        "bool" => match value {
+            "true" => LlamaModelKvOverrideValue::Bool(true),
+            "false" => LlamaModelKvOverrideValue::Bool(false),
+            _ => return Err(invalid("bool")),
+        },
+        "str" if value.len() > LLAMA_KV_OVERRIDE_MAX_LEN => {
+            return Err(format!("KV override '{}': string values are limited to {} bytes", data, LLAMA_KV_OVERRIDE_MAX_LEN));
+        }
+        "str" => LlamaModelKvOverrideValue::Str(value.to_string()),
+        _ => return Err(format!("invalid type '{}' for KV override '{}' (int, float, bool or str)", ty, data)),
+    };
+    Ok(LlamaModelKvOverride { key: key.to_string(), value })
 }
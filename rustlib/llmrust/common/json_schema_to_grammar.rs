@@ -1,6 +1,750 @@
 // common/json_schema_to_grammar.rs - JSON schema to grammar conversion
+//
+// Converts a JSON Schema into GBNF so that `response_format: {"type":
+// "json_schema"}` requests can be enforced by the grammar sampler. The rule
+// layout follows llama.cpp's SchemaConverter: one rule per schema node, shared
+// primitive rules, and a `space` rule between tokens. Keywords that constrain
+// the output but cannot be expressed here are rejected instead of ignored.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: common/json_schema_to_grammar.rs - File loaded successfully");
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde_json::Value;
+
+const SPACE_RULE: &str = r#"| " " | "\n"{1,2} [ \t]{0,20}"#;
+
+/// A shared rule and the other shared rules it references
+struct BuiltinRule {
+    content: &'static str,
+    deps: &'static [&'static str],
+}
+
+const PRIMITIVE_RULES: &[(&str, BuiltinRule)] = &[
+    ("boolean", BuiltinRule { content: r#"("true" | "false") space"#, deps: &[] }),
+    ("decimal-part", BuiltinRule { content: r#"[0-9]{1,16}"#, deps: &[] }),
+    ("integral-part", BuiltinRule { content: r#"[0] | [1-9] [0-9]{0,15}"#, deps: &[] }),
+    (
+        "number",
+        BuiltinRule {
+            content: r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+            deps: &["integral-part", "decimal-part"],
+        },
+    ),
+    ("integer", BuiltinRule { content: r#"("-"? integral-part) space"#, deps: &["integral-part"] }),
+    (
+        "value",
+        BuiltinRule {
+            content: r#"object | array | string | number | boolean | null"#,
+            deps: &["object", "array", "string", "number", "boolean", "null"],
+        },
+    ),
+    (
+        "object",
+        BuiltinRule {
+            content: r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+            deps: &["string", "value"],
+        },
+    ),
+    ("array", BuiltinRule { content: r#""[" space ( value ("," space value)* )? "]" space"#, deps: &["value"] }),
+    (
+        "uuid",
+        BuiltinRule {
+            content: r#""\"" [0-9a-fA-F]{8} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{12} "\"" space"#,
+            deps: &[],
+        },
+    ),
+    ("char", BuiltinRule { content: r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#, deps: &[] }),
+    ("string", BuiltinRule { content: r#""\"" char* "\"" space"#, deps: &["char"] }),
+    ("null", BuiltinRule { content: r#""null" space"#, deps: &[] }),
+];
+
+const STRING_FORMAT_RULES: &[(&str, BuiltinRule)] = &[
+    (
+        "date",
+        BuiltinRule {
+            content: r#"[0-9]{4} "-" ( "0" [1-9] | "1" [0-2] ) "-" ( "0" [1-9] | [1-2] [0-9] | "3" [0-1] )"#,
+            deps: &[],
+        },
+    ),
+    (
+        "time",
+        BuiltinRule {
+            content: r#"([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9] ":" [0-5] [0-9] ( "." [0-9]{3} )? ( "Z" | ( "+" | "-" ) ( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] )"#,
+            deps: &[],
+        },
+    ),
+    ("date-time", BuiltinRule { content: r#"date "T" time"#, deps: &["date", "time"] }),
+    ("date-string", BuiltinRule { content: r#""\"" date "\"" space"#, deps: &["date"] }),
+    ("time-string", BuiltinRule { content: r#""\"" time "\"" space"#, deps: &["time"] }),
+    ("date-time-string", BuiltinRule { content: r#""\"" date-time "\"" space"#, deps: &["date-time"] }),
+];
+
+/// Keywords that only annotate a schema and never constrain the output
+const ANNOTATION_KEYWORDS: &[&str] = &[
+    "title",
+    "description",
+    "$schema",
+    "$id",
+    "$comment",
+    "examples",
+    "default",
+    "readOnly",
+    "writeOnly",
+    "deprecated",
+];
+
+/// Keywords the converter understands
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "type",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "prefixItems",
+    "minItems",
+    "maxItems",
+    "enum",
+    "const",
+    "format",
+    "minLength",
+    "maxLength",
+    "$ref",
+    "$defs",
+    "definitions",
+    "anyOf",
+    "oneOf",
+];
+
+fn builtin_rule(name: &str) -> Option<&'static BuiltinRule> {
+    PRIMITIVE_RULES
+        .iter()
+        .chain(STRING_FORMAT_RULES.iter())
+        .find(|(n, _)| *n == name)
+        .map(|(_, r)| r)
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    name == "root" || name == "dot" || builtin_rule(name).is_some()
+}
+
+/// Formats `literal` as a GBNF string literal.
+fn format_literal(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len() + 2);
+    out.push('"');
+    for c in literal.chars() {
+        match c {
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats a single character for use inside a GBNF character class.
+fn format_class_char(c: char) -> String {
+    match c {
+        ']' | '[' | '\\' | '-' | '^' | '"' => format!("\\{}", c),
+        _ if (c as u32) < 0x20 || c as u32 == 0x7F => format!("\\x{:02X}", c as u32),
+        _ => c.to_string(),
+    }
+}
+
+/// Repeats `item_rule` between `min_items` and `max_items` times, optionally
+/// separated by `separator_rule`.
+fn build_repetition(item_rule: &str, min_items: u64, max_items: Option<u64>, separator_rule: Option<&str>) -> String {
+    if max_items == Some(0) {
+        return String::new();
+    }
+    if min_items == 0 && max_items == Some(1) {
+        return format!("{}?", item_rule);
+    }
+
+    let separator_rule = match separator_rule {
+        Some(sep) if !sep.is_empty() => sep,
+        _ => {
+            return match (min_items, max_items) {
+                (1, None) => format!("{}+", item_rule),
+                (0, None) => format!("{}*", item_rule),
+                (min, max) => format!("{}{{{},{}}}", item_rule, min, max.map(|m| m.to_string()).unwrap_or_default()),
+            };
+        }
+    };
+
+    let result = format!(
+        "{} {}",
+        item_rule,
+        build_repetition(
+            &format!("({} {})", separator_rule, item_rule),
+            min_items.saturating_sub(1),
+            max_items.map(|m| m - 1),
+            None,
+        )
+    );
+    if min_items == 0 {
+        format!("({})?", result)
+    } else {
+        result
+    }
+}
+
+/// Elements of an array whose first ones follow the rules of `tuple` in
+/// order and whose further ones follow `rest`, `min_items` to `max_items` in
+/// all; `first` when no element precedes them.
+fn build_tuple(tuple: &[String], rest: Option<&str>, min_items: u64, max_items: Option<u64>, first: bool) -> String {
+    let Some((head, tail)) = tuple.split_first() else {
+        return match rest {
+            None => String::new(),
+            Some(rest) if first => build_repetition(rest, min_items, max_items, Some("\",\" space")),
+            Some(rest) => build_repetition(&format!("(\",\" space {})", rest), min_items, max_items, None),
+        };
+    };
+    if max_items == Some(0) {
+        return String::new();
+    }
+    let separator = if first { "" } else { "\",\" space " };
+    let tail = build_tuple(tail, rest, min_items.saturating_sub(1), max_items.map(|m| m - 1), false);
+    let elements = format!("{}{} {}", separator, head, tail);
+    let elements = elements.trim_end();
+    if min_items == 0 {
+        format!("({})?", elements)
+    } else {
+        elements.to_string()
+    }
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: BTreeMap<char, TrieNode>,
+    is_end_of_string: bool,
+}
+
+impl TrieNode {
+    fn insert(&mut self, s: &str) {
+        let mut node = self;
+        for c in s.chars() {
+            node = node.children.entry(c).or_default();
+        }
+        node.is_end_of_string = true;
+    }
+}
+
+/// Builds GBNF rules from a JSON schema.
+pub struct SchemaConverter {
+    root: Value,
+    rules: BTreeMap<String, String>,
+    /// Rule name reserved for each `$ref`, assigned before the target is visited
+    ref_names: HashMap<String, String>,
+}
+
+impl SchemaConverter {
+    pub fn new(root: Value) -> Self {
+        let mut rules = BTreeMap::new();
+        rules.insert("space".to_string(), SPACE_RULE.to_string());
+        Self { root, rules, ref_names: HashMap::new() }
+    }
+
+    /// Adds `rule` under a sanitized `name`, reusing an identical rule or
+    /// suffixing the name on conflict. Returns the final rule name.
+    fn add_rule(&mut self, name: &str, rule: &str) -> String {
+        let esc_name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' }).collect();
+        let key = if self.rules.get(&esc_name).is_none_or(|r| r == rule) {
+            esc_name
+        } else {
+            let mut i = 0;
+            loop {
+                let candidate = format!("{}{}", esc_name, i);
+                if self.rules.get(&candidate).is_none_or(|r| r == rule) {
+                    break candidate;
+                }
+                i += 1;
+            }
+        };
+        self.rules.insert(key.clone(), rule.to_string());
+        key
+    }
+
+    fn add_primitive(&mut self, name: &str, rule: &BuiltinRule) -> String {
+        let n = self.add_rule(name, rule.content);
+        for dep in rule.deps {
+            if !self.rules.contains_key(*dep) {
+                let dep_rule = builtin_rule(dep).expect("builtin rule dependency");
+                self.add_primitive(dep, dep_rule);
+            }
+        }
+        n
+    }
+
+    fn add_builtin(&mut self, name: &str) -> String {
+        self.add_primitive(name, builtin_rule(name).expect("builtin rule"))
+    }
+
+    /// Rule matching any JSON string that is not one of `strings`.
+    fn not_strings(&mut self, strings: &[String]) -> String {
+        let mut trie = TrieNode::default();
+        for s in strings {
+            trie.insert(s);
+        }
+
+        let char_rule = self.add_builtin("char");
+        let mut out = String::from("[\"] ( ");
+        fn visit(node: &TrieNode, char_rule: &str, out: &mut String) {
+            let mut rejects = String::new();
+            let mut first = true;
+            for (c, child) in &node.children {
+                rejects.push_str(&format_class_char(*c));
+                if first {
+                    first = false;
+                } else {
+                    out.push_str(" | ");
+                }
+                out.push_str(&format!("[{}]", format_class_char(*c)));
+                if !child.children.is_empty() {
+                    out.push_str(" (");
+                    visit(child, char_rule, out);
+                    out.push(')');
+                    if !child.is_end_of_string {
+                        out.push('?');
+                    }
+                } else if child.is_end_of_string {
+                    out.push_str(&format!(" {}+", char_rule));
+                }
+            }
+            if !node.children.is_empty() {
+                if !first {
+                    out.push_str(" | ");
+                }
+                out.push_str(&format!("[^\"{}] {}*", rejects, char_rule));
+            }
+        }
+        visit(&trie, &char_rule, &mut out);
+        out.push_str(&format!(" ){} [\"] space", if trie.is_end_of_string { "" } else { "?" }));
+        out
+    }
+
+    fn resolve_ref(&mut self, reference: &str, path: &str) -> Result<String, String> {
+        if let Some(name) = self.ref_names.get(reference) {
+            return Ok(name.clone());
+        }
+        let pointer = reference
+            .strip_prefix('#')
+            .ok_or_else(|| format!("unsupported remote $ref {:?} at {}", reference, path))?;
+        let target = self
+            .root
+            .pointer(pointer)
+            .cloned()
+            .ok_or_else(|| format!("unresolved $ref {:?} at {}", reference, path))?;
+
+        // Reserve a name before visiting so recursive references can point at it
+        let base = reference.rsplit('/').next().filter(|s| !s.is_empty()).unwrap_or("ref");
+        let base: String = base.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' }).collect();
+        let base = if is_reserved_name(&base) { format!("{}-", base) } else { base };
+        let taken = |n: &str, conv: &Self| conv.rules.contains_key(n) || conv.ref_names.values().any(|v| v == n);
+        let mut name = base.clone();
+        let mut i = 0;
+        while taken(&name, self) {
+            name = format!("{}{}", base, i);
+            i += 1;
+        }
+
+        self.ref_names.insert(reference.to_string(), name.clone());
+        let actual = self.visit(&target, &name, reference)?;
+        if actual != name {
+            self.rules.insert(name.clone(), actual);
+        }
+        Ok(name)
+    }
+
+    fn generate_union_rule(&mut self, name: &str, alt_schemas: &[Value], path: &str) -> Result<String, String> {
+        let mut alts = Vec::with_capacity(alt_schemas.len());
+        for (i, alt) in alt_schemas.iter().enumerate() {
+            let sub_name = format!("{}{}{}", name, if name.is_empty() { "" } else { "-" }, i);
+            alts.push(self.visit(alt, &sub_name, &format!("{}/{}", path, i))?);
+        }
+        Ok(alts.join(" | "))
+    }
+
+    fn generate_constant_rule(value: &Value) -> String {
+        format_literal(&value.to_string())
+    }
+
+    fn build_object_rule(
+        &mut self,
+        properties: &[(String, Value)],
+        required: &HashSet<String>,
+        name: &str,
+        additional_properties: Option<&Value>,
+        path: &str,
+    ) -> Result<String, String> {
+        let prefix = if name.is_empty() { String::new() } else { format!("{}-", name) };
+        let mut prop_kv_rule_names: HashMap<String, String> = HashMap::new();
+        for (prop_name, prop_schema) in properties {
+            let prop_rule_name =
+                self.visit(prop_schema, &format!("{}{}", prefix, prop_name), &format!("{}/properties/{}", path, prop_name))?;
+            let kv_rule = format!("{} space \":\" space {}", format_literal(&Value::String(prop_name.clone()).to_string()), prop_rule_name);
+            let kv_name = self.add_rule(&format!("{}{}-kv", prefix, prop_name), &kv_rule);
+            prop_kv_rule_names.insert(prop_name.clone(), kv_name);
+        }
+
+        let required_props: Vec<String> =
+            properties.iter().map(|(k, _)| k.clone()).filter(|k| required.contains(k)).collect();
+        let mut optional_props: Vec<String> =
+            properties.iter().map(|(k, _)| k.clone()).filter(|k| !required.contains(k)).collect();
+
+        if let Some(additional) = additional_properties.filter(|v| !matches!(v, Value::Bool(false))) {
+            let sub_name = format!("{}additional", prefix);
+            let value_rule = if additional.is_object() {
+                self.visit(additional, &format!("{}-value", sub_name), &format!("{}/additionalProperties", path))?
+            } else {
+                self.add_builtin("value")
+            };
+            let key_rule = if properties.is_empty() {
+                self.add_builtin("string")
+            } else {
+                let names: Vec<String> = properties.iter().map(|(k, _)| k.clone()).collect();
+                let rule = self.not_strings(&names);
+                self.add_rule(&format!("{}-k", sub_name), &rule)
+            };
+            let kv_name = self.add_rule(&format!("{}-kv", sub_name), &format!("{} \":\" space {}", key_rule, value_rule));
+            prop_kv_rule_names.insert("*".to_string(), kv_name);
+            optional_props.push("*".to_string());
+        }
+
+        let mut rule = String::from("\"{\" space ");
+        rule.push_str(
+            &required_props.iter().map(|k| prop_kv_rule_names[k].clone()).collect::<Vec<_>>().join(" \",\" space "),
+        );
+
+        if !optional_props.is_empty() {
+            rule.push_str(" (");
+            if !required_props.is_empty() {
+                rule.push_str(" \",\" space ( ");
+            }
+
+            let mut alternatives = Vec::with_capacity(optional_props.len());
+            for i in 0..optional_props.len() {
+                alternatives.push(self.get_recursive_refs(&optional_props[i..], false, &prefix, &prop_kv_rule_names));
+            }
+            rule.push_str(&alternatives.join(" | "));
+
+            if !required_props.is_empty() {
+                rule.push_str(" )");
+            }
+            rule.push_str(" )?");
+        }
+
+        rule.push_str(" \"}\" space");
+        Ok(rule)
+    }
+
+    /// Chains optional properties so each may be omitted while keeping their order.
+    fn get_recursive_refs(
+        &mut self,
+        ks: &[String],
+        first_is_optional: bool,
+        prefix: &str,
+        prop_kv_rule_names: &HashMap<String, String>,
+    ) -> String {
+        let k = &ks[0];
+        let kv_rule_name = &prop_kv_rule_names[k];
+        let comma_ref = format!("( \",\" space {} )", kv_rule_name);
+        let mut res = if first_is_optional {
+            format!("{}{}", comma_ref, if k == "*" { "*" } else { "?" })
+        } else if k == "*" {
+            format!("{} {}*", kv_rule_name, comma_ref)
+        } else {
+            kv_rule_name.clone()
+        };
+        if ks.len() > 1 {
+            let rest = self.get_recursive_refs(&ks[1..], true, prefix, prop_kv_rule_names);
+            let rest_name = self.add_rule(&format!("{}{}-rest", prefix, k), &rest);
+            res.push(' ');
+            res.push_str(&rest_name);
+        }
+        res
+    }
+
+    /// Rejects keywords that would constrain the output but are not supported.
+    fn check_keywords(schema: &serde_json::Map<String, Value>, path: &str) -> Result<(), String> {
+        for key in schema.keys() {
+            if !SUPPORTED_KEYWORDS.contains(&key.as_str()) && !ANNOTATION_KEYWORDS.contains(&key.as_str()) {
+                return Err(format!("unsupported JSON schema keyword {:?} at {}", key, path));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_u64(schema: &serde_json::Map<String, Value>, key: &str, path: &str) -> Result<Option<u64>, String> {
+        match schema.get(key) {
+            None => Ok(None),
+            Some(v) => v.as_u64().map(Some).ok_or_else(|| format!("{} must be a non-negative integer at {}", key, path)),
+        }
+    }
+
+    /// Converts `schema` into rules and returns the name of its rule.
+    pub fn visit(&mut self, schema: &Value, name: &str, path: &str) -> Result<String, String> {
+        let rule_name = if is_reserved_name(name) {
+            format!("{}-", name)
+        } else if name.is_empty() {
+            "root".to_string()
+        } else {
+            name.to_string()
+        };
+
+        let schema = match schema {
+            Value::Bool(true) => {
+                let value = self.add_builtin("value");
+                return Ok(self.add_rule(&rule_name, &value));
+            }
+            Value::Bool(false) => return Err(format!("schema `false` matches nothing at {}", path)),
+            Value::Object(map) => map,
+            _ => return Err(format!("schema must be an object at {}", path)),
+        };
+        Self::check_keywords(schema, path)?;
+
+        let schema_type = schema.get("type");
+
+        if let Some(reference) = schema.get("$ref") {
+            let reference = reference.as_str().ok_or_else(|| format!("$ref must be a string at {}", path))?;
+            // the referenced schema alone decides the rule, so constraints next to it would be lost
+            let definitions = ["$ref", "$defs", "definitions"];
+            if let Some(key) =
+                schema.keys().find(|k| !definitions.contains(&k.as_str()) && !ANNOTATION_KEYWORDS.contains(&k.as_str()))
+            {
+                return Err(format!("unsupported JSON schema: keyword {:?} next to $ref at {}", key, path));
+            }
+            let target = self.resolve_ref(reference, path)?;
+            return Ok(self.add_rule(&rule_name, &target));
+        }
+
+        if let Some(alts) = schema.get("oneOf").or_else(|| schema.get("anyOf")) {
+            // each alternative alone decides the rule, as with $ref
+            let keywords = ["oneOf", "anyOf", "$defs", "definitions"];
+            if schema.contains_key("oneOf") && schema.contains_key("anyOf") {
+                return Err(format!("unsupported JSON schema: oneOf next to anyOf at {}", path));
+            }
+            if let Some(key) =
+                schema.keys().find(|k| !keywords.contains(&k.as_str()) && !ANNOTATION_KEYWORDS.contains(&k.as_str()))
+            {
+                return Err(format!("unsupported JSON schema: keyword {:?} next to anyOf/oneOf at {}", key, path));
+            }
+            let alts = alts.as_array().ok_or_else(|| format!("anyOf/oneOf must be an array at {}", path))?;
+            let rule = self.generate_union_rule(name, alts, path)?;
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        if let Some(Value::Array(types)) = schema_type {
+            let alts: Vec<Value> = types
+                .iter()
+                .map(|t| {
+                    let mut alt = schema.clone();
+                    alt.insert("type".to_string(), t.clone());
+                    Value::Object(alt)
+                })
+                .collect();
+            let rule = self.generate_union_rule(name, &alts, path)?;
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        if let Some(value) = schema.get("const") {
+            let rule = format!("{} space", Self::generate_constant_rule(value));
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().ok_or_else(|| format!("enum must be an array at {}", path))?;
+            if values.is_empty() {
+                return Err(format!("enum must not be empty at {}", path));
+            }
+            let alts: Vec<String> = values.iter().map(Self::generate_constant_rule).collect();
+            let rule = format!("({}) space", alts.join(" | "));
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        let schema_type = match schema_type {
+            None => None,
+            Some(Value::String(t)) => Some(t.as_str()),
+            Some(_) => return Err(format!("type must be a string or an array at {}", path)),
+        };
+
+        // without a type each keyword constrains only values of its own type;
+        // the rule can follow one such type, not several
+        if schema_type.is_none() {
+            let mut types: Vec<&str> = schema.keys().filter_map(|k| Self::keyword_type(k)).collect();
+            types.sort_unstable();
+            types.dedup();
+            if types.len() > 1 {
+                return Err(format!("unsupported JSON schema: keywords of types {:?} without a type at {}", types, path));
+            }
+        }
+
+        if matches!(schema_type, None | Some("object"))
+            && (schema.contains_key("properties")
+                || schema.contains_key("required")
+                || schema.get("additionalProperties").is_some_and(|v| !matches!(v, Value::Bool(true))))
+        {
+            let properties: Vec<(String, Value)> = match schema.get("properties") {
+                None => Vec::new(),
+                Some(Value::Object(props)) => props.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+                Some(_) => return Err(format!("properties must be an object at {}", path)),
+            };
+            let required: HashSet<String> = match schema.get("required") {
+                None => HashSet::new(),
+                Some(Value::Array(req)) => req
+                    .iter()
+                    .map(|v| v.as_str().map(str::to_string).ok_or_else(|| format!("required entries must be strings at {}", path)))
+                    .collect::<Result<_, _>>()?,
+                Some(_) => return Err(format!("required must be an array at {}", path)),
+            };
+            if let Some(missing) = required.iter().find(|k| !properties.iter().any(|(name, _)| name == *k)) {
+                return Err(format!("unsupported JSON schema: required property {:?} is not in properties at {}", missing, path));
+            }
+            let rule = self.build_object_rule(&properties, &required, name, schema.get("additionalProperties"), path)?;
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        if matches!(schema_type, None | Some("array"))
+            && ["items", "prefixItems", "minItems", "maxItems"].iter().any(|k| schema.contains_key(*k))
+        {
+            let any = Value::Object(serde_json::Map::new());
+            // `prefixItems` (or an `items` array, as in older drafts) gives
+            // the leading elements; `items` the ones after them
+            let (tuple, tuple_key, rest) = match (schema.get("prefixItems"), schema.get("items")) {
+                (Some(Value::Array(_)), Some(Value::Array(_))) => {
+                    return Err(format!("unsupported JSON schema: prefixItems next to an items array at {}", path))
+                }
+                (Some(Value::Array(tuple)), items) => (tuple.as_slice(), "prefixItems", items),
+                (Some(_), _) => return Err(format!("prefixItems must be an array at {}", path)),
+                (None, Some(Value::Array(tuple))) => (tuple.as_slice(), "items", None),
+                (None, items) => (&[][..], "items", Some(items.unwrap_or(&any))),
+            };
+            let n_tuple = tuple.len() as u64;
+            // without minItems a tuple is generated in full
+            let min_items = Self::get_u64(schema, "minItems", path)?.unwrap_or(n_tuple);
+            let max_items = Self::get_u64(schema, "maxItems", path)?;
+            if max_items.is_some_and(|max| max < min_items) {
+                return Err(format!("maxItems is smaller than minItems at {}", path));
+            }
+            let rest = match rest {
+                Some(Value::Bool(false)) => None,
+                Some(rest) => Some(rest),
+                // elements past the tuple are unconstrained, but only generated when needed
+                None if min_items > n_tuple => Some(&any),
+                None => None,
+            };
+            if rest.is_none() && min_items > n_tuple {
+                return Err(format!("minItems {} exceeds the {} items allowed at {}", min_items, n_tuple, path));
+            }
+            let max_items = if rest.is_none() { Some(max_items.map_or(n_tuple, |max| max.min(n_tuple))) } else { max_items };
+
+            let prefix = if name.is_empty() { String::new() } else { format!("{}-", name) };
+            let mut item_rules = Vec::with_capacity(tuple.len());
+            for (i, item) in tuple.iter().enumerate() {
+                item_rules.push(self.visit(item, &format!("{}tuple-{}", prefix, i), &format!("{}/{}/{}", path, tuple_key, i))?);
+            }
+            let rest_rule = match rest {
+                Some(rest) => Some(self.visit(rest, &format!("{}item", prefix), &format!("{}/items", path))?),
+                None => None,
+            };
+            let rule = format!(
+                "\"[\" space {} \"]\" space",
+                build_tuple(&item_rules, rest_rule.as_deref(), min_items, max_items, true)
+            );
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        if let Some(format) = schema.get("format") {
+            let format = format.as_str().ok_or_else(|| format!("format must be a string at {}", path))?;
+            if !matches!(schema_type, None | Some("string")) {
+                return Err(format!("format {:?} is only supported on strings at {}", format, path));
+            }
+            if let Some(key) = ["minLength", "maxLength"].into_iter().find(|k| schema.contains_key(*k)) {
+                return Err(format!("unsupported JSON schema: format {:?} combined with {} at {}", format, key, path));
+            }
+            return match format {
+                "date" | "time" | "date-time" => {
+                    let prim = self.add_builtin(&format!("{}-string", format));
+                    Ok(self.add_rule(&rule_name, &prim))
+                }
+                "uuid" => {
+                    let prim = self.add_builtin("uuid");
+                    Ok(self.add_rule(&rule_name, &prim))
+                }
+                _ => Err(format!("unsupported string format {:?} at {}", format, path)),
+            };
+        }
+
+        if matches!(schema_type, None | Some("string")) && (schema.contains_key("minLength") || schema.contains_key("maxLength")) {
+            let char_rule = self.add_builtin("char");
+            let min_len = Self::get_u64(schema, "minLength", path)?.unwrap_or(0);
+            let max_len = Self::get_u64(schema, "maxLength", path)?;
+            if max_len.is_some_and(|max| max < min_len) {
+                return Err(format!("maxLength is smaller than minLength at {}", path));
+            }
+            let rule = format!("\"\\\"\" {} \"\\\"\" space", build_repetition(&char_rule, min_len, max_len, None));
+            return Ok(self.add_rule(&rule_name, &rule));
+        }
+
+        for key in ["properties", "required", "additionalProperties", "items", "prefixItems", "minItems", "maxItems", "minLength", "maxLength"] {
+            if schema.contains_key(key) && !Self::keyword_applies(key, schema_type) {
+                return Err(format!("keyword {:?} does not apply to type {:?} at {}", key, schema_type.unwrap_or("any"), path));
+            }
+        }
+
+        match schema_type {
+            None => {
+                let value = self.add_builtin("value");
+                if rule_name == "root" {
+                    Ok(self.add_rule(&rule_name, &value))
+                } else {
+                    Ok(value)
+                }
+            }
+            Some(t) if matches!(t, "object" | "array" | "string" | "number" | "integer" | "boolean" | "null") => {
+                let prim = self.add_builtin(t);
+                if rule_name == "root" {
+                    Ok(self.add_rule(&rule_name, &prim))
+                } else {
+                    Ok(prim)
+                }
+            }
+            Some(t) => Err(format!("unsupported JSON schema type {:?} at {}", t, path)),
+        }
+    }
+
+    /// Type of the values a keyword constrains, `None` for any value
+    fn keyword_type(key: &str) -> Option<&'static str> {
+        match key {
+            "properties" | "required" | "additionalProperties" => Some("object"),
+            "items" | "prefixItems" | "minItems" | "maxItems" => Some("array"),
+            "minLength" | "maxLength" | "format" => Some("string"),
+            _ => None,
+        }
+    }
+
+    fn keyword_applies(key: &str, schema_type: Option<&str>) -> bool {
+        match (Self::keyword_type(key), schema_type) {
+            (Some(keyword_type), Some(schema_type)) => keyword_type == schema_type,
+            _ => true,
+        }
+    }
+
+    /// Renders the collected rules as GBNF text.
+    pub fn format_grammar(&self) -> String {
+        let mut out = String::new();
+        for (name, rule) in &self.rules {
+            out.push_str(&format!("{} ::= {}\n", name, rule));
+        }
+        out
+    }
+}
+
+/// Converts a JSON schema into a GBNF grammar with a `root` rule.
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String, String> {
+    let mut converter = SchemaConverter::new(schema.clone());
+    converter.visit(schema, "", "#")?;
+    Ok(converter.format_grammar())
 }
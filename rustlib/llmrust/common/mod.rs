@@ -1,6 +1,15 @@
 // common/mod.rs - Common module entry point
 #![allow(dead_code)]
 
+pub mod arg;
+pub mod base64;
+pub mod chat;
+#[allow(clippy::module_inception)]
+pub mod common;
+pub mod json_schema_to_grammar;
+pub mod prompt_cache;
+pub mod sampling;
+
 pub fn debug_print() {
     println!("DEBUG: common/mod.rs - File loaded successfully");
 }
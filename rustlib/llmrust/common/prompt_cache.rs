@@ -0,0 +1,326 @@
+// common/prompt_cache.rs - Reuse of cached prompt prefixes across requests
+//
+// Finished sequences stay in the paged KV cache and are indexed by their
+// tokens in a radix tree. A new request looks up the longest cached prefix of
+// its prompt, shares those blocks (copy-on-write) and only evaluates the rest.
+// Entries are evicted least recently used first, when the cache grows past its
+// memory cap or when blocks are needed for new requests.
+//
+// The CLI keeps its prompt in a session file instead (`path_prompt_cache`),
+// restored at startup so that only the part of the prompt that changed is
+// evaluated again.
+#![allow(dead_code)]
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use crate::llmrust::src::llama_io::{llama_state_seq_load_file, llama_state_seq_save_file};
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvCachePaged};
+use crate::llmrust::src::llama_memory::{LlamaMemory, LlamaSeqId};
+use crate::llmrust::src::llama_vocab::LlamaToken;
+
+fn common_prefix_len(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[derive(Debug, Default)]
+struct RadixNode {
+    /// Tokens on the edge from the parent
+    edge: Vec<LlamaToken>,
+    /// Children keyed by the first token of their edge
+    children: BTreeMap<LlamaToken, RadixNode>,
+    /// Sequence whose tokens end at this node
+    seq_id: Option<LlamaSeqId>,
+}
+
+impl RadixNode {
+    fn any_seq(&self) -> Option<LlamaSeqId> {
+        self.seq_id.or_else(|| self.children.values().find_map(|c| c.any_seq()))
+    }
+
+    /// Drops empty leaves and merges pass-through nodes into their only child.
+    fn prune(&mut self) {
+        for child in self.children.values_mut() {
+            child.prune();
+        }
+        self.children.retain(|_, c| c.seq_id.is_some() || !c.children.is_empty());
+        for child in self.children.values_mut() {
+            if child.seq_id.is_none() && child.children.len() == 1 {
+                let (_, grandchild) = child.children.pop_first().unwrap();
+                child.edge.extend(grandchild.edge);
+                child.children = grandchild.children;
+                child.seq_id = grandchild.seq_id;
+            }
+        }
+    }
+}
+
+/// Radix tree mapping token sequences to the sequence ids holding them
+#[derive(Debug, Default)]
+pub struct LlamaRadixTree {
+    root: RadixNode,
+    n_entries: usize,
+}
+
+impl LlamaRadixTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_entries == 0
+    }
+
+    /// Adds `tokens` as held by `seq_id`. Entries whose tokens are a prefix of
+    /// `tokens` (including an equal one) are superseded: they are removed and
+    /// their sequence ids returned.
+    pub fn insert(&mut self, tokens: &[LlamaToken], seq_id: LlamaSeqId) -> Vec<LlamaSeqId> {
+        let mut superseded = Vec::new();
+        if tokens.is_empty() {
+            return superseded;
+        }
+
+        let mut node = &mut self.root;
+        let mut rest = tokens;
+        loop {
+            superseded.extend(node.seq_id.take());
+            if rest.is_empty() {
+                node.seq_id = Some(seq_id);
+                break;
+            }
+            let child = match node.children.entry(rest[0]) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    e.insert(RadixNode { edge: rest.to_vec(), children: BTreeMap::new(), seq_id: Some(seq_id) });
+                    break;
+                }
+            };
+            let n = common_prefix_len(&child.edge, rest);
+            if n < child.edge.len() {
+                let tail = RadixNode {
+                    edge: child.edge.split_off(n),
+                    children: std::mem::take(&mut child.children),
+                    seq_id: child.seq_id.take(),
+                };
+                child.children.insert(tail.edge[0], tail);
+            }
+            rest = &rest[n..];
+            node = child;
+        }
+
+        self.root.prune();
+        self.n_entries = self.n_entries + 1 - superseded.len();
+        superseded
+    }
+
+    /// Removes the entry for exactly `tokens` and returns its sequence id.
+    pub fn remove(&mut self, tokens: &[LlamaToken]) -> Option<LlamaSeqId> {
+        let mut node = &mut self.root;
+        let mut rest = tokens;
+        while !rest.is_empty() {
+            let child = node.children.get_mut(&rest[0])?;
+            if !rest.starts_with(&child.edge) {
+                return None;
+            }
+            rest = &rest[child.edge.len()..];
+            node = child;
+        }
+        let seq_id = node.seq_id.take()?;
+        self.root.prune();
+        self.n_entries -= 1;
+        Some(seq_id)
+    }
+
+    /// Longest prefix of `tokens` held by any entry, with one of the sequences
+    /// holding it.
+    pub fn longest_prefix(&self, tokens: &[LlamaToken]) -> Option<(LlamaSeqId, usize)> {
+        let mut node = &self.root;
+        let mut n_match = 0;
+        while let Some(child) = tokens.get(n_match).and_then(|t| node.children.get(t)) {
+            let n = common_prefix_len(&child.edge, &tokens[n_match..]);
+            n_match += n;
+            node = child;
+            if n < child.edge.len() {
+                break;
+            }
+        }
+        if n_match == 0 {
+            return None;
+        }
+        // every sequence below the node shares the matched tokens
+        node.any_seq().map(|seq_id| (seq_id, n_match))
+    }
+}
+
+#[derive(Debug)]
+struct PromptCacheEntry {
+    group: u64,
+    tokens: Vec<LlamaToken>,
+    last_used: u64,
+}
+
+/// Cached prompt prefix found for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptCacheHit {
+    /// Sequence holding the cached tokens
+    pub seq_id: LlamaSeqId,
+    /// Number of leading prompt tokens it holds
+    pub n_tokens: usize,
+}
+
+/// Sequences of a paged KV cache kept for reuse, indexed by their tokens.
+/// Entries are kept per group: cells computed under one set of adapters
+/// cannot be reused under another.
+#[derive(Debug)]
+pub struct PromptCache {
+    trees: BTreeMap<u64, LlamaRadixTree>,
+    entries: BTreeMap<LlamaSeqId, PromptCacheEntry>,
+    /// Memory cap in bytes of the blocks held by cached sequences
+    max_bytes: usize,
+    tick: u64,
+}
+
+impl PromptCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { trees: BTreeMap::new(), entries: BTreeMap::new(), max_bytes, tick: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn contains(&self, seq_id: LlamaSeqId) -> bool {
+        self.entries.contains_key(&seq_id)
+    }
+
+    fn touch(&mut self, seq_id: LlamaSeqId) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(&seq_id) {
+            entry.last_used = self.tick;
+        }
+    }
+
+    /// Finds the longest cached prefix of `tokens` in `group` and marks it as used.
+    pub fn lookup(&mut self, group: u64, tokens: &[LlamaToken]) -> Option<PromptCacheHit> {
+        let (seq_id, n_tokens) = self.trees.get(&group)?.longest_prefix(tokens)?;
+        self.touch(seq_id);
+        Some(PromptCacheHit { seq_id, n_tokens })
+    }
+
+    /// Takes over sequence `seq_id` of `kv`, which holds `tokens` at positions
+    /// 0.., as a cache entry of `group`. Entries of the group it extends are
+    /// dropped from the cache and the KV cache, then least recently used
+    /// entries are evicted down to the memory cap.
+    pub fn insert(&mut self, kv: &mut LlamaKvCachePaged, seq_id: LlamaSeqId, group: u64, tokens: Vec<LlamaToken>) {
+        if tokens.is_empty() {
+            kv.seq_rm(seq_id, -1, -1);
+            return;
+        }
+        if let Some(old) = self.entries.remove(&seq_id) {
+            self.remove_from_tree(&old);
+        }
+        for old in self.trees.entry(group).or_default().insert(&tokens, seq_id) {
+            self.entries.remove(&old);
+            kv.seq_rm(old, -1, -1);
+        }
+        self.entries.insert(seq_id, PromptCacheEntry { group, tokens, last_used: 0 });
+        self.touch(seq_id);
+
+        while self.memory_size(kv) > self.max_bytes && self.evict_lru(kv).is_some() {}
+    }
+
+    /// Distinct KV blocks held by cached sequences
+    pub fn n_blocks(&self, kv: &LlamaKvCachePaged) -> usize {
+        let blocks: BTreeSet<usize> = self.entries.keys().flat_map(|&seq_id| kv.seq_blocks(seq_id).iter().copied()).collect();
+        blocks.len()
+    }
+
+    pub fn memory_size(&self, kv: &LlamaKvCachePaged) -> usize {
+        self.n_blocks(kv) * kv.block_memory_size()
+    }
+
+    /// Evicts the least recently used entry and frees its sequence.
+    pub fn evict_lru(&mut self, kv: &mut LlamaKvCachePaged) -> Option<LlamaSeqId> {
+        let seq_id = self.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(&seq_id, _)| seq_id)?;
+        let entry = self.entries.remove(&seq_id).unwrap();
+        self.remove_from_tree(&entry);
+        kv.seq_rm(seq_id, -1, -1);
+        Some(seq_id)
+    }
+
+    fn remove_from_tree(&mut self, entry: &PromptCacheEntry) {
+        if let Some(tree) = self.trees.get_mut(&entry.group) {
+            tree.remove(&entry.tokens);
+            if tree.is_empty() {
+                self.trees.remove(&entry.group);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, kv: &mut LlamaKvCachePaged) {
+        while self.evict_lru(kv).is_some() {}
+    }
+}
+
+/// Session file of the CLI prompt cache
+#[derive(Debug, Clone)]
+pub struct PromptCacheFile {
+    pub path: PathBuf,
+    /// Also save the generated tokens, at the end of the run (`prompt_cache_all`)
+    pub all: bool,
+    /// Load the file but never write it (`prompt_cache_ro`)
+    pub read_only: bool,
+}
+
+impl PromptCacheFile {
+    pub fn new(path: impl Into<PathBuf>, all: bool, read_only: bool) -> Self {
+        Self { path: path.into(), all, read_only }
+    }
+
+    /// Restores the saved session into `seq_id` and drops the cells past the
+    /// part that matches `prompt`. Returns the number of prompt tokens that do
+    /// not need to be evaluated again; the last prompt token is always
+    /// evaluated. A missing file restores nothing.
+    pub fn restore(&self, kv: &mut LlamaKvCache, seq_id: LlamaSeqId, fingerprint: u64, prompt: &[LlamaToken]) -> Result<usize, String> {
+        if !self.path.exists() {
+            return Ok(0);
+        }
+        let tokens = llama_state_seq_load_file(&self.path, kv, seq_id, fingerprint, kv.size())?;
+        let n_match = common_prefix_len(&tokens, prompt).min(prompt.len().saturating_sub(1));
+        kv.seq_rm(seq_id, n_match as i32, -1);
+        Ok(n_match)
+    }
+
+    /// Saves the evaluated prompt; the run's tokens are saved by `save_final`
+    /// instead when `all` is set. Returns whether the file was written.
+    pub fn save_prompt(&self, kv: &LlamaKvCache, seq_id: LlamaSeqId, fingerprint: u64, prompt: &[LlamaToken]) -> Result<bool, String> {
+        self.save(!self.all, kv, seq_id, fingerprint, prompt)
+    }
+
+    /// Saves the prompt and generated tokens at the end of a run when `all` is
+    /// set. Returns whether the file was written.
+    pub fn save_final(&self, kv: &LlamaKvCache, seq_id: LlamaSeqId, fingerprint: u64, tokens: &[LlamaToken]) -> Result<bool, String> {
+        self.save(self.all, kv, seq_id, fingerprint, tokens)
+    }
+
+    fn save(&self, enabled: bool, kv: &LlamaKvCache, seq_id: LlamaSeqId, fingerprint: u64, tokens: &[LlamaToken]) -> Result<bool, String> {
+        if !enabled || self.read_only {
+            return Ok(false);
+        }
+        llama_state_seq_save_file(&self.path, kv, seq_id, fingerprint, tokens)?;
+        Ok(true)
+    }
+}
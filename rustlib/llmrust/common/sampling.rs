@@ -1,6 +1,168 @@
-// common/sampling.rs - Auto-generated debug file
+// common/sampling.rs - Sampling parameters and sampler chain construction
+//
+// Turns request-level sampling options into a sampler chain. Token-level
+// stages run first (logit bias, banned strings, grammar or regex), then the
+// truncation stages and finally the selecting stage.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: common/sampling.rs - File loaded successfully");
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_grammar::GrammarTrigger;
+use crate::llmrust::src::llama_sampling::{
+    BannedStringsSampler, DistSampler, GrammarSampler, GreedySampler, LogitBiasSampler, MinPSampler,
+    RegexSampler, SamplerChain, TempSampler, TopKSampler, TopPSampler, LLAMA_DEFAULT_SEED,
+};
+use crate::llmrust::src::llama_vocab::{LlamaToken, LlamaVocab};
+
+/// Key of a `logit_bias` entry: a token id or text resolved through the tokenizer
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogitBiasKey {
+    Token(LlamaToken),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogitBiasEntry {
+    pub key: LogitBiasKey,
+    pub bias: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommonParamsSampling {
+    pub seed: u32,
+    pub top_k: i32,
+    pub top_p: f32,
+    pub min_p: f32,
+    pub temp: f32,
+    pub min_keep: usize,
+    pub logit_bias: Vec<LogitBiasEntry>,
+    pub banned_strings: Vec<String>,
+    /// GBNF grammar; empty for none
+    pub grammar: String,
+    pub grammar_lazy: bool,
+    pub grammar_triggers: Vec<GrammarTrigger>,
+    /// Regular expression the whole output must match; empty for none
+    pub regex: String,
+}
+
+impl Default for CommonParamsSampling {
+    fn default() -> Self {
+        Self {
+            seed: LLAMA_DEFAULT_SEED,
+            top_k: 40,
+            top_p: 0.95,
+            min_p: 0.05,
+            temp: 0.80,
+            min_keep: 0,
+            logit_bias: Vec::new(),
+            banned_strings: Vec::new(),
+            grammar: String::new(),
+            grammar_lazy: false,
+            grammar_triggers: Vec::new(),
+            regex: String::new(),
+        }
+    }
+}
+
+/// Parses a `logit_bias` field, either OpenAI style `{"15043": -100, "Hello": 5}`
+/// or as pairs `[[15043, -100], ["Hello", false]]`. `false` bans the token(s).
+pub fn parse_logit_bias(value: &serde_json::Value) -> Result<Vec<LogitBiasEntry>, String> {
+    fn parse_bias(v: &serde_json::Value) -> Result<f32, String> {
+        match v {
+            serde_json::Value::Bool(false) => Ok(f32::NEG_INFINITY),
+            serde_json::Value::Number(n) => n.as_f64().map(|b| b as f32).ok_or_else(|| "invalid bias value".to_string()),
+            _ => Err(format!("logit bias must be a number or false, got {}", v)),
+        }
+    }
+    fn parse_key(v: &serde_json::Value) -> Result<LogitBiasKey, String> {
+        match v {
+            serde_json::Value::Number(n) => n
+                .as_i64()
+                .and_then(|t| LlamaToken::try_from(t).ok())
+                .map(LogitBiasKey::Token)
+                .ok_or_else(|| format!("invalid token id {}", n)),
+            serde_json::Value::String(s) if s.is_empty() => Err("logit bias text must not be empty".to_string()),
+            serde_json::Value::String(s) => Ok(match s.parse::<LlamaToken>() {
+                Ok(token) => LogitBiasKey::Token(token),
+                Err(_) => LogitBiasKey::Text(s.clone()),
+            }),
+            _ => Err(format!("logit bias key must be a token id or string, got {}", v)),
+        }
+    }
+
+    match value {
+        serde_json::Value::Object(map) => map
+            .iter()
+            .map(|(k, v)| Ok(LogitBiasEntry { key: parse_key(&serde_json::Value::String(k.clone()))?, bias: parse_bias(v)? }))
+            .collect(),
+        serde_json::Value::Array(pairs) => pairs
+            .iter()
+            .map(|pair| match pair.as_array().map(|p| p.as_slice()) {
+                Some([k, v]) => Ok(LogitBiasEntry { key: parse_key(k)?, bias: parse_bias(v)? }),
+                _ => Err("logit bias entries must be [key, bias] pairs".to_string()),
+            })
+            .collect(),
+        _ => Err("logit_bias must be an object or an array".to_string()),
+    }
+}
+
+/// Resolves bias entries to token ids. Text keys bias every token of the text's
+/// tokenization, as if each token had been listed by id.
+pub fn resolve_logit_bias(vocab: &LlamaVocab, entries: &[LogitBiasEntry]) -> Result<Vec<(LlamaToken, f32)>, String> {
+    let mut biases = Vec::new();
+    for entry in entries {
+        match &entry.key {
+            LogitBiasKey::Token(token) => {
+                if *token < 0 || *token as usize >= vocab.n_tokens() {
+                    return Err(format!("logit bias token {} is out of range", token));
+                }
+                biases.push((*token, entry.bias));
+            }
+            LogitBiasKey::Text(text) => {
+                let tokens = vocab.tokenize(text);
+                if tokens.is_empty() {
+                    return Err(format!("logit bias text {:?} cannot be tokenized", text));
+                }
+                biases.extend(tokens.into_iter().map(|t| (t, entry.bias)));
+            }
+        }
+    }
+    Ok(biases)
+}
+
+/// Builds the sampler chain for `params`.
+pub fn common_sampler_init(vocab: Arc<LlamaVocab>, params: &CommonParamsSampling) -> Result<SamplerChain, String> {
+    if !params.grammar.is_empty() && !params.regex.is_empty() {
+        return Err("grammar and regex cannot be combined".to_string());
+    }
+
+    let mut chain = SamplerChain::new();
+    if !params.logit_bias.is_empty() {
+        chain.add(Box::new(LogitBiasSampler::new(&resolve_logit_bias(&vocab, &params.logit_bias)?)));
+    }
+    if !params.banned_strings.is_empty() {
+        chain.add(Box::new(BannedStringsSampler::new(vocab.clone(), &params.banned_strings)?));
+    }
+    if !params.grammar.is_empty() {
+        let sampler = if params.grammar_lazy {
+            GrammarSampler::new_lazy(vocab.clone(), &params.grammar, "root", params.grammar_triggers.clone())?
+        } else {
+            GrammarSampler::new(vocab.clone(), &params.grammar, "root")?
+        };
+        chain.add(Box::new(sampler));
+    }
+    if !params.regex.is_empty() {
+        chain.add(Box::new(RegexSampler::new(vocab.clone(), &params.regex)?));
+    }
+
+    if params.temp <= 0.0 {
+        chain.add(Box::new(GreedySampler));
+    } else {
+        chain.add(Box::new(TopKSampler::new(params.top_k)));
+        chain.add(Box::new(TopPSampler::new(params.top_p, params.min_keep)));
+        chain.add(Box::new(MinPSampler::new(params.min_p, params.min_keep)));
+        chain.add(Box::new(TempSampler::new(params.temp)));
+        chain.add(Box::new(DistSampler::new(params.seed)));
+    }
+    Ok(chain)
 }
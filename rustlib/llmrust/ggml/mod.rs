@@ -0,0 +1,4 @@
+// ggml/mod.rs - Tensor library: public headers and CPU implementation
+#![allow(dead_code)]
+
+pub mod src;
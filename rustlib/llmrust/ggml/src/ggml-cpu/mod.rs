@@ -1,6 +1,11 @@
-// ggml/src/ggml-cpu/mod.rs - Auto-generated debug file
+// ggml/src/ggml-cpu/mod.rs - CPU backend kernels
 #![allow(dead_code)]
 
+pub mod ops;
+pub mod quants;
+pub mod repack;
+pub mod vec;
+
 pub fn debug_print() {
     println!("DEBUG: ggml/src/ggml-cpu/mod.rs - File loaded successfully");
 }
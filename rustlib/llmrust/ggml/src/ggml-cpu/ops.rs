@@ -1,6 +1,306 @@
-// ggml/src/ggml-cpu/ops.rs - Auto-generated debug file
+// ggml/src/ggml-cpu/ops.rs - CPU implementations of tensor operations
+//
+// Kernels work one head or one row at a time: activations are f32 slices and
+// stored K/V rows are bytes in their ggml type. Callers handle the tensor
+// bookkeeping.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml-cpu/ops.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_cpu::quants::{vec_dot_q4_0_q8_0, vec_dot_q8_0_q8_0, vec_mad_q4_0, vec_mad_q8_0};
+use crate::llmrust::ggml::src::ggml_cpu::vec::{vec_dot_f16, vec_dot_f32, vec_mad_f16, vec_mad_f32};
+use crate::llmrust::ggml::src::ggml_quants::quantize_row_q8_0;
+
+/// How RoPE pairs up the dimensions of a head
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RopeMode {
+    /// Adjacent pairs (x[2i], x[2i+1]), as in the original LLaMA
+    Normal,
+    /// Split halves (x[i], x[i + n_dims/2]), as in GPT-NeoX
+    Neox,
+}
+
+/// RoPE settings of one rotation: the base frequencies and how they are
+/// scaled for contexts longer than the training context.
+///
+/// Linear scaling divides positions by `1 / freq_scale`. YaRN (a nonzero
+/// `ext_factor`) keeps the high-frequency dimensions extrapolated and
+/// interpolates only the low-frequency ones, ramping between the two over
+/// the dimensions whose wavelength fits between `beta_fast` and `beta_slow`
+/// rotations in the original context, and scales magnitudes to keep the
+/// attention entropy.
+/// LongRoPE divides each base frequency by its own factor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RopeParams {
+    pub mode: RopeMode,
+    /// Rotated dimensions per head
+    pub n_dims: usize,
+    pub freq_base: f32,
+    pub freq_scale: f32,
+    /// YaRN mix of extrapolated and interpolated frequencies, 0 disables YaRN
+    pub ext_factor: f32,
+    /// Scale of the rotated values
+    pub attn_factor: f32,
+    pub beta_fast: f32,
+    pub beta_slow: f32,
+    /// Context length the model was trained on, for the YaRN ramp
+    pub n_ctx_orig: u32,
+    /// LongRoPE divisors of the `n_dims / 2` base frequencies
+    pub freq_factors: Option<Vec<f32>>,
+}
+
+impl RopeParams {
+    /// Plain RoPE with linear scaling
+    pub fn new(mode: RopeMode, n_dims: usize, freq_base: f32, freq_scale: f32) -> Self {
+        Self {
+            mode,
+            n_dims,
+            freq_base,
+            freq_scale,
+            ext_factor: 0.0,
+            attn_factor: 1.0,
+            beta_fast: 32.0,
+            beta_slow: 1.0,
+            n_ctx_orig: 0,
+            freq_factors: None,
+        }
+    }
+
+    /// First and last dimension of the YaRN ramp
+    pub fn yarn_corr_dims(&self) -> [f32; 2] {
+        let corr_dim = |n_rot: f32| {
+            self.n_dims as f32 * (self.n_ctx_orig as f32 / (n_rot * 2.0 * std::f32::consts::PI)).ln()
+                / (2.0 * self.freq_base.ln())
+        };
+        let start = corr_dim(self.beta_fast).floor();
+        let end = corr_dim(self.beta_slow).ceil();
+        [start.max(0.0), end.min(self.n_dims as f32 - 1.0)]
+    }
+
+    /// Magnitude of the rotated values relative to the input
+    pub fn mscale(&self) -> f32 {
+        if self.ext_factor != 0.0 {
+            self.attn_factor * (1.0 + 0.1 * (1.0 / self.freq_scale).ln())
+        } else {
+            self.attn_factor
+        }
+    }
+
+    /// The same rotation at unit magnitude, to move values that were already
+    /// rotated (and scaled) to another position.
+    pub fn shift(&self) -> Self {
+        Self { attn_factor: self.attn_factor / self.mscale(), ..self.clone() }
+    }
+
+    /// `(cos, sin)` of each rotated pair at position `pos`, times `mscale`
+    pub fn cache(&self, pos: f32) -> Vec<(f32, f32)> {
+        let half = self.n_dims / 2;
+        let theta_scale = self.freq_base.powf(-2.0 / self.n_dims as f32);
+        let corr_dims = self.yarn_corr_dims();
+        let mscale = self.mscale();
+        let mut theta_base = pos;
+        (0..half)
+            .map(|i| {
+                let ff = self.freq_factors.as_ref().map_or(1.0, |f| f[i]);
+                let theta_extrap = theta_base / ff;
+                let theta_interp = self.freq_scale * theta_extrap;
+                let theta = if self.ext_factor != 0.0 {
+                    let y = (i as f32 - corr_dims[0]) / (corr_dims[1] - corr_dims[0]).max(0.001);
+                    let ramp_mix = (1.0 - y.clamp(0.0, 1.0)) * self.ext_factor;
+                    theta_interp * (1.0 - ramp_mix) + theta_extrap * ramp_mix
+                } else {
+                    theta_interp
+                };
+                theta_base *= theta_scale;
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                (cos_theta * mscale, sin_theta * mscale)
+            })
+            .collect()
+    }
+}
+
+/// Rotates the first `params.n_dims` values of one head by position `pos`.
+pub fn rope_ext(x: &mut [f32], pos: f32, params: &RopeParams) {
+    let half = params.n_dims / 2;
+    for (i, (cos_theta, sin_theta)) in params.cache(pos).into_iter().enumerate() {
+        let (i0, i1) = match params.mode {
+            RopeMode::Normal => (2 * i, 2 * i + 1),
+            RopeMode::Neox => (i, i + half),
+        };
+        let x0 = x[i0];
+        let x1 = x[i1];
+        x[i0] = x0 * cos_theta - x1 * sin_theta;
+        x[i1] = x0 * sin_theta + x1 * cos_theta;
+    }
+}
+
+/// Rotates the first `n_dims` values of one head by position `pos`.
+///
+/// Rotations compose additively, so applying this with `pos = delta` to an
+/// already rotated head moves it from position `p` to `p + delta`.
+pub fn rope_f32(x: &mut [f32], n_dims: usize, pos: f32, freq_base: f32, freq_scale: f32, mode: RopeMode) {
+    rope_ext(x, pos, &RopeParams::new(mode, n_dims, freq_base, freq_scale));
+}
+
+/// A query head converted for dot products with keys of one storage type:
+/// kept in f32 for f32/f16 keys, quantized to q8_0 for quantized keys.
+pub enum QueryRow {
+    F32(Vec<f32>),
+    Q8_0(Vec<u8>),
+}
+
+impl QueryRow {
+    pub fn new(q: &[f32], type_k: GgmlType) -> Self {
+        if type_k.is_quantized() {
+            let mut data = vec![0u8; GgmlType::Q8_0.row_size(q.len())];
+            quantize_row_q8_0(q, &mut data);
+            Self::Q8_0(data)
+        } else {
+            Self::F32(q.to_vec())
+        }
+    }
+
+    /// Dot product with a key row stored as `type_k`
+    pub fn dot(&self, type_k: GgmlType, k: &[u8]) -> f32 {
+        match (self, type_k) {
+            (Self::F32(q), GgmlType::F32) => vec_dot_f32(k, q),
+            (Self::F32(q), GgmlType::F16) => vec_dot_f16(k, q),
+            (Self::Q8_0(q), GgmlType::Q8_0) => vec_dot_q8_0_q8_0(k, q),
+            (Self::Q8_0(q), GgmlType::Q4_0) => vec_dot_q4_0_q8_0(k, q),
+            _ => panic!("query row was prepared for a different key type than {}", type_k.name()),
+        }
+    }
+}
+
+/// y += x * v for a value row stored as `type_v`
+pub fn vec_mad_row(type_v: GgmlType, y: &mut [f32], x: &[u8], v: f32) {
+    match type_v {
+        GgmlType::F32 => vec_mad_f32(y, x, v),
+        GgmlType::F16 => vec_mad_f16(y, x, v),
+        GgmlType::Q4_0 => vec_mad_q4_0(y, x, v),
+        GgmlType::Q8_0 => vec_mad_q8_0(y, x, v),
+    }
+}
+
+/// Attention of one query head: softmax(scale * q·k + mask) · v, where `k` and
+/// `v` are the rows of one key/value head in their storage types and `mask`
+/// holds 0 or -inf per row. The output is zero if every row is masked.
+#[allow(clippy::too_many_arguments)]
+pub fn attn_head(
+    q: &[f32],
+    k: &[&[u8]],
+    type_k: GgmlType,
+    v: &[&[u8]],
+    type_v: GgmlType,
+    mask: &[f32],
+    scale: f32,
+    out: &mut [f32],
+) {
+    out.fill(0.0);
+    let q = QueryRow::new(q, type_k);
+    let scores: Vec<f32> = k
+        .iter()
+        .zip(mask)
+        .map(|(k, &m)| if m == f32::NEG_INFINITY { m } else { q.dot(type_k, k) * scale + m })
+        .collect();
+
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return;
+    }
+    let mut sum = 0.0;
+    for (s, v) in scores.iter().zip(v) {
+        if *s == f32::NEG_INFINITY {
+            continue;
+        }
+        let w = (s - max).exp();
+        sum += w;
+        vec_mad_row(type_v, out, v, w);
+    }
+    out.iter_mut().for_each(|o| *o /= sum);
+}
+
+/// Key/value rows processed per step of `flash_attn_ext`
+pub const FLASH_ATTN_KV_TILE: usize = 32;
+
+/// Fused attention of `n_q` query heads that share one key/value head (the
+/// query heads of a GQA group), `q` holding them one after the other. Each
+/// output head is the same softmax(scale * q·k + mask) · v as `attn_head`,
+/// but the key/value rows are walked in tiles of `FLASH_ATTN_KV_TILE` with
+/// an online softmax: only one tile of scores per head is held, the running
+/// output is rescaled whenever the maximum score grows, and every K/V row is
+/// read once for all the heads of the group. Heads with every row masked
+/// get zeros.
+#[allow(clippy::too_many_arguments)]
+pub fn flash_attn_ext(
+    q: &[f32],
+    n_q: usize,
+    k: &[&[u8]],
+    type_k: GgmlType,
+    v: &[&[u8]],
+    type_v: GgmlType,
+    mask: &[f32],
+    scale: f32,
+    out: &mut [f32],
+) {
+    out.fill(0.0);
+    if n_q == 0 {
+        return;
+    }
+    let (head_k, head_v) = (q.len() / n_q, out.len() / n_q);
+    let queries: Vec<QueryRow> = q.chunks_exact(head_k).map(|q| QueryRow::new(q, type_k)).collect();
+    // running maximum and sum of exponentials per head
+    let mut m = vec![f32::NEG_INFINITY; n_q];
+    let mut l = vec![0.0f32; n_q];
+    let mut scores = [0.0f32; FLASH_ATTN_KV_TILE];
+
+    for t0 in (0..k.len()).step_by(FLASH_ATTN_KV_TILE) {
+        let t1 = (t0 + FLASH_ATTN_KV_TILE).min(k.len());
+        if mask[t0..t1].iter().all(|&x| x == f32::NEG_INFINITY) {
+            continue;
+        }
+        for (h, query) in queries.iter().enumerate() {
+            let mut tile_max = f32::NEG_INFINITY;
+            for (j, s) in scores[..t1 - t0].iter_mut().enumerate() {
+                let mk = mask[t0 + j];
+                *s = if mk == f32::NEG_INFINITY { mk } else { query.dot(type_k, k[t0 + j]) * scale + mk };
+                tile_max = tile_max.max(*s);
+            }
+            if tile_max == f32::NEG_INFINITY {
+                continue;
+            }
+            let o = &mut out[h * head_v..(h + 1) * head_v];
+            if tile_max > m[h] {
+                let ms = (m[h] - tile_max).exp();
+                o.iter_mut().for_each(|x| *x *= ms);
+                l[h] *= ms;
+                m[h] = tile_max;
+            }
+            for (j, &s) in scores[..t1 - t0].iter().enumerate() {
+                if s == f32::NEG_INFINITY {
+                    continue;
+                }
+                let w = (s - m[h]).exp();
+                l[h] += w;
+                vec_mad_row(type_v, o, v[t0 + j], w);
+            }
+        }
+    }
+    for (o, &l) in out.chunks_exact_mut(head_v).zip(&l) {
+        if l > 0.0 {
+            o.iter_mut().for_each(|x| *x /= l);
+        }
+    }
+}
+
+/// `out[j * nrows + r] = w_r · x_j` for a `[ncols, nrows]` weight matrix in
+/// file layout and `x.len() / ncols` activation rows. For quantized weights
+/// the activations are quantized to q8_0 first.
+pub fn mul_mat(ty: GgmlType, w: &[u8], ncols: usize, nrows: usize, x: &[f32], out: &mut [f32]) {
+    let row_size = ty.row_size(ncols);
+    for (xr, or) in x.chunks_exact(ncols).zip(out.chunks_exact_mut(nrows)) {
+        let q = QueryRow::new(xr, ty);
+        for (o, wr) in or.iter_mut().zip(w.chunks_exact(row_size)) {
+            *o = q.dot(ty, wr);
+        }
+    }
 }
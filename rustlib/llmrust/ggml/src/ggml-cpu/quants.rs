@@ -1,6 +1,52 @@
-// ggml/src/ggml-cpu/quants.rs - Auto-generated debug file
+// ggml/src/ggml-cpu/quants.rs - Vector kernels on quantized rows
+//
+// Dot products run on the integer values of each block and apply the block
+// scales once per block, so quantized rows are never expanded to f32. The
+// other operand is quantized to q8_0 first, as ggml does.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml-cpu/quants.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml_quants::{block_scale, BLOCK_Q4_0_SIZE, BLOCK_Q8_0_SIZE, QK4_0, QK8_0};
+
+pub fn vec_dot_q8_0_q8_0(x: &[u8], y: &[u8]) -> f32 {
+    x.chunks_exact(BLOCK_Q8_0_SIZE)
+        .zip(y.chunks_exact(BLOCK_Q8_0_SIZE))
+        .map(|(xb, yb)| {
+            let sumi: i32 = xb[2..].iter().zip(&yb[2..]).map(|(&a, &b)| a as i8 as i32 * b as i8 as i32).sum();
+            sumi as f32 * block_scale(xb) * block_scale(yb)
+        })
+        .sum()
+}
+
+pub fn vec_dot_q4_0_q8_0(x: &[u8], y: &[u8]) -> f32 {
+    x.chunks_exact(BLOCK_Q4_0_SIZE)
+        .zip(y.chunks_exact(BLOCK_Q8_0_SIZE))
+        .map(|(xb, yb)| {
+            let mut sumi = 0i32;
+            for j in 0..QK4_0 / 2 {
+                let v0 = (xb[2 + j] & 0x0f) as i32 - 8;
+                let v1 = (xb[2 + j] >> 4) as i32 - 8;
+                sumi += v0 * yb[2 + j] as i8 as i32 + v1 * yb[2 + j + QK4_0 / 2] as i8 as i32;
+            }
+            sumi as f32 * block_scale(xb) * block_scale(yb)
+        })
+        .sum()
+}
+
+pub fn vec_mad_q8_0(y: &mut [f32], x: &[u8], v: f32) {
+    for (yb, xb) in y.chunks_exact_mut(QK8_0).zip(x.chunks_exact(BLOCK_Q8_0_SIZE)) {
+        let d = block_scale(xb) * v;
+        for (acc, &q) in yb.iter_mut().zip(&xb[2..]) {
+            *acc += q as i8 as f32 * d;
+        }
+    }
+}
+
+pub fn vec_mad_q4_0(y: &mut [f32], x: &[u8], v: f32) {
+    for (yb, xb) in y.chunks_exact_mut(QK4_0).zip(x.chunks_exact(BLOCK_Q4_0_SIZE)) {
+        let d = block_scale(xb) * v;
+        for j in 0..QK4_0 / 2 {
+            yb[j] += ((xb[2 + j] & 0x0f) as i32 - 8) as f32 * d;
+            yb[j + QK4_0 / 2] += ((xb[2 + j] >> 4) as i32 - 8) as f32 * d;
+        }
+    }
 }
@@ -1,6 +1,210 @@
-// ggml/src/ggml-cpu/repack.rs - Auto-generated debug file
+// ggml/src/ggml-cpu/repack.rs - Interleaved layouts of quantized weights
+//
+// In file layout every row of a q4_0/q8_0 matrix is a run of blocks, so a
+// matrix product streams one weight row at a time. Repacking groups `nrows`
+// consecutive rows and stores their blocks of the same column range
+// together: first the `nrows` f16 scales, then the quants of all rows
+// interleaved in chunks of `blck` bytes (row 0 bytes 0..blck, row 1 bytes
+// 0..blck, ..., row 0 bytes blck..2*blck, ...). A kernel then reads one
+// contiguous group block and produces `nrows` partial dot products from it,
+// which is the access pattern the SIMD GEMM kernels of ggml are written for.
+// Repacking only moves bytes: the repacked tensor has the same size and the
+// same values, and `unpack` restores the file layout exactly.
+//
+// Only q4_0 and q8_0 have repacked layouts. The K-quants (q4_K and the like)
+// are not tensor types this backend loads, so they have no layout here; the
+// model loader logs every weight matrix it leaves unrepacked and why.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml-cpu/repack.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml::{fp16_to_fp32, GgmlType};
+use crate::llmrust::ggml::src::ggml_quants::{block_scale, quantize_row_q8_0, BLOCK_Q8_0_SIZE, QK4_0};
+
+/// Rows per group and bytes per interleaved chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepackLayout {
+    pub nrows: usize,
+    pub blck: usize,
+}
+
+/// 4 rows interleaved in 4-byte chunks (NEON dot product kernels)
+pub const REPACK_4X4: RepackLayout = RepackLayout { nrows: 4, blck: 4 };
+/// 8 rows interleaved in 8-byte chunks (AVX2 kernels)
+pub const REPACK_8X8: RepackLayout = RepackLayout { nrows: 8, blck: 8 };
+
+impl RepackLayout {
+    /// The layout the kernels of this CPU stream best, among those that fit
+    /// a matrix of `nrows` rows.
+    pub fn preferred(nrows: usize) -> Option<Self> {
+        if nrows.is_multiple_of(8) && cpu_has_avx2() {
+            Some(REPACK_8X8)
+        } else if nrows.is_multiple_of(4) {
+            Some(REPACK_4X4)
+        } else {
+            None
+        }
+    }
+
+    /// Name of the repacked type, e.g. `q4_0_8x8`
+    pub fn type_name(&self, ty: GgmlType) -> String {
+        format!("{}_{}x{}", ty.name(), self.nrows, self.blck)
+    }
+
+    fn check(&self, ty: GgmlType, ncols: usize, nrows: usize) -> Result<(), String> {
+        if !matches!(ty, GgmlType::Q4_0 | GgmlType::Q8_0) {
+            return Err(format!("{} weights cannot be repacked", ty.name()));
+        }
+        if !nrows.is_multiple_of(self.nrows) || !ncols.is_multiple_of(ty.blck_size()) {
+            return Err(format!("a [{}, {}] {} matrix does not fit the {} layout", ncols, nrows, ty.name(), self.type_name(ty)));
+        }
+        if !qs_size(ty).is_multiple_of(self.blck) {
+            return Err(format!("{} blocks do not split into {}-byte chunks", ty.name(), self.blck));
+        }
+        Ok(())
+    }
+}
+
+fn cpu_has_avx2() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        std::arch::is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+/// Bytes of quants in one block
+fn qs_size(ty: GgmlType) -> usize {
+    ty.type_size() - 2
+}
+
+/// Repacks a `[ncols, nrows]` matrix (ggml order) from file layout.
+pub fn repack(ty: GgmlType, layout: RepackLayout, data: &[u8], ncols: usize, nrows: usize) -> Result<Vec<u8>, String> {
+    layout.check(ty, ncols, nrows)?;
+    let row_size = ty.row_size(ncols);
+    if data.len() != row_size * nrows {
+        return Err(format!("expected {} bytes of {} data, got {}", row_size * nrows, ty.name(), data.len()));
+    }
+    let (bs, qs, n) = (ty.type_size(), qs_size(ty), layout.nrows);
+    let mut out = Vec::with_capacity(data.len());
+    for group in data.chunks_exact(row_size * n) {
+        for b in 0..ncols / ty.blck_size() {
+            let block = |r: usize| &group[r * row_size + b * bs..r * row_size + (b + 1) * bs];
+            for r in 0..n {
+                out.extend_from_slice(&block(r)[..2]);
+            }
+            for c in 0..qs / layout.blck {
+                for r in 0..n {
+                    out.extend_from_slice(&block(r)[2 + c * layout.blck..2 + (c + 1) * layout.blck]);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Restores the file layout of a repacked matrix.
+pub fn unpack(ty: GgmlType, layout: RepackLayout, data: &[u8], ncols: usize, nrows: usize) -> Result<Vec<u8>, String> {
+    layout.check(ty, ncols, nrows)?;
+    let row_size = ty.row_size(ncols);
+    let (bs, qs, n) = (ty.type_size(), qs_size(ty), layout.nrows);
+    let mut out = vec![0u8; data.len()];
+    for (g, group) in data.chunks_exact(row_size * n).enumerate() {
+        for (b, gb) in group.chunks_exact(bs * n).enumerate() {
+            let (scales, quants) = gb.split_at(2 * n);
+            for r in 0..n {
+                let dst = &mut out[(g * n + r) * row_size + b * bs..][..bs];
+                dst[..2].copy_from_slice(&scales[2 * r..2 * r + 2]);
+                for c in 0..qs / layout.blck {
+                    let src = &quants[(c * n + r) * layout.blck..][..layout.blck];
+                    dst[2 + c * layout.blck..2 + (c + 1) * layout.blck].copy_from_slice(src);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Adds the dot products of the `n` rows of one group block with one q8_0
+/// activation block to `acc`.
+fn group_block_dot(ty: GgmlType, layout: RepackLayout, gb: &[u8], yb: &[u8], acc: &mut [f32]) {
+    let n = layout.nrows;
+    let (scales, quants) = gb.split_at(2 * n);
+    let y = &yb[2..];
+    let mut sumi = [0i32; 8];
+    for (i, chunk) in quants.chunks_exact(layout.blck).enumerate() {
+        let (r, off) = (i % n, (i / n) * layout.blck);
+        let s = &mut sumi[r];
+        match ty {
+            GgmlType::Q4_0 => {
+                for (k, &q) in chunk.iter().enumerate() {
+                    *s += ((q & 0x0f) as i32 - 8) * y[off + k] as i8 as i32
+                        + ((q >> 4) as i32 - 8) * y[off + k + QK4_0 / 2] as i8 as i32;
+                }
+            }
+            _ => {
+                for (k, &q) in chunk.iter().enumerate() {
+                    *s += q as i8 as i32 * y[off + k] as i8 as i32;
+                }
+            }
+        }
+    }
+    let dy = block_scale(yb);
+    for (r, a) in acc.iter_mut().enumerate() {
+        *a += sumi[r] as f32 * fp16_to_fp32(u16::from_le_bytes([scales[2 * r], scales[2 * r + 1]])) * dy;
+    }
+}
+
+/// Activation rows processed together, so that every group block loaded is
+/// used for several of them
+const GEMM_TILE: usize = 4;
+
+/// `out[j * nrows + r] = w_r · x_j` for a repacked `[ncols, nrows]` weight
+/// matrix and `n_x` activation rows of `ncols` values. Activations are
+/// quantized to q8_0 first, as for the file layout kernels, so the results
+/// match `mul_mat` on the original weights.
+pub fn gemm_repacked(
+    ty: GgmlType,
+    layout: RepackLayout,
+    w: &[u8],
+    ncols: usize,
+    nrows: usize,
+    x: &[f32],
+    out: &mut [f32],
+) -> Result<(), String> {
+    layout.check(ty, ncols, nrows)?;
+    if ncols == 0 {
+        return Err("gemm_repacked: the weights have no columns".to_string());
+    }
+    let n_x = x.len() / ncols;
+    if x.len() != n_x * ncols || out.len() != n_x * nrows || w.len() != ty.row_size(ncols) * nrows {
+        return Err("gemm_repacked: operand sizes do not match".to_string());
+    }
+    let n = layout.nrows;
+    let nb = ncols / ty.blck_size();
+    let gb_size = ty.type_size() * n;
+    let xq_row = GgmlType::Q8_0.row_size(ncols);
+    let mut xq = vec![0u8; xq_row * n_x];
+    for (xr, qr) in x.chunks_exact(ncols).zip(xq.chunks_exact_mut(xq_row)) {
+        quantize_row_q8_0(xr, qr);
+    }
+
+    out.fill(0.0);
+    for (g, group) in w.chunks_exact(gb_size * nb).enumerate() {
+        for j0 in (0..n_x).step_by(GEMM_TILE) {
+            let tile = GEMM_TILE.min(n_x - j0);
+            let mut acc = [[0.0f32; 8]; GEMM_TILE];
+            for (b, gb) in group.chunks_exact(gb_size).enumerate() {
+                for (t, acc) in acc.iter_mut().enumerate().take(tile) {
+                    let yb = &xq[(j0 + t) * xq_row + b * BLOCK_Q8_0_SIZE..][..BLOCK_Q8_0_SIZE];
+                    group_block_dot(ty, layout, gb, yb, &mut acc[..n]);
+                }
+            }
+            for (t, acc) in acc.iter().enumerate().take(tile) {
+                out[(j0 + t) * nrows + g * n..][..n].copy_from_slice(&acc[..n]);
+            }
+        }
+    }
+    Ok(())
 }
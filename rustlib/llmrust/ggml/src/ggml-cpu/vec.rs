@@ -1,6 +1,27 @@
-// ggml/src/ggml-cpu/vec.rs - Auto-generated debug file
+// ggml/src/ggml-cpu/vec.rs - Vector kernels for f32 and f16 rows
+//
+// Rows of stored tensors are little-endian bytes; the other operand is f32.
+// `vec_dot_*` returns the dot product, `vec_mad_*` computes y += x * v.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml-cpu/vec.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml::fp16_to_fp32;
+
+pub fn vec_dot_f32(x: &[u8], y: &[f32]) -> f32 {
+    x.chunks_exact(4).zip(y).map(|(b, v)| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * v).sum()
+}
+
+pub fn vec_dot_f16(x: &[u8], y: &[f32]) -> f32 {
+    x.chunks_exact(2).zip(y).map(|(b, v)| fp16_to_fp32(u16::from_le_bytes([b[0], b[1]])) * v).sum()
+}
+
+pub fn vec_mad_f32(y: &mut [f32], x: &[u8], v: f32) {
+    for (acc, b) in y.iter_mut().zip(x.chunks_exact(4)) {
+        *acc += f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * v;
+    }
+}
+
+pub fn vec_mad_f16(y: &mut [f32], x: &[u8], v: f32) {
+    for (acc, b) in y.iter_mut().zip(x.chunks_exact(2)) {
+        *acc += fp16_to_fp32(u16::from_le_bytes([b[0], b[1]])) * v;
+    }
 }
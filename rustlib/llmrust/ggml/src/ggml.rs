@@ -1,6 +1,130 @@
-// ggml/src/ggml.rs - Auto-generated debug file
+// ggml/src/ggml.rs - Tensor element types and half-precision conversion
+//
+// Type traits for the element types the CPU backend stores: block size (number
+// of values sharing one scale), bytes per block, and the resulting row sizes.
+// Enum values match ggml's `ggml_type` so they can be passed through the C API.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml_quants::{BLOCK_Q4_0_SIZE, BLOCK_Q8_0_SIZE, QK4_0, QK8_0};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q8_0 = 8,
+}
+
+impl GgmlType {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::F32),
+            1 => Some(Self::F16),
+            2 => Some(Self::Q4_0),
+            8 => Some(Self::Q8_0),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f32" => Some(Self::F32),
+            "f16" => Some(Self::F16),
+            "q4_0" => Some(Self::Q4_0),
+            "q8_0" => Some(Self::Q8_0),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::F16 => "f16",
+            Self::Q4_0 => "q4_0",
+            Self::Q8_0 => "q8_0",
+        }
+    }
+
+    /// Number of values per block
+    pub fn blck_size(&self) -> usize {
+        match self {
+            Self::F32 | Self::F16 => 1,
+            Self::Q4_0 => QK4_0,
+            Self::Q8_0 => QK8_0,
+        }
+    }
+
+    /// Bytes per block
+    pub fn type_size(&self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::F16 => 2,
+            Self::Q4_0 => BLOCK_Q4_0_SIZE,
+            Self::Q8_0 => BLOCK_Q8_0_SIZE,
+        }
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        matches!(self, Self::Q4_0 | Self::Q8_0)
+    }
+
+    /// Bytes used by `n` values; `n` must be a multiple of the block size.
+    pub fn row_size(&self, n: usize) -> usize {
+        debug_assert!(n.is_multiple_of(self.blck_size()));
+        n / self.blck_size() * self.type_size()
+    }
+}
+
+/// Converts to IEEE half precision, rounding to nearest even.
+pub fn fp32_to_fp16(f: f32) -> u16 {
+    let x = f.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xff) as i32;
+    let mant = x & 0x7f_ffff;
+
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    let round = |m: u32, shift: u32| {
+        let r = m >> shift;
+        let rem = m & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        if rem > half || (rem == half && r & 1 == 1) {
+            r + 1
+        } else {
+            r
+        }
+    };
+    if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // subnormal half
+        return sign | round(mant | 0x80_0000, (14 - e) as u32) as u16;
+    }
+    // a carry out of the mantissa correctly bumps the exponent (up to infinity)
+    sign | (((e as u32) << 10) + round(mant, 13)) as u16
+}
+
+pub fn fp16_to_fp32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mant = (h & 0x3ff) as u32;
+    match exp {
+        0 => {
+            let value = mant as f32 * (1.0 / 16_777_216.0);
+            if sign != 0 {
+                -value
+            } else {
+                value
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mant << 13)),
+        _ => f32::from_bits(sign | ((exp + 112) << 23) | (mant << 13)),
+    }
 }
@@ -1,6 +1,76 @@
-// ggml/src/ggml_backend.rs - Auto-generated debug file
+// ggml/src/ggml_backend.rs - Backend buffer types
+//
+// A buffer type says where a tensor's data lives and how it is laid out. On
+// the CPU backend that is either memory mapped from the model file, an
+// anonymous host allocation the data is copied into, or the repack buffer,
+// which stores quantized weights in interleaved layouts for the SIMD
+// kernels. The discriminants are the `buft_type` values of
+// `llama_model_tensor_buft_override`.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml_backend.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml::GgmlType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum GgmlBackendBufferType {
+    /// Anonymous host memory
+    Cpu = 0,
+    /// Pages of the mapped model file
+    CpuMapped = 1,
+    /// Host memory holding repacked (interleaved) weights
+    CpuRepack = 2,
+}
+
+impl GgmlBackendBufferType {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Cpu),
+            1 => Some(Self::CpuMapped),
+            2 => Some(Self::CpuRepack),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "CPU" => Some(Self::Cpu),
+            "CPU_Mapped" => Some(Self::CpuMapped),
+            "CPU_REPACK" => Some(Self::CpuRepack),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Cpu => "CPU",
+            Self::CpuMapped => "CPU_Mapped",
+            Self::CpuRepack => "CPU_REPACK",
+        }
+    }
+
+    /// Whether a tensor of type `ty` and shape `dims` (ggml order) can be
+    /// stored in this buffer type.
+    pub fn supports(&self, ty: GgmlType, dims: &[usize]) -> bool {
+        self.unsupported_reason(ty, dims).is_none()
+    }
+
+    /// Why a tensor of type `ty` and shape `dims` cannot be stored in this
+    /// buffer type. Repacking works on whole groups of rows of quantized
+    /// matrices, and only q4_0 and q8_0 have repacked layouts: K-quants
+    /// (q4_K and the like) are not among the types this backend loads.
+    pub fn unsupported_reason(&self, ty: GgmlType, dims: &[usize]) -> Option<String> {
+        match self {
+            Self::Cpu | Self::CpuMapped => None,
+            Self::CpuRepack => {
+                if !matches!(ty, GgmlType::Q4_0 | GgmlType::Q8_0) {
+                    Some(format!("{} has no repacked layout, only q4_0 and q8_0 do", ty.name()))
+                } else if dims.len() != 2 {
+                    Some(format!("{} dimensions, only matrices are repacked", dims.len()))
+                } else if !dims[1].is_multiple_of(4) {
+                    Some(format!("{} rows do not split into groups of 4", dims[1]))
+                } else {
+                    None
+                }
+            }
+        }
+    }
 }
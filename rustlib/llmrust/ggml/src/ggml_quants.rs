@@ -1,6 +1,100 @@
-// ggml/src/ggml_quants.rs - Auto-generated debug file
+// ggml/src/ggml_quants.rs - Reference quantization of rows
+//
+// Block formats follow ggml, stored as little-endian bytes:
+//   q8_0: f16 scale d, then 32 x i8           (x = d * q)
+//   q4_0: f16 scale d, then 16 bytes of nibbles; the low nibbles hold values
+//         0..15 and the high nibbles values 16..31 (x = d * (q - 8))
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/ggml_quants.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml::{fp16_to_fp32, fp32_to_fp16, GgmlType};
+
+pub const QK8_0: usize = 32;
+pub const QK4_0: usize = 32;
+pub const BLOCK_Q8_0_SIZE: usize = 2 + QK8_0;
+pub const BLOCK_Q4_0_SIZE: usize = 2 + QK4_0 / 2;
+
+pub fn block_scale(block: &[u8]) -> f32 {
+    fp16_to_fp32(u16::from_le_bytes([block[0], block[1]]))
+}
+
+pub fn quantize_row_q8_0(x: &[f32], y: &mut [u8]) {
+    for (xb, yb) in x.chunks_exact(QK8_0).zip(y.chunks_exact_mut(BLOCK_Q8_0_SIZE)) {
+        let amax = xb.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        let d = amax / 127.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+        yb[..2].copy_from_slice(&fp32_to_fp16(d).to_le_bytes());
+        for (q, v) in yb[2..].iter_mut().zip(xb) {
+            *q = (v * id).round() as i8 as u8;
+        }
+    }
+}
+
+pub fn dequantize_row_q8_0(x: &[u8], y: &mut [f32]) {
+    for (xb, yb) in x.chunks_exact(BLOCK_Q8_0_SIZE).zip(y.chunks_exact_mut(QK8_0)) {
+        let d = block_scale(xb);
+        for (v, &q) in yb.iter_mut().zip(&xb[2..]) {
+            *v = q as i8 as f32 * d;
+        }
+    }
+}
+
+pub fn quantize_row_q4_0(x: &[f32], y: &mut [u8]) {
+    for (xb, yb) in x.chunks_exact(QK4_0).zip(y.chunks_exact_mut(BLOCK_Q4_0_SIZE)) {
+        // the value with the largest magnitude maps to -8
+        let max = xb.iter().fold(0.0f32, |m, &v| if v.abs() > m.abs() { v } else { m });
+        let d = max / -8.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+        yb[..2].copy_from_slice(&fp32_to_fp16(d).to_le_bytes());
+        for j in 0..QK4_0 / 2 {
+            let x0 = ((xb[j] * id + 8.5) as u8).min(15);
+            let x1 = ((xb[j + QK4_0 / 2] * id + 8.5) as u8).min(15);
+            yb[2 + j] = x0 | (x1 << 4);
+        }
+    }
+}
+
+pub fn dequantize_row_q4_0(x: &[u8], y: &mut [f32]) {
+    for (xb, yb) in x.chunks_exact(BLOCK_Q4_0_SIZE).zip(y.chunks_exact_mut(QK4_0)) {
+        let d = block_scale(xb);
+        for j in 0..QK4_0 / 2 {
+            yb[j] = ((xb[2 + j] & 0x0f) as i32 - 8) as f32 * d;
+            yb[j + QK4_0 / 2] = ((xb[2 + j] >> 4) as i32 - 8) as f32 * d;
+        }
+    }
+}
+
+/// Converts `x` into a row of type `ty`; `y` must hold `ty.row_size(x.len())` bytes.
+pub fn quantize_row(ty: GgmlType, x: &[f32], y: &mut [u8]) {
+    match ty {
+        GgmlType::F32 => {
+            for (v, b) in x.iter().zip(y.chunks_exact_mut(4)) {
+                b.copy_from_slice(&v.to_le_bytes());
+            }
+        }
+        GgmlType::F16 => {
+            for (v, b) in x.iter().zip(y.chunks_exact_mut(2)) {
+                b.copy_from_slice(&fp32_to_fp16(*v).to_le_bytes());
+            }
+        }
+        GgmlType::Q4_0 => quantize_row_q4_0(x, y),
+        GgmlType::Q8_0 => quantize_row_q8_0(x, y),
+    }
+}
+
+/// Converts a row of type `ty` back to f32.
+pub fn dequantize_row(ty: GgmlType, x: &[u8], y: &mut [f32]) {
+    match ty {
+        GgmlType::F32 => {
+            for (v, b) in y.iter_mut().zip(x.chunks_exact(4)) {
+                *v = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            }
+        }
+        GgmlType::F16 => {
+            for (v, b) in y.iter_mut().zip(x.chunks_exact(2)) {
+                *v = fp16_to_fp32(u16::from_le_bytes([b[0], b[1]]));
+            }
+        }
+        GgmlType::Q4_0 => dequantize_row_q4_0(x, y),
+        GgmlType::Q8_0 => dequantize_row_q8_0(x, y),
+    }
 }
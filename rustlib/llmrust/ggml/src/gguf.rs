@@ -1,6 +1,565 @@
-// ggml/src/gguf.rs - Auto-generated debug file
+// ggml/src/gguf.rs - GGUF file reading and writing
+//
+// A GGUF file is a header (magic, version, counts), typed key/value metadata,
+// tensor descriptors (name, shape, element type, offset) and the aligned
+// tensor data. Shapes are in ggml order: `dims[0]` is the contiguous (row)
+// dimension. The whole file is read into memory; tensors are borrowed from
+// it or converted to f32 on request. A file can instead be memory mapped, so
+// that tensors are used in place from the page cache, and reading only the
+// metadata stops before the tensor data.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: ggml/src/gguf.rs - File loaded successfully");
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::llmrust::ggml::src::ggml::{fp16_to_fp32, GgmlType};
+use crate::llmrust::ggml::src::ggml_quants::dequantize_row;
+
+pub const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+pub const GGUF_VERSION: u32 = 3;
+pub const GGUF_DEFAULT_ALIGNMENT: usize = 32;
+pub const GGUF_KEY_GENERAL_ALIGNMENT: &str = "general.alignment";
+
+const GGUF_ERR_EOF: &str = "unexpected end of GGUF file";
+
+/// A metadata value; the variant order is the GGUF type id
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    fn type_id(&self) -> u32 {
+        match self {
+            Self::U8(_) => 0,
+            Self::I8(_) => 1,
+            Self::U16(_) => 2,
+            Self::I16(_) => 3,
+            Self::U32(_) => 4,
+            Self::I32(_) => 5,
+            Self::F32(_) => 6,
+            Self::Bool(_) => 7,
+            Self::String(_) => 8,
+            Self::Array(_) => 9,
+            Self::U64(_) => 10,
+            Self::I64(_) => 11,
+            Self::F64(_) => 12,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Integer value of any integer type
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Self::U8(v) => Some(v as i64),
+            Self::I8(v) => Some(v as i64),
+            Self::U16(v) => Some(v as i64),
+            Self::I16(v) => Some(v as i64),
+            Self::U32(v) => Some(v as i64),
+            Self::I32(v) => Some(v as i64),
+            Self::U64(v) => i64::try_from(v).ok(),
+            Self::I64(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(v) => Some(v as f64),
+            Self::F64(v) => Some(v),
+            _ => self.as_i64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Self::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Value as text, as printed by `llama_model_meta_val_str`
+    pub fn to_display(&self) -> String {
+        match self {
+            Self::String(s) => s.clone(),
+            Self::Bool(b) => b.to_string(),
+            Self::F32(v) => v.to_string(),
+            Self::F64(v) => v.to_string(),
+            Self::Array(items) => format!("[{}]", items.iter().map(|v| v.to_display()).collect::<Vec<_>>().join(", ")),
+            other => other.as_i64().map_or_else(String::new, |v| v.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    /// Shape in ggml order (`dims[0]` is contiguous)
+    pub dims: Vec<usize>,
+    pub ty: GgmlType,
+    /// Offset of the data from the start of the data section
+    pub offset: usize,
+}
+
+impl GgufTensorInfo {
+    pub fn n_elements(&self) -> usize {
+        self.dims.iter().product()
+    }
+
+    pub fn n_bytes(&self) -> usize {
+        self.ty.row_size(self.n_elements())
+    }
+
+    /// `n_bytes`, or None if the shape overflows
+    fn checked_n_bytes(&self) -> Option<usize> {
+        let n_elements = self.dims.iter().try_fold(1usize, |n, &d| n.checked_mul(d))?;
+        (n_elements / self.ty.blck_size()).checked_mul(self.ty.type_size())
+    }
+}
+
+/// Read-only memory mapping of a whole file
+pub struct GgufMmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is never written through and lives until dropped
+unsafe impl Send for GgufMmap {}
+unsafe impl Sync for GgufMmap {}
+
+impl GgufMmap {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        use std::os::fd::AsRawFd;
+
+        let err = |e: std::io::Error| format!("failed to map {}: {}", path.display(), e);
+        let f = std::fs::File::open(path).map_err(err)?;
+        let len = f.metadata().map_err(err)?.len() as usize;
+        if len == 0 {
+            return Err(format!("failed to map {}: the file is empty", path.display()));
+        }
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, f.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(err(std::io::Error::last_os_error()));
+        }
+        Ok(Self { ptr, len })
+    }
+}
+
+impl std::ops::Deref for GgufMmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for GgufMmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+impl std::fmt::Debug for GgufMmap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GgufMmap({} bytes)", self.len)
+    }
+}
+
+/// Where the tensor data of a file is
+#[derive(Debug, Clone)]
+enum GgufData {
+    /// Copied into memory
+    Owned(Vec<u8>),
+    /// Mapped from the file, from the given offset
+    Mapped(Arc<GgufMmap>, usize),
+    /// Left in the file (metadata only), from the given offset
+    File(PathBuf, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufFile {
+    pub version: u32,
+    kv: Vec<(String, GgufValue)>,
+    tensors: Vec<GgufTensorInfo>,
+    data: GgufData,
+}
+
+impl GgufFile {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::from_bytes(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Maps the file at `path` instead of reading it: the tensor data stays in
+    /// the page cache and is shared with other processes mapping the file.
+    pub fn read_mmap(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let map = GgufMmap::open(path)?;
+        let (mut file, data_start) = Self::parse_header(&map).map_err(|e| format!("{}: {}", path.display(), e))?;
+        file.check_data_bounds(map.len().saturating_sub(data_start)).map_err(|e| format!("{}: {}", path.display(), e))?;
+        file.data = GgufData::Mapped(Arc::new(map), data_start);
+        Ok(file)
+    }
+
+    /// Reads only the metadata and tensor descriptors of the file at `path`.
+    /// The tensor data stays in the file: `read_tensor_data` fetches it, while
+    /// `tensor_data` and `tensor_f32` must not be used on the result.
+    pub fn read_metadata(path: impl AsRef<Path>) -> Result<Self, String> {
+        use std::io::Read;
+
+        let path = path.as_ref();
+        let err = |e: std::io::Error| format!("failed to read {}: {}", path.display(), e);
+        let mut f = std::fs::File::open(path).map_err(err)?;
+        let mut bytes = Vec::new();
+        let mut chunk = 1 << 20;
+        loop {
+            let n = (&mut f).take(chunk as u64).read_to_end(&mut bytes).map_err(err)?;
+            match Self::parse_header(&bytes) {
+                Ok((file, data_start)) => return Ok(Self { data: GgufData::File(path.to_path_buf(), data_start), ..file }),
+                Err(e) if e == GGUF_ERR_EOF && n == chunk => chunk *= 2,
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (mut file, data_start) = Self::parse_header(bytes)?;
+        let data = bytes.get(data_start..).unwrap_or_default();
+        file.check_data_bounds(data.len())?;
+        file.data = GgufData::Owned(data.to_vec());
+        Ok(file)
+    }
+
+    fn check_data_bounds(&self, data_len: usize) -> Result<(), String> {
+        for t in &self.tensors {
+            if t.offset.checked_add(t.n_bytes()).is_none_or(|end| end > data_len) {
+                return Err(format!("tensor '{}' data is out of bounds", t.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses everything before the tensor data; returns the file without
+    /// data and the offset of the data section.
+    fn parse_header(bytes: &[u8]) -> Result<(Self, usize), String> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(4)? != GGUF_MAGIC {
+            return Err("not a GGUF file (bad magic)".to_string());
+        }
+        let version = r.u32()?;
+        if !(2..=GGUF_VERSION).contains(&version) {
+            return Err(format!("unsupported GGUF version {}", version));
+        }
+        let n_tensors = r.u64()? as usize;
+        let n_kv = r.u64()? as usize;
+
+        let mut kv = Vec::with_capacity(n_kv.min(1024));
+        for _ in 0..n_kv {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            let value = r.value(ty)?;
+            kv.push((key, value));
+        }
+        let mut tensors = Vec::with_capacity(n_tensors.min(4096));
+        for _ in 0..n_tensors {
+            let name = r.string()?;
+            let n_dims = r.u32()? as usize;
+            if n_dims > 4 {
+                return Err(format!("tensor '{}' has {} dimensions", name, n_dims));
+            }
+            let dims = (0..n_dims).map(|_| r.u64().map(|d| d as usize)).collect::<Result<Vec<_>, _>>()?;
+            let ty_id = r.u32()?;
+            let ty = GgmlType::from_i32(ty_id as i32).ok_or_else(|| format!("tensor '{}' has unsupported type {}", name, ty_id))?;
+            let offset = r.u64()? as usize;
+            tensors.push(GgufTensorInfo { name, dims, ty, offset });
+        }
+
+        let file = Self { version, kv, tensors, data: GgufData::Owned(Vec::new()) };
+        for t in &file.tensors {
+            if !t.dims.first().is_none_or(|&d| d.is_multiple_of(t.ty.blck_size())) {
+                return Err(format!("tensor '{}' row size {} is not a multiple of the {} block size", t.name, t.dims[0], t.ty.name()));
+            }
+            if t.checked_n_bytes().is_none() {
+                return Err(format!("tensor '{}' shape {:?} is too large", t.name, t.dims));
+            }
+        }
+        let data_start = r.pos.next_multiple_of(file.alignment());
+        Ok((file, data_start))
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.get(GGUF_KEY_GENERAL_ALIGNMENT)
+            .and_then(|v| v.as_i64())
+            .filter(|&a| a > 0 && (a as usize).is_power_of_two())
+            .map_or(GGUF_DEFAULT_ALIGNMENT, |a| a as usize)
+    }
+
+    /// Metadata in file order
+    pub fn kv(&self) -> &[(String, GgufValue)] {
+        &self.kv
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.kv.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(|v| v.as_str())
+    }
+
+    pub fn get_f32(&self, key: &str) -> Option<f32> {
+        self.get(key).and_then(|v| v.as_f64()).map(|v| v as f32)
+    }
+
+    pub fn get_u32(&self, key: &str) -> Option<u32> {
+        self.get(key).and_then(|v| v.as_i64()).and_then(|v| u32::try_from(v).ok())
+    }
+
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    /// Whether the tensor data is mapped from the file
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.data, GgufData::Mapped(..))
+    }
+
+    /// The mapping of a mapped file and the byte range of a tensor in it
+    pub fn tensor_mapping(&self, info: &GgufTensorInfo) -> Option<(Arc<GgufMmap>, Range<usize>)> {
+        match &self.data {
+            GgufData::Mapped(map, data_start) => {
+                let start = data_start + info.offset;
+                Some((map.clone(), start..start + info.n_bytes()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Raw data of a tensor
+    pub fn tensor_data(&self, info: &GgufTensorInfo) -> &[u8] {
+        let data = match &self.data {
+            GgufData::Owned(data) => data.as_slice(),
+            GgufData::Mapped(map, data_start) => &map[*data_start..],
+            GgufData::File(path, _) => panic!("tensor data of {} was not loaded", path.display()),
+        };
+        &data[info.offset..info.offset + info.n_bytes()]
+    }
+
+    /// Raw data of a tensor, read from the file if only the metadata was loaded
+    pub fn read_tensor_data(&self, info: &GgufTensorInfo) -> Result<Cow<'_, [u8]>, String> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let GgufData::File(path, data_start) = &self.data else {
+            return Ok(Cow::Borrowed(self.tensor_data(info)));
+        };
+        let err = |e: std::io::Error| format!("failed to read tensor '{}' from {}: {}", info.name, path.display(), e);
+        let mut f = std::fs::File::open(path).map_err(err)?;
+        f.seek(SeekFrom::Start((*data_start as u64).saturating_add(info.offset as u64))).map_err(err)?;
+        let mut data = vec![0; info.n_bytes()];
+        f.read_exact(&mut data).map_err(err)?;
+        Ok(Cow::Owned(data))
+    }
+
+    /// Tensor values converted to f32
+    pub fn tensor_f32(&self, info: &GgufTensorInfo) -> Vec<f32> {
+        let data = self.tensor_data(info);
+        match info.ty {
+            GgmlType::F32 => data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+            GgmlType::F16 => data.chunks_exact(2).map(|b| fp16_to_fp32(u16::from_le_bytes([b[0], b[1]]))).collect(),
+            ty => {
+                let mut out = vec![0.0; info.n_elements()];
+                dequantize_row(ty, data, &mut out);
+                out
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.bytes.len()).ok_or(GGUF_ERR_EOF)?;
+        let out = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap_or([0; N]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let n = self.u64()? as usize;
+        String::from_utf8(self.take(n)?.to_vec()).map_err(|_| "invalid UTF-8 string in GGUF file".to_string())
+    }
+
+    fn value(&mut self, ty: u32) -> Result<GgufValue, String> {
+        Ok(match ty {
+            0 => GgufValue::U8(self.array::<1>()?[0]),
+            1 => GgufValue::I8(self.array::<1>()?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => GgufValue::Bool(self.array::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let elem_ty = self.u32()?;
+                if elem_ty == 9 {
+                    return Err("nested GGUF arrays are not supported".to_string());
+                }
+                let n = self.u64()? as usize;
+                let mut items = Vec::with_capacity(n.min(1 << 16));
+                for _ in 0..n {
+                    items.push(self.value(elem_ty)?);
+                }
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+            _ => return Err(format!("unknown GGUF value type {}", ty)),
+        })
+    }
+}
+
+/// Builds a GGUF file in memory
+#[derive(Debug, Default)]
+pub struct GgufWriter {
+    kv: BTreeMap<String, GgufValue>,
+    tensors: Vec<(String, Vec<usize>, GgmlType, Vec<u8>)>,
+}
+
+impl GgufWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: &str, value: GgufValue) -> &mut Self {
+        self.kv.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) -> &mut Self {
+        self.set(key, GgufValue::String(value.to_string()))
+    }
+
+    /// Adds a tensor from raw data of type `ty`.
+    pub fn add_tensor(&mut self, name: &str, dims: &[usize], ty: GgmlType, data: Vec<u8>) -> Result<&mut Self, String> {
+        let n_bytes = ty.row_size(dims.iter().product());
+        if data.len() != n_bytes {
+            return Err(format!("tensor '{}' needs {} bytes, got {}", name, n_bytes, data.len()));
+        }
+        self.tensors.push((name.to_string(), dims.to_vec(), ty, data));
+        Ok(self)
+    }
+
+    pub fn add_tensor_f32(&mut self, name: &str, dims: &[usize], values: &[f32]) -> Result<&mut Self, String> {
+        self.add_tensor(name, dims, GgmlType::F32, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&GGUF_MAGIC);
+        out.extend_from_slice(&GGUF_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.tensors.len() as u64).to_le_bytes());
+        out.extend_from_slice(&(self.kv.len() as u64).to_le_bytes());
+        for (key, value) in &self.kv {
+            write_string(&mut out, key);
+            out.extend_from_slice(&value.type_id().to_le_bytes());
+            write_value(&mut out, value);
+        }
+
+        let alignment = self.kv.get(GGUF_KEY_GENERAL_ALIGNMENT).and_then(|v| v.as_i64()).map_or(GGUF_DEFAULT_ALIGNMENT, |a| a as usize);
+        let mut offset = 0;
+        for (name, dims, ty, data) in &self.tensors {
+            write_string(&mut out, name);
+            out.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+            for &d in dims {
+                out.extend_from_slice(&(d as u64).to_le_bytes());
+            }
+            out.extend_from_slice(&(*ty as u32).to_le_bytes());
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            offset = (offset + data.len()).next_multiple_of(alignment);
+        }
+        for (_, _, _, data) in &self.tensors {
+            out.resize(out.len().next_multiple_of(alignment), 0);
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_bytes()).map_err(|e| format!("failed to write {}: {}", path.display(), e))
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &GgufValue) {
+    match value {
+        GgufValue::U8(v) => out.push(*v),
+        GgufValue::I8(v) => out.push(*v as u8),
+        GgufValue::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::U32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::I32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::F32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::Bool(v) => out.push(*v as u8),
+        GgufValue::String(s) => write_string(out, s),
+        GgufValue::Array(items) => {
+            // empty arrays are written as arrays of u8
+            out.extend_from_slice(&items.first().map_or(0, |v| v.type_id()).to_le_bytes());
+            out.extend_from_slice(&(items.len() as u64).to_le_bytes());
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        GgufValue::U64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        GgufValue::F64(v) => out.extend_from_slice(&v.to_le_bytes()),
+    }
 }
@@ -1,6 +1,13 @@
-// ggml/src/mod.rs - Auto-generated debug file
+// ggml/src/mod.rs - ggml implementation modules
 #![allow(dead_code)]
 
+pub mod ggml;
+pub mod ggml_backend;
+#[path = "ggml-cpu/mod.rs"]
+pub mod ggml_cpu;
+pub mod ggml_quants;
+pub mod gguf;
+
 pub fn debug_print() {
     println!("DEBUG: ggml/src/mod.rs - File loaded successfully");
 }
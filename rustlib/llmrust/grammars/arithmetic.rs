@@ -1,6 +1,14 @@
-// grammars/arithmetic.rs - Auto-generated debug file
-#![allow(dead_code)]
+// grammars/arithmetic.rs - Arithmetic expression grammar
+//
+// One or more `expr = term` lines built from identifiers, integers, the four
+// basic operators and parentheses.
 
-pub fn debug_print() {
-    println!("DEBUG: grammars/arithmetic.rs - File loaded successfully");
-}
+pub const NAME: &str = "arithmetic";
+
+pub const GRAMMAR: &str = r#"root  ::= (expr "=" ws term "\n")+
+expr  ::= term ([-+*/] term)*
+term  ::= ident | num | "(" ws expr ")" ws
+ident ::= [a-z] [a-z0-9_]* ws
+num   ::= [0-9]+ ws
+ws    ::= [ \t\n]*
+"#;
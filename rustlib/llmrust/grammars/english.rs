@@ -1,6 +1,13 @@
-// grammars/english.rs - Auto-generated debug file
-#![allow(dead_code)]
+// grammars/english.rs - Plain English text grammar
+//
+// Whitespace separated words made of ASCII letters, digits and punctuation.
 
-pub fn debug_print() {
-    println!("DEBUG: grammars/english.rs - File loaded successfully");
-}
+pub const NAME: &str = "english";
+
+pub const GRAMMAR: &str = r##"# note: this might be incomplete, mostly an example
+root        ::= en-char+ ([ \t\n] en-char+)*
+en-char     ::= letter | digit | punctuation
+letter      ::= [a-zA-Z]
+digit       ::= [0-9]
+punctuation ::= [!"#$%&'()*+,-./:;<=>?@[\\\]^_`{|}~]
+"##;
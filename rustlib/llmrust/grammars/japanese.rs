@@ -1,6 +1,15 @@
-// grammars/japanese.rs - Auto-generated debug file
-#![allow(dead_code)]
+// grammars/japanese.rs - Japanese text grammar
+//
+// Whitespace separated runs of hiragana, katakana, CJK ideographs and
+// Japanese punctuation.
 
-pub fn debug_print() {
-    println!("DEBUG: grammars/japanese.rs - File loaded successfully");
-}
+pub const NAME: &str = "japanese";
+
+pub const GRAMMAR: &str = r#"# A probably incorrect grammar for Japanese
+root        ::= jp-char+ ([ \t\n] jp-char+)*
+jp-char     ::= hiragana | katakana | punctuation | cjk
+hiragana    ::= [ぁ-ゟ]
+katakana    ::= [ァ-ヿ]
+punctuation ::= [、-〾]
+cjk         ::= [一-鿿]
+"#;
@@ -1,6 +1,33 @@
-// grammars/json.rs - Auto-generated debug file
-#![allow(dead_code)]
+// grammars/json.rs - JSON object grammar
+//
+// A single JSON object with the usual value types. Whitespace is only allowed
+// after literal characters, which keeps generated output compact.
 
-pub fn debug_print() {
-    println!("DEBUG: grammars/json.rs - File loaded successfully");
-}
+pub const NAME: &str = "json";
+
+pub const GRAMMAR: &str = r#"root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\bfnrt] | "u" [0-9a-fA-F]{4}) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+# Optional space: by convention, applied in this grammar after literal chars when allowed
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
@@ -1,6 +1,42 @@
-// grammars/json_arr.rs - Auto-generated debug file
-#![allow(dead_code)]
+// grammars/json_arr.rs - JSON array grammar
+//
+// Same value rules as `json`, but the root is a newline separated array with
+// no trailing whitespace, which suits generating lists of JSON records.
 
-pub fn debug_print() {
-    println!("DEBUG: grammars/json_arr.rs - File loaded successfully");
-}
+pub const NAME: &str = "json_arr";
+
+pub const GRAMMAR: &str = r#"# This is the same as json.gbnf but we restrict whitespaces at the end of the root array
+# Useful for generating JSON arrays
+
+root   ::= arr
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+arr  ::=
+  "[\n" ws (
+            value
+    (",\n" ws value)*
+  )? "]"
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\bfnrt] | "u" [0-9a-fA-F]{4}) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+# Optional space: by convention, applied in this grammar after literal chars when allowed
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
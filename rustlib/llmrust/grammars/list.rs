@@ -1,6 +1,11 @@
-// grammars/list.rs - Auto-generated debug file
-#![allow(dead_code)]
+// grammars/list.rs - Markdown bullet list grammar
+//
+// One or more "- item" lines; items may not contain line break characters.
 
-pub fn debug_print() {
-    println!("DEBUG: grammars/list.rs - File loaded successfully");
-}
+pub const NAME: &str = "list";
+
+pub const GRAMMAR: &str = r#"root ::= item+
+
+# Excludes various line break characters
+item ::= "- " [^\r\n\x0b\x0c\x85\u2028\u2029]+ "\n"
+"#;
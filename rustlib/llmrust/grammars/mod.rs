@@ -1,6 +1,64 @@
-// grammars/mod.rs - Auto-generated debug file
+// grammars/mod.rs - Built-in GBNF grammars
+//
+// Every grammar is embedded as text and registered by name so requests can
+// select one with `"grammar": "<name>"` instead of sending the GBNF source.
 #![allow(dead_code)]
 
+pub mod arithmetic;
+pub mod c;
+pub mod chess;
+pub mod english;
+pub mod japanese;
+pub mod json;
+pub mod json_arr;
+pub mod list;
+
+use crate::llmrust::src::llama_grammar::LlamaGrammar;
+
+/// Root rule used by all built-in grammars
+pub const GRAMMAR_ROOT: &str = "root";
+
+/// A named, embedded grammar
+#[derive(Debug, Clone, Copy)]
+pub struct BuiltinGrammar {
+    pub name: &'static str,
+    pub text: &'static str,
+}
+
+/// Registry of all built-in grammars
+pub const BUILTIN_GRAMMARS: &[BuiltinGrammar] = &[
+    BuiltinGrammar { name: arithmetic::NAME, text: arithmetic::GRAMMAR },
+    BuiltinGrammar { name: c::NAME, text: c::GRAMMAR },
+    BuiltinGrammar { name: chess::NAME, text: chess::GRAMMAR },
+    BuiltinGrammar { name: english::NAME, text: english::GRAMMAR },
+    BuiltinGrammar { name: japanese::NAME, text: japanese::GRAMMAR },
+    BuiltinGrammar { name: json::NAME, text: json::GRAMMAR },
+    BuiltinGrammar { name: json_arr::NAME, text: json_arr::GRAMMAR },
+    BuiltinGrammar { name: list::NAME, text: list::GRAMMAR },
+];
+
+/// Looks up a built-in grammar by name.
+pub fn lookup(name: &str) -> Option<&'static BuiltinGrammar> {
+    BUILTIN_GRAMMARS.iter().find(|g| g.name == name)
+}
+
+/// Names of all built-in grammars.
+pub fn names() -> Vec<&'static str> {
+    BUILTIN_GRAMMARS.iter().map(|g| g.name).collect()
+}
+
+/// GBNF source of a request's `grammar` field: the embedded text of a
+/// built-in name, otherwise the field itself.
+pub fn source(spec: &str) -> &str {
+    lookup(spec.trim()).map(|g| g.text).unwrap_or(spec)
+}
+
+/// Resolves a request's `grammar` field: a built-in name selects the embedded
+/// grammar, anything else is treated as GBNF source.
+pub fn resolve(spec: &str) -> Result<LlamaGrammar, String> {
+    LlamaGrammar::parse(source(spec), GRAMMAR_ROOT)
+}
+
 pub fn debug_print() {
     println!("DEBUG: grammars/mod.rs - File loaded successfully");
 }
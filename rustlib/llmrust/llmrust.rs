@@ -1,3 +1,12 @@
+pub mod common;
+pub mod ggml;
+pub mod grammars;
+pub mod src;
+pub mod tools;
+
+#[cfg(test)]
+mod tests;
+
 #[no_mangle]
 pub extern "C" fn llmrust_hello() {
     eprintln!("[INFO] Hello from Rust LLM!");
@@ -1,6 +1,131 @@
-// src/adapter_loader.rs - Auto-generated debug file
+// src/adapter_loader.rs - Loading LoRA adapters from GGUF files
+//
+// An adapter file (as written by convert_lora_to_gguf.py) has
+// `general.type = "adapter"`, `adapter.type = "lora"`, the architecture of
+// the base model and, for every patched weight `<name>`, the tensors
+// `<name>.lora_a` (`[n_in, r]`) and `<name>.lora_b` (`[r, n_out]`). Every pair
+// is checked against the base weight before the adapter is accepted, so a
+// mismatched adapter fails at load time rather than in the middle of a
+// decode. The base model itself is only consulted, never modified.
+//
+// A control vector file (as written by repeng) holds one 1-D tensor
+// `direction.<il>` of `n_embd` values per layer `il`, counted from 1.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/adapter_loader.rs - File loaded successfully");
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::llmrust::ggml::src::gguf::GgufFile;
+use crate::llmrust::src::llama_adapter::{LlamaAdapterLora, LlamaLoraWeight};
+use crate::llmrust::src::llama_arch::{LLM_KV_GENERAL_ARCHITECTURE, LLM_KV_GENERAL_TYPE};
+
+pub const LLM_KV_ADAPTER_TYPE: &str = "adapter.type";
+pub const LLM_KV_ADAPTER_LORA_ALPHA: &str = "adapter.lora.alpha";
+
+/// Loads the adapter at `path` for a model of architecture `arch` whose
+/// weights have the shapes given by `base_shape` (`[n_in, n_out]`).
+pub fn llama_adapter_lora_load(
+    path: impl AsRef<Path>,
+    arch: &str,
+    base_shape: impl Fn(&str) -> Option<[usize; 2]>,
+) -> Result<LlamaAdapterLora, String> {
+    let path = path.as_ref();
+    let gguf = GgufFile::read(path)?;
+    lora_from_gguf(&gguf, &path.display().to_string(), arch, base_shape).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn lora_from_gguf(
+    gguf: &GgufFile,
+    path: &str,
+    arch: &str,
+    base_shape: impl Fn(&str) -> Option<[usize; 2]>,
+) -> Result<LlamaAdapterLora, String> {
+    let expect = |key: &str, want: &str| match gguf.get_str(key) {
+        Some(v) if v == want => Ok(()),
+        Some(v) => Err(format!("expected {} '{}', got '{}'", key, want, v)),
+        None => Err(format!("missing {} (expected '{}')", key, want)),
+    };
+    expect(LLM_KV_GENERAL_TYPE, "adapter")?;
+    expect(LLM_KV_ADAPTER_TYPE, "lora")?;
+    expect(LLM_KV_GENERAL_ARCHITECTURE, arch)?;
+    let alpha = gguf.get_f32(LLM_KV_ADAPTER_LORA_ALPHA).unwrap_or(0.0);
+
+    let mut pairs: BTreeMap<&str, [Option<usize>; 2]> = BTreeMap::new();
+    for (i, t) in gguf.tensors().iter().enumerate() {
+        let (base, slot) = match (t.name.strip_suffix(".lora_a"), t.name.strip_suffix(".lora_b")) {
+            (Some(base), _) => (base, 0),
+            (_, Some(base)) => (base, 1),
+            _ => return Err(format!("unexpected tensor '{}' (LoRA tensors end in .lora_a or .lora_b)", t.name)),
+        };
+        pairs.entry(base).or_default()[slot] = Some(i);
+    }
+    if pairs.is_empty() {
+        return Err("the adapter has no tensors".to_string());
+    }
+
+    let mut weights = BTreeMap::new();
+    for (base, [a, b]) in pairs {
+        let (Some(a), Some(b)) = (a, b) else {
+            return Err(format!("LoRA pair of '{}' is missing {}", base, if a.is_none() { "lora_a" } else { "lora_b" }));
+        };
+        let (a, b) = (&gguf.tensors()[a], &gguf.tensors()[b]);
+        let [n_in, n_out] = base_shape(base).ok_or_else(|| format!("the base model has no weight '{}'", base))?;
+        let (&[a_in, a_rank], &[b_rank, b_out]) = (a.dims.as_slice(), b.dims.as_slice()) else {
+            return Err(format!("LoRA tensors of '{}' must be 2-D", base));
+        };
+        if a_rank != b_rank || a_rank == 0 {
+            return Err(format!("LoRA rank mismatch for '{}': lora_a has {}, lora_b has {}", base, a_rank, b_rank));
+        }
+        if a_in != n_in || b_out != n_out {
+            return Err(format!(
+                "LoRA shape mismatch for '{}': adapter is [{}, {}], base weight is [{}, {}]",
+                base, a_in, b_out, n_in, n_out
+            ));
+        }
+        let weight = LlamaLoraWeight { a: gguf.tensor_f32(a), b: gguf.tensor_f32(b), n_in, n_out, rank: a_rank };
+        weights.insert(base.to_string(), weight);
+    }
+
+    let meta = gguf.kv().iter().map(|(k, v)| (k.clone(), v.to_display())).collect();
+    Ok(LlamaAdapterLora::new(path, alpha, meta, weights))
+}
+
+/// Directions of one control vector file, keyed by layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaControlVectorFile {
+    pub n_embd: usize,
+    pub directions: BTreeMap<usize, Vec<f32>>,
+}
+
+pub fn llama_control_vector_load(path: impl AsRef<Path>) -> Result<LlamaControlVectorFile, String> {
+    let path = path.as_ref();
+    let gguf = GgufFile::read(path)?;
+    control_vector_from_gguf(&gguf).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn control_vector_from_gguf(gguf: &GgufFile) -> Result<LlamaControlVectorFile, String> {
+    let mut n_embd = 0;
+    let mut directions = BTreeMap::new();
+    for t in gguf.tensors() {
+        let il = t
+            .name
+            .strip_prefix("direction.")
+            .and_then(|il| il.parse::<usize>().ok())
+            .ok_or_else(|| format!("unexpected tensor '{}' (control vector tensors are named direction.<layer>)", t.name))?;
+        if il == 0 {
+            return Err("direction.0 is invalid: layers are counted from 1".to_string());
+        }
+        let &[n] = t.dims.as_slice() else {
+            return Err(format!("tensor '{}' must be 1-D", t.name));
+        };
+        if n_embd != 0 && n != n_embd {
+            return Err(format!("tensor '{}' has {} values, expected {}", t.name, n, n_embd));
+        }
+        n_embd = n;
+        directions.insert(il, gguf.tensor_f32(t));
+    }
+    if directions.is_empty() {
+        return Err("no direction tensors found".to_string());
+    }
+    Ok(LlamaControlVectorFile { n_embd, directions })
 }
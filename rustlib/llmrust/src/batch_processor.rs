@@ -1,6 +1,271 @@
-// src/batch_processor.rs - Auto-generated debug file
+// src/batch_processor.rs - Continuous batching of concurrent sequences
+//
+// Every step packs one batch from all active sequences: first the next token
+// of each generating sequence, so running generations advance on every step,
+// then chunks of pending prompts in arrival order until `n_batch` tokens are
+// used. When generating sequences alone would fill the batch they take turns,
+// and one token is left for pending prompts; with a batch of one token,
+// generation and prompts get it on alternate steps. Logits are only computed for the
+// last prompt token and for generated tokens; the token sampled from each
+// output row is routed back to its sequence and decoded in the next step.
+// Sequences join and leave between steps, so a new request does not wait for
+// the running ones to finish.
+//
+// Sequences that need a different context setup (for example another control
+// vector) are put in different groups. A batch only carries sequences of one
+// group; when several groups have work, steps serve them in turn.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/batch_processor.rs - File loaded successfully");
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaSplitStrategy, LlamaUbatch};
+use crate::llmrust::src::llama_memory::{LlamaPos, LlamaSeqId};
+use crate::llmrust::src::llama_vocab::LlamaToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchStopReason {
+    /// An end-of-generation token was sampled
+    Eog,
+    /// The requested number of tokens was generated
+    Length,
+}
+
+/// A sequence being evaluated by the scheduler
+#[derive(Debug, Clone)]
+pub struct BatchSequence {
+    pub seq_id: LlamaSeqId,
+    /// Prompt followed by the generated tokens; `tokens[i]` is at position `i`
+    pub tokens: Vec<LlamaToken>,
+    /// Tokens already evaluated (in memory)
+    pub n_past: usize,
+    pub n_prompt: usize,
+    /// Tokens left to generate
+    pub n_remain: usize,
+    pub stop: Option<BatchStopReason>,
+    /// Only sequences of the same group are batched together
+    pub group: u64,
+}
+
+impl BatchSequence {
+    pub fn is_generating(&self) -> bool {
+        self.n_past >= self.n_prompt
+    }
+
+    /// Tokens generated so far
+    pub fn generated(&self) -> &[LlamaToken] {
+        &self.tokens[self.n_prompt.min(self.tokens.len())..]
+    }
+
+    fn has_work(&self) -> bool {
+        !self.is_generating() || self.n_past < self.tokens.len()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.stop.is_some() || (self.n_remain == 0 && self.is_generating())
+    }
+}
+
+/// Batch built for one step
+#[derive(Debug, Clone, Default)]
+pub struct ScheduledBatch {
+    pub batch: LlamaBatch,
+    /// Micro-batches to evaluate, in order
+    pub ubatches: Vec<LlamaUbatch>,
+    /// Group of every sequence in the batch
+    pub group: u64,
+    /// Sequence of each output (logits) row
+    pub outputs: Vec<LlamaSeqId>,
+}
+
+pub struct BatchScheduler {
+    n_batch: usize,
+    n_ubatch: usize,
+    split: LlamaSplitStrategy,
+    seqs: Vec<BatchSequence>,
+    /// First generating sequence to schedule when they do not all fit
+    cursor: usize,
+    /// Group of the previous batch
+    last_group: Option<u64>,
+    /// With `n_batch == 1`, whether the last contested step went to a prompt
+    prompt_turn: bool,
+}
+
+impl BatchScheduler {
+    pub fn new(n_batch: usize, n_ubatch: usize) -> Result<Self, String> {
+        if n_batch == 0 || n_ubatch == 0 {
+            return Err(format!("n_batch ({}) and n_ubatch ({}) must be positive", n_batch, n_ubatch));
+        }
+        Ok(Self { n_batch, n_ubatch: n_ubatch.min(n_batch), split: LlamaSplitStrategy::default(), seqs: Vec::new(), cursor: 0, last_group: None, prompt_turn: false })
+    }
+
+    pub fn n_batch(&self) -> usize {
+        self.n_batch
+    }
+
+    pub fn n_ubatch(&self) -> usize {
+        self.n_ubatch
+    }
+
+    pub fn split(&self) -> LlamaSplitStrategy {
+        self.split
+    }
+
+    /// Sets how scheduled batches are split into ubatches.
+    pub fn set_split(&mut self, split: LlamaSplitStrategy) {
+        self.split = split;
+    }
+
+    pub fn len(&self) -> usize {
+        self.seqs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seqs.is_empty()
+    }
+
+    /// Adds a sequence whose first `n_past` prompt tokens are already in
+    /// memory (e.g. from the prompt cache); the last prompt token is always
+    /// evaluated.
+    pub fn add(&mut self, seq_id: LlamaSeqId, prompt: Vec<LlamaToken>, n_past: usize, n_predict: usize) -> Result<(), String> {
+        if self.get(seq_id).is_some() {
+            return Err(format!("sequence {} is already scheduled", seq_id));
+        }
+        if prompt.is_empty() {
+            return Err("the prompt must not be empty".to_string());
+        }
+        if n_past >= prompt.len() {
+            return Err(format!("n_past ({}) must be less than the prompt length ({})", n_past, prompt.len()));
+        }
+        let n_prompt = prompt.len();
+        self.seqs.push(BatchSequence { seq_id, tokens: prompt, n_past, n_prompt, n_remain: n_predict, stop: None, group: 0 });
+        Ok(())
+    }
+
+    /// Like `add`, for a sequence of `group`.
+    pub fn add_to_group(
+        &mut self,
+        seq_id: LlamaSeqId,
+        prompt: Vec<LlamaToken>,
+        n_past: usize,
+        n_predict: usize,
+        group: u64,
+    ) -> Result<(), String> {
+        self.add(seq_id, prompt, n_past, n_predict)?;
+        if let Some(seq) = self.seqs.last_mut() {
+            seq.group = group;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, seq_id: LlamaSeqId) -> Option<BatchSequence> {
+        let i = self.seqs.iter().position(|s| s.seq_id == seq_id)?;
+        Some(self.seqs.remove(i))
+    }
+
+    pub fn get(&self, seq_id: LlamaSeqId) -> Option<&BatchSequence> {
+        self.seqs.iter().find(|s| s.seq_id == seq_id)
+    }
+
+    pub fn get_mut(&mut self, seq_id: LlamaSeqId) -> Option<&mut BatchSequence> {
+        self.seqs.iter_mut().find(|s| s.seq_id == seq_id)
+    }
+
+    pub fn sequences(&self) -> &[BatchSequence] {
+        &self.seqs
+    }
+
+    /// Builds the next batch and marks its tokens as evaluated; `None` when no
+    /// sequence has tokens to evaluate. The caller decodes the batch and then
+    /// passes the tokens sampled from its output rows to `commit`.
+    pub fn next_batch(&mut self) -> Option<ScheduledBatch> {
+        // the group after the previous one that has work, in turn
+        let mut groups: Vec<u64> = self.seqs.iter().filter(|s| s.has_work()).map(|s| s.group).collect();
+        groups.sort_unstable();
+        groups.dedup();
+        let group = *groups.iter().find(|&&g| self.last_group.is_some_and(|last| g > last)).or(groups.first())?;
+        self.last_group = Some(group);
+        let mut scheduled = ScheduledBatch { group, ..Default::default() };
+
+        let generating: Vec<usize> = (0..self.seqs.len())
+            .filter(|&i| self.seqs[i].group == group && self.seqs[i].is_generating() && self.seqs[i].has_work())
+            .collect();
+        if !generating.is_empty() {
+            // leave room for pending prompts so that new requests get started
+            let prompts_pending = self.seqs.iter().any(|s| s.group == group && !s.is_generating());
+            let n_decode = match (prompts_pending, self.n_batch) {
+                (false, n_batch) => n_batch,
+                (true, 1) => {
+                    self.prompt_turn = !self.prompt_turn;
+                    usize::from(!self.prompt_turn)
+                }
+                (true, n_batch) => n_batch - 1,
+            };
+            let n_decode = generating.len().min(n_decode);
+            let start = self.cursor % generating.len();
+            for &i in generating.iter().cycle().skip(start).take(n_decode) {
+                let seq = &mut self.seqs[i];
+                scheduled.batch.add(seq.tokens[seq.n_past], seq.n_past as LlamaPos, &[seq.seq_id], true);
+                scheduled.outputs.push(seq.seq_id);
+                seq.n_past += 1;
+            }
+            self.cursor = start + n_decode;
+        }
+
+        for seq in self.seqs.iter_mut().filter(|s| s.group == group && !s.is_generating()) {
+            let n_free = self.n_batch - scheduled.batch.n_tokens();
+            if n_free == 0 {
+                break;
+            }
+            let n_chunk = (seq.n_prompt - seq.n_past).min(n_free);
+            for pos in seq.n_past..seq.n_past + n_chunk {
+                let logits = pos + 1 == seq.n_prompt && seq.n_remain > 0;
+                scheduled.batch.add(seq.tokens[pos], pos as LlamaPos, &[seq.seq_id], logits);
+                if logits {
+                    scheduled.outputs.push(seq.seq_id);
+                }
+            }
+            seq.n_past += n_chunk;
+        }
+
+        if scheduled.batch.is_empty() {
+            return None;
+        }
+        scheduled.ubatches = scheduled.batch.split(self.split, self.n_ubatch);
+        Some(scheduled)
+    }
+
+    /// Routes the token sampled from each output row of `scheduled` to its
+    /// sequence and removes and returns the sequences that finished.
+    pub fn commit(
+        &mut self,
+        scheduled: &ScheduledBatch,
+        sampled: &[LlamaToken],
+        is_eog: impl Fn(LlamaToken) -> bool,
+    ) -> Result<Vec<BatchSequence>, String> {
+        if sampled.len() != scheduled.outputs.len() {
+            return Err(format!("got {} sampled tokens for {} output rows", sampled.len(), scheduled.outputs.len()));
+        }
+        for (&seq_id, &token) in scheduled.outputs.iter().zip(sampled) {
+            // sequences removed since the batch was built are skipped
+            let Some(seq) = self.get_mut(seq_id) else { continue };
+            seq.tokens.push(token);
+            seq.n_remain = seq.n_remain.saturating_sub(1);
+            if is_eog(token) {
+                seq.stop = Some(BatchStopReason::Eog);
+            } else if seq.n_remain == 0 {
+                seq.stop = Some(BatchStopReason::Length);
+            }
+        }
+
+        let mut finished = Vec::new();
+        let mut i = 0;
+        while i < self.seqs.len() {
+            if self.seqs[i].is_finished() {
+                let mut seq = self.seqs.remove(i);
+                seq.stop.get_or_insert(BatchStopReason::Length);
+                finished.push(seq);
+            } else {
+                i += 1;
+            }
+        }
+        Ok(finished)
+    }
 }
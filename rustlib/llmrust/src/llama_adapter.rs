@@ -1,6 +1,239 @@
-// src/llama_adapter.rs - Auto-generated debug file
+// src/llama_adapter.rs - LoRA adapters applied at inference time
+//
+// A LoRA adapter replaces a weight `W` (`[n_in, n_out]`) by `W + s * B·A`,
+// where `A` is `[n_in, r]`, `B` is `[r, n_out]` and `r` is much smaller than
+// either dimension. The product is never merged into the base weights: the
+// graph computes `W·x + s * B·(A·x)` for each active adapter, so adapters can
+// be enabled, disabled and rescaled between batches without reloading the
+// model. The effective scale is the user scale times `alpha / r` when the
+// adapter sets `adapter.lora.alpha`.
+//
+// A control vector adds a fixed direction to the residual stream (the output
+// of a layer) of every token, for the layers of a configured range. Layer 0
+// has no direction; the data passed in starts at layer 1.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_adapter.rs - File loaded successfully");
+use std::collections::BTreeMap;
+use std::os::raw::c_void;
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+
+/// Low-rank pair of one base weight, stored as f32
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaLoraWeight {
+    /// `[n_in, rank]`: `rank` rows of `n_in` values
+    pub a: Vec<f32>,
+    /// `[rank, n_out]`: `n_out` rows of `rank` values
+    pub b: Vec<f32>,
+    pub n_in: usize,
+    pub n_out: usize,
+    pub rank: usize,
+}
+
+impl LlamaLoraWeight {
+    /// Adds `scale * B·(A·x)` to `y`.
+    pub fn apply(&self, x: &[f32], scale: f32, y: &mut [f32]) {
+        let ax: Vec<f32> = self.a.chunks_exact(self.n_in).map(|row| dot(row, x)).collect();
+        for (yo, row) in y.iter_mut().zip(self.b.chunks_exact(self.rank)) {
+            *yo += scale * dot(row, &ax);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LlamaAdapterLora {
+    pub path: String,
+    /// `adapter.lora.alpha`, 0 if unset
+    pub alpha: f32,
+    /// Metadata as text, in file order
+    meta: Vec<(String, String)>,
+    /// Keyed by base weight name
+    weights: BTreeMap<String, LlamaLoraWeight>,
+}
+
+impl LlamaAdapterLora {
+    pub fn new(path: &str, alpha: f32, meta: Vec<(String, String)>, weights: BTreeMap<String, LlamaLoraWeight>) -> Self {
+        Self { path: path.to_string(), alpha, meta, weights }
+    }
+
+    pub fn weight(&self, name: &str) -> Option<&LlamaLoraWeight> {
+        self.weights.get(name)
+    }
+
+    /// Names of the base weights the adapter patches
+    pub fn weight_names(&self) -> impl Iterator<Item = &str> {
+        self.weights.keys().map(String::as_str)
+    }
+
+    pub fn meta_val_str(&self, key: &str) -> Option<&str> {
+        self.meta.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn meta(&self) -> &[(String, String)] {
+        &self.meta
+    }
+
+    /// Scale of `B·A` for the user scale `scale`
+    pub fn effective_scale(&self, weight: &LlamaLoraWeight, scale: f32) -> f32 {
+        if self.alpha != 0.0 {
+            scale * self.alpha / weight.rank as f32
+        } else {
+            scale
+        }
+    }
+}
+
+/// Adapters active on a context, with their user scales, in the order they
+/// were enabled
+#[derive(Debug, Clone, Default)]
+pub struct LlamaAdapterLoras {
+    entries: Vec<(Arc<LlamaAdapterLora>, f32)>,
+}
+
+impl LlamaAdapterLoras {
+    /// Enables `adapter` with `scale`, or rescales it if already enabled.
+    pub fn set(&mut self, adapter: Arc<LlamaAdapterLora>, scale: f32) {
+        match self.entries.iter_mut().find(|(a, _)| Arc::ptr_eq(a, &adapter)) {
+            Some(entry) => entry.1 = scale,
+            None => self.entries.push((adapter, scale)),
+        }
+    }
+
+    /// Disables `adapter`; false if it was not enabled.
+    pub fn remove(&mut self, adapter: &Arc<LlamaAdapterLora>) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(a, _)| !Arc::ptr_eq(a, adapter));
+        self.entries.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn scale(&self, adapter: &Arc<LlamaAdapterLora>) -> Option<f32> {
+        self.entries.iter().find(|(a, _)| Arc::ptr_eq(a, adapter)).map(|&(_, s)| s)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Arc<LlamaAdapterLora>, f32)> {
+        self.entries.iter().map(|(a, s)| (a, *s))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// `W·x` for the base weight `name` (`n_out` rows of `x.len()` values)
+    /// plus the deltas of the active adapters that patch it
+    pub fn mm(&self, name: &str, w: &[f32], x: &[f32]) -> Vec<f32> {
+        let mut y: Vec<f32> = w.chunks_exact(x.len()).map(|row| dot(row, x)).collect();
+        for (adapter, scale) in self.iter() {
+            if let Some(weight) = adapter.weight(name) {
+                if weight.n_in == x.len() && weight.n_out == y.len() {
+                    weight.apply(x, adapter.effective_scale(weight, scale), &mut y);
+                }
+            }
+        }
+        y
+    }
+}
+
+/// Control vector applied to layers `layer_start..=layer_end`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LlamaAdapterCvec {
+    layer_start: i32,
+    layer_end: i32,
+    /// Direction of each layer, indexed by layer; empty where there is none
+    layers: Vec<Vec<f32>>,
+}
+
+impl LlamaAdapterCvec {
+    /// Builds the vector of `llama_apply_adapter_cvec`: `data` holds `n_embd`
+    /// values per layer starting at layer 1; layers beyond its end get none.
+    pub fn new(hparams: &LlamaHparams, data: &[f32], n_embd: usize, layer_start: i32, layer_end: i32) -> Result<Self, String> {
+        if n_embd != hparams.n_embd as usize {
+            return Err(format!("control vector n_embd = {} does not match the model's n_embd = {}", n_embd, hparams.n_embd));
+        }
+        let mut layers = vec![Vec::new(); hparams.n_layer as usize];
+        for (il, dir) in layers.iter_mut().enumerate().skip(1) {
+            if let Some(src) = data.get(n_embd * (il - 1)..n_embd * il) {
+                *dir = src.to_vec();
+            }
+        }
+        Ok(Self { layer_start, layer_end, layers })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.iter().all(Vec::is_empty)
+    }
+
+    pub fn layer_range(&self) -> (i32, i32) {
+        (self.layer_start, self.layer_end)
+    }
+
+    /// Hash of the range and directions; equal vectors have equal fingerprints
+    pub fn fingerprint(&self) -> u64 {
+        let words = [self.layer_start as u32, self.layer_end as u32]
+            .into_iter()
+            .chain(self.layers.iter().flat_map(|dir| std::iter::once(dir.len() as u32).chain(dir.iter().map(|v| v.to_bits()))));
+        // FNV-1a
+        words
+            .flat_map(|w| w.to_le_bytes())
+            .fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    /// Direction added after layer `il`, if any
+    pub fn tensor_for(&self, il: i32) -> Option<&[f32]> {
+        if il < self.layer_start || il > self.layer_end {
+            return None;
+        }
+        let dir = self.layers.get(usize::try_from(il).ok()?)?;
+        (!dir.is_empty()).then_some(dir.as_slice())
+    }
+
+    /// Adds the direction of layer `il` to the hidden state `cur`.
+    pub fn apply_to(&self, cur: &mut [f32], il: i32) {
+        if let Some(dir) = self.tensor_for(il) {
+            for (c, d) in cur.iter_mut().zip(dir) {
+                *c += d;
+            }
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Creates a C handle holding a reference to `adapter`; release it with
+/// `free_handle`. Contexts using the adapter keep their own references.
+pub fn into_handle(adapter: Arc<LlamaAdapterLora>) -> *mut c_void {
+    Arc::into_raw(adapter) as *mut c_void
+}
+
+/// New reference to the adapter behind a C handle.
+///
+/// # Safety
+/// `adapter` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn from_handle(adapter: *mut c_void) -> Option<Arc<LlamaAdapterLora>> {
+    if adapter.is_null() {
+        return None;
+    }
+    let ptr = adapter as *const LlamaAdapterLora;
+    Arc::increment_strong_count(ptr);
+    Some(Arc::from_raw(ptr))
+}
+
+/// Releases a handle created by `into_handle`.
+///
+/// # Safety
+/// `adapter` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn free_handle(adapter: *mut c_void) {
+    if !adapter.is_null() {
+        drop(Arc::from_raw(adapter as *const LlamaAdapterLora));
+    }
 }
@@ -1,6 +1,89 @@
-// src/llama_arch.rs - Auto-generated debug file
+// src/llama_arch.rs - Model architectures and their tensor layout
+//
+// The architecture name is the `general.architecture` GGUF key; files that
+// patch a model (LoRA adapters, control vectors) must name the same one. The
+// weight shapes follow from the hyperparameters and are given in ggml order,
+// `[n_in, n_out]`: a weight maps rows of `n_in` values to `n_out` values.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_arch.rs - File loaded successfully");
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+
+pub const LLM_ARCH_LLAMA: &str = "llama";
+pub const LLM_ARCH_BERT: &str = "bert";
+pub const LLM_ARCH_NOMIC_BERT: &str = "nomic-bert";
+pub const LLM_ARCH_JINA_BERT_V2: &str = "jina-bert-v2";
+
+pub const LLM_KV_GENERAL_ARCHITECTURE: &str = "general.architecture";
+pub const LLM_KV_GENERAL_TYPE: &str = "general.type";
+
+// Keys of architecture hyperparameters; `%s` stands for the architecture name
+pub const LLM_KV_VOCAB_SIZE: &str = "%s.vocab_size";
+pub const LLM_KV_CONTEXT_LENGTH: &str = "%s.context_length";
+pub const LLM_KV_EMBEDDING_LENGTH: &str = "%s.embedding_length";
+pub const LLM_KV_BLOCK_COUNT: &str = "%s.block_count";
+pub const LLM_KV_FEED_FORWARD_LENGTH: &str = "%s.feed_forward_length";
+pub const LLM_KV_EXPERT_COUNT: &str = "%s.expert_count";
+pub const LLM_KV_EXPERT_USED_COUNT: &str = "%s.expert_used_count";
+pub const LLM_KV_POOLING_TYPE: &str = "%s.pooling_type";
+pub const LLM_KV_ATTENTION_HEAD_COUNT: &str = "%s.attention.head_count";
+pub const LLM_KV_ATTENTION_HEAD_COUNT_KV: &str = "%s.attention.head_count_kv";
+pub const LLM_KV_ATTENTION_KEY_LENGTH: &str = "%s.attention.key_length";
+pub const LLM_KV_ATTENTION_VALUE_LENGTH: &str = "%s.attention.value_length";
+pub const LLM_KV_ATTENTION_SLIDING_WINDOW: &str = "%s.attention.sliding_window";
+pub const LLM_KV_ATTENTION_SLIDING_WINDOW_PATTERN: &str = "%s.attention.sliding_window_pattern";
+pub const LLM_KV_ATTENTION_CAUSAL: &str = "%s.attention.causal";
+pub const LLM_KV_ATTENTION_LAYERNORM_RMS_EPS: &str = "%s.attention.layer_norm_rms_epsilon";
+pub const LLM_KV_ROPE_DIMENSION_COUNT: &str = "%s.rope.dimension_count";
+pub const LLM_KV_ROPE_FREQ_BASE: &str = "%s.rope.freq_base";
+pub const LLM_KV_ROPE_SCALE_LINEAR: &str = "%s.rope.scale_linear";
+pub const LLM_KV_ROPE_SCALING_TYPE: &str = "%s.rope.scaling.type";
+pub const LLM_KV_ROPE_SCALING_FACTOR: &str = "%s.rope.scaling.factor";
+pub const LLM_KV_ROPE_SCALING_ATTN_FACTOR: &str = "%s.rope.scaling.attn_factor";
+pub const LLM_KV_ROPE_SCALING_ORIG_CTX_LEN: &str = "%s.rope.scaling.original_context_length";
+
+// LongRoPE frequency factors for contexts up to and beyond the original length
+pub const LLM_TENSOR_ROPE_FACTORS_SHORT: &str = "rope_factors_short.weight";
+pub const LLM_TENSOR_ROPE_FACTORS_LONG: &str = "rope_factors_long.weight";
+
+pub const LLM_KV_TOKENIZER_LIST: &str = "tokenizer.ggml.tokens";
+pub const LLM_KV_TOKENIZER_ADD_BOS: &str = "tokenizer.ggml.add_bos_token";
+pub const LLM_KV_TOKENIZER_ADD_EOS: &str = "tokenizer.ggml.add_eos_token";
+// (sic) the key name used by GGUF files
+pub const LLM_KV_TOKENIZER_SEP_ID: &str = "tokenizer.ggml.seperator_token_id";
+
+/// Full key name of `kv` for architecture `arch`
+pub fn llm_kv(kv: &str, arch: &str) -> String {
+    kv.replace("%s", arch)
+}
+
+/// Whether `arch` is a bidirectional encoder, whose attention is non-causal
+/// unless the model says otherwise
+pub fn llm_arch_is_encoder(arch: &str) -> bool {
+    matches!(arch, LLM_ARCH_BERT | LLM_ARCH_NOMIC_BERT | LLM_ARCH_JINA_BERT_V2)
+}
+
+/// Shape of the 2-D weight `name` of a LLaMA-style model, or `None` if the
+/// model has no such weight.
+pub fn llama_tensor_shape(hparams: &LlamaHparams, name: &str) -> Option<[usize; 2]> {
+    let n_embd = hparams.n_embd as usize;
+    let n_q = (hparams.n_head * hparams.n_embd_head_k) as usize;
+    let n_out_v = (hparams.n_head * hparams.n_embd_head_v) as usize;
+    let n_ff = hparams.n_ff as usize;
+    match name {
+        "token_embd.weight" | "output.weight" => return Some([n_embd, hparams.n_vocab as usize]),
+        _ => {}
+    }
+    let (il, tensor) = name.strip_prefix("blk.")?.split_once('.')?;
+    if il.parse::<u32>().ok()? >= hparams.n_layer {
+        return None;
+    }
+    match tensor {
+        "attn_q.weight" => Some([n_embd, n_q]),
+        "attn_k.weight" => Some([n_embd, hparams.n_embd_k_gqa() as usize]),
+        "attn_v.weight" => Some([n_embd, hparams.n_embd_v_gqa() as usize]),
+        "attn_output.weight" => Some([n_out_v, n_embd]),
+        "ffn_gate.weight" | "ffn_up.weight" => Some([n_embd, n_ff]),
+        "ffn_down.weight" => Some([n_ff, n_embd]),
+        _ => None,
+    }
 }
@@ -1,6 +1,240 @@
-// src/llama_batch.rs - Auto-generated debug file
+// src/llama_batch.rs - Token batches and their micro-batches
+//
+// A batch holds the tokens of one forward pass: for each token its position,
+// the sequences it belongs to and whether its logits are needed. Tokens of
+// different sequences can share a batch. A batch larger than `n_ubatch` is
+// evaluated as several micro-batches (ubatches), each a list of token indices
+// into the batch. How tokens are grouped into ubatches is the split strategy:
+//
+// - simple: consecutive tokens in batch order, fastest for attention models;
+// - equal: the same number of tokens from each of several sequences, which
+//   recurrent models need to update their per-sequence state in lockstep;
+// - seq: one sequence per ubatch.
+//
+// Every strategy keeps the tokens of a sequence in batch order, so a model
+// whose memory carries state across ubatches gives the same outputs for any
+// split.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_batch.rs - File loaded successfully");
+use std::collections::VecDeque;
+
+use crate::llmrust::src::llama_memory::{LlamaPos, LlamaSeqId};
+use crate::llmrust::src::llama_vocab::LlamaToken;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LlamaBatch {
+    pub token: Vec<LlamaToken>,
+    pub pos: Vec<LlamaPos>,
+    pub seq_id: Vec<Vec<LlamaSeqId>>,
+    /// Whether to compute the logits of each token
+    pub logits: Vec<bool>,
+}
+
+impl LlamaBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn n_tokens(&self) -> usize {
+        self.token.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.token.is_empty()
+    }
+
+    /// Number of tokens whose logits are computed, i.e. rows of the output
+    pub fn n_outputs(&self) -> usize {
+        self.logits.iter().filter(|&&l| l).count()
+    }
+
+    /// Appends a token (`common_batch_add`).
+    pub fn add(&mut self, token: LlamaToken, pos: LlamaPos, seq_ids: &[LlamaSeqId], logits: bool) {
+        self.token.push(token);
+        self.pos.push(pos);
+        self.seq_id.push(seq_ids.to_vec());
+        self.logits.push(logits);
+    }
+
+    /// Removes all tokens (`common_batch_clear`).
+    pub fn clear(&mut self) {
+        self.token.clear();
+        self.pos.clear();
+        self.seq_id.clear();
+        self.logits.clear();
+    }
+
+    /// Checks that the per-token arrays agree and every token belongs to at
+    /// least one valid sequence.
+    pub fn validate(&self) -> Result<(), String> {
+        let n = self.token.len();
+        if self.pos.len() != n || self.seq_id.len() != n || self.logits.len() != n {
+            return Err(format!(
+                "batch arrays differ in length: {} tokens, {} positions, {} seq_id lists, {} logits flags",
+                n,
+                self.pos.len(),
+                self.seq_id.len(),
+                self.logits.len()
+            ));
+        }
+        for (i, seq_ids) in self.seq_id.iter().enumerate() {
+            if seq_ids.is_empty() {
+                return Err(format!("token {} of the batch has no sequence", i));
+            }
+            if let Some(&s) = seq_ids.iter().find(|&&s| s < 0) {
+                return Err(format!("token {} of the batch has invalid seq_id {}", i, s));
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the batch into ubatches of at most `n_ubatch` tokens.
+    pub fn split(&self, strategy: LlamaSplitStrategy, n_ubatch: usize) -> Vec<LlamaUbatch> {
+        match strategy {
+            LlamaSplitStrategy::Simple => self.split_simple(n_ubatch),
+            LlamaSplitStrategy::Equal => self.split_equal(n_ubatch),
+            LlamaSplitStrategy::Seq => self.split_seq(n_ubatch),
+        }
+    }
+
+    /// Splits the batch into consecutive ubatches of at most `n_ubatch` tokens.
+    pub fn split_simple(&self, n_ubatch: usize) -> Vec<LlamaUbatch> {
+        let n_ubatch = n_ubatch.max(1);
+        (0..self.n_tokens())
+            .step_by(n_ubatch)
+            .map(|start| LlamaUbatch { idxs: (start..(start + n_ubatch).min(self.n_tokens())).collect(), equal_seqs: false })
+            .collect()
+    }
+
+    /// Splits the batch so that every ubatch holds the same number of tokens
+    /// from each of its sequences. Tokens shared by several sequences are
+    /// grouped by their set of sequences, and groups that share a sequence
+    /// never go into the same ubatch.
+    pub fn split_equal(&self, n_ubatch: usize) -> Vec<LlamaUbatch> {
+        let n_ubatch = n_ubatch.max(1);
+        let mut groups = self.seq_groups();
+        let mut ubatches = Vec::new();
+        while let Some(first) = next_group(&groups) {
+            let mut chosen = vec![(first, eligible(&groups, first))];
+            let mut by_start: Vec<usize> = (0..groups.len()).filter(|&g| g != first && !groups[g].idxs.is_empty()).collect();
+            by_start.sort_by_key(|&g| groups[g].idxs[0]);
+            for g in by_start {
+                if chosen.len() == n_ubatch {
+                    break;
+                }
+                if chosen.iter().any(|&(c, _)| overlaps(&groups[c].seq_ids, &groups[g].seq_ids)) {
+                    continue;
+                }
+                let n = eligible(&groups, g);
+                if n > 0 {
+                    chosen.push((g, n));
+                }
+            }
+            let n_seq_tokens = chosen.iter().map(|&(_, n)| n).min().unwrap_or(1).min(n_ubatch / chosen.len());
+            let idxs = chosen.iter().flat_map(|&(g, _)| groups[g].idxs.drain(..n_seq_tokens).collect::<Vec<_>>()).collect();
+            ubatches.push(LlamaUbatch { idxs, equal_seqs: true });
+        }
+        ubatches
+    }
+
+    /// Splits the batch so that every ubatch holds tokens of a single set of
+    /// sequences.
+    pub fn split_seq(&self, n_ubatch: usize) -> Vec<LlamaUbatch> {
+        let n_ubatch = n_ubatch.max(1);
+        let mut groups = self.seq_groups();
+        let mut ubatches = Vec::new();
+        while let Some(g) = next_group(&groups) {
+            let n = eligible(&groups, g).min(n_ubatch);
+            ubatches.push(LlamaUbatch { idxs: groups[g].idxs.drain(..n).collect(), equal_seqs: true });
+        }
+        ubatches
+    }
+
+    /// Token indices grouped by their (sorted) set of sequences
+    fn seq_groups(&self) -> Vec<SeqGroup> {
+        let mut groups: Vec<SeqGroup> = Vec::new();
+        for (i, seq_ids) in self.seq_id.iter().enumerate() {
+            let mut seq_ids = seq_ids.clone();
+            seq_ids.sort_unstable();
+            seq_ids.dedup();
+            match groups.iter_mut().find(|g| g.seq_ids == seq_ids) {
+                Some(group) => group.idxs.push_back(i),
+                None => groups.push(SeqGroup { seq_ids, idxs: VecDeque::from([i]) }),
+            }
+        }
+        groups
+    }
+}
+
+#[derive(Debug)]
+struct SeqGroup {
+    seq_ids: Vec<LlamaSeqId>,
+    /// Remaining token indices, in batch order
+    idxs: VecDeque<usize>,
+}
+
+fn overlaps(a: &[LlamaSeqId], b: &[LlamaSeqId]) -> bool {
+    a.iter().any(|s| b.contains(s))
+}
+
+/// Group holding the earliest remaining token
+fn next_group(groups: &[SeqGroup]) -> Option<usize> {
+    (0..groups.len()).filter(|&g| !groups[g].idxs.is_empty()).min_by_key(|&g| groups[g].idxs[0])
+}
+
+/// Remaining tokens of group `g` that can be evaluated before any pending token
+/// of an overlapping group, which keeps every sequence in batch order
+fn eligible(groups: &[SeqGroup], g: usize) -> usize {
+    let limit = groups
+        .iter()
+        .enumerate()
+        .filter(|&(o, other)| o != g && !other.idxs.is_empty() && overlaps(&other.seq_ids, &groups[g].seq_ids))
+        .map(|(_, other)| other.idxs[0])
+        .min()
+        .unwrap_or(usize::MAX);
+    groups[g].idxs.iter().take_while(|&&i| i < limit).count()
+}
+
+/// How a batch is split into ubatches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LlamaSplitStrategy {
+    #[default]
+    Simple,
+    Equal,
+    Seq,
+}
+
+impl LlamaSplitStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "simple" => Some(Self::Simple),
+            "equal" => Some(Self::Equal),
+            "seq" => Some(Self::Seq),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Simple => "simple",
+            Self::Equal => "equal",
+            Self::Seq => "seq",
+        }
+    }
+}
+
+/// Tokens of a batch evaluated together
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LlamaUbatch {
+    /// Indices of the tokens in the batch; for equal splits, the tokens of
+    /// each sequence are contiguous
+    pub idxs: Vec<usize>,
+    /// Every sequence contributes the same number of tokens
+    pub equal_seqs: bool,
+}
+
+impl LlamaUbatch {
+    pub fn n_tokens(&self) -> usize {
+        self.idxs.len()
+    }
 }
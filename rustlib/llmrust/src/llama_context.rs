@@ -1,6 +1,531 @@
-// src/llama_context.rs - Auto-generated debug file
+// src/llama_context.rs - Inference context and its saved state
+//
+// A context ties the memory (KV cache) of a model to the sampler chains of the
+// sequences being generated. Its state, or the state of a single sequence, can
+// be written to any `LlamaIoWrite` and restored later into a context of the
+// same model: the KV cells and the sampler state (RNG, grammar position, ...)
+// so that generation continues exactly where it stopped. The C API copies the
+// state to and from caller-provided buffers.
+//
+// `decode` evaluates a batch through a graph, split into ubatches of at most
+// `n_ubatch` tokens by the configured strategy, and keeps the logits of the
+// batch's output tokens in batch order whatever the split. A context created
+// for embeddings keeps hidden states instead: per output token without
+// pooling, otherwise one pooled vector per sequence of the batch.
+//
+// LoRA adapters and the control vector are set per context and handed to the
+// graph at every decode, so they can be swapped or rescaled between requests
+// while the model weights stay shared. The attention type works the same way,
+// so one context of a GritLM-style model can embed non-causally and generate
+// causally.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_context.rs - File loaded successfully");
+use std::collections::BTreeMap;
+use std::os::raw::c_void;
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_adapter::{LlamaAdapterCvec, LlamaAdapterLora, LlamaAdapterLoras};
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaSplitStrategy};
+use crate::llmrust::src::llama_cparams::LlamaCparams;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::{LlamaAttentionType, LlamaHparams, LlamaPoolingType};
+use crate::llmrust::src::llama_io::{
+    llama_state_read_header, llama_state_write_header, LlamaIoRead, LlamaIoReadBuffer, LlamaIoWrite, LlamaIoWriteBuffer,
+    LlamaIoWriteDummy, LLAMA_STATE_MAGIC, LLAMA_STATE_SEQ_MAGIC,
+};
+use crate::llmrust::src::llama_memory::{LlamaMemory, LlamaMemoryHandle, LlamaSeqId};
+use crate::llmrust::src::llama_sampling::{LlamaSampler, SamplerChain};
+use crate::llmrust::src::llama_vocab::LlamaToken;
+
+pub struct LlamaContext {
+    /// `LlamaHparams::fingerprint` of the model; state of other models is rejected
+    fingerprint: u64,
+    cparams: LlamaCparams,
+    memory: Option<LlamaMemoryHandle>,
+    samplers: BTreeMap<LlamaSeqId, SamplerChain>,
+    /// Logits rows of the last decoded batch
+    logits: Vec<f32>,
+    n_vocab: usize,
+    /// Per-token embedding rows of the last decoded batch (no pooling)
+    embd: Vec<f32>,
+    n_embd: usize,
+    /// Pooled embedding of each sequence of the last decoded batch
+    embd_seq: BTreeMap<LlamaSeqId, Vec<f32>>,
+    /// Row in `logits` or `embd` of each token of the last decoded batch
+    output_ids: Vec<Option<usize>>,
+    loras: LlamaAdapterLoras,
+    cvec: Arc<LlamaAdapterCvec>,
+    /// Tokens whose cells were restored into sequence 0 from a session file
+    session_tokens: Vec<LlamaToken>,
+    /// Hyperparameters of the model the context was created from
+    model_hparams: Option<LlamaHparams>,
+}
+
+impl LlamaContext {
+    pub fn new(fingerprint: u64, n_ctx: u32, memory: Option<LlamaMemoryHandle>) -> Self {
+        let cparams = LlamaCparams { n_ctx, ..Default::default() };
+        Self {
+            fingerprint,
+            cparams,
+            memory,
+            samplers: BTreeMap::new(),
+            logits: Vec::new(),
+            n_vocab: 0,
+            embd: Vec::new(),
+            n_embd: 0,
+            embd_seq: BTreeMap::new(),
+            output_ids: Vec::new(),
+            loras: LlamaAdapterLoras::default(),
+            cvec: Arc::default(),
+            session_tokens: Vec::new(),
+            model_hparams: None,
+        }
+    }
+
+    pub fn with_cparams(fingerprint: u64, cparams: LlamaCparams, memory: Option<LlamaMemoryHandle>) -> Result<Self, String> {
+        let mut ctx = Self::new(fingerprint, cparams.n_ctx, memory);
+        ctx.set_ubatch(cparams.n_ubatch, cparams.split)?;
+        ctx.cparams = cparams;
+        Ok(ctx)
+    }
+
+    /// Attaches the hyperparameters of the model, whose defaults (causal
+    /// attention, pooling) apply where the context params leave them open.
+    pub fn with_model_hparams(mut self, hparams: LlamaHparams) -> Self {
+        self.model_hparams = Some(hparams);
+        self
+    }
+
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub fn model_hparams(&self) -> Option<&LlamaHparams> {
+        self.model_hparams.as_ref()
+    }
+
+    /// Pooling in effect: the context's, else the model's
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        self.cparams.pooling_for(self.model_hparams.as_ref().map_or(LlamaPoolingType::None, |hp| hp.pooling_type))
+    }
+
+    /// Whether attention is causal: the context's attention type, else the model's
+    pub fn causal_attn(&self) -> bool {
+        self.cparams.causal_attn_for(self.model_hparams.as_ref().is_none_or(|hp| hp.causal_attn))
+    }
+
+    pub fn cparams(&self) -> &LlamaCparams {
+        &self.cparams
+    }
+
+    pub fn n_ctx(&self) -> u32 {
+        self.cparams.n_ctx
+    }
+
+    pub fn memory(&self) -> Option<&dyn LlamaMemory> {
+        self.memory.as_deref()
+    }
+
+    pub fn memory_mut(&mut self) -> Option<&mut LlamaMemoryHandle> {
+        self.memory.as_mut()
+    }
+
+    pub fn session_tokens(&self) -> &[LlamaToken] {
+        &self.session_tokens
+    }
+
+    pub fn set_session_tokens(&mut self, tokens: Vec<LlamaToken>) {
+        self.session_tokens = tokens;
+    }
+
+    /// Sets the sampler chain used for `seq_id`.
+    pub fn set_sampler(&mut self, seq_id: LlamaSeqId, chain: SamplerChain) {
+        self.samplers.insert(seq_id, chain);
+    }
+
+    pub fn sampler(&self, seq_id: LlamaSeqId) -> Option<&SamplerChain> {
+        self.samplers.get(&seq_id)
+    }
+
+    pub fn sampler_mut(&mut self, seq_id: LlamaSeqId) -> Option<&mut SamplerChain> {
+        self.samplers.get_mut(&seq_id)
+    }
+
+    pub fn n_ubatch(&self) -> usize {
+        self.cparams.n_ubatch
+    }
+
+    pub fn split(&self) -> LlamaSplitStrategy {
+        self.cparams.split
+    }
+
+    /// Sets how batches are split into ubatches of at most `n_ubatch` tokens.
+    /// Recurrent graphs use the equal split instead of the simple one.
+    pub fn set_ubatch(&mut self, n_ubatch: usize, split: LlamaSplitStrategy) -> Result<(), String> {
+        if n_ubatch == 0 {
+            return Err("n_ubatch must be positive".to_string());
+        }
+        self.cparams.n_ubatch = n_ubatch;
+        self.cparams.split = split;
+        Ok(())
+    }
+
+    /// Switches between extracting embeddings and logits (`llama_set_embeddings`).
+    pub fn set_embeddings(&mut self, embeddings: bool) {
+        self.cparams.embeddings = embeddings;
+    }
+
+    /// Switches between causal and non-causal attention (`llama_set_causal_attn`).
+    pub fn set_causal_attn(&mut self, causal: bool) {
+        self.cparams.attention_type = if causal { LlamaAttentionType::Causal } else { LlamaAttentionType::NonCausal };
+    }
+
+    /// Enables `adapter` with `scale`, or rescales it (`llama_set_adapter_lora`).
+    pub fn set_adapter_lora(&mut self, adapter: Arc<LlamaAdapterLora>, scale: f32) {
+        self.loras.set(adapter, scale);
+    }
+
+    /// Disables `adapter`; false if it was not enabled (`llama_rm_adapter_lora`).
+    pub fn rm_adapter_lora(&mut self, adapter: &Arc<LlamaAdapterLora>) -> bool {
+        self.loras.remove(adapter)
+    }
+
+    pub fn clear_adapter_lora(&mut self) {
+        self.loras.clear();
+    }
+
+    pub fn adapter_loras(&self) -> &LlamaAdapterLoras {
+        &self.loras
+    }
+
+    /// Replaces the control vector (`llama_apply_adapter_cvec`); an empty
+    /// vector clears it.
+    pub fn set_adapter_cvec(&mut self, cvec: LlamaAdapterCvec) {
+        self.cvec = Arc::new(cvec);
+    }
+
+    pub fn adapter_cvec(&self) -> &LlamaAdapterCvec {
+        &self.cvec
+    }
+
+    /// Evaluates `batch` through `graph`, one ubatch at a time. If a ubatch
+    /// fails, the memory keeps the ubatches evaluated before it and no outputs
+    /// are available.
+    pub fn decode(&mut self, batch: &LlamaBatch, graph: &mut dyn LlamaGraph) -> Result<(), String> {
+        self.logits.clear();
+        self.embd.clear();
+        self.embd_seq.clear();
+        self.output_ids.clear();
+        batch.validate()?;
+        if batch.is_empty() {
+            return Err("the batch is empty".to_string());
+        }
+        let split = match self.cparams.split {
+            LlamaSplitStrategy::Simple if graph.is_recurrent() => LlamaSplitStrategy::Equal,
+            split => split,
+        };
+        // a token must see its whole sequence, so the batch cannot be split
+        let causal = self.causal_attn();
+        if !causal && batch.n_tokens() > self.cparams.n_ubatch {
+            return Err(format!(
+                "non-causal attention needs the whole batch in one ubatch ({} tokens, n_ubatch = {})",
+                batch.n_tokens(),
+                self.cparams.n_ubatch
+            ));
+        }
+        let ubatches = batch.split(split, self.cparams.n_ubatch);
+        graph.set_adapter_loras(&self.loras);
+        graph.set_adapter_cvec(&self.cvec);
+        graph.set_causal_attn(causal);
+        let output_ids = output_ids(batch);
+
+        if !self.cparams.embeddings {
+            let n_vocab = graph.n_vocab();
+            let mut logits = Vec::new();
+            for ubatch in &ubatches {
+                let rows = graph.compute(batch, ubatch)?;
+                let outputs: Vec<usize> = ubatch.idxs.iter().filter_map(|&i| output_ids[i]).collect();
+                scatter_rows(&mut logits, n_vocab, &rows, &outputs, "logits")?;
+            }
+            self.logits = logits;
+            self.n_vocab = n_vocab;
+            self.output_ids = output_ids;
+            return Ok(());
+        }
+
+        let n_embd = graph.n_embd();
+        let pooling = self.cparams.pooling_for(graph.pooling_type());
+        if pooling == LlamaPoolingType::None {
+            let mut embd = Vec::new();
+            for ubatch in &ubatches {
+                let rows = graph.embed(batch, ubatch)?;
+                check_rows(&rows, ubatch.n_tokens(), n_embd)?;
+                let (outputs, rows): (Vec<usize>, Vec<Vec<f32>>) =
+                    ubatch.idxs.iter().zip(rows).filter_map(|(&i, row)| Some((output_ids[i]?, row))).unzip();
+                scatter_rows(&mut embd, n_embd, &rows, &outputs, "embedding")?;
+            }
+            self.embd = embd;
+            self.n_embd = n_embd;
+            self.output_ids = output_ids;
+            return Ok(());
+        }
+
+        // pooling needs the hidden state of every token
+        let mut hidden = vec![Vec::new(); batch.n_tokens()];
+        for ubatch in &ubatches {
+            let rows = graph.embed(batch, ubatch)?;
+            check_rows(&rows, ubatch.n_tokens(), n_embd)?;
+            for (&i, row) in ubatch.idxs.iter().zip(rows) {
+                hidden[i] = row;
+            }
+        }
+        let mut seq_tokens: BTreeMap<LlamaSeqId, Vec<usize>> = BTreeMap::new();
+        for (i, seq_ids) in batch.seq_id.iter().enumerate() {
+            for &seq_id in seq_ids {
+                seq_tokens.entry(seq_id).or_default().push(i);
+            }
+        }
+        let mut embd_seq = BTreeMap::new();
+        for (seq_id, idxs) in seq_tokens {
+            let cls = || {
+                idxs.iter()
+                    .find(|&&i| batch.pos[i] == 0)
+                    .map(|&i| hidden[i].clone())
+                    .ok_or_else(|| format!("sequence {} has no token at position 0 for {} pooling", seq_id, pooling.name()))
+            };
+            let pooled = match pooling {
+                LlamaPoolingType::Mean => {
+                    let mut sum = vec![0.0f32; n_embd];
+                    for &i in &idxs {
+                        sum.iter_mut().zip(&hidden[i]).for_each(|(s, h)| *s += h);
+                    }
+                    sum.iter().map(|s| s / idxs.len() as f32).collect()
+                }
+                LlamaPoolingType::Cls => cls()?,
+                LlamaPoolingType::Last => idxs.iter().max_by_key(|&&i| batch.pos[i]).map(|&i| hidden[i].clone()).unwrap_or_default(),
+                LlamaPoolingType::Rank => graph.classify(&cls()?)?,
+                LlamaPoolingType::None | LlamaPoolingType::Unspecified => unreachable!(),
+            };
+            embd_seq.insert(seq_id, pooled);
+        }
+        self.embd_seq = embd_seq;
+        self.n_embd = n_embd;
+        Ok(())
+    }
+
+    /// Logits of token `i` of the last decoded batch; negative `i` counts
+    /// from the last output (`llama_get_logits_ith`).
+    pub fn get_logits_ith(&self, i: i32) -> Option<&[f32]> {
+        output_row(&self.logits, self.n_vocab, &self.output_ids, i)
+    }
+
+    /// Embedding of token `i` of the last batch decoded without pooling;
+    /// negative `i` counts from the last output (`llama_get_embeddings_ith`).
+    pub fn get_embeddings_ith(&self, i: i32) -> Option<&[f32]> {
+        output_row(&self.embd, self.n_embd, &self.output_ids, i)
+    }
+
+    /// Pooled embedding of `seq_id` from the last decoded batch; for rank
+    /// pooling, the classification scores (`llama_get_embeddings_seq`).
+    pub fn get_embeddings_seq(&self, seq_id: LlamaSeqId) -> Option<&[f32]> {
+        self.embd_seq.get(&seq_id).map(|e| e.as_slice())
+    }
+
+    /// Writes the whole state: the sampler state of every sequence, then all
+    /// of the memory.
+    pub fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        llama_state_write_header(io, LLAMA_STATE_MAGIC, self.fingerprint)?;
+        io.write_u32(self.samplers.len() as u32)?;
+        for (&seq_id, chain) in &self.samplers {
+            io.write_i32(seq_id)?;
+            chain.state_write(io)?;
+        }
+        self.memory_write(io, -1)
+    }
+
+    /// Restores state written by `state_write`. Every sequence with sampler
+    /// state must already have a sampler chain of the same configuration.
+    /// Samplers are only updated once the memory has been restored; if that
+    /// fails the memory is left empty.
+    pub fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        llama_state_read_header(io, LLAMA_STATE_MAGIC, self.fingerprint)?;
+        let n_samplers = io.read_u32()?;
+        let mut samplers = Vec::new();
+        for _ in 0..n_samplers {
+            let seq_id = io.read_i32()?;
+            samplers.push((seq_id, self.sampler_read(io, seq_id)?));
+        }
+        self.memory_read(io, -1)?;
+        self.samplers.extend(samplers);
+        Ok(())
+    }
+
+    /// Writes the state of one sequence: its sampler state, if it has a
+    /// sampler chain, and its cells.
+    pub fn state_seq_write(&self, io: &mut dyn LlamaIoWrite, seq_id: LlamaSeqId) -> Result<(), String> {
+        llama_state_write_header(io, LLAMA_STATE_SEQ_MAGIC, self.fingerprint)?;
+        let chain = self.samplers.get(&seq_id);
+        io.write_u32(chain.is_some() as u32)?;
+        if let Some(chain) = chain {
+            chain.state_write(io)?;
+        }
+        self.memory_write(io, seq_id)
+    }
+
+    /// Restores state written by `state_seq_write`, possibly for another
+    /// sequence, into `seq_id`.
+    pub fn state_seq_read(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String> {
+        if seq_id < 0 {
+            return Err(format!("invalid seq_id {}", seq_id));
+        }
+        llama_state_read_header(io, LLAMA_STATE_SEQ_MAGIC, self.fingerprint)?;
+        let chain = if io.read_u32()? != 0 { Some(self.sampler_read(io, seq_id)?) } else { None };
+        self.memory_read(io, seq_id)?;
+        if let Some(chain) = chain {
+            self.samplers.insert(seq_id, chain);
+        }
+        Ok(())
+    }
+
+    /// Reads sampler state into a copy of the chain of `seq_id`.
+    fn sampler_read(&self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<SamplerChain, String> {
+        let mut chain = self.samplers.get(&seq_id).cloned().ok_or_else(|| format!("no sampler chain for seq_id {}", seq_id))?;
+        chain.state_read(io)?;
+        Ok(chain)
+    }
+
+    fn memory_write(&self, io: &mut dyn LlamaIoWrite, seq_id: LlamaSeqId) -> Result<(), String> {
+        io.write_u32(self.memory.is_some() as u32)?;
+        match self.memory.as_ref() {
+            Some(memory) => memory.state_write(io, seq_id),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_read(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String> {
+        let has_memory = io.read_u32()? != 0;
+        match self.memory.as_mut() {
+            Some(memory) if has_memory => memory.state_read(io, seq_id),
+            None if !has_memory => Ok(()),
+            Some(_) => Err("state has no memory but the context does".to_string()),
+            None => Err("state has memory but the context does not".to_string()),
+        }
+    }
+
+    /// Size in bytes of the whole state
+    pub fn state_get_size(&self) -> Result<usize, String> {
+        let mut io = LlamaIoWriteDummy::new();
+        self.state_write(&mut io)?;
+        Ok(io.n_bytes())
+    }
+
+    /// Copies the whole state into `dst`; returns the number of bytes written.
+    pub fn state_get_data(&self, dst: &mut [u8]) -> Result<usize, String> {
+        let mut io = LlamaIoWriteBuffer::new(dst);
+        self.state_write(&mut io)?;
+        Ok(io.n_bytes())
+    }
+
+    /// Restores the whole state from `src`; returns the number of bytes read.
+    pub fn state_set_data(&mut self, src: &[u8]) -> Result<usize, String> {
+        let mut io = LlamaIoReadBuffer::new(src);
+        self.state_read(&mut io)?;
+        Ok(io.n_bytes())
+    }
+
+    pub fn state_seq_get_size(&self, seq_id: LlamaSeqId) -> Result<usize, String> {
+        let mut io = LlamaIoWriteDummy::new();
+        self.state_seq_write(&mut io, seq_id)?;
+        Ok(io.n_bytes())
+    }
+
+    pub fn state_seq_get_data(&self, dst: &mut [u8], seq_id: LlamaSeqId) -> Result<usize, String> {
+        let mut io = LlamaIoWriteBuffer::new(dst);
+        self.state_seq_write(&mut io, seq_id)?;
+        Ok(io.n_bytes())
+    }
+
+    pub fn state_seq_set_data(&mut self, src: &[u8], seq_id: LlamaSeqId) -> Result<usize, String> {
+        let mut io = LlamaIoReadBuffer::new(src);
+        self.state_seq_read(&mut io, seq_id)?;
+        Ok(io.n_bytes())
+    }
+}
+
+/// Output row of each token of `batch`, in batch order
+fn output_ids(batch: &LlamaBatch) -> Vec<Option<usize>> {
+    let mut n_outputs = 0;
+    batch
+        .logits
+        .iter()
+        .map(|&l| {
+            l.then(|| {
+                n_outputs += 1;
+                n_outputs - 1
+            })
+        })
+        .collect()
+}
+
+fn check_rows(rows: &[Vec<f32>], n_rows: usize, width: usize) -> Result<(), String> {
+    if rows.len() != n_rows {
+        return Err(format!("graph returned {} embedding rows for {} tokens", rows.len(), n_rows));
+    }
+    match rows.iter().find(|r| r.len() != width) {
+        Some(row) => Err(format!("graph returned an embedding row of {} values, expected {}", row.len(), width)),
+        None => Ok(()),
+    }
+}
+
+/// Copies `rows` to the output rows `outputs` of `dst`, growing it as needed.
+fn scatter_rows(dst: &mut Vec<f32>, width: usize, rows: &[Vec<f32>], outputs: &[usize], what: &str) -> Result<(), String> {
+    if rows.len() != outputs.len() {
+        return Err(format!("graph returned {} {} rows for {} outputs", rows.len(), what, outputs.len()));
+    }
+    for (row, &out) in rows.iter().zip(outputs) {
+        if row.len() != width {
+            return Err(format!("graph returned a {} row of {} values, expected {}", what, row.len(), width));
+        }
+        if dst.len() < (out + 1) * width {
+            dst.resize((out + 1) * width, 0.0);
+        }
+        dst[out * width..(out + 1) * width].copy_from_slice(row);
+    }
+    Ok(())
+}
+
+fn output_row<'a>(rows: &'a [f32], width: usize, output_ids: &[Option<usize>], i: i32) -> Option<&'a [f32]> {
+    if rows.is_empty() {
+        return None;
+    }
+    let n_outputs = output_ids.iter().flatten().count();
+    let row = if i < 0 {
+        n_outputs.checked_sub(i.unsigned_abs() as usize)?
+    } else {
+        (*output_ids.get(i as usize)?)?
+    };
+    rows.get(row * width..(row + 1) * width)
+}
+
+/// Creates a C handle owning `ctx`; release it with `free_handle`.
+pub fn into_handle(ctx: LlamaContext) -> *mut c_void {
+    Box::into_raw(Box::new(ctx)) as *mut c_void
+}
+
+/// Borrows the context behind a C handle.
+///
+/// # Safety
+/// `ctx` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn from_handle<'a>(ctx: *mut c_void) -> Option<&'a mut LlamaContext> {
+    (ctx as *mut LlamaContext).as_mut()
+}
+
+/// Releases a handle created by `into_handle`, including its memory.
+///
+/// # Safety
+/// `ctx` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn free_handle(ctx: *mut c_void) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx as *mut LlamaContext));
+    }
 }
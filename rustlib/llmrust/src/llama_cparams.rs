@@ -1,6 +1,203 @@
-// src/llama_cparams.rs - Auto-generated debug file
+// src/llama_cparams.rs - Context parameters
+//
+// Settings of one inference context, chosen when the context is created and
+// independent of the model weights: the context size, how batches are split
+// into ubatches and whether the context produces embeddings instead of
+// logits. Where a setting has a model default (the pooling type, the
+// attention type, the RoPE frequencies and scaling), the context value
+// overrides it unless it is left unspecified. The flash attention setting
+// picks the fused attention kernel over the naive one.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_cparams.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml_cpu::ops::RopeParams;
+use crate::llmrust::src::llama_batch::LlamaSplitStrategy;
+use crate::llmrust::src::llama_hparams::{LlamaAttentionType, LlamaHparams, LlamaPoolingType, LlamaRopeScalingType};
+
+/// Attention kernel selection (`llama_flash_attn_type`; the discriminants
+/// are the C API values)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LlamaFlashAttnType {
+    /// Fused kernel wherever it is supported
+    #[default]
+    Auto = -1,
+    /// Naive kernel: full scores per query head, then softmax
+    Disabled = 0,
+    /// Fused tiled kernel with online softmax
+    Enabled = 1,
+}
+
+impl LlamaFlashAttnType {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            -1 => Some(Self::Auto),
+            0 => Some(Self::Disabled),
+            1 => Some(Self::Enabled),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "off" => Some(Self::Disabled),
+            "on" => Some(Self::Enabled),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Disabled => "off",
+            Self::Enabled => "on",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LlamaCparams {
+    pub n_ctx: u32,
+    /// Maximum tokens per ubatch
+    pub n_ubatch: usize,
+    pub split: LlamaSplitStrategy,
+    /// Extract embeddings instead of logits
+    pub embeddings: bool,
+    pub pooling_type: LlamaPoolingType,
+    /// Causal or non-causal attention; can change between decodes
+    pub attention_type: LlamaAttentionType,
+
+    pub rope_scaling_type: LlamaRopeScalingType,
+    /// RoPE base frequency, 0 for the model's
+    pub rope_freq_base: f32,
+    /// RoPE frequency scale, 0 for the model's
+    pub rope_freq_scale: f32,
+    /// YaRN extrapolation mix, negative for 1 with YaRN scaling and 0 without
+    pub yarn_ext_factor: f32,
+    /// Magnitude scale of rotated values, negative for the model's
+    pub yarn_attn_factor: f32,
+    pub yarn_beta_fast: f32,
+    pub yarn_beta_slow: f32,
+    /// Original context length for YaRN and LongRoPE, 0 for the model's
+    pub yarn_orig_ctx: u32,
+
+    pub flash_attn_type: LlamaFlashAttnType,
+}
+
+impl Default for LlamaCparams {
+    fn default() -> Self {
+        Self {
+            n_ctx: 4096,
+            n_ubatch: 512,
+            split: LlamaSplitStrategy::Simple,
+            embeddings: false,
+            pooling_type: LlamaPoolingType::Unspecified,
+            attention_type: LlamaAttentionType::Unspecified,
+            rope_scaling_type: LlamaRopeScalingType::Unspecified,
+            rope_freq_base: 0.0,
+            rope_freq_scale: 0.0,
+            yarn_ext_factor: -1.0,
+            yarn_attn_factor: -1.0,
+            yarn_beta_fast: 32.0,
+            yarn_beta_slow: 1.0,
+            yarn_orig_ctx: 0,
+            flash_attn_type: LlamaFlashAttnType::Auto,
+        }
+    }
+}
+
+impl LlamaCparams {
+    /// Pooling in effect for a model whose default is `model_pooling`
+    pub fn pooling_for(&self, model_pooling: LlamaPoolingType) -> LlamaPoolingType {
+        match self.pooling_type {
+            LlamaPoolingType::Unspecified => match model_pooling {
+                LlamaPoolingType::Unspecified => LlamaPoolingType::None,
+                pooling => pooling,
+            },
+            pooling => pooling,
+        }
+    }
+
+    /// Whether attention is causal for a model whose default is `model_causal`
+    pub fn causal_attn_for(&self, model_causal: bool) -> bool {
+        match self.attention_type {
+            LlamaAttentionType::Unspecified => model_causal,
+            attention => attention == LlamaAttentionType::Causal,
+        }
+    }
+
+    /// Whether attention uses the fused kernel. The CPU kernel handles every
+    /// KV cache type and head size, so `auto` always resolves to it.
+    pub fn flash_attn(&self) -> bool {
+        self.flash_attn_type != LlamaFlashAttnType::Disabled
+    }
+
+    /// RoPE scaling in effect for a model trained with `model_scaling`
+    pub fn rope_scaling_for(&self, model_scaling: LlamaRopeScalingType) -> LlamaRopeScalingType {
+        match self.rope_scaling_type {
+            LlamaRopeScalingType::Unspecified => model_scaling,
+            scaling_type => scaling_type,
+        }
+    }
+
+    /// RoPE scaling in effect for a model, `None` if it has no rotary
+    /// embeddings. Scaling `none` ignores the frequency scale; NTK turns it
+    /// into a larger frequency base; dynamic NTK does so by how far `n_ctx`
+    /// exceeds the original context; LongRoPE uses the long factors once the
+    /// context exceeds the original length.
+    pub fn rope_params(&self, hparams: &LlamaHparams) -> Result<Option<RopeParams>, String> {
+        let Some(mut rope) = hparams.rope_params() else {
+            return Ok(None);
+        };
+        if self.rope_freq_base != 0.0 {
+            rope.freq_base = self.rope_freq_base;
+        }
+        if self.rope_freq_scale != 0.0 {
+            rope.freq_scale = self.rope_freq_scale;
+        }
+        if rope.freq_base <= 0.0 || rope.freq_scale <= 0.0 {
+            return Err(format!("invalid rope frequencies: base {}, scale {}", rope.freq_base, rope.freq_scale));
+        }
+        let scaling_type = self.rope_scaling_for(hparams.rope_scaling_type_train);
+        rope.n_ctx_orig = if self.yarn_orig_ctx != 0 { self.yarn_orig_ctx } else { hparams.n_ctx_orig() };
+        rope.beta_fast = self.yarn_beta_fast;
+        rope.beta_slow = self.yarn_beta_slow;
+        rope.attn_factor = if self.yarn_attn_factor >= 0.0 { self.yarn_attn_factor } else { hparams.rope_attn_factor };
+        rope.ext_factor = match scaling_type {
+            _ if self.yarn_ext_factor >= 0.0 => self.yarn_ext_factor,
+            LlamaRopeScalingType::Yarn => 1.0,
+            _ => 0.0,
+        };
+        match scaling_type {
+            LlamaRopeScalingType::None => rope.freq_scale = 1.0,
+            LlamaRopeScalingType::Ntk => {
+                if rope.n_dims <= 2 {
+                    return Err(format!("ntk scaling needs more than 2 rotated dimensions, got {}", rope.n_dims));
+                }
+                // base^(-(d-2)/d) is the lowest frequency: dividing it by the
+                // scale s takes a base of base * s^(d/(d-2))
+                let n_dims = rope.n_dims as f32;
+                rope.freq_base *= (1.0 / rope.freq_scale).powf(n_dims / (n_dims - 2.0));
+                rope.freq_scale = 1.0;
+            }
+            LlamaRopeScalingType::Dynamic => {
+                if rope.n_dims <= 2 {
+                    return Err(format!("dynamic scaling needs more than 2 rotated dimensions, got {}", rope.n_dims));
+                }
+                // the base is fixed by the context size rather than the
+                // sequence length, so cached keys never go stale
+                if self.n_ctx > rope.n_ctx_orig {
+                    let (n_dims, factor) = (rope.n_dims as f32, 1.0 / rope.freq_scale);
+                    let ratio = factor * self.n_ctx as f32 / rope.n_ctx_orig as f32 - (factor - 1.0);
+                    rope.freq_base *= ratio.powf(n_dims / (n_dims - 2.0));
+                }
+                rope.freq_scale = 1.0;
+            }
+            LlamaRopeScalingType::LongRope => {
+                let factors = if self.n_ctx > rope.n_ctx_orig { &hparams.rope_factors_long } else { &hparams.rope_factors_short };
+                rope.freq_factors = Some(factors.clone().ok_or("longrope scaling needs the model's rope factors")?);
+            }
+            _ => {}
+        }
+        Ok(Some(rope))
+    }
 }
@@ -1,6 +1,1146 @@
-// src/llama_grammar.rs - Auto-generated debug file
+// src/llama_grammar.rs - GBNF grammar parser and incremental matcher
+//
+// Parses GBNF (GGML BNF) grammar text into flat rule tables and walks them with
+// a set of pushdown stacks, one per live parse. Each accepted code point
+// advances every stack; a grammar is accepting when at least one stack has been
+// fully consumed. This mirrors llama.cpp's llama-grammar so grammars written
+// for it work unchanged.
+
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_grammar.rs - File loaded successfully");
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::llama_io::{LlamaIoRead, LlamaIoWrite};
+use super::llama_regex::RegexSearch;
+use super::llama_sampling::LlamaTokenDataArray;
+use super::llama_vocab::{LlamaToken, LlamaVocab};
+
+/// Grammar element types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GretType {
+    /// End of rule definition
+    End,
+    /// Start of alternate definition for rule
+    Alt,
+    /// Non-terminal element: reference to rule
+    RuleRef,
+    /// Terminal element: character (code point)
+    Char,
+    /// Inverse char(s) ([^a], [^a-b], [^abc])
+    CharNot,
+    /// Modifies a preceding Char or CharAlt to be an inclusive range ([a-z])
+    CharRngUpper,
+    /// Modifies a preceding Char or CharRngUpper to add an alternate char to match ([ab], [a-zA])
+    CharAlt,
+    /// Any character (.)
+    CharAny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrammarElement {
+    pub ty: GretType,
+    /// Unicode code point or rule ID
+    pub value: u32,
+}
+
+impl GrammarElement {
+    pub const fn new(ty: GretType, value: u32) -> Self {
+        Self { ty, value }
+    }
+}
+
+pub type GrammarRule = Vec<GrammarElement>;
+
+/// Position of an element inside the rule table: (rule index, element index)
+pub type ElementPos = (usize, usize);
+
+/// A parse stack; the top (last) element is the next element to match
+pub type GrammarStack = Vec<ElementPos>;
+
+fn is_end_of_sequence(el: &GrammarElement) -> bool {
+    matches!(el.ty, GretType::End | GretType::Alt)
+}
+
+// =============================================================================
+// Parser
+// =============================================================================
+
+/// Parses GBNF text into a rule table.
+#[derive(Debug, Default, Clone)]
+pub struct GrammarParser {
+    pub symbol_ids: HashMap<String, u32>,
+    pub rules: Vec<GrammarRule>,
+}
+
+fn is_digit_char(c: u8) -> bool {
+    c.is_ascii_digit()
+}
+
+fn is_word_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
+
+/// Decodes one UTF-8 sequence starting at `pos`; returns (code point, next position).
+fn decode_utf8(src: &[u8], pos: usize) -> (u32, usize) {
+    const LOOKUP: [usize; 16] = [1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 2, 2, 3, 4];
+    let first = src[pos];
+    let highbits = first >> 4;
+    let len = LOOKUP[highbits as usize];
+    let mask = ((1u32 << (8 - len)) - 1) as u8;
+    let mut value = (first & mask) as u32;
+    let mut p = pos + 1;
+    let end = (pos + len).min(src.len());
+    while p < end && src[p] != 0 {
+        value = (value << 6) + (src[p] & 0x3F) as u32;
+        p += 1;
+    }
+    (value, p.max(pos + 1))
+}
+
+fn parse_hex(src: &[u8], pos: usize, size: usize) -> Result<(u32, usize), String> {
+    let end = pos + size;
+    let mut value = 0u32;
+    let mut p = pos;
+    while p < end && p < src.len() {
+        let c = src[p];
+        let digit = match c {
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            b'0'..=b'9' => c - b'0',
+            _ => break,
+        };
+        value = (value << 4) + digit as u32;
+        p += 1;
+    }
+    if p != end {
+        return Err(format!(
+            "expecting {} hex chars at {}",
+            size,
+            String::from_utf8_lossy(&src[pos..])
+        ));
+    }
+    Ok((value, p))
+}
+
+fn parse_space(src: &[u8], mut pos: usize, newline_ok: bool) -> usize {
+    while pos < src.len() {
+        let c = src[pos];
+        if c == b' ' || c == b'\t' || c == b'#' || (newline_ok && (c == b'\r' || c == b'\n')) {
+            if c == b'#' {
+                while pos < src.len() && src[pos] != b'\r' && src[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                pos += 1;
+            }
+        } else {
+            break;
+        }
+    }
+    pos
+}
+
+fn parse_name(src: &[u8], pos: usize) -> Result<usize, String> {
+    let mut p = pos;
+    while p < src.len() && is_word_char(src[p]) {
+        p += 1;
+    }
+    if p == pos {
+        return Err(format!("expecting name at {}", String::from_utf8_lossy(&src[pos..])));
+    }
+    Ok(p)
+}
+
+fn parse_int(src: &[u8], pos: usize) -> Result<(u32, usize), String> {
+    let mut p = pos;
+    while p < src.len() && is_digit_char(src[p]) {
+        p += 1;
+    }
+    if p == pos {
+        return Err(format!("expecting integer at {}", String::from_utf8_lossy(&src[pos..])));
+    }
+    let value = std::str::from_utf8(&src[pos..p])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "integer out of range".to_string())?;
+    Ok((value, p))
+}
+
+fn parse_char(src: &[u8], pos: usize) -> Result<(u32, usize), String> {
+    if src[pos] == b'\\' {
+        if pos + 1 >= src.len() {
+            return Err("unexpected end of input".to_string());
+        }
+        match src[pos + 1] {
+            b'x' => parse_hex(src, pos + 2, 2),
+            b'u' => parse_hex(src, pos + 2, 4),
+            b'U' => parse_hex(src, pos + 2, 8),
+            b't' => Ok(('\t' as u32, pos + 2)),
+            b'r' => Ok(('\r' as u32, pos + 2)),
+            b'n' => Ok(('\n' as u32, pos + 2)),
+            c @ (b'\\' | b'"' | b'[' | b']') => Ok((c as u32, pos + 2)),
+            _ => Err(format!("unknown escape at {}", String::from_utf8_lossy(&src[pos..]))),
+        }
+    } else {
+        Ok(decode_utf8(src, pos))
+    }
+}
+
+impl GrammarParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get_symbol_id(&mut self, name: &str) -> u32 {
+        let next_id = self.symbol_ids.len() as u32;
+        *self.symbol_ids.entry(name.to_string()).or_insert(next_id)
+    }
+
+    fn generate_symbol_id(&mut self, base_name: &str) -> u32 {
+        let next_id = self.symbol_ids.len() as u32;
+        self.symbol_ids.insert(format!("{}_{}", base_name, next_id), next_id);
+        next_id
+    }
+
+    fn add_rule(&mut self, rule_id: u32, rule: GrammarRule) {
+        let idx = rule_id as usize;
+        if self.rules.len() <= idx {
+            self.rules.resize(idx + 1, Vec::new());
+        }
+        self.rules[idx] = rule;
+    }
+
+    fn parse_sequence(
+        &mut self,
+        src: &[u8],
+        mut pos: usize,
+        rule_name: &str,
+        out: &mut GrammarRule,
+        is_nested: bool,
+    ) -> Result<usize, String> {
+        let mut last_sym_start = out.len();
+
+        while pos < src.len() {
+            let c = src[pos];
+            if c == b'"' {
+                // literal string
+                pos += 1;
+                last_sym_start = out.len();
+                while pos < src.len() && src[pos] != b'"' {
+                    let (chr, next) = parse_char(src, pos)?;
+                    pos = next;
+                    out.push(GrammarElement::new(GretType::Char, chr));
+                }
+                if pos >= src.len() {
+                    return Err("unexpected end of input".to_string());
+                }
+                pos = parse_space(src, pos + 1, is_nested);
+            } else if c == b'[' {
+                // char range(s)
+                pos += 1;
+                let mut start_type = GretType::Char;
+                if pos < src.len() && src[pos] == b'^' {
+                    pos += 1;
+                    start_type = GretType::CharNot;
+                }
+                last_sym_start = out.len();
+                while pos < src.len() && src[pos] != b']' {
+                    let (chr, next) = parse_char(src, pos)?;
+                    pos = next;
+                    let ty = if last_sym_start < out.len() { GretType::CharAlt } else { start_type };
+                    out.push(GrammarElement::new(ty, chr));
+                    if pos + 1 < src.len() && src[pos] == b'-' && src[pos + 1] != b']' {
+                        let (end_chr, next) = parse_char(src, pos + 1)?;
+                        pos = next;
+                        out.push(GrammarElement::new(GretType::CharRngUpper, end_chr));
+                    }
+                }
+                if pos >= src.len() {
+                    return Err("unexpected end of input".to_string());
+                }
+                pos = parse_space(src, pos + 1, is_nested);
+            } else if is_word_char(c) {
+                // rule reference
+                let name_end = parse_name(src, pos)?;
+                let name = String::from_utf8_lossy(&src[pos..name_end]).into_owned();
+                let ref_rule_id = self.get_symbol_id(&name);
+                pos = parse_space(src, name_end, is_nested);
+                last_sym_start = out.len();
+                out.push(GrammarElement::new(GretType::RuleRef, ref_rule_id));
+            } else if c == b'(' {
+                // grouping: parse nested alternates into synthesized rule
+                pos = parse_space(src, pos + 1, true);
+                let sub_rule_id = self.generate_symbol_id(rule_name);
+                pos = self.parse_alternates(src, pos, rule_name, sub_rule_id, true)?;
+                last_sym_start = out.len();
+                out.push(GrammarElement::new(GretType::RuleRef, sub_rule_id));
+                if pos >= src.len() || src[pos] != b')' {
+                    return Err(format!(
+                        "expecting ')' at {}",
+                        String::from_utf8_lossy(&src[pos.min(src.len())..])
+                    ));
+                }
+                pos = parse_space(src, pos + 1, is_nested);
+            } else if c == b'.' {
+                // any char
+                last_sym_start = out.len();
+                out.push(GrammarElement::new(GretType::CharAny, 0));
+                pos = parse_space(src, pos + 1, is_nested);
+            } else if c == b'*' {
+                pos = parse_space(src, pos + 1, is_nested);
+                self.handle_repetitions(out, last_sym_start, rule_name, 0, -1)?;
+            } else if c == b'+' {
+                pos = parse_space(src, pos + 1, is_nested);
+                self.handle_repetitions(out, last_sym_start, rule_name, 1, -1)?;
+            } else if c == b'?' {
+                pos = parse_space(src, pos + 1, is_nested);
+                self.handle_repetitions(out, last_sym_start, rule_name, 0, 1)?;
+            } else if c == b'{' {
+                pos = parse_space(src, pos + 1, is_nested);
+                if pos >= src.len() || !is_digit_char(src[pos]) {
+                    return Err(format!(
+                        "expecting an int at {}",
+                        String::from_utf8_lossy(&src[pos.min(src.len())..])
+                    ));
+                }
+                let (min_times, next) = parse_int(src, pos)?;
+                pos = parse_space(src, next, is_nested);
+
+                let max_times: i64;
+                if pos < src.len() && src[pos] == b'}' {
+                    max_times = min_times as i64;
+                    pos = parse_space(src, pos + 1, is_nested);
+                } else if pos < src.len() && src[pos] == b',' {
+                    pos = parse_space(src, pos + 1, is_nested);
+                    if pos < src.len() && is_digit_char(src[pos]) {
+                        let (max, next) = parse_int(src, pos)?;
+                        max_times = max as i64;
+                        pos = parse_space(src, next, is_nested);
+                    } else {
+                        max_times = -1;
+                    }
+                    if pos >= src.len() || src[pos] != b'}' {
+                        return Err(format!(
+                            "expecting '}}' at {}",
+                            String::from_utf8_lossy(&src[pos.min(src.len())..])
+                        ));
+                    }
+                    pos = parse_space(src, pos + 1, is_nested);
+                } else {
+                    return Err(format!(
+                        "expecting ',' at {}",
+                        String::from_utf8_lossy(&src[pos.min(src.len())..])
+                    ));
+                }
+                self.handle_repetitions(out, last_sym_start, rule_name, min_times as i64, max_times)?;
+            } else {
+                break;
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Rewrites the last symbol of `out` as `min_times` mandatory copies followed by
+    /// a chain of optional rules (or a single recursive rule when unbounded).
+    fn handle_repetitions(
+        &mut self,
+        out: &mut GrammarRule,
+        last_sym_start: usize,
+        rule_name: &str,
+        min_times: i64,
+        max_times: i64,
+    ) -> Result<(), String> {
+        if last_sym_start == out.len() {
+            return Err("expecting preceding item to */+/?/{".to_string());
+        }
+        let prev_rule: GrammarRule = out[last_sym_start..].to_vec();
+        if min_times == 0 {
+            out.truncate(last_sym_start);
+        } else {
+            for _ in 1..min_times {
+                out.extend_from_slice(&prev_rule);
+            }
+        }
+
+        let mut last_rec_rule_id = 0u32;
+        let n_opt = if max_times < 0 { 1 } else { max_times - min_times };
+
+        let mut rec_rule = prev_rule.clone();
+        for i in 0..n_opt {
+            rec_rule.truncate(prev_rule.len());
+            let rec_rule_id = self.generate_symbol_id(rule_name);
+            if i > 0 || max_times < 0 {
+                let target = if max_times < 0 { rec_rule_id } else { last_rec_rule_id };
+                rec_rule.push(GrammarElement::new(GretType::RuleRef, target));
+            }
+            rec_rule.push(GrammarElement::new(GretType::Alt, 0));
+            rec_rule.push(GrammarElement::new(GretType::End, 0));
+            self.add_rule(rec_rule_id, rec_rule.clone());
+            last_rec_rule_id = rec_rule_id;
+        }
+        if n_opt > 0 {
+            out.push(GrammarElement::new(GretType::RuleRef, last_rec_rule_id));
+        }
+        Ok(())
+    }
+
+    fn parse_alternates(
+        &mut self,
+        src: &[u8],
+        pos: usize,
+        rule_name: &str,
+        rule_id: u32,
+        is_nested: bool,
+    ) -> Result<usize, String> {
+        let mut rule = GrammarRule::new();
+        let mut pos = self.parse_sequence(src, pos, rule_name, &mut rule, is_nested)?;
+        while pos < src.len() && src[pos] == b'|' {
+            rule.push(GrammarElement::new(GretType::Alt, 0));
+            pos = parse_space(src, pos + 1, true);
+            pos = self.parse_sequence(src, pos, rule_name, &mut rule, is_nested)?;
+        }
+        rule.push(GrammarElement::new(GretType::End, 0));
+        self.add_rule(rule_id, rule);
+        Ok(pos)
+    }
+
+    fn parse_rule(&mut self, src: &[u8], pos: usize) -> Result<usize, String> {
+        let name_end = parse_name(src, pos)?;
+        let mut p = parse_space(src, name_end, false);
+        let name = String::from_utf8_lossy(&src[pos..name_end]).into_owned();
+        let rule_id = self.get_symbol_id(&name);
+
+        if !(src[p..].starts_with(b"::=")) {
+            return Err(format!("expecting ::= at {}", String::from_utf8_lossy(&src[p..])));
+        }
+        p = parse_space(src, p + 3, true);
+        p = self.parse_alternates(src, p, &name, rule_id, false)?;
+
+        if p < src.len() && src[p] == b'\r' {
+            p += if p + 1 < src.len() && src[p + 1] == b'\n' { 2 } else { 1 };
+        } else if p < src.len() && src[p] == b'\n' {
+            p += 1;
+        } else if p < src.len() {
+            return Err(format!("expecting newline or end at {}", String::from_utf8_lossy(&src[p..])));
+        }
+        Ok(parse_space(src, p, true))
+    }
+
+    /// Parses a complete grammar, validating that every referenced rule is defined.
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut parser = Self::new();
+        let bytes = src.as_bytes();
+        let mut pos = parse_space(bytes, 0, true);
+        while pos < bytes.len() {
+            pos = parser.parse_rule(bytes, pos)?;
+        }
+
+        // Validate the state to ensure that all rules are defined
+        for rule in &parser.rules {
+            if rule.is_empty() {
+                return Err("Undefined rule".to_string());
+            }
+            for elem in rule {
+                if elem.ty == GretType::RuleRef {
+                    let idx = elem.value as usize;
+                    if idx >= parser.rules.len() || parser.rules[idx].is_empty() {
+                        let name = parser
+                            .symbol_ids
+                            .iter()
+                            .find(|(_, &id)| id == elem.value)
+                            .map(|(name, _)| name.as_str())
+                            .unwrap_or("?");
+                        return Err(format!("Undefined rule identifier '{}'", name));
+                    }
+                }
+            }
+        }
+        Ok(parser)
+    }
+
+    /// Returns the name of a rule id, if known.
+    pub fn rule_name(&self, rule_id: u32) -> Option<&str> {
+        self.symbol_ids
+            .iter()
+            .find(|(_, &id)| id == rule_id)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+// =============================================================================
+// Matcher
+// =============================================================================
+
+fn detect_left_recursion(
+    rules: &[GrammarRule],
+    rule_index: usize,
+    visited: &mut [bool],
+    in_progress: &mut [bool],
+    may_be_empty: &mut [bool],
+) -> bool {
+    if in_progress[rule_index] {
+        return true;
+    }
+    in_progress[rule_index] = true;
+    let rule = &rules[rule_index];
+
+    // First check if the rule might produce the empty string
+    let mut at_rule_start = true;
+    for el in rule {
+        if is_end_of_sequence(el) {
+            if at_rule_start {
+                may_be_empty[rule_index] = true;
+                break;
+            }
+            at_rule_start = true;
+        } else {
+            at_rule_start = false;
+        }
+    }
+
+    // Recurse into leftmost nonterminals (or next-leftmost as long as the previous
+    // nonterminal may be empty)
+    let mut recurse_into_nonterminal = true;
+    for el in rule {
+        if el.ty == GretType::RuleRef && recurse_into_nonterminal {
+            let sub = el.value as usize;
+            if detect_left_recursion(rules, sub, visited, in_progress, may_be_empty) {
+                return true;
+            }
+            if !may_be_empty[sub] {
+                recurse_into_nonterminal = false;
+            }
+        } else {
+            recurse_into_nonterminal = is_end_of_sequence(el);
+        }
+    }
+
+    in_progress[rule_index] = false;
+    visited[rule_index] = true;
+    false
+}
+
+/// Decoder state for a UTF-8 sequence split across tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PartialUtf8 {
+    /// Bit value so far (unshifted)
+    pub value: u32,
+    /// Number of bytes remaining; -1 indicates invalid sequence
+    pub n_remain: i32,
+}
+
+/// A token being checked against the grammar: its index in the candidate
+/// array, its decoded code points (0-terminated) and the read position.
+#[derive(Debug, Clone, Copy)]
+pub struct GrammarCandidate<'a> {
+    pub index: usize,
+    pub code_points: &'a [u32],
+    pub pos: usize,
+    pub partial_utf8: PartialUtf8,
+}
+
+/// Event that switches a lazy grammar from free generation to enforcement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrammarTrigger {
+    /// Text in the output; the grammar is matched starting at the word itself
+    Word(String),
+    /// A sampled token, typically a control token such as `<tool_call>`
+    Token(LlamaToken),
+    /// Output matching a regular expression; the grammar is matched starting
+    /// where the earliest match starts (capture groups are not tracked)
+    Pattern(Arc<RegexSearch>),
+}
+
+/// Runtime grammar state: rule table plus the set of live parse stacks.
+#[derive(Debug, Clone)]
+pub struct LlamaGrammar {
+    rules: Vec<GrammarRule>,
+    stacks: Vec<GrammarStack>,
+    /// Buffer for partially generated UTF-8 sequence from accepted tokens
+    partial_utf8: PartialUtf8,
+
+    /// Lazy grammars only constrain output after one of `triggers` is seen
+    lazy: bool,
+    awaiting_trigger: bool,
+    /// Output generated while awaiting a trigger, searched for trigger words
+    trigger_buffer: Vec<u8>,
+    triggers: Vec<GrammarTrigger>,
+}
+
+impl LlamaGrammar {
+    /// Parses `grammar_str` and starts matching at rule `grammar_root`.
+    pub fn parse(grammar_str: &str, grammar_root: &str) -> Result<Self, String> {
+        let parser = GrammarParser::parse(grammar_str)?;
+        if parser.rules.is_empty() {
+            return Err("empty grammar".to_string());
+        }
+        let root = *parser
+            .symbol_ids
+            .get(grammar_root)
+            .ok_or_else(|| format!("grammar does not contain a '{}' symbol", grammar_root))?;
+        Self::from_rules(parser.rules, root as usize)
+    }
+
+    /// Builds a grammar from an already parsed rule table.
+    pub fn from_rules(rules: Vec<GrammarRule>, start_rule_index: usize) -> Result<Self, String> {
+        if start_rule_index >= rules.len() {
+            return Err(format!("start rule index {} out of range", start_rule_index));
+        }
+
+        // Check for left recursion
+        let n = rules.len();
+        let mut visited = vec![false; n];
+        let mut in_progress = vec![false; n];
+        let mut may_be_empty = vec![false; n];
+        for i in 0..n {
+            if visited[i] {
+                continue;
+            }
+            if detect_left_recursion(&rules, i, &mut visited, &mut in_progress, &mut may_be_empty) {
+                return Err(format!("unsupported grammar, left recursion detected for rule {}", i));
+            }
+        }
+
+        // Loop over alternates of start rule to build initial stacks
+        let mut stacks = Vec::new();
+        let mut pos = 0;
+        loop {
+            let mut stack = GrammarStack::new();
+            if !is_end_of_sequence(&rules[start_rule_index][pos]) {
+                // if alternate is nonempty, add to stack
+                stack.push((start_rule_index, pos));
+            }
+            advance_stack(&rules, stack, &mut stacks);
+            while !is_end_of_sequence(&rules[start_rule_index][pos]) {
+                pos += 1;
+            }
+            if rules[start_rule_index][pos].ty == GretType::Alt {
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        Ok(Self {
+            rules,
+            stacks,
+            partial_utf8: PartialUtf8::default(),
+            lazy: false,
+            awaiting_trigger: false,
+            trigger_buffer: Vec::new(),
+            triggers: Vec::new(),
+        })
+    }
+
+    /// Makes the grammar lazy: output is unconstrained until a trigger appears.
+    /// Without triggers the grammar would never activate, so that is an error.
+    pub fn into_lazy(mut self, triggers: Vec<GrammarTrigger>) -> Result<Self, String> {
+        if triggers.is_empty() {
+            return Err("lazy grammar requires at least one trigger word, pattern or token".to_string());
+        }
+        if triggers.iter().any(|t| matches!(t, GrammarTrigger::Word(w) if w.is_empty())) {
+            return Err("grammar trigger words must not be empty".to_string());
+        }
+        self.lazy = true;
+        self.awaiting_trigger = true;
+        self.triggers = triggers;
+        Ok(self)
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// True while a lazy grammar has not seen any of its triggers yet.
+    pub fn is_awaiting_trigger(&self) -> bool {
+        self.awaiting_trigger
+    }
+
+    pub fn triggers(&self) -> &[GrammarTrigger] {
+        &self.triggers
+    }
+
+    /// Buffers `piece` and returns the output from the earliest trigger word
+    /// or pattern match onwards once one has been generated.
+    fn find_trigger_word(&mut self, piece: &[u8]) -> Option<Vec<u8>> {
+        self.trigger_buffer.extend_from_slice(piece);
+        let buffer = &self.trigger_buffer;
+        let start = self
+            .triggers
+            .iter()
+            .filter_map(|t| match t {
+                GrammarTrigger::Word(w) => buffer.windows(w.len()).position(|win| win == w.as_bytes()),
+                GrammarTrigger::Pattern(p) => p.find(buffer),
+                GrammarTrigger::Token(_) => None,
+            })
+            .min();
+        if let Some(start) = start {
+            let constrained = self.trigger_buffer[start..].to_vec();
+            self.trigger_buffer.clear();
+            return Some(constrained);
+        }
+
+        // A pattern match can start anywhere in the output
+        if self.triggers.iter().any(|t| matches!(t, GrammarTrigger::Pattern(_))) {
+            return None;
+        }
+        // Only a suffix shorter than the longest word can still start a match
+        let max_word = self
+            .triggers
+            .iter()
+            .map(|t| if let GrammarTrigger::Word(w) = t { w.len() } else { 0 })
+            .max()
+            .unwrap_or(0);
+        let keep = max_word.saturating_sub(1);
+        if self.trigger_buffer.len() > keep {
+            self.trigger_buffer.drain(..self.trigger_buffer.len() - keep);
+        }
+        None
+    }
+
+    pub fn rules(&self) -> &[GrammarRule] {
+        &self.rules
+    }
+
+    pub fn stacks(&self) -> &[GrammarStack] {
+        &self.stacks
+    }
+
+    /// Advances the grammar by one code point. Returns false if no parse can accept it,
+    /// in which case the grammar is left in a dead state.
+    pub fn accept_char(&mut self, chr: u32) -> bool {
+        self.stacks = accept(&self.rules, &self.stacks, chr);
+        !self.stacks.is_empty()
+    }
+
+    /// Advances the grammar over every code point of `text`.
+    pub fn accept_str(&mut self, text: &str) -> bool {
+        text.chars().all(|c| self.accept_char(c as u32))
+    }
+
+    /// True when some parse has consumed its whole rule, i.e. the input so far is a
+    /// complete sentence of the grammar.
+    pub fn is_accepting(&self) -> bool {
+        self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// True when the grammar can no longer accept any input.
+    pub fn is_dead(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    pub fn partial_utf8(&self) -> PartialUtf8 {
+        self.partial_utf8
+    }
+
+    /// Writes the parse state: the live stacks, the pending UTF-8 sequence and,
+    /// for lazy grammars, the trigger state. Rules are not written; the state
+    /// is read back into a grammar parsed from the same text.
+    pub fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        io.write_u32(self.stacks.len() as u32)?;
+        for stack in &self.stacks {
+            io.write_u32(stack.len() as u32)?;
+            for &(rule, elem) in stack {
+                io.write_u32(rule as u32)?;
+                io.write_u32(elem as u32)?;
+            }
+        }
+        io.write_u32(self.partial_utf8.value)?;
+        io.write_i32(self.partial_utf8.n_remain)?;
+        io.write_u32(self.awaiting_trigger as u32)?;
+        io.write_buf(&self.trigger_buffer)
+    }
+
+    /// Restores state written by `state_write`. Stack positions outside the
+    /// rule table are rejected and leave the grammar unchanged.
+    pub fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        let n_stacks = io.read_u32()?;
+        let mut stacks = Vec::with_capacity(n_stacks as usize);
+        for _ in 0..n_stacks {
+            let n_elems = io.read_u32()?;
+            let mut stack = GrammarStack::with_capacity(n_elems as usize);
+            for _ in 0..n_elems {
+                let rule = io.read_u32()? as usize;
+                let elem = io.read_u32()? as usize;
+                if self.rules.get(rule).is_none_or(|r| elem >= r.len()) {
+                    return Err(format!("grammar state refers to missing element {} of rule {}", elem, rule));
+                }
+                stack.push((rule, elem));
+            }
+            stacks.push(stack);
+        }
+        let partial_utf8 = PartialUtf8 { value: io.read_u32()?, n_remain: io.read_i32()? };
+        let awaiting_trigger = io.read_u32()? != 0;
+        if awaiting_trigger && !self.lazy {
+            return Err("grammar state awaits a trigger but the grammar is not lazy".to_string());
+        }
+        self.trigger_buffer = io.read_buf()?;
+        self.stacks = stacks;
+        self.partial_utf8 = partial_utf8;
+        self.awaiting_trigger = awaiting_trigger;
+        Ok(())
+    }
+
+    /// Masks (sets to -inf) every candidate whose piece cannot continue the grammar.
+    /// End-of-generation tokens are only allowed once the grammar is accepting.
+    pub fn apply(&self, vocab: &LlamaVocab, cur_p: &mut LlamaTokenDataArray) {
+        if self.awaiting_trigger {
+            return;
+        }
+
+        let allow_eog = self.is_accepting();
+
+        let mut decoded: Vec<(usize, Vec<u32>, PartialUtf8)> = Vec::with_capacity(cur_p.data.len());
+        for (i, d) in cur_p.data.iter_mut().enumerate() {
+            let piece = vocab.token_to_piece(d.id);
+            if vocab.is_eog(d.id) {
+                if !allow_eog {
+                    d.logit = f32::NEG_INFINITY;
+                }
+            } else if piece.is_empty() || piece[0] == 0 {
+                d.logit = f32::NEG_INFINITY;
+            } else {
+                let (code_points, partial) = decode_utf8_partial(piece, self.partial_utf8);
+                decoded.push((i, code_points, partial));
+            }
+        }
+
+        let candidates: Vec<GrammarCandidate> = decoded
+            .iter()
+            .map(|(i, cps, partial)| GrammarCandidate { index: *i, code_points: cps, pos: 0, partial_utf8: *partial })
+            .collect();
+
+        for reject in reject_candidates(&self.rules, &self.stacks, &candidates) {
+            cur_p.data[reject.index].logit = f32::NEG_INFINITY;
+        }
+    }
+
+    /// Advances the grammar over the piece of an accepted token.
+    pub fn accept_token(&mut self, vocab: &LlamaVocab, token: LlamaToken) -> Result<(), String> {
+        if self.awaiting_trigger {
+            let piece = vocab.token_to_piece(token).to_vec();
+            if self.triggers.contains(&GrammarTrigger::Token(token)) {
+                self.awaiting_trigger = false;
+                self.trigger_buffer.clear();
+                return if piece.is_empty() { Ok(()) } else { self.accept_bytes(&piece) };
+            }
+            if let Some(constrained) = self.find_trigger_word(&piece) {
+                self.awaiting_trigger = false;
+                return self.accept_bytes(&constrained);
+            }
+            return Ok(());
+        }
+
+        if vocab.is_eog(token) {
+            if self.is_accepting() {
+                return Ok(());
+            }
+            return Err("end of generation token accepted before grammar completed".to_string());
+        }
+        let piece = vocab.token_to_piece(token).to_vec();
+        self.accept_bytes(&piece)
+    }
+
+    /// Advances the grammar over raw bytes that may end in a partial UTF-8 sequence.
+    pub fn accept_bytes(&mut self, piece: &[u8]) -> Result<(), String> {
+        let (code_points, partial) = decode_utf8_partial(piece, self.partial_utf8);
+        for &cp in &code_points[..code_points.len() - 1] {
+            self.stacks = accept(&self.rules, &self.stacks, cp);
+        }
+        self.partial_utf8 = partial;
+        if self.stacks.is_empty() {
+            return Err(format!(
+                "Unexpected empty grammar stack after accepting piece: {}",
+                String::from_utf8_lossy(piece)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a UTF-8 string which may end in an incomplete sequence. Adds a
+/// terminating 0 for use as a pointer. If an invalid sequence is encountered,
+/// returns a [0] code point list with `n_remain == -1`.
+pub fn decode_utf8_partial(src: &[u8], partial_start: PartialUtf8) -> (Vec<u32>, PartialUtf8) {
+    const LOOKUP: [i32; 16] = [1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 2, 2, 3, 4];
+    let mut code_points = Vec::with_capacity(src.len() + 1);
+    let mut value = partial_start.value;
+    let mut n_remain = partial_start.n_remain;
+    let mut pos = 0;
+
+    // continue previous decode, if applicable
+    while pos < src.len() && src[pos] != 0 && n_remain > 0 {
+        let next_byte = src[pos];
+        if (next_byte >> 6) != 2 {
+            // invalid sequence, abort
+            code_points.push(0);
+            return (code_points, PartialUtf8 { value: 0, n_remain: -1 });
+        }
+        value = (value << 6) + (next_byte & 0x3F) as u32;
+        pos += 1;
+        n_remain -= 1;
+    }
+
+    if partial_start.n_remain > 0 && n_remain == 0 {
+        code_points.push(value);
+    }
+
+    // decode any subsequent utf-8 sequences, which may be incomplete
+    while pos < src.len() && src[pos] != 0 {
+        let first_byte = src[pos];
+        let highbits = first_byte >> 4;
+        n_remain = LOOKUP[highbits as usize] - 1;
+
+        if n_remain < 0 {
+            // invalid sequence, abort
+            code_points.clear();
+            code_points.push(0);
+            return (code_points, PartialUtf8 { value: 0, n_remain });
+        }
+
+        let mask = ((1u32 << (7 - n_remain)) - 1) as u8;
+        value = (first_byte & mask) as u32;
+        pos += 1;
+        while pos < src.len() && src[pos] != 0 && n_remain > 0 {
+            value = (value << 6) + (src[pos] & 0x3F) as u32;
+            pos += 1;
+            n_remain -= 1;
+        }
+        if n_remain == 0 {
+            code_points.push(value);
+        }
+    }
+    code_points.push(0);
+
+    (code_points, PartialUtf8 { value, n_remain })
+}
+
+/// Returns true if `el` at `pos` matches `chr` and the position after the character set.
+fn match_char(rules: &[GrammarRule], pos: ElementPos, chr: u32) -> (bool, ElementPos) {
+    let rule = &rules[pos.0];
+    let mut i = pos.1;
+    let mut found = false;
+    let is_positive_char = matches!(rule[i].ty, GretType::Char | GretType::CharAny);
+
+    loop {
+        if i + 1 < rule.len() && rule[i + 1].ty == GretType::CharRngUpper {
+            // inclusive range, e.g. [a-z]
+            found = found || (rule[i].value <= chr && chr <= rule[i + 1].value);
+            i += 2;
+        } else if rule[i].ty == GretType::CharAny {
+            // Any character matches "."
+            found = true;
+            i += 1;
+        } else {
+            // exact char match, e.g. [a] or "a"
+            found = found || rule[i].value == chr;
+            i += 1;
+        }
+        if rule[i].ty != GretType::CharAlt {
+            break;
+        }
+    }
+
+    (found == is_positive_char, (pos.0, i))
+}
+
+/// Transforms a stack into one or more stacks whose top is a terminal element,
+/// expanding rule references (and their alternates) as needed.
+fn advance_stack(rules: &[GrammarRule], stack: GrammarStack, new_stacks: &mut Vec<GrammarStack>) {
+    let top = match stack.last() {
+        Some(&top) => top,
+        None => {
+            if !new_stacks.contains(&stack) {
+                new_stacks.push(stack);
+            }
+            return;
+        }
+    };
+
+    let el = rules[top.0][top.1];
+    match el.ty {
+        GretType::RuleRef => {
+            let rule_id = el.value as usize;
+            let mut sub = 0;
+            loop {
+                // init new stack without the top (pos)
+                let mut next_stack: GrammarStack = stack[..stack.len() - 1].to_vec();
+                if !is_end_of_sequence(&rules[top.0][top.1 + 1]) {
+                    // if this rule ref is followed by another element, add that to stack
+                    next_stack.push((top.0, top.1 + 1));
+                }
+                if !is_end_of_sequence(&rules[rule_id][sub]) {
+                    // if alternate is nonempty, add to stack
+                    next_stack.push((rule_id, sub));
+                }
+                advance_stack(rules, next_stack, new_stacks);
+                while !is_end_of_sequence(&rules[rule_id][sub]) {
+                    // scan to end of alternate def
+                    sub += 1;
+                }
+                if rules[rule_id][sub].ty == GretType::Alt {
+                    // there's another alternate def of this rule to process
+                    sub += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        GretType::Char | GretType::CharNot | GretType::CharAny => {
+            if !new_stacks.contains(&stack) {
+                // only add the stack if it's not a duplicate of one we already have
+                new_stacks.push(stack);
+            }
+        }
+        _ => {
+            // End, Alt, CharAlt and CharRngUpper never sit on top of a stack
+            unreachable!("grammar stack top must be a terminal or rule reference");
+        }
+    }
+}
+
+/// Takes a set of possible pushdown stacks on a grammar, which are required to
+/// be positioned at a character range, and produces the N possible stacks if
+/// the given char is accepted at those positions.
+pub fn accept(rules: &[GrammarRule], stacks: &[GrammarStack], chr: u32) -> Vec<GrammarStack> {
+    let mut new_stacks = Vec::new();
+    for stack in stacks {
+        let top = match stack.last() {
+            Some(&top) => top,
+            None => continue,
+        };
+        let (matched, after) = match_char(rules, top, chr);
+        if matched {
+            let mut new_stack: GrammarStack = stack[..stack.len() - 1].to_vec();
+            if !is_end_of_sequence(&rules[after.0][after.1]) {
+                new_stack.push(after);
+            }
+            advance_stack(rules, new_stack, &mut new_stacks);
+        }
+    }
+    new_stacks
+}
+
+/// Returns true if the partial UTF-8 sequence could satisfy the char range at `pos`.
+fn match_partial_char(rules: &[GrammarRule], pos: ElementPos, partial_utf8: PartialUtf8) -> bool {
+    let rule = &rules[pos.0];
+    let mut i = pos.1;
+    let is_positive_char = matches!(rule[i].ty, GretType::Char | GretType::CharAny);
+
+    let partial_value = partial_utf8.value;
+    let n_remain = partial_utf8.n_remain;
+
+    // invalid sequence or 7-bit char split across 2 bytes (overlong)
+    if n_remain < 0 || (n_remain == 1 && partial_value < 2) {
+        return false;
+    }
+
+    // range of possible code points this partial UTF-8 sequence could complete to
+    let mut low = partial_value << (n_remain * 6);
+    let high = low | ((1u32 << (n_remain * 6)) - 1);
+
+    if low == 0 {
+        if n_remain == 2 {
+            low = 1 << 11;
+        } else if n_remain == 3 {
+            low = 1 << 16;
+        }
+    }
+
+    loop {
+        if i + 1 < rule.len() && rule[i + 1].ty == GretType::CharRngUpper {
+            // inclusive range, e.g. [a-z]
+            if rule[i].value <= high && low <= rule[i + 1].value {
+                return is_positive_char;
+            }
+            i += 2;
+        } else if rule[i].ty == GretType::CharAny {
+            // Any character matches "."
+            return true;
+        } else {
+            // exact char match, e.g. [a] or "a"
+            if low <= rule[i].value && rule[i].value <= high {
+                return is_positive_char;
+            }
+            i += 1;
+        }
+        if rule[i].ty != GretType::CharAlt {
+            break;
+        }
+    }
+
+    !is_positive_char
+}
+
+fn reject_candidates_for_stack<'a>(
+    rules: &[GrammarRule],
+    stack: &GrammarStack,
+    candidates: &[GrammarCandidate<'a>],
+) -> Vec<GrammarCandidate<'a>> {
+    let mut rejects = Vec::with_capacity(candidates.len());
+
+    let stack_pos = match stack.last() {
+        Some(&pos) => pos,
+        None => {
+            for tok in candidates {
+                if tok.code_points[tok.pos] != 0 || tok.partial_utf8.n_remain != 0 {
+                    rejects.push(*tok);
+                }
+            }
+            return rejects;
+        }
+    };
+
+    let mut next_candidates = Vec::with_capacity(candidates.len());
+    for tok in candidates {
+        let cp = tok.code_points[tok.pos];
+        if cp == 0 {
+            // reached end of full codepoints in token, reject iff it ended in a partial sequence
+            // that cannot satisfy this position in grammar
+            if tok.partial_utf8.n_remain != 0 && !match_partial_char(rules, stack_pos, tok.partial_utf8) {
+                rejects.push(*tok);
+            }
+        } else if match_char(rules, stack_pos, cp).0 {
+            next_candidates.push(GrammarCandidate { pos: tok.pos + 1, ..*tok });
+        } else {
+            rejects.push(*tok);
+        }
+    }
+
+    let stack_pos_after = match_char(rules, stack_pos, 0).1;
+
+    // update top of stack to next element, if any
+    let mut stack_after: GrammarStack = stack[..stack.len() - 1].to_vec();
+    if !is_end_of_sequence(&rules[stack_pos_after.0][stack_pos_after.1]) {
+        stack_after.push(stack_pos_after);
+    }
+    let mut next_stacks = Vec::new();
+    advance_stack(rules, stack_after, &mut next_stacks);
+
+    for tok in reject_candidates(rules, &next_stacks, &next_candidates) {
+        rejects.push(GrammarCandidate { pos: tok.pos - 1, ..tok });
+    }
+
+    rejects
+}
+
+/// Returns the candidates that no stack can accept.
+pub fn reject_candidates<'a>(
+    rules: &[GrammarRule],
+    stacks: &[GrammarStack],
+    candidates: &[GrammarCandidate<'a>],
+) -> Vec<GrammarCandidate<'a>> {
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+    if stacks.is_empty() {
+        return candidates.to_vec();
+    }
+
+    let mut rejects = reject_candidates_for_stack(rules, &stacks[0], candidates);
+    for stack in &stacks[1..] {
+        rejects = reject_candidates_for_stack(rules, stack, &rejects);
+    }
+    rejects
 }
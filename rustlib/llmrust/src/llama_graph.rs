@@ -1,6 +1,72 @@
-// src/llama_graph.rs - Auto-generated debug file
+// src/llama_graph.rs - Forward pass of a model over one micro-batch
+//
+// The context splits a batch into ubatches and hands them to the graph one at
+// a time, in order. The graph evaluates the tokens of the ubatch against its
+// memory (storing their K/V or updating the recurrent state of their
+// sequences) and returns the logits of the tokens that requested them. A
+// recurrent graph needs every sequence of a ubatch to contribute the same
+// number of tokens, so the context never uses the simple split for it.
+//
+// Embedding models (BERT, nomic-bert, e5, ...) instead return the final
+// hidden state of every token; the context pools them per sequence. Rerankers
+// also provide the classification head applied to the pooled CLS state.
+//
+// Before each decode the context passes its active LoRA adapters and its
+// control vector; a graph that supports them routes its matrix products
+// through `LlamaAdapterLoras::mm` and adds `LlamaAdapterCvec::apply_to` to the
+// output of each layer. It also passes the attention type, taken from the
+// context params or else the model's hyperparameters: a causal graph masks
+// the cells of later positions, a non-causal one lets every token see its
+// whole sequence, which the context keeps within one ubatch.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_graph.rs - File loaded successfully");
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_adapter::{LlamaAdapterCvec, LlamaAdapterLoras};
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaUbatch};
+use crate::llmrust::src::llama_hparams::LlamaPoolingType;
+
+pub trait LlamaGraph {
+    /// Width of a logits row
+    fn n_vocab(&self) -> usize;
+
+    /// Whether the memory of the graph is a per-sequence recurrent state
+    fn is_recurrent(&self) -> bool {
+        false
+    }
+
+    /// Adapters to apply in the following computations
+    fn set_adapter_loras(&mut self, _loras: &LlamaAdapterLoras) {}
+
+    /// Control vector to apply in the following computations
+    fn set_adapter_cvec(&mut self, _cvec: &Arc<LlamaAdapterCvec>) {}
+
+    /// Attention type of the following computations
+    fn set_causal_attn(&mut self, _causal: bool) {}
+
+    /// Evaluates the tokens `ubatch.idxs` of `batch` and returns one logits
+    /// row for each of them whose `batch.logits` flag is set, in ubatch order.
+    fn compute(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String>;
+
+    /// Width of a hidden state
+    fn n_embd(&self) -> usize {
+        0
+    }
+
+    /// Pooling the model was trained with
+    fn pooling_type(&self) -> LlamaPoolingType {
+        LlamaPoolingType::None
+    }
+
+    /// Evaluates the tokens `ubatch.idxs` of `batch` and returns the hidden
+    /// state of each of them, in ubatch order.
+    fn embed(&mut self, _batch: &LlamaBatch, _ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        Err("the model does not produce embeddings".to_string())
+    }
+
+    /// Applies the classification head to a pooled CLS state and returns the
+    /// class scores.
+    fn classify(&self, _cls: &[f32]) -> Result<Vec<f32>, String> {
+        Err("the model has no classification head for rank pooling".to_string())
+    }
 }
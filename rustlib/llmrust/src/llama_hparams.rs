@@ -1,6 +1,322 @@
-// src/llama_hparams.rs - Auto-generated debug file
+// src/llama_hparams.rs - Model hyperparameters
+//
+// Shapes and constants that describe a model architecture, as read from the
+// GGUF metadata. Context-level overrides live in llama_cparams.rs.
+
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_hparams.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml_cpu::ops::{RopeMode, RopeParams};
+
+/// RoPE variant of a model; `None` for models without rotary embeddings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlamaRopeType {
+    None,
+    Norm,
+    Neox,
+}
+
+impl LlamaRopeType {
+    pub fn mode(&self) -> Option<RopeMode> {
+        match self {
+            Self::None => None,
+            Self::Norm => Some(RopeMode::Normal),
+            Self::Neox => Some(RopeMode::Neox),
+        }
+    }
+}
+
+/// How RoPE frequencies are scaled beyond the training context
+/// (`llama_rope_scaling_type`; the discriminants are the C API values)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LlamaRopeScalingType {
+    /// Use the model's default
+    #[default]
+    Unspecified = -1,
+    None = 0,
+    /// Positions divided by the scaling factor
+    Linear = 1,
+    /// Interpolation of the low frequencies only, with attention scaling
+    Yarn = 2,
+    /// Per-dimension frequency factors, short or long by context size
+    LongRope = 3,
+    /// NTK-aware: the frequency base raised so that the lowest frequency is
+    /// scaled like linear scaling while the highest is kept
+    Ntk = 4,
+    /// Dynamic NTK: no scaling within the original context, beyond it the
+    /// frequency base raised by how far the context exceeds it
+    Dynamic = 5,
+}
+
+impl LlamaRopeScalingType {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            -1 => Some(Self::Unspecified),
+            0 => Some(Self::None),
+            1 => Some(Self::Linear),
+            2 => Some(Self::Yarn),
+            3 => Some(Self::LongRope),
+            4 => Some(Self::Ntk),
+            5 => Some(Self::Dynamic),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "linear" => Some(Self::Linear),
+            "yarn" => Some(Self::Yarn),
+            "longrope" => Some(Self::LongRope),
+            "ntk" => Some(Self::Ntk),
+            "dynamic" => Some(Self::Dynamic),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "unspecified",
+            Self::None => "none",
+            Self::Linear => "linear",
+            Self::Yarn => "yarn",
+            Self::LongRope => "longrope",
+            Self::Ntk => "ntk",
+            Self::Dynamic => "dynamic",
+        }
+    }
+}
+
+/// How the hidden states of a sequence are reduced to one embedding
+/// (`llama_pooling_type`; the discriminants are the C API values)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LlamaPoolingType {
+    /// Use the model's default
+    Unspecified = -1,
+    /// One embedding per output token
+    #[default]
+    None = 0,
+    Mean = 1,
+    /// Hidden state of the first token (position 0)
+    Cls = 2,
+    /// Hidden state of the last token
+    Last = 3,
+    /// Classification head applied to the CLS embedding, for rerankers
+    Rank = 4,
+}
+
+impl LlamaPoolingType {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            -1 => Some(Self::Unspecified),
+            0 => Some(Self::None),
+            1 => Some(Self::Mean),
+            2 => Some(Self::Cls),
+            3 => Some(Self::Last),
+            4 => Some(Self::Rank),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "mean" => Some(Self::Mean),
+            "cls" => Some(Self::Cls),
+            "last" => Some(Self::Last),
+            "rank" => Some(Self::Rank),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "unspecified",
+            Self::None => "none",
+            Self::Mean => "mean",
+            Self::Cls => "cls",
+            Self::Last => "last",
+            Self::Rank => "rank",
+        }
+    }
+}
+
+/// Whether a token attends to later tokens of its sequence
+/// (`llama_attention_type`; the discriminants are the C API values)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LlamaAttentionType {
+    /// Use the model's default
+    #[default]
+    Unspecified = -1,
+    /// Each token sees itself and the tokens before it
+    Causal = 0,
+    /// Each token sees the whole sequence, as in BERT-style encoders
+    NonCausal = 1,
+}
+
+impl LlamaAttentionType {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            -1 => Some(Self::Unspecified),
+            0 => Some(Self::Causal),
+            1 => Some(Self::NonCausal),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "causal" => Some(Self::Causal),
+            "non-causal" => Some(Self::NonCausal),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "unspecified",
+            Self::Causal => "causal",
+            Self::NonCausal => "non-causal",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LlamaHparams {
+    pub n_vocab: u32,
+    pub n_ctx_train: u32,
+    pub n_embd: u32,
+    pub n_layer: u32,
+    pub n_head: u32,
+    pub n_head_kv: u32,
+    /// Dimension of a key head
+    pub n_embd_head_k: u32,
+    /// Dimension of a value head
+    pub n_embd_head_v: u32,
+    pub n_ff: u32,
+    /// Experts of mixture-of-experts models, 0 for dense models
+    pub n_expert: u32,
+    /// Experts evaluated per token
+    pub n_expert_used: u32,
+    /// Number of rotated dimensions per head
+    pub n_rot: u32,
+    /// Sliding attention window, 0 for full attention in every layer
+    pub n_swa: u32,
+    /// Every `n_swa_pattern`-th layer attends to the full context, the
+    /// others to the window; 0 puts every layer on the window
+    pub n_swa_pattern: u32,
+    /// Causal attention (`{arch}.attention.causal`); encoders default to
+    /// bidirectional attention
+    pub causal_attn: bool,
+
+    pub rope_type: LlamaRopeType,
+    pub rope_freq_base_train: f32,
+    pub rope_freq_scale_train: f32,
+    /// Scaling the model was trained or fine-tuned with
+    pub rope_scaling_type_train: LlamaRopeScalingType,
+    /// Context length before scaling, for YaRN and LongRoPE; 0 for `n_ctx_train`
+    pub n_ctx_orig_yarn: u32,
+    /// Magnitude scale of rotated values (`{arch}.rope.scaling.attn_factor`)
+    pub rope_attn_factor: f32,
+    /// LongRoPE frequency factors, `n_rot / 2` each
+    pub rope_factors_short: Option<Vec<f32>>,
+    pub rope_factors_long: Option<Vec<f32>>,
+
+    pub f_norm_rms_eps: f32,
+
+    /// Pooling of embedding models (`{arch}.pooling_type`)
+    pub pooling_type: LlamaPoolingType,
+}
+
+impl Default for LlamaHparams {
+    fn default() -> Self {
+        Self {
+            n_vocab: 0,
+            n_ctx_train: 0,
+            n_embd: 0,
+            n_layer: 0,
+            n_head: 0,
+            n_head_kv: 0,
+            n_embd_head_k: 0,
+            n_embd_head_v: 0,
+            n_ff: 0,
+            n_expert: 0,
+            n_expert_used: 0,
+            n_rot: 0,
+            n_swa: 0,
+            n_swa_pattern: 0,
+            causal_attn: true,
+            rope_type: LlamaRopeType::Norm,
+            rope_freq_base_train: 10000.0,
+            rope_freq_scale_train: 1.0,
+            rope_scaling_type_train: LlamaRopeScalingType::Linear,
+            n_ctx_orig_yarn: 0,
+            rope_attn_factor: 1.0,
+            rope_factors_short: None,
+            rope_factors_long: None,
+            f_norm_rms_eps: 1e-5,
+            pooling_type: LlamaPoolingType::None,
+        }
+    }
+}
+
+impl LlamaHparams {
+    /// Hash of the model shape, used to reject state saved for another model
+    pub fn fingerprint(&self) -> u64 {
+        let fields = [
+            self.n_vocab,
+            self.n_ctx_train,
+            self.n_embd,
+            self.n_layer,
+            self.n_head,
+            self.n_head_kv,
+            self.n_embd_head_k,
+            self.n_embd_head_v,
+            self.n_ff,
+            self.n_rot,
+            self.rope_type as u32,
+            self.rope_freq_base_train.to_bits(),
+            self.rope_freq_scale_train.to_bits(),
+        ];
+        // FNV-1a
+        fields
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    /// RoPE as the model was trained, `None` without rotary embeddings;
+    /// `LlamaCparams::rope_params` applies the context's overrides.
+    pub fn rope_params(&self) -> Option<RopeParams> {
+        let mode = self.rope_type.mode()?;
+        Some(RopeParams::new(mode, self.n_rot as usize, self.rope_freq_base_train, self.rope_freq_scale_train))
+    }
+
+    /// Context length the RoPE scaling is relative to
+    pub fn n_ctx_orig(&self) -> u32 {
+        if self.n_ctx_orig_yarn != 0 {
+            self.n_ctx_orig_yarn
+        } else {
+            self.n_ctx_train
+        }
+    }
+
+    /// Whether layer `il` uses sliding window attention
+    pub fn is_swa(&self, il: u32) -> bool {
+        self.n_swa > 0 && (self.n_swa_pattern == 0 || il % self.n_swa_pattern < self.n_swa_pattern - 1)
+    }
+
+    /// Query heads per key/value head
+    pub fn n_gqa(&self) -> u32 {
+        self.n_head.checked_div(self.n_head_kv).unwrap_or(0)
+    }
+
+    /// Dimension of keys across all key/value heads
+    pub fn n_embd_k_gqa(&self) -> u32 {
+        self.n_embd_head_k * self.n_head_kv
+    }
+
+    /// Dimension of values across all key/value heads
+    pub fn n_embd_v_gqa(&self) -> u32 {
+        self.n_embd_head_v * self.n_head_kv
+    }
 }
@@ -1,6 +1,305 @@
-// src/llama_io.rs - Auto-generated debug file
+// src/llama_io.rs - State serialization and session files
+//
+// Writer/reader traits used to serialize state, implemented over files and
+// caller-provided byte buffers (plus a writer that only counts bytes, to size
+// buffers), the header of context and sequence state, and the session file
+// format: a versioned header with a model fingerprint and the token list,
+// followed by the KV cells of one sequence. All values are little-endian.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_io.rs - File loaded successfully");
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::llmrust::src::llama_kv_cache::LlamaKvCache;
+use crate::llmrust::src::llama_memory::{LlamaMemory, LlamaSeqId};
+use crate::llmrust::src::llama_vocab::LlamaToken;
+
+/// 'ggsn'
+pub const LLAMA_SESSION_MAGIC: u32 = 0x6767_736e;
+pub const LLAMA_SESSION_VERSION: u32 = 1;
+
+/// 'ggst'
+pub const LLAMA_STATE_MAGIC: u32 = 0x6767_7374;
+/// 'ggsq'
+pub const LLAMA_STATE_SEQ_MAGIC: u32 = 0x6767_7371;
+pub const LLAMA_STATE_VERSION: u32 = 1;
+
+pub trait LlamaIoWrite {
+    fn write(&mut self, src: &[u8]) -> Result<(), String>;
+
+    /// Bytes written so far
+    fn n_bytes(&self) -> usize;
+
+    fn write_u32(&mut self, value: u32) -> Result<(), String> {
+        self.write(&value.to_le_bytes())
+    }
+
+    fn write_i32(&mut self, value: i32) -> Result<(), String> {
+        self.write(&value.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, value: u64) -> Result<(), String> {
+        self.write(&value.to_le_bytes())
+    }
+
+    /// Writes `buf` preceded by its length.
+    fn write_buf(&mut self, buf: &[u8]) -> Result<(), String> {
+        self.write_u32(buf.len() as u32)?;
+        self.write(buf)
+    }
+}
+
+pub trait LlamaIoRead {
+    /// Fills `dst`; running out of data is an error.
+    fn read_to(&mut self, dst: &mut [u8]) -> Result<(), String>;
+
+    /// Bytes read so far
+    fn n_bytes(&self) -> usize;
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0; 4];
+        self.read_to(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, String> {
+        let mut buf = [0; 4];
+        self.read_to(&mut buf)?;
+        Ok(i32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0; 8];
+        self.read_to(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads a buffer written by `write_buf`.
+    fn read_buf(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.read_u32()? as usize;
+        let mut buf = vec![0; len];
+        self.read_to(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Counts the bytes that would be written
+#[derive(Debug, Default)]
+pub struct LlamaIoWriteDummy {
+    n_bytes: usize,
+}
+
+impl LlamaIoWriteDummy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LlamaIoWrite for LlamaIoWriteDummy {
+    fn write(&mut self, src: &[u8]) -> Result<(), String> {
+        self.n_bytes += src.len();
+        Ok(())
+    }
+
+    fn n_bytes(&self) -> usize {
+        self.n_bytes
+    }
+}
+
+/// Writes into a fixed-size buffer; running out of space is an error.
+pub struct LlamaIoWriteBuffer<'a> {
+    buf: &'a mut [u8],
+    n_bytes: usize,
+}
+
+impl<'a> LlamaIoWriteBuffer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, n_bytes: 0 }
+    }
+}
+
+impl LlamaIoWrite for LlamaIoWriteBuffer<'_> {
+    fn write(&mut self, src: &[u8]) -> Result<(), String> {
+        let end = self.n_bytes + src.len();
+        if end > self.buf.len() {
+            return Err(format!("state buffer too small: need at least {} bytes, have {}", end, self.buf.len()));
+        }
+        self.buf[self.n_bytes..end].copy_from_slice(src);
+        self.n_bytes = end;
+        Ok(())
+    }
+
+    fn n_bytes(&self) -> usize {
+        self.n_bytes
+    }
+}
+
+pub struct LlamaIoReadBuffer<'a> {
+    buf: &'a [u8],
+    n_bytes: usize,
+}
+
+impl<'a> LlamaIoReadBuffer<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, n_bytes: 0 }
+    }
+}
+
+impl LlamaIoRead for LlamaIoReadBuffer<'_> {
+    fn read_to(&mut self, dst: &mut [u8]) -> Result<(), String> {
+        let end = self.n_bytes + dst.len();
+        if end > self.buf.len() {
+            return Err(format!("unexpectedly reached end of state buffer ({} bytes)", self.buf.len()));
+        }
+        dst.copy_from_slice(&self.buf[self.n_bytes..end]);
+        self.n_bytes = end;
+        Ok(())
+    }
+
+    fn n_bytes(&self) -> usize {
+        self.n_bytes
+    }
+}
+
+pub struct LlamaIoWriteFile {
+    file: BufWriter<File>,
+    n_bytes: usize,
+}
+
+impl LlamaIoWriteFile {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+        Ok(Self { file: BufWriter::new(file), n_bytes: 0 })
+    }
+
+    pub fn finish(mut self) -> Result<usize, String> {
+        self.file.flush().map_err(|e| e.to_string())?;
+        Ok(self.n_bytes)
+    }
+}
+
+impl LlamaIoWrite for LlamaIoWriteFile {
+    fn write(&mut self, src: &[u8]) -> Result<(), String> {
+        self.file.write_all(src).map_err(|e| e.to_string())?;
+        self.n_bytes += src.len();
+        Ok(())
+    }
+
+    fn n_bytes(&self) -> usize {
+        self.n_bytes
+    }
+}
+
+pub struct LlamaIoReadFile {
+    file: BufReader<File>,
+    n_bytes: usize,
+}
+
+impl LlamaIoReadFile {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+        Ok(Self { file: BufReader::new(file), n_bytes: 0 })
+    }
+}
+
+impl LlamaIoRead for LlamaIoReadFile {
+    fn read_to(&mut self, dst: &mut [u8]) -> Result<(), String> {
+        self.file.read_exact(dst).map_err(|e| format!("unexpectedly reached end of file: {}", e))?;
+        self.n_bytes += dst.len();
+        Ok(())
+    }
+
+    fn n_bytes(&self) -> usize {
+        self.n_bytes
+    }
+}
+
+/// Writes the session header: magic, version, model fingerprint and tokens.
+pub fn llama_session_write_header(io: &mut dyn LlamaIoWrite, fingerprint: u64, tokens: &[LlamaToken]) -> Result<(), String> {
+    io.write_u32(LLAMA_SESSION_MAGIC)?;
+    io.write_u32(LLAMA_SESSION_VERSION)?;
+    io.write_u64(fingerprint)?;
+    io.write_u32(tokens.len() as u32)?;
+    for &token in tokens {
+        io.write_i32(token)?;
+    }
+    Ok(())
+}
+
+/// Reads and checks the session header and returns its tokens. Files of
+/// another format version or written for another model are rejected.
+pub fn llama_session_read_header(io: &mut dyn LlamaIoRead, fingerprint: u64, n_token_capacity: usize) -> Result<Vec<LlamaToken>, String> {
+    let magic = io.read_u32()?;
+    let version = io.read_u32()?;
+    if magic != LLAMA_SESSION_MAGIC || version != LLAMA_SESSION_VERSION {
+        return Err(format!("unknown session file format (magic {:08x}, version {})", magic, version));
+    }
+    let file_fingerprint = io.read_u64()?;
+    if file_fingerprint != fingerprint {
+        return Err(format!(
+            "session file was created for a different model (fingerprint {:016x}, expected {:016x})",
+            file_fingerprint, fingerprint
+        ));
+    }
+    let n_tokens = io.read_u32()? as usize;
+    if n_tokens > n_token_capacity {
+        return Err(format!("token count in session file exceeded capacity! {} > {}", n_tokens, n_token_capacity));
+    }
+    (0..n_tokens).map(|_| io.read_i32()).collect()
+}
+
+/// Writes the header of a context (or sequence) state: `magic`, version and
+/// model fingerprint.
+pub fn llama_state_write_header(io: &mut dyn LlamaIoWrite, magic: u32, fingerprint: u64) -> Result<(), String> {
+    io.write_u32(magic)?;
+    io.write_u32(LLAMA_STATE_VERSION)?;
+    io.write_u64(fingerprint)
+}
+
+/// Reads and checks a state header written by `llama_state_write_header`.
+pub fn llama_state_read_header(io: &mut dyn LlamaIoRead, magic: u32, fingerprint: u64) -> Result<(), String> {
+    let file_magic = io.read_u32()?;
+    let version = io.read_u32()?;
+    if file_magic != magic || version != LLAMA_STATE_VERSION {
+        return Err(format!("unknown state format (magic {:08x}, version {})", file_magic, version));
+    }
+    let state_fingerprint = io.read_u64()?;
+    if state_fingerprint != fingerprint {
+        return Err(format!(
+            "state was saved for a different model (fingerprint {:016x}, expected {:016x})",
+            state_fingerprint, fingerprint
+        ));
+    }
+    Ok(())
+}
+
+/// Saves `tokens` and the KV cells of `seq_id` to a session file; returns the
+/// file size.
+pub fn llama_state_seq_save_file(
+    path: &Path,
+    kv: &LlamaKvCache,
+    seq_id: LlamaSeqId,
+    fingerprint: u64,
+    tokens: &[LlamaToken],
+) -> Result<usize, String> {
+    let mut io = LlamaIoWriteFile::create(path)?;
+    llama_session_write_header(&mut io, fingerprint, tokens)?;
+    kv.state_write(&mut io, seq_id)?;
+    io.finish()
+}
+
+/// Restores a session file into `seq_id`, replacing its cells, and returns
+/// the saved tokens.
+pub fn llama_state_seq_load_file(
+    path: &Path,
+    kv: &mut LlamaKvCache,
+    seq_id: LlamaSeqId,
+    fingerprint: u64,
+    n_token_capacity: usize,
+) -> Result<Vec<LlamaToken>, String> {
+    let mut io = LlamaIoReadFile::open(path)?;
+    let tokens = llama_session_read_header(&mut io, fingerprint, n_token_capacity)?;
+    kv.state_read(&mut io, seq_id)?;
+    Ok(tokens)
 }
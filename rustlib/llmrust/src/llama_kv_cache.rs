@@ -1,6 +1,1285 @@
-// src/llama_kv_cache.rs - Auto-generated debug file
+// src/llama_kv_cache.rs - Cell-based KV cache shared by multiple sequences
+//
+// The cache is a ring of `size` cells. Each cell holds the K and V rows of one
+// token for every layer, the token's position and the set of sequences the
+// token belongs to. A cell shared by several sequences (e.g. a common prompt
+// prefix after seq_cp) is stored once. Position edits (seq_add / seq_div) are
+// recorded as a per-cell delta and applied to the keys by `update`, which
+// re-rotates them with RoPE so they match keys computed at the new position.
+//
+// K and V are stored in their ggml type (f32, f16, q8_0 or q4_0), one row per
+// cell, and attention reads the stored rows without expanding them to f32,
+// either through the naive kernel or the fused flash attention kernel. Layers
+// with sliding window attention mask the keys that fell out of the window.
+//
+// `LlamaKvCachePaged` lays the same rows out in fixed-size blocks instead: each
+// sequence owns a block table mapping its tokens to blocks, unused blocks sit
+// on a free list, and sequences forked from a common prefix share its blocks
+// until one of them writes to a shared block (copy-on-write).
+
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_kv_cache.rs - File loaded successfully");
+use std::collections::BTreeMap;
+
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_cpu::ops::{attn_head, flash_attn_ext, rope_ext, RopeParams};
+use crate::llmrust::ggml::src::ggml_quants::{dequantize_row, quantize_row};
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+use crate::llmrust::src::llama_io::{LlamaIoRead, LlamaIoWrite};
+use crate::llmrust::src::llama_memory::{LlamaMemory, LlamaPos, LlamaSeqId};
+
+/// Upper bound on the number of parallel sequences (cells store a bitset)
+pub const LLAMA_MAX_SEQ: usize = 64;
+
+/// Default number of tokens per block of the paged cache
+pub const LLAMA_KV_BLOCK_SIZE: usize = 16;
+
+fn in_range(pos: LlamaPos, p0: LlamaPos, p1: LlamaPos) -> bool {
+    let p0 = if p0 < 0 { 0 } else { p0 };
+    let p1 = if p1 < 0 { LlamaPos::MAX } else { p1 };
+    pos >= p0 && pos < p1
+}
+
+/// K and V rows of every layer for a fixed number of slots, in their storage types
+struct KvStorage {
+    n_layer: usize,
+    n_embd_k_gqa: usize,
+    n_embd_v_gqa: usize,
+    n_embd_head_k: usize,
+    n_embd_head_v: usize,
+    n_head_kv: usize,
+    n_rot: usize,
+
+    /// RoPE the keys were rotated with, `None` for models without it
+    rope: Option<RopeParams>,
+
+    /// Sliding window, and the layers that use it
+    n_swa: u32,
+    swa_layers: Vec<bool>,
+    /// Use the fused attention kernel
+    flash_attn: bool,
+
+    type_k: GgmlType,
+    type_v: GgmlType,
+
+    /// Per layer: one row of n_embd_k_gqa keys per slot, stored as `type_k`
+    k_l: Vec<Vec<u8>>,
+    /// Per layer: one row of n_embd_v_gqa values per slot, stored as `type_v`
+    v_l: Vec<Vec<u8>>,
+}
+
+impl KvStorage {
+    fn new(hparams: &LlamaHparams, n_slots: usize, type_k: GgmlType, type_v: GgmlType) -> Result<Self, String> {
+        // quantized rows are split per head, so heads must hold whole blocks
+        for (name, ty, n_embd_head) in [("K", type_k, hparams.n_embd_head_k), ("V", type_v, hparams.n_embd_head_v)] {
+            if !(n_embd_head as usize).is_multiple_of(ty.blck_size()) {
+                return Err(format!(
+                    "{} cache type {} requires a head size that is a multiple of {}, got {}",
+                    name,
+                    ty.name(),
+                    ty.blck_size(),
+                    n_embd_head
+                ));
+            }
+        }
+
+        let n_layer = hparams.n_layer as usize;
+        let n_embd_k_gqa = hparams.n_embd_k_gqa() as usize;
+        let n_embd_v_gqa = hparams.n_embd_v_gqa() as usize;
+        Ok(Self {
+            n_layer,
+            n_embd_k_gqa,
+            n_embd_v_gqa,
+            n_embd_head_k: hparams.n_embd_head_k as usize,
+            n_embd_head_v: hparams.n_embd_head_v as usize,
+            n_head_kv: hparams.n_head_kv as usize,
+            n_rot: hparams.n_rot as usize,
+            rope: hparams.rope_params(),
+            n_swa: hparams.n_swa,
+            swa_layers: (0..hparams.n_layer).map(|il| hparams.is_swa(il)).collect(),
+            flash_attn: false,
+            type_k,
+            type_v,
+            // zeroed per layer rather than cloned, so untouched pages stay unmapped
+            k_l: (0..n_layer).map(|_| vec![0; n_slots * type_k.row_size(n_embd_k_gqa)]).collect(),
+            v_l: (0..n_layer).map(|_| vec![0; n_slots * type_v.row_size(n_embd_v_gqa)]).collect(),
+        })
+    }
+
+    fn k_row_size(&self) -> usize {
+        self.type_k.row_size(self.n_embd_k_gqa)
+    }
+
+    fn v_row_size(&self) -> usize {
+        self.type_v.row_size(self.n_embd_v_gqa)
+    }
+
+    fn memory_size(&self) -> (usize, usize) {
+        (self.k_l.iter().map(|l| l.len()).sum(), self.v_l.iter().map(|l| l.len()).sum())
+    }
+
+    /// Zeroes the rows by reallocating them, so that untouched pages of a large
+    /// cache are not written.
+    fn clear(&mut self) {
+        self.k_l.iter_mut().for_each(|l| *l = vec![0; l.len()]);
+        self.v_l.iter_mut().for_each(|l| *l = vec![0; l.len()]);
+    }
+
+    fn cpy_k(&mut self, il: usize, slot: usize, k: &[f32]) {
+        let n = self.k_row_size();
+        quantize_row(self.type_k, &k[..self.n_embd_k_gqa], &mut self.k_l[il][slot * n..(slot + 1) * n]);
+    }
+
+    fn cpy_v(&mut self, il: usize, slot: usize, v: &[f32]) {
+        let n = self.v_row_size();
+        quantize_row(self.type_v, &v[..self.n_embd_v_gqa], &mut self.v_l[il][slot * n..(slot + 1) * n]);
+    }
+
+    fn get_k(&self, il: usize, slot: usize) -> &[u8] {
+        let n = self.k_row_size();
+        &self.k_l[il][slot * n..(slot + 1) * n]
+    }
+
+    fn get_v(&self, il: usize, slot: usize) -> &[u8] {
+        let n = self.v_row_size();
+        &self.v_l[il][slot * n..(slot + 1) * n]
+    }
+
+    fn get_k_f32(&self, il: usize, slot: usize) -> Vec<f32> {
+        let mut k = vec![0.0; self.n_embd_k_gqa];
+        dequantize_row(self.type_k, self.get_k(il, slot), &mut k);
+        k
+    }
+
+    fn get_v_f32(&self, il: usize, slot: usize) -> Vec<f32> {
+        let mut v = vec![0.0; self.n_embd_v_gqa];
+        dequantize_row(self.type_v, self.get_v(il, slot), &mut v);
+        v
+    }
+
+    /// Writes the K rows then the V rows of `slots`, layer by layer, each
+    /// preceded by the type and row size.
+    fn state_write(&self, io: &mut dyn LlamaIoWrite, slots: &[usize]) -> Result<(), String> {
+        io.write_u32(self.n_layer as u32)?;
+        for (ty, row_size, layers) in [(self.type_k, self.k_row_size(), &self.k_l), (self.type_v, self.v_row_size(), &self.v_l)] {
+            io.write_i32(ty as i32)?;
+            io.write_u32(row_size as u32)?;
+            for layer in layers {
+                for &slot in slots {
+                    io.write(&layer[slot * row_size..(slot + 1) * row_size])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads rows written by `state_write` into `slots`. The layer count, types
+    /// and row sizes must match this storage.
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead, slots: &[usize]) -> Result<(), String> {
+        let n_layer = io.read_u32()? as usize;
+        if n_layer != self.n_layer {
+            return Err(format!("mismatched layer count ({} != {})", n_layer, self.n_layer));
+        }
+        let (k_row_size, v_row_size) = (self.k_row_size(), self.v_row_size());
+        for (name, ty, row_size, layers) in [("key", self.type_k, k_row_size, &mut self.k_l), ("value", self.type_v, v_row_size, &mut self.v_l)] {
+            let ty_ref = io.read_i32()?;
+            if ty_ref != ty as i32 {
+                return Err(format!("mismatched {} type ({} != {})", name, ty_ref, ty as i32));
+            }
+            let row_size_ref = io.read_u32()? as usize;
+            if row_size_ref != row_size {
+                return Err(format!("mismatched {} row size ({} != {})", name, row_size_ref, row_size));
+            }
+            for layer in layers.iter_mut() {
+                for &slot in slots {
+                    io.read_to(&mut layer[slot * row_size..(slot + 1) * row_size])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the K and V rows of every layer from slot `src` to slot `dst`.
+    fn copy_slot(&mut self, src: usize, dst: usize) {
+        let (nk, nv) = (self.k_row_size(), self.v_row_size());
+        for il in 0..self.n_layer {
+            self.k_l[il].copy_within(src * nk..(src + 1) * nk, dst * nk);
+            self.v_l[il].copy_within(src * nv..(src + 1) * nv, dst * nv);
+        }
+    }
+
+    /// Re-rotates the keys of `slot` by `delta` positions; quantized keys are
+    /// expanded, rotated and quantized again.
+    fn shift_k(&mut self, slot: usize, delta: LlamaPos) {
+        let Some(rope) = &self.rope else {
+            return;
+        };
+        // the stored keys already carry the magnitude scale
+        let rope = RopeParams { n_dims: self.n_rot.min(self.n_embd_head_k), ..rope.shift() };
+        for il in 0..self.n_layer {
+            let mut row = self.get_k_f32(il, slot);
+            for head in row.chunks_mut(self.n_embd_head_k).take(self.n_head_kv) {
+                rope_ext(head, delta as f32, &rope);
+            }
+            self.cpy_k(il, slot, &row);
+        }
+    }
+
+    /// Whether layer `il` hides a key at `p0` from a query at `p1`
+    /// because it is outside the sliding window.
+    fn is_masked_swa(&self, il: usize, p0: LlamaPos, p1: LlamaPos) -> bool {
+        self.swa_layers[il] && p1 - p0 >= self.n_swa as LlamaPos
+    }
+
+    /// Attention of the query heads in `q` (one, or the heads of a GQA
+    /// group) over the rows of `kv_head` in `slots`; `mask[i]` is 0 or -inf
+    /// for `slots[i]`. Returns the output heads one after the other.
+    fn attn(&self, il: usize, kv_head: usize, q: &[f32], slots: &[usize], mask: &[f32], scale: f32) -> Vec<f32> {
+        let k_head = self.type_k.row_size(self.n_embd_head_k);
+        let v_head = self.type_v.row_size(self.n_embd_head_v);
+        let k: Vec<&[u8]> = slots.iter().map(|&i| &self.get_k(il, i)[kv_head * k_head..(kv_head + 1) * k_head]).collect();
+        let v: Vec<&[u8]> = slots.iter().map(|&i| &self.get_v(il, i)[kv_head * v_head..(kv_head + 1) * v_head]).collect();
+
+        let n_q = (q.len() / self.n_embd_head_k).max(1);
+        let q = &q[..n_q * self.n_embd_head_k];
+        let mut out = vec![0.0; n_q * self.n_embd_head_v];
+        if self.flash_attn {
+            flash_attn_ext(q, n_q, &k, self.type_k, &v, self.type_v, mask, scale, &mut out);
+        } else {
+            for (q, out) in q.chunks_exact(self.n_embd_head_k).zip(out.chunks_exact_mut(self.n_embd_head_v)) {
+                attn_head(q, &k, self.type_k, &v, self.type_v, mask, scale, out);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LlamaKvCell {
+    /// Position of the token, or -1 if the cell is empty
+    pub pos: LlamaPos,
+    /// Position change not yet applied to the stored keys
+    pub delta: LlamaPos,
+    seq: u64,
+}
+
+impl LlamaKvCell {
+    fn empty() -> Self {
+        Self { pos: -1, delta: 0, seq: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seq == 0
+    }
+
+    pub fn has_seq_id(&self, seq_id: LlamaSeqId) -> bool {
+        (0..LLAMA_MAX_SEQ as LlamaSeqId).contains(&seq_id) && self.seq & (1u64 << seq_id) != 0
+    }
+
+    pub fn seq_count(&self) -> u32 {
+        self.seq.count_ones()
+    }
+
+    fn add_seq(&mut self, seq_id: LlamaSeqId) {
+        self.seq |= 1u64 << seq_id;
+    }
+
+    fn rm_seq(&mut self, seq_id: LlamaSeqId) {
+        self.seq &= !(1u64 << seq_id);
+    }
+}
+
+/// One token of a micro-batch as placed in the cache
+#[derive(Debug, Clone)]
+pub struct LlamaKvUbatchToken {
+    pub pos: LlamaPos,
+    pub seq_ids: Vec<LlamaSeqId>,
+}
+
+pub struct LlamaKvCache {
+    n_seq_max: usize,
+
+    cells: Vec<LlamaKvCell>,
+    /// Next cell to try when searching for a slot
+    head: usize,
+    used: usize,
+    /// Set when some cell has a pending delta
+    has_shift: bool,
+
+    storage: KvStorage,
+}
+
+impl LlamaKvCache {
+    pub fn new(
+        hparams: &LlamaHparams,
+        kv_size: u32,
+        n_seq_max: u32,
+        type_k: GgmlType,
+        type_v: GgmlType,
+    ) -> Result<Self, String> {
+        if kv_size == 0 {
+            return Err("KV cache size must be positive".to_string());
+        }
+        if n_seq_max == 0 || n_seq_max as usize > LLAMA_MAX_SEQ {
+            return Err(format!("n_seq_max must be between 1 and {}, got {}", LLAMA_MAX_SEQ, n_seq_max));
+        }
+        let size = kv_size as usize;
+        Ok(Self {
+            n_seq_max: n_seq_max as usize,
+            cells: vec![LlamaKvCell::empty(); size],
+            head: 0,
+            used: 0,
+            has_shift: false,
+            storage: KvStorage::new(hparams, size, type_k, type_v)?,
+        })
+    }
+
+    /// Sets the RoPE used when re-rotating shifted keys (the context may
+    /// override the frequencies and scaling the model was trained with).
+    pub fn set_rope(&mut self, rope: Option<RopeParams>) {
+        self.storage.rope = rope;
+    }
+
+    /// Selects the fused attention kernel (`LlamaCparams::flash_attn`).
+    pub fn set_flash_attn(&mut self, flash_attn: bool) {
+        self.storage.flash_attn = flash_attn;
+    }
+
+    pub fn flash_attn(&self) -> bool {
+        self.storage.flash_attn
+    }
+
+    pub fn size(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn n_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn n_seq_max(&self) -> usize {
+        self.n_seq_max
+    }
+
+    pub fn cell(&self, i: usize) -> &LlamaKvCell {
+        &self.cells[i]
+    }
+
+    pub fn has_shift(&self) -> bool {
+        self.has_shift
+    }
+
+    pub fn type_k(&self) -> GgmlType {
+        self.storage.type_k
+    }
+
+    pub fn type_v(&self) -> GgmlType {
+        self.storage.type_v
+    }
+
+    /// Size in bytes of the K and V buffers
+    pub fn memory_size(&self) -> (usize, usize) {
+        self.storage.memory_size()
+    }
+
+    /// Size in bytes of the K and V buffers a cache with these parameters would allocate
+    pub fn memory_footprint(hparams: &LlamaHparams, kv_size: u32, type_k: GgmlType, type_v: GgmlType) -> (usize, usize) {
+        let cells = kv_size as usize * hparams.n_layer as usize;
+        (
+            cells * type_k.row_size(hparams.n_embd_k_gqa() as usize),
+            cells * type_v.row_size(hparams.n_embd_v_gqa() as usize),
+        )
+    }
+
+    fn valid_seq(&self, seq_id: LlamaSeqId) -> bool {
+        seq_id >= 0 && (seq_id as usize) < self.n_seq_max
+    }
+
+    fn free_cell(&mut self, i: usize) {
+        self.cells[i].pos = -1;
+        self.cells[i].delta = 0;
+        self.cells[i].seq = 0;
+        self.used -= 1;
+        if i < self.head {
+            self.head = i;
+        }
+    }
+
+    /// Finds `n_tokens` contiguous empty cells, starting the search at `head`.
+    /// Returns the index of the first cell.
+    pub fn find_slot(&mut self, n_tokens: usize) -> Option<usize> {
+        let size = self.cells.len();
+        if n_tokens == 0 || n_tokens > size {
+            return None;
+        }
+        if self.head + n_tokens > size {
+            self.head = 0;
+        }
+
+        let mut tested = 0;
+        while tested < size {
+            if self.head + n_tokens > size {
+                tested += size - self.head;
+                self.head = 0;
+                continue;
+            }
+            match (0..n_tokens).find(|&i| !self.cells[self.head + i].is_empty()) {
+                None => return Some(self.head),
+                Some(i) => {
+                    self.head += i + 1;
+                    tested += i + 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// Claims cells for a micro-batch and returns the cell index of each token.
+    pub fn apply_ubatch(&mut self, tokens: &[LlamaKvUbatchToken]) -> Result<Vec<usize>, String> {
+        for token in tokens {
+            if token.seq_ids.is_empty() {
+                return Err("every token must belong to at least one sequence".to_string());
+            }
+            if let Some(&s) = token.seq_ids.iter().find(|&&s| !self.valid_seq(s)) {
+                return Err(format!("invalid seq_id {} (n_seq_max = {})", s, self.n_seq_max));
+            }
+        }
+
+        let start = self
+            .find_slot(tokens.len())
+            .ok_or_else(|| format!("failed to find a KV cache slot for {} tokens", tokens.len()))?;
+
+        let mut idxs = Vec::with_capacity(tokens.len());
+        for (i, token) in tokens.iter().enumerate() {
+            let cell = &mut self.cells[start + i];
+            cell.pos = token.pos;
+            cell.delta = 0;
+            for &s in &token.seq_ids {
+                cell.add_seq(s);
+            }
+            idxs.push(start + i);
+        }
+        self.used += tokens.len();
+        self.head = start + tokens.len();
+        if self.head >= self.cells.len() {
+            self.head = 0;
+        }
+        Ok(idxs)
+    }
+
+    /// Stores the keys of `cell`, converting them to the cache's K type.
+    pub fn cpy_k(&mut self, il: usize, cell: usize, k: &[f32]) {
+        self.storage.cpy_k(il, cell, k);
+    }
+
+    /// Stores the values of `cell`, converting them to the cache's V type.
+    pub fn cpy_v(&mut self, il: usize, cell: usize, v: &[f32]) {
+        self.storage.cpy_v(il, cell, v);
+    }
+
+    /// Stored key row of `cell`, in the cache's K type
+    pub fn get_k(&self, il: usize, cell: usize) -> &[u8] {
+        self.storage.get_k(il, cell)
+    }
+
+    /// Stored value row of `cell`, in the cache's V type
+    pub fn get_v(&self, il: usize, cell: usize) -> &[u8] {
+        self.storage.get_v(il, cell)
+    }
+
+    pub fn get_k_f32(&self, il: usize, cell: usize) -> Vec<f32> {
+        self.storage.get_k_f32(il, cell)
+    }
+
+    pub fn get_v_f32(&self, il: usize, cell: usize) -> Vec<f32> {
+        self.storage.get_v_f32(il, cell)
+    }
+
+    /// Whether a query of `seq_id` at position `pos` must not attend to `cell`.
+    pub fn is_masked(&self, cell: usize, seq_id: LlamaSeqId, pos: LlamaPos, causal: bool) -> bool {
+        let c = &self.cells[cell];
+        c.is_empty() || !c.has_seq_id(seq_id) || (causal && c.pos > pos)
+    }
+
+    /// Attention of the query heads `q` of `seq_id` at `pos` (one head or
+    /// the heads sharing `kv_head`) over the cached rows of key/value head
+    /// `kv_head` in layer `il`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn attn(&self, il: usize, kv_head: usize, q: &[f32], seq_id: LlamaSeqId, pos: LlamaPos, causal: bool, scale: f32) -> Vec<f32> {
+        let slots: Vec<usize> = (0..self.cells.len()).collect();
+        let mask: Vec<f32> = slots
+            .iter()
+            .map(|&i| {
+                let masked = self.is_masked(i, seq_id, pos, causal) || self.storage.is_masked_swa(il, self.cells[i].pos, pos);
+                if masked { f32::NEG_INFINITY } else { 0.0 }
+            })
+            .collect();
+        self.storage.attn(il, kv_head, q, &slots, &mask, scale)
+    }
+
+    fn state_read_cells(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String> {
+        if seq_id < 0 {
+            self.clear(false);
+        } else {
+            self.seq_rm(seq_id, -1, -1);
+        }
+
+        let n_cells = io.read_u32()? as usize;
+        let mut tokens = Vec::with_capacity(n_cells);
+        let mut deltas = Vec::with_capacity(n_cells);
+        for _ in 0..n_cells {
+            let pos = io.read_i32()?;
+            deltas.push(io.read_i32()?);
+            let seq_ids = if seq_id < 0 {
+                let n_seq_id = io.read_u32()?;
+                (0..n_seq_id).map(|_| io.read_i32()).collect::<Result<Vec<_>, _>>()?
+            } else {
+                vec![seq_id]
+            };
+            tokens.push(LlamaKvUbatchToken { pos, seq_ids });
+        }
+        if n_cells == 0 {
+            // the layer header is written for an empty cache as well
+            return self.storage.state_read(io, &[]);
+        }
+        let cells = self.apply_ubatch(&tokens)?;
+        for (&i, &delta) in cells.iter().zip(&deltas) {
+            self.cells[i].delta = delta;
+            self.has_shift |= delta != 0;
+        }
+        self.storage.state_read(io, &cells)
+    }
+
+    /// Applies pending position shifts to the stored keys. Returns true if any
+    /// keys were re-rotated.
+    pub fn update(&mut self) -> bool {
+        if !self.has_shift {
+            return false;
+        }
+        self.has_shift = false;
+
+        let can_shift = self.can_shift();
+        for i in 0..self.cells.len() {
+            let delta = self.cells[i].delta;
+            if delta != 0 && can_shift {
+                self.storage.shift_k(i, delta);
+            }
+            self.cells[i].delta = 0;
+        }
+        can_shift
+    }
+}
+
+impl LlamaMemory for LlamaKvCache {
+    fn clear(&mut self, data: bool) {
+        self.cells.iter_mut().for_each(|c| *c = LlamaKvCell::empty());
+        self.head = 0;
+        self.used = 0;
+        self.has_shift = false;
+        if data {
+            self.storage.clear();
+        }
+    }
+
+    fn add_ubatch(&mut self, tokens: &[LlamaKvUbatchToken]) -> Result<(), String> {
+        self.apply_ubatch(tokens).map(|_| ())
+    }
+
+    fn seq_rm(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos) -> bool {
+        if seq_id >= 0 && !self.valid_seq(seq_id) {
+            return false;
+        }
+        for i in 0..self.cells.len() {
+            if self.cells[i].is_empty() || !in_range(self.cells[i].pos, p0, p1) {
+                continue;
+            }
+            if seq_id < 0 {
+                self.cells[i].seq = 0;
+            } else if self.cells[i].has_seq_id(seq_id) {
+                self.cells[i].rm_seq(seq_id);
+            } else {
+                continue;
+            }
+            if self.cells[i].is_empty() {
+                self.free_cell(i);
+            }
+        }
+        true
+    }
+
+    fn seq_cp(&mut self, seq_id_src: LlamaSeqId, seq_id_dst: LlamaSeqId, p0: LlamaPos, p1: LlamaPos) {
+        if seq_id_src == seq_id_dst || !self.valid_seq(seq_id_src) || !self.valid_seq(seq_id_dst) {
+            return;
+        }
+        for cell in &mut self.cells {
+            if cell.has_seq_id(seq_id_src) && in_range(cell.pos, p0, p1) {
+                cell.add_seq(seq_id_dst);
+            }
+        }
+    }
+
+    fn seq_keep(&mut self, seq_id: LlamaSeqId) {
+        if !self.valid_seq(seq_id) {
+            return;
+        }
+        for i in 0..self.cells.len() {
+            if self.cells[i].is_empty() {
+                continue;
+            }
+            if self.cells[i].has_seq_id(seq_id) {
+                self.cells[i].seq = 1u64 << seq_id;
+            } else {
+                self.cells[i].seq = 0;
+                self.free_cell(i);
+            }
+        }
+    }
+
+    fn seq_add(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, delta: LlamaPos) {
+        if delta == 0 || !self.valid_seq(seq_id) {
+            return;
+        }
+        for i in 0..self.cells.len() {
+            let cell = &mut self.cells[i];
+            if !cell.has_seq_id(seq_id) || !in_range(cell.pos, p0, p1) {
+                continue;
+            }
+            self.has_shift = true;
+            cell.pos += delta;
+            cell.delta += delta;
+            if cell.pos < 0 {
+                cell.seq = 0;
+                self.free_cell(i);
+            }
+        }
+    }
+
+    fn seq_div(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, d: i32) {
+        if d <= 1 || !self.valid_seq(seq_id) {
+            return;
+        }
+        for cell in &mut self.cells {
+            if !cell.has_seq_id(seq_id) || !in_range(cell.pos, p0, p1) {
+                continue;
+            }
+            let p_old = cell.pos;
+            cell.pos /= d;
+            cell.delta += cell.pos - p_old;
+            if cell.pos != p_old {
+                self.has_shift = true;
+            }
+        }
+    }
+
+    fn seq_pos_min(&self, seq_id: LlamaSeqId) -> LlamaPos {
+        if !self.valid_seq(seq_id) {
+            return -1;
+        }
+        self.cells.iter().filter(|c| c.has_seq_id(seq_id)).map(|c| c.pos).min().unwrap_or(-1)
+    }
+
+    fn seq_pos_max(&self, seq_id: LlamaSeqId) -> LlamaPos {
+        if !self.valid_seq(seq_id) {
+            return -1;
+        }
+        self.cells.iter().filter(|c| c.has_seq_id(seq_id)).map(|c| c.pos).max().unwrap_or(-1)
+    }
+
+    fn can_shift(&self) -> bool {
+        self.storage.rope.is_some()
+    }
+
+    /// Writes the cells (position, pending shift and, for all sequences, the
+    /// sequence ids) followed by their K and V rows.
+    fn state_write(&self, io: &mut dyn LlamaIoWrite, seq_id: LlamaSeqId) -> Result<(), String> {
+        let cells: Vec<usize> = (0..self.cells.len())
+            .filter(|&i| if seq_id < 0 { !self.cells[i].is_empty() } else { self.cells[i].has_seq_id(seq_id) })
+            .collect();
+        io.write_u32(cells.len() as u32)?;
+        for &i in &cells {
+            let cell = &self.cells[i];
+            io.write_i32(cell.pos)?;
+            io.write_i32(cell.delta)?;
+            if seq_id < 0 {
+                let seq_ids: Vec<LlamaSeqId> = (0..self.n_seq_max as LlamaSeqId).filter(|&s| cell.has_seq_id(s)).collect();
+                io.write_u32(seq_ids.len() as u32)?;
+                for s in seq_ids {
+                    io.write_i32(s)?;
+                }
+            }
+        }
+        self.storage.state_write(io, &cells)
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String> {
+        if seq_id >= 0 && !self.valid_seq(seq_id) {
+            return Err(format!("invalid seq_id {} (n_seq_max = {})", seq_id, self.n_seq_max));
+        }
+        let result = self.state_read_cells(io, seq_id);
+        if result.is_err() {
+            if seq_id < 0 {
+                self.clear(false);
+            } else {
+                self.seq_rm(seq_id, -1, -1);
+            }
+        }
+        result
+    }
+}
+
+/// Blocks holding the tokens of one sequence, in order
+#[derive(Debug, Clone, Default)]
+struct LlamaKvBlockTable {
+    blocks: Vec<usize>,
+    n_tokens: usize,
+}
+
+pub struct LlamaKvCachePaged {
+    block_size: usize,
+
+    /// Position of the token in every slot (n_blocks * block_size)
+    pos: Vec<LlamaPos>,
+    /// Position change not yet applied to the keys of every slot
+    delta: Vec<LlamaPos>,
+    /// Number of block tables referencing each block; 0 for free blocks
+    ref_count: Vec<u32>,
+    free_blocks: Vec<usize>,
+    tables: BTreeMap<LlamaSeqId, LlamaKvBlockTable>,
+    has_shift: bool,
+
+    storage: KvStorage,
+}
+
+impl LlamaKvCachePaged {
+    pub fn new(
+        hparams: &LlamaHparams,
+        n_blocks: u32,
+        block_size: u32,
+        type_k: GgmlType,
+        type_v: GgmlType,
+    ) -> Result<Self, String> {
+        if n_blocks == 0 || block_size == 0 {
+            return Err("paged KV cache needs at least one block of at least one token".to_string());
+        }
+        let n_blocks = n_blocks as usize;
+        let block_size = block_size as usize;
+        let n_slots = n_blocks * block_size;
+        Ok(Self {
+            block_size,
+            pos: vec![-1; n_slots],
+            delta: vec![0; n_slots],
+            ref_count: vec![0; n_blocks],
+            // popped from the back, so blocks are handed out in ascending order
+            free_blocks: (0..n_blocks).rev().collect(),
+            tables: BTreeMap::new(),
+            has_shift: false,
+            storage: KvStorage::new(hparams, n_slots, type_k, type_v)?,
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn n_blocks(&self) -> usize {
+        self.ref_count.len()
+    }
+
+    pub fn n_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    /// Blocks needed to hold `n_tokens` tokens of one sequence
+    pub fn blocks_for(&self, n_tokens: usize) -> usize {
+        n_tokens.div_ceil(self.block_size)
+    }
+
+    pub fn seq_n_tokens(&self, seq_id: LlamaSeqId) -> usize {
+        self.tables.get(&seq_id).map_or(0, |t| t.n_tokens)
+    }
+
+    /// Block table of `seq_id`
+    pub fn seq_blocks(&self, seq_id: LlamaSeqId) -> &[usize] {
+        self.tables.get(&seq_id).map_or(&[], |t| t.blocks.as_slice())
+    }
+
+    pub fn block_ref_count(&self, block: usize) -> u32 {
+        self.ref_count[block]
+    }
+
+    pub fn has_shift(&self) -> bool {
+        self.has_shift
+    }
+
+    pub fn type_k(&self) -> GgmlType {
+        self.storage.type_k
+    }
+
+    pub fn type_v(&self) -> GgmlType {
+        self.storage.type_v
+    }
+
+    pub fn memory_size(&self) -> (usize, usize) {
+        self.storage.memory_size()
+    }
+
+    /// Bytes of K and V held by one block
+    pub fn block_memory_size(&self) -> usize {
+        let (k, v) = self.storage.memory_size();
+        (k + v) / self.n_blocks()
+    }
+
+    pub fn set_rope(&mut self, rope: Option<RopeParams>) {
+        self.storage.rope = rope;
+    }
+
+    pub fn set_flash_attn(&mut self, flash_attn: bool) {
+        self.storage.flash_attn = flash_attn;
+    }
+
+    pub fn flash_attn(&self) -> bool {
+        self.storage.flash_attn
+    }
+
+    /// Slot holding token `idx` of a sequence
+    fn slot(&self, table: &LlamaKvBlockTable, idx: usize) -> usize {
+        table.blocks[idx / self.block_size] * self.block_size + idx % self.block_size
+    }
+
+    fn seq_slots(&self, seq_id: LlamaSeqId) -> Vec<usize> {
+        match self.tables.get(&seq_id) {
+            Some(table) => (0..table.n_tokens).map(|i| self.slot(table, i)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn alloc_block(&mut self) -> Result<usize, String> {
+        let block = self.free_blocks.pop().ok_or("out of KV cache blocks")?;
+        self.ref_count[block] = 1;
+        Ok(block)
+    }
+
+    fn release_block(&mut self, block: usize) {
+        self.ref_count[block] -= 1;
+        if self.ref_count[block] == 0 {
+            self.free_blocks.push(block);
+        }
+    }
+
+    fn release_table(&mut self, table: LlamaKvBlockTable) {
+        for block in table.blocks {
+            self.release_block(block);
+        }
+    }
+
+    /// Gives `seq_id` its own copy of block `bi` of its table if the block is shared.
+    fn make_writable(&mut self, seq_id: LlamaSeqId, bi: usize) -> Result<(), String> {
+        let (old, n_tokens) = {
+            let table = &self.tables[&seq_id];
+            (table.blocks[bi], table.n_tokens)
+        };
+        if self.ref_count[old] <= 1 {
+            return Ok(());
+        }
+        let new = self.alloc_block()?;
+        let n_filled = n_tokens.saturating_sub(bi * self.block_size).min(self.block_size);
+        for i in 0..n_filled {
+            let (src, dst) = (old * self.block_size + i, new * self.block_size + i);
+            self.storage.copy_slot(src, dst);
+            self.pos[dst] = self.pos[src];
+            self.delta[dst] = self.delta[src];
+        }
+        self.release_block(old);
+        self.tables.get_mut(&seq_id).unwrap().blocks[bi] = new;
+        Ok(())
+    }
+
+    /// Appends a token at `pos` to `seq_id` and returns its slot. A new block is
+    /// taken from the free list when the last one is full; a shared last block
+    /// is copied first.
+    pub fn append(&mut self, seq_id: LlamaSeqId, pos: LlamaPos) -> Result<usize, String> {
+        if seq_id < 0 {
+            return Err(format!("invalid seq_id {}", seq_id));
+        }
+        let idx = self.seq_n_tokens(seq_id);
+        if idx.is_multiple_of(self.block_size) {
+            let block = self.alloc_block()?;
+            self.tables.entry(seq_id).or_default().blocks.push(block);
+        } else {
+            self.make_writable(seq_id, idx / self.block_size)?;
+        }
+        let table = self.tables.get_mut(&seq_id).unwrap();
+        table.n_tokens += 1;
+        let slot = self.slot(&self.tables[&seq_id], idx);
+        self.pos[slot] = pos;
+        self.delta[slot] = 0;
+        Ok(slot)
+    }
+
+    pub fn cpy_k(&mut self, il: usize, slot: usize, k: &[f32]) {
+        self.storage.cpy_k(il, slot, k);
+    }
+
+    pub fn cpy_v(&mut self, il: usize, slot: usize, v: &[f32]) {
+        self.storage.cpy_v(il, slot, v);
+    }
+
+    pub fn get_k_f32(&self, il: usize, slot: usize) -> Vec<f32> {
+        self.storage.get_k_f32(il, slot)
+    }
+
+    pub fn get_v_f32(&self, il: usize, slot: usize) -> Vec<f32> {
+        self.storage.get_v_f32(il, slot)
+    }
+
+    /// Attention of the query heads `q` of `seq_id` at `pos` over the
+    /// sequence's cached rows of key/value head `kv_head` in layer `il`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn attn(&self, il: usize, kv_head: usize, q: &[f32], seq_id: LlamaSeqId, pos: LlamaPos, causal: bool, scale: f32) -> Vec<f32> {
+        let slots = self.seq_slots(seq_id);
+        let mask: Vec<f32> = slots
+            .iter()
+            .map(|&s| {
+                let masked = (causal && self.pos[s] > pos) || self.storage.is_masked_swa(il, self.pos[s], pos);
+                if masked { f32::NEG_INFINITY } else { 0.0 }
+            })
+            .collect();
+        self.storage.attn(il, kv_head, q, &slots, &mask, scale)
+    }
+
+    /// Copies the tokens of `seq_id_src` in [p0, p1) to `seq_id_dst`, replacing
+    /// its previous contents. When the copied tokens are a prefix of the source,
+    /// their blocks are shared instead of copied.
+    pub fn try_seq_cp(&mut self, seq_id_src: LlamaSeqId, seq_id_dst: LlamaSeqId, p0: LlamaPos, p1: LlamaPos) -> Result<(), String> {
+        if seq_id_src == seq_id_dst || seq_id_dst < 0 {
+            return Ok(());
+        }
+        if let Some(table) = self.tables.remove(&seq_id_dst) {
+            self.release_table(table);
+        }
+        let src_slots = self.seq_slots(seq_id_src);
+        let selected: Vec<usize> = src_slots.iter().copied().filter(|&s| in_range(self.pos[s], p0, p1)).collect();
+        if selected.is_empty() {
+            return Ok(());
+        }
+
+        if selected[..] == src_slots[..selected.len()] {
+            let n_tokens = selected.len();
+            let blocks = self.tables[&seq_id_src].blocks[..self.blocks_for(n_tokens)].to_vec();
+            for &block in &blocks {
+                self.ref_count[block] += 1;
+            }
+            self.tables.insert(seq_id_dst, LlamaKvBlockTable { blocks, n_tokens });
+            return Ok(());
+        }
+
+        for src in selected {
+            match self.append(seq_id_dst, self.pos[src]) {
+                Ok(dst) => {
+                    self.storage.copy_slot(src, dst);
+                    self.delta[dst] = self.delta[src];
+                }
+                Err(e) => {
+                    if let Some(table) = self.tables.remove(&seq_id_dst) {
+                        self.release_table(table);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `delta` to the positions [p0, p1) of `seq_id`. Fails with the
+    /// cache unchanged when shared blocks would need more free blocks than
+    /// there are.
+    pub fn try_seq_add(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, delta: LlamaPos) -> Result<(), String> {
+        if delta == 0 {
+            return Ok(());
+        }
+        self.seq_update_pos(seq_id, p0, p1, |p| p + delta)
+    }
+
+    /// Integer-divides the positions [p0, p1) of `seq_id` by `d`; fails like
+    /// `try_seq_add`.
+    pub fn try_seq_div(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, d: i32) -> Result<(), String> {
+        if d <= 1 {
+            return Ok(());
+        }
+        self.seq_update_pos(seq_id, p0, p1, |p| p / d)
+    }
+
+    /// Number of the given blocks of `seq_id`'s table (by index) that are
+    /// shared, i.e. the free blocks needed to write to all of them.
+    fn n_shared_blocks(&self, seq_id: LlamaSeqId, block_idxs: impl IntoIterator<Item = usize>) -> usize {
+        let Some(table) = self.tables.get(&seq_id) else {
+            return 0;
+        };
+        let mut block_idxs: Vec<usize> = block_idxs.into_iter().collect();
+        block_idxs.sort_unstable();
+        block_idxs.dedup();
+        block_idxs.into_iter().filter(|&bi| self.ref_count[table.blocks[bi]] > 1).count()
+    }
+
+    /// Blocks (by table index) that compacting a sequence with token
+    /// `positions` writes when the tokens matching `remove` are dropped.
+    fn compaction_blocks(&self, positions: &[LlamaPos], remove: impl Fn(LlamaPos) -> bool) -> std::ops::Range<usize> {
+        let Some(first) = positions.iter().position(|&p| remove(p)) else {
+            return 0..0;
+        };
+        let n_kept = positions.iter().filter(|&&p| !remove(p)).count();
+        if first >= n_kept {
+            return 0..0;
+        }
+        first / self.block_size..(n_kept - 1) / self.block_size + 1
+    }
+
+    /// Free blocks `seq_rm_where` needs to remove the tokens of `seq_id`
+    /// matching `remove`
+    fn rm_blocks_needed(&self, seq_id: LlamaSeqId, remove: impl Fn(LlamaPos) -> bool) -> usize {
+        let positions: Vec<LlamaPos> = self.seq_slots(seq_id).into_iter().map(|s| self.pos[s]).collect();
+        self.n_shared_blocks(seq_id, self.compaction_blocks(&positions, remove))
+    }
+
+    /// Applies `f` to the position and pending delta of the tokens of `seq_id`
+    /// in [p0, p1), copying shared blocks first. Tokens moved below position 0
+    /// are removed. The free blocks needed for the copies are checked up
+    /// front, so on error nothing has changed.
+    fn seq_update_pos(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, f: impl Fn(LlamaPos) -> LlamaPos) -> Result<(), String> {
+        let positions: Vec<LlamaPos> = self.seq_slots(seq_id).into_iter().map(|s| self.pos[s]).collect();
+        let changed: Vec<usize> = (0..positions.len()).filter(|&i| in_range(positions[i], p0, p1) && f(positions[i]) != positions[i]).collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let mut updated = positions.clone();
+        for &idx in &changed {
+            updated[idx] = f(positions[idx]);
+        }
+        let written = changed.iter().map(|&idx| idx / self.block_size).chain(self.compaction_blocks(&updated, |pos| pos < 0));
+        let n_needed = self.n_shared_blocks(seq_id, written);
+        if n_needed > self.free_blocks.len() {
+            return Err(format!(
+                "out of KV cache blocks: moving positions of sequence {} needs {} free blocks, {} available",
+                seq_id,
+                n_needed,
+                self.free_blocks.len()
+            ));
+        }
+
+        for idx in changed {
+            self.make_writable(seq_id, idx / self.block_size)?;
+            let slot = self.slot(&self.tables[&seq_id], idx);
+            let p_old = self.pos[slot];
+            let p_new = f(p_old);
+            self.pos[slot] = p_new;
+            self.delta[slot] += p_new - p_old;
+            self.has_shift = true;
+        }
+        if !self.seq_rm_where(seq_id, |pos| pos < 0) {
+            return Err(format!("failed to remove the tokens of sequence {} moved below position 0", seq_id));
+        }
+        Ok(())
+    }
+
+    /// Removes the tokens of `seq_id` whose position matches `remove` and
+    /// compacts the sequence so that its remaining tokens stay contiguous in
+    /// its blocks; emptied blocks are freed. Returns false, with nothing
+    /// changed, if copying the shared blocks it writes needs more free blocks
+    /// than there are.
+    fn seq_rm_where(&mut self, seq_id: LlamaSeqId, remove: impl Fn(LlamaPos) -> bool) -> bool {
+        if self.rm_blocks_needed(seq_id, &remove) > self.free_blocks.len() {
+            return false;
+        }
+        let n_tokens = self.seq_n_tokens(seq_id);
+        let mut n_kept = 0;
+        for idx in 0..n_tokens {
+            let src = self.slot(&self.tables[&seq_id], idx);
+            if remove(self.pos[src]) {
+                continue;
+            }
+            if n_kept != idx {
+                if self.make_writable(seq_id, n_kept / self.block_size).is_err() {
+                    return false;
+                }
+                let table = &self.tables[&seq_id];
+                let (src, dst) = (self.slot(table, idx), self.slot(table, n_kept));
+                self.storage.copy_slot(src, dst);
+                self.pos[dst] = self.pos[src];
+                self.delta[dst] = self.delta[src];
+            }
+            n_kept += 1;
+        }
+        if n_kept == n_tokens {
+            return true;
+        }
+
+        let n_blocks = self.blocks_for(n_kept);
+        let table = self.tables.get_mut(&seq_id).unwrap();
+        table.n_tokens = n_kept;
+        let released: Vec<usize> = table.blocks.drain(n_blocks..).collect();
+        if n_kept == 0 {
+            self.tables.remove(&seq_id);
+        }
+        for block in released {
+            self.release_block(block);
+        }
+        true
+    }
+
+    /// Reads one sequence written by `state_write` into the (empty) `seq_id`.
+    fn state_read_seq(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String> {
+        let result = (|| {
+            let n_tokens = io.read_u32()?;
+            let mut slots = Vec::with_capacity(n_tokens as usize);
+            for _ in 0..n_tokens {
+                let pos = io.read_i32()?;
+                let delta = io.read_i32()?;
+                let slot = self.append(seq_id, pos)?;
+                self.delta[slot] = delta;
+                self.has_shift |= delta != 0;
+                slots.push(slot);
+            }
+            self.storage.state_read(io, &slots)
+        })();
+        if result.is_err() {
+            self.seq_rm(seq_id, -1, -1);
+        }
+        result
+    }
+
+    /// Applies pending position shifts to the stored keys. Returns true if any
+    /// keys were re-rotated.
+    pub fn update(&mut self) -> bool {
+        if !self.has_shift {
+            return false;
+        }
+        self.has_shift = false;
+
+        let can_shift = self.can_shift();
+        for slot in 0..self.pos.len() {
+            let delta = self.delta[slot];
+            if delta != 0 && can_shift && self.ref_count[slot / self.block_size] > 0 {
+                self.storage.shift_k(slot, delta);
+            }
+            self.delta[slot] = 0;
+        }
+        can_shift
+    }
+}
+
+impl LlamaMemory for LlamaKvCachePaged {
+    fn clear(&mut self, data: bool) {
+        self.tables.clear();
+        self.ref_count.fill(0);
+        self.free_blocks = (0..self.n_blocks()).rev().collect();
+        self.pos.fill(-1);
+        self.delta.fill(0);
+        self.has_shift = false;
+        if data {
+            self.storage.clear();
+        }
+    }
+
+    /// A token of several sequences takes a slot in each of them.
+    fn add_ubatch(&mut self, tokens: &[LlamaKvUbatchToken]) -> Result<(), String> {
+        for token in tokens {
+            for &seq_id in &token.seq_ids {
+                self.append(seq_id, token.pos)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn seq_rm(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos) -> bool {
+        if seq_id < 0 {
+            // check every sequence first so that none is changed on failure
+            let seq_ids: Vec<LlamaSeqId> = self.tables.keys().copied().collect();
+            let n_needed: usize = seq_ids.iter().map(|&s| self.rm_blocks_needed(s, |pos| in_range(pos, p0, p1))).sum();
+            if n_needed > self.free_blocks.len() {
+                return false;
+            }
+            return seq_ids.into_iter().all(|s| self.seq_rm_where(s, |pos| in_range(pos, p0, p1)));
+        }
+        self.seq_rm_where(seq_id, |pos| in_range(pos, p0, p1))
+    }
+
+    fn seq_cp(&mut self, seq_id_src: LlamaSeqId, seq_id_dst: LlamaSeqId, p0: LlamaPos, p1: LlamaPos) {
+        // on failure the destination is left empty
+        let _ = self.try_seq_cp(seq_id_src, seq_id_dst, p0, p1);
+    }
+
+    fn seq_keep(&mut self, seq_id: LlamaSeqId) {
+        let others: Vec<LlamaSeqId> = self.tables.keys().copied().filter(|&s| s != seq_id).collect();
+        for s in others {
+            let table = self.tables.remove(&s).unwrap();
+            self.release_table(table);
+        }
+    }
+
+    fn seq_add(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, delta: LlamaPos) {
+        // on failure the sequence is left unchanged
+        let _ = self.try_seq_add(seq_id, p0, p1, delta);
+    }
+
+    fn seq_div(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, d: i32) {
+        let _ = self.try_seq_div(seq_id, p0, p1, d);
+    }
+
+    fn seq_pos_min(&self, seq_id: LlamaSeqId) -> LlamaPos {
+        self.seq_slots(seq_id).into_iter().map(|s| self.pos[s]).min().unwrap_or(-1)
+    }
+
+    fn seq_pos_max(&self, seq_id: LlamaSeqId) -> LlamaPos {
+        self.seq_slots(seq_id).into_iter().map(|s| self.pos[s]).max().unwrap_or(-1)
+    }
+
+    fn can_shift(&self) -> bool {
+        self.storage.rope.is_some()
+    }
+
+    /// Writes each sequence as its token count, the position and pending
+    /// shift of every token and their K and V rows; for all sequences, the
+    /// sequence count and ids are written too. Shared blocks are written once
+    /// per sequence and restored unshared.
+    fn state_write(&self, io: &mut dyn LlamaIoWrite, seq_id: LlamaSeqId) -> Result<(), String> {
+        let seq_ids: Vec<LlamaSeqId> = if seq_id < 0 { self.tables.keys().copied().collect() } else { vec![seq_id] };
+        if seq_id < 0 {
+            io.write_u32(seq_ids.len() as u32)?;
+        }
+        for s in seq_ids {
+            if seq_id < 0 {
+                io.write_i32(s)?;
+            }
+            let slots = self.seq_slots(s);
+            io.write_u32(slots.len() as u32)?;
+            for &slot in &slots {
+                io.write_i32(self.pos[slot])?;
+                io.write_i32(self.delta[slot])?;
+            }
+            self.storage.state_write(io, &slots)?;
+        }
+        Ok(())
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String> {
+        if seq_id >= 0 {
+            self.seq_rm(seq_id, -1, -1);
+            return self.state_read_seq(io, seq_id);
+        }
+        self.clear(false);
+        let n_seqs = io.read_u32()?;
+        for _ in 0..n_seqs {
+            let result = io.read_i32().and_then(|s| {
+                if s < 0 {
+                    return Err(format!("invalid seq_id {} in KV state", s));
+                }
+                self.state_read_seq(io, s)
+            });
+            if result.is_err() {
+                self.clear(false);
+                return result;
+            }
+        }
+        Ok(())
+    }
 }
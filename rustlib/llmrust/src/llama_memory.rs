@@ -1,6 +1,82 @@
-// src/llama_memory.rs - Auto-generated debug file
+// src/llama_memory.rs - Sequence memory interface
+//
+// The memory of a context stores the per-token state (for transformers, the
+// KV cache) of one or more sequences. Positions are per sequence; ranges are
+// half-open [p0, p1), where a negative p0 means 0 and a negative p1 means
+// infinity. A negative seq_id addresses every sequence.
+
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_memory.rs - File loaded successfully");
+use crate::llmrust::src::llama_io::{LlamaIoRead, LlamaIoWrite};
+use crate::llmrust::src::llama_kv_cache::LlamaKvUbatchToken;
+
+pub type LlamaPos = i32;
+pub type LlamaSeqId = i32;
+
+pub trait LlamaMemory: Send {
+    /// Removes all sequences; when `data` is true the stored tensors are zeroed too.
+    fn clear(&mut self, data: bool);
+
+    /// Stores the tokens of a micro-batch in their sequences. Fails when
+    /// there is no room for them.
+    fn add_ubatch(&mut self, tokens: &[LlamaKvUbatchToken]) -> Result<(), String>;
+
+    /// Removes the positions [p0, p1) of `seq_id`. Returns false if the
+    /// request cannot be honored (e.g. an invalid sequence id).
+    fn seq_rm(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos) -> bool;
+
+    /// Makes the positions [p0, p1) of `seq_id_src` also belong to `seq_id_dst`.
+    fn seq_cp(&mut self, seq_id_src: LlamaSeqId, seq_id_dst: LlamaSeqId, p0: LlamaPos, p1: LlamaPos);
+
+    /// Removes every sequence except `seq_id`.
+    fn seq_keep(&mut self, seq_id: LlamaSeqId);
+
+    /// Adds `delta` to the positions [p0, p1) of `seq_id`.
+    fn seq_add(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, delta: LlamaPos);
+
+    /// Integer-divides the positions [p0, p1) of `seq_id` by `d`.
+    fn seq_div(&mut self, seq_id: LlamaSeqId, p0: LlamaPos, p1: LlamaPos, d: i32);
+
+    /// Smallest position of `seq_id`, or -1 if the sequence is empty.
+    fn seq_pos_min(&self, seq_id: LlamaSeqId) -> LlamaPos;
+
+    /// Largest position of `seq_id`, or -1 if the sequence is empty.
+    fn seq_pos_max(&self, seq_id: LlamaSeqId) -> LlamaPos;
+
+    /// Whether positions can be shifted (seq_add / seq_div).
+    fn can_shift(&self) -> bool;
+
+    /// Serializes the tokens of `seq_id`, or of every sequence if it is negative.
+    fn state_write(&self, io: &mut dyn LlamaIoWrite, seq_id: LlamaSeqId) -> Result<(), String>;
+
+    /// Restores state written by `state_write` into `seq_id`, replacing its
+    /// tokens; with a negative `seq_id` the whole memory is replaced. On error
+    /// the affected sequences are left empty.
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead, seq_id: LlamaSeqId) -> Result<(), String>;
+}
+
+/// Handle passed across the C API: a thin pointer to a boxed memory object.
+pub type LlamaMemoryHandle = Box<dyn LlamaMemory>;
+
+/// Creates a C handle owning `memory`; release it with `free_handle`.
+pub fn into_handle(memory: LlamaMemoryHandle) -> *mut std::os::raw::c_void {
+    Box::into_raw(Box::new(memory)) as *mut std::os::raw::c_void
+}
+
+/// Borrows the memory behind a C handle.
+///
+/// # Safety
+/// `mem` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn from_handle<'a>(mem: *mut std::os::raw::c_void) -> Option<&'a mut LlamaMemoryHandle> {
+    (mem as *mut LlamaMemoryHandle).as_mut()
+}
+
+/// Releases a handle created by `into_handle`.
+///
+/// # Safety
+/// `mem` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn free_handle(mem: *mut std::os::raw::c_void) {
+    if !mem.is_null() {
+        drop(Box::from_raw(mem as *mut LlamaMemoryHandle));
+    }
 }
@@ -1,6 +1,188 @@
-// src/llama_model.rs - Auto-generated debug file
+// src/llama_model.rs - Model hyperparameters derived from GGUF metadata
+//
+// Everything here reads the metadata through the model loader, so the
+// `kv_overrides` of the model params are already in effect: an override of
+// `llama.expert_used_count` or `llama.rope.scaling.factor` changes the
+// hyperparameters exactly as if the file had held that value. The C API hands
+// the result out as a `LlamaModel` handle, together with the tensor data as
+// placed by the loader, so contexts and adapters created from it see the same
+// hyperparameters.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_model.rs - File loaded successfully");
+use std::os::raw::c_void;
+
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::src::llama_arch::*;
+use crate::llmrust::src::llama_hparams::{LlamaHparams, LlamaPoolingType, LlamaRopeScalingType, LlamaRopeType};
+use crate::llmrust::src::llama_model_loader::{LlamaModelLoader, LlamaTensorData};
+
+/// What the model file says about the model, after overrides
+#[derive(Debug, Clone)]
+pub struct LlamaModelInfo {
+    pub arch: String,
+    pub hparams: LlamaHparams,
+    /// Whether tokenization adds a BOS token (`tokenizer.ggml.add_bos_token`)
+    pub add_bos: bool,
+    pub add_eos: bool,
+    /// Separator token of cross-encoder inputs (`tokenizer.ggml.seperator_token_id`)
+    pub sep_token: Option<i32>,
+}
+
+/// A loaded model behind a C `llama_model` handle
+#[derive(Debug, Clone)]
+pub struct LlamaModel {
+    pub info: LlamaModelInfo,
+    /// Tensor data by name, in the buffer each tensor was placed in
+    pub tensors: Vec<(String, LlamaTensorData)>,
+}
+
+impl LlamaModel {
+    pub fn hparams(&self) -> &LlamaHparams {
+        &self.info.hparams
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&LlamaTensorData> {
+        self.tensors.iter().find(|(n, _)| n == name).map(|(_, data)| data)
+    }
+}
+
+/// Creates a C handle owning `model`; release it with `free_handle`.
+pub fn into_handle(model: LlamaModel) -> *mut c_void {
+    Box::into_raw(Box::new(model)) as *mut c_void
+}
+
+/// Borrows the model behind a C handle.
+///
+/// # Safety
+/// `model` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn from_handle<'a>(model: *mut c_void) -> Option<&'a LlamaModel> {
+    (model as *const LlamaModel).as_ref()
+}
+
+/// Releases a handle created by `into_handle`.
+///
+/// # Safety
+/// `model` must be null or a pointer returned by `into_handle` that has not been freed.
+pub unsafe fn free_handle(model: *mut c_void) {
+    if !model.is_null() {
+        drop(Box::from_raw(model as *mut LlamaModel));
+    }
+}
+
+pub fn llama_model_load_info(ml: &LlamaModelLoader) -> Result<LlamaModelInfo, String> {
+    let arch = ml.arch_name()?;
+    let hparams = llama_model_load_hparams(ml, &arch)?;
+    let add_bos = ml.get_bool(LLM_KV_TOKENIZER_ADD_BOS)?.unwrap_or(true);
+    let add_eos = ml.get_bool(LLM_KV_TOKENIZER_ADD_EOS)?.unwrap_or(false);
+    let sep_token = ml.get_u32(LLM_KV_TOKENIZER_SEP_ID)?.map(|t| t as i32);
+    Ok(LlamaModelInfo { arch, hparams, add_bos, add_eos, sep_token })
+}
+
+pub fn llama_model_load_hparams(ml: &LlamaModelLoader, arch: &str) -> Result<LlamaHparams, String> {
+    let key = |kv: &str| llm_kv(kv, arch);
+    let required = |kv: &str| -> Result<u32, String> {
+        let key = key(kv);
+        ml.get_u32(&key)?.ok_or_else(|| format!("missing {}", key))
+    };
+    let mut hp = LlamaHparams {
+        n_ctx_train: required(LLM_KV_CONTEXT_LENGTH)?,
+        n_embd: required(LLM_KV_EMBEDDING_LENGTH)?,
+        n_layer: required(LLM_KV_BLOCK_COUNT)?,
+        n_ff: required(LLM_KV_FEED_FORWARD_LENGTH)?,
+        n_head: required(LLM_KV_ATTENTION_HEAD_COUNT)?,
+        ..Default::default()
+    };
+    // the vocab size key is optional; the token list always has it
+    hp.n_vocab = match ml.get_u32(&key(LLM_KV_VOCAB_SIZE))? {
+        Some(n) => n,
+        None => ml.get_arr_n(LLM_KV_TOKENIZER_LIST).unwrap_or(0) as u32,
+    };
+    hp.n_head_kv = ml.get_u32(&key(LLM_KV_ATTENTION_HEAD_COUNT_KV))?.unwrap_or(hp.n_head);
+    if hp.n_head == 0 || hp.n_head_kv == 0 || !hp.n_head.is_multiple_of(hp.n_head_kv) {
+        return Err(format!("invalid head counts: {} heads, {} key/value heads", hp.n_head, hp.n_head_kv));
+    }
+
+    let n_embd_head = hp.n_embd / hp.n_head;
+    hp.n_embd_head_k = ml.get_u32(&key(LLM_KV_ATTENTION_KEY_LENGTH))?.unwrap_or(n_embd_head);
+    hp.n_embd_head_v = ml.get_u32(&key(LLM_KV_ATTENTION_VALUE_LENGTH))?.unwrap_or(n_embd_head);
+    hp.n_rot = ml.get_u32(&key(LLM_KV_ROPE_DIMENSION_COUNT))?.unwrap_or(hp.n_embd_head_k);
+    if hp.n_rot > hp.n_embd_head_k {
+        return Err(format!("{} rotated dimensions exceed the head size {}", hp.n_rot, hp.n_embd_head_k));
+    }
+
+    hp.n_expert = ml.get_u32(&key(LLM_KV_EXPERT_COUNT))?.unwrap_or(0);
+    hp.n_expert_used = ml.get_u32(&key(LLM_KV_EXPERT_USED_COUNT))?.unwrap_or(0);
+    if hp.n_expert_used > hp.n_expert || (hp.n_expert > 0 && hp.n_expert_used == 0) {
+        return Err(format!("invalid expert counts: {} used of {}", hp.n_expert_used, hp.n_expert));
+    }
+
+    hp.n_swa = ml.get_u32(&key(LLM_KV_ATTENTION_SLIDING_WINDOW))?.unwrap_or(0);
+    hp.n_swa_pattern = ml.get_u32(&key(LLM_KV_ATTENTION_SLIDING_WINDOW_PATTERN))?.unwrap_or(0);
+
+    hp.rope_type = LlamaRopeType::Norm;
+    if let Some(base) = ml.get_f32(&key(LLM_KV_ROPE_FREQ_BASE))? {
+        hp.rope_freq_base_train = base;
+    }
+    // the scaling factor supersedes the older linear scale key
+    let factor = match ml.get_f32(&key(LLM_KV_ROPE_SCALING_FACTOR))? {
+        Some(factor) => Some(factor),
+        None => ml.get_f32(&key(LLM_KV_ROPE_SCALE_LINEAR))?,
+    };
+    hp.rope_freq_scale_train = match factor {
+        Some(factor) if factor > 0.0 => 1.0 / factor,
+        _ => 1.0,
+    };
+    if let Some(name) = ml.get_str(&key(LLM_KV_ROPE_SCALING_TYPE))? {
+        hp.rope_scaling_type_train =
+            LlamaRopeScalingType::from_name(&name).ok_or_else(|| format!("unknown rope scaling type '{}'", name))?;
+    }
+    hp.n_ctx_orig_yarn = ml.get_u32(&key(LLM_KV_ROPE_SCALING_ORIG_CTX_LEN))?.unwrap_or(hp.n_ctx_train);
+    hp.rope_factors_short = llama_model_load_rope_factors(ml, LLM_TENSOR_ROPE_FACTORS_SHORT, hp.n_rot)?;
+    hp.rope_factors_long = llama_model_load_rope_factors(ml, LLM_TENSOR_ROPE_FACTORS_LONG, hp.n_rot)?;
+    hp.rope_attn_factor = match ml.get_f32(&key(LLM_KV_ROPE_SCALING_ATTN_FACTOR))? {
+        Some(attn_factor) => attn_factor,
+        // LongRoPE keeps attention entropy with sqrt(1 + ln(s) / ln(n_ctx_orig))
+        None if hp.rope_scaling_type_train == LlamaRopeScalingType::LongRope && hp.n_ctx_train > hp.n_ctx_orig_yarn => {
+            let s = hp.n_ctx_train as f32 / hp.n_ctx_orig_yarn as f32;
+            (1.0 + s.ln() / (hp.n_ctx_orig_yarn as f32).ln()).sqrt()
+        }
+        None => 1.0,
+    };
+    if hp.rope_scaling_type_train == LlamaRopeScalingType::LongRope
+        && (hp.rope_factors_short.is_none() || hp.rope_factors_long.is_none())
+    {
+        return Err(format!("longrope scaling needs {} and {}", LLM_TENSOR_ROPE_FACTORS_SHORT, LLM_TENSOR_ROPE_FACTORS_LONG));
+    }
+
+    if let Some(eps) = ml.get_f32(&key(LLM_KV_ATTENTION_LAYERNORM_RMS_EPS))? {
+        hp.f_norm_rms_eps = eps;
+    }
+    hp.causal_attn = ml.get_bool(&key(LLM_KV_ATTENTION_CAUSAL))?.unwrap_or(!llm_arch_is_encoder(arch));
+    if let Some(pooling) = ml.get_i64(&key(LLM_KV_POOLING_TYPE))? {
+        hp.pooling_type = i32::try_from(pooling)
+            .ok()
+            .and_then(LlamaPoolingType::from_raw)
+            .ok_or_else(|| format!("unknown pooling type {}", pooling))?;
+    }
+    Ok(hp)
+}
+
+/// LongRoPE frequency factors stored as tensor `name`, one per rotated pair
+fn llama_model_load_rope_factors(ml: &LlamaModelLoader, name: &str, n_rot: u32) -> Result<Option<Vec<f32>>, String> {
+    let Some(t) = ml.gguf().tensor(name) else {
+        return Ok(None);
+    };
+    if t.ty != GgmlType::F32 || t.dims != [n_rot as usize / 2] {
+        return Err(format!("{} must hold {} f32 values, got {} {:?}", name, n_rot / 2, t.ty.name(), t.dims));
+    }
+    let factors: Vec<f32> = ml
+        .gguf()
+        .read_tensor_data(t)?
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    if factors.iter().any(|&f| f <= 0.0) {
+        return Err(format!("{} must hold positive factors", name));
+    }
+    Ok(Some(factors))
 }
@@ -1,6 +1,380 @@
-// src/llama_model_loader.rs - Auto-generated debug file
+// src/llama_model_loader.rs - GGUF metadata access for model loading
+//
+// The loader wraps the metadata of a model file together with the
+// `kv_overrides` of the model params. Every typed getter looks at the
+// overrides first, so an override replaces the file's value (or supplies a
+// missing one) before anything is derived from it. An override must have
+// the type the key is read as; a mismatch is an error rather than a silent
+// conversion, since it almost always means a typo in the override spec.
+//
+// The `tensor_buft_overrides` of the model params decide where tensor data
+// goes: the first override whose regex is found in a tensor name picks its
+// buffer type, and tensors no override matches get the default of the load.
+// With `use_extra_bufts` that default is the repack buffer for the weights
+// it can hold, whose data is rearranged into interleaved layouts as it is
+// loaded. Otherwise it is the mapped file when the file was memory mapped,
+// where the data is used in place, and a copy in host memory when not.
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_model_loader.rs - File loaded successfully");
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use crate::llmrust::ggml::src::ggml_backend::GgmlBackendBufferType;
+use crate::llmrust::ggml::src::ggml_cpu::repack::{repack, RepackLayout};
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufMmap, GgufValue};
+use crate::llmrust::src::llama_arch::LLM_KV_GENERAL_ARCHITECTURE;
+use crate::llmrust::src::llama_regex::RegexDfa;
+
+/// Longest key or string value an override can hold; the C struct stores
+/// both in 128-byte NUL-terminated buffers
+pub const LLAMA_KV_OVERRIDE_MAX_LEN: usize = 127;
+
+/// Value of a metadata override (`llama_model_kv_override_type`; the
+/// discriminants are the C API tags)
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlamaModelKvOverrideValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl LlamaModelKvOverrideValue {
+    pub fn tag(&self) -> i32 {
+        match self {
+            Self::Int(_) => 0,
+            Self::Float(_) => 1,
+            Self::Bool(_) => 2,
+            Self::Str(_) => 3,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "int",
+            Self::Float(_) => "float",
+            Self::Bool(_) => "bool",
+            Self::Str(_) => "str",
+        }
+    }
+}
+
+impl fmt::Display for LlamaModelKvOverrideValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{}", v),
+            Self::Bool(v) => write!(f, "{}", v),
+            Self::Str(v) => write!(f, "'{}'", v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaModelKvOverride {
+    pub key: String,
+    pub value: LlamaModelKvOverrideValue,
+}
+
+/// The `key=type:value` spec the override is parsed from
+impl fmt::Display for LlamaModelKvOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            LlamaModelKvOverrideValue::Str(v) => write!(f, "{}=str:{}", self.key, v),
+            v => write!(f, "{}={}:{}", self.key, v.type_name(), v),
+        }
+    }
+}
+
+/// Tensors whose name contains a match of `pattern` are stored in `buft`
+#[derive(Debug, Clone)]
+pub struct LlamaModelTensorBuftOverride {
+    pub pattern: String,
+    pub buft: GgmlBackendBufferType,
+    regex: RegexDfa,
+}
+
+impl LlamaModelTensorBuftOverride {
+    pub fn new(pattern: &str, buft: GgmlBackendBufferType) -> Result<Self, String> {
+        let regex = RegexDfa::compile_search(pattern).map_err(|e| format!("tensor override pattern: {}", e))?;
+        Ok(Self { pattern: pattern.to_string(), buft, regex })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.regex.matches(name)
+    }
+}
+
+/// Where one tensor of the model goes
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaTensorPlacement {
+    pub name: String,
+    pub n_bytes: usize,
+    pub buft: GgmlBackendBufferType,
+    /// Index of the override that chose `buft`, `None` for the default
+    pub override_idx: Option<usize>,
+}
+
+/// Memory holding the data of a placed tensor
+#[derive(Debug, Clone)]
+pub enum LlamaTensorBuffer {
+    /// Anonymous host memory
+    Host(Vec<u8>),
+    /// A range of the mapped model file
+    Mapped(Arc<GgufMmap>, Range<usize>),
+}
+
+impl Deref for LlamaTensorBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Host(data) => data,
+            Self::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+/// Data of a placed tensor
+#[derive(Debug, Clone)]
+pub struct LlamaTensorData {
+    pub data: LlamaTensorBuffer,
+    /// Interleaved layout of repacked data, `None` for file layout
+    pub layout: Option<RepackLayout>,
+}
+
+/// Tensors of the same kind across layers (`blk.*.ffn_up.weight`) placed
+/// the same way
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaTensorGroup {
+    pub name: String,
+    pub buft: GgmlBackendBufferType,
+    pub override_idx: Option<usize>,
+    pub n_tensors: usize,
+    pub n_bytes: usize,
+}
+
+/// Groups placements by tensor name with the layer index left out, in the
+/// order the groups first appear.
+pub fn llama_tensor_groups(placements: &[LlamaTensorPlacement]) -> Vec<LlamaTensorGroup> {
+    let mut groups: Vec<LlamaTensorGroup> = Vec::new();
+    for p in placements {
+        let name = match p.name.strip_prefix("blk.").and_then(|rest| rest.split_once('.')) {
+            Some((il, rest)) if il.bytes().all(|b| b.is_ascii_digit()) => format!("blk.*.{}", rest),
+            _ => p.name.clone(),
+        };
+        match groups.iter_mut().find(|g| g.name == name && g.buft == p.buft && g.override_idx == p.override_idx) {
+            Some(g) => {
+                g.n_tensors += 1;
+                g.n_bytes += p.n_bytes;
+            }
+            None => groups.push(LlamaTensorGroup { name, buft: p.buft, override_idx: p.override_idx, n_tensors: 1, n_bytes: p.n_bytes }),
+        }
+    }
+    groups
+}
+
+pub struct LlamaModelLoader {
+    gguf: GgufFile,
+    overrides: Vec<LlamaModelKvOverride>,
+    /// Keys whose override has been read
+    used: RefCell<BTreeSet<String>>,
+}
+
+impl LlamaModelLoader {
+    /// Later overrides of the same key win.
+    pub fn new(gguf: GgufFile, overrides: Vec<LlamaModelKvOverride>) -> Self {
+        Self { gguf, overrides, used: RefCell::default() }
+    }
+
+    pub fn gguf(&self) -> &GgufFile {
+        &self.gguf
+    }
+
+    pub fn arch_name(&self) -> Result<String, String> {
+        self.get_str(LLM_KV_GENERAL_ARCHITECTURE)?
+            .ok_or_else(|| format!("missing {}", LLM_KV_GENERAL_ARCHITECTURE))
+    }
+
+    fn get_override(&self, key: &str, want: &'static str) -> Result<Option<&LlamaModelKvOverrideValue>, String> {
+        let Some(ovr) = self.overrides.iter().rev().find(|o| o.key == key) else {
+            return Ok(None);
+        };
+        if ovr.value.type_name() != want {
+            return Err(format!(
+                "bad metadata override type for key '{}': expected {} but got {}",
+                key,
+                want,
+                ovr.value.type_name()
+            ));
+        }
+        self.used.borrow_mut().insert(key.to_string());
+        Ok(Some(&ovr.value))
+    }
+
+    fn get_file<T>(&self, key: &str, want: &str, conv: impl Fn(&GgufValue) -> Option<T>) -> Result<Option<T>, String> {
+        match self.gguf.get(key) {
+            None => Ok(None),
+            Some(v) => conv(v).map(Some).ok_or_else(|| format!("key '{}' has the wrong type (expected {})", key, want)),
+        }
+    }
+
+    pub fn get_i64(&self, key: &str) -> Result<Option<i64>, String> {
+        match self.get_override(key, "int")? {
+            Some(LlamaModelKvOverrideValue::Int(v)) => Ok(Some(*v)),
+            _ => self.get_file(key, "int", |v| v.as_i64()),
+        }
+    }
+
+    pub fn get_u32(&self, key: &str) -> Result<Option<u32>, String> {
+        match self.get_i64(key)? {
+            Some(v) => u32::try_from(v).map(Some).map_err(|_| format!("key '{}' = {} is out of range", key, v)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_f64(&self, key: &str) -> Result<Option<f64>, String> {
+        match self.get_override(key, "float")? {
+            Some(LlamaModelKvOverrideValue::Float(v)) => Ok(Some(*v)),
+            _ => self.get_file(key, "float", |v| v.as_f64()),
+        }
+    }
+
+    pub fn get_f32(&self, key: &str) -> Result<Option<f32>, String> {
+        Ok(self.get_f64(key)?.map(|v| v as f32))
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.get_override(key, "bool")? {
+            Some(LlamaModelKvOverrideValue::Bool(v)) => Ok(Some(*v)),
+            _ => self.get_file(key, "bool", |v| v.as_bool()),
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<Option<String>, String> {
+        match self.get_override(key, "str")? {
+            Some(LlamaModelKvOverrideValue::Str(v)) => Ok(Some(v.clone())),
+            _ => self.get_file(key, "str", |v| v.as_str().map(str::to_string)),
+        }
+    }
+
+    /// Number of elements of an array key (overrides cannot hold arrays)
+    pub fn get_arr_n(&self, key: &str) -> Option<usize> {
+        match self.gguf.get(key) {
+            Some(GgufValue::Array(values)) => Some(values.len()),
+            _ => None,
+        }
+    }
+
+    /// Buffer type of every tensor of the file: the first of `overrides`
+    /// that matches its name, otherwise the repack buffer for the matrix
+    /// weights it can hold with `use_extra_bufts`, otherwise the mapped file
+    /// with `use_mmap` if the file was mapped and host memory if not.
+    /// Overriding a tensor to a buffer type that cannot hold it, or to the
+    /// mapped file when it was not mapped, is an error.
+    pub fn tensor_placement(
+        &self,
+        use_mmap: bool,
+        use_extra_bufts: bool,
+        overrides: &[LlamaModelTensorBuftOverride],
+    ) -> Result<Vec<LlamaTensorPlacement>, String> {
+        let mapped = self.gguf.is_mapped();
+        let host = if use_mmap && mapped { GgmlBackendBufferType::CpuMapped } else { GgmlBackendBufferType::Cpu };
+        self.gguf
+            .tensors()
+            .iter()
+            .map(|t| {
+                // the token embeddings are looked up by row, never multiplied
+                let repack = use_extra_bufts
+                    && t.name != "token_embd.weight"
+                    && GgmlBackendBufferType::CpuRepack.supports(t.ty, &t.dims);
+                let (buft, override_idx) = match overrides.iter().position(|o| o.matches(&t.name)) {
+                    Some(i) => (overrides[i].buft, Some(i)),
+                    None if repack => (GgmlBackendBufferType::CpuRepack, None),
+                    None => (host, None),
+                };
+                if !buft.supports(t.ty, &t.dims) {
+                    return Err(format!(
+                        "tensor '{}' ({} {:?}) cannot be stored in buffer type {} (override '{}')",
+                        t.name,
+                        t.ty.name(),
+                        t.dims,
+                        buft.name(),
+                        override_idx.map_or("", |i| overrides[i].pattern.as_str())
+                    ));
+                }
+                if buft == GgmlBackendBufferType::CpuMapped && !mapped {
+                    return Err(format!(
+                        "tensor '{}' cannot be stored in buffer type {}: the model file is not mapped (override '{}')",
+                        t.name,
+                        buft.name(),
+                        override_idx.map_or("", |i| overrides[i].pattern.as_str())
+                    ));
+                }
+                Ok(LlamaTensorPlacement { name: t.name.clone(), n_bytes: t.n_bytes(), buft, override_idx })
+            })
+            .collect()
+    }
+
+    /// Weight matrices that stay in host memory although extra buffer types
+    /// are enabled, with the reason they could not be repacked. Tensors
+    /// placed by an override and the token embeddings are not listed.
+    pub fn repack_skipped(&self, placement: &[LlamaTensorPlacement]) -> Vec<(String, String)> {
+        placement
+            .iter()
+            .filter(|p| p.override_idx.is_none() && p.buft != GgmlBackendBufferType::CpuRepack && p.name != "token_embd.weight")
+            .filter_map(|p| {
+                let t = self.gguf.tensor(&p.name)?;
+                if t.dims.len() != 2 {
+                    return None;
+                }
+                GgmlBackendBufferType::CpuRepack.unsupported_reason(t.ty, &t.dims).map(|reason| (p.name.clone(), reason))
+            })
+            .collect()
+    }
+
+    /// Data of a placed tensor: the range of the mapped file for the mapped
+    /// buffer, a copy for host memory, and a copy repacked into the preferred
+    /// layout of this CPU for the repack buffer.
+    pub fn load_tensor(&self, placement: &LlamaTensorPlacement) -> Result<LlamaTensorData, String> {
+        let t = self.gguf.tensor(&placement.name).ok_or_else(|| format!("no tensor '{}'", placement.name))?;
+        match placement.buft {
+            GgmlBackendBufferType::CpuMapped => {
+                let (map, range) =
+                    self.gguf.tensor_mapping(t).ok_or_else(|| format!("tensor '{}': the model file is not mapped", t.name))?;
+                Ok(LlamaTensorData { data: LlamaTensorBuffer::Mapped(map, range), layout: None })
+            }
+            GgmlBackendBufferType::Cpu => {
+                Ok(LlamaTensorData { data: LlamaTensorBuffer::Host(self.gguf.tensor_data(t).to_vec()), layout: None })
+            }
+            GgmlBackendBufferType::CpuRepack => {
+                let &[ncols, nrows] = t.dims.as_slice() else {
+                    return Err(format!("tensor '{}' is not a matrix", t.name));
+                };
+                let layout =
+                    RepackLayout::preferred(nrows).ok_or_else(|| format!("tensor '{}' has no repacked layout", t.name))?;
+                let data = repack(t.ty, layout, self.gguf.tensor_data(t), ncols, nrows)
+                    .map_err(|e| format!("tensor '{}': {}", t.name, e))?;
+                Ok(LlamaTensorData { data: LlamaTensorBuffer::Host(data), layout: Some(layout) })
+            }
+        }
+    }
+
+    /// Overrides that have been read so far, in the order given
+    pub fn used_overrides(&self) -> Vec<&LlamaModelKvOverride> {
+        let used = self.used.borrow();
+        let mut seen = BTreeSet::new();
+        let mut list: Vec<_> = self.overrides.iter().rev().filter(|o| used.contains(&o.key) && seen.insert(&o.key)).collect();
+        list.reverse();
+        list
+    }
+
+    /// Overrides of keys nothing has read, usually misspelled keys
+    pub fn unused_overrides(&self) -> Vec<&LlamaModelKvOverride> {
+        let used = self.used.borrow();
+        self.overrides.iter().filter(|o| !used.contains(&o.key)).collect()
+    }
 }
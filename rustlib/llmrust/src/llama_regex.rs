@@ -0,0 +1,850 @@
+// src/llama_regex.rs - Regular expression to DFA compiler for constrained sampling
+//
+// A pattern is parsed into a small AST, compiled to a byte-level NFA (code
+// point ranges are expanded into UTF-8 byte sequences) and determinized. The
+// whole output must match the pattern. Because token pieces are byte strings,
+// every token can be run through the DFA: `RegexTokenTable` keeps, for each
+// DFA state reached, the state each token leads to, so masking and accepting
+// in a state seen before are lookups rather than scans.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::llama_vocab::{LlamaToken, LlamaVocab};
+
+/// Upper bound on DFA states, guarding against exponential blow-up
+pub const REGEX_MAX_DFA_STATES: usize = 10_000;
+/// Upper bound on the count of a single repetition
+const REGEX_MAX_REPEAT: u32 = 1_000;
+/// Upper bound on NFA states, which grow with the product of nested repetition
+/// counts (e.g. `((a{1000}){1000}){1000}`)
+pub const REGEX_MAX_NFA_STATES: usize = 100_000;
+
+const MAX_CODE_POINT: u32 = 0x10FFFF;
+
+/// Sentinel for "no transition"
+pub const DFA_DEAD: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+enum RegexNode {
+    Empty,
+    /// Sorted, non-overlapping inclusive code point ranges
+    Class(Vec<(u32, u32)>),
+    Concat(Vec<RegexNode>),
+    Alt(Vec<RegexNode>),
+    Repeat { node: Box<RegexNode>, min: u32, max: Option<u32> },
+}
+
+fn normalize_ranges(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort();
+    let mut out: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match out.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+            _ => out.push((lo, hi)),
+        }
+    }
+    out
+}
+
+fn negate_ranges(ranges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut out = Vec::new();
+    let mut next = 0u32;
+    for &(lo, hi) in ranges {
+        if lo > next {
+            out.push((next, lo - 1));
+        }
+        next = hi + 1;
+    }
+    if next <= MAX_CODE_POINT {
+        out.push((next, MAX_CODE_POINT));
+    }
+    out
+}
+
+fn class_digit() -> Vec<(u32, u32)> {
+    vec![('0' as u32, '9' as u32)]
+}
+
+fn class_word() -> Vec<(u32, u32)> {
+    normalize_ranges(vec![('0' as u32, '9' as u32), ('A' as u32, 'Z' as u32), ('_' as u32, '_' as u32), ('a' as u32, 'z' as u32)])
+}
+
+fn class_space() -> Vec<(u32, u32)> {
+    normalize_ranges(vec![(0x09, 0x0D), (0x20, 0x20)])
+}
+
+/// Any character but a newline
+fn dot() -> RegexNode {
+    RegexNode::Class(negate_ranges(&[('\n' as u32, '\n' as u32)]))
+}
+
+/// A top-level alternative and whether it is anchored with `^` and `$`
+struct RegexBranch {
+    node: RegexNode,
+    start: bool,
+    end: bool,
+}
+
+struct RegexParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    pattern: &'a str,
+    /// Nesting depth of groups; anchors are only allowed outside groups
+    depth: usize,
+}
+
+impl<'a> RegexParser<'a> {
+    fn new(pattern: &'a str) -> Self {
+        Self { chars: pattern.chars().collect(), pos: 0, pattern, depth: 0 }
+    }
+
+    fn error(&self, msg: &str) -> String {
+        format!("{} at position {} in regex {:?}", msg, self.pos, self.pattern)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    /// Parses the top-level alternatives. `^` may start and `$` may end each
+    /// of them; anywhere else anchors are rejected.
+    fn parse(mut self) -> Result<Vec<RegexBranch>, String> {
+        let mut branches = Vec::new();
+        loop {
+            let start = self.peek() == Some('^');
+            if start {
+                self.pos += 1;
+            }
+            let node = self.parse_concat()?;
+            let end = self.peek() == Some('$');
+            if end {
+                self.pos += 1;
+            }
+            branches.push(RegexBranch { node, start, end });
+            match self.next() {
+                Some('|') => {}
+                None => return Ok(branches),
+                Some(')') => {
+                    self.pos -= 1;
+                    return Err(self.error("unmatched ')'"));
+                }
+                Some(_) => {
+                    self.pos -= 1;
+                    return Err(self.error("anchors are only supported at the start and end of the pattern"));
+                }
+            }
+        }
+    }
+
+    fn parse_alt(&mut self) -> Result<RegexNode, String> {
+        let mut alts = vec![self.parse_concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_concat()?);
+        }
+        Ok(if alts.len() == 1 { alts.pop().unwrap() } else { RegexNode::Alt(alts) })
+    }
+
+    fn parse_concat(&mut self) -> Result<RegexNode, String> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            // a `$` outside groups ends the alternative; `parse` checks where
+            if c == '|' || c == ')' || (c == '$' && self.depth == 0) {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            items.push(self.parse_quantifier(atom)?);
+        }
+        Ok(match items.len() {
+            0 => RegexNode::Empty,
+            1 => items.pop().unwrap(),
+            _ => RegexNode::Concat(items),
+        })
+    }
+
+    fn parse_number(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return None;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    fn parse_quantifier(&mut self, atom: RegexNode) -> Result<RegexNode, String> {
+        let mut node = atom;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    let save = self.pos;
+                    self.pos += 1;
+                    let min = self.parse_number();
+                    let bounds = match (min, self.peek()) {
+                        (Some(min), Some('}')) => Some((min, Some(min))),
+                        (Some(min), Some(',')) => {
+                            self.pos += 1;
+                            let max = self.parse_number();
+                            if self.peek() == Some('}') {
+                                Some((min, max))
+                            } else {
+                                None
+                            }
+                        }
+                        _ => None,
+                    };
+                    match bounds {
+                        Some(b) => b,
+                        None => {
+                            // Not a quantifier; treat '{' as a literal
+                            self.pos = save;
+                            return Ok(node);
+                        }
+                    }
+                }
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            if max.is_some_and(|max| max < min) {
+                return Err(self.error("invalid repetition bounds"));
+            }
+            if min.max(max.unwrap_or(0)) > REGEX_MAX_REPEAT {
+                return Err(self.error("repetition count too large"));
+            }
+            if matches!(node, RegexNode::Empty) {
+                return Err(self.error("nothing to repeat"));
+            }
+            // Lazy and possessive modifiers describe the same language
+            if matches!(self.peek(), Some('?') | Some('+')) {
+                self.pos += 1;
+            }
+            node = RegexNode::Repeat { node: Box::new(node), min, max };
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<RegexNode, String> {
+        let c = self.next().ok_or_else(|| self.error("unexpected end"))?;
+        match c {
+            '(' => {
+                if self.peek() == Some('?') {
+                    self.pos += 1;
+                    match self.next() {
+                        Some(':') => {}
+                        Some('P') | Some('<') if self.peek() != Some('=') && self.peek() != Some('!') => {
+                            // named group: skip the name
+                            if self.chars[self.pos - 1] == 'P' && self.next() != Some('<') {
+                                return Err(self.error("invalid named group"));
+                            }
+                            while let Some(c) = self.next() {
+                                if c == '>' {
+                                    break;
+                                }
+                            }
+                        }
+                        _ => return Err(self.error("lookaround and inline flags are not supported")),
+                    }
+                }
+                self.depth += 1;
+                let node = self.parse_alt()?;
+                self.depth -= 1;
+                if self.next() != Some(')') {
+                    return Err(self.error("missing ')'"));
+                }
+                Ok(node)
+            }
+            ')' => Err(self.error("unmatched ')'")),
+            '[' => self.parse_class(),
+            '.' => Ok(dot()),
+            '^' | '$' => Err(self.error("anchors are only supported at the start and end of the pattern")),
+            '*' | '+' | '?' => Err(self.error("nothing to repeat")),
+            '\\' => match self.parse_escape()? {
+                Escape::Char(c) => Ok(RegexNode::Class(vec![(c, c)])),
+                Escape::Class(ranges) => Ok(RegexNode::Class(ranges)),
+            },
+            c => Ok(RegexNode::Class(vec![(c as u32, c as u32)])),
+        }
+    }
+
+    fn parse_hex(&mut self, n: usize) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..n {
+            let d = self.next().and_then(|c| c.to_digit(16)).ok_or_else(|| self.error("invalid hex escape"))?;
+            value = value * 16 + d;
+        }
+        Ok(value)
+    }
+
+    fn parse_escape(&mut self) -> Result<Escape, String> {
+        let c = self.next().ok_or_else(|| self.error("trailing backslash"))?;
+        Ok(match c {
+            'd' => Escape::Class(class_digit()),
+            'D' => Escape::Class(negate_ranges(&class_digit())),
+            'w' => Escape::Class(class_word()),
+            'W' => Escape::Class(negate_ranges(&class_word())),
+            's' => Escape::Class(class_space()),
+            'S' => Escape::Class(negate_ranges(&class_space())),
+            'n' => Escape::Char('\n' as u32),
+            'r' => Escape::Char('\r' as u32),
+            't' => Escape::Char('\t' as u32),
+            'f' => Escape::Char(0x0C),
+            'v' => Escape::Char(0x0B),
+            '0' => Escape::Char(0),
+            'x' => Escape::Char(self.parse_hex(2)?),
+            'u' => Escape::Char(self.parse_hex(4)?),
+            'b' | 'B' | 'A' | 'z' | 'Z' => return Err(self.error("word boundaries and anchors are not supported")),
+            c if c.is_ascii_digit() => return Err(self.error("backreferences are not supported")),
+            c if c.is_ascii_alphanumeric() => return Err(self.error("unknown escape")),
+            c => Escape::Char(c as u32),
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<RegexNode, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or_else(|| self.error("missing ']'"))?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => match self.parse_escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(r) => {
+                        ranges.extend(r);
+                        continue;
+                    }
+                },
+                c => c as u32,
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let hi = match self.next() {
+                    Some('\\') => match self.parse_escape()? {
+                        Escape::Char(c) => c,
+                        Escape::Class(_) => return Err(self.error("invalid class range")),
+                    },
+                    Some(c) => c as u32,
+                    None => return Err(self.error("missing ']'")),
+                };
+                if hi < lo {
+                    return Err(self.error("invalid class range"));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        let ranges = normalize_ranges(ranges);
+        Ok(RegexNode::Class(if negated { negate_ranges(&ranges) } else { ranges }))
+    }
+}
+
+enum Escape {
+    Char(u32),
+    Class(Vec<(u32, u32)>),
+}
+
+/// Splits a code point range into UTF-8 byte-range sequences. Surrogates are skipped.
+fn utf8_sequences(lo: u32, hi: u32, out: &mut Vec<Vec<(u8, u8)>>) {
+    if lo > hi {
+        return;
+    }
+    // Exclude surrogates, which have no UTF-8 encoding
+    if lo <= 0xDFFF && hi >= 0xD800 {
+        if lo < 0xD800 {
+            utf8_sequences(lo, 0xD7FF, out);
+        }
+        if hi > 0xDFFF {
+            utf8_sequences(0xE000, hi, out);
+        }
+        return;
+    }
+    // Split at encoded length boundaries
+    for boundary in [0x7F, 0x7FF, 0xFFFF] {
+        if lo <= boundary && hi > boundary {
+            utf8_sequences(lo, boundary, out);
+            utf8_sequences(boundary + 1, hi, out);
+            return;
+        }
+    }
+    let n = encoded_len(lo);
+    if n == 1 {
+        out.push(vec![(lo as u8, hi as u8)]);
+        return;
+    }
+    // Split until every continuation byte position spans a full or aligned range
+    for i in 1..n {
+        let max = (1u32 << (6 * i)) - 1;
+        if lo & !max != hi & !max {
+            if lo & max != 0 {
+                utf8_sequences(lo, lo | max, out);
+                utf8_sequences((lo | max) + 1, hi, out);
+                return;
+            }
+            if hi & max != max {
+                utf8_sequences(lo, (hi & !max) - 1, out);
+                utf8_sequences(hi & !max, hi, out);
+                return;
+            }
+        }
+    }
+    let lo_bytes = encode_utf8(lo);
+    let hi_bytes = encode_utf8(hi);
+    out.push(lo_bytes.iter().zip(hi_bytes.iter()).map(|(&a, &b)| (a, b)).collect());
+}
+
+fn encoded_len(cp: u32) -> usize {
+    match cp {
+        0..=0x7F => 1,
+        0x80..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        _ => 4,
+    }
+}
+
+fn encode_utf8(cp: u32) -> Vec<u8> {
+    match encoded_len(cp) {
+        1 => vec![cp as u8],
+        2 => vec![0xC0 | (cp >> 6) as u8, 0x80 | (cp & 0x3F) as u8],
+        3 => vec![0xE0 | (cp >> 12) as u8, 0x80 | ((cp >> 6) & 0x3F) as u8, 0x80 | (cp & 0x3F) as u8],
+        _ => vec![
+            0xF0 | (cp >> 18) as u8,
+            0x80 | ((cp >> 12) & 0x3F) as u8,
+            0x80 | ((cp >> 6) & 0x3F) as u8,
+            0x80 | (cp & 0x3F) as u8,
+        ],
+    }
+}
+
+/// Byte-level NFA with epsilon transitions
+#[derive(Default)]
+struct Nfa {
+    eps: Vec<Vec<usize>>,
+    trans: Vec<Vec<(u8, u8, usize)>>,
+}
+
+impl Nfa {
+    fn add_state(&mut self) -> usize {
+        self.eps.push(Vec::new());
+        self.trans.push(Vec::new());
+        self.eps.len() - 1
+    }
+
+    /// Compiles `node` between `start` and a new end state, which is returned.
+    /// Fails once the automaton has more than `REGEX_MAX_NFA_STATES` states.
+    fn compile(&mut self, node: &RegexNode, start: usize) -> Result<usize, String> {
+        if self.eps.len() > REGEX_MAX_NFA_STATES {
+            return Err(format!("more than {} NFA states", REGEX_MAX_NFA_STATES));
+        }
+        Ok(match node {
+            RegexNode::Empty => start,
+            RegexNode::Class(ranges) => {
+                let end = self.add_state();
+                let mut seqs = Vec::new();
+                for &(lo, hi) in ranges {
+                    utf8_sequences(lo, hi, &mut seqs);
+                }
+                for seq in seqs {
+                    let mut cur = start;
+                    for (i, &(lo, hi)) in seq.iter().enumerate() {
+                        let next = if i + 1 == seq.len() { end } else { self.add_state() };
+                        self.trans[cur].push((lo, hi, next));
+                        cur = next;
+                    }
+                }
+                end
+            }
+            RegexNode::Concat(items) => items.iter().try_fold(start, |cur, item| self.compile(item, cur))?,
+            RegexNode::Alt(alts) => {
+                let end = self.add_state();
+                for alt in alts {
+                    let s = self.add_state();
+                    self.eps[start].push(s);
+                    let e = self.compile(alt, s)?;
+                    self.eps[e].push(end);
+                }
+                end
+            }
+            RegexNode::Repeat { node, min, max } => {
+                let mut cur = start;
+                for _ in 0..*min {
+                    let s = self.add_state();
+                    self.eps[cur].push(s);
+                    cur = self.compile(node, s)?;
+                }
+                match max {
+                    None => {
+                        // Kleene star: loop back through a hub state
+                        let hub = self.add_state();
+                        self.eps[cur].push(hub);
+                        let s = self.add_state();
+                        self.eps[hub].push(s);
+                        let e = self.compile(node, s)?;
+                        self.eps[e].push(hub);
+                        hub
+                    }
+                    Some(max) => {
+                        let end = self.add_state();
+                        self.eps[cur].push(end);
+                        for _ in *min..*max {
+                            let s = self.add_state();
+                            self.eps[cur].push(s);
+                            cur = self.compile(node, s)?;
+                            self.eps[cur].push(end);
+                        }
+                        end
+                    }
+                }
+            }
+        })
+    }
+
+    fn closure(&self, states: &mut Vec<usize>) {
+        let mut seen = vec![false; self.eps.len()];
+        let mut stack: Vec<usize> = states.clone();
+        for &s in states.iter() {
+            seen[s] = true;
+        }
+        while let Some(s) = stack.pop() {
+            for &n in &self.eps[s] {
+                if !seen[n] {
+                    seen[n] = true;
+                    states.push(n);
+                    stack.push(n);
+                }
+            }
+        }
+        states.sort_unstable();
+        states.dedup();
+    }
+}
+
+/// Deterministic byte automaton for a regular expression. State 0 is the start
+/// state; transitions into states that can never reach a match are removed.
+#[derive(Debug, Clone)]
+pub struct RegexDfa {
+    pattern: String,
+    trans: Vec<[u32; 256]>,
+    accepting: Vec<bool>,
+}
+
+impl RegexDfa {
+    pub fn compile(pattern: &str) -> Result<Self, String> {
+        // The whole output is matched, so anchors are implied
+        let branches = RegexParser::new(pattern).parse()?;
+        Self::build(pattern, RegexNode::Alt(branches.into_iter().map(|b| b.node).collect()))
+    }
+
+    /// Compiles a DFA for the single-line texts that contain a match of
+    /// `pattern` anywhere (`std::regex_search` rather than a whole match).
+    /// Each top-level alternative may be anchored with `^` or `$`.
+    pub fn compile_search(pattern: &str) -> Result<Self, String> {
+        let any = || RegexNode::Repeat { node: Box::new(dot()), min: 0, max: None };
+        let branches = RegexParser::new(pattern)
+            .parse()?
+            .into_iter()
+            .map(|b| {
+                let mut items = Vec::with_capacity(3);
+                if !b.start {
+                    items.push(any());
+                }
+                items.push(b.node);
+                if !b.end {
+                    items.push(any());
+                }
+                RegexNode::Concat(items)
+            })
+            .collect();
+        Self::build(pattern, RegexNode::Alt(branches))
+    }
+
+    fn build(pattern: &str, ast: RegexNode) -> Result<Self, String> {
+        let mut nfa = Nfa::default();
+        let start = nfa.add_state();
+        let accept = nfa.compile(&ast, start).map_err(|e| format!("regex {:?} is too large: {}", pattern, e))?;
+
+        // Subset construction
+        let mut start_set = vec![start];
+        nfa.closure(&mut start_set);
+        let mut sets: Vec<Vec<usize>> = vec![start_set.clone()];
+        let mut index: std::collections::HashMap<Vec<usize>, u32> = std::collections::HashMap::new();
+        index.insert(start_set, 0);
+        let mut trans: Vec<[u32; 256]> = Vec::new();
+        let mut i = 0;
+        while i < sets.len() {
+            let mut row = [DFA_DEAD; 256];
+            let mut targets: Vec<Vec<usize>> = vec![Vec::new(); 256];
+            for &s in &sets[i] {
+                for &(lo, hi, next) in &nfa.trans[s] {
+                    for b in lo..=hi {
+                        targets[b as usize].push(next);
+                    }
+                }
+            }
+            for (b, mut target) in targets.into_iter().enumerate() {
+                if target.is_empty() {
+                    continue;
+                }
+                nfa.closure(&mut target);
+                let id = match index.get(&target) {
+                    Some(&id) => id,
+                    None => {
+                        if sets.len() >= REGEX_MAX_DFA_STATES {
+                            return Err(format!("regex {:?} needs more than {} DFA states", pattern, REGEX_MAX_DFA_STATES));
+                        }
+                        let id = sets.len() as u32;
+                        index.insert(target.clone(), id);
+                        sets.push(target);
+                        id
+                    }
+                };
+                row[b] = id;
+            }
+            trans.push(row);
+            i += 1;
+        }
+        let accepting: Vec<bool> = sets.iter().map(|s| s.binary_search(&accept).is_ok()).collect();
+
+        // Prune states from which no accepting state is reachable
+        let n = trans.len();
+        let mut live = accepting.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for s in 0..n {
+                if !live[s] && trans[s].iter().any(|&t| t != DFA_DEAD && live[t as usize]) {
+                    live[s] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !live[0] {
+            return Err(format!("regex {:?} matches nothing", pattern));
+        }
+        for row in trans.iter_mut() {
+            for t in row.iter_mut() {
+                if *t != DFA_DEAD && !live[*t as usize] {
+                    *t = DFA_DEAD;
+                }
+            }
+        }
+
+        Ok(Self { pattern: pattern.to_string(), trans, accepting })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn n_states(&self) -> usize {
+        self.trans.len()
+    }
+
+    pub fn start(&self) -> u32 {
+        0
+    }
+
+    /// Next state after `byte`, or `DFA_DEAD`
+    pub fn next(&self, state: u32, byte: u8) -> u32 {
+        if state == DFA_DEAD {
+            return DFA_DEAD;
+        }
+        self.trans[state as usize][byte as usize]
+    }
+
+    /// Runs `bytes` from `state`; `DFA_DEAD` if the match cannot continue.
+    pub fn run(&self, state: u32, bytes: &[u8]) -> u32 {
+        bytes.iter().try_fold(state, |s, &b| match self.next(s, b) {
+            DFA_DEAD => None,
+            n => Some(n),
+        })
+        .unwrap_or(DFA_DEAD)
+    }
+
+    pub fn is_accepting(&self, state: u32) -> bool {
+        state != DFA_DEAD && self.accepting[state as usize]
+    }
+
+    /// True if the whole of `text` matches the pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        self.is_accepting(self.run(self.start(), text.as_bytes()))
+    }
+}
+
+/// A pattern looked for in a growing text. A search pattern may match
+/// anywhere, as with `std::regex_search`, and `^` or `$` anchor a top-level
+/// alternative to the start or end of the text; a full pattern must match the
+/// whole text. Unlike `compile_search`, the text may span several lines.
+#[derive(Debug)]
+pub struct RegexSearch {
+    pattern: String,
+    full: bool,
+    /// Whole-match DFAs of the top-level alternatives, grouped by whether
+    /// they are anchored at the start and at the end
+    branches: Vec<(bool, bool, RegexDfa)>,
+}
+
+impl RegexSearch {
+    pub fn new(pattern: &str, full: bool) -> Result<Self, String> {
+        let branches = if full {
+            vec![(true, true, RegexDfa::compile(pattern)?)]
+        } else {
+            let parsed = RegexParser::new(pattern).parse()?;
+            let mut groups: Vec<(bool, bool, Vec<RegexNode>)> = Vec::new();
+            for b in parsed {
+                match groups.iter_mut().find(|(start, end, _)| *start == b.start && *end == b.end) {
+                    Some((_, _, nodes)) => nodes.push(b.node),
+                    None => groups.push((b.start, b.end, vec![b.node])),
+                }
+            }
+            groups
+                .into_iter()
+                .map(|(start, end, nodes)| Ok((start, end, RegexDfa::build(pattern, RegexNode::Alt(nodes))?)))
+                .collect::<Result<_, String>>()?
+        };
+        Ok(Self { pattern: pattern.to_string(), full, branches })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Start of the earliest match in `text`
+    pub fn find(&self, text: &[u8]) -> Option<usize> {
+        (0..=text.len()).find(|&i| {
+            self.branches.iter().filter(|(start, _, _)| !*start || i == 0).any(|(_, end, dfa)| {
+                if *end {
+                    return dfa.is_accepting(dfa.run(dfa.start(), &text[i..]));
+                }
+                let mut state = dfa.start();
+                for &b in &text[i..] {
+                    if dfa.is_accepting(state) {
+                        return true;
+                    }
+                    state = dfa.next(state, b);
+                    if state == DFA_DEAD {
+                        return false;
+                    }
+                }
+                dfa.is_accepting(state)
+            })
+        })
+    }
+}
+
+impl PartialEq for RegexSearch {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.full == other.full
+    }
+}
+
+impl Eq for RegexSearch {}
+
+/// Transitions of the tokens from one DFA state: the tokens whose whole
+/// piece keeps the match alive, one bit per token id, and the state each of
+/// them leads to
+#[derive(Debug, Clone)]
+pub struct RegexTokenRow {
+    bits: Vec<u64>,
+    /// Next state of each allowed token, by increasing token id
+    next: Vec<(LlamaToken, u32)>,
+}
+
+impl RegexTokenRow {
+    pub fn contains(&self, token: LlamaToken) -> bool {
+        token >= 0 && self.bits.get(token as usize / 64).is_some_and(|w| w & (1 << (token as usize % 64)) != 0)
+    }
+
+    /// Allowed token ids in increasing order
+    pub fn tokens(&self) -> Vec<LlamaToken> {
+        self.next.iter().map(|&(token, _)| token).collect()
+    }
+
+    /// State after emitting `token`, or `DFA_DEAD`
+    pub fn next(&self, token: LlamaToken) -> u32 {
+        if !self.contains(token) {
+            return DFA_DEAD;
+        }
+        self.next.binary_search_by_key(&token, |&(t, _)| t).map_or(DFA_DEAD, |i| self.next[i].1)
+    }
+}
+
+/// Token transitions, computed per DFA state on first use: the row of a
+/// state runs every piece through the DFA once, and masking and accepting in
+/// that state are lookups from then on. Only the states a generation reaches
+/// are ever expanded, so a pattern with many states costs a row for each
+/// state visited rather than for every state. A row is built outside the
+/// table lock, so requests in other states do not wait for it.
+#[derive(Debug)]
+pub struct RegexTokenTable {
+    dfa: Arc<RegexDfa>,
+    vocab: Arc<LlamaVocab>,
+    rows: Mutex<HashMap<u32, Arc<OnceLock<Arc<RegexTokenRow>>>>>,
+    dead: Arc<RegexTokenRow>,
+}
+
+impl RegexTokenTable {
+    pub fn new(dfa: Arc<RegexDfa>, vocab: Arc<LlamaVocab>) -> Self {
+        let dead = Arc::new(RegexTokenRow { bits: vec![0; vocab.n_tokens().div_ceil(64)], next: Vec::new() });
+        Self { dfa, vocab, rows: Mutex::new(HashMap::new()), dead }
+    }
+
+    /// Transitions of the tokens from `state`
+    pub fn row(&self, state: u32) -> Arc<RegexTokenRow> {
+        if state == DFA_DEAD {
+            return Arc::clone(&self.dead);
+        }
+        let cell = Arc::clone(self.rows.lock().unwrap_or_else(|e| e.into_inner()).entry(state).or_default());
+        Arc::clone(cell.get_or_init(|| Arc::new(self.build_row(state))))
+    }
+
+    fn build_row(&self, state: u32) -> RegexTokenRow {
+        let n_vocab = self.vocab.n_tokens();
+        let mut bits = vec![0u64; n_vocab.div_ceil(64)];
+        let mut next = Vec::new();
+        for id in 0..n_vocab {
+            let piece = self.vocab.token_to_piece(id as LlamaToken);
+            if piece.is_empty() || self.vocab.is_eog(id as LlamaToken) {
+                continue;
+            }
+            let to = self.dfa.run(state, piece);
+            if to != DFA_DEAD {
+                bits[id / 64] |= 1 << (id % 64);
+                next.push((id as LlamaToken, to));
+            }
+        }
+        RegexTokenRow { bits, next }
+    }
+
+    /// Number of states whose rows have been computed
+    pub fn n_expanded(&self) -> usize {
+        self.rows.lock().unwrap_or_else(|e| e.into_inner()).values().filter(|row| row.get().is_some()).count()
+    }
+
+    /// State after emitting `token` in `state`, or `DFA_DEAD`
+    pub fn next(&self, state: u32, token: LlamaToken) -> u32 {
+        self.row(state).next(token)
+    }
+}
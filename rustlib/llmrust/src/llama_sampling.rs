@@ -1,6 +1,1005 @@
-// src/llama_sampling.rs - Auto-generated debug file
+// src/llama_sampling.rs - Sampler interface, sampler chain and built-in samplers
+//
+// A sampler transforms a candidate array (token id, logit, probability) in
+// place. Samplers are composed into a chain that is applied in order; the last
+// stage (greedy or dist) selects the token. Samplers also observe accepted
+// tokens so stateful stages such as the grammar can follow the output.
+
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_sampling.rs - File loaded successfully");
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::llama_grammar::{GrammarTrigger, LlamaGrammar};
+use super::llama_io::{LlamaIoRead, LlamaIoWrite};
+use super::llama_regex::{RegexDfa, RegexTokenTable, DFA_DEAD};
+use super::llama_vocab::{LlamaToken, LlamaVocab};
+
+pub const LLAMA_DEFAULT_SEED: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LlamaTokenData {
+    /// Token id
+    pub id: LlamaToken,
+    /// Log-odds of the token
+    pub logit: f32,
+    /// Probability of the token
+    pub p: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LlamaTokenDataArray {
+    pub data: Vec<LlamaTokenData>,
+    /// Index into `data` of the selected token, if any
+    pub selected: Option<usize>,
+    /// Whether `data` is sorted by descending logit
+    pub sorted: bool,
+}
+
+impl LlamaTokenDataArray {
+    /// Builds a candidate array over the full vocabulary from a row of logits.
+    pub fn from_logits(logits: &[f32]) -> Self {
+        let data = logits
+            .iter()
+            .enumerate()
+            .map(|(i, &logit)| LlamaTokenData { id: i as LlamaToken, logit, p: 0.0 })
+            .collect();
+        Self { data, selected: None, sorted: false }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn sort_by_logit(&mut self) {
+        if !self.sorted {
+            self.data.sort_by(|a, b| b.logit.total_cmp(&a.logit));
+            self.sorted = true;
+        }
+    }
+
+    /// Normalizes logits into probabilities (sorting first).
+    pub fn softmax(&mut self) {
+        self.sort_by_logit();
+        let max_l = self.data.first().map(|d| d.logit).unwrap_or(0.0);
+        let mut cum_sum = 0.0f32;
+        for d in self.data.iter_mut() {
+            let p = if d.logit == f32::NEG_INFINITY { 0.0 } else { (d.logit - max_l).exp() };
+            d.p = p;
+            cum_sum += p;
+        }
+        if cum_sum > 0.0 {
+            for d in self.data.iter_mut() {
+                d.p /= cum_sum;
+            }
+        }
+    }
+
+    pub fn selected_token(&self) -> Option<LlamaToken> {
+        self.selected.and_then(|i| self.data.get(i)).map(|d| d.id)
+    }
+}
+
+/// Small deterministic PRNG (splitmix64) so seeded sampling is reproducible.
+#[derive(Debug, Clone)]
+pub struct SamplerRng {
+    state: u64,
+}
+
+impl SamplerRng {
+    pub fn new(seed: u32) -> Self {
+        let seed = if seed == LLAMA_DEFAULT_SEED {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)
+        } else {
+            seed as u64
+        };
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform float in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
+/// Interface implemented by every sampling stage.
+pub trait LlamaSampler: Send {
+    fn name(&self) -> &str;
+
+    /// Observes a token that was appended to the output.
+    fn accept(&mut self, _token: LlamaToken) {}
+
+    /// Transforms the candidate array in place.
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray);
+
+    /// Returns the sampler to its initial state.
+    fn reset(&mut self) {}
+
+    /// Number of trailing tokens the caller must drop from its output and
+    /// context after the last `accept`; resets to zero.
+    fn take_rollback(&mut self) -> usize {
+        0
+    }
+
+    /// Whether `accept` changes the state, so that tokens taken back by
+    /// another sampler have to be taken back here as well.
+    fn tracks_output(&self) -> bool {
+        false
+    }
+
+    /// Writes the state that changes while sampling (RNG, grammar position,
+    /// ...). Stateless samplers write nothing.
+    fn state_write(&self, _io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Restores state written by `state_write` of the same sampler configuration.
+    fn state_read(&mut self, _io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler>;
+}
+
+impl Clone for Box<dyn LlamaSampler> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Ordered list of samplers applied one after another.
+///
+/// When a sampler takes tokens back, the samplers that track the output are
+/// rewound with it: they are restored to their state before the first
+/// accepted token and the kept tokens are accepted again.
+#[derive(Clone, Default)]
+pub struct SamplerChain {
+    samplers: Vec<Box<dyn LlamaSampler>>,
+    /// State of the output-tracking samplers before the first accepted token
+    initial: Option<Vec<(usize, Box<dyn LlamaSampler>)>>,
+    /// Tokens accepted since `initial`
+    accepted: Vec<LlamaToken>,
+    pending_rollback: usize,
+}
+
+impl SamplerChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, sampler: Box<dyn LlamaSampler>) {
+        self.samplers.push(sampler);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&dyn LlamaSampler> {
+        self.samplers.get(i).map(|s| s.as_ref())
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut Box<dyn LlamaSampler>> {
+        self.samplers.get_mut(i)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.samplers.iter().map(|s| s.name()).collect()
+    }
+
+    /// Builds candidates from `logits`, runs the chain and returns the selected token.
+    pub fn sample(&mut self, logits: &[f32]) -> LlamaToken {
+        let mut cur_p = LlamaTokenDataArray::from_logits(logits);
+        self.apply(&mut cur_p);
+        cur_p
+            .selected_token()
+            .unwrap_or_else(|| greedy_select(&cur_p).and_then(|i| cur_p.data.get(i)).map(|d| d.id).unwrap_or(0))
+    }
+}
+
+impl LlamaSampler for SamplerChain {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        if self.initial.is_none() {
+            let tracking = self.samplers.iter().enumerate().filter(|(_, s)| s.tracks_output());
+            self.initial = Some(tracking.map(|(i, s)| (i, s.clone())).collect());
+        }
+        for s in self.samplers.iter_mut() {
+            s.accept(token);
+        }
+        self.accepted.push(token);
+
+        let rollbacks: Vec<usize> = self.samplers.iter_mut().map(|s| s.take_rollback()).collect();
+        let rollback = rollbacks.iter().copied().max().unwrap_or(0);
+        if rollback == 0 {
+            return;
+        }
+        self.accepted.truncate(self.accepted.len().saturating_sub(rollback));
+        self.pending_rollback += rollback;
+        // Rewind the tracking samplers that did not take the tokens back themselves
+        for (i, initial) in self.initial.iter().flatten() {
+            if rollbacks[*i] == 0 {
+                let mut sampler = initial.clone();
+                for &t in &self.accepted {
+                    sampler.accept(t);
+                }
+                self.samplers[*i] = sampler;
+            }
+        }
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        for s in self.samplers.iter_mut() {
+            s.apply(cur_p);
+        }
+    }
+
+    fn reset(&mut self) {
+        for s in self.samplers.iter_mut() {
+            s.reset();
+        }
+        self.initial = None;
+        self.accepted.clear();
+        self.pending_rollback = 0;
+    }
+
+    fn take_rollback(&mut self) -> usize {
+        std::mem::take(&mut self.pending_rollback)
+    }
+
+    fn tracks_output(&self) -> bool {
+        self.samplers.iter().any(|s| s.tracks_output())
+    }
+
+    /// Writes the sampler names, so that state is only restored into the same
+    /// chain, followed by the state of each sampler and what the chain needs
+    /// to rewind them: the initial state of the tracking samplers and the
+    /// tokens accepted since.
+    fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        io.write_u32(self.samplers.len() as u32)?;
+        for s in &self.samplers {
+            io.write_buf(s.name().as_bytes())?;
+        }
+        for s in &self.samplers {
+            s.state_write(io)?;
+        }
+        match &self.initial {
+            None => io.write_u32(0)?,
+            Some(initial) => {
+                io.write_u32(1)?;
+                io.write_u32(initial.len() as u32)?;
+                for (i, s) in initial {
+                    io.write_u32(*i as u32)?;
+                    s.state_write(io)?;
+                }
+            }
+        }
+        io.write_u32(self.accepted.len() as u32)?;
+        for &token in &self.accepted {
+            io.write_i32(token)?;
+        }
+        Ok(())
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        let n_samplers = io.read_u32()? as usize;
+        if n_samplers != self.samplers.len() {
+            return Err(format!("sampler state has {} samplers, chain has {}", n_samplers, self.samplers.len()));
+        }
+        for s in &self.samplers {
+            let name = io.read_buf()?;
+            if name != s.name().as_bytes() {
+                return Err(format!("sampler state is for '{}', chain has '{}'", String::from_utf8_lossy(&name), s.name()));
+            }
+        }
+        for s in self.samplers.iter_mut() {
+            s.state_read(io)?;
+        }
+        self.initial = match io.read_u32()? {
+            0 => None,
+            _ => {
+                let n_initial = io.read_u32()? as usize;
+                let mut initial = Vec::with_capacity(n_initial.min(self.samplers.len()));
+                for _ in 0..n_initial {
+                    let i = io.read_u32()? as usize;
+                    let sampler = self
+                        .samplers
+                        .get(i)
+                        .filter(|s| s.tracks_output())
+                        .ok_or_else(|| format!("sampler state rewinds sampler {}, which does not track the output", i))?;
+                    let mut sampler = sampler.clone();
+                    sampler.state_read(io)?;
+                    initial.push((i, sampler));
+                }
+                Some(initial)
+            }
+        };
+        let n_accepted = io.read_u32()? as usize;
+        self.accepted = (0..n_accepted).map(|_| io.read_i32()).collect::<Result<_, _>>()?;
+        self.pending_rollback = 0;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+fn greedy_select(cur_p: &LlamaTokenDataArray) -> Option<usize> {
+    cur_p
+        .data
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.logit.total_cmp(&b.1.logit))
+        .map(|(i, _)| i)
+}
+
+// =============================================================================
+// Built-in samplers
+// =============================================================================
+
+/// Selects the most likely token.
+#[derive(Debug, Clone, Default)]
+pub struct GreedySampler;
+
+impl LlamaSampler for GreedySampler {
+    fn name(&self) -> &str {
+        "greedy"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        cur_p.selected = greedy_select(cur_p);
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Draws a token from the softmax distribution using a seeded RNG.
+#[derive(Debug, Clone)]
+pub struct DistSampler {
+    seed: u32,
+    rng: SamplerRng,
+}
+
+impl DistSampler {
+    pub fn new(seed: u32) -> Self {
+        Self { seed, rng: SamplerRng::new(seed) }
+    }
+
+    pub fn rng(&self) -> &SamplerRng {
+        &self.rng
+    }
+
+    pub fn rng_mut(&mut self) -> &mut SamplerRng {
+        &mut self.rng
+    }
+}
+
+impl LlamaSampler for DistSampler {
+    fn name(&self) -> &str {
+        "dist"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        cur_p.softmax();
+        let r = self.rng.next_f32();
+        let mut cum = 0.0f32;
+        let mut selected = None;
+        for (i, d) in cur_p.data.iter().enumerate() {
+            if d.p <= 0.0 {
+                continue;
+            }
+            cum += d.p;
+            selected = Some(i);
+            if r < cum {
+                break;
+            }
+        }
+        cur_p.selected = selected.or_else(|| greedy_select(cur_p));
+    }
+
+    fn reset(&mut self) {
+        self.rng = SamplerRng::new(self.seed);
+    }
+
+    fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        io.write_u64(self.rng.state())
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        self.rng.set_state(io.read_u64()?);
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Divides logits by a temperature; a non-positive temperature keeps only the maximum.
+#[derive(Debug, Clone)]
+pub struct TempSampler {
+    temp: f32,
+}
+
+impl TempSampler {
+    pub fn new(temp: f32) -> Self {
+        Self { temp }
+    }
+}
+
+impl LlamaSampler for TempSampler {
+    fn name(&self) -> &str {
+        "temp"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        if self.temp <= 0.0 {
+            if let Some(max_i) = greedy_select(cur_p) {
+                for (i, d) in cur_p.data.iter_mut().enumerate() {
+                    if i != max_i {
+                        d.logit = f32::NEG_INFINITY;
+                    }
+                }
+            }
+            return;
+        }
+        for d in cur_p.data.iter_mut() {
+            d.logit /= self.temp;
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Keeps the `k` most likely candidates.
+#[derive(Debug, Clone)]
+pub struct TopKSampler {
+    k: i32,
+}
+
+impl TopKSampler {
+    pub fn new(k: i32) -> Self {
+        Self { k }
+    }
+}
+
+impl LlamaSampler for TopKSampler {
+    fn name(&self) -> &str {
+        "top-k"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        if self.k <= 0 {
+            return;
+        }
+        cur_p.sort_by_logit();
+        cur_p.data.truncate(self.k as usize);
+        cur_p.selected = None;
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Keeps the smallest set of candidates whose cumulative probability reaches `p`.
+#[derive(Debug, Clone)]
+pub struct TopPSampler {
+    p: f32,
+    min_keep: usize,
+}
+
+impl TopPSampler {
+    pub fn new(p: f32, min_keep: usize) -> Self {
+        Self { p, min_keep }
+    }
+}
+
+impl LlamaSampler for TopPSampler {
+    fn name(&self) -> &str {
+        "top-p"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        if self.p >= 1.0 {
+            return;
+        }
+        cur_p.softmax();
+        let mut cum_sum = 0.0f32;
+        let mut last_idx = cur_p.data.len();
+        for (i, d) in cur_p.data.iter().enumerate() {
+            cum_sum += d.p;
+            if cum_sum >= self.p && i + 1 >= self.min_keep {
+                last_idx = i + 1;
+                break;
+            }
+        }
+        cur_p.data.truncate(last_idx);
+        cur_p.selected = None;
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Drops candidates whose probability is below `p` times the top probability.
+#[derive(Debug, Clone)]
+pub struct MinPSampler {
+    p: f32,
+    min_keep: usize,
+}
+
+impl MinPSampler {
+    pub fn new(p: f32, min_keep: usize) -> Self {
+        Self { p, min_keep }
+    }
+}
+
+impl LlamaSampler for MinPSampler {
+    fn name(&self) -> &str {
+        "min-p"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        if self.p <= 0.0 || cur_p.data.is_empty() {
+            return;
+        }
+        cur_p.sort_by_logit();
+        let min_logit = cur_p.data[0].logit + self.p.ln();
+        let keep = cur_p
+            .data
+            .iter()
+            .position(|d| d.logit < min_logit)
+            .unwrap_or(cur_p.data.len())
+            .max(self.min_keep.min(cur_p.data.len()));
+        cur_p.data.truncate(keep);
+        cur_p.selected = None;
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Masks every candidate the grammar cannot accept next and advances the
+/// grammar over accepted tokens.
+#[derive(Clone)]
+pub struct GrammarSampler {
+    vocab: Arc<LlamaVocab>,
+    grammar_str: String,
+    grammar_root: String,
+    grammar: Option<LlamaGrammar>,
+    /// Non-empty for lazy grammars
+    triggers: Vec<GrammarTrigger>,
+}
+
+impl GrammarSampler {
+    /// Creates a grammar sampler; an empty grammar string yields a no-op sampler.
+    pub fn new(vocab: Arc<LlamaVocab>, grammar_str: &str, grammar_root: &str) -> Result<Self, String> {
+        let grammar = if grammar_str.is_empty() {
+            None
+        } else {
+            Some(LlamaGrammar::parse(grammar_str, grammar_root)?)
+        };
+        Ok(Self {
+            vocab,
+            grammar_str: grammar_str.to_string(),
+            grammar_root: grammar_root.to_string(),
+            grammar,
+            triggers: Vec::new(),
+        })
+    }
+
+    /// Creates a lazy grammar sampler that only constrains output once one of
+    /// `triggers` has been generated.
+    pub fn new_lazy(
+        vocab: Arc<LlamaVocab>,
+        grammar_str: &str,
+        grammar_root: &str,
+        triggers: Vec<GrammarTrigger>,
+    ) -> Result<Self, String> {
+        let mut sampler = Self::new(vocab, grammar_str, grammar_root)?;
+        if let Some(grammar) = sampler.grammar.take() {
+            sampler.grammar = Some(grammar.into_lazy(triggers.clone())?);
+        }
+        sampler.triggers = triggers;
+        Ok(sampler)
+    }
+
+    pub fn grammar(&self) -> Option<&LlamaGrammar> {
+        self.grammar.as_ref()
+    }
+}
+
+impl LlamaSampler for GrammarSampler {
+    fn name(&self) -> &str {
+        "grammar"
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        if let Some(grammar) = self.grammar.as_mut() {
+            // A rejected token leaves the grammar dead, which masks all further candidates
+            let _ = grammar.accept_token(&self.vocab, token);
+        }
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        if let Some(grammar) = self.grammar.as_ref() {
+            grammar.apply(&self.vocab, cur_p);
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.grammar.is_some() {
+            let grammar = LlamaGrammar::parse(&self.grammar_str, &self.grammar_root).ok();
+            self.grammar = if self.triggers.is_empty() {
+                grammar
+            } else {
+                grammar.and_then(|g| g.into_lazy(self.triggers.clone()).ok())
+            };
+        }
+    }
+
+    fn tracks_output(&self) -> bool {
+        true
+    }
+
+    fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        io.write_u32(self.grammar.is_some() as u32)?;
+        match self.grammar.as_ref() {
+            Some(grammar) => grammar.state_write(io),
+            None => Ok(()),
+        }
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        let has_grammar = io.read_u32()? != 0;
+        match self.grammar.as_mut() {
+            Some(grammar) if has_grammar => grammar.state_read(io),
+            None if !has_grammar => Ok(()),
+            _ => Err("grammar sampler state does not match the sampler's grammar".to_string()),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Adds a fixed bias to the logits of selected tokens; `-inf` bans a token.
+#[derive(Clone)]
+pub struct LogitBiasSampler {
+    biases: HashMap<LlamaToken, f32>,
+}
+
+impl LogitBiasSampler {
+    /// Biases for the same token add up.
+    pub fn new(biases: &[(LlamaToken, f32)]) -> Self {
+        let mut map = HashMap::new();
+        for &(token, bias) in biases {
+            *map.entry(token).or_insert(0.0) += bias;
+        }
+        Self { biases: map }
+    }
+
+    pub fn biases(&self) -> &HashMap<LlamaToken, f32> {
+        &self.biases
+    }
+}
+
+impl LlamaSampler for LogitBiasSampler {
+    fn name(&self) -> &str {
+        "logit-bias"
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        if self.biases.is_empty() {
+            return;
+        }
+        for d in cur_p.data.iter_mut() {
+            if let Some(bias) = self.biases.get(&d.id) {
+                d.logit += bias;
+            }
+        }
+        cur_p.sorted = false;
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Maximum number of token sequences considered per banned string
+pub const BANNED_STRING_MAX_SEQUENCES: usize = 64;
+
+/// Prevents the output from containing any of a list of strings.
+///
+/// Every token sequence that spells a banned string is known up front: its
+/// last token is masked whenever the output ends with the rest of the
+/// sequence. A banned string can still appear across token boundaries the
+/// sequences do not cover (e.g. inside a longer token), so accepted output is
+/// also scanned; on a hit the sampler backtracks to the token where the string
+/// starts, bans that token at that position and reports how many tokens the
+/// caller must remove from its context (see `take_rollback`).
+#[derive(Clone)]
+pub struct BannedStringsSampler {
+    vocab: Arc<LlamaVocab>,
+    strings: Vec<Vec<u8>>,
+    sequences: Vec<Vec<LlamaToken>>,
+    tokens: Vec<LlamaToken>,
+    text: Vec<u8>,
+    /// Byte offset of the end of each accepted token in `text`
+    token_ends: Vec<usize>,
+    /// Tokens banned at an output position after backtracking
+    position_bans: HashMap<usize, Vec<LlamaToken>>,
+    pending_rollback: usize,
+}
+
+impl BannedStringsSampler {
+    pub fn new(vocab: Arc<LlamaVocab>, strings: &[String]) -> Result<Self, String> {
+        let mut sequences = Vec::new();
+        for s in strings {
+            if s.is_empty() {
+                return Err("banned strings must not be empty".to_string());
+            }
+            sequences.extend(vocab.tokenize_all(s, BANNED_STRING_MAX_SEQUENCES));
+        }
+        sequences.sort();
+        sequences.dedup();
+        Ok(Self {
+            vocab,
+            strings: strings.iter().map(|s| s.as_bytes().to_vec()).collect(),
+            sequences,
+            tokens: Vec::new(),
+            text: Vec::new(),
+            token_ends: Vec::new(),
+            position_bans: HashMap::new(),
+            pending_rollback: 0,
+        })
+    }
+
+    /// Token sequences that spell one of the banned strings
+    pub fn sequences(&self) -> &[Vec<LlamaToken>] {
+        &self.sequences
+    }
+
+    fn banned_now(&self) -> Vec<LlamaToken> {
+        let mut banned: Vec<LlamaToken> = self.position_bans.get(&self.tokens.len()).cloned().unwrap_or_default();
+        for seq in &self.sequences {
+            let prefix = &seq[..seq.len() - 1];
+            if self.tokens.ends_with(prefix) {
+                banned.push(seq[seq.len() - 1]);
+            }
+        }
+        banned
+    }
+
+    /// Finds a banned string overlapping bytes after `from` and returns its start offset.
+    fn find_banned(&self, from: usize) -> Option<usize> {
+        self.strings
+            .iter()
+            .filter_map(|s| {
+                let start = from.saturating_sub(s.len() - 1);
+                self.text[start..].windows(s.len()).position(|w| w == s.as_slice()).map(|p| start + p)
+            })
+            .min()
+    }
+}
+
+impl LlamaSampler for BannedStringsSampler {
+    fn name(&self) -> &str {
+        "banned-strings"
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        let from = self.text.len();
+        self.tokens.push(token);
+        self.text.extend_from_slice(self.vocab.token_to_piece(token));
+        self.token_ends.push(self.text.len());
+
+        if let Some(start) = self.find_banned(from) {
+            // Backtrack to the token containing the first byte of the banned string
+            let index = self.token_ends.iter().position(|&end| end > start).unwrap_or(0);
+            let culprit = self.tokens[index];
+            self.pending_rollback += self.tokens.len() - index;
+            self.tokens.truncate(index);
+            self.token_ends.truncate(index);
+            self.text.truncate(self.token_ends.last().copied().unwrap_or(0));
+            self.position_bans.retain(|&pos, _| pos <= index);
+            self.position_bans.entry(index).or_default().push(culprit);
+        }
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        let banned = self.banned_now();
+        if banned.is_empty() {
+            return;
+        }
+        for d in cur_p.data.iter_mut() {
+            if banned.contains(&d.id) {
+                d.logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.tokens.clear();
+        self.text.clear();
+        self.token_ends.clear();
+        self.position_bans.clear();
+        self.pending_rollback = 0;
+    }
+
+    fn take_rollback(&mut self) -> usize {
+        std::mem::take(&mut self.pending_rollback)
+    }
+
+    fn tracks_output(&self) -> bool {
+        true
+    }
+
+    /// Writes the accepted tokens, bans placed by backtracking and the pending
+    /// rollback; the text is rebuilt from the tokens on read.
+    fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        io.write_u32(self.tokens.len() as u32)?;
+        for &token in &self.tokens {
+            io.write_i32(token)?;
+        }
+        let mut positions: Vec<_> = self.position_bans.iter().collect();
+        positions.sort_by_key(|(&pos, _)| pos);
+        io.write_u32(positions.len() as u32)?;
+        for (&pos, tokens) in positions {
+            io.write_u32(pos as u32)?;
+            io.write_u32(tokens.len() as u32)?;
+            for &token in tokens {
+                io.write_i32(token)?;
+            }
+        }
+        io.write_u32(self.pending_rollback as u32)
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        let n_tokens = io.read_u32()?;
+        let tokens = (0..n_tokens).map(|_| io.read_i32()).collect::<Result<Vec<_>, _>>()?;
+        let n_positions = io.read_u32()?;
+        let mut position_bans = HashMap::new();
+        for _ in 0..n_positions {
+            let pos = io.read_u32()? as usize;
+            let n_banned = io.read_u32()?;
+            let banned = (0..n_banned).map(|_| io.read_i32()).collect::<Result<Vec<_>, _>>()?;
+            position_bans.insert(pos, banned);
+        }
+        let pending_rollback = io.read_u32()? as usize;
+
+        self.reset();
+        for token in tokens {
+            self.tokens.push(token);
+            self.text.extend_from_slice(self.vocab.token_to_piece(token));
+            self.token_ends.push(self.text.len());
+        }
+        self.position_bans = position_bans;
+        self.pending_rollback = pending_rollback;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Constrains output to a regular expression. Uses the same masking rules as
+/// the grammar sampler, with the token transitions of each DFA state computed
+/// once and shared by the clones of the sampler.
+#[derive(Clone)]
+pub struct RegexSampler {
+    vocab: Arc<LlamaVocab>,
+    dfa: Arc<RegexDfa>,
+    table: Arc<RegexTokenTable>,
+    state: u32,
+}
+
+impl RegexSampler {
+    pub fn new(vocab: Arc<LlamaVocab>, pattern: &str) -> Result<Self, String> {
+        let dfa = Arc::new(RegexDfa::compile(pattern)?);
+        let table = Arc::new(RegexTokenTable::new(Arc::clone(&dfa), Arc::clone(&vocab)));
+        Ok(Self { vocab, state: dfa.start(), dfa, table })
+    }
+
+    pub fn dfa(&self) -> &RegexDfa {
+        &self.dfa
+    }
+
+    /// True when the output so far is a complete match
+    pub fn is_accepting(&self) -> bool {
+        self.dfa.is_accepting(self.state)
+    }
+
+    /// True when no token can continue the match
+    pub fn is_dead(&self) -> bool {
+        self.state == DFA_DEAD
+    }
+}
+
+impl LlamaSampler for RegexSampler {
+    fn name(&self) -> &str {
+        "regex"
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        if !self.vocab.is_eog(token) {
+            self.state = self.table.next(self.state, token);
+        }
+    }
+
+    fn apply(&mut self, cur_p: &mut LlamaTokenDataArray) {
+        let allow_eog = self.is_accepting();
+        let allowed_tokens = self.table.row(self.state);
+        for d in cur_p.data.iter_mut() {
+            let allowed = if self.vocab.is_eog(d.id) { allow_eog } else { allowed_tokens.contains(d.id) };
+            if !allowed {
+                d.logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = self.dfa.start();
+    }
+
+    fn tracks_output(&self) -> bool {
+        true
+    }
+
+    fn state_write(&self, io: &mut dyn LlamaIoWrite) -> Result<(), String> {
+        io.write_u32(self.state)
+    }
+
+    fn state_read(&mut self, io: &mut dyn LlamaIoRead) -> Result<(), String> {
+        let state = io.read_u32()?;
+        if state != DFA_DEAD && state as usize >= self.dfa.n_states() {
+            return Err(format!("regex sampler state {} out of range", state));
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn LlamaSampler> {
+        Box::new(self.clone())
+    }
 }
@@ -1,6 +1,322 @@
-// src/llama_vocab.rs - Auto-generated debug file
+// src/llama_vocab.rs - Vocabulary: token pieces, attributes and special tokens
+//
+// Holds the token table of a model together with the decoded byte sequence of
+// every token, which is what the constrained samplers match against.
+
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: src/llama_vocab.rs - File loaded successfully");
+use std::collections::HashMap;
+
+pub type LlamaToken = i32;
+
+pub const LLAMA_TOKEN_NULL: LlamaToken = -1;
+
+/// Token types as stored in GGUF `tokenizer.ggml.token_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlamaTokenType {
+    Undefined = 0,
+    Normal = 1,
+    Unknown = 2,
+    Control = 3,
+    UserDefined = 4,
+    Unused = 5,
+    Byte = 6,
+}
+
+impl LlamaTokenType {
+    pub fn from_i32(value: i32) -> Self {
+        match value {
+            1 => Self::Normal,
+            2 => Self::Unknown,
+            3 => Self::Control,
+            4 => Self::UserDefined,
+            5 => Self::Unused,
+            6 => Self::Byte,
+            _ => Self::Undefined,
+        }
+    }
+}
+
+/// Tokenizer family, which decides how token text maps to bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlamaVocabType {
+    /// SentencePiece: "▁" encodes a space, `<0xXX>` tokens encode raw bytes
+    Spm,
+    /// GPT-2 style byte-level BPE: bytes are remapped to printable code points
+    Bpe,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlamaTokenData {
+    pub text: String,
+    pub score: f32,
+    pub ttype: LlamaTokenType,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlamaVocab {
+    vocab_type: LlamaVocabType,
+    id_to_token: Vec<LlamaTokenData>,
+    token_to_id: HashMap<String, LlamaToken>,
+    /// Decoded bytes of every token; empty for control tokens
+    pieces: Vec<Vec<u8>>,
+    /// Tokens by decoded piece, for resolving text into token sequences
+    piece_to_ids: HashMap<Vec<u8>, Vec<LlamaToken>>,
+    max_piece_len: usize,
+    special_bos_id: LlamaToken,
+    special_eos_id: LlamaToken,
+    special_eot_id: LlamaToken,
+    special_sep_id: LlamaToken,
+    special_eog_ids: Vec<LlamaToken>,
+}
+
+/// GPT-2 byte-to-unicode table used by byte-level BPE vocabularies.
+fn bpe_unicode_to_byte() -> HashMap<char, u8> {
+    let mut map = HashMap::new();
+    let mut n = 0u32;
+    for b in 0u32..256 {
+        let printable = (0x21..=0x7E).contains(&b) || (0xA1..=0xAC).contains(&b) || (0xAE..=0xFF).contains(&b);
+        let cp = if printable {
+            b
+        } else {
+            n += 1;
+            255 + n
+        };
+        if let Some(c) = char::from_u32(cp) {
+            map.insert(c, b as u8);
+        }
+    }
+    map
+}
+
+fn parse_byte_token(text: &str) -> Option<u8> {
+    let hex = text.strip_prefix("<0x")?.strip_suffix('>')?;
+    u8::from_str_radix(hex, 16).ok()
+}
+
+impl LlamaVocab {
+    /// Builds a vocabulary from token texts, scores and GGUF token types.
+    pub fn new(
+        vocab_type: LlamaVocabType,
+        tokens: Vec<String>,
+        scores: Vec<f32>,
+        token_types: Vec<i32>,
+    ) -> Self {
+        let bpe_map = if vocab_type == LlamaVocabType::Bpe { Some(bpe_unicode_to_byte()) } else { None };
+
+        let mut id_to_token = Vec::with_capacity(tokens.len());
+        let mut token_to_id = HashMap::with_capacity(tokens.len());
+        let mut pieces = Vec::with_capacity(tokens.len());
+        for (i, text) in tokens.into_iter().enumerate() {
+            let ttype = token_types.get(i).copied().map(LlamaTokenType::from_i32).unwrap_or(LlamaTokenType::Normal);
+            let piece = match ttype {
+                LlamaTokenType::Control | LlamaTokenType::Unused | LlamaTokenType::Undefined => Vec::new(),
+                LlamaTokenType::Byte => parse_byte_token(&text).map(|b| vec![b]).unwrap_or_default(),
+                LlamaTokenType::UserDefined => text.as_bytes().to_vec(),
+                _ => match &bpe_map {
+                    Some(map) => text.chars().map(|c| map.get(&c).copied().unwrap_or(b'?')).collect(),
+                    None => text.replace('\u{2581}', " ").into_bytes(),
+                },
+            };
+            token_to_id.insert(text.clone(), i as LlamaToken);
+            pieces.push(piece);
+            id_to_token.push(LlamaTokenData { text, score: scores.get(i).copied().unwrap_or(0.0), ttype });
+        }
+
+        let mut piece_to_ids: HashMap<Vec<u8>, Vec<LlamaToken>> = HashMap::new();
+        for (i, piece) in pieces.iter().enumerate() {
+            if !piece.is_empty() {
+                piece_to_ids.entry(piece.clone()).or_default().push(i as LlamaToken);
+            }
+        }
+        let max_piece_len = pieces.iter().map(|p| p.len()).max().unwrap_or(0);
+
+        Self {
+            vocab_type,
+            id_to_token,
+            token_to_id,
+            pieces,
+            piece_to_ids,
+            max_piece_len,
+            special_bos_id: LLAMA_TOKEN_NULL,
+            special_eos_id: LLAMA_TOKEN_NULL,
+            special_eot_id: LLAMA_TOKEN_NULL,
+            special_sep_id: LLAMA_TOKEN_NULL,
+            special_eog_ids: Vec::new(),
+        }
+    }
+
+    /// Builds an SPM vocabulary of normal tokens from plain texts (mainly for tests and tools).
+    pub fn from_pieces(pieces: &[&str]) -> Self {
+        let tokens = pieces.iter().map(|p| p.to_string()).collect();
+        Self::new(LlamaVocabType::Spm, tokens, Vec::new(), Vec::new())
+    }
+
+    pub fn set_special_tokens(&mut self, bos: LlamaToken, eos: LlamaToken, eot: LlamaToken, sep: LlamaToken) {
+        self.special_bos_id = bos;
+        self.special_eos_id = eos;
+        self.special_eot_id = eot;
+        self.special_sep_id = sep;
+        self.special_eog_ids = [eos, eot].into_iter().filter(|&t| t != LLAMA_TOKEN_NULL).collect();
+        self.special_eog_ids.dedup();
+    }
+
+    pub fn vocab_type(&self) -> LlamaVocabType {
+        self.vocab_type
+    }
+
+    pub fn n_tokens(&self) -> usize {
+        self.id_to_token.len()
+    }
+
+    pub fn bos(&self) -> LlamaToken {
+        self.special_bos_id
+    }
+
+    pub fn eos(&self) -> LlamaToken {
+        self.special_eos_id
+    }
+
+    pub fn eot(&self) -> LlamaToken {
+        self.special_eot_id
+    }
+
+    pub fn sep(&self) -> LlamaToken {
+        self.special_sep_id
+    }
+
+    /// End-of-generation tokens (EOS, EOT)
+    pub fn is_eog(&self, token: LlamaToken) -> bool {
+        token != LLAMA_TOKEN_NULL && self.special_eog_ids.contains(&token)
+    }
+
+    pub fn is_control(&self, token: LlamaToken) -> bool {
+        self.token_get_attr(token) == Some(LlamaTokenType::Control)
+    }
+
+    pub fn token_get_attr(&self, token: LlamaToken) -> Option<LlamaTokenType> {
+        self.id_to_token.get(token as usize).map(|t| t.ttype)
+    }
+
+    /// Raw token text as stored in the vocabulary
+    pub fn token_get_text(&self, token: LlamaToken) -> &str {
+        self.id_to_token.get(token as usize).map(|t| t.text.as_str()).unwrap_or("")
+    }
+
+    pub fn token_get_score(&self, token: LlamaToken) -> f32 {
+        self.id_to_token.get(token as usize).map(|t| t.score).unwrap_or(0.0)
+    }
+
+    /// Decoded bytes produced when the token is emitted
+    pub fn token_to_piece(&self, token: LlamaToken) -> &[u8] {
+        self.pieces.get(token as usize).map(|p| p.as_slice()).unwrap_or(&[])
+    }
+
+    /// Looks up a token by its exact vocabulary text
+    pub fn text_to_token(&self, text: &str) -> Option<LlamaToken> {
+        self.token_to_id.get(text).copied()
+    }
+
+    /// Tokens whose piece is exactly `bytes`
+    fn tokens_for_piece(&self, bytes: &[u8]) -> &[LlamaToken] {
+        self.piece_to_ids.get(bytes).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// Tokenizes `text` into the fewest tokens whose pieces spell it exactly,
+    /// preferring longer pieces first. Empty if the text cannot be spelled.
+    pub fn tokenize(&self, text: &str) -> Vec<LlamaToken> {
+        let bytes = text.as_bytes();
+        let n = bytes.len();
+        // best[i]: (token count, token, next position) for the suffix starting at i
+        let mut best: Vec<Option<(usize, LlamaToken, usize)>> = vec![None; n + 1];
+        best[n] = Some((0, LLAMA_TOKEN_NULL, n));
+        for i in (0..n).rev() {
+            for len in (1..=self.max_piece_len.min(n - i)).rev() {
+                let Some(&token) = self.tokens_for_piece(&bytes[i..i + len]).first() else {
+                    continue;
+                };
+                if let Some((count, _, _)) = best[i + len] {
+                    if best[i].is_none_or(|(c, _, _)| count + 1 < c) {
+                        best[i] = Some((count + 1, token, i + len));
+                    }
+                }
+            }
+        }
+
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        while pos < n {
+            match best[pos] {
+                Some((_, token, next)) => {
+                    tokens.push(token);
+                    pos = next;
+                }
+                None => return Vec::new(),
+            }
+        }
+        tokens
+    }
+
+    /// Every token sequence whose pieces concatenate to exactly `text`, fewest
+    /// tokens first, limited to `max_sequences`.
+    pub fn tokenize_all(&self, text: &str, max_sequences: usize) -> Vec<Vec<LlamaToken>> {
+        let bytes = text.as_bytes();
+        let n = bytes.len();
+        if n == 0 || max_sequences == 0 {
+            return Vec::new();
+        }
+        // reachable[i]: the suffix starting at i can be spelled
+        let mut reachable = vec![false; n + 1];
+        reachable[n] = true;
+        for i in (0..n).rev() {
+            reachable[i] = (1..=self.max_piece_len.min(n - i))
+                .any(|len| reachable[i + len] && !self.tokens_for_piece(&bytes[i..i + len]).is_empty());
+        }
+        if !reachable[0] {
+            return Vec::new();
+        }
+
+        // Enumerate with a generous cap so the shortest sequences survive the sort
+        let cap = max_sequences.saturating_mul(16);
+        let mut out = Vec::new();
+        let mut current = Vec::new();
+        self.enumerate_tokenizations(bytes, 0, &reachable, &mut current, &mut out, cap);
+        out.sort_by_key(|seq| seq.len());
+        out.truncate(max_sequences);
+        out
+    }
+
+    fn enumerate_tokenizations(
+        &self,
+        bytes: &[u8],
+        pos: usize,
+        reachable: &[bool],
+        current: &mut Vec<LlamaToken>,
+        out: &mut Vec<Vec<LlamaToken>>,
+        cap: usize,
+    ) {
+        if pos == bytes.len() {
+            out.push(current.clone());
+            return;
+        }
+        for len in (1..=self.max_piece_len.min(bytes.len() - pos)).rev() {
+            if !reachable[pos + len] {
+                continue;
+            }
+            for &token in self.tokens_for_piece(&bytes[pos..pos + len]) {
+                if out.len() >= cap {
+                    return;
+                }
+                current.push(token);
+                self.enumerate_tokenizations(bytes, pos + len, reachable, current, out, cap);
+                current.pop();
+            }
+        }
+    }
+
+    /// Concatenates the decoded pieces of `tokens`
+    pub fn detokenize(&self, tokens: &[LlamaToken]) -> String {
+        let bytes: Vec<u8> = tokens.iter().flat_map(|&t| self.token_to_piece(t).iter().copied()).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
 }
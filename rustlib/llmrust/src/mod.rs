@@ -1,6 +1,25 @@
-// src/mod.rs - Auto-generated debug file
+// src/mod.rs - Core llama runtime modules
 #![allow(dead_code)]
 
+pub mod adapter_loader;
+pub mod batch_processor;
+pub mod llama_adapter;
+pub mod llama_arch;
+pub mod llama_batch;
+pub mod llama_context;
+pub mod llama_cparams;
+pub mod llama_grammar;
+pub mod llama_graph;
+pub mod llama_hparams;
+pub mod llama_io;
+pub mod llama_kv_cache;
+pub mod llama_memory;
+pub mod llama_model;
+pub mod llama_model_loader;
+pub mod llama_regex;
+pub mod llama_sampling;
+pub mod llama_vocab;
+
 pub fn debug_print() {
     println!("DEBUG: src/mod.rs - File loaded successfully");
 }
@@ -1,6 +1,27 @@
-// tests/mod.rs - Auto-generated debug file
+// tests/mod.rs - Unit tests for the llmrust modules
 #![allow(dead_code)]
 
+mod test_attention_type;
+mod test_batch;
+mod test_batch_processor;
+mod test_control_vector;
+mod test_embeddings;
+mod test_flash_attn;
+mod test_gguf;
+mod test_grammar;
+mod test_json_schema_to_grammar;
+mod test_kv_cache;
+mod test_kv_override;
+mod test_lora;
+mod test_prompt_cache;
+mod test_quants;
+mod test_regex;
+mod test_repack;
+mod test_rope;
+mod test_sampling;
+mod test_state;
+mod test_tensor_override;
+
 pub fn debug_print() {
     println!("DEBUG: tests/mod.rs - File loaded successfully");
 }
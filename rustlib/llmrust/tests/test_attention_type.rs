@@ -0,0 +1,235 @@
+// tests/test_attention_type.rs - Causal and non-causal attention tests
+
+use std::os::raw::c_void;
+
+use crate::common::log::{cstr, llama_set_causal_attn};
+use crate::common::model::{
+    llama_apply_adapter_cvec, llama_context_default_params, llama_free, llama_init_from_model, llama_model_default_params,
+    llama_model_free, llama_model_load_from_file, llama_pooling_type, LLAMA_ATTENTION_TYPE_CAUSAL, LLAMA_ATTENTION_TYPE_UNSPECIFIED,
+    LLAMA_POOLING_TYPE_CLS, LLAMA_POOLING_TYPE_MEAN, LLAMA_POOLING_TYPE_UNSPECIFIED,
+};
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaUbatch};
+use crate::llmrust::src::llama_context::{self as llama_context, LlamaContext};
+use crate::llmrust::src::llama_cparams::LlamaCparams;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::{LlamaAttentionType, LlamaHparams, LlamaPoolingType};
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvUbatchToken};
+use crate::llmrust::src::llama_model::llama_model_load_hparams;
+use crate::llmrust::src::llama_model_loader::LlamaModelLoader;
+
+const N_EMBD: usize = 4;
+
+fn row(token: i32, salt: f32) -> Vec<f32> {
+    (0..N_EMBD).map(|i| ((token as f32 + 1.0) * 0.9 + i as f32 * 0.4 + salt).sin()).collect()
+}
+
+/// One attention layer whose output is the hidden state; the K/V of the
+/// whole ubatch are stored before any token attends
+struct AttnEncoder {
+    kv: LlamaKvCache,
+    causal: bool,
+}
+
+impl AttnEncoder {
+    fn new() -> Self {
+        let hparams = LlamaHparams {
+            n_layer: 1,
+            n_head: 1,
+            n_head_kv: 1,
+            n_embd_head_k: N_EMBD as u32,
+            n_embd_head_v: N_EMBD as u32,
+            ..Default::default()
+        };
+        let kv = LlamaKvCache::new(&hparams, 16, 1, GgmlType::F32, GgmlType::F32).unwrap();
+        Self { kv, causal: true }
+    }
+}
+
+impl LlamaGraph for AttnEncoder {
+    fn n_vocab(&self) -> usize {
+        8
+    }
+
+    fn set_causal_attn(&mut self, causal: bool) {
+        self.causal = causal;
+    }
+
+    fn compute(&mut self, _batch: &LlamaBatch, _ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        Err("encoder only".to_string())
+    }
+
+    fn n_embd(&self) -> usize {
+        N_EMBD
+    }
+
+    fn embed(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        let tokens: Vec<LlamaKvUbatchToken> =
+            ubatch.idxs.iter().map(|&i| LlamaKvUbatchToken { pos: batch.pos[i], seq_ids: batch.seq_id[i].clone() }).collect();
+        for (&i, cell) in ubatch.idxs.iter().zip(self.kv.apply_ubatch(&tokens)?) {
+            self.kv.cpy_k(0, cell, &row(batch.token[i], 0.5));
+            self.kv.cpy_v(0, cell, &row(batch.token[i], 2.0));
+        }
+        Ok(ubatch
+            .idxs
+            .iter()
+            .map(|&i| self.kv.attn(0, 0, &row(batch.token[i], 0.0), batch.seq_id[i][0], batch.pos[i], self.causal, 0.5))
+            .collect())
+    }
+}
+
+fn prompt() -> LlamaBatch {
+    let mut batch = LlamaBatch::new();
+    for (pos, token) in [3, 1, 4, 1].into_iter().enumerate() {
+        batch.add(token, pos as i32, &[0], true);
+    }
+    batch
+}
+
+/// Context of a model that attends causally or not by default
+fn ctx(attention_type: LlamaAttentionType, n_ubatch: usize, model_causal: bool) -> LlamaContext {
+    let cparams = LlamaCparams { n_ubatch, embeddings: true, attention_type, ..Default::default() };
+    let hparams = LlamaHparams { causal_attn: model_causal, ..Default::default() };
+    LlamaContext::with_cparams(0, cparams, None).unwrap().with_model_hparams(hparams)
+}
+
+fn hidden(ctx: &mut LlamaContext) -> Vec<Vec<f32>> {
+    ctx.decode(&prompt(), &mut AttnEncoder::new()).unwrap();
+    (0..4).map(|i| ctx.get_embeddings_ith(i).unwrap().to_vec()).collect()
+}
+
+fn assert_close(a: &[f32], b: &[f32]) {
+    assert!(a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-5), "{:?} vs {:?}", a, b);
+}
+
+#[test]
+fn test_attention_type_masks() {
+    // the model default: causal for a decoder, bidirectional for an encoder
+    let causal = hidden(&mut ctx(LlamaAttentionType::Unspecified, 8, true));
+    let non_causal = hidden(&mut ctx(LlamaAttentionType::Unspecified, 8, false));
+
+    // the first token only sees itself causally, the whole prompt otherwise
+    assert_close(&causal[0], &row(3, 2.0));
+    assert!((0..N_EMBD).any(|i| (non_causal[0][i] - causal[0][i]).abs() > 1e-3));
+    assert!((0..N_EMBD).any(|i| (non_causal[1][i] - causal[1][i]).abs() > 1e-3));
+    // the last token sees everything either way
+    assert_close(&non_causal[3], &causal[3]);
+
+    // the context setting overrides the model
+    assert_eq!(hidden(&mut ctx(LlamaAttentionType::NonCausal, 8, true)), non_causal);
+    assert_eq!(hidden(&mut ctx(LlamaAttentionType::Causal, 8, false)), causal);
+
+    // the attention type reported is the one the graph is given
+    for (attention_type, model_causal) in [(LlamaAttentionType::Unspecified, false), (LlamaAttentionType::Causal, false)] {
+        let mut ctx = ctx(attention_type, 8, model_causal);
+        let mut graph = AttnEncoder::new();
+        ctx.decode(&prompt(), &mut graph).unwrap();
+        assert_eq!(graph.causal, ctx.causal_attn());
+    }
+}
+
+#[test]
+fn test_attention_type_switch_between_decodes() {
+    // GritLM: embed bidirectionally, then generate causally, on one context
+    let mut ctx = ctx(LlamaAttentionType::Unspecified, 8, true);
+    let causal = hidden(&mut ctx);
+    ctx.set_causal_attn(false);
+    assert_eq!(ctx.cparams().attention_type, LlamaAttentionType::NonCausal);
+    let non_causal = hidden(&mut ctx);
+    assert_ne!(non_causal, causal);
+    ctx.set_causal_attn(true);
+    assert_eq!(hidden(&mut ctx), causal);
+    ctx.set_causal_attn(false);
+    assert_eq!(hidden(&mut ctx), non_causal);
+}
+
+#[test]
+fn test_attention_type_needs_one_ubatch() {
+    // split causally, the ubatches give the same states as one ubatch
+    let causal = hidden(&mut ctx(LlamaAttentionType::Causal, 8, true));
+    assert_eq!(hidden(&mut ctx(LlamaAttentionType::Causal, 2, true)), causal);
+
+    let mut split = ctx(LlamaAttentionType::NonCausal, 2, true);
+    let err = split.decode(&prompt(), &mut AttnEncoder::new()).unwrap_err();
+    assert!(err.contains("one ubatch"), "{}", err);
+    let mut fits = ctx(LlamaAttentionType::NonCausal, 4, true);
+    assert!(fits.decode(&prompt(), &mut AttnEncoder::new()).is_ok());
+}
+
+fn load(arch: &str, causal: Option<bool>) -> LlamaHparams {
+    let ml = LlamaModelLoader::new(GgufFile::from_bytes(&writer(arch, causal).to_bytes()).unwrap(), Vec::new());
+    llama_model_load_hparams(&ml, arch).unwrap()
+}
+
+fn writer(arch: &str, causal: Option<bool>) -> GgufWriter {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", arch)
+        .set(&format!("{}.context_length", arch), GgufValue::U32(512))
+        .set(&format!("{}.embedding_length", arch), GgufValue::U32(64))
+        .set(&format!("{}.block_count", arch), GgufValue::U32(2))
+        .set(&format!("{}.feed_forward_length", arch), GgufValue::U32(128))
+        .set(&format!("{}.attention.head_count", arch), GgufValue::U32(4));
+    if let Some(causal) = causal {
+        w.set(&format!("{}.attention.causal", arch), GgufValue::Bool(causal));
+    }
+    w
+}
+
+#[test]
+fn test_attention_type_from_gguf_and_cparams() {
+    assert!(load("llama", None).causal_attn);
+    assert!(!load("bert", None).causal_attn);
+    assert!(!load("nomic-bert", None).causal_attn);
+    // gte-Qwen2 style: a decoder architecture marked bidirectional
+    assert!(!load("llama", Some(false)).causal_attn);
+    assert!(load("bert", Some(true)).causal_attn);
+
+    let cparams = |attention_type| LlamaCparams { attention_type, ..Default::default() };
+    assert!(cparams(LlamaAttentionType::Unspecified).causal_attn_for(true));
+    assert!(!cparams(LlamaAttentionType::Unspecified).causal_attn_for(false));
+    assert!(cparams(LlamaAttentionType::Causal).causal_attn_for(false));
+    assert!(!cparams(LlamaAttentionType::NonCausal).causal_attn_for(true));
+
+    assert_eq!(LlamaAttentionType::from_raw(1), Some(LlamaAttentionType::NonCausal));
+    assert_eq!(LlamaAttentionType::from_name("causal"), Some(LlamaAttentionType::Causal));
+    assert_eq!(LlamaAttentionType::NonCausal.name(), "non-causal");
+    assert!(LlamaAttentionType::from_raw(2).is_none());
+}
+
+#[test]
+fn test_attention_type_c_api() {
+    let mut w = writer("bert", None);
+    w.set("bert.pooling_type", GgufValue::U32(LlamaPoolingType::Mean as u32));
+    let path = std::env::temp_dir().join(format!("llmrust_attention_type_{}.gguf", std::process::id()));
+    w.write(&path).unwrap();
+    let model = llama_model_load_from_file(cstr(path.to_str().unwrap()).as_ptr(), llama_model_default_params());
+    std::fs::remove_file(&path).unwrap();
+    assert!(!model.is_null());
+
+    // the model's attention and pooling reach contexts created from it
+    let embeddings = |pooling_type, attention_type| {
+        let mut cparams = llama_context_default_params();
+        (cparams.embeddings, cparams.pooling_type, cparams.attention_type) = (true, pooling_type, attention_type);
+        cparams
+    };
+    let ctx = llama_init_from_model(model, embeddings(LLAMA_POOLING_TYPE_UNSPECIFIED, LLAMA_ATTENTION_TYPE_UNSPECIFIED));
+    let context = unsafe { llama_context::from_handle(ctx as *mut c_void) }.unwrap();
+    assert!(!context.causal_attn());
+    assert_eq!(context.model_hparams().map(|hp| hp.n_embd), Some(64));
+    assert_eq!(llama_pooling_type(ctx), LLAMA_POOLING_TYPE_MEAN);
+    llama_set_causal_attn(ctx, true);
+    assert!(unsafe { llama_context::from_handle(ctx as *mut c_void) }.unwrap().causal_attn());
+
+    // control vectors are checked against the model's width
+    assert_eq!(llama_apply_adapter_cvec(ctx, [0.5; 64].as_ptr(), 64, 64, 1, 1), 0);
+    assert_eq!(llama_apply_adapter_cvec(ctx, [0.5; 4096].as_ptr(), 4096, 4096, 1, 1), -1);
+    llama_free(ctx);
+
+    // and the context params still override them
+    let ctx = llama_init_from_model(model, embeddings(LLAMA_POOLING_TYPE_CLS, LLAMA_ATTENTION_TYPE_CAUSAL));
+    assert!(unsafe { llama_context::from_handle(ctx as *mut c_void) }.unwrap().causal_attn());
+    assert_eq!(llama_pooling_type(ctx), LLAMA_POOLING_TYPE_CLS);
+    llama_free(ctx);
+    llama_model_free(model);
+}
@@ -0,0 +1,310 @@
+// tests/test_batch.rs - Micro-batch split and decode tests
+
+use std::collections::BTreeMap;
+
+use crate::common::log::{
+    cstr, llama_batch_free, llama_batch_get_one, llama_batch_init, llama_decode, llama_get_logits_ith, llama_get_memory,
+    llama_memory_seq_pos_max, llama_set_embeddings,
+};
+use crate::common::model::{
+    common_batch_add, common_batch_clear, llama_context_default_params, llama_free, llama_init_from_model, llama_model_default_params,
+    llama_model_free, llama_model_load_from_file, mock_model_hparams, LLAMA_POOLING_TYPE_RANK,
+};
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_cpu::ops::{rope_f32, RopeMode};
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaSplitStrategy, LlamaUbatch};
+use crate::llmrust::src::llama_context::LlamaContext;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvUbatchToken};
+use crate::llmrust::src::llama_memory::LlamaSeqId;
+
+const N_VOCAB: usize = 6;
+const N_EMBD: usize = 8;
+
+fn embd(token: i32, salt: f32) -> Vec<f32> {
+    (0..N_EMBD).map(|i| ((token as f32 + 1.0) * 0.71 + i as f32 * 0.29 + salt).sin()).collect()
+}
+
+fn project(x: &[f32]) -> Vec<f32> {
+    (0..N_VOCAB).map(|v| x.iter().enumerate().map(|(i, xi)| xi * ((v * N_EMBD + i) as f32 * 0.13).cos()).sum()).collect()
+}
+
+/// One attention layer with a single head over a KV cache
+struct AttnGraph {
+    kv: LlamaKvCache,
+}
+
+impl AttnGraph {
+    fn new() -> Self {
+        let hparams = LlamaHparams {
+            n_layer: 1,
+            n_head: 1,
+            n_head_kv: 1,
+            n_embd_head_k: N_EMBD as u32,
+            n_embd_head_v: N_EMBD as u32,
+            n_rot: N_EMBD as u32,
+            ..Default::default()
+        };
+        Self { kv: LlamaKvCache::new(&hparams, 64, 4, GgmlType::F32, GgmlType::F32).unwrap() }
+    }
+}
+
+impl LlamaGraph for AttnGraph {
+    fn n_vocab(&self) -> usize {
+        N_VOCAB
+    }
+
+    fn compute(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        let tokens: Vec<LlamaKvUbatchToken> =
+            ubatch.idxs.iter().map(|&i| LlamaKvUbatchToken { pos: batch.pos[i], seq_ids: batch.seq_id[i].clone() }).collect();
+        let cells = self.kv.apply_ubatch(&tokens)?;
+        for (&i, &cell) in ubatch.idxs.iter().zip(&cells) {
+            let mut k = embd(batch.token[i], 0.5);
+            rope_f32(&mut k, N_EMBD, batch.pos[i] as f32, 10000.0, 1.0, RopeMode::Normal);
+            self.kv.cpy_k(0, cell, &k);
+            self.kv.cpy_v(0, cell, &embd(batch.token[i], 1.5));
+        }
+        let scale = 1.0 / (N_EMBD as f32).sqrt();
+        Ok(ubatch
+            .idxs
+            .iter()
+            .filter(|&&i| batch.logits[i])
+            .map(|&i| {
+                let mut q = embd(batch.token[i], 0.0);
+                rope_f32(&mut q, N_EMBD, batch.pos[i] as f32, 10000.0, 1.0, RopeMode::Normal);
+                project(&self.kv.attn(0, 0, &q, batch.seq_id[i][0], batch.pos[i], true, scale))
+            })
+            .collect())
+    }
+}
+
+/// A recurrent layer: each sequence carries a running state, so every
+/// sequence of a ubatch must contribute the same number of tokens
+struct RecurrentGraph {
+    state: BTreeMap<LlamaSeqId, Vec<f32>>,
+}
+
+impl LlamaGraph for RecurrentGraph {
+    fn n_vocab(&self) -> usize {
+        N_VOCAB
+    }
+
+    fn is_recurrent(&self) -> bool {
+        true
+    }
+
+    fn compute(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        if !ubatch.equal_seqs {
+            return Err("recurrent graph needs an equal split".to_string());
+        }
+        let mut counts: BTreeMap<LlamaSeqId, usize> = BTreeMap::new();
+        let mut rows = Vec::new();
+        for &i in &ubatch.idxs {
+            let seq_id = batch.seq_id[i][0];
+            *counts.entry(seq_id).or_default() += 1;
+            let x = embd(batch.token[i], 0.0);
+            let state = self.state.entry(seq_id).or_insert_with(|| vec![0.0; N_EMBD]);
+            for (s, xi) in state.iter_mut().zip(&x) {
+                *s = 0.5 * *s + xi;
+            }
+            if batch.logits[i] {
+                rows.push(project(state));
+            }
+        }
+        if counts.values().any(|&n| n != counts.values().next().copied().unwrap_or(0)) {
+            return Err(format!("unequal sequences in ubatch: {:?}", counts));
+        }
+        Ok(rows)
+    }
+}
+
+/// Three sequences of different lengths, interleaved, with every token an output
+fn mixed_batch() -> LlamaBatch {
+    let mut batch = LlamaBatch::new();
+    let lens = [7, 3, 5];
+    for step in 0..7 {
+        for (s, &len) in lens.iter().enumerate() {
+            if step < len {
+                batch.add(10 * s as i32 + step, step, &[s as LlamaSeqId], true);
+            }
+        }
+    }
+    batch
+}
+
+fn decode_all(graph: &mut dyn LlamaGraph, batch: &LlamaBatch, n_ubatch: usize, split: LlamaSplitStrategy) -> Vec<Vec<f32>> {
+    let mut ctx = LlamaContext::new(0, 64, None);
+    ctx.set_ubatch(n_ubatch, split).unwrap();
+    ctx.decode(batch, graph).unwrap();
+    (0..batch.n_tokens() as i32).map(|i| ctx.get_logits_ith(i).unwrap().to_vec()).collect()
+}
+
+fn assert_close(a: &[Vec<f32>], b: &[Vec<f32>]) {
+    assert_eq!(a.len(), b.len());
+    for (ra, rb) in a.iter().zip(b) {
+        for (x, y) in ra.iter().zip(rb) {
+            assert!((x - y).abs() < 1e-5, "{} != {}", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_batch_split_keeps_sequence_order() {
+    let batch = mixed_batch();
+    for strategy in [LlamaSplitStrategy::Simple, LlamaSplitStrategy::Equal, LlamaSplitStrategy::Seq] {
+        for n_ubatch in 1..=batch.n_tokens() {
+            let ubatches = batch.split(strategy, n_ubatch);
+            let mut idxs: Vec<usize> = ubatches.iter().flat_map(|u| u.idxs.clone()).collect();
+            assert!(ubatches.iter().all(|u| u.n_tokens() <= n_ubatch && u.n_tokens() > 0));
+            // within each sequence, tokens come out in batch order
+            for seq_id in 0..3 {
+                let order: Vec<usize> = idxs.iter().copied().filter(|&i| batch.seq_id[i][0] == seq_id).collect();
+                assert!(order.windows(2).all(|w| w[0] < w[1]), "{} {} {:?}", strategy.name(), n_ubatch, order);
+            }
+            idxs.sort_unstable();
+            assert_eq!(idxs, (0..batch.n_tokens()).collect::<Vec<_>>());
+        }
+    }
+
+    // equal: 3 tokens from each sequence while all three have tokens left
+    let ubatches = batch.split_equal(9);
+    assert_eq!(ubatches[0].idxs, vec![0, 3, 6, 1, 4, 7, 2, 5, 8]);
+    assert!(ubatches.iter().all(|u| u.equal_seqs));
+    // seq: one sequence per ubatch
+    let ubatches = batch.split_seq(4);
+    assert_eq!(ubatches[0].idxs, vec![0, 3, 6, 9]);
+    assert_eq!(ubatches[1].idxs, vec![1, 4, 7]);
+    assert_eq!(LlamaSplitStrategy::from_name("equal"), Some(LlamaSplitStrategy::Equal));
+    assert_eq!(LlamaSplitStrategy::from_name("rows"), None);
+}
+
+#[test]
+fn test_batch_split_equal_shared_tokens() {
+    // a prompt shared by sequences 0 and 1, then a token for each
+    let mut batch = LlamaBatch::new();
+    for pos in 0..3 {
+        batch.add(pos, pos, &[0, 1], false);
+    }
+    batch.add(5, 3, &[0], true);
+    batch.add(6, 3, &[1], true);
+    batch.add(7, 0, &[2], true);
+
+    let ubatches = batch.split_equal(8);
+    let idxs: Vec<Vec<usize>> = ubatches.iter().map(|u| u.idxs.clone()).collect();
+    // the shared tokens go with sequence 2 only, never with 0 or 1
+    assert_eq!(idxs, vec![vec![0, 5], vec![1, 2], vec![3, 4]]);
+
+    assert!(batch.validate().is_ok());
+    batch.seq_id[5].clear();
+    assert!(batch.validate().is_err());
+}
+
+#[test]
+fn test_batch_decode_logits_independent_of_split() {
+    let batch = mixed_batch();
+    let reference = decode_all(&mut AttnGraph::new(), &batch, batch.n_tokens(), LlamaSplitStrategy::Simple);
+    for strategy in [LlamaSplitStrategy::Simple, LlamaSplitStrategy::Equal, LlamaSplitStrategy::Seq] {
+        for n_ubatch in [1, 2, 3, 4, 7, 32] {
+            assert_close(&decode_all(&mut AttnGraph::new(), &batch, n_ubatch, strategy), &reference);
+        }
+    }
+
+    // only the requested rows are kept; -1 is the last output
+    let mut sparse = mixed_batch();
+    sparse.logits.iter_mut().enumerate().for_each(|(i, l)| *l = i % 4 == 3);
+    let mut ctx = LlamaContext::new(0, 64, None);
+    ctx.set_ubatch(3, LlamaSplitStrategy::Equal).unwrap();
+    ctx.decode(&sparse, &mut AttnGraph::new()).unwrap();
+    assert!(ctx.get_logits_ith(0).is_none());
+    assert_close(&[ctx.get_logits_ith(3).unwrap().to_vec()], &reference[3..4]);
+    assert_eq!(ctx.get_logits_ith(-1), ctx.get_logits_ith(11));
+    assert!(ctx.decode(&LlamaBatch::new(), &mut AttnGraph::new()).is_err());
+    assert!(ctx.set_ubatch(0, LlamaSplitStrategy::Simple).is_err());
+}
+
+#[test]
+fn test_batch_decode_recurrent_uses_equal_split() {
+    let batch = mixed_batch();
+    let fresh = || RecurrentGraph { state: BTreeMap::new() };
+    let reference = decode_all(&mut fresh(), &batch, batch.n_tokens(), LlamaSplitStrategy::Seq);
+    for strategy in [LlamaSplitStrategy::Simple, LlamaSplitStrategy::Equal, LlamaSplitStrategy::Seq] {
+        for n_ubatch in [1, 2, 3, 5, 32] {
+            // the recurrent state is updated in the same order, so logits are exact
+            assert_eq!(decode_all(&mut fresh(), &batch, n_ubatch, strategy), reference);
+        }
+    }
+}
+
+#[test]
+fn test_batch_c_api_decode() {
+    let model = llama_model_load_from_file(cstr("llmrust_no_such_model.gguf").as_ptr(), llama_model_default_params());
+    let mut params = llama_context_default_params();
+    params.n_ctx = 8;
+    params.n_seq_max = 2;
+    let ctx = llama_init_from_model(model, params);
+    let mem = llama_get_memory(ctx as *mut _);
+
+    // a prompt of two sequences, with the logits of the last token of each
+    let mut batch = llama_batch_init(4, 0, 2);
+    for (i, (seq_id, pos)) in [(0, 0), (0, 1), (1, 0), (1, 1)].into_iter().enumerate() {
+        common_batch_add(&mut batch, 10 + i as i32, pos, [seq_id].as_ptr(), 1, pos == 1);
+    }
+    // the batch is full
+    common_batch_add(&mut batch, 20, 2, [0].as_ptr(), 1, true);
+    assert_eq!(batch.n_tokens, 4);
+    assert_eq!(llama_decode(ctx, batch), 0);
+    assert!(llama_get_logits_ith(ctx, 0).is_null());
+    let n_vocab = mock_model_hparams().n_vocab as usize;
+    let argmax = |i: i32| {
+        let logits = unsafe { std::slice::from_raw_parts(llama_get_logits_ith(ctx, i), n_vocab) };
+        (0..n_vocab).max_by(|&a, &b| logits[a].total_cmp(&logits[b])).unwrap() as i32
+    };
+    assert_eq!((argmax(1), argmax(3)), (12, 14));
+    assert_eq!((llama_memory_seq_pos_max(mem, 0), llama_memory_seq_pos_max(mem, 1)), (1, 1));
+
+    // a batch of bare tokens continues sequence 0 and outputs its last token
+    let tokens = [30, 31];
+    assert_eq!(llama_decode(ctx, llama_batch_get_one(tokens.as_ptr(), 2)), 0);
+    assert_eq!(argmax(-1), 32);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), 3);
+
+    // an invalid sequence is rejected; a batch that does not fit in the cache
+    // is not decoded
+    common_batch_clear(&mut batch);
+    assert_eq!(batch.n_tokens, 0);
+    // more sequences than the batch was allocated for, or a missing list,
+    // leave the token out
+    common_batch_add(&mut batch, 1, 0, [0, 1, 2].as_ptr(), 3, true);
+    common_batch_add(&mut batch, 1, 0, std::ptr::null(), 1, true);
+    assert_eq!(batch.n_tokens, 0);
+    let mut unallocated = llama_batch_get_one(tokens.as_ptr(), 0);
+    common_batch_add(&mut unallocated, 1, 0, [0].as_ptr(), 1, true);
+    assert_eq!(unallocated.n_tokens, 0);
+    common_batch_add(&mut batch, 1, 0, [-1].as_ptr(), 1, true);
+    assert_eq!(llama_decode(ctx, batch), -1);
+    let tokens = [40, 41, 42];
+    assert_eq!(llama_decode(ctx, llama_batch_get_one(tokens.as_ptr(), 3)), 1);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), 3);
+    assert_eq!(llama_decode(std::ptr::null_mut(), batch), -1);
+
+    llama_batch_free(batch);
+    llama_free(ctx);
+
+    // a failed evaluation takes its cells back out of the memory, so the same
+    // positions can be decoded again
+    let mut params = llama_context_default_params();
+    params.n_ctx = 8;
+    params.embeddings = true;
+    params.pooling_type = LLAMA_POOLING_TYPE_RANK;
+    let ctx = llama_init_from_model(model, params);
+    let mem = llama_get_memory(ctx as *mut _);
+    let tokens = [1, 2, 3];
+    assert_eq!(llama_decode(ctx, llama_batch_get_one(tokens.as_ptr(), 3)), -2);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), -1);
+    llama_set_embeddings(ctx, false);
+    assert_eq!(llama_decode(ctx, llama_batch_get_one(tokens.as_ptr(), 3)), 0);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), 2);
+    llama_free(ctx);
+    llama_model_free(model);
+}
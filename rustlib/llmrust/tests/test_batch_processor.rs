@@ -0,0 +1,137 @@
+// tests/test_batch_processor.rs - Continuous batching scheduler tests
+
+use crate::llmrust::src::batch_processor::{BatchScheduler, BatchStopReason};
+
+#[test]
+fn test_batch_scheduler_packs_decode_and_prompts() {
+    let mut scheduler = BatchScheduler::new(8, 4).unwrap();
+    scheduler.add(0, vec![10, 11, 12, 13, 14], 0, 3).unwrap();
+    scheduler.add(1, (20..30).collect(), 0, 3).unwrap();
+    assert!(scheduler.add(1, vec![1], 0, 1).is_err());
+    assert!(scheduler.add(2, vec![], 0, 1).is_err());
+    assert!(scheduler.add(2, vec![1, 2], 2, 1).is_err());
+
+    // seq 0's whole prompt, then the first chunk of seq 1's
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!(step.batch.token, vec![10, 11, 12, 13, 14, 20, 21, 22]);
+    assert_eq!(step.batch.pos, vec![0, 1, 2, 3, 4, 0, 1, 2]);
+    assert_eq!(step.batch.logits, vec![false, false, false, false, true, false, false, false]);
+    assert_eq!(step.outputs, vec![0]);
+    assert_eq!(step.ubatches.iter().map(|u| u.idxs.clone()).collect::<Vec<_>>(), vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]);
+    assert!(scheduler.commit(&step, &[], |_| false).is_err());
+    assert!(scheduler.commit(&step, &[100], |_| false).unwrap().is_empty());
+
+    // the sampled token is decoded first, next to the rest of the prompt
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!(step.batch.token, vec![100, 23, 24, 25, 26, 27, 28, 29]);
+    assert_eq!(step.batch.pos[..2], [5, 3]);
+    assert_eq!(step.batch.n_outputs(), 2);
+    assert_eq!(step.outputs, vec![0, 1]);
+    assert!(scheduler.commit(&step, &[101, 200], |_| false).unwrap().is_empty());
+
+    // generating sequences take turns at the front of the batch
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!(step.batch.token, vec![200, 101]);
+    assert_eq!(step.batch.seq_id, vec![vec![1], vec![0]]);
+    assert_eq!(step.batch.pos, vec![10, 6]);
+    let finished = scheduler.commit(&step, &[201, 102], |_| false).unwrap();
+    assert_eq!(finished.len(), 1);
+    assert_eq!(finished[0].seq_id, 0);
+    assert_eq!(finished[0].generated(), &[100, 101, 102]);
+    assert_eq!(finished[0].stop, Some(BatchStopReason::Length));
+    assert_eq!(scheduler.len(), 1);
+}
+
+#[test]
+fn test_batch_scheduler_stops_and_removes() {
+    let mut scheduler = BatchScheduler::new(16, 16).unwrap();
+    // 3 of 4 prompt tokens already in memory
+    scheduler.add(0, vec![1, 2, 3, 4], 3, 10).unwrap();
+    scheduler.add(1, vec![5, 6], 0, 10).unwrap();
+    scheduler.add(2, vec![7], 0, 0).unwrap();
+
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!(step.batch.token, vec![4, 5, 6, 7]);
+    // no logits for a sequence that generates nothing
+    assert_eq!(step.batch.logits, vec![true, false, true, false]);
+    let finished = scheduler.commit(&step, &[2, 9], |t| t == 2).unwrap();
+    let stops: Vec<_> = finished.iter().map(|s| (s.seq_id, s.stop)).collect();
+    assert_eq!(stops, vec![(0, Some(BatchStopReason::Eog)), (2, Some(BatchStopReason::Length))]);
+
+    // a sequence removed while its batch is evaluated is skipped
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!(step.outputs, vec![1]);
+    assert_eq!(scheduler.remove(1).unwrap().generated(), &[9]);
+    assert!(scheduler.commit(&step, &[3], |_| false).unwrap().is_empty());
+    assert!(scheduler.next_batch().is_none());
+}
+
+#[test]
+fn test_batch_scheduler_rotates_decode_tokens() {
+    let mut scheduler = BatchScheduler::new(2, 1).unwrap();
+    for seq_id in 0..3 {
+        scheduler.add(seq_id, vec![seq_id], 0, 10).unwrap();
+    }
+    let mut decoded = Vec::new();
+    for _ in 0..6 {
+        let step = scheduler.next_batch().unwrap();
+        assert_eq!(step.ubatches.len(), step.batch.n_tokens());
+        let sampled = vec![0; step.outputs.len()];
+        decoded.extend(step.outputs.clone());
+        scheduler.commit(&step, &sampled, |_| false).unwrap();
+    }
+    // every sequence advances even though only two fit in a batch
+    for seq_id in 0..3 {
+        assert!(decoded.iter().filter(|&&s| s == seq_id).count() >= 3, "{:?}", decoded);
+    }
+}
+
+#[test]
+fn test_batch_scheduler_single_token_batches_alternate() {
+    let mut scheduler = BatchScheduler::new(1, 1).unwrap();
+    scheduler.add(0, vec![1], 0, 10).unwrap();
+    let step = scheduler.next_batch().unwrap();
+    scheduler.commit(&step, &[2], |_| false).unwrap();
+
+    // a prompt arriving while sequence 0 generates still gets evaluated
+    scheduler.add(1, vec![3, 4, 5], 0, 10).unwrap();
+    let mut steps = Vec::new();
+    for _ in 0..6 {
+        let step = scheduler.next_batch().unwrap();
+        assert_eq!(step.batch.n_tokens(), 1);
+        steps.push(step.batch.seq_id[0][0]);
+        let sampled = vec![0; step.outputs.len()];
+        scheduler.commit(&step, &sampled, |_| false).unwrap();
+    }
+    assert_eq!(steps, vec![1, 0, 1, 0, 1, 0]);
+    assert!(scheduler.get(1).unwrap().is_generating());
+}
+
+#[test]
+fn test_batch_scheduler_keeps_groups_apart() {
+    let mut scheduler = BatchScheduler::new(16, 16).unwrap();
+    scheduler.add_to_group(0, vec![1, 2], 0, 2, 7).unwrap();
+    scheduler.add(1, vec![3, 4, 5], 0, 2).unwrap();
+    scheduler.add_to_group(2, vec![6], 0, 2, 7).unwrap();
+
+    // groups take turns, in group order
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!((step.group, step.batch.token.clone()), (0, vec![3, 4, 5]));
+    scheduler.commit(&step, &[10], |_| false).unwrap();
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!((step.group, step.batch.token.clone()), (7, vec![1, 2, 6]));
+    assert_eq!(step.outputs, vec![0, 2]);
+    scheduler.commit(&step, &[20, 30], |_| false).unwrap();
+    let step = scheduler.next_batch().unwrap();
+    assert_eq!((step.group, step.batch.seq_id.clone()), (0, vec![vec![1]]));
+    scheduler.commit(&step, &[11], |_| false).unwrap();
+
+    // a group without work is skipped
+    let step = scheduler.next_batch().unwrap();
+    let mut tokens = step.batch.token.clone();
+    tokens.sort_unstable();
+    assert_eq!((step.group, tokens), (7, vec![20, 30]));
+    scheduler.commit(&step, &[21, 31], |_| false).unwrap();
+    assert!(scheduler.next_batch().is_none());
+    assert!(scheduler.is_empty());
+}
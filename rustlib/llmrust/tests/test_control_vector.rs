@@ -0,0 +1,146 @@
+// tests/test_control_vector.rs - Control vector loading and application tests
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::llmrust::common::common::{common_control_vector_load, CommonControlVectorLoadInfo};
+use crate::llmrust::ggml::src::gguf::GgufWriter;
+use crate::llmrust::src::adapter_loader::llama_control_vector_load;
+use crate::llmrust::src::llama_adapter::LlamaAdapterCvec;
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaUbatch};
+use crate::llmrust::src::llama_context::LlamaContext;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+
+const N_EMBD: usize = 3;
+const N_LAYER: u32 = 4;
+
+fn hparams() -> LlamaHparams {
+    LlamaHparams { n_embd: N_EMBD as u32, n_layer: N_LAYER, ..Default::default() }
+}
+
+struct Fixture {
+    path: PathBuf,
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn cvec_file(name: &str, directions: &[(&str, &[f32])]) -> Fixture {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama").set_str("controlvector.model_hint", "llama");
+    for (tensor, values) in directions {
+        w.add_tensor_f32(tensor, &[values.len()], values).unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("llmrust_cvec_{}_{}.gguf", name, std::process::id()));
+    w.write(&path).unwrap();
+    Fixture { path }
+}
+
+fn info(fixture: &Fixture, strength: f32) -> CommonControlVectorLoadInfo {
+    CommonControlVectorLoadInfo { strength, fname: fixture.path.display().to_string() }
+}
+
+/// Identity layers: the hidden state of token t starts as [t, 0, 0] and the
+/// control vector is added after every layer; the logits are the final state
+struct ResidualGraph {
+    cvec: Arc<LlamaAdapterCvec>,
+}
+
+impl LlamaGraph for ResidualGraph {
+    fn n_vocab(&self) -> usize {
+        N_EMBD
+    }
+
+    fn set_adapter_cvec(&mut self, cvec: &Arc<LlamaAdapterCvec>) {
+        self.cvec = Arc::clone(cvec);
+    }
+
+    fn compute(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        Ok(ubatch
+            .idxs
+            .iter()
+            .filter(|&&i| batch.logits[i])
+            .map(|&i| {
+                let mut cur = vec![batch.token[i] as f32, 0.0, 0.0];
+                for il in 0..N_LAYER as i32 {
+                    self.cvec.apply_to(&mut cur, il);
+                }
+                cur
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn test_control_vector_load_and_sum() {
+    let happy = cvec_file("happy", &[("direction.1", &[1.0, 0.0, 0.0]), ("direction.3", &[0.0, 2.0, 0.0])]);
+    let calm = cvec_file("calm", &[("direction.2", &[0.0, 0.0, 4.0]), ("direction.3", &[1.0, 1.0, 1.0])]);
+
+    let file = llama_control_vector_load(&happy.path).unwrap();
+    assert_eq!(file.n_embd, N_EMBD);
+    assert_eq!(file.directions.keys().copied().collect::<Vec<_>>(), vec![1, 3]);
+
+    let data = common_control_vector_load(&[info(&happy, 2.0), info(&calm, -0.5)]).unwrap();
+    assert_eq!(data.n_embd, N_EMBD);
+    assert_eq!(data.n_layers(), 3);
+    // layers 1, 2 and 3
+    assert_eq!(data.data, vec![2.0, 0.0, 0.0, 0.0, 0.0, -2.0, -0.5, 3.5, -0.5]);
+
+    let wide = cvec_file("wide", &[("direction.1", &[1.0; 4])]);
+    assert!(common_control_vector_load(&[info(&happy, 1.0), info(&wide, 1.0)]).unwrap_err().contains("n_embd"));
+    let zero = cvec_file("zero", &[("direction.0", &[1.0; 3])]);
+    assert!(llama_control_vector_load(&zero.path).is_err());
+    let ragged = cvec_file("ragged", &[("direction.1", &[1.0; 3]), ("direction.2", &[1.0; 2])]);
+    assert!(llama_control_vector_load(&ragged.path).is_err());
+    let other = cvec_file("other", &[("output.weight", &[1.0; 3])]);
+    assert!(llama_control_vector_load(&other.path).is_err());
+    assert!(common_control_vector_load(&[]).is_err());
+}
+
+#[test]
+fn test_control_vector_layer_range() {
+    let data = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+    assert!(LlamaAdapterCvec::new(&hparams(), &data, 2, 1, 3).is_err());
+
+    let cvec = LlamaAdapterCvec::new(&hparams(), &data, N_EMBD, 2, 3).unwrap();
+    assert!(cvec.tensor_for(0).is_none());
+    assert!(cvec.tensor_for(1).is_none());
+    assert_eq!(cvec.tensor_for(2), Some(&[0.0, 1.0, 0.0][..]));
+    assert_eq!(cvec.tensor_for(3), Some(&[0.0, 0.0, 1.0][..]));
+    assert!(cvec.tensor_for(4).is_none());
+    assert_eq!(cvec.layer_range(), (2, 3));
+    assert_eq!(cvec.fingerprint(), LlamaAdapterCvec::new(&hparams(), &data, N_EMBD, 2, 3).unwrap().fingerprint());
+    assert_ne!(cvec.fingerprint(), LlamaAdapterCvec::new(&hparams(), &data, N_EMBD, 1, 3).unwrap().fingerprint());
+
+    // data for fewer layers than the model has
+    let short = LlamaAdapterCvec::new(&hparams(), &data[..3], N_EMBD, 0, 10).unwrap();
+    assert_eq!(short.tensor_for(1), Some(&[1.0, 0.0, 0.0][..]));
+    assert!(short.tensor_for(2).is_none());
+    assert!(LlamaAdapterCvec::default().is_empty());
+}
+
+#[test]
+fn test_control_vector_on_context() {
+    let mut batch = LlamaBatch::new();
+    batch.add(5, 0, &[0], true);
+    let mut ctx = LlamaContext::new(0, 64, None);
+    let mut graph = ResidualGraph { cvec: Arc::default() };
+    let mut logits = |ctx: &mut LlamaContext| {
+        ctx.decode(&batch, &mut graph).unwrap();
+        ctx.get_logits_ith(0).unwrap().to_vec()
+    };
+    assert_eq!(logits(&mut ctx), vec![5.0, 0.0, 0.0]);
+
+    let data = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+    ctx.set_adapter_cvec(LlamaAdapterCvec::new(&hparams(), &data, N_EMBD, 1, 3).unwrap());
+    assert_eq!(logits(&mut ctx), vec![6.0, 1.0, 1.0]);
+    ctx.set_adapter_cvec(LlamaAdapterCvec::new(&hparams(), &data, N_EMBD, 2, 2).unwrap());
+    assert_eq!(logits(&mut ctx), vec![5.0, 1.0, 0.0]);
+    ctx.set_adapter_cvec(LlamaAdapterCvec::default());
+    assert!(ctx.adapter_cvec().is_empty());
+    assert_eq!(logits(&mut ctx), vec![5.0, 0.0, 0.0]);
+}
@@ -0,0 +1,142 @@
+// tests/test_embeddings.rs - Embedding extraction and pooling tests
+
+use crate::llmrust::common::common::{common_embd_normalize, common_embd_similarity_cos};
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaSplitStrategy, LlamaUbatch};
+use crate::llmrust::src::llama_context::LlamaContext;
+use crate::llmrust::src::llama_cparams::LlamaCparams;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::LlamaPoolingType;
+
+/// Hidden state of a token is [token, pos, 1]; the classification head sums it
+struct EncoderGraph {
+    pooling: LlamaPoolingType,
+    has_head: bool,
+}
+
+impl LlamaGraph for EncoderGraph {
+    fn n_vocab(&self) -> usize {
+        8
+    }
+
+    fn compute(&mut self, _batch: &LlamaBatch, _ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        Err("encoder only".to_string())
+    }
+
+    fn n_embd(&self) -> usize {
+        3
+    }
+
+    fn pooling_type(&self) -> LlamaPoolingType {
+        self.pooling
+    }
+
+    fn embed(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        Ok(ubatch.idxs.iter().map(|&i| vec![batch.token[i] as f32, batch.pos[i] as f32, 1.0]).collect())
+    }
+
+    fn classify(&self, cls: &[f32]) -> Result<Vec<f32>, String> {
+        match self.has_head {
+            true => Ok(vec![cls.iter().sum()]),
+            false => Err("no head".to_string()),
+        }
+    }
+}
+
+fn graph(pooling: LlamaPoolingType) -> EncoderGraph {
+    EncoderGraph { pooling, has_head: true }
+}
+
+/// Sequence 0: tokens 2, 4, 6; sequence 1: tokens 3, 5, interleaved
+fn two_seqs(logits: bool) -> LlamaBatch {
+    let mut batch = LlamaBatch::new();
+    batch.add(2, 0, &[0], logits);
+    batch.add(3, 0, &[1], logits);
+    batch.add(4, 1, &[0], logits);
+    batch.add(5, 1, &[1], true);
+    batch.add(6, 2, &[0], true);
+    batch
+}
+
+fn embd_ctx(pooling: LlamaPoolingType, n_ubatch: usize) -> LlamaContext {
+    let cparams = LlamaCparams { n_ubatch, embeddings: true, pooling_type: pooling, ..Default::default() };
+    LlamaContext::with_cparams(0, cparams, None).unwrap()
+}
+
+#[test]
+fn test_embeddings_pooling_modes() {
+    for n_ubatch in [1, 2, 5] {
+        let mut ctx = embd_ctx(LlamaPoolingType::Mean, n_ubatch);
+        ctx.decode(&two_seqs(false), &mut graph(LlamaPoolingType::None)).unwrap();
+        assert_eq!(ctx.get_embeddings_seq(0).unwrap(), &[4.0, 1.0, 1.0]);
+        assert_eq!(ctx.get_embeddings_seq(1).unwrap(), &[4.0, 0.5, 1.0]);
+        assert!(ctx.get_embeddings_seq(2).is_none());
+        assert!(ctx.get_embeddings_ith(0).is_none());
+    }
+
+    let mut ctx = embd_ctx(LlamaPoolingType::Cls, 2);
+    ctx.decode(&two_seqs(false), &mut graph(LlamaPoolingType::None)).unwrap();
+    assert_eq!(ctx.get_embeddings_seq(0).unwrap(), &[2.0, 0.0, 1.0]);
+    assert_eq!(ctx.get_embeddings_seq(1).unwrap(), &[3.0, 0.0, 1.0]);
+
+    let mut ctx = embd_ctx(LlamaPoolingType::Last, 2);
+    ctx.decode(&two_seqs(false), &mut graph(LlamaPoolingType::None)).unwrap();
+    assert_eq!(ctx.get_embeddings_seq(0).unwrap(), &[6.0, 2.0, 1.0]);
+    assert_eq!(ctx.get_embeddings_seq(1).unwrap(), &[5.0, 1.0, 1.0]);
+
+    // rank: the classification head over the CLS state
+    let mut ctx = embd_ctx(LlamaPoolingType::Rank, 2);
+    ctx.decode(&two_seqs(false), &mut graph(LlamaPoolingType::None)).unwrap();
+    assert_eq!(ctx.get_embeddings_seq(0).unwrap(), &[3.0]);
+    assert_eq!(ctx.get_embeddings_seq(1).unwrap(), &[4.0]);
+    let mut headless = EncoderGraph { pooling: LlamaPoolingType::Rank, has_head: false };
+    assert!(ctx.decode(&two_seqs(false), &mut headless).is_err());
+
+    // CLS needs the token at position 0
+    let mut batch = LlamaBatch::new();
+    batch.add(7, 3, &[0], true);
+    let mut ctx = embd_ctx(LlamaPoolingType::Cls, 2);
+    assert!(ctx.decode(&batch, &mut graph(LlamaPoolingType::None)).is_err());
+}
+
+#[test]
+fn test_embeddings_without_pooling_and_model_default() {
+    // no pooling: one row per output token, like logits
+    let mut ctx = embd_ctx(LlamaPoolingType::None, 2);
+    ctx.decode(&two_seqs(false), &mut graph(LlamaPoolingType::Mean)).unwrap();
+    assert!(ctx.get_embeddings_ith(0).is_none());
+    assert_eq!(ctx.get_embeddings_ith(3).unwrap(), &[5.0, 1.0, 1.0]);
+    assert_eq!(ctx.get_embeddings_ith(-1).unwrap(), &[6.0, 2.0, 1.0]);
+    assert!(ctx.get_embeddings_seq(0).is_none());
+    assert!(ctx.get_logits_ith(3).is_none());
+
+    // unspecified falls back to the model's pooling
+    let mut ctx = embd_ctx(LlamaPoolingType::Unspecified, 4);
+    ctx.decode(&two_seqs(true), &mut graph(LlamaPoolingType::Last)).unwrap();
+    assert_eq!(ctx.get_embeddings_seq(1).unwrap(), &[5.0, 1.0, 1.0]);
+    assert_eq!(LlamaCparams::default().pooling_for(LlamaPoolingType::Unspecified), LlamaPoolingType::None);
+
+    // switching back to logits uses the graph's logits
+    ctx.set_embeddings(false);
+    assert!(ctx.decode(&two_seqs(true), &mut graph(LlamaPoolingType::Last)).is_err());
+    assert!(LlamaContext::with_cparams(0, LlamaCparams { n_ubatch: 0, ..Default::default() }, None).is_err());
+    assert_eq!(embd_ctx(LlamaPoolingType::Mean, 3).split(), LlamaSplitStrategy::Simple);
+    assert_eq!(LlamaPoolingType::from_raw(4), Some(LlamaPoolingType::Rank));
+    assert_eq!(LlamaPoolingType::from_raw(5), None);
+}
+
+#[test]
+fn test_embeddings_normalize() {
+    let v = [3.0, -4.0];
+    assert_eq!(common_embd_normalize(&v, -1), vec![3.0, -4.0]);
+    assert_eq!(common_embd_normalize(&v, 2), vec![0.6, -0.8]);
+    let taxicab = common_embd_normalize(&v, 1);
+    assert!((taxicab[0] - 3.0 / 7.0).abs() < 1e-6 && (taxicab[1] + 4.0 / 7.0).abs() < 1e-6);
+    assert_eq!(common_embd_normalize(&v, 0), vec![24570.0, -32760.0]);
+    let p3 = common_embd_normalize(&v, 3);
+    assert!((p3.iter().map(|x| x.abs().powi(3)).sum::<f32>() - 1.0).abs() < 1e-5);
+    assert_eq!(common_embd_normalize(&[0.0, 0.0], 2), vec![0.0, 0.0]);
+
+    assert!((common_embd_similarity_cos(&v, &[6.0, -8.0]) - 1.0).abs() < 1e-6);
+    assert!(common_embd_similarity_cos(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+    assert_eq!(common_embd_similarity_cos(&[0.0], &[0.0]), 1.0);
+}
@@ -0,0 +1,175 @@
+// tests/test_flash_attn.rs - Fused attention kernel tests
+
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_cpu::ops::{attn_head, flash_attn_ext, FLASH_ATTN_KV_TILE};
+use crate::llmrust::ggml::src::ggml_quants::quantize_row;
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+use crate::llmrust::src::llama_cparams::{LlamaCparams, LlamaFlashAttnType};
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvCachePaged, LlamaKvUbatchToken};
+use crate::llmrust::src::llama_model::llama_model_load_hparams;
+use crate::llmrust::src::llama_model_loader::LlamaModelLoader;
+
+fn data(n: usize, seed: f32) -> Vec<f32> {
+    (0..n).map(|i| ((i as f32 + seed) * 0.731).sin() * 2.0 - 0.3).collect()
+}
+
+fn max_err(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max)
+}
+
+fn store(ty: GgmlType, rows: &[Vec<f32>]) -> Vec<Vec<u8>> {
+    rows.iter()
+        .map(|r| {
+            let mut b = vec![0u8; ty.row_size(r.len())];
+            quantize_row(ty, r, &mut b);
+            b
+        })
+        .collect()
+}
+
+/// Naive attention of each head of `q`, one after the other
+#[allow(clippy::too_many_arguments)]
+fn naive(q: &[f32], head: usize, k: &[&[u8]], type_k: GgmlType, v: &[&[u8]], type_v: GgmlType, mask: &[f32], scale: f32) -> Vec<f32> {
+    let mut out = vec![0.0; q.len()];
+    for (q, o) in q.chunks_exact(head).zip(out.chunks_exact_mut(head)) {
+        attn_head(q, k, type_k, v, type_v, mask, scale, o);
+    }
+    out
+}
+
+#[test]
+fn test_flash_attn_matches_naive() {
+    let (head, n_q) = (64, 4);
+    // two full tiles and a partial one; scores grow along the rows so the
+    // running maximum changes from tile to tile
+    let n_kv = 2 * FLASH_ATTN_KV_TILE + 11;
+    let q = data(head * n_q, 3.0);
+    let keys: Vec<Vec<f32>> = (0..n_kv).map(|i| data(head, 7.0 * i as f32).iter().map(|x| x * (1.0 + i as f32 / 20.0)).collect()).collect();
+    let values: Vec<Vec<f32>> = (0..n_kv).map(|i| data(head, 5.0 + 3.0 * i as f32)).collect();
+    // causal at position 60 with a hole, and the whole first tile masked
+    let mask: Vec<f32> = (0..n_kv)
+        .map(|i| if !(FLASH_ATTN_KV_TILE..=60).contains(&i) || i == 45 { f32::NEG_INFINITY } else { 0.0 })
+        .collect();
+
+    for scale in [0.125, 4.0] {
+        for (type_k, type_v) in [
+            (GgmlType::F32, GgmlType::F32),
+            (GgmlType::F16, GgmlType::F16),
+            (GgmlType::Q8_0, GgmlType::Q8_0),
+            (GgmlType::Q8_0, GgmlType::Q4_0),
+            (GgmlType::Q4_0, GgmlType::F16),
+        ] {
+            let (k, v) = (store(type_k, &keys), store(type_v, &values));
+            let k: Vec<&[u8]> = k.iter().map(|r| r.as_slice()).collect();
+            let v: Vec<&[u8]> = v.iter().map(|r| r.as_slice()).collect();
+            let expected = naive(&q, head, &k, type_k, &v, type_v, &mask, scale);
+            let mut out = vec![f32::NAN; head * n_q];
+            flash_attn_ext(&q, n_q, &k, type_k, &v, type_v, &mask, scale, &mut out);
+            let err = max_err(&out, &expected);
+            assert!(err < 1e-4, "K {} / V {} scale {}: error {}", type_k.name(), type_v.name(), scale, err);
+        }
+    }
+
+    // every row masked, or no rows at all: zeros
+    let k = store(GgmlType::F16, &keys);
+    let k: Vec<&[u8]> = k.iter().map(|r| r.as_slice()).collect();
+    let mut out = vec![1.0; head * n_q];
+    flash_attn_ext(&q, n_q, &k, GgmlType::F16, &k, GgmlType::F16, &vec![f32::NEG_INFINITY; n_kv], 1.0, &mut out);
+    assert!(out.iter().all(|&x| x == 0.0));
+    let mut out = vec![1.0; head];
+    flash_attn_ext(&q[..head], 1, &[], GgmlType::F16, &[], GgmlType::F16, &[], 1.0, &mut out);
+    assert!(out.iter().all(|&x| x == 0.0));
+}
+
+fn hparams() -> LlamaHparams {
+    LlamaHparams {
+        n_layer: 2,
+        n_head: 4,
+        n_head_kv: 2,
+        n_embd_head_k: 32,
+        n_embd_head_v: 32,
+        n_rot: 32,
+        // layer 0 on a 3-token window, layer 1 full
+        n_swa: 3,
+        n_swa_pattern: 2,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_flash_attn_kv_cache_swa_and_gqa() {
+    let hp = hparams();
+    assert!(hp.is_swa(0) && !hp.is_swa(1));
+    let n_k = hp.n_embd_k_gqa() as usize;
+    let row = |pos: i32, seed: f32| data(n_k, pos as f32 * 3.0 + seed);
+
+    let mut cells = LlamaKvCache::new(&hp, 32, 1, GgmlType::Q8_0, GgmlType::F16).unwrap();
+    let mut blocks = LlamaKvCachePaged::new(&hp, 4, 4, GgmlType::Q8_0, GgmlType::F16).unwrap();
+    let tokens: Vec<LlamaKvUbatchToken> = (0..10).map(|pos| LlamaKvUbatchToken { pos, seq_ids: vec![0] }).collect();
+    for (&cell, pos) in cells.apply_ubatch(&tokens).unwrap().iter().zip(0..) {
+        let slot = blocks.append(0, pos).unwrap();
+        for il in 0..2 {
+            cells.cpy_k(il, cell, &row(pos, 0.0));
+            cells.cpy_v(il, cell, &row(pos, 1.0));
+            blocks.cpy_k(il, slot, &row(pos, 0.0));
+            blocks.cpy_v(il, slot, &row(pos, 1.0));
+        }
+    }
+
+    // the two query heads of a GQA group at once
+    let q = data(64, 11.0);
+    for il in 0..2 {
+        for kv_head in 0..2 {
+            let naive = cells.attn(il, kv_head, &q, 0, 9, true, 0.2);
+            assert_eq!(naive.len(), 64);
+            assert_eq!(&naive[32..], cells.attn(il, kv_head, &q[32..], 0, 9, true, 0.2));
+            cells.set_flash_attn(true);
+            blocks.set_flash_attn(true);
+            let fused = cells.attn(il, kv_head, &q, 0, 9, true, 0.2);
+            let paged = blocks.attn(il, kv_head, &q, 0, 9, true, 0.2);
+            cells.set_flash_attn(false);
+            blocks.set_flash_attn(false);
+            assert!(max_err(&fused, &naive) < 1e-4 && max_err(&paged, &naive) < 1e-4);
+        }
+    }
+
+    // layer 0 only sees positions 7..=9, layer 1 everything up to 9
+    let window = |positions: std::ops::Range<i32>| {
+        let k = store(GgmlType::Q8_0, &positions.clone().map(|p| row(p, 0.0)[..32].to_vec()).collect::<Vec<_>>());
+        let v = store(GgmlType::F16, &positions.map(|p| row(p, 1.0)[..32].to_vec()).collect::<Vec<_>>());
+        let k: Vec<&[u8]> = k.iter().map(|r| r.as_slice()).collect();
+        let v: Vec<&[u8]> = v.iter().map(|r| r.as_slice()).collect();
+        naive(&q[..32], 32, &k, GgmlType::Q8_0, &v, GgmlType::F16, &vec![0.0; k.len()], 0.2)
+    };
+    assert!(max_err(&cells.attn(0, 0, &q[..32], 0, 9, true, 0.2), &window(7..10)) < 1e-5);
+    assert!(max_err(&cells.attn(1, 0, &q[..32], 0, 9, true, 0.2), &window(0..10)) < 1e-5);
+    assert!(max_err(&blocks.attn(0, 0, &q[..32], 0, 9, true, 0.2), &window(7..10)) < 1e-5);
+}
+
+#[test]
+fn test_flash_attn_type() {
+    assert!(LlamaCparams::default().flash_attn());
+    let off = LlamaCparams { flash_attn_type: LlamaFlashAttnType::Disabled, ..Default::default() };
+    assert!(!off.flash_attn());
+    assert_eq!(LlamaFlashAttnType::from_raw(1), Some(LlamaFlashAttnType::Enabled));
+    assert_eq!(LlamaFlashAttnType::from_name("off"), Some(LlamaFlashAttnType::Disabled));
+    assert_eq!(LlamaFlashAttnType::Auto.name(), "auto");
+    assert!(LlamaFlashAttnType::from_raw(2).is_none());
+
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama")
+        .set("llama.context_length", GgufValue::U32(8192))
+        .set("llama.embedding_length", GgufValue::U32(64))
+        .set("llama.block_count", GgufValue::U32(6))
+        .set("llama.feed_forward_length", GgufValue::U32(128))
+        .set("llama.attention.head_count", GgufValue::U32(4))
+        .set("llama.attention.sliding_window", GgufValue::U32(1024))
+        .set("llama.attention.sliding_window_pattern", GgufValue::U32(3));
+    let ml = LlamaModelLoader::new(GgufFile::from_bytes(&w.to_bytes()).unwrap(), Vec::new());
+    let hp = llama_model_load_hparams(&ml, "llama").unwrap();
+    assert_eq!(hp.n_swa, 1024);
+    let swa: Vec<bool> = (0..6).map(|il| hp.is_swa(il)).collect();
+    assert_eq!(swa, [true, true, false, true, true, false]);
+    assert!(!LlamaHparams::default().is_swa(0));
+}
@@ -1,6 +1,100 @@
-// tests/test_gguf.rs - Auto-generated debug file
-#![allow(dead_code)]
+// tests/test_gguf.rs - GGUF reading and writing tests
 
-pub fn debug_print() {
-    println!("DEBUG: tests/test_gguf.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_quants::quantize_row;
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+
+#[test]
+fn test_gguf_roundtrip_metadata_and_tensors() {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama")
+        .set("llama.block_count", GgufValue::U32(2))
+        .set("llama.rope.freq_base", GgufValue::F32(10000.0))
+        .set("general.tags", GgufValue::Array(vec![GgufValue::String("a".into()), GgufValue::String("b".into())]))
+        .set("flag", GgufValue::Bool(true))
+        .set("big", GgufValue::I64(-5));
+    let values: Vec<f32> = (0..64).map(|i| i as f32 / 8.0 - 4.0).collect();
+    w.add_tensor_f32("small", &[3], &[1.0, -2.0, 0.5]).unwrap();
+    let mut q8 = vec![0u8; GgmlType::Q8_0.row_size(64)];
+    quantize_row(GgmlType::Q8_0, &values, &mut q8);
+    w.add_tensor("q", &[32, 2], GgmlType::Q8_0, q8).unwrap();
+    assert!(w.add_tensor("bad", &[32], GgmlType::F32, vec![0; 4]).is_err());
+
+    let file = GgufFile::from_bytes(&w.to_bytes()).unwrap();
+    assert_eq!(file.version, 3);
+    assert_eq!(file.get_str("general.architecture"), Some("llama"));
+    assert_eq!(file.get_u32("llama.block_count"), Some(2));
+    assert_eq!(file.get_f32("llama.rope.freq_base"), Some(10000.0));
+    assert_eq!(file.get("flag").and_then(|v| v.as_bool()), Some(true));
+    assert_eq!(file.get("big").unwrap().to_display(), "-5");
+    assert_eq!(file.get("general.tags").unwrap().to_display(), "[a, b]");
+    assert!(file.get("missing").is_none());
+
+    let small = file.tensor("small").unwrap();
+    assert_eq!(file.tensor_f32(small), vec![1.0, -2.0, 0.5]);
+    // tensor data starts aligned
+    let q = file.tensor("q").unwrap();
+    assert_eq!(q.offset % 32, 0);
+    assert_eq!(q.dims, vec![32, 2]);
+    let deq = file.tensor_f32(q);
+    assert!(deq.iter().zip(&values).all(|(a, b)| (a - b).abs() < 0.05));
+}
+
+#[test]
+fn test_gguf_rejects_malformed_files() {
+    assert!(GgufFile::from_bytes(b"GGML\x03\0\0\0").is_err());
+    let mut w = GgufWriter::new();
+    w.add_tensor_f32("t", &[4], &[1.0; 4]).unwrap();
+    let bytes = w.to_bytes();
+    // truncated data section
+    assert!(GgufFile::from_bytes(&bytes[..bytes.len() - 4]).is_err());
+    // unsupported version
+    let mut v1 = bytes.clone();
+    v1[4] = 1;
+    assert!(GgufFile::from_bytes(&v1).is_err());
+
+    let path = std::env::temp_dir().join(format!("llmrust_gguf_{}.gguf", std::process::id()));
+    w.write(&path).unwrap();
+    assert_eq!(GgufFile::read(&path).unwrap().tensors().len(), 1);
+    // metadata only: the data is read from the file on request
+    let meta = GgufFile::read_metadata(&path).unwrap();
+    let t = meta.tensor("t").unwrap();
+    assert_eq!(meta.read_tensor_data(t).unwrap().as_ref(), GgufFile::from_bytes(&bytes).unwrap().tensor_data(t));
+    std::fs::write(&path, &bytes[..20]).unwrap();
+    assert!(GgufFile::read_metadata(&path).unwrap_err().contains("unexpected end"));
+    w.write(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(GgufFile::read(&path).is_err());
+}
+
+/// A file with one F32 tensor described by `dims` and `offset`, followed by 32 bytes of data.
+fn crafted_file(dims: &[u64], offset: u64) -> Vec<u8> {
+    let mut bytes = b"GGUF".to_vec();
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.push(b't');
+    bytes.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+    for d in dims {
+        bytes.extend_from_slice(&d.to_le_bytes());
+    }
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&offset.to_le_bytes());
+    bytes.resize(bytes.len().next_multiple_of(32) + 32, 0);
+    bytes
+}
+
+#[test]
+fn test_gguf_rejects_overflowing_shapes() {
+    assert_eq!(GgufFile::from_bytes(&crafted_file(&[8], 0)).unwrap().tensors()[0].dims, vec![8]);
+    // the element count overflows
+    let e = GgufFile::from_bytes(&crafted_file(&[1 << 33, 1 << 33], 0)).unwrap_err();
+    assert!(e.contains("too large"), "{}", e);
+    // the element count fits but its byte size does not
+    let e = GgufFile::from_bytes(&crafted_file(&[1 << 62], 0)).unwrap_err();
+    assert!(e.contains("too large"), "{}", e);
+    // offset + size overflows
+    let e = GgufFile::from_bytes(&crafted_file(&[8], u64::MAX - 4)).unwrap_err();
+    assert!(e.contains("out of bounds"), "{}", e);
 }
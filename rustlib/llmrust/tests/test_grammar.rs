@@ -1,6 +1,146 @@
-// tests/test_grammar.rs - Auto-generated debug file
-#![allow(dead_code)]
+// tests/test_grammar.rs - GBNF parser, matcher and built-in grammar tests
 
-pub fn debug_print() {
-    println!("DEBUG: tests/test_grammar.rs - File loaded successfully");
+use crate::llmrust::grammars::{self, BUILTIN_GRAMMARS, GRAMMAR_ROOT};
+use crate::llmrust::src::llama_grammar::{GrammarParser, LlamaGrammar};
+
+fn builtin(name: &str) -> LlamaGrammar {
+    grammars::resolve(name).unwrap_or_else(|e| panic!("failed to parse built-in grammar {}: {}", name, e))
+}
+
+fn match_string(grammar: &LlamaGrammar, input: &str) -> bool {
+    let mut g = grammar.clone();
+    g.accept_str(input) && g.is_accepting()
+}
+
+fn check(name: &str, valid: &[&str], invalid: &[&str]) {
+    let grammar = builtin(name);
+    for input in valid {
+        assert!(match_string(&grammar, input), "{}: expected {:?} to be accepted", name, input);
+    }
+    for input in invalid {
+        assert!(!match_string(&grammar, input), "{}: expected {:?} to be rejected", name, input);
+    }
+}
+
+#[test]
+fn test_registry_contains_all_grammars() {
+    let names = grammars::names();
+    for name in ["arithmetic", "c", "chess", "english", "japanese", "json", "json_arr", "list"] {
+        assert!(names.contains(&name), "missing built-in grammar {}", name);
+    }
+    for g in BUILTIN_GRAMMARS {
+        assert!(LlamaGrammar::parse(g.text, GRAMMAR_ROOT).is_ok(), "{} does not parse", g.name);
+    }
+    assert!(grammars::lookup("does_not_exist").is_none());
+}
+
+#[test]
+fn test_grammar_arithmetic() {
+    check(
+        "arithmetic",
+        &["x = 1\n", "a +b = 3\n", "(1 +2)*x = 2\n", "y = 4\nz = y\n"],
+        &["1 + 2 = 3\n", "X = 1\n", "x = y", "= 1\n"],
+    );
+}
+
+#[test]
+fn test_grammar_c() {
+    check(
+        "c",
+        &[
+            "int main(){return 0;}",
+            "int add(int a){int b = a+1;return b;}",
+            "float f(){while(x<10){x = x+1;}}",
+        ],
+        &["int main() {return 0;}", "void main(){}", "int main(){return 0}"],
+    );
+}
+
+#[test]
+fn test_grammar_chess() {
+    check(
+        "chess",
+        &["1. e4 e5\n2. Nf3 Nc6\n", "1. d4 d5\n2. c4 dxc4\n3. O-O Qxd1+\n"],
+        &["1. e4 e5\n", "1. e9 e5\n2. Nf3 Nc6\n", "1.e4 e5\n2. Nf3 Nc6\n"],
+    );
+}
+
+#[test]
+fn test_grammar_english() {
+    check(
+        "english",
+        &["Hello, world!", "The answer is 42.\nYes"],
+        &["Hello  world", "héllo", " leading space"],
+    );
+}
+
+#[test]
+fn test_grammar_japanese() {
+    check(
+        "japanese",
+        &["こんにちは 世界", "カタカナ、ひらがな。"],
+        &["hello", "こんにちは  世界"],
+    );
+}
+
+#[test]
+fn test_grammar_json() {
+    check(
+        "json",
+        &[
+            r#"{}"#,
+            r#"{"a": [1, 2.5, true, null], "b": {"c": "d\n"}}"#,
+            r#"{"neg": -12, "exp": 1e5, "u": "é"}"#,
+        ],
+        &[r#"[1, 2]"#, r#"{"a": 01}"#, r#"{"a": 1,}"#, r#"{'a': 1}"#],
+    );
+}
+
+#[test]
+fn test_grammar_json_arr() {
+    check(
+        "json_arr",
+        &["[\n]", "[\n{\"a\": 1},\n{\"b\": 2}\n]", "[\n\"x\",\n3\n]"],
+        &["[\n1\n] ", "{\"a\": 1}", "[1, 2]"],
+    );
+}
+
+#[test]
+fn test_grammar_list() {
+    check(
+        "list",
+        &["- apples\n", "- apples\n- pears\n"],
+        &["- \n", "* apples\n", "- apples"],
+    );
+}
+
+#[test]
+fn test_grammar_repetition_bounds() {
+    let grammar = LlamaGrammar::parse(r#"root ::= "a"{2,3} "b"?"#, "root").unwrap();
+    assert!(match_string(&grammar, "aa"));
+    assert!(match_string(&grammar, "aaab"));
+    assert!(!match_string(&grammar, "a"));
+    assert!(!match_string(&grammar, "aaaa"));
+}
+
+#[test]
+fn test_grammar_partial_input_is_not_accepting() {
+    let mut grammar = builtin("json");
+    assert!(grammar.accept_str(r#"{"key": "val"#));
+    assert!(!grammar.is_accepting());
+    assert!(!grammar.is_dead());
+    assert!(grammar.accept_str(r#"ue"}"#));
+    assert!(grammar.is_accepting());
+}
+
+#[test]
+fn test_grammar_parse_errors() {
+    assert!(GrammarParser::parse(r#"root ::= missing"#).is_err());
+    assert!(GrammarParser::parse(r#"root = "a""#).is_err());
+    assert!(GrammarParser::parse(r#"root ::= ("a""#).is_err());
+    assert!(GrammarParser::parse(r#"root ::= "a"{,2}"#).is_err());
+    assert!(LlamaGrammar::parse(r#"root ::= root "a" | "b""#, "root").is_err());
+    assert!(LlamaGrammar::parse(r#"start ::= "a""#, "root").is_err());
+    // Unknown names fall back to GBNF parsing, which fails for a bare word
+    assert!(grammars::resolve("json_array").is_err());
 }
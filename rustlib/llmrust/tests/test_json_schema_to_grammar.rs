@@ -0,0 +1,203 @@
+// tests/test_json_schema_to_grammar.rs - JSON schema conversion tests
+//
+// Each schema is converted to GBNF, parsed, and matched against documents
+// that must be accepted or rejected.
+
+use serde_json::json;
+
+use crate::llmrust::common::json_schema_to_grammar::json_schema_to_grammar;
+use crate::llmrust::src::llama_grammar::LlamaGrammar;
+
+fn check(schema: serde_json::Value, valid: &[&str], invalid: &[&str]) {
+    let text = json_schema_to_grammar(&schema).unwrap_or_else(|e| panic!("conversion failed for {}: {}", schema, e));
+    let grammar = LlamaGrammar::parse(&text, "root").unwrap_or_else(|e| panic!("invalid grammar {}:\n{}", e, text));
+    for input in valid {
+        let mut g = grammar.clone();
+        assert!(g.accept_str(input) && g.is_accepting(), "expected {:?} to be accepted by\n{}", input, text);
+    }
+    for input in invalid {
+        let mut g = grammar.clone();
+        assert!(!(g.accept_str(input) && g.is_accepting()), "expected {:?} to be rejected by\n{}", input, text);
+    }
+}
+
+#[test]
+fn test_primitives() {
+    check(json!({"type": "integer"}), &["0", "-12", "42"], &["01", "1.5", "\"1\""]);
+    check(json!({"type": "number"}), &["1.5", "-0.25e3", "7"], &["1.", "+1"]);
+    check(json!({"type": "boolean"}), &["true", "false"], &["True", "1"]);
+    check(json!({"type": "null"}), &["null"], &["nil"]);
+    check(json!({"type": "string"}), &[r#""abc""#, r#""a\"bé""#], &["abc", r#""a"#]);
+    check(json!({}), &["1", r#"{"a": [true, null]}"#], &["{"]);
+}
+
+#[test]
+fn test_object_required_and_optional() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"},
+            "age": {"type": "integer"},
+            "email": {"type": "string"}
+        },
+        "required": ["name"]
+    });
+    check(
+        schema,
+        &[
+            r#"{"name": "Ann"}"#,
+            r#"{"name": "Ann", "age": 3}"#,
+            r#"{"name": "Ann", "email": "a@b"}"#,
+            r#"{"name": "Ann", "age": 3, "email": "a@b"}"#,
+        ],
+        &[r#"{}"#, r#"{"age": 3}"#, r#"{"name": "Ann", "other": 1}"#, r#"{"age": 3, "name": "Ann"}"#],
+    );
+}
+
+#[test]
+fn test_additional_properties() {
+    let schema = json!({
+        "type": "object",
+        "properties": {"a": {"type": "integer"}},
+        "additionalProperties": {"type": "boolean"}
+    });
+    check(schema, &[r#"{"a": 1}"#, r#"{"a": 1, "b": true}"#, r#"{"x": false, "ab": true}"#], &[r#"{"a": 1, "b": 2}"#, r#"{"a": true}"#]);
+
+    check(json!({"type": "object"}), &[r#"{}"#, r#"{"k": [1]}"#], &["[]"]);
+}
+
+#[test]
+fn test_enum_and_const() {
+    check(json!({"enum": ["red", "green", 3, null]}), &[r#""red""#, r#""green""#, "3", "null"], &[r#""blue""#, "4"]);
+    check(json!({"const": {"kind": "fixed"}}), &[r#"{"kind":"fixed"}"#], &[r#"{"kind": "fixed"}"#]);
+    check(json!({"const": "a\"b"}), &[r#""a\"b""#], &[r#""ab""#]);
+}
+
+#[test]
+fn test_array_bounds() {
+    let schema = json!({"type": "array", "items": {"type": "integer"}, "minItems": 1, "maxItems": 3});
+    check(schema, &["[1]", "[1, 2]", "[1,2,3]"], &["[]", "[1, 2, 3, 4]", r#"["a"]"#]);
+
+    check(json!({"type": "array", "prefixItems": [{"type": "string"}, {"type": "integer"}]}), &[r#"["a", 1]"#], &[r#"[1, "a"]"#, r#"["a"]"#]);
+    check(json!({"type": "array", "maxItems": 0}), &["[]"], &["[1]"]);
+}
+
+#[test]
+fn test_untyped_bounds() {
+    // bounds without a type constrain the rule to their type
+    check(json!({"minLength": 3}), &[r#""abc""#, r#""abcd""#], &[r#""ab""#]);
+    check(json!({"minItems": 2}), &["[1, 2]", r#"[1, "a", null]"#], &["[1]", "[]"]);
+    check(json!({"maxItems": 1, "items": {"type": "integer"}}), &["[]", "[1]"], &["[1, 2]", r#"["a"]"#]);
+    assert!(json_schema_to_grammar(&json!({"minLength": 1, "minItems": 1})).is_err());
+}
+
+#[test]
+fn test_tuples() {
+    // items constrains the elements after prefixItems
+    let schema = json!({"type": "array", "prefixItems": [{"type": "string"}], "items": {"type": "integer"}});
+    check(schema, &[r#"["a"]"#, r#"["a", 1, 2]"#], &[r#"["a", "b"]"#, "[1]", "[]"]);
+    let schema = json!({"type": "array", "prefixItems": [{"type": "string"}], "items": false, "minItems": 0});
+    check(schema, &["[]", r#"["a"]"#], &[r#"["a", 1]"#]);
+    assert!(json_schema_to_grammar(&json!({"prefixItems": [{}], "items": [{}]})).is_err());
+    assert!(json_schema_to_grammar(&json!({"prefixItems": [{}], "items": false, "minItems": 2})).is_err());
+
+    // minItems and maxItems count the tuple elements too
+    let tuple = json!([{"type": "string"}, {"type": "integer"}, {"type": "boolean"}]);
+    check(
+        json!({"type": "array", "prefixItems": tuple, "minItems": 1, "maxItems": 2}),
+        &[r#"["a"]"#, r#"["a", 1]"#],
+        &["[]", r#"["a", 1, true]"#, "[1]"],
+    );
+    check(
+        json!({"type": "array", "prefixItems": tuple, "items": {"type": "null"}, "minItems": 4}),
+        &[r#"["a", 1, true, null]"#, r#"["a", 1, true, null, null]"#],
+        &[r#"["a", 1, true]"#, r#"["a", 1, true, 1]"#],
+    );
+    check(json!({"type": "array", "prefixItems": [{"type": "integer"}], "minItems": 2}), &[r#"[1, "a"]"#], &["[1]"]);
+}
+
+#[test]
+fn test_string_formats_and_length() {
+    check(json!({"type": "string", "format": "date"}), &[r#""2024-02-29""#], &[r#""2024-13-01""#, r#""24-01-01""#]);
+    check(json!({"type": "string", "format": "time"}), &[r#""23:59:59Z""#, r#""08:00:00.123+02:00""#], &[r#""24:00:00Z""#]);
+    check(json!({"type": "string", "format": "date-time"}), &[r#""2024-01-02T03:04:05Z""#], &[r#""2024-01-02 03:04:05Z""#]);
+    check(
+        json!({"type": "string", "format": "uuid"}),
+        &[r#""123e4567-e89b-12d3-a456-426614174000""#],
+        &[r#""123e4567e89b12d3a456426614174000""#],
+    );
+    check(json!({"type": "string", "minLength": 2, "maxLength": 3}), &[r#""ab""#, r#""abc""#], &[r#""a""#, r#""abcd""#]);
+}
+
+#[test]
+fn test_refs_and_any_of() {
+    let schema = json!({
+        "$defs": {
+            "node": {
+                "type": "object",
+                "properties": {
+                    "value": {"type": "integer"},
+                    "next": {"anyOf": [{"$ref": "#/$defs/node"}, {"type": "null"}]}
+                },
+                "required": ["value", "next"]
+            }
+        },
+        "$ref": "#/$defs/node"
+    });
+    check(
+        schema,
+        &[r#"{"value": 1, "next": null}"#, r#"{"value": 1, "next": {"value": 2, "next": null}}"#],
+        &[r#"{"value": 1}"#, r#"{"value": 1, "next": {"value": "x", "next": null}}"#],
+    );
+
+    let schema = json!({
+        "definitions": {"string": {"type": "string", "maxLength": 1}},
+        "type": "array",
+        "items": {"$ref": "#/definitions/string"}
+    });
+    check(schema, &[r#"["a", "b"]"#], &[r#"["ab"]"#]);
+
+    check(json!({"type": ["integer", "null"]}), &["1", "null"], &[r#""1""#]);
+}
+
+#[test]
+fn test_unsupported_features_are_rejected() {
+    let unsupported = [
+        json!({"type": "string", "pattern": "^a$"}),
+        json!({"type": "integer", "minimum": 0}),
+        json!({"allOf": [{"type": "string"}]}),
+        json!({"type": "string", "format": "email"}),
+        json!({"$ref": "https://example.com/schema.json"}),
+        json!({"$ref": "#/$defs/missing"}),
+        json!({"type": "tuple"}),
+        json!({"type": "integer", "items": {"type": "string"}}),
+        json!({"type": "array", "items": {"type": "string"}, "minItems": 3, "maxItems": 1}),
+        json!({"type": "object", "properties": {"a": {"type": "string"}}, "required": ["a", "b"]}),
+        json!({"type": "object", "required": ["a"]}),
+        json!({"type": "string", "format": "date", "maxLength": 4}),
+        json!({"$defs": {"s": {"type": "string"}}, "properties": {"a": {"$ref": "#/$defs/s", "minLength": 2}}}),
+        json!({"type": "object", "required": ["a", 1], "properties": {"a": {}}}),
+        json!({"anyOf": [{"type": "string"}], "oneOf": [{"type": "integer"}]}),
+        json!(false),
+    ];
+    for schema in unsupported {
+        assert!(json_schema_to_grammar(&schema).is_err(), "expected {} to be rejected", schema);
+    }
+
+    let err = json_schema_to_grammar(&json!({"properties": {"a": {"type": "string", "pattern": "x"}}})).unwrap_err();
+    assert!(err.contains("pattern") && err.contains("#/properties/a"), "{}", err);
+
+    let err = json_schema_to_grammar(&json!({"properties": {"a": {"$ref": "#/properties/b", "type": "integer"}, "b": {}}})).unwrap_err();
+    assert!(err.contains("$ref") && err.contains("#/properties/a"), "{}", err);
+
+    // oneOf/anyOf siblings would be dropped, so they are rejected
+    let err = json_schema_to_grammar(&json!({"oneOf": [{"type": "object"}], "properties": {"a": {}}, "required": ["a"]})).unwrap_err();
+    assert!(err.contains("properties") && err.contains("anyOf/oneOf"), "{}", err);
+    assert!(json_schema_to_grammar(&json!({"type": "string", "anyOf": [{"maxLength": 2}]})).is_err());
+    let err = json_schema_to_grammar(&json!({"properties": {"a": {}}, "required": [true]})).unwrap_err();
+    assert!(err.contains("required entries must be strings"), "{}", err);
+
+    // Annotations are accepted and ignored, also next to $ref
+    assert!(json_schema_to_grammar(&json!({"title": "t", "description": "d", "type": "boolean"})).is_ok());
+    assert!(json_schema_to_grammar(&json!({"$defs": {"b": {"type": "boolean"}}, "$ref": "#/$defs/b", "description": "d"})).is_ok());
+}
@@ -0,0 +1,419 @@
+// tests/test_kv_cache.rs - KV cache sequence operation tests
+
+use crate::common::log::{llama_memory_seq_add, llama_memory_seq_pos_max, llama_memory_seq_pos_min, llama_memory_seq_rm};
+use crate::common::model::{llama_memory_can_shift, llama_memory_clear};
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_cpu::ops::{rope_f32, RopeMode};
+use crate::llmrust::src::llama_hparams::{LlamaHparams, LlamaRopeType};
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvCachePaged, LlamaKvUbatchToken};
+use crate::llmrust::src::llama_memory::{self, LlamaMemory, LlamaPos, LlamaSeqId};
+
+fn hparams() -> LlamaHparams {
+    LlamaHparams {
+        n_layer: 2,
+        n_head: 4,
+        n_head_kv: 2,
+        n_embd_head_k: 8,
+        n_embd_head_v: 8,
+        n_rot: 8,
+        ..Default::default()
+    }
+}
+
+fn tokens(seq_id: LlamaSeqId, positions: std::ops::Range<LlamaPos>) -> Vec<LlamaKvUbatchToken> {
+    positions.map(|pos| LlamaKvUbatchToken { pos, seq_ids: vec![seq_id] }).collect()
+}
+
+fn key(pos: LlamaPos, n: usize) -> Vec<f32> {
+    (0..n).map(|i| ((i as f32) * 0.37 + 1.0).sin() + pos as f32 * 0.01).collect()
+}
+
+#[test]
+fn test_kv_cache_seq_rm() {
+    let mut kv = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&tokens(0, 0..8)).unwrap();
+    assert_eq!(kv.n_used(), 8);
+
+    assert!(kv.seq_rm(0, 4, -1));
+    assert_eq!(kv.n_used(), 4);
+    assert_eq!(kv.seq_pos_max(0), 3);
+
+    assert!(kv.seq_rm(0, -1, 2));
+    assert_eq!(kv.seq_pos_min(0), 2);
+    assert!(!kv.seq_rm(5, -1, -1));
+
+    // freed cells are reused
+    kv.apply_ubatch(&tokens(1, 0..12)).unwrap();
+    assert_eq!(kv.n_used(), 14);
+    assert!(kv.apply_ubatch(&tokens(1, 12..15)).is_err());
+
+    assert!(kv.seq_rm(-1, -1, -1));
+    assert_eq!(kv.n_used(), 0);
+    assert_eq!(kv.seq_pos_max(1), -1);
+}
+
+#[test]
+fn test_kv_cache_seq_cp_shares_cells() {
+    let mut kv = LlamaKvCache::new(&hparams(), 16, 4, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&tokens(0, 0..6)).unwrap();
+
+    kv.seq_cp(0, 1, -1, 4);
+    assert_eq!(kv.n_used(), 6);
+    assert_eq!(kv.seq_pos_max(1), 3);
+    assert!(kv.cell(0).has_seq_id(1));
+    assert_eq!(kv.cell(0).seq_count(), 2);
+
+    // removing the source leaves the shared prefix with the copy
+    kv.seq_rm(0, -1, -1);
+    assert_eq!(kv.n_used(), 4);
+    assert_eq!((kv.seq_pos_min(1), kv.seq_pos_max(1)), (0, 3));
+
+    kv.apply_ubatch(&tokens(2, 0..3)).unwrap();
+    kv.seq_keep(2);
+    assert_eq!(kv.n_used(), 3);
+    assert_eq!(kv.seq_pos_max(1), -1);
+}
+
+#[test]
+fn test_kv_cache_seq_add_rerotates_keys() {
+    let hp = hparams();
+    let n_k = hp.n_embd_k_gqa() as usize;
+    let head = hp.n_embd_head_k as usize;
+    let mut kv = LlamaKvCache::new(&hp, 16, 1, GgmlType::F32, GgmlType::F32).unwrap();
+
+    // keys as the model would store them: rotated at their position
+    let rotated = |pos: LlamaPos| {
+        let mut k = key(pos, n_k);
+        for h in k.chunks_mut(head) {
+            rope_f32(h, head, pos as f32, 10000.0, 1.0, RopeMode::Normal);
+        }
+        k
+    };
+
+    let cells = kv.apply_ubatch(&tokens(0, 0..8)).unwrap();
+    for (&cell, pos) in cells.iter().zip(0..) {
+        for il in 0..2 {
+            kv.cpy_k(il, cell, &rotated(pos));
+        }
+    }
+
+    // drop the first 3 tokens and move the rest back
+    kv.seq_rm(0, 0, 3);
+    kv.seq_add(0, 3, -1, -3);
+    assert!(kv.has_shift());
+    assert_eq!((kv.seq_pos_min(0), kv.seq_pos_max(0)), (0, 4));
+    assert!(kv.update());
+    assert!(!kv.has_shift());
+
+    for &cell in &cells[3..] {
+        let new_pos = kv.cell(cell).pos;
+        let mut expected = key(new_pos + 3, n_k);
+        for h in expected.chunks_mut(head) {
+            rope_f32(h, head, new_pos as f32, 10000.0, 1.0, RopeMode::Normal);
+        }
+        for il in 0..2 {
+            for (a, b) in kv.get_k_f32(il, cell).iter().zip(&expected) {
+                assert!((a - b).abs() < 1e-4, "cell {} layer {}: {} vs {}", cell, il, a, b);
+            }
+        }
+    }
+
+    // shifting below zero discards the cells
+    kv.seq_add(0, -1, -1, -3);
+    assert_eq!(kv.n_used(), 2);
+}
+
+#[test]
+fn test_kv_cache_seq_div() {
+    let mut kv = LlamaKvCache::new(&hparams(), 32, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&tokens(0, 0..16)).unwrap();
+
+    kv.seq_div(0, 8, -1, 4);
+    assert_eq!(kv.seq_pos_max(0), 7);
+    assert_eq!(kv.cell(15).pos, 3);
+    assert_eq!(kv.cell(8).pos, 2);
+    assert_eq!(kv.cell(8).delta, -6);
+    assert_eq!(kv.cell(7).pos, 7);
+    assert!(kv.update());
+    assert_eq!(kv.cell(8).delta, 0);
+}
+
+#[test]
+fn test_kv_cache_masking() {
+    let mut kv = LlamaKvCache::new(&hparams(), 8, 2, GgmlType::F32, GgmlType::F32).unwrap();
+    let a = kv.apply_ubatch(&tokens(0, 0..3)).unwrap();
+    let b = kv.apply_ubatch(&tokens(1, 0..3)).unwrap();
+
+    assert!(!kv.is_masked(a[1], 0, 1, true));
+    assert!(kv.is_masked(a[2], 0, 1, true));
+    assert!(!kv.is_masked(a[2], 0, 1, false));
+    assert!(kv.is_masked(b[0], 0, 2, true));
+}
+
+#[test]
+fn test_kv_cache_no_rope_cannot_shift() {
+    let hp = LlamaHparams { rope_type: LlamaRopeType::None, ..hparams() };
+    let kv = LlamaKvCache::new(&hp, 8, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    assert!(!kv.can_shift());
+    assert!(LlamaKvCache::new(&hp, 8, 65, GgmlType::F32, GgmlType::F32).is_err());
+}
+
+#[test]
+fn test_kv_cache_c_api() {
+    let mut kv = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&tokens(0, 0..10)).unwrap();
+    let mem = llama_memory::into_handle(Box::new(kv));
+
+    assert!(llama_memory_can_shift(mem));
+    // -1 passed through uintptr_t means "from the start"
+    assert!(llama_memory_seq_rm(mem, 0, usize::MAX, 4));
+    assert_eq!(llama_memory_seq_pos_min(mem, 0), 4);
+    llama_memory_seq_add(mem, 0, 4, -1, -4);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), 5);
+    llama_memory_clear(mem, true);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), -1);
+
+    unsafe { llama_memory::free_handle(mem) };
+
+    assert!(!llama_memory_can_shift(std::ptr::null_mut()));
+    assert!(!llama_memory_seq_rm(std::ptr::null_mut(), 0, 0, 1));
+}
+
+#[test]
+fn test_common_context_shift() {
+    use crate::llmrust::common::common::common_context_shift;
+
+    let mut kv = LlamaKvCache::new(&hparams(), 10, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&tokens(0, 0..10)).unwrap();
+    let mut history: Vec<i32> = (100..110).collect();
+
+    let shift = common_context_shift(&mut kv, 0, &mut history, 2).unwrap();
+    assert_eq!((shift.n_keep, shift.n_discard), (2, 4));
+    assert_eq!(history, vec![100, 101, 106, 107, 108, 109]);
+    assert_eq!(kv.n_used(), 6);
+    assert_eq!((kv.seq_pos_min(0), kv.seq_pos_max(0)), (0, 5));
+
+    // the freed cells take the next tokens
+    kv.apply_ubatch(&tokens(0, 6..10)).unwrap();
+    history.extend(110..114);
+    assert!(common_context_shift(&mut kv, 0, &mut history, 5).is_ok());
+    assert!(common_context_shift(&mut kv, 0, &mut vec![1, 2], 2).is_err());
+    // the removal fails for a sequence the cache does not hold
+    let before = history.clone();
+    assert!(common_context_shift(&mut kv, 3, &mut history, 2).is_err());
+    assert_eq!(history, before);
+
+    let hp = LlamaHparams { rope_type: LlamaRopeType::None, ..hparams() };
+    let mut kv = LlamaKvCache::new(&hp, 4, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    assert!(common_context_shift(&mut kv, 0, &mut vec![1, 2, 3, 4], 0).is_err());
+}
+
+#[test]
+fn test_kv_cache_quantized_types() {
+    let hp = LlamaHparams { n_embd_head_k: 32, n_embd_head_v: 32, n_rot: 32, ..hparams() };
+    let (f16_k, f16_v) = LlamaKvCache::memory_footprint(&hp, 64, GgmlType::F16, GgmlType::F16);
+    let (q8_k, q4_v) = LlamaKvCache::memory_footprint(&hp, 64, GgmlType::Q8_0, GgmlType::Q4_0);
+    assert_eq!(f16_k, 64 * 2 * 64 * 2);
+    assert_eq!(q8_k * 64, f16_k * 34);
+    assert_eq!(q4_v * 64, f16_v * 18);
+    assert!(LlamaKvCache::new(&hparams(), 8, 1, GgmlType::Q8_0, GgmlType::F16).is_err());
+
+    let n_k = hp.n_embd_k_gqa() as usize;
+    let mut reference = LlamaKvCache::new(&hp, 8, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    let mut quantized = LlamaKvCache::new(&hp, 8, 1, GgmlType::Q8_0, GgmlType::Q4_0).unwrap();
+    assert_eq!(quantized.memory_size(), (q8_k / 8, q4_v / 8));
+
+    for kv in [&mut reference, &mut quantized] {
+        let cells = kv.apply_ubatch(&tokens(0, 0..5)).unwrap();
+        for (&cell, pos) in cells.iter().zip(0..) {
+            for il in 0..2 {
+                kv.cpy_k(il, cell, &key(pos, n_k));
+                kv.cpy_v(il, cell, &key(pos + 50, n_k));
+            }
+        }
+    }
+
+    let q = key(9, 32);
+    for kv_head in 0..2 {
+        let a = reference.attn(1, kv_head, &q, 0, 3, true, 0.2);
+        let b = quantized.attn(1, kv_head, &q, 0, 3, true, 0.2);
+        for (x, y) in a.iter().zip(&b) {
+            assert!((x - y).abs() < 0.1, "{} vs {}", x, y);
+        }
+    }
+
+    // shifting re-quantizes the rotated keys
+    quantized.seq_add(0, 2, -1, 4);
+    assert!(quantized.update());
+    let k = quantized.get_k_f32(0, 4);
+    let mut expected = key(4, n_k);
+    for h in expected.chunks_mut(32) {
+        rope_f32(h, 32, 4.0, 10000.0, 1.0, RopeMode::Normal);
+    }
+    for (x, y) in k.iter().zip(&expected) {
+        assert!((x - y).abs() < 0.05, "{} vs {}", x, y);
+    }
+}
+
+fn paged(n_blocks: u32) -> LlamaKvCachePaged {
+    LlamaKvCachePaged::new(&hparams(), n_blocks, 4, GgmlType::F32, GgmlType::F32).unwrap()
+}
+
+#[test]
+fn test_kv_cache_paged_blocks() {
+    let mut kv = paged(4);
+    for pos in 0..6 {
+        kv.append(0, pos).unwrap();
+    }
+    assert_eq!(kv.seq_blocks(0), &[0, 1]);
+    assert_eq!(kv.n_free_blocks(), 2);
+    assert_eq!(kv.blocks_for(9), 3);
+
+    for pos in 0..8 {
+        kv.append(7, pos).unwrap();
+    }
+    assert_eq!(kv.n_free_blocks(), 0);
+    assert!(kv.append(7, 8).is_err());
+
+    // removing a middle range compacts the sequence and frees its last block
+    assert!(kv.seq_rm(7, 2, 6));
+    assert_eq!(kv.seq_n_tokens(7), 4);
+    assert_eq!(kv.n_free_blocks(), 1);
+    assert_eq!((kv.seq_pos_min(7), kv.seq_pos_max(7)), (0, 7));
+
+    kv.seq_keep(0);
+    assert_eq!(kv.n_free_blocks(), 2);
+    assert!(kv.seq_rm(-1, -1, -1));
+    assert_eq!(kv.n_free_blocks(), 4);
+    assert_eq!(kv.seq_pos_max(0), -1);
+}
+
+#[test]
+fn test_kv_cache_paged_copy_on_write() {
+    let n_k = hparams().n_embd_k_gqa() as usize;
+    let mut kv = paged(8);
+    for pos in 0..6 {
+        let slot = kv.append(0, pos).unwrap();
+        kv.cpy_k(0, slot, &key(pos, n_k));
+        kv.cpy_v(0, slot, &key(pos + 100, n_k));
+    }
+
+    // a prefix copy shares the blocks, including the partially filled one
+    kv.seq_cp(0, 1, -1, 5);
+    assert_eq!(kv.seq_blocks(1), kv.seq_blocks(0));
+    assert_eq!(kv.block_ref_count(1), 2);
+    assert_eq!(kv.n_free_blocks(), 6);
+
+    // writing into the shared block copies it first
+    let slot = kv.append(1, 5).unwrap();
+    kv.cpy_k(0, slot, &key(55, n_k));
+    assert_eq!(kv.seq_blocks(1)[0], kv.seq_blocks(0)[0]);
+    assert_ne!(kv.seq_blocks(1)[1], kv.seq_blocks(0)[1]);
+    assert_eq!(kv.block_ref_count(1), 1);
+    assert_eq!(kv.n_free_blocks(), 5);
+
+    let last = |kv: &LlamaKvCachePaged, seq| {
+        let block = kv.seq_blocks(seq)[1];
+        (kv.get_k_f32(0, block * 4), kv.get_k_f32(0, block * 4 + 1))
+    };
+    assert_eq!(last(&kv, 0).0, key(4, n_k));
+    assert_eq!(last(&kv, 1).0, key(4, n_k));
+    assert_eq!(last(&kv, 0).1, key(5, n_k));
+    assert_eq!(last(&kv, 1).1, key(55, n_k));
+
+    // a non-prefix copy is physical
+    kv.seq_cp(0, 2, 2, -1);
+    assert_eq!(kv.seq_n_tokens(2), 4);
+    assert_eq!(kv.seq_pos_min(2), 2);
+
+    // freeing the source keeps the shared block alive for the copy
+    kv.seq_rm(0, -1, -1);
+    assert_eq!(kv.block_ref_count(kv.seq_blocks(1)[0]), 1);
+    assert_eq!(kv.seq_n_tokens(1), 6);
+}
+
+#[test]
+fn test_kv_cache_paged_out_of_blocks_leaves_cache_unchanged() {
+    let mut kv = paged(3);
+    for pos in 0..8 {
+        kv.append(0, pos).unwrap();
+    }
+    kv.seq_cp(0, 1, -1, -1);
+    assert_eq!(kv.n_free_blocks(), 1);
+    let state = |kv: &LlamaKvCachePaged| {
+        (kv.seq_blocks(1).to_vec(), kv.seq_n_tokens(1), kv.seq_pos_min(1), kv.seq_pos_max(1), kv.n_free_blocks(), kv.has_shift())
+    };
+    let before = state(&kv);
+
+    // both shared blocks would have to be copied, but only one block is free
+    assert!(kv.try_seq_add(1, 2, -1, -2).is_err());
+    assert_eq!(state(&kv), before);
+    kv.seq_div(1, 0, -1, 2);
+    assert_eq!(state(&kv), before);
+    // compacting after removing [0, 2) rewrites both blocks as well
+    assert!(!kv.seq_rm(1, 0, 2));
+    assert!(!kv.seq_rm(-1, 0, 2));
+    assert_eq!(state(&kv), before);
+    assert_eq!(kv.seq_n_tokens(0), 8);
+
+    // removing a suffix writes nothing and frees the second block
+    assert!(kv.seq_rm(1, 4, -1));
+    assert_eq!((kv.seq_n_tokens(1), kv.seq_pos_max(1)), (4, 3));
+    assert_eq!(kv.block_ref_count(kv.seq_blocks(0)[1]), 1);
+    // one shared block to copy and one free block
+    assert!(kv.try_seq_add(1, 0, -1, 10).is_ok());
+    assert_eq!((kv.seq_pos_min(1), kv.seq_pos_min(0)), (10, 0));
+}
+
+#[test]
+fn test_kv_cell_seq_id_out_of_range() {
+    let mut kv = LlamaKvCache::new(&hparams(), 4, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&tokens(0, 0..2)).unwrap();
+    assert!(kv.cell(0).has_seq_id(0));
+    for seq_id in [-1, 1, 63, 64, 1000] {
+        assert!(!kv.cell(0).has_seq_id(seq_id));
+    }
+    assert_eq!(kv.seq_pos_max(64), -1);
+}
+
+#[test]
+fn test_kv_cache_paged_shift_matches_cells() {
+    use crate::llmrust::common::common::common_context_shift;
+
+    let hp = hparams();
+    let n_k = hp.n_embd_k_gqa() as usize;
+    let mut cells = LlamaKvCache::new(&hp, 16, 2, GgmlType::F32, GgmlType::F32).unwrap();
+    let mut blocks = paged(8);
+
+    let idx = cells.apply_ubatch(&tokens(0, 0..10)).unwrap();
+    for pos in 0..10 {
+        let slot = blocks.append(0, pos).unwrap();
+        for il in 0..2 {
+            cells.cpy_k(il, idx[pos as usize], &key(pos, n_k));
+            cells.cpy_v(il, idx[pos as usize], &key(pos + 20, n_k));
+            blocks.cpy_k(il, slot, &key(pos, n_k));
+            blocks.cpy_v(il, slot, &key(pos + 20, n_k));
+        }
+    }
+    // share the prompt with a second sequence so the shift has to copy blocks
+    blocks.seq_cp(0, 1, -1, -1);
+
+    let mut history_a: Vec<i32> = (0..10).collect();
+    let mut history_b = history_a.clone();
+    common_context_shift(&mut cells, 0, &mut history_a, 2).unwrap();
+    common_context_shift(&mut blocks, 0, &mut history_b, 2).unwrap();
+    assert_eq!(history_a, history_b);
+    assert!(cells.update());
+    assert!(blocks.update());
+    assert_eq!(blocks.seq_n_tokens(0), 6);
+    assert_eq!(blocks.seq_n_tokens(1), 10);
+
+    let q = key(3, 8);
+    for kv_head in 0..2 {
+        let a = cells.attn(1, kv_head, &q, 0, 5, true, 0.3);
+        let b = blocks.attn(1, kv_head, &q, 0, 5, true, 0.3);
+        for (x, y) in a.iter().zip(&b) {
+            assert!((x - y).abs() < 1e-5, "{} vs {}", x, y);
+        }
+    }
+}
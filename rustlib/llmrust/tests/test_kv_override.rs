@@ -0,0 +1,148 @@
+// tests/test_kv_override.rs - GGUF metadata override tests
+
+use std::os::raw::c_void;
+
+use crate::common::log::cstr;
+use crate::common::model::{
+    llama_context_default_params, llama_free, llama_init_from_model, llama_model_default_params, llama_model_free, llama_model_kv_override,
+    llama_model_load_from_file, llama_model_n_layer, load_model_info, LLAMA_KV_OVERRIDE_TYPE_INT,
+};
+use crate::llmrust::common::arg::common_params_parse_kv_overrides;
+use crate::llmrust::common::common::string_parse_kv_override;
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+use crate::llmrust::src::llama_context;
+use crate::llmrust::src::llama_model::llama_model_load_info;
+use crate::llmrust::src::llama_model_loader::{LlamaModelKvOverride, LlamaModelKvOverrideValue, LlamaModelLoader};
+
+/// A small mixture-of-experts LLaMA model
+fn metadata() -> GgufFile {
+    GgufFile::from_bytes(&writer().to_bytes()).unwrap()
+}
+
+fn writer() -> GgufWriter {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama")
+        .set("llama.context_length", GgufValue::U32(2048))
+        .set("llama.embedding_length", GgufValue::U32(256))
+        .set("llama.block_count", GgufValue::U32(4))
+        .set("llama.feed_forward_length", GgufValue::U32(512))
+        .set("llama.attention.head_count", GgufValue::U32(8))
+        .set("llama.attention.head_count_kv", GgufValue::U32(2))
+        .set("llama.expert_count", GgufValue::U32(8))
+        .set("llama.expert_used_count", GgufValue::U32(2))
+        .set("llama.rope.freq_base", GgufValue::F32(500000.0))
+        .set("tokenizer.ggml.tokens", GgufValue::Array((0..100).map(|i| GgufValue::String(i.to_string())).collect()));
+    w
+}
+
+fn overrides(specs: &[&str]) -> Vec<LlamaModelKvOverride> {
+    specs.iter().map(|spec| string_parse_kv_override(spec).unwrap()).collect()
+}
+
+#[test]
+fn test_kv_override_parse() {
+    let ovr = string_parse_kv_override("llama.expert_used_count=int:4").unwrap();
+    assert_eq!(ovr.key, "llama.expert_used_count");
+    assert_eq!(ovr.value, LlamaModelKvOverrideValue::Int(4));
+    assert_eq!(string_parse_kv_override("a=float:-0.5").unwrap().value, LlamaModelKvOverrideValue::Float(-0.5));
+    assert_eq!(string_parse_kv_override("a=bool:false").unwrap().value, LlamaModelKvOverrideValue::Bool(false));
+    // string values may contain the separators
+    let ovr = string_parse_kv_override("general.name=str:a=b:c").unwrap();
+    assert_eq!(ovr.value, LlamaModelKvOverrideValue::Str("a=b:c".to_string()));
+    assert_eq!(string_parse_kv_override(&ovr.to_string()).unwrap(), ovr);
+    let float = string_parse_kv_override("a=float:2").unwrap();
+    assert_eq!(string_parse_kv_override(&float.to_string()).unwrap(), float);
+
+    for bad in ["no_equals", "=int:1", "a=int", "a=int:1.5", "a=float:x", "a=bool:yes", "a=u32:1"] {
+        assert!(string_parse_kv_override(bad).is_err(), "{}", bad);
+    }
+    assert!(string_parse_kv_override(&format!("{}=int:1", "k".repeat(128))).is_err());
+    assert!(string_parse_kv_override(&format!("a=str:{}", "v".repeat(128))).is_err());
+
+    let args: Vec<String> = ["llm", "run", "--override-kv", "a=int:1", "-v", "--override-kv=b=bool:true"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(common_params_parse_kv_overrides(&args).unwrap(), overrides(&["a=int:1", "b=bool:true"]));
+    assert!(common_params_parse_kv_overrides(&args[..3]).unwrap_err().contains("expects a value"));
+    assert!(common_params_parse_kv_overrides(&["--override-kv=a=int:x".to_string()]).is_err());
+}
+
+#[test]
+fn test_kv_override_hparams() {
+    let info = llama_model_load_info(&LlamaModelLoader::new(metadata(), Vec::new())).unwrap();
+    let hp = &info.hparams;
+    assert_eq!(info.arch, "llama");
+    assert_eq!((hp.n_vocab, hp.n_embd_head_k, hp.n_rot, hp.n_gqa()), (100, 32, 32, 4));
+    assert_eq!((hp.n_expert, hp.n_expert_used), (8, 2));
+    assert_eq!((hp.rope_freq_base_train, hp.rope_freq_scale_train), (500000.0, 1.0));
+    assert!(info.add_bos);
+
+    // overrides replace file values, supply missing ones, and the last one wins
+    let ml = LlamaModelLoader::new(
+        metadata(),
+        overrides(&[
+            "llama.expert_used_count=int:1",
+            "llama.expert_used_count=int:3",
+            "llama.rope.scaling.factor=float:4",
+            "tokenizer.ggml.add_bos_token=bool:false",
+            "llama.typo_count=int:1",
+        ]),
+    );
+    let info = llama_model_load_info(&ml).unwrap();
+    assert_eq!(info.hparams.n_expert_used, 3);
+    assert_eq!(info.hparams.rope_freq_scale_train, 0.25);
+    assert!(!info.add_bos);
+    let used: Vec<_> = ml.used_overrides().iter().map(|o| o.to_string()).collect();
+    assert_eq!(used, ["llama.expert_used_count=int:3", "llama.rope.scaling.factor=float:4", "tokenizer.ggml.add_bos_token=bool:false"]);
+    assert_eq!(ml.unused_overrides().len(), 1);
+
+    // the override type must match the type the key is read as
+    let ml = LlamaModelLoader::new(metadata(), overrides(&["llama.block_count=float:8"]));
+    assert!(llama_model_load_info(&ml).unwrap_err().contains("bad metadata override type"));
+    // derived values are still validated
+    let ml = LlamaModelLoader::new(metadata(), overrides(&["llama.expert_used_count=int:9"]));
+    assert!(llama_model_load_info(&ml).unwrap_err().contains("expert"));
+    let ml = LlamaModelLoader::new(metadata(), overrides(&["llama.block_count=int:-1"]));
+    assert!(llama_model_load_info(&ml).is_err());
+    // the architecture itself can be overridden, and then selects other keys
+    let ml = LlamaModelLoader::new(metadata(), overrides(&["general.architecture=str:qwen2"]));
+    assert!(llama_model_load_info(&ml).unwrap_err().contains("qwen2.context_length"));
+}
+
+#[test]
+fn test_kv_override_c_api() {
+    let path = std::env::temp_dir().join(format!("llmrust_kv_override_{}.gguf", std::process::id()));
+    writer().write(&path).unwrap();
+    let c_path = cstr(path.to_str().unwrap());
+
+    let mut ovr: [llama_model_kv_override; 2] = unsafe { std::mem::zeroed() };
+    for (dst, &src) in ovr[0].key.iter_mut().zip(b"llama.block_count") {
+        *dst = src as _;
+    }
+    ovr[0].tag = LLAMA_KV_OVERRIDE_TYPE_INT;
+    ovr[0].value.val_i64 = 2;
+    let mut mparams = llama_model_default_params();
+    mparams.kv_overrides = ovr.as_ptr();
+
+    // the overridden hyperparameters reach the model and its contexts
+    let model = llama_model_load_from_file(c_path.as_ptr(), mparams);
+    assert!(!model.is_null());
+    assert_eq!(llama_model_n_layer(model), 2);
+    let hparams = load_model_info(path.to_str().unwrap(), overrides(&["llama.block_count=int:2"])).unwrap().hparams;
+    assert_eq!(hparams.n_layer, 2);
+    let ctx = llama_init_from_model(model, llama_context_default_params());
+    let fingerprint = unsafe { llama_context::from_handle(ctx as *mut c_void) }.unwrap().fingerprint();
+    assert_eq!(fingerprint, hparams.fingerprint());
+    llama_free(ctx);
+    llama_model_free(model);
+
+    // a file that exists but does not parse is an error, not the mock model
+    std::fs::write(&path, b"GGUF\x03\0\0\0").unwrap();
+    assert!(llama_model_load_from_file(c_path.as_ptr(), llama_model_default_params()).is_null());
+    assert!(load_model_info(path.to_str().unwrap(), Vec::new()).is_err());
+    std::fs::remove_file(&path).unwrap();
+    let model = llama_model_load_from_file(c_path.as_ptr(), llama_model_default_params());
+    assert_eq!(llama_model_n_layer(model), 32);
+    llama_model_free(model);
+}
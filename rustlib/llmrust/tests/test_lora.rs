@@ -0,0 +1,227 @@
+// tests/test_lora.rs - LoRA adapter loading and hot-swapping tests
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::llmrust::ggml::src::gguf::{GgufValue, GgufWriter};
+use crate::llmrust::src::adapter_loader::llama_adapter_lora_load;
+use crate::llmrust::src::llama_adapter::{LlamaAdapterLora, LlamaAdapterLoras};
+use crate::llmrust::src::llama_arch::llama_tensor_shape;
+use crate::llmrust::src::llama_batch::{LlamaBatch, LlamaUbatch};
+use crate::llmrust::src::llama_context::LlamaContext;
+use crate::llmrust::src::llama_graph::LlamaGraph;
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+
+const N_EMBD: usize = 4;
+const N_VOCAB: usize = 3;
+
+/// Base output weight, `[N_EMBD, N_VOCAB]`
+fn base_w() -> Vec<f32> {
+    (0..N_EMBD * N_VOCAB).map(|i| i as f32 * 0.1).collect()
+}
+
+fn base_shape(name: &str) -> Option<[usize; 2]> {
+    match name {
+        "output.weight" => Some([N_EMBD, N_VOCAB]),
+        "blk.0.attn_q.weight" => Some([N_EMBD, N_EMBD]),
+        _ => None,
+    }
+}
+
+struct Fixture {
+    path: PathBuf,
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn adapter_file(name: &str, edit: impl FnOnce(&mut GgufWriter)) -> Fixture {
+    let mut w = GgufWriter::new();
+    w.set_str("general.type", "adapter")
+        .set_str("general.architecture", "llama")
+        .set_str("adapter.type", "lora")
+        .set_str("adapter.lora.task_name", name);
+    edit(&mut w);
+    let path = std::env::temp_dir().join(format!("llmrust_lora_{}_{}.gguf", name, std::process::id()));
+    w.write(&path).unwrap();
+    Fixture { path }
+}
+
+/// Rank-`rank` pair for `output.weight` with deterministic values
+fn add_pair(w: &mut GgufWriter, rank: usize, seed: f32) {
+    let a: Vec<f32> = (0..N_EMBD * rank).map(|i| ((i as f32 + seed) * 0.37).sin()).collect();
+    let b: Vec<f32> = (0..rank * N_VOCAB).map(|i| ((i as f32 - seed) * 0.53).cos()).collect();
+    w.add_tensor_f32("output.weight.lora_a", &[N_EMBD, rank], &a).unwrap();
+    w.add_tensor_f32("output.weight.lora_b", &[rank, N_VOCAB], &b).unwrap();
+}
+
+fn load(fixture: &Fixture) -> Result<LlamaAdapterLora, String> {
+    llama_adapter_lora_load(&fixture.path, "llama", base_shape)
+}
+
+/// Adds `s·B·A` of the adapter's `output.weight` pair to `w`
+fn merge(adapter: &LlamaAdapterLora, scale: f32, w: &mut [f32]) {
+    let lw = adapter.weight("output.weight").unwrap();
+    let s = adapter.effective_scale(lw, scale);
+    for o in 0..N_VOCAB {
+        for i in 0..N_EMBD {
+            w[o * N_EMBD + i] += s * (0..lw.rank).map(|k| lw.b[o * lw.rank + k] * lw.a[k * N_EMBD + i]).sum::<f32>();
+        }
+    }
+}
+
+fn mm(w: &[f32], x: &[f32]) -> Vec<f32> {
+    w.chunks_exact(x.len()).map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum()).collect()
+}
+
+fn assert_close(a: &[f32], b: &[f32]) {
+    assert_eq!(a.len(), b.len());
+    for (x, y) in a.iter().zip(b) {
+        assert!((x - y).abs() < 1e-5, "{:?} != {:?}", a, b);
+    }
+}
+
+/// Hidden state of token t is [t, 1, -t, 0.5]; logits go through `output.weight`
+struct LoraGraph {
+    loras: LlamaAdapterLoras,
+}
+
+impl LlamaGraph for LoraGraph {
+    fn n_vocab(&self) -> usize {
+        N_VOCAB
+    }
+
+    fn set_adapter_loras(&mut self, loras: &LlamaAdapterLoras) {
+        self.loras = loras.clone();
+    }
+
+    fn compute(&mut self, batch: &LlamaBatch, ubatch: &LlamaUbatch) -> Result<Vec<Vec<f32>>, String> {
+        let w = base_w();
+        Ok(ubatch
+            .idxs
+            .iter()
+            .filter(|&&i| batch.logits[i])
+            .map(|&i| {
+                let t = batch.token[i] as f32;
+                self.loras.mm("output.weight", &w, &[t, 1.0, -t, 0.5])
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn test_lora_load_and_validate() {
+    let ok = adapter_file("ok", |w| {
+        w.set("adapter.lora.alpha", GgufValue::F32(4.0));
+        add_pair(w, 2, 0.0);
+    });
+    let adapter = load(&ok).unwrap();
+    assert_eq!(adapter.alpha, 4.0);
+    assert_eq!(adapter.meta_val_str("adapter.lora.task_name"), Some("ok"));
+    assert_eq!(adapter.weight_names().collect::<Vec<_>>(), vec!["output.weight"]);
+    let lw = adapter.weight("output.weight").unwrap();
+    assert_eq!((lw.n_in, lw.n_out, lw.rank), (N_EMBD, N_VOCAB, 2));
+    assert_eq!(adapter.effective_scale(lw, 0.5), 1.0);
+
+    let wrong_arch = adapter_file("arch", |w| {
+        w.set_str("general.architecture", "bert");
+        add_pair(w, 2, 0.0);
+    });
+    assert!(load(&wrong_arch).unwrap_err().contains("general.architecture"));
+
+    let not_lora = adapter_file("type", |w| {
+        w.set_str("general.type", "model");
+        add_pair(w, 2, 0.0);
+    });
+    assert!(load(&not_lora).is_err());
+
+    let missing_b = adapter_file("missing", |w| {
+        w.add_tensor_f32("output.weight.lora_a", &[N_EMBD, 1], &[0.0; N_EMBD]).unwrap();
+    });
+    assert!(load(&missing_b).unwrap_err().contains("missing lora_b"));
+
+    let bad_shape = adapter_file("shape", |w| {
+        w.add_tensor_f32("blk.0.attn_q.weight.lora_a", &[N_EMBD, 1], &[0.0; N_EMBD]).unwrap();
+        w.add_tensor_f32("blk.0.attn_q.weight.lora_b", &[1, N_VOCAB], &[0.0; N_VOCAB]).unwrap();
+    });
+    assert!(load(&bad_shape).unwrap_err().contains("shape mismatch"));
+
+    let unknown = adapter_file("unknown", |w| {
+        w.add_tensor_f32("blk.9.ffn_up.weight.lora_a", &[N_EMBD, 1], &[0.0; N_EMBD]).unwrap();
+        w.add_tensor_f32("blk.9.ffn_up.weight.lora_b", &[1, N_EMBD], &[0.0; N_EMBD]).unwrap();
+    });
+    assert!(load(&unknown).unwrap_err().contains("no weight"));
+
+    let hparams = LlamaHparams { n_vocab: 100, n_embd: 64, n_layer: 2, n_head: 8, n_head_kv: 2, n_embd_head_k: 8, n_embd_head_v: 8, n_ff: 128, ..Default::default() };
+    assert_eq!(llama_tensor_shape(&hparams, "blk.1.attn_k.weight"), Some([64, 16]));
+    assert_eq!(llama_tensor_shape(&hparams, "blk.0.ffn_down.weight"), Some([128, 64]));
+    assert_eq!(llama_tensor_shape(&hparams, "output.weight"), Some([64, 100]));
+    assert_eq!(llama_tensor_shape(&hparams, "blk.2.attn_q.weight"), None);
+}
+
+#[test]
+fn test_lora_mm_matches_merged_weights() {
+    let f1 = adapter_file("mm1", |w| {
+        w.set("adapter.lora.alpha", GgufValue::F32(8.0));
+        add_pair(w, 4, 1.0);
+    });
+    let f2 = adapter_file("mm2", |w| add_pair(w, 1, 3.0));
+    let (a1, a2) = (Arc::new(load(&f1).unwrap()), Arc::new(load(&f2).unwrap()));
+    let x = [0.3, -1.2, 2.0, 0.7];
+
+    let mut loras = LlamaAdapterLoras::default();
+    assert_close(&loras.mm("output.weight", &base_w(), &x), &mm(&base_w(), &x));
+
+    loras.set(a1.clone(), 0.75);
+    loras.set(a2.clone(), -0.5);
+    let mut merged = base_w();
+    merge(&a1, 0.75, &mut merged);
+    merge(&a2, -0.5, &mut merged);
+    assert_close(&loras.mm("output.weight", &base_w(), &x), &mm(&merged, &x));
+    // weights an adapter does not patch are untouched
+    assert_close(&loras.mm("blk.0.attn_q.weight", &[1.0; 16], &x), &mm(&[1.0; 16], &x));
+}
+
+#[test]
+fn test_lora_hot_swap_on_context() {
+    let f1 = adapter_file("swap1", |w| add_pair(w, 2, 0.0));
+    let f2 = adapter_file("swap2", |w| add_pair(w, 3, 5.0));
+    let (a1, a2) = (Arc::new(load(&f1).unwrap()), Arc::new(load(&f2).unwrap()));
+
+    let mut batch = LlamaBatch::new();
+    batch.add(1, 0, &[0], false);
+    batch.add(2, 1, &[0], true);
+    let x = [2.0, 1.0, -2.0, 0.5];
+    let mut ctx = LlamaContext::new(0, 64, None);
+    let mut graph = LoraGraph { loras: LlamaAdapterLoras::default() };
+    let mut logits = |ctx: &mut LlamaContext| {
+        ctx.decode(&batch, &mut graph).unwrap();
+        ctx.get_logits_ith(-1).unwrap().to_vec()
+    };
+
+    let base = logits(&mut ctx);
+    assert_close(&base, &mm(&base_w(), &x));
+
+    ctx.set_adapter_lora(a1.clone(), 1.0);
+    ctx.set_adapter_lora(a2.clone(), 2.0);
+    let mut merged = base_w();
+    merge(&a1, 1.0, &mut merged);
+    merge(&a2, 2.0, &mut merged);
+    assert_close(&logits(&mut ctx), &mm(&merged, &x));
+
+    // rescale one, disable the other
+    ctx.set_adapter_lora(a1.clone(), 0.5);
+    assert!(ctx.rm_adapter_lora(&a2));
+    assert!(!ctx.rm_adapter_lora(&a2));
+    assert_eq!(ctx.adapter_loras().len(), 1);
+    assert_eq!(ctx.adapter_loras().scale(&a1), Some(0.5));
+    let mut merged = base_w();
+    merge(&a1, 0.5, &mut merged);
+    assert_close(&logits(&mut ctx), &mm(&merged, &x));
+
+    ctx.clear_adapter_lora();
+    assert_close(&logits(&mut ctx), &base);
+}
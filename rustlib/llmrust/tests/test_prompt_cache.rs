@@ -0,0 +1,318 @@
+// tests/test_prompt_cache.rs - Prompt prefix cache tests
+
+use std::path::PathBuf;
+
+use crate::common::log::{common_params, cstr, llama_state_load_file, llama_state_save_file};
+use crate::common::model::{
+    common_prompt_cache_reuse, common_prompt_cache_save, llama_context_default_params, llama_free, llama_init_from_model, llama_model_default_params,
+    llama_model_free, llama_model_load_from_file, restore_prompt_cache,
+};
+use crate::llmrust::common::prompt_cache::{LlamaRadixTree, PromptCache, PromptCacheFile, PromptCacheHit};
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvCachePaged, LlamaKvUbatchToken};
+use crate::llmrust::src::llama_memory::{LlamaMemory, LlamaSeqId};
+
+fn hparams() -> LlamaHparams {
+    LlamaHparams {
+        n_layer: 2,
+        n_head: 4,
+        n_head_kv: 2,
+        n_embd_head_k: 8,
+        n_embd_head_v: 8,
+        n_rot: 8,
+        ..Default::default()
+    }
+}
+
+fn paged(n_blocks: u32) -> LlamaKvCachePaged {
+    LlamaKvCachePaged::new(&hparams(), n_blocks, 4, GgmlType::F32, GgmlType::F32).unwrap()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("llmrust_{}_{}.session", name, std::process::id()))
+}
+
+/// Fills `seq_id` with one token per entry of `tokens`, keyed by the token.
+fn fill(kv: &mut LlamaKvCachePaged, seq_id: LlamaSeqId, tokens: &[i32]) {
+    for (pos, &token) in tokens.iter().enumerate() {
+        let slot = kv.append(seq_id, pos as i32).unwrap();
+        kv.cpy_k(0, slot, &[token as f32; 16]);
+    }
+}
+
+#[test]
+fn test_radix_tree() {
+    let mut tree = LlamaRadixTree::new();
+    assert!(tree.insert(&[1, 2, 3, 4], 0).is_empty());
+    assert!(tree.insert(&[1, 2, 5], 1).is_empty());
+    assert_eq!(tree.len(), 2);
+
+    assert_eq!(tree.longest_prefix(&[1, 2, 3, 9]), Some((0, 3)));
+    assert_eq!(tree.longest_prefix(&[1, 2, 5, 6]), Some((1, 3)));
+    assert_eq!(tree.longest_prefix(&[1, 2, 7]).map(|(_, n)| n), Some(2));
+    assert_eq!(tree.longest_prefix(&[9, 1]), None);
+    assert_eq!(tree.longest_prefix(&[]), None);
+
+    // a prefix of other entries is its own entry
+    assert!(tree.insert(&[1, 2], 2).is_empty());
+    assert_eq!(tree.longest_prefix(&[1, 2]), Some((2, 2)));
+
+    // entries on the path of a longer sequence are superseded by it
+    assert_eq!(tree.insert(&[1, 2, 3, 4, 6], 3), vec![2, 0]);
+    assert_eq!(tree.len(), 2);
+    assert_eq!(tree.longest_prefix(&[1, 2, 3, 4, 6, 7]), Some((3, 5)));
+
+    assert_eq!(tree.remove(&[1, 2]), None);
+    assert_eq!(tree.remove(&[1, 2, 5]), Some(1));
+    assert_eq!(tree.remove(&[1, 2, 5]), None);
+    assert_eq!(tree.len(), 1);
+    assert_eq!(tree.longest_prefix(&[1, 2, 5]), Some((3, 2)));
+
+    assert_eq!(tree.insert(&[1, 2, 3, 4, 6], 4), vec![3]);
+    assert_eq!(tree.remove(&[1, 2, 3, 4, 6]), Some(4));
+    assert!(tree.is_empty());
+    assert_eq!(tree.longest_prefix(&[1, 2]), None);
+}
+
+#[test]
+fn test_prompt_cache_reuse() {
+    let mut kv = paged(8);
+    let mut cache = PromptCache::new(usize::MAX);
+    let prompt = [10, 11, 12, 13, 14, 15];
+    fill(&mut kv, 0, &prompt);
+    cache.insert(&mut kv, 0, 0, prompt.to_vec());
+
+    let hit = cache.lookup(0, &[10, 11, 12, 13, 14, 99, 100]).unwrap();
+    assert_eq!(hit, PromptCacheHit { seq_id: 0, n_tokens: 5 });
+    assert_eq!(cache.lookup(0, &[11]), None);
+
+    // the request shares the cached blocks and copies the one it writes to
+    kv.try_seq_cp(hit.seq_id, 1, 0, hit.n_tokens as i32).unwrap();
+    assert_eq!(kv.seq_blocks(1), kv.seq_blocks(0));
+    let slot = kv.append(1, 5).unwrap();
+    kv.cpy_k(0, slot, &[99.0; 16]);
+    assert_eq!(kv.seq_blocks(1)[0], kv.seq_blocks(0)[0]);
+    assert_ne!(kv.seq_blocks(1)[1], kv.seq_blocks(0)[1]);
+    assert_eq!(kv.block_ref_count(kv.seq_blocks(0)[0]), 2);
+    assert_eq!(kv.get_k_f32(0, kv.seq_blocks(1)[1] * 4)[0], 14.0);
+    assert_eq!(kv.get_k_f32(0, kv.seq_blocks(0)[1] * 4 + 1)[0], 15.0);
+    assert_eq!(cache.n_blocks(&kv), 2);
+
+    // a longer prompt replaces the entry it extends
+    let longer = [10, 11, 12, 13, 14, 15, 16];
+    fill(&mut kv, 2, &longer);
+    cache.insert(&mut kv, 2, 0, longer.to_vec());
+    assert_eq!(cache.len(), 1);
+    assert!(!cache.contains(0));
+    assert_eq!(kv.seq_n_tokens(0), 0);
+    assert_eq!(cache.lookup(0, &prompt), Some(PromptCacheHit { seq_id: 2, n_tokens: 6 }));
+
+    cache.clear(&mut kv);
+    assert!(cache.is_empty());
+    assert_eq!(kv.n_free_blocks(), 8 - kv.seq_blocks(1).len());
+}
+
+#[test]
+fn test_prompt_cache_groups() {
+    let mut kv = paged(8);
+    let mut cache = PromptCache::new(usize::MAX);
+    let prompt = [10, 11, 12, 13, 14, 15];
+    fill(&mut kv, 0, &prompt);
+    cache.insert(&mut kv, 0, 0, prompt.to_vec());
+    fill(&mut kv, 1, &prompt);
+    cache.insert(&mut kv, 1, 7, prompt.to_vec());
+
+    // the same tokens under another group neither supersede nor match
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.lookup(0, &prompt), Some(PromptCacheHit { seq_id: 0, n_tokens: 6 }));
+    assert_eq!(cache.lookup(7, &prompt), Some(PromptCacheHit { seq_id: 1, n_tokens: 6 }));
+    assert_eq!(cache.lookup(8, &prompt), None);
+
+    assert_eq!(cache.evict_lru(&mut kv), Some(0));
+    assert_eq!(cache.lookup(0, &prompt), None);
+    assert_eq!(cache.lookup(7, &prompt[..3]), Some(PromptCacheHit { seq_id: 1, n_tokens: 3 }));
+}
+
+#[test]
+fn test_prompt_cache_lru_eviction() {
+    let mut kv = paged(8);
+    let block_bytes = kv.block_memory_size();
+    assert_eq!(block_bytes, 4 * 2 * 2 * 16 * 4);
+    let mut cache = PromptCache::new(3 * block_bytes);
+
+    fill(&mut kv, 0, &[1, 2, 3]);
+    cache.insert(&mut kv, 0, 0, vec![1, 2, 3]);
+    fill(&mut kv, 1, &[4, 5, 6]);
+    cache.insert(&mut kv, 1, 0, vec![4, 5, 6]);
+    assert_eq!(cache.memory_size(&kv), 2 * block_bytes);
+
+    // seq 0 was used more recently than seq 1
+    assert!(cache.lookup(0, &[1, 2]).is_some());
+    fill(&mut kv, 2, &[7, 8, 9, 10, 11]);
+    cache.insert(&mut kv, 2, 0, vec![7, 8, 9, 10, 11]);
+    assert!(cache.contains(0));
+    assert!(!cache.contains(1));
+    assert!(cache.contains(2));
+    assert_eq!(kv.seq_n_tokens(1), 0);
+    assert_eq!(cache.memory_size(&kv), 3 * block_bytes);
+    assert_eq!(kv.n_free_blocks(), 5);
+
+    assert_eq!(cache.evict_lru(&mut kv), Some(0));
+    assert_eq!(cache.evict_lru(&mut kv), Some(2));
+    assert_eq!(cache.evict_lru(&mut kv), None);
+    assert_eq!(kv.n_free_blocks(), 8);
+}
+
+/// Cell cache holding `prompt` in seq 0, with K and V rows derived from the token
+fn session_kv(type_kv: GgmlType, prompt: &[i32]) -> LlamaKvCache {
+    let mut kv = LlamaKvCache::new(&hparams(), 16, 2, type_kv, type_kv).unwrap();
+    let tokens: Vec<_> = (0..prompt.len() as i32).map(|pos| LlamaKvUbatchToken { pos, seq_ids: vec![0] }).collect();
+    let cells = kv.apply_ubatch(&tokens).unwrap();
+    for (&cell, &token) in cells.iter().zip(prompt) {
+        for il in 0..2 {
+            let row: Vec<f32> = (0..16).map(|i| token as f32 + i as f32 * 0.25 + il as f32).collect();
+            kv.cpy_k(il, cell, &row);
+            kv.cpy_v(il, cell, &row.iter().map(|x| -x).collect::<Vec<_>>());
+        }
+    }
+    kv
+}
+
+#[test]
+fn test_prompt_cache_file() {
+    let path = temp_path("file");
+    let _ = std::fs::remove_file(&path);
+    let fingerprint = hparams().fingerprint();
+    let prompt = [5, 6, 7, 8, 9, 10];
+    let kv = session_kv(GgmlType::F16, &prompt);
+
+    // nothing to restore yet
+    let file = PromptCacheFile::new(&path, false, false);
+    let mut restored = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F16, GgmlType::F16).unwrap();
+    assert_eq!(file.restore(&mut restored, 0, fingerprint, &prompt), Ok(0));
+
+    assert_eq!(file.save_final(&kv, 0, fingerprint, &prompt), Ok(false));
+    assert!(!path.exists());
+    assert_eq!(file.save_prompt(&kv, 0, fingerprint, &prompt), Ok(true));
+
+    // the part of the new prompt that matches is kept
+    assert_eq!(file.restore(&mut restored, 0, fingerprint, &[5, 6, 7, 8, 42]), Ok(4));
+    assert_eq!(restored.n_used(), 4);
+    assert_eq!(restored.seq_pos_max(0), 3);
+    for cell in 0..4 {
+        assert_eq!(restored.cell(cell).pos, cell as i32);
+        for il in 0..2 {
+            assert_eq!(restored.get_k(il, cell), kv.get_k(il, cell));
+            assert_eq!(restored.get_v(il, cell), kv.get_v(il, cell));
+        }
+    }
+    // the same prompt still evaluates its last token
+    assert_eq!(file.restore(&mut restored, 1, fingerprint, &prompt), Ok(5));
+    assert_eq!(restored.n_used(), 9);
+
+    // another model, or another cache type, is rejected
+    let other = LlamaHparams { n_layer: 3, ..hparams() }.fingerprint();
+    assert_ne!(other, fingerprint);
+    let e = file.restore(&mut restored, 0, other, &prompt).unwrap_err();
+    assert!(e.contains("different model"), "{}", e);
+    let mut wrong_type = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F32, GgmlType::F32).unwrap();
+    let e = file.restore(&mut wrong_type, 0, fingerprint, &prompt).unwrap_err();
+    assert!(e.contains("mismatched key type"), "{}", e);
+    assert_eq!(wrong_type.n_used(), 0);
+
+    // read-only never writes
+    let modified = session_kv(GgmlType::F16, &[1, 2, 3]);
+    let read_only = PromptCacheFile::new(&path, true, true);
+    let before = std::fs::read(&path).unwrap();
+    assert_eq!(read_only.save_prompt(&modified, 0, fingerprint, &[1, 2, 3]), Ok(false));
+    assert_eq!(read_only.save_final(&modified, 0, fingerprint, &[1, 2, 3]), Ok(false));
+    assert_eq!(std::fs::read(&path).unwrap(), before);
+
+    // with `all` the final tokens are saved
+    let all = PromptCacheFile::new(&path, true, false);
+    assert_eq!(all.save_prompt(&modified, 0, fingerprint, &[1, 2, 3]), Ok(false));
+    assert_eq!(all.save_final(&modified, 0, fingerprint, &[1, 2, 3]), Ok(true));
+    assert_eq!(all.restore(&mut restored, 0, fingerprint, &[1, 2, 3, 4]), Ok(3));
+
+    // unknown format versions are rejected
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[4] = 99;
+    std::fs::write(&path, bytes).unwrap();
+    let e = all.restore(&mut restored, 0, fingerprint, &prompt).unwrap_err();
+    assert!(e.contains("unknown session file format"), "{}", e);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_state_file_ffi() {
+    let path = temp_path("ffi");
+    let c_path = cstr(path.to_str().unwrap());
+    let tokens = [1, 15043, 3186];
+    assert!(llama_state_save_file(std::ptr::null_mut(), c_path.as_ptr(), tokens.as_ptr(), tokens.len()));
+
+    let mut out = [0; 8];
+    let mut n_out = 0;
+    assert!(llama_state_load_file(std::ptr::null_mut(), c_path.as_ptr(), out.as_mut_ptr(), out.len(), &mut n_out));
+    assert_eq!(&out[..n_out], &tokens);
+    // not enough room for the tokens
+    assert!(!llama_state_load_file(std::ptr::null_mut(), c_path.as_ptr(), out.as_mut_ptr(), 2, &mut n_out));
+    assert_eq!(n_out, 0);
+
+    // no buffer for the tokens
+    assert!(!llama_state_load_file(std::ptr::null_mut(), c_path.as_ptr(), std::ptr::null_mut(), 8, &mut n_out));
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(!llama_state_load_file(std::ptr::null_mut(), c_path.as_ptr(), out.as_mut_ptr(), out.len(), &mut n_out));
+}
+
+#[test]
+fn test_prompt_cache_session_ffi() {
+    let path = temp_path("session");
+    let _ = std::fs::remove_file(&path);
+    let c_path = cstr(path.to_str().unwrap());
+    let mut params: common_params = unsafe { std::mem::zeroed() };
+    params.path_prompt_cache = c_path.as_ptr();
+    let model = llama_model_load_from_file(cstr("llmrust_no_such_model.gguf").as_ptr(), llama_model_default_params());
+    let new_ctx = || llama_init_from_model(model, llama_context_default_params());
+
+    // first run: nothing to reuse, the prompt is saved once evaluated and the
+    // output only with `prompt_cache_all`
+    let ctx = new_ctx();
+    restore_prompt_cache(ctx, path.to_str().unwrap(), false);
+    let prompt = [1, 15043, 3186, 29991];
+    assert_eq!(common_prompt_cache_reuse(ctx, prompt.as_ptr(), prompt.len()), 0);
+    assert!(common_prompt_cache_save(ctx, &params, prompt.as_ptr(), prompt.len(), false));
+    let output = [1, 15043, 3186, 29991, 450, 1234];
+    assert!(!common_prompt_cache_save(ctx, &params, output.as_ptr(), output.len(), true));
+    llama_free(ctx);
+
+    // second run: the matching prefix is skipped
+    let ctx = new_ctx();
+    restore_prompt_cache(ctx, path.to_str().unwrap(), false);
+    assert_eq!(common_prompt_cache_reuse(ctx, [1, 15043, 42].as_ptr(), 3), 2);
+    llama_free(ctx);
+    let ctx = new_ctx();
+    restore_prompt_cache(ctx, path.to_str().unwrap(), false);
+    // the last prompt token is evaluated again
+    assert_eq!(common_prompt_cache_reuse(ctx, prompt.as_ptr(), prompt.len()), 3);
+    params.prompt_cache_all = true;
+    assert!(common_prompt_cache_save(ctx, &params, output.as_ptr(), output.len(), true));
+    llama_free(ctx);
+
+    // read-only: the saved output is reused but the file is never written
+    params.prompt_cache_ro = true;
+    let before = std::fs::read(&path).unwrap();
+    let ctx = new_ctx();
+    restore_prompt_cache(ctx, path.to_str().unwrap(), true);
+    let longer = [1, 15043, 3186, 29991, 450, 1234, 7];
+    assert_eq!(common_prompt_cache_reuse(ctx, longer.as_ptr(), longer.len()), 6);
+    assert!(!common_prompt_cache_save(ctx, &params, longer.as_ptr(), longer.len(), false));
+    assert!(!common_prompt_cache_save(ctx, &params, longer.as_ptr(), longer.len(), true));
+    assert_eq!(std::fs::read(&path).unwrap(), before);
+    llama_free(ctx);
+    llama_model_free(model);
+
+    std::fs::remove_file(&path).unwrap();
+}
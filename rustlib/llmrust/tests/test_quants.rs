@@ -0,0 +1,118 @@
+// tests/test_quants.rs - Half precision, quantization and quantized dot product tests
+
+use crate::llmrust::ggml::src::ggml::{fp16_to_fp32, fp32_to_fp16, GgmlType};
+use crate::llmrust::ggml::src::ggml_cpu::ops::{attn_head, vec_mad_row, QueryRow};
+use crate::llmrust::ggml::src::ggml_quants::{dequantize_row, quantize_row};
+
+fn data(n: usize, seed: f32) -> Vec<f32> {
+    (0..n).map(|i| ((i as f32 + seed) * 0.731).sin() * 2.0 - 0.3).collect()
+}
+
+fn max_err(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max)
+}
+
+#[test]
+fn test_fp16_conversion() {
+    for &v in &[0.0f32, 1.0, -2.5, 65504.0, 0.333_333_34, 6.1e-5, 5.96e-8, -1e-3] {
+        let h = fp32_to_fp16(v);
+        let back = fp16_to_fp32(h);
+        assert!((back - v).abs() <= v.abs() * 1e-3 + 6e-8, "{} -> {:#06x} -> {}", v, h, back);
+    }
+    assert_eq!(fp32_to_fp16(1.0), 0x3c00);
+    assert_eq!(fp32_to_fp16(-2.0), 0xc000);
+    assert_eq!(fp32_to_fp16(1e6), 0x7c00);
+    assert_eq!(fp32_to_fp16(1e-9), 0);
+    // 1 + 2^-11 is halfway between 1 and the next half; ties go to even
+    assert_eq!(fp32_to_fp16(1.0 + 1.0 / 2048.0), 0x3c00);
+    assert_eq!(fp32_to_fp16(1.0 + 3.0 / 2048.0), 0x3c02);
+    assert!(fp16_to_fp32(fp32_to_fp16(f32::NAN)).is_nan());
+}
+
+#[test]
+fn test_quantize_roundtrip() {
+    let x = data(128, 0.0);
+    for (ty, tolerance) in [(GgmlType::F32, 0.0), (GgmlType::F16, 2e-3), (GgmlType::Q8_0, 0.02), (GgmlType::Q4_0, 0.3)] {
+        let mut q = vec![0u8; ty.row_size(x.len())];
+        quantize_row(ty, &x, &mut q);
+        let mut y = vec![0.0; x.len()];
+        dequantize_row(ty, &q, &mut y);
+        assert!(max_err(&x, &y) <= tolerance, "{}: error {}", ty.name(), max_err(&x, &y));
+    }
+    assert_eq!(GgmlType::Q8_0.row_size(128), 4 * 34);
+    assert_eq!(GgmlType::Q4_0.row_size(128), 4 * 18);
+    assert_eq!(GgmlType::from_name("q4_0"), Some(GgmlType::Q4_0));
+    assert_eq!(GgmlType::from_i32(8), Some(GgmlType::Q8_0));
+    assert_eq!(GgmlType::from_i32(3), None);
+}
+
+#[test]
+fn test_quantized_vec_dot_and_mad() {
+    let k = data(64, 1.0);
+    let q = data(64, 7.0);
+    let exact: f32 = k.iter().zip(&q).map(|(a, b)| a * b).sum();
+
+    for ty in [GgmlType::F32, GgmlType::F16, GgmlType::Q8_0, GgmlType::Q4_0] {
+        let mut row = vec![0u8; ty.row_size(k.len())];
+        quantize_row(ty, &k, &mut row);
+        let mut kq = vec![0.0; k.len()];
+        dequantize_row(ty, &row, &mut kq);
+        let reference: f32 = kq.iter().zip(&q).map(|(a, b)| a * b).sum();
+
+        let dot = QueryRow::new(&q, ty).dot(ty, &row);
+        assert!((dot - reference).abs() < 0.05 * exact.abs().max(1.0), "{}: {} vs {}", ty.name(), dot, reference);
+
+        let mut acc = vec![1.0; k.len()];
+        vec_mad_row(ty, &mut acc, &row, 0.5);
+        let expected: Vec<f32> = kq.iter().map(|v| 1.0 + v * 0.5).collect();
+        assert!(max_err(&acc, &expected) < 1e-5, "{}", ty.name());
+    }
+}
+
+#[test]
+fn test_attn_head_quantized_kv() {
+    let n_kv = 6;
+    let head = 32;
+    let q = data(head, 3.0);
+    let keys: Vec<Vec<f32>> = (0..n_kv).map(|i| data(head, 10.0 * i as f32)).collect();
+    let values: Vec<Vec<f32>> = (0..n_kv).map(|i| data(head, 5.0 + 3.0 * i as f32)).collect();
+    let mask = [0.0, 0.0, f32::NEG_INFINITY, 0.0, 0.0, f32::NEG_INFINITY];
+    let scale = 1.0 / (head as f32).sqrt();
+
+    let run = |type_k: GgmlType, type_v: GgmlType| {
+        let store = |ty: GgmlType, rows: &[Vec<f32>]| -> Vec<Vec<u8>> {
+            rows.iter()
+                .map(|r| {
+                    let mut b = vec![0u8; ty.row_size(r.len())];
+                    quantize_row(ty, r, &mut b);
+                    b
+                })
+                .collect()
+        };
+        let k = store(type_k, &keys);
+        let v = store(type_v, &values);
+        let k_rows: Vec<&[u8]> = k.iter().map(|r| r.as_slice()).collect();
+        let v_rows: Vec<&[u8]> = v.iter().map(|r| r.as_slice()).collect();
+        let mut out = vec![0.0; head];
+        attn_head(&q, &k_rows, type_k, &v_rows, type_v, &mask, scale, &mut out);
+        out
+    };
+
+    let reference = run(GgmlType::F32, GgmlType::F32);
+    for (type_k, type_v, tolerance) in [
+        (GgmlType::F16, GgmlType::F16, 4e-3),
+        (GgmlType::Q8_0, GgmlType::Q8_0, 0.03),
+        (GgmlType::Q8_0, GgmlType::Q4_0, 0.25),
+        (GgmlType::Q4_0, GgmlType::Q4_0, 0.3),
+    ] {
+        let out = run(type_k, type_v);
+        let err = max_err(&out, &reference);
+        assert!(err < tolerance, "K {} / V {}: error {}", type_k.name(), type_v.name(), err);
+    }
+
+    // fully masked rows give zeros
+    let mut out = vec![1.0; head];
+    let k: Vec<&[u8]> = vec![];
+    attn_head(&q, &k, GgmlType::F32, &k, GgmlType::F32, &[], scale, &mut out);
+    assert!(out.iter().all(|&v| v == 0.0));
+}
@@ -0,0 +1,160 @@
+// tests/test_regex.rs - Regex DFA compiler and regex sampler tests
+
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_regex::{RegexDfa, RegexSearch, RegexTokenTable, DFA_DEAD};
+use crate::llmrust::src::llama_sampling::{LlamaSampler, LlamaTokenDataArray, RegexSampler};
+use crate::llmrust::src::llama_vocab::LlamaVocab;
+
+fn check(pattern: &str, valid: &[&str], invalid: &[&str]) {
+    let dfa = RegexDfa::compile(pattern).unwrap_or_else(|e| panic!("{}", e));
+    for input in valid {
+        assert!(dfa.matches(input), "{:?} should match {:?}", pattern, input);
+    }
+    for input in invalid {
+        assert!(!dfa.matches(input), "{:?} should not match {:?}", pattern, input);
+    }
+}
+
+#[test]
+fn test_regex_matching() {
+    check(r"\d{4}-\d{2}-\d{2}", &["2024-01-31"], &["2024-1-31", "2024-01-31x", ""]);
+    check(r"^[A-Z]{2}-\d+$", &["AB-1", "ZZ-0042"], &["ab-1", "AB-"]);
+    check(r"(cat|dog)s?", &["cat", "dogs"], &["cow", "catss"]);
+    check(r"a{2,3}b*", &["aa", "aaabbb"], &["a", "aaaa"]);
+    check(r"(?:ab)+|x?", &["", "x", "abab"], &["aba", "xx"]);
+    check(r"[^0-9\s]+", &["abc", "é!"], &["a1", "a b"]);
+    check(r"SELECT \w+ FROM \w+;", &["SELECT id FROM users;"], &["select id from users;"]);
+    check(r"caf[é-ë]\.", &["café.", "cafë."], &["cafe.", "café"]);
+    check(r"é|\x41", &["é", "A"], &["e"]);
+    check(r".{3}", &["a\u{1F600}b"], &["a\nb"]);
+}
+
+#[test]
+fn test_regex_errors() {
+    for pattern in ["(a", "a)", "[a-", "*a", r"(?=a)b", r"(a)\1", "a{3,1}", r"\bword", "a^b", "[z-a]"] {
+        assert!(RegexDfa::compile(pattern).is_err(), "{:?} should be rejected", pattern);
+    }
+    assert!(RegexDfa::compile("[^\\x00-\\uFFFF]{0}").is_ok());
+    for pattern in ["(^a)", "(a$)", "a$b", "a|b^"] {
+        assert!(RegexDfa::compile(pattern).is_err(), "{:?} should be rejected", pattern);
+    }
+
+    // every count is allowed, their product is not
+    let err = RegexDfa::compile("((a{1000}){1000}){1000}").unwrap_err();
+    assert!(err.contains("too large"), "{}", err);
+    assert!(RegexDfa::compile("(a{100}){10}").is_ok() && RegexDfa::compile(".{100}").is_ok());
+}
+
+#[test]
+fn test_regex_anchors() {
+    check(r"^a|b$", &["a", "b"], &["ab", ""]);
+    check(r"\\$", &["\\"], &["\\$"]);
+    check(r"\$", &["$"], &[""]);
+
+    // `^` anchors only its own alternative
+    let dfa = RegexDfa::compile_search(r"^a|b").unwrap();
+    assert!(dfa.matches("ab") && dfa.matches("xb") && dfa.matches("b"));
+    assert!(!dfa.matches("xa") && !dfa.matches("xyz"));
+    // an escaped backslash followed by an anchor
+    let dfa = RegexDfa::compile_search(r"\\$").unwrap();
+    assert!(dfa.matches("a\\") && !dfa.matches("a\\b"));
+    // an escaped dollar is a literal
+    let dfa = RegexDfa::compile_search(r"\$").unwrap();
+    assert!(dfa.matches("$5") && !dfa.matches("5"));
+}
+
+#[test]
+fn test_regex_search_find() {
+    let search = RegexSearch::new("<fn=[a-z]+>", false).unwrap();
+    assert_eq!(search.find(b"call\nx <fn=get> y"), Some(7));
+    assert_eq!(search.find(b"<fn=>"), None);
+    // the earliest start wins, across alternatives
+    let search = RegexSearch::new("b+c|ab", false).unwrap();
+    assert_eq!(search.find(b"xabbc"), Some(1));
+    // anchored alternatives only match at the ends of the text
+    let search = RegexSearch::new("^a|b$", false).unwrap();
+    assert_eq!(search.find(b"ab"), Some(0));
+    assert_eq!(search.find(b"xab"), Some(2));
+    assert_eq!(search.find(b"xba"), None);
+    let full = RegexSearch::new("a+b", true).unwrap();
+    assert_eq!(full.find(b"aab"), Some(0));
+    assert_eq!(full.find(b"xaab"), None);
+    assert!(RegexSearch::new("(a", false).is_err());
+}
+
+fn vocab() -> Arc<LlamaVocab> {
+    let mut vocab = LlamaVocab::from_pieces(&["</s>", "20", "2", "4", "-", "-0", "1-", "a", "\u{2581}"]);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    Arc::new(vocab)
+}
+
+#[test]
+fn test_regex_token_table() {
+    let vocab = vocab();
+    let dfa = RegexDfa::compile(r"\d{2}-\d").unwrap();
+    let table = RegexTokenTable::new(Arc::new(dfa.clone()), Arc::clone(&vocab));
+
+    assert_eq!(table.row(dfa.start()).tokens(), vec![1, 2, 3]);
+
+    let s = table.next(dfa.start(), 2);
+    assert_eq!(table.row(s).tokens(), vec![2, 3, 6]);
+    assert_eq!(table.next(s, 7), DFA_DEAD);
+    assert!(table.row(DFA_DEAD).tokens().is_empty());
+
+    // only the states reached are expanded, however many the pattern has
+    let dfa = Arc::new(RegexDfa::compile("[-0-9a]{0,999}").unwrap());
+    assert!(dfa.n_states() > 999);
+    let table = RegexTokenTable::new(Arc::clone(&dfa), Arc::clone(&vocab));
+    let s = table.next(dfa.start(), 1);
+    assert!(table.row(s).contains(2));
+    assert_eq!(table.n_expanded(), 2);
+
+    // rows requested from several threads at once are built once each
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let mut state = dfa.start();
+                for _ in 0..8 {
+                    state = table.next(state, 2);
+                }
+                assert_ne!(state, DFA_DEAD);
+            });
+        }
+    });
+    // the states after zero to seven tokens
+    assert_eq!(table.n_expanded(), 8);
+
+    // Token transitions agree with running the piece bytes through the DFA
+    for state in 0..dfa.n_states() as u32 {
+        for token in 0..vocab.n_tokens() as i32 {
+            if vocab.is_eog(token) {
+                continue;
+            }
+            assert_eq!(table.next(state, token), dfa.run(state, vocab.token_to_piece(token)));
+            assert_eq!(table.row(state).contains(token), table.next(state, token) != DFA_DEAD);
+        }
+    }
+}
+
+#[test]
+fn test_regex_sampler() {
+    let mut sampler = RegexSampler::new(vocab(), r"\d{2}-\d").unwrap();
+    let allowed = |sampler: &mut RegexSampler| {
+        let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 9]);
+        sampler.apply(&mut cur_p);
+        cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect::<Vec<_>>()
+    };
+
+    assert_eq!(allowed(&mut sampler), vec![1, 2, 3]);
+    sampler.accept(1);
+    assert_eq!(allowed(&mut sampler), vec![4, 5]);
+    sampler.accept(5);
+    assert!(sampler.is_accepting());
+    assert_eq!(allowed(&mut sampler), vec![0]);
+
+    sampler.reset();
+    assert!(!sampler.is_accepting());
+    sampler.accept(7);
+    assert!(sampler.is_dead());
+}
@@ -0,0 +1,174 @@
+// tests/test_repack.rs - Interleaved weight layout tests
+
+use std::os::raw::c_void;
+
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_backend::GgmlBackendBufferType::{CpuMapped, CpuRepack};
+use crate::llmrust::ggml::src::ggml_cpu::ops::mul_mat;
+use crate::llmrust::ggml::src::ggml_cpu::repack::{gemm_repacked, repack, unpack, RepackLayout, REPACK_4X4, REPACK_8X8};
+use crate::llmrust::ggml::src::ggml_quants::quantize_row;
+use crate::common::log::cstr;
+use crate::common::model::{llama_model_default_params, llama_model_free, llama_model_load_from_file};
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+use crate::llmrust::src::llama_model;
+use crate::llmrust::src::llama_model_loader::{LlamaModelLoader, LlamaTensorBuffer};
+use crate::llmrust::tools::llama_bench::{llama_bench, llama_bench_markdown, llama_bench_parse_args};
+
+fn weights(ty: GgmlType, ncols: usize, nrows: usize) -> Vec<u8> {
+    let row_size = ty.row_size(ncols);
+    let mut data = vec![0u8; row_size * nrows];
+    for (r, row) in data.chunks_exact_mut(row_size).enumerate() {
+        let values: Vec<f32> = (0..ncols).map(|c| ((r * 7 + c * 3) % 23) as f32 / 11.0 - 1.0).collect();
+        quantize_row(ty, &values, row);
+    }
+    data
+}
+
+#[test]
+fn test_repack_round_trip_and_gemm() {
+    let (ncols, nrows) = (64, 16);
+    for ty in [GgmlType::Q4_0, GgmlType::Q8_0] {
+        let w = weights(ty, ncols, nrows);
+        for layout in [REPACK_4X4, REPACK_8X8] {
+            let packed = repack(ty, layout, &w, ncols, nrows).unwrap();
+            assert_eq!(packed.len(), w.len());
+            assert_ne!(packed, w);
+            assert_eq!(unpack(ty, layout, &packed, ncols, nrows).unwrap(), w);
+
+            // 6 activation rows: one full tile and a partial one
+            let x: Vec<f32> = (0..6 * ncols).map(|i| ((i % 17) as f32 - 8.0) / 8.0).collect();
+            let mut expected = vec![0.0; 6 * nrows];
+            mul_mat(ty, &w, ncols, nrows, &x, &mut expected);
+            let mut out = vec![0.0; 6 * nrows];
+            gemm_repacked(ty, layout, &packed, ncols, nrows, &x, &mut out).unwrap();
+            for (a, b) in out.iter().zip(&expected) {
+                assert!((a - b).abs() <= 1e-4 * b.abs().max(1.0), "{} {}: {} vs {}", layout.type_name(ty), ncols, a, b);
+            }
+        }
+    }
+    assert_eq!(REPACK_8X8.type_name(GgmlType::Q4_0), "q4_0_8x8");
+    assert_eq!(RepackLayout::preferred(6), None);
+    assert!(RepackLayout::preferred(12) == Some(REPACK_4X4));
+
+    let w = weights(GgmlType::Q8_0, ncols, 12);
+    assert!(repack(GgmlType::Q8_0, REPACK_8X8, &w, ncols, 12).unwrap_err().contains("does not fit"));
+    assert!(repack(GgmlType::F16, REPACK_4X4, &[0; 16], 4, 4).unwrap_err().contains("cannot be repacked"));
+    let mut out = vec![0.0; 12];
+    assert!(gemm_repacked(GgmlType::Q8_0, REPACK_4X4, &w, ncols, 12, &[0.0; 10], &mut out).is_err());
+    assert!(gemm_repacked(GgmlType::Q8_0, REPACK_4X4, &[], 0, 4, &[], &mut []).unwrap_err().contains("no columns"));
+}
+
+#[test]
+fn test_repack_model_placement() {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama");
+    w.add_tensor("token_embd.weight", &[32, 8], GgmlType::Q8_0, weights(GgmlType::Q8_0, 32, 8)).unwrap();
+    w.add_tensor("blk.0.attn_q.weight", &[32, 8], GgmlType::Q4_0, weights(GgmlType::Q4_0, 32, 8)).unwrap();
+    w.add_tensor_f32("blk.0.attn_norm.weight", &[32], &[1.0; 32]).unwrap();
+    let path = std::env::temp_dir().join(format!("llmrust_repack_{}.gguf", std::process::id()));
+    w.write(&path).unwrap();
+    let ml = LlamaModelLoader::new(GgufFile::read_mmap(&path).unwrap(), Vec::new());
+    std::fs::remove_file(&path).unwrap();
+
+    let placement = ml.tensor_placement(true, true, &[]).unwrap();
+    let bufts: Vec<_> = placement.iter().map(|p| (p.name.as_str(), p.buft)).collect();
+    assert_eq!(
+        bufts,
+        [("token_embd.weight", CpuMapped), ("blk.0.attn_q.weight", CpuRepack), ("blk.0.attn_norm.weight", CpuMapped)]
+    );
+
+    assert!(ml.repack_skipped(&placement).is_empty());
+
+    let attn_q = ml.load_tensor(&placement[1]).unwrap();
+    let layout = attn_q.layout.unwrap();
+    let original = weights(GgmlType::Q4_0, 32, 8);
+    assert_eq!(unpack(GgmlType::Q4_0, layout, &attn_q.data, 32, 8).unwrap(), original);
+
+    // mapped tensors are used in place, not copied
+    let token_embd = ml.load_tensor(&placement[0]).unwrap();
+    assert!(token_embd.layout.is_none());
+    assert!(matches!(token_embd.data, LlamaTensorBuffer::Mapped(..)));
+    assert_eq!(&token_embd.data[..], weights(GgmlType::Q8_0, 32, 8));
+    assert_eq!(token_embd.data.as_ptr(), ml.load_tensor(&placement[0]).unwrap().data.as_ptr());
+}
+
+#[test]
+fn test_repack_skipped_reasons() {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama");
+    w.add_tensor("blk.0.attn_q.weight", &[32, 8], GgmlType::F16, weights(GgmlType::F16, 32, 8)).unwrap();
+    w.add_tensor("blk.0.attn_k.weight", &[32, 6], GgmlType::Q8_0, weights(GgmlType::Q8_0, 32, 6)).unwrap();
+    w.add_tensor("blk.0.attn_v.weight", &[32, 8], GgmlType::Q4_0, weights(GgmlType::Q4_0, 32, 8)).unwrap();
+    w.add_tensor_f32("blk.0.attn_norm.weight", &[32], &[1.0; 32]).unwrap();
+    let path = std::env::temp_dir().join(format!("llmrust_repack_skipped_{}.gguf", std::process::id()));
+    w.write(&path).unwrap();
+    let ml = LlamaModelLoader::new(GgufFile::read_mmap(&path).unwrap(), Vec::new());
+    std::fs::remove_file(&path).unwrap();
+
+    // vectors are never repacked and are not reported
+    let placement = ml.tensor_placement(true, true, &[]).unwrap();
+    let skipped = ml.repack_skipped(&placement);
+    let names: Vec<_> = skipped.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["blk.0.attn_q.weight", "blk.0.attn_k.weight"]);
+    assert!(skipped[0].1.contains("only q4_0 and q8_0"));
+    assert!(skipped[1].1.contains("groups of 4"));
+    assert_eq!(CpuRepack.unsupported_reason(GgmlType::Q4_0, &[32, 8]), None);
+}
+
+#[test]
+fn test_repack_model_load() {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama")
+        .set("llama.context_length", GgufValue::U32(256))
+        .set("llama.embedding_length", GgufValue::U32(32))
+        .set("llama.block_count", GgufValue::U32(1))
+        .set("llama.feed_forward_length", GgufValue::U32(64))
+        .set("llama.attention.head_count", GgufValue::U32(4));
+    w.add_tensor("token_embd.weight", &[32, 8], GgmlType::Q8_0, weights(GgmlType::Q8_0, 32, 8)).unwrap();
+    w.add_tensor("blk.0.attn_q.weight", &[32, 8], GgmlType::Q4_0, weights(GgmlType::Q4_0, 32, 8)).unwrap();
+    let path = std::env::temp_dir().join(format!("llmrust_repack_load_{}.gguf", std::process::id()));
+    w.write(&path).unwrap();
+    let c_path = cstr(path.to_str().unwrap());
+
+    // the weights are repacked as the model loads, the embeddings stay mapped
+    let model = llama_model_load_from_file(c_path.as_ptr(), llama_model_default_params());
+    let loaded = unsafe { llama_model::from_handle(model as *mut c_void) }.unwrap();
+    let attn_q = loaded.tensor("blk.0.attn_q.weight").unwrap();
+    let layout = attn_q.layout.unwrap();
+    assert_eq!(unpack(GgmlType::Q4_0, layout, &attn_q.data, 32, 8).unwrap(), weights(GgmlType::Q4_0, 32, 8));
+    assert!(matches!(loaded.tensor("token_embd.weight").unwrap().data, LlamaTensorBuffer::Mapped(..)));
+    llama_model_free(model);
+
+    let mut mparams = llama_model_default_params();
+    mparams.use_extra_bufts = false;
+    mparams.use_mmap = false;
+    let model = llama_model_load_from_file(c_path.as_ptr(), mparams);
+    let loaded = unsafe { llama_model::from_handle(model as *mut c_void) }.unwrap();
+    assert!(loaded.tensors.iter().all(|(_, t)| t.layout.is_none() && matches!(t.data, LlamaTensorBuffer::Host(_))));
+    llama_model_free(model);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_repack_bench() {
+    let args: Vec<String> = ["-p", "5", "-r", "1", "--type", "q4_0,q8_0", "--n-embd", "64", "--n-ff", "128", "--n-layer", "1"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let params = llama_bench_parse_args(&args).unwrap();
+    assert_eq!(params.repack, [false, true]);
+    let results = llama_bench(&params).unwrap();
+    assert_eq!(results.len(), 4);
+    for pair in results.chunks(2) {
+        assert!(pair[0].layout.is_none() && pair[1].layout.is_some());
+        assert!((pair[0].checksum - pair[1].checksum).abs() <= 1e-3 * pair[0].checksum.abs().max(1.0));
+        assert!(pair.iter().all(|r| r.avg_ts() > 0.0));
+    }
+    let table = llama_bench_markdown(&params, &results);
+    assert_eq!(table.lines().count(), 6);
+    assert!(table.contains("| q8_0 | file | 64 | 128 | 1 | pp5 |"));
+
+    assert!(llama_bench_parse_args(&["--type".to_string(), "f32".to_string()]).is_err());
+    assert!(llama_bench_parse_args(&["--n-embd".to_string(), "48".to_string()]).is_err());
+    assert!(llama_bench_parse_args(&["-p".to_string()]).is_err());
+}
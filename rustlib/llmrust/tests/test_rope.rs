@@ -1,6 +1,245 @@
-// tests/test_rope.rs - Auto-generated debug file
-#![allow(dead_code)]
+// tests/test_rope.rs - RoPE and RoPE scaling tests
 
-pub fn debug_print() {
-    println!("DEBUG: tests/test_rope.rs - File loaded successfully");
+use crate::llmrust::ggml::src::ggml_cpu::ops::{rope_ext, rope_f32, RopeMode, RopeParams};
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufValue, GgufWriter};
+use crate::llmrust::src::llama_cparams::LlamaCparams;
+use crate::llmrust::src::llama_hparams::{LlamaHparams, LlamaRopeScalingType};
+use crate::llmrust::src::llama_model::llama_model_load_hparams;
+use crate::llmrust::src::llama_model_loader::LlamaModelLoader;
+
+fn head(n: usize) -> Vec<f32> {
+    (0..n).map(|i| ((i as f32) * 0.37 + 1.0).sin()).collect()
+}
+
+fn assert_close(a: &[f32], b: &[f32]) {
+    for (x, y) in a.iter().zip(b) {
+        assert!((x - y).abs() < 1e-4, "{:?} vs {:?}", a, b);
+    }
+}
+
+fn yarn(n_dims: usize, freq_scale: f32) -> RopeParams {
+    RopeParams { ext_factor: 1.0, n_ctx_orig: 4096, ..RopeParams::new(RopeMode::Neox, n_dims, 10000.0, freq_scale) }
+}
+
+/// A 2-layer model with the given RoPE metadata and factor tensors
+fn loader(kv: &[(&str, GgufValue)], factors: &[(&str, Vec<f32>)]) -> LlamaModelLoader {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama")
+        .set("llama.context_length", GgufValue::U32(16384))
+        .set("llama.embedding_length", GgufValue::U32(64))
+        .set("llama.block_count", GgufValue::U32(2))
+        .set("llama.feed_forward_length", GgufValue::U32(128))
+        .set("llama.attention.head_count", GgufValue::U32(4));
+    for (key, value) in kv {
+        w.set(key, value.clone());
+    }
+    for (name, values) in factors {
+        w.add_tensor_f32(name, &[values.len()], values).unwrap();
+    }
+    LlamaModelLoader::new(GgufFile::from_bytes(&w.to_bytes()).unwrap(), Vec::new())
+}
+
+fn hparams(kv: &[(&str, GgufValue)], factors: &[(&str, Vec<f32>)]) -> Result<LlamaHparams, String> {
+    llama_model_load_hparams(&loader(kv, factors), "llama")
+}
+
+#[test]
+fn test_rope_modes_and_linear_scaling() {
+    let x = head(16);
+    for mode in [RopeMode::Normal, RopeMode::Neox] {
+        let mut a = x.clone();
+        rope_f32(&mut a, 16, 7.0, 10000.0, 1.0, mode);
+        let mut b = x.clone();
+        rope_ext(&mut b, 7.0, &RopeParams::new(mode, 16, 10000.0, 1.0));
+        assert_eq!(a, b);
+        // rotations keep the norm and compose additively
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>();
+        assert!((norm(&a) - norm(&x)).abs() < 1e-4);
+        rope_f32(&mut b, 16, 5.0, 10000.0, 1.0, mode);
+        let mut c = x.clone();
+        rope_f32(&mut c, 16, 12.0, 10000.0, 1.0, mode);
+        assert_close(&b, &c);
+
+        // linear scaling by 4: position 20 rotates like position 5
+        let mut scaled = x.clone();
+        rope_f32(&mut scaled, 16, 20.0, 10000.0, 0.25, mode);
+        let mut plain = x.clone();
+        rope_f32(&mut plain, 16, 5.0, 10000.0, 1.0, mode);
+        assert_close(&scaled, &plain);
+    }
+
+    // normal mode pairs adjacent values, NeoX the two halves; with n_dims
+    // smaller than the head the rest is left alone
+    let mut normal = vec![1.0, 0.0, 1.0, 0.0, 9.0];
+    rope_f32(&mut normal, 4, 1.0, 10000.0, 1.0, RopeMode::Normal);
+    assert_close(&normal[..2], &[1f32.cos(), 1f32.sin()]);
+    assert_eq!(normal[4], 9.0);
+    let mut neox = vec![1.0, 1.0, 0.0, 0.0];
+    rope_f32(&mut neox, 4, 1.0, 10000.0, 1.0, RopeMode::Neox);
+    assert_close(&[neox[0], neox[2]], &[1f32.cos(), 1f32.sin()]);
+}
+
+#[test]
+fn test_rope_yarn() {
+    let params = yarn(128, 0.25);
+    assert_eq!(params.yarn_corr_dims(), [20.0, 46.0]);
+    let mscale = 1.0 + 0.1 * 4f32.ln();
+    assert!((params.mscale() - mscale).abs() < 1e-6);
+
+    // high frequencies are extrapolated, low frequencies interpolated
+    let cache = params.cache(1000.0);
+    let extrap = RopeParams::new(RopeMode::Neox, 128, 10000.0, 1.0).cache(1000.0);
+    let interp = RopeParams::new(RopeMode::Neox, 128, 10000.0, 0.25).cache(1000.0);
+    for i in [0, 10, 19] {
+        assert!((cache[i].0 - extrap[i].0 * mscale).abs() < 1e-4 && (cache[i].1 - extrap[i].1 * mscale).abs() < 1e-4);
+    }
+    for i in [47, 60] {
+        assert!((cache[i].0 - interp[i].0 * mscale).abs() < 1e-4 && (cache[i].1 - interp[i].1 * mscale).abs() < 1e-4);
+    }
+    // without the extrapolation mix, YaRN is linear scaling times attn_factor
+    let linear = RopeParams { ext_factor: 0.0, attn_factor: 2.0, ..yarn(128, 0.25) }.cache(1000.0);
+    for (a, b) in linear.iter().zip(&interp) {
+        assert!((a.0 - 2.0 * b.0).abs() < 1e-4 && (a.1 - 2.0 * b.1).abs() < 1e-4);
+    }
+
+    // shifting keys rotated with YaRN keeps their magnitude
+    let x = head(128);
+    let mut shifted = x.clone();
+    rope_ext(&mut shifted, 100.0, &params);
+    rope_ext(&mut shifted, 37.0, &params.shift());
+    let mut direct = x.clone();
+    rope_ext(&mut direct, 137.0, &params);
+    assert_close(&shifted, &direct);
+}
+
+#[test]
+fn test_rope_longrope_factors() {
+    let mut factors = vec![1.0; 8];
+    factors[3] = 2.0;
+    let params = RopeParams { freq_factors: Some(factors), ..RopeParams::new(RopeMode::Neox, 16, 10000.0, 1.0) };
+    let plain = RopeParams::new(RopeMode::Neox, 16, 10000.0, 1.0);
+    let (scaled, at_half) = (params.cache(40.0), plain.cache(20.0));
+    assert!((scaled[3].0 - at_half[3].0).abs() < 1e-5 && (scaled[3].1 - at_half[3].1).abs() < 1e-5);
+    assert_eq!(scaled[2], plain.cache(40.0)[2]);
+}
+
+#[test]
+fn test_rope_scaling_from_gguf_and_cparams() {
+    let hp = hparams(
+        &[
+            ("llama.rope.scaling.type", GgufValue::String("yarn".to_string())),
+            ("llama.rope.scaling.factor", GgufValue::F32(4.0)),
+            ("llama.rope.scaling.original_context_length", GgufValue::U32(4096)),
+        ],
+        &[],
+    )
+    .unwrap();
+    assert_eq!((hp.rope_scaling_type_train, hp.n_ctx_orig_yarn, hp.rope_freq_scale_train), (LlamaRopeScalingType::Yarn, 4096, 0.25));
+
+    // the model's scaling by default, YaRN mixing enabled
+    let rope = LlamaCparams::default().rope_params(&hp).unwrap().unwrap();
+    assert_eq!((rope.freq_base, rope.freq_scale, rope.ext_factor, rope.attn_factor), (10000.0, 0.25, 1.0, 1.0));
+    assert_eq!((rope.n_dims, rope.n_ctx_orig, rope.mode), (16, 4096, RopeMode::Normal));
+
+    // context params override the model
+    let cparams = LlamaCparams { rope_freq_base: 500000.0, yarn_orig_ctx: 8192, yarn_attn_factor: 0.5, ..Default::default() };
+    let rope = cparams.rope_params(&hp).unwrap().unwrap();
+    assert_eq!((rope.freq_base, rope.n_ctx_orig, rope.attn_factor), (500000.0, 8192, 0.5));
+    let none = LlamaCparams { rope_scaling_type: LlamaRopeScalingType::None, ..Default::default() };
+    let rope = none.rope_params(&hp).unwrap().unwrap();
+    assert_eq!((rope.freq_scale, rope.ext_factor), (1.0, 0.0));
+    let linear = LlamaCparams { rope_scaling_type: LlamaRopeScalingType::Linear, rope_freq_scale: 0.5, ..Default::default() };
+    let rope = linear.rope_params(&hp).unwrap().unwrap();
+    assert_eq!((rope.freq_scale, rope.ext_factor), (0.5, 0.0));
+    assert!(LlamaCparams { rope_freq_base: -1.0, ..Default::default() }.rope_params(&hp).is_err());
+
+    // defaults without scaling metadata
+    let hp = hparams(&[], &[]).unwrap();
+    assert_eq!((hp.rope_scaling_type_train, hp.n_ctx_orig_yarn), (LlamaRopeScalingType::Linear, 16384));
+    let rope = LlamaCparams::default().rope_params(&hp).unwrap().unwrap();
+    assert_eq!((rope.freq_scale, rope.ext_factor, rope.freq_factors), (1.0, 0.0, None));
+    let err = hparams(&[("llama.rope.scaling.type", GgufValue::String("su".to_string()))], &[]).unwrap_err();
+    assert!(err.contains("unknown rope scaling type"), "{}", err);
+}
+
+#[test]
+fn test_rope_ntk() {
+    let hp = hparams(
+        &[("llama.rope.scaling.type", GgufValue::String("ntk".to_string())), ("llama.rope.scaling.factor", GgufValue::F32(4.0))],
+        &[],
+    )
+    .unwrap();
+    assert_eq!(hp.rope_scaling_type_train, LlamaRopeScalingType::Ntk);
+    let rope = LlamaCparams::default().rope_params(&hp).unwrap().unwrap();
+    // 16 rotated dimensions: base * 4^(16/14)
+    assert!((rope.freq_base / (10000.0 * 4f32.powf(16.0 / 14.0)) - 1.0).abs() < 1e-5);
+    assert_eq!((rope.freq_scale, rope.ext_factor), (1.0, 0.0));
+
+    // the highest frequency is kept, the lowest one is scaled like linear scaling
+    let n_dims = 16;
+    let theta = |base: f32, i: usize| base.powf(-2.0 * i as f32 / n_dims as f32);
+    assert_eq!(theta(rope.freq_base, 0), theta(10000.0, 0));
+    let lowest = n_dims / 2 - 1;
+    assert!((theta(rope.freq_base, lowest) / (theta(10000.0, lowest) * 0.25) - 1.0).abs() < 1e-4);
+
+    // the context can ask for NTK scaling of a model trained with another one
+    let plain = hparams(&[], &[]).unwrap();
+    let cparams = LlamaCparams { rope_scaling_type: LlamaRopeScalingType::Ntk, rope_freq_scale: 0.5, ..Default::default() };
+    let rope = cparams.rope_params(&plain).unwrap().unwrap();
+    assert!((rope.freq_base / (10000.0 * 2f32.powf(16.0 / 14.0)) - 1.0).abs() < 1e-5);
+    assert_eq!(LlamaRopeScalingType::from_raw(4), Some(LlamaRopeScalingType::Ntk));
+}
+
+#[test]
+fn test_rope_dynamic_ntk() {
+    let hp = hparams(
+        &[
+            ("llama.rope.scaling.type", GgufValue::String("dynamic".to_string())),
+            ("llama.rope.scaling.factor", GgufValue::F32(4.0)),
+            ("llama.rope.scaling.original_context_length", GgufValue::U32(4096)),
+        ],
+        &[],
+    )
+    .unwrap();
+    assert_eq!((hp.rope_scaling_type_train, hp.rope_freq_scale_train), (LlamaRopeScalingType::Dynamic, 0.25));
+    let rope = |n_ctx| LlamaCparams { n_ctx, ..Default::default() }.rope_params(&hp).unwrap().unwrap();
+
+    // unscaled within the original context
+    for n_ctx in [2048, 4096] {
+        assert_eq!((rope(n_ctx).freq_base, rope(n_ctx).freq_scale), (10000.0, 1.0));
+    }
+    // beyond it: base * (4 * 8192 / 4096 - 3)^(16/14)
+    let expected = 10000.0 * 5f32.powf(16.0 / 14.0);
+    assert!((rope(8192).freq_base / expected - 1.0).abs() < 1e-5);
+    assert_eq!((rope(8192).freq_scale, rope(8192).ext_factor), (1.0, 0.0));
+    // and the base grows with the context
+    assert!(rope(16384).freq_base > rope(8192).freq_base);
+
+    assert_eq!(LlamaRopeScalingType::from_raw(5), Some(LlamaRopeScalingType::Dynamic));
+    assert_eq!(LlamaRopeScalingType::Dynamic.name(), "dynamic");
+}
+
+#[test]
+fn test_rope_longrope_model() {
+    let longrope = [
+        ("llama.rope.scaling.type", GgufValue::String("longrope".to_string())),
+        ("llama.rope.scaling.original_context_length", GgufValue::U32(4096)),
+    ];
+    let (short, long) = (vec![1.0; 8], vec![2.0; 8]);
+    let hp = hparams(&longrope, &[("rope_factors_short.weight", short.clone()), ("rope_factors_long.weight", long.clone())]).unwrap();
+    // sqrt(1 + ln(16384 / 4096) / ln(4096))
+    assert!((hp.rope_attn_factor - (1.0 + 4f32.ln() / 4096f32.ln()).sqrt()).abs() < 1e-6);
+
+    let rope = |n_ctx| LlamaCparams { n_ctx, ..Default::default() }.rope_params(&hp).unwrap().unwrap();
+    assert_eq!(rope(4096).freq_factors, Some(short));
+    assert_eq!(rope(16384).freq_factors, Some(long));
+    assert_eq!(rope(16384).ext_factor, 0.0);
+
+    // the factors are required, one per rotated pair
+    assert!(hparams(&longrope, &[("rope_factors_short.weight", vec![1.0; 8])]).unwrap_err().contains("rope_factors_long"));
+    let err = hparams(&longrope, &[("rope_factors_short.weight", vec![1.0; 4]), ("rope_factors_long.weight", vec![1.0; 8])]);
+    assert!(err.unwrap_err().contains("8 f32 values"));
+    let plain = hparams(&[], &[]).unwrap();
+    let cparams = LlamaCparams { rope_scaling_type: LlamaRopeScalingType::LongRope, ..Default::default() };
+    assert!(cparams.rope_params(&plain).is_err());
 }
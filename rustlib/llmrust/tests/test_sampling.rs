@@ -1,6 +1,328 @@
-// tests/test_sampling.rs - Auto-generated debug file
-#![allow(dead_code)]
+// tests/test_sampling.rs - Sampler chain and grammar sampler tests
 
-pub fn debug_print() {
-    println!("DEBUG: tests/test_sampling.rs - File loaded successfully");
+use std::sync::Arc;
+
+use crate::llmrust::src::llama_sampling::{
+    BannedStringsSampler, GrammarSampler, GreedySampler, LlamaSampler, LlamaTokenDataArray, LogitBiasSampler,
+    RegexSampler, SamplerChain,
+};
+use crate::llmrust::common::chat::{parse_grammar_triggers, ChatFormat};
+use crate::llmrust::common::sampling::{
+    common_sampler_init, parse_logit_bias, resolve_logit_bias, CommonParamsSampling, LogitBiasEntry, LogitBiasKey,
+};
+use crate::llmrust::src::llama_grammar::{GrammarTrigger, LlamaGrammar};
+use crate::llmrust::src::llama_vocab::{LlamaVocab, LlamaVocabType};
+
+fn vocab() -> Arc<LlamaVocab> {
+    let mut vocab = LlamaVocab::from_pieces(&["</s>", "{", "}", "\"a\"", ":", "1", "x", "\u{2581}", "\u{00e9}"]);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    Arc::new(vocab)
+}
+
+#[test]
+fn test_greedy_chain() {
+    let mut chain = SamplerChain::new();
+    chain.add(Box::new(GreedySampler));
+    assert_eq!(chain.sample(&[0.1, 2.0, -1.0]), 1);
+}
+
+#[test]
+fn test_grammar_sampler_masks_tokens() {
+    let vocab = vocab();
+    let mut sampler = GrammarSampler::new(vocab.clone(), r#"root ::= "{" "\"a\"" ":" [0-9] "}""#, "root").unwrap();
+
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 9]);
+    sampler.apply(&mut cur_p);
+    let allowed: Vec<i32> = cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect();
+    assert_eq!(allowed, vec![1]);
+
+    for token in [1, 3, 4, 5] {
+        sampler.accept(token);
+    }
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 9]);
+    sampler.apply(&mut cur_p);
+    let allowed: Vec<i32> = cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect();
+    assert_eq!(allowed, vec![2]);
+
+    // End of generation only once the grammar is complete
+    sampler.accept(2);
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 9]);
+    sampler.apply(&mut cur_p);
+    let allowed: Vec<i32> = cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect();
+    assert_eq!(allowed, vec![0]);
+}
+
+#[test]
+fn test_grammar_sampler_in_chain() {
+    let vocab = vocab();
+    let mut chain = SamplerChain::new();
+    chain.add(Box::new(GrammarSampler::new(vocab, r#"root ::= "x" | " " "1""#, "root").unwrap()));
+    chain.add(Box::new(GreedySampler));
+    // "1" has the highest logit but only "x" and " " can start the grammar
+    let logits = [0.0, 0.0, 0.0, 0.0, 0.0, 9.0, 1.0, 2.0, 0.0];
+    assert_eq!(chain.sample(&logits), 7);
+}
+
+#[test]
+fn test_grammar_partial_utf8_pieces() {
+    let mut grammar = LlamaGrammar::parse(r#"root ::= "é""#, "root").unwrap();
+    // "é" split across two byte tokens
+    assert!(grammar.accept_bytes(&[0xC3]).is_ok());
+    assert!(!grammar.is_accepting());
+    assert!(grammar.accept_bytes(&[0xA9]).is_ok());
+    assert!(grammar.is_accepting());
+
+    // A lead byte is only allowed when the grammar can complete its sequence
+    let tokens = ["<0xC3>", "<0xE3>", "a"].iter().map(|t| t.to_string()).collect();
+    let vocab = Arc::new(LlamaVocab::new(LlamaVocabType::Spm, tokens, Vec::new(), vec![6, 6, 1]));
+    let mut sampler = GrammarSampler::new(vocab, r#"root ::= "é""#, "root").unwrap();
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 3]);
+    sampler.apply(&mut cur_p);
+    let allowed: Vec<i32> = cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect();
+    assert_eq!(allowed, vec![0]);
+}
+
+fn allowed_tokens(sampler: &mut GrammarSampler, n_vocab: usize) -> Vec<i32> {
+    let mut cur_p = LlamaTokenDataArray::from_logits(&vec![0.0; n_vocab]);
+    sampler.apply(&mut cur_p);
+    cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect()
+}
+
+#[test]
+fn test_lazy_grammar_word_trigger() {
+    // The trigger word is split across tokens and preceded by free text
+    let pieces = ["</s>", "Sure", "<tool", "_call>", "{", "}", "x"];
+    let mut vocab = LlamaVocab::from_pieces(&pieces);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    let triggers = ChatFormat::Hermes2Pro.grammar_triggers(Some(&vocab));
+    assert_eq!(triggers, vec![GrammarTrigger::Word("<tool_call>".to_string())]);
+
+    let grammar = r#"root ::= "<tool_call>" "{" "}""#;
+    let mut sampler = GrammarSampler::new_lazy(Arc::new(vocab), grammar, "root", triggers).unwrap();
+
+    // Unconstrained before the trigger
+    assert_eq!(allowed_tokens(&mut sampler, pieces.len()).len(), pieces.len());
+    sampler.accept(1);
+    sampler.accept(2);
+    assert!(sampler.grammar().unwrap().is_awaiting_trigger());
+    sampler.accept(3);
+    assert!(!sampler.grammar().unwrap().is_awaiting_trigger());
+
+    // Enforced from the trigger onwards
+    assert_eq!(allowed_tokens(&mut sampler, pieces.len()), vec![4]);
+    sampler.accept(4);
+    sampler.accept(5);
+    assert_eq!(allowed_tokens(&mut sampler, pieces.len()), vec![0]);
+
+    // Reset returns to free generation
+    sampler.reset();
+    assert!(sampler.grammar().unwrap().is_awaiting_trigger());
+}
+
+#[test]
+fn test_lazy_grammar_token_trigger() {
+    let tokens = ["</s>", "<|python_tag|>", "a", "b"].iter().map(|t| t.to_string()).collect();
+    let mut vocab = LlamaVocab::new(LlamaVocabType::Bpe, tokens, Vec::new(), vec![3, 3, 1, 1]);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    let triggers = ChatFormat::Llama3x.grammar_triggers(Some(&vocab));
+    assert_eq!(triggers, vec![GrammarTrigger::Word("{\"name\"".to_string()), GrammarTrigger::Token(1)]);
+
+    let mut sampler = GrammarSampler::new_lazy(Arc::new(vocab), r#"root ::= "b"+"#, "root", triggers).unwrap();
+    sampler.accept(2);
+    assert_eq!(allowed_tokens(&mut sampler, 4).len(), 4);
+    sampler.accept(1);
+    assert_eq!(allowed_tokens(&mut sampler, 4), vec![3]);
+}
+
+#[test]
+fn test_lazy_grammar_pattern_trigger() {
+    let pieces = ["</s>", "Sure", "\n", "<fn=", "get", ">", "{", "}", "x", "<fn=>"];
+    let mut vocab = LlamaVocab::from_pieces(&pieces);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    let vocab = Arc::new(vocab);
+    let grammar = r#"root ::= "<fn=" [a-z]+ ">" "{" "}""#;
+
+    // A match on a later line; the grammar starts where it starts
+    let triggers = parse_grammar_triggers(&serde_json::json!([{"type": "pattern", "value": "<fn=[a-z]+>"}])).unwrap();
+    let mut sampler = GrammarSampler::new_lazy(Arc::clone(&vocab), grammar, "root", triggers).unwrap();
+    for token in [1, 2, 9, 3, 4] {
+        sampler.accept(token);
+        assert!(sampler.grammar().unwrap().is_awaiting_trigger());
+    }
+    assert_eq!(allowed_tokens(&mut sampler, pieces.len()).len(), pieces.len());
+    sampler.accept(5);
+    assert!(!sampler.grammar().unwrap().is_awaiting_trigger());
+    assert_eq!(allowed_tokens(&mut sampler, pieces.len()), vec![6]);
+
+    // A full pattern must match the whole output, which the grammar then covers
+    let triggers = parse_grammar_triggers(&serde_json::json!([{"type": "pattern_full", "value": "<fn=[a-z]+>"}])).unwrap();
+    let mut sampler = GrammarSampler::new_lazy(Arc::clone(&vocab), grammar, "root", triggers.clone()).unwrap();
+    for token in [3, 4, 5] {
+        sampler.accept(token);
+    }
+    assert_eq!(allowed_tokens(&mut sampler, pieces.len()), vec![6]);
+    let mut sampler = GrammarSampler::new_lazy(vocab, grammar, "root", triggers).unwrap();
+    for token in [8, 3, 4, 5] {
+        sampler.accept(token);
+    }
+    assert!(sampler.grammar().unwrap().is_awaiting_trigger());
+}
+
+#[test]
+fn test_chat_format_triggers() {
+    assert_eq!(ChatFormat::from_template("mistral-nemo"), ChatFormat::MistralNemo);
+    assert_eq!(ChatFormat::from_template("{% if tools %}<tool_call>{% endif %}"), ChatFormat::Hermes2Pro);
+    assert_eq!(ChatFormat::from_template("{{ messages }}"), ChatFormat::ContentOnly);
+    assert!(ChatFormat::ContentOnly.grammar_triggers(None).is_empty());
+
+    let triggers = parse_grammar_triggers(&serde_json::json!([
+        {"type": "word", "value": "[TOOL_CALLS]"},
+        {"type": "token", "value": 7}
+    ]))
+    .unwrap();
+    assert_eq!(triggers, vec![GrammarTrigger::Word("[TOOL_CALLS]".to_string()), GrammarTrigger::Token(7)]);
+    assert!(parse_grammar_triggers(&serde_json::json!([{"type": "token", "value": "x"}])).is_err());
+    let triggers = parse_grammar_triggers(&serde_json::json!([{"type": "pattern_full", "value": "<tool_call>.*"}])).unwrap();
+    assert!(matches!(&triggers[..], [GrammarTrigger::Pattern(p)] if p.is_full() && p.pattern() == "<tool_call>.*"));
+    assert!(parse_grammar_triggers(&serde_json::json!([{"type": "pattern", "value": "(a"}])).is_err());
+
+    let grammar = LlamaGrammar::parse(r#"root ::= "a""#, "root").unwrap();
+    assert!(grammar.into_lazy(Vec::new()).is_err());
+}
+
+fn word_vocab() -> Arc<LlamaVocab> {
+    let mut vocab = LlamaVocab::from_pieces(&["</s>", "H", "e", "l", "o", "He", "ll", "llo", "Hello", "!", "xH"]);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    Arc::new(vocab)
+}
+
+#[test]
+fn test_tokenize_all() {
+    let vocab = word_vocab();
+    assert_eq!(vocab.tokenize("Hello!"), vec![8, 9]);
+    let all = vocab.tokenize_all("Hello", 64);
+    assert_eq!(all[0], vec![8]);
+    assert!(all.contains(&vec![5, 7]));
+    assert!(all.contains(&vec![1, 2, 3, 3, 4]));
+    assert!(all.iter().all(|seq| vocab.detokenize(seq) == "Hello"));
+    assert!(vocab.tokenize_all("Help", 64).is_empty());
+    assert_eq!(vocab.tokenize_all("Hello", 2).len(), 2);
+}
+
+#[test]
+fn test_logit_bias() {
+    let vocab = word_vocab();
+    let entries = parse_logit_bias(&serde_json::json!({"9": false, "He": 2.0, "4": -1.5})).unwrap();
+    assert_eq!(entries[0], LogitBiasEntry { key: LogitBiasKey::Token(9), bias: f32::NEG_INFINITY });
+    assert_eq!(entries[1].key, LogitBiasKey::Text("He".to_string()));
+
+    let biases = resolve_logit_bias(&vocab, &entries).unwrap();
+    let mut sampler = LogitBiasSampler::new(&biases);
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 11]);
+    sampler.apply(&mut cur_p);
+    assert_eq!(cur_p.data[9].logit, f32::NEG_INFINITY);
+    assert_eq!(cur_p.data[5].logit, 2.0);
+    assert_eq!(cur_p.data[4].logit, -1.5);
+    assert_eq!(cur_p.data[1].logit, 0.0);
+
+    assert!(resolve_logit_bias(&vocab, &parse_logit_bias(&serde_json::json!([[99, 1.0]])).unwrap()).is_err());
+    assert!(parse_logit_bias(&serde_json::json!({"1": true})).is_err());
+}
+
+#[test]
+fn test_banned_strings_masks_sequences() {
+    let mut sampler = BannedStringsSampler::new(word_vocab(), &["Hello".to_string()]).unwrap();
+    let masked = |sampler: &mut BannedStringsSampler| {
+        let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 11]);
+        sampler.apply(&mut cur_p);
+        cur_p.data.iter().filter(|d| !d.logit.is_finite()).map(|d| d.id).collect::<Vec<_>>()
+    };
+
+    // The single-token spelling is always banned
+    assert_eq!(masked(&mut sampler), vec![8]);
+    sampler.accept(5);
+    assert_eq!(masked(&mut sampler), vec![7, 8]);
+    sampler.accept(6);
+    assert_eq!(masked(&mut sampler), vec![4, 8]);
+}
+
+#[test]
+fn test_banned_strings_backtracking() {
+    let mut sampler = BannedStringsSampler::new(word_vocab(), &["Hello".to_string()]).unwrap();
+    // "xH" + "e" + "llo" spells the string across a token that starts before it
+    sampler.accept(9);
+    sampler.accept(10);
+    sampler.accept(2);
+    assert_eq!(sampler.take_rollback(), 0);
+    sampler.accept(7);
+    assert_eq!(sampler.take_rollback(), 3);
+    assert_eq!(sampler.take_rollback(), 0);
+
+    // Back at position 1, where "xH" is now banned
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 11]);
+    sampler.apply(&mut cur_p);
+    assert_eq!(cur_p.data[10].logit, f32::NEG_INFINITY);
+    assert_eq!(cur_p.data[1].logit, 0.0);
+
+    sampler.reset();
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 11]);
+    sampler.apply(&mut cur_p);
+    assert_eq!(cur_p.data[10].logit, 0.0);
+}
+
+#[test]
+fn test_banned_strings_rollback_through_chain() {
+    let mut chain = SamplerChain::new();
+    chain.add(Box::new(BannedStringsSampler::new(word_vocab(), &["Hello".to_string()]).unwrap()));
+    chain.add(Box::new(GreedySampler));
+    for token in [9, 10, 2] {
+        chain.accept(token);
+        assert_eq!(chain.take_rollback(), 0);
+    }
+    chain.accept(7);
+    assert_eq!(chain.take_rollback(), 3);
+    assert_eq!(chain.take_rollback(), 0);
+}
+
+#[test]
+fn test_banned_strings_rollback_rewinds_constraints() {
+    let vocab = word_vocab();
+    let mut chain = SamplerChain::new();
+    chain.add(Box::new(BannedStringsSampler::new(vocab.clone(), &["Hello".to_string()]).unwrap()));
+    chain.add(Box::new(RegexSampler::new(vocab, "!(xHello|He)").unwrap()));
+    for token in [9, 10, 2, 7] {
+        chain.accept(token);
+    }
+    assert_eq!(chain.take_rollback(), 3);
+
+    // The regex is back after "!": "xH" is banned there, "He" and "H" remain
+    let mut cur_p = LlamaTokenDataArray::from_logits(&[0.0; 11]);
+    chain.apply(&mut cur_p);
+    let allowed: Vec<_> = cur_p.data.iter().filter(|d| d.logit.is_finite()).map(|d| d.id).collect();
+    assert_eq!(allowed, vec![1, 5]);
+    chain.accept(5);
+    assert_eq!(chain.take_rollback(), 0);
+}
+
+#[test]
+fn test_common_sampler_chain() {
+    let vocab = word_vocab();
+    let params = CommonParamsSampling {
+        logit_bias: vec![LogitBiasEntry { key: LogitBiasKey::Token(3), bias: 1.0 }],
+        banned_strings: vec!["Hello".to_string()],
+        regex: "[Hel]+".to_string(),
+        ..Default::default()
+    };
+    let chain = common_sampler_init(vocab.clone(), &params).unwrap();
+    assert_eq!(chain.names(), vec!["logit-bias", "banned-strings", "regex", "top-k", "top-p", "min-p", "temp", "dist"]);
+
+    let params = CommonParamsSampling { temp: 0.0, banned_strings: vec!["Hello".to_string()], ..Default::default() };
+    let mut chain = common_sampler_init(vocab.clone(), &params).unwrap();
+    let mut logits = [0.0f32; 11];
+    logits[8] = 10.0;
+    logits[5] = 5.0;
+    assert_eq!(chain.sample(&logits), 5);
+
+    let params = CommonParamsSampling { grammar: "root ::= \"a\"".to_string(), regex: "a".to_string(), ..Default::default() };
+    assert!(common_sampler_init(vocab, &params).is_err());
 }
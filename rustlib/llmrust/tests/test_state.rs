@@ -0,0 +1,375 @@
+// tests/test_state.rs - Context and sequence state save/restore tests
+
+use std::sync::Arc;
+
+use crate::common::log::{
+    cstr, llama_get_memory, llama_memory_seq_pos_max, llama_memory_seq_pos_min, llama_state_get_data, llama_state_get_size,
+    llama_state_load_file, llama_state_save_file, llama_state_seq_get_data, llama_state_seq_get_size, llama_state_seq_set_data,
+    llama_state_set_data,
+};
+use crate::common::model::{
+    llama_context_default_params, llama_free, llama_init_from_model, llama_model_default_params, llama_model_free, llama_model_load_from_file,
+    mock_model_hparams,
+};
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::src::llama_context::LlamaContext;
+use crate::llmrust::src::llama_hparams::LlamaHparams;
+use crate::llmrust::src::llama_io::{LlamaIoReadBuffer, LlamaIoWrite, LlamaIoWriteBuffer, LlamaIoWriteDummy};
+use crate::llmrust::src::llama_kv_cache::{LlamaKvCache, LlamaKvCachePaged, LlamaKvUbatchToken};
+use crate::llmrust::src::llama_memory::{self, LlamaMemory, LlamaSeqId};
+use crate::llmrust::src::llama_sampling::{BannedStringsSampler, DistSampler, GrammarSampler, LlamaSampler, RegexSampler, SamplerChain};
+use crate::llmrust::src::llama_vocab::LlamaVocab;
+
+fn hparams() -> LlamaHparams {
+    LlamaHparams {
+        n_layer: 2,
+        n_head: 4,
+        n_head_kv: 2,
+        n_embd_head_k: 8,
+        n_embd_head_v: 8,
+        n_rot: 8,
+        ..Default::default()
+    }
+}
+
+fn row(pos: i32, il: usize, seq_id: LlamaSeqId) -> Vec<f32> {
+    (0..16).map(|i| pos as f32 + i as f32 * 0.25 + il as f32 + seq_id as f32 * 10.0).collect()
+}
+
+/// Cell cache with seq 0 at positions 0..6 and seq 1 sharing its first 3 cells,
+/// then 2 cells of its own
+fn cell_kv() -> LlamaKvCache {
+    let mut kv = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F16, GgmlType::F16).unwrap();
+    let mut tokens: Vec<_> = (0..6).map(|pos| LlamaKvUbatchToken { pos, seq_ids: vec![0] }).collect();
+    tokens.extend((3..5).map(|pos| LlamaKvUbatchToken { pos, seq_ids: vec![1] }));
+    let cells = kv.apply_ubatch(&tokens).unwrap();
+    for (&cell, token) in cells.iter().zip(&tokens) {
+        for il in 0..2 {
+            kv.cpy_k(il, cell, &row(token.pos, il, token.seq_ids[0]));
+            kv.cpy_v(il, cell, &row(-token.pos, il, token.seq_ids[0]));
+        }
+    }
+    kv.seq_cp(0, 1, 0, 3);
+    kv
+}
+
+/// Serializes `memory` (one sequence, or all with -1) into an exactly sized buffer.
+fn write_state(memory: &dyn LlamaMemory, seq_id: LlamaSeqId) -> Vec<u8> {
+    let mut dummy = LlamaIoWriteDummy::new();
+    memory.state_write(&mut dummy, seq_id).unwrap();
+    let mut buf = vec![0; dummy.n_bytes()];
+    let mut io = LlamaIoWriteBuffer::new(&mut buf);
+    memory.state_write(&mut io, seq_id).unwrap();
+    assert_eq!(io.n_bytes(), buf.len());
+    buf
+}
+
+/// Rows of `seq_id` by position
+fn seq_rows(kv: &LlamaKvCache, seq_id: LlamaSeqId) -> Vec<(i32, Vec<f32>, Vec<f32>)> {
+    let mut rows: Vec<_> = (0..kv.size())
+        .filter(|&cell| kv.cell(cell).has_seq_id(seq_id))
+        .map(|cell| (kv.cell(cell).pos, kv.get_k_f32(1, cell), kv.get_v_f32(1, cell)))
+        .collect();
+    rows.sort_by_key(|r| r.0);
+    rows
+}
+
+#[test]
+fn test_state_kv_cells_roundtrip() {
+    let kv = cell_kv();
+
+    // the whole cache, with shared cells
+    let buf = write_state(&kv, -1);
+    let mut restored = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F16, GgmlType::F16).unwrap();
+    restored.apply_ubatch(&[LlamaKvUbatchToken { pos: 0, seq_ids: vec![1] }]).unwrap();
+    restored.state_read(&mut LlamaIoReadBuffer::new(&buf), -1).unwrap();
+    assert_eq!(restored.n_used(), kv.n_used());
+    for seq_id in 0..2 {
+        assert_eq!(seq_rows(&restored, seq_id), seq_rows(&kv, seq_id));
+    }
+
+    // one sequence, restored into another
+    let buf = write_state(&kv, 1);
+    let mut restored = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F16, GgmlType::F16).unwrap();
+    restored.state_read(&mut LlamaIoReadBuffer::new(&buf), 0).unwrap();
+    assert_eq!(restored.n_used(), 5);
+    assert_eq!(seq_rows(&restored, 0), seq_rows(&kv, 1));
+
+    // truncated state leaves the sequence empty
+    let e = restored.state_read(&mut LlamaIoReadBuffer::new(&buf[..buf.len() - 1]), 0).unwrap_err();
+    assert!(e.contains("end of state buffer"), "{}", e);
+    assert_eq!(restored.n_used(), 0);
+}
+
+#[test]
+fn test_state_kv_paged_roundtrip() {
+    let mut kv = LlamaKvCachePaged::new(&hparams(), 8, 4, GgmlType::F32, GgmlType::F16).unwrap();
+    for (seq_id, n) in [(0, 6), (3, 9)] {
+        for pos in 0..n {
+            let slot = kv.append(seq_id, pos).unwrap();
+            kv.cpy_k(0, slot, &row(pos, 0, seq_id));
+            kv.cpy_v(0, slot, &row(-pos, 0, seq_id));
+        }
+    }
+
+    let buf = write_state(&kv, -1);
+    let mut restored = LlamaKvCachePaged::new(&hparams(), 8, 4, GgmlType::F32, GgmlType::F16).unwrap();
+    restored.state_read(&mut LlamaIoReadBuffer::new(&buf), -1).unwrap();
+    for seq_id in [0, 3] {
+        assert_eq!(restored.seq_n_tokens(seq_id), kv.seq_n_tokens(seq_id));
+        assert_eq!(restored.seq_pos_max(seq_id), kv.seq_pos_max(seq_id));
+    }
+    assert_eq!(restored.n_free_blocks(), kv.n_free_blocks());
+    assert_eq!(write_state(&restored, -1), buf);
+
+    // not enough blocks for the state
+    let buf = write_state(&kv, 3);
+    let mut small = LlamaKvCachePaged::new(&hparams(), 2, 4, GgmlType::F32, GgmlType::F16).unwrap();
+    assert!(small.state_read(&mut LlamaIoReadBuffer::new(&buf), 0).is_err());
+    assert_eq!(small.seq_n_tokens(0), 0);
+    assert_eq!(small.n_free_blocks(), 2);
+}
+
+fn vocab() -> Arc<LlamaVocab> {
+    let mut vocab = LlamaVocab::from_pieces(&["</s>", "a", "b", "c", "ab"]);
+    vocab.set_special_tokens(-1, 0, -1, -1);
+    Arc::new(vocab)
+}
+
+fn chain(seed: u32) -> SamplerChain {
+    let mut chain = SamplerChain::new();
+    chain.add(Box::new(GrammarSampler::new(vocab(), r#"root ::= ("a" | "b")+ "c""#, "root").unwrap()));
+    chain.add(Box::new(DistSampler::new(seed)));
+    chain
+}
+
+fn generate(ctx: &mut LlamaContext, seq_id: LlamaSeqId, n: usize) -> Vec<i32> {
+    let chain = ctx.sampler_mut(seq_id).unwrap();
+    (0..n)
+        .map(|_| {
+            let token = chain.sample(&[0.0, 1.0, 1.0, 0.5, 1.0]);
+            chain.accept(token);
+            token
+        })
+        .collect()
+}
+
+#[test]
+fn test_state_sampler_roundtrip() {
+    let fingerprint = hparams().fingerprint();
+    let mut ctx = LlamaContext::new(fingerprint, 16, Some(Box::new(cell_kv())));
+    ctx.set_sampler(0, chain(42));
+    generate(&mut ctx, 0, 3);
+
+    let mut buf = vec![0; ctx.state_get_size().unwrap()];
+    assert_eq!(ctx.state_get_data(&mut buf), Ok(buf.len()));
+    let expected = generate(&mut ctx, 0, 8);
+
+    // a fresh context continues with the same tokens
+    let memory = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F16, GgmlType::F16).unwrap();
+    let mut restored = LlamaContext::new(fingerprint, 16, Some(Box::new(memory)));
+    restored.set_sampler(0, chain(7));
+    assert_eq!(restored.state_set_data(&buf), Ok(buf.len()));
+    assert_eq!(restored.memory().unwrap().seq_pos_max(1), 4);
+    assert_eq!(generate(&mut restored, 0, 8), expected);
+
+    // the sampler chain must match
+    let mut other = SamplerChain::new();
+    other.add(Box::new(DistSampler::new(42)));
+    restored.set_sampler(0, other);
+    let e = restored.state_set_data(&buf).unwrap_err();
+    assert!(e.contains("sampler state has 2 samplers"), "{}", e);
+}
+
+/// Samples `n` tokens and the rollback each one asks for, removing the
+/// rolled back tokens from the output like the server does.
+fn generate_with_rollback(ctx: &mut LlamaContext, seq_id: LlamaSeqId, n: usize) -> Vec<(i32, usize)> {
+    let chain = ctx.sampler_mut(seq_id).unwrap();
+    (0..n)
+        .map(|_| {
+            let token = chain.sample(&[0.0, 1.0, 1.0, 0.5, 1.0]);
+            chain.accept(token);
+            (token, chain.take_rollback())
+        })
+        .collect()
+}
+
+fn save_and_continue(make_chain: impl Fn(u32) -> SamplerChain, n_before: usize, n_after: usize) {
+    let fingerprint = hparams().fingerprint();
+    let mut ctx = LlamaContext::new(fingerprint, 16, Some(Box::new(cell_kv())));
+    ctx.set_sampler(0, make_chain(42));
+    let before = generate_with_rollback(&mut ctx, 0, n_before);
+
+    let mut buf = vec![0; ctx.state_get_size().unwrap()];
+    assert_eq!(ctx.state_get_data(&mut buf), Ok(buf.len()));
+    let expected = generate_with_rollback(&mut ctx, 0, n_after);
+
+    let memory = LlamaKvCache::new(&hparams(), 16, 2, GgmlType::F16, GgmlType::F16).unwrap();
+    let mut restored = LlamaContext::new(fingerprint, 16, Some(Box::new(memory)));
+    restored.set_sampler(0, make_chain(7));
+    assert_eq!(restored.state_set_data(&buf), Ok(buf.len()));
+    assert_eq!(generate_with_rollback(&mut restored, 0, n_after), expected, "after {:?}", before);
+}
+
+#[test]
+fn test_state_regex_sampler_roundtrip() {
+    let regex_chain = |seed| {
+        let mut chain = SamplerChain::new();
+        chain.add(Box::new(RegexSampler::new(vocab(), "a{2}(b|c)+").unwrap()));
+        chain.add(Box::new(DistSampler::new(seed)));
+        chain
+    };
+    save_and_continue(regex_chain, 2, 8);
+
+    // the DFA state is checked against the pattern
+    let mut regex = RegexSampler::new(vocab(), "a{2}(b|c)+").unwrap();
+    let e = regex.state_read(&mut LlamaIoReadBuffer::new(&1000u32.to_le_bytes())).unwrap_err();
+    assert!(e.contains("out of range"), "{}", e);
+}
+
+#[test]
+fn test_state_banned_strings_sampler_roundtrip() {
+    let banned_chain = |seed| {
+        let mut chain = SamplerChain::new();
+        chain.add(Box::new(BannedStringsSampler::new(vocab(), &["ba".to_string(), "cc".to_string()]).unwrap()));
+        chain.add(Box::new(DistSampler::new(seed)));
+        chain
+    };
+    // saved after backtracking, so the bans it placed must carry over
+    let mut ctx = LlamaContext::new(hparams().fingerprint(), 16, None);
+    ctx.set_sampler(0, banned_chain(42));
+    let n_before = (1..=64)
+        .find(|_| generate_with_rollback(&mut ctx, 0, 1)[0].1 > 0)
+        .expect("no banned string was backtracked over");
+    save_and_continue(banned_chain, n_before, 16);
+    save_and_continue(banned_chain, 2, 16);
+}
+
+#[test]
+fn test_state_sampler_rollback_across_restore() {
+    let make_chain = || {
+        let mut chain = SamplerChain::new();
+        chain.add(Box::new(RegexSampler::new(vocab(), "(a|b)+c").unwrap()));
+        chain.add(Box::new(BannedStringsSampler::new(vocab(), &["ba".to_string()]).unwrap()));
+        chain.add(Box::new(DistSampler::new(42)));
+        chain
+    };
+    let state = |sampler: &dyn LlamaSampler| {
+        let mut dummy = LlamaIoWriteDummy::new();
+        sampler.state_write(&mut dummy).unwrap();
+        let mut buf = vec![0; dummy.n_bytes()];
+        sampler.state_write(&mut LlamaIoWriteBuffer::new(&mut buf)).unwrap();
+        buf
+    };
+    let mut chain = make_chain();
+    chain.accept(1);
+    chain.accept(2);
+    let saved = state(&chain);
+
+    // "ba" takes back the "b" accepted before the save, in the restored chain too
+    let mut restored = make_chain();
+    restored.state_read(&mut LlamaIoReadBuffer::new(&saved)).unwrap();
+    for chain in [&mut chain, &mut restored] {
+        chain.accept(1);
+        assert_eq!(chain.take_rollback(), 2);
+    }
+    assert_eq!(state(&restored), state(&chain));
+    // the regex is back where only the first "a" was accepted
+    let mut rewound = make_chain();
+    rewound.accept(1);
+    assert_eq!(state(restored.get(0).unwrap()), state(rewound.get(0).unwrap()));
+}
+
+#[test]
+fn test_state_context_errors() {
+    let fingerprint = hparams().fingerprint();
+    let ctx = LlamaContext::new(fingerprint, 16, Some(Box::new(cell_kv())));
+    let size = ctx.state_get_size().unwrap();
+
+    let mut small = vec![0; size - 1];
+    let e = ctx.state_get_data(&mut small).unwrap_err();
+    assert!(e.contains("state buffer too small"), "{}", e);
+
+    let mut buf = vec![0; size];
+    ctx.state_get_data(&mut buf).unwrap();
+    let other = LlamaHparams { n_layer: 3, ..hparams() }.fingerprint();
+    let mut wrong_model = LlamaContext::new(other, 16, None);
+    let e = wrong_model.state_set_data(&buf).unwrap_err();
+    assert!(e.contains("different model"), "{}", e);
+
+    // sequence state is not whole-context state
+    let mut no_memory = LlamaContext::new(fingerprint, 16, None);
+    assert!(no_memory.state_seq_set_data(&buf, 0).unwrap_err().contains("unknown state format"));
+    let mut seq_buf = vec![0; ctx.state_seq_get_size(1).unwrap()];
+    ctx.state_seq_get_data(&mut seq_buf, 1).unwrap();
+    let e = no_memory.state_seq_set_data(&seq_buf, 0).unwrap_err();
+    assert!(e.contains("context does not"), "{}", e);
+}
+
+#[test]
+fn test_state_ffi() {
+    // no model file: the mock model
+    let model = llama_model_load_from_file(cstr("llmrust_no_such_model.gguf").as_ptr(), llama_model_default_params());
+    let mut params = llama_context_default_params();
+    params.n_seq_max = 2;
+    let ctx = llama_init_from_model(model, params);
+    assert!(!ctx.is_null());
+    assert!(!llama_get_memory(ctx as *mut _).is_null());
+
+    let size = llama_state_get_size(ctx as *mut _);
+    assert!(size > 0);
+    let mut buf = vec![0u8; size];
+    assert_eq!(llama_state_get_data(ctx as *mut _, buf.as_mut_ptr(), size - 1), 0);
+    assert_eq!(llama_state_get_data(ctx as *mut _, buf.as_mut_ptr(), size), size);
+    assert_eq!(llama_state_set_data(ctx as *mut _, buf.as_ptr(), size), size);
+    assert_eq!(llama_state_set_data(ctx as *mut _, buf.as_ptr(), size - 1), 0);
+
+    let seq_size = llama_state_seq_get_size(ctx as *mut _, 0);
+    let mut seq_buf = vec![0u8; seq_size];
+    assert_eq!(llama_state_seq_get_data(ctx as *mut _, seq_buf.as_mut_ptr(), seq_size, 0), seq_size);
+    assert_eq!(llama_state_seq_set_data(ctx as *mut _, seq_buf.as_ptr(), seq_size, 1), seq_size);
+    // whole-context state is rejected as sequence state
+    assert_eq!(llama_state_seq_set_data(ctx as *mut _, buf.as_ptr(), size, 1), 0);
+
+    assert_eq!(llama_state_get_size(std::ptr::null_mut()), 0);
+    llama_free(ctx);
+    llama_model_free(model);
+}
+
+#[test]
+fn test_state_file_kv_cells() {
+    let model = llama_model_load_from_file(cstr("llmrust_no_such_model.gguf").as_ptr(), llama_model_default_params());
+    let init = || {
+        let mut params = llama_context_default_params();
+        params.n_ctx = 16;
+        llama_init_from_model(model, params)
+    };
+    let ctx = init();
+    let mem = llama_get_memory(ctx as *mut _);
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), -1);
+
+    // the context's cache has the model's layout: fill it from a standalone one
+    let mut kv = LlamaKvCache::new(&mock_model_hparams(), 16, 1, GgmlType::F32, GgmlType::F32).unwrap();
+    kv.apply_ubatch(&(0..4).map(|pos| LlamaKvUbatchToken { pos, seq_ids: vec![0] }).collect::<Vec<_>>()).unwrap();
+    let buf = write_state(&kv, 0);
+    unsafe { llama_memory::from_handle(mem) }.unwrap().state_read(&mut LlamaIoReadBuffer::new(&buf), 0).unwrap();
+    assert_eq!(llama_memory_seq_pos_max(mem, 0), 3);
+
+    let path = std::env::temp_dir().join(format!("llmrust_state_kv_{}.bin", std::process::id()));
+    let tokens = [1, 2, 3, 4];
+    assert!(llama_state_save_file(ctx as *mut _, cstr(path.to_str().unwrap()).as_ptr(), tokens.as_ptr(), tokens.len()));
+
+    let restored = init();
+    let mut out = [0; 8];
+    let mut n_out = 0;
+    assert!(llama_state_load_file(restored as *mut _, cstr(path.to_str().unwrap()).as_ptr(), out.as_mut_ptr(), out.len(), &mut n_out));
+    assert_eq!(&out[..n_out], &tokens);
+    let restored_mem = llama_get_memory(restored as *mut _);
+    assert_eq!(llama_memory_seq_pos_min(restored_mem, 0), 0);
+    assert_eq!(llama_memory_seq_pos_max(restored_mem, 0), 3);
+
+    std::fs::remove_file(&path).ok();
+    llama_free(restored);
+    llama_free(ctx);
+    llama_model_free(model);
+}
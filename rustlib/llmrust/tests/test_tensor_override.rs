@@ -0,0 +1,93 @@
+// tests/test_tensor_override.rs - Tensor buffer type override tests
+
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_backend::GgmlBackendBufferType::{self, Cpu, CpuMapped, CpuRepack};
+use crate::llmrust::ggml::src::gguf::{GgufFile, GgufWriter};
+use crate::llmrust::src::llama_model_loader::{llama_tensor_groups, LlamaModelLoader, LlamaModelTensorBuftOverride};
+use crate::llmrust::src::llama_regex::RegexDfa;
+
+/// Two MoE layers with Q8_0 expert weights, memory mapped
+fn loader() -> LlamaModelLoader {
+    let path = std::env::temp_dir().join(format!("llmrust_tensor_override_{}.gguf", std::process::id()));
+    writer().write(&path).unwrap();
+    let gguf = GgufFile::read_mmap(&path).unwrap();
+    // the mapping outlives the file
+    std::fs::remove_file(&path).unwrap();
+    LlamaModelLoader::new(gguf, Vec::new())
+}
+
+fn writer() -> GgufWriter {
+    let mut w = GgufWriter::new();
+    w.set_str("general.architecture", "llama");
+    let q8 = |rows: usize| vec![0u8; GgmlType::Q8_0.row_size(32) * rows];
+    w.add_tensor_f32("token_embd.weight", &[4, 2], &[0.0; 8]).unwrap();
+    for il in 0..2 {
+        w.add_tensor_f32(&format!("blk.{}.attn_q.weight", il), &[4, 4], &[0.0; 16]).unwrap();
+        for ffn in ["gate", "up"] {
+            w.add_tensor(&format!("blk.{}.ffn_{}_exps.weight", il, ffn), &[32, 8], GgmlType::Q8_0, q8(8)).unwrap();
+        }
+        w.add_tensor(&format!("blk.{}.ffn_down_exps.weight", il), &[32, 6], GgmlType::Q8_0, q8(6)).unwrap();
+    }
+    w
+}
+
+fn ovr(pattern: &str, buft: GgmlBackendBufferType) -> LlamaModelTensorBuftOverride {
+    LlamaModelTensorBuftOverride::new(pattern, buft).unwrap()
+}
+
+#[test]
+fn test_tensor_override_search_patterns() {
+    let dfa = RegexDfa::compile_search(r"ffn_(up|gate)_exps").unwrap();
+    assert!(dfa.matches("blk.3.ffn_up_exps.weight"));
+    assert!(!dfa.matches("blk.3.ffn_down_exps.weight"));
+    let anchored = RegexDfa::compile_search(r"^blk\.1\.").unwrap();
+    assert!(anchored.matches("blk.1.attn_q.weight"));
+    assert!(!anchored.matches("blk.11.attn_q.weight"));
+    let tail = RegexDfa::compile_search(r"exps\.weight$").unwrap();
+    assert!(tail.matches("blk.0.ffn_up_exps.weight"));
+    assert!(!tail.matches("blk.0.ffn_up_exps.weight.lora_a"));
+    assert!(LlamaModelTensorBuftOverride::new("ffn_(", Cpu).is_err());
+
+    assert_eq!(GgmlBackendBufferType::from_name("CPU_REPACK"), Some(CpuRepack));
+    assert_eq!(GgmlBackendBufferType::from_i32(1).map(|b| b.name()), Some("CPU_Mapped"));
+    assert!(GgmlBackendBufferType::from_i32(7).is_none());
+}
+
+#[test]
+fn test_tensor_override_placement() {
+    let ml = loader();
+    let default = ml.tensor_placement(true, false, &[]).unwrap();
+    assert!(default.iter().all(|p| p.buft == CpuMapped && p.override_idx.is_none()));
+    assert!(ml.tensor_placement(false, false, &[]).unwrap().iter().all(|p| p.buft == Cpu));
+
+    // a file read into memory has no mapping to place tensors in
+    let unmapped = LlamaModelLoader::new(GgufFile::from_bytes(&writer().to_bytes()).unwrap(), Vec::new());
+    assert!(unmapped.tensor_placement(true, false, &[]).unwrap().iter().all(|p| p.buft == Cpu));
+    assert!(unmapped.tensor_placement(true, false, &[ovr("attn_q", CpuMapped)]).unwrap_err().contains("not mapped"));
+
+    // the first matching override wins
+    let overrides = [ovr(r"ffn_(up|gate)_exps", CpuRepack), ovr("exps", Cpu), ovr("ffn_up", CpuMapped)];
+    let placement = ml.tensor_placement(true, false, &overrides).unwrap();
+    let buft = |name: &str| placement.iter().find(|p| p.name == name).map(|p| (p.buft, p.override_idx)).unwrap();
+    assert_eq!(buft("blk.1.ffn_up_exps.weight"), (CpuRepack, Some(0)));
+    assert_eq!(buft("blk.0.ffn_down_exps.weight"), (Cpu, Some(1)));
+    assert_eq!(buft("blk.0.attn_q.weight"), (CpuMapped, None));
+
+    let groups = llama_tensor_groups(&placement);
+    let names: Vec<_> = groups.iter().map(|g| (g.name.as_str(), g.n_tensors, g.buft)).collect();
+    assert_eq!(
+        names,
+        [
+            ("token_embd.weight", 1, CpuMapped),
+            ("blk.*.attn_q.weight", 2, CpuMapped),
+            ("blk.*.ffn_gate_exps.weight", 2, CpuRepack),
+            ("blk.*.ffn_up_exps.weight", 2, CpuRepack),
+            ("blk.*.ffn_down_exps.weight", 2, Cpu),
+        ]
+    );
+    assert_eq!(groups[2].n_bytes, 2 * 8 * GgmlType::Q8_0.row_size(32));
+
+    // repacking needs quantized matrices with whole groups of rows
+    assert!(ml.tensor_placement(true, false, &[ovr("attn_q", CpuRepack)]).unwrap_err().contains("cannot be stored"));
+    assert!(ml.tensor_placement(true, false, &[ovr("ffn_down", CpuRepack)]).is_err());
+}
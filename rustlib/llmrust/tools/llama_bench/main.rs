@@ -1,6 +1,271 @@
-// tools/llama_bench/main.rs - Auto-generated debug file
+// tools/llama_bench/main.rs - Prompt processing benchmark
+//
+// Measures prompt processing (pp) throughput of the CPU matrix kernels on a
+// synthetic LLaMA-style stack: per layer the attention projections and a
+// SwiGLU feed-forward block, with quantized weights and every prompt token
+// going through each matrix product. Each weight type is run once in file
+// layout and once repacked, so the effect of repacking is measured on the
+// same weights; both runs must produce the same hidden states.
+//
+// Usage: llama_bench [-p 512,128] [-r 5] [--type q4_0,q8_0] [--repack 0,1]
+//                    [--n-embd 1024] [--n-ff 2816] [--n-layer 2]
 #![allow(dead_code)]
 
-pub fn debug_print() {
-    println!("DEBUG: tools/llama_bench/main.rs - File loaded successfully");
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::time::Instant;
+
+use crate::llmrust::ggml::src::ggml::GgmlType;
+use crate::llmrust::ggml::src::ggml_cpu::ops::mul_mat;
+use crate::llmrust::ggml::src::ggml_cpu::repack::{gemm_repacked, repack, RepackLayout};
+use crate::llmrust::ggml::src::ggml_quants::quantize_row;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaBenchParams {
+    pub n_prompt: Vec<usize>,
+    pub repetitions: usize,
+    pub types: Vec<GgmlType>,
+    pub repack: Vec<bool>,
+    pub n_embd: usize,
+    pub n_ff: usize,
+    pub n_layer: usize,
+}
+
+impl Default for LlamaBenchParams {
+    fn default() -> Self {
+        Self {
+            n_prompt: vec![512],
+            repetitions: 5,
+            types: vec![GgmlType::Q4_0],
+            repack: vec![false, true],
+            n_embd: 1024,
+            n_ff: 2816,
+            n_layer: 2,
+        }
+    }
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    value.split(',').map(|v| parse(v.trim())).collect()
+}
+
+pub fn llama_bench_parse_args(args: &[String]) -> Result<LlamaBenchParams, String> {
+    let mut params = LlamaBenchParams::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().map(String::as_str).ok_or_else(|| format!("error: {} expects a value", arg));
+        let invalid = |value: &str| format!("error: invalid value '{}' for {}", value, arg);
+        let count = |v: &str| v.parse::<usize>().ok().filter(|&n| n > 0);
+        match arg.as_str() {
+            "-p" | "--n-prompt" => {
+                let v = value()?;
+                params.n_prompt = parse_list(v, count).ok_or_else(|| invalid(v))?;
+            }
+            "-r" | "--repetitions" => {
+                let v = value()?;
+                params.repetitions = count(v).ok_or_else(|| invalid(v))?;
+            }
+            "--type" => {
+                let v = value()?;
+                params.types = parse_list(v, |t| GgmlType::from_name(t).filter(|t| t.is_quantized())).ok_or_else(|| invalid(v))?;
+            }
+            "--repack" => {
+                let v = value()?;
+                params.repack = parse_list(v, |b| match b {
+                    "0" => Some(false),
+                    "1" => Some(true),
+                    _ => None,
+                })
+                .ok_or_else(|| invalid(v))?;
+            }
+            "--n-embd" | "--n-ff" | "--n-layer" => {
+                let v = value()?;
+                let n = count(v).ok_or_else(|| invalid(v))?;
+                match arg.as_str() {
+                    "--n-embd" => params.n_embd = n,
+                    "--n-ff" => params.n_ff = n,
+                    _ => params.n_layer = n,
+                }
+            }
+            _ => return Err(format!("error: unknown argument '{}'", arg)),
+        }
+    }
+    if !params.n_embd.is_multiple_of(32) || !params.n_ff.is_multiple_of(32) {
+        return Err("error: --n-embd and --n-ff must be multiples of 32".to_string());
+    }
+    Ok(params)
+}
+
+/// One quantized weight matrix, in file layout or repacked
+struct BenchWeight {
+    data: Vec<u8>,
+    layout: Option<RepackLayout>,
+    ncols: usize,
+    nrows: usize,
+}
+
+impl BenchWeight {
+    fn new(ty: GgmlType, ncols: usize, nrows: usize, seed: usize, repacked: bool) -> Result<Self, String> {
+        let row_size = ty.row_size(ncols);
+        let mut data = vec![0u8; row_size * nrows];
+        for (r, row) in data.chunks_exact_mut(row_size).enumerate() {
+            let values: Vec<f32> = (0..ncols).map(|c| (((r * 31 + c * 17 + seed * 7) % 97) as f32 / 48.5 - 1.0) * 0.05).collect();
+            quantize_row(ty, &values, row);
+        }
+        let layout = if repacked { RepackLayout::preferred(nrows) } else { None };
+        if let Some(layout) = layout {
+            data = repack(ty, layout, &data, ncols, nrows)?;
+        }
+        Ok(Self { data, layout, ncols, nrows })
+    }
+
+    fn mul(&self, ty: GgmlType, x: &[f32]) -> Result<Vec<f32>, String> {
+        let mut out = vec![0.0; x.len() / self.ncols * self.nrows];
+        match self.layout {
+            Some(layout) => gemm_repacked(ty, layout, &self.data, self.ncols, self.nrows, x, &mut out)?,
+            None => mul_mat(ty, &self.data, self.ncols, self.nrows, x, &mut out),
+        }
+        Ok(out)
+    }
+}
+
+struct BenchLayer {
+    wq: BenchWeight,
+    wk: BenchWeight,
+    wv: BenchWeight,
+    wo: BenchWeight,
+    gate: BenchWeight,
+    up: BenchWeight,
+    down: BenchWeight,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaBenchResult {
+    pub ty: GgmlType,
+    /// Name of the repacked layout, `None` for file layout
+    pub layout: Option<String>,
+    pub n_prompt: usize,
+    /// Tokens per second of each repetition
+    pub samples: Vec<f64>,
+    /// Sum of the final hidden states, equal with and without repacking
+    pub checksum: f64,
+}
+
+impl LlamaBenchResult {
+    pub fn avg_ts(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / self.samples.len() as f64
+    }
+
+    pub fn stdev_ts(&self) -> f64 {
+        let n = self.samples.len();
+        if n < 2 {
+            return 0.0;
+        }
+        let avg = self.avg_ts();
+        (self.samples.iter().map(|s| (s - avg) * (s - avg)).sum::<f64>() / (n - 1) as f64).sqrt()
+    }
+}
+
+fn run_pp(ty: GgmlType, layers: &[BenchLayer], n_embd: usize, n_prompt: usize) -> Result<Vec<f32>, String> {
+    let mut x: Vec<f32> = (0..n_prompt * n_embd).map(|i| ((i % 13) as f32 - 6.0) / 6.0).collect();
+    for l in layers {
+        let q = l.wq.mul(ty, &x)?;
+        let _k = l.wk.mul(ty, &x)?;
+        let _v = l.wv.mul(ty, &x)?;
+        for (xi, oi) in x.iter_mut().zip(l.wo.mul(ty, &q)?) {
+            *xi += oi;
+        }
+        let gate = l.gate.mul(ty, &x)?;
+        let h: Vec<f32> = gate.iter().zip(l.up.mul(ty, &x)?).map(|(&g, u)| g / (1.0 + (-g).exp()) * u).collect();
+        for (xi, di) in x.iter_mut().zip(l.down.mul(ty, &h)?) {
+            *xi += di;
+        }
+    }
+    Ok(x)
+}
+
+/// Runs every combination of weight type, layout and prompt size.
+pub fn llama_bench(params: &LlamaBenchParams) -> Result<Vec<LlamaBenchResult>, String> {
+    let (n_embd, n_ff) = (params.n_embd, params.n_ff);
+    let mut results = Vec::new();
+    for &ty in &params.types {
+        for &repacked in &params.repack {
+            let layers = (0..params.n_layer)
+                .map(|il| {
+                    let w = |ncols, nrows, k| BenchWeight::new(ty, ncols, nrows, il * 7 + k, repacked);
+                    Ok(BenchLayer {
+                        wq: w(n_embd, n_embd, 0)?,
+                        wk: w(n_embd, n_embd, 1)?,
+                        wv: w(n_embd, n_embd, 2)?,
+                        wo: w(n_embd, n_embd, 3)?,
+                        gate: w(n_embd, n_ff, 4)?,
+                        up: w(n_embd, n_ff, 5)?,
+                        down: w(n_ff, n_embd, 6)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let layout = layers.first().and_then(|l| l.wq.layout).map(|layout| layout.type_name(ty));
+            for &n_prompt in &params.n_prompt {
+                // warmup, also the reference output
+                let checksum = run_pp(ty, &layers, n_embd, n_prompt)?.iter().map(|&v| v as f64).sum();
+                let mut samples = Vec::with_capacity(params.repetitions);
+                for _ in 0..params.repetitions {
+                    let t0 = Instant::now();
+                    run_pp(ty, &layers, n_embd, n_prompt)?;
+                    samples.push(n_prompt as f64 / t0.elapsed().as_secs_f64().max(1e-9));
+                }
+                results.push(LlamaBenchResult { ty, layout: layout.clone(), n_prompt, samples, checksum });
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Results as a markdown table, like llama-bench prints them
+pub fn llama_bench_markdown(params: &LlamaBenchParams, results: &[LlamaBenchResult]) -> String {
+    let mut out = String::from("| type | layout | n_embd | n_ff | n_layer | test | t/s |\n");
+    out.push_str("| ---- | ------ | -----: | ---: | ------: | ---: | --: |\n");
+    for r in results {
+        out.push_str(&format!(
+            "| {} | {} | {} | {} | {} | pp{} | {:.2} ± {:.2} |\n",
+            r.ty.name(),
+            r.layout.as_deref().unwrap_or("file"),
+            params.n_embd,
+            params.n_ff,
+            params.n_layer,
+            r.n_prompt,
+            r.avg_ts(),
+            r.stdev_ts()
+        ));
+    }
+    out
+}
+
+/// Entry point of `llm bench`; `argv` holds the benchmark's arguments only.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn rust_llama_bench(argc: c_int, argv: *const *const c_char) -> c_int {
+    let args: Vec<String> = (0..argc.max(0) as usize)
+        .filter_map(|i| unsafe {
+            let arg = *argv.add(i);
+            (!arg.is_null()).then(|| CStr::from_ptr(arg).to_string_lossy().into_owned())
+        })
+        .collect();
+    let params = match llama_bench_parse_args(&args) {
+        Ok(params) => params,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    match llama_bench(&params) {
+        Ok(results) => {
+            print!("{}", llama_bench_markdown(&params, &results));
+            0
+        }
+        Err(e) => {
+            eprintln!("llama_bench: {}", e);
+            1
+        }
+    }
 }
@@ -4,3 +4,6 @@
 pub fn debug_print() {
     println!("DEBUG: tools/mod.rs - File loaded successfully");
 }
+
+#[path = "llama_bench/main.rs"]
+pub mod llama_bench;
@@ -58,8 +58,6 @@ pub fn cpu_info_platform() -> CpuInfo {
 
 #[cfg(target_os = "linux")]
 pub fn cpu_info_platform() -> CpuInfo {
-    use std::fs;
-    
     let logical = std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(0);
     
     // Read /proc/cpuinfo on Linux to get actual CPU information
@@ -183,6 +181,7 @@ fn write_brand(dest: &mut [u8; 128], src: &[u8]) {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn rust_get_cpu_info(out: *mut CpuInfo) -> bool {
     if out.is_null() {
         return false;
@@ -200,6 +199,7 @@ pub extern "C" fn rust_get_cpu_info(out: *mut CpuInfo) -> bool {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn rust_get_cpu_brand(buf: *mut u8, buf_len: usize) -> usize {
     if buf.is_null() || buf_len == 0 {
         return 0;